@@ -39,6 +39,224 @@ public interface INativeRemoteAuthService {
          */
         boolean sendRequest(int connectionId, byte[] request, ResponseCallback callback);
 
+        /**
+         * Sends message to the remote authenticator, which should be answered within
+         * timeoutMillis.
+         *
+         * <p>Transports that can't bound the time of a request ignore the timeout: the native
+         * side fails the request once its deadline has passed anyway.
+         *
+         * @param connectionId connection ID of the {@link android.remoteauth.RemoteAuthenticator}
+         * @param request payload of the request
+         * @param timeoutMillis time left until the deadline of the request
+         * @param callback to be used to pass the response result
+         * @return true if succeeded, false otherwise.
+         * @hide
+         */
+        default boolean sendRequest(
+                int connectionId, byte[] request, long timeoutMillis, ResponseCallback callback) {
+            return sendRequest(connectionId, request, callback);
+        }
+
+        /**
+         * Sends a message to the remote authenticator that expects no response.
+         *
+         * @param connectionId connection ID of the {@link android.remoteauth.RemoteAuthenticator}
+         * @param payload payload of the notification
+         * @return true if succeeded, false otherwise.
+         * @hide
+         */
+        default boolean sendNotification(int connectionId, byte[] payload) {
+            return false;
+        }
+
+        /**
+         * Stops sending a request the native side no longer waits for, because it was cancelled
+         * or timed out. The callback of the request may still be invoked, and is then ignored.
+         *
+         * @param connectionId connection ID the request was sent on
+         * @param callback callback passed to {@link #sendRequest} with the request
+         * @hide
+         */
+        default void cancelRequest(int connectionId, ResponseCallback callback) {}
+
+        /**
+         * Returns the properties of a connection, as {@code [mtu, transport, rssi,
+         * latencyMillis]}, with {@link Integer#MIN_VALUE} for an unknown RSSI and a negative
+         * latency when there is no estimate.
+         *
+         * @param connectionId connection ID of the {@link android.remoteauth.RemoteAuthenticator}
+         * @return the properties, or null if the connection is unknown.
+         * @hide
+         */
+        default int[] getConnectionInfo(int connectionId) {
+            return null;
+        }
+
+        /**
+         * Opens a connection to a remote device.
+         *
+         * @param deviceId id of the remote device
+         * @param callback to be passed the id of the connection
+         * @hide
+         */
+        default void openConnection(String deviceId, OperationCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Closes a connection to a remote device.
+         *
+         * @param connectionId connection ID of the {@link android.remoteauth.RemoteAuthenticator}
+         * @param callback to be passed the id of the closed connection
+         * @hide
+         */
+        default void closeConnection(int connectionId, OperationCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Reports how much of a large request was sent, for requests with progress forwarding.
+         *
+         * @param traceId identifies the request, or its authentication flow, in native logs
+         * @param bytesSent bytes of the request sent so far
+         * @param total size of the whole request, in bytes
+         * @hide
+         */
+        default void onRequestProgress(long traceId, long bytesSent, long total) {}
+
+        /**
+         * Invoked when the native side closed the platform for being idle. Requests can't be sent
+         * through it anymore.
+         *
+         * @hide
+         */
+        default void onIdleClosed() {}
+
+        /**
+         * Signs data with the device key stored in Keystore under an alias.
+         *
+         * @param alias alias of the key
+         * @param data data to sign
+         * @param callback to be passed the DER-encoded ECDSA signature
+         * @hide
+         */
+        default void signWithDeviceKey(String alias, byte[] data, KeystoreCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Gets the public key of the device key stored in Keystore under an alias.
+         *
+         * @param alias alias of the key
+         * @param callback to be passed the encoded public key
+         * @hide
+         */
+        default void getDevicePublicKey(String alias, KeystoreCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Generates a device key in Keystore under an alias.
+         *
+         * @param alias alias of the key
+         * @param strongBox whether the key should be backed by StrongBox
+         * @param callback to be passed the encoded public key
+         * @hide
+         */
+        default void generateKeyPair(String alias, boolean strongBox, KeystoreCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Derives the secret protecting enrollment backups from a Keystore key.
+         *
+         * @param salt salt of the derivation
+         * @param callback to be passed the secret
+         * @hide
+         */
+        default void deriveBackupSecret(byte[] salt, KeystoreCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Persists the enrollment record of a device, encrypted with a Keystore key.
+         *
+         * @param deviceId id of the enrolled device
+         * @param record serialized enrollment record
+         * @param callback to be passed an empty result
+         * @hide
+         */
+        default void storeEnrollment(String deviceId, byte[] record, KeystoreCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /**
+         * Deletes the persisted enrollment record of a device.
+         *
+         * @param deviceId id of the enrolled device
+         * @param callback to be passed an empty result
+         * @hide
+         */
+        default void deleteEnrollment(String deviceId, KeystoreCallback callback) {
+            callback.onFailure(ERROR_UNSUPPORTED);
+        }
+
+        /** Error code of operations the platform doesn't support. */
+        int ERROR_UNSUPPORTED = -1;
+
+        /** Error code of Keystore operations when the device has no key under the alias. */
+        int ERROR_KEY_NOT_FOUND = 1;
+
+        /** Error code of Keystore operations when StrongBox was required but is unavailable. */
+        int ERROR_STRONGBOX_UNAVAILABLE = 2;
+
+        /**
+         * Interface for a callback to complete a connection operation.
+         *
+         * @hide
+         */
+        interface OperationCallback {
+            /**
+             * Invoked when the operation succeeds.
+             *
+             * @param connectionId id of the connection opened or closed
+             * @hide
+             */
+            void onSuccess(int connectionId);
+
+            /**
+             * Invoked when the operation fails.
+             *
+             * @param errorCode indicating the error
+             * @hide
+             */
+            void onFailure(int errorCode);
+        }
+
+        /**
+         * Interface for a callback to complete a Keystore operation.
+         *
+         * @hide
+         */
+        interface KeystoreCallback {
+            /**
+             * Invoked when the operation succeeds.
+             *
+             * @param result signature, public key or secret, empty for enrollment records
+             * @hide
+             */
+            void onSuccess(byte[] result);
+
+            /**
+             * Invoked when the operation fails.
+             *
+             * @param errorCode indicating the error
+             * @hide
+             */
+            void onFailure(int errorCode);
+        }
+
         /**
          * Interface for a callback to send a response back.
          *
@@ -53,6 +271,44 @@ public interface INativeRemoteAuthService {
              */
             void onSuccess(byte[] response);
 
+            /**
+             * Invoked when message sending succeeds, with how the response arrived.
+             *
+             * @param response contains response
+             * @param transport transport the response arrived over, or -1 if unknown
+             * @param remoteStatus status reported by the remote device besides the response, 0 if
+             *     none
+             * @hide
+             */
+            default void onSuccess(byte[] response, int transport, int remoteStatus) {
+                onSuccess(response);
+            }
+
+            /**
+             * Invoked as a large request is sent.
+             *
+             * @param bytesSent bytes of the request sent so far
+             * @param total size of the whole request, in bytes
+             * @hide
+             */
+            default void onProgress(long bytesSent, long total) {}
+
+            /**
+             * Invoked for each chunk of a response streamed by the remote device, instead of
+             * {@link #onSuccess}.
+             *
+             * @param chunk next part of the response
+             * @hide
+             */
+            default void onChunk(byte[] chunk) {}
+
+            /**
+             * Invoked after the last chunk of a streamed response.
+             *
+             * @hide
+             */
+            default void onComplete() {}
+
             /**
              * Invoked when message sending fails.
              *
@@ -0,0 +1,33 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package com.android.server.remoteauth.jni;
+
+import com.android.internal.annotations.Keep;
+
+/**
+ * Exception thrown by the native rust implementation of {@link
+ * com.android.server.remoteauth.RemoteAuthService} when Java delivers a response larger than its
+ * connection allows. The request is failed natively.
+ *
+ * @hide
+ */
+@Keep
+public class MessageTooLargeException extends RuntimeException {
+    public MessageTooLargeException(final String message) {
+        super(message);
+    }
+}
@@ -0,0 +1,510 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package com.android.server.remoteauth.jni;
+
+import android.util.Log;
+
+import com.android.internal.annotations.Keep;
+import com.android.server.remoteauth.jni.INativeRemoteAuthService.IPlatform;
+
+import java.util.concurrent.ConcurrentHashMap;
+
+/**
+ * The Java side of a native platform: receives the upcalls of the Rust implementation, passes
+ * them to an {@link IPlatform}, and hands the results back to Rust.
+ *
+ * <p>Declares the native functions of the library, which are exported for this class.
+ *
+ * @hide
+ */
+@Keep
+public class NativeRemoteAuthJavaPlatform {
+    private static final String TAG = NativeRemoteAuthJavaPlatform.class.getSimpleName();
+    private static final int TRANSPORT_UNKNOWN = -1;
+    private static final int REMOTE_STATUS_NONE = 0;
+    // Matches Connection.ERROR_UNKNOWN.
+    private static final int ERROR_UNKNOWN = 0;
+    private static final long INVALID_HANDLE = -1;
+
+    private final IPlatform mPlatform;
+    private final Object mNativeLock;
+    // Requests sent through mPlatform, by response handle, until they complete, are cancelled or
+    // time out.
+    private final ConcurrentHashMap<Long, InFlightRequest> mRequests = new ConcurrentHashMap<>();
+    private volatile long mPlatformHandle = INVALID_HANDLE;
+
+    private static final class InFlightRequest {
+        final int mConnectionId;
+        final IPlatform.ResponseCallback mCallback;
+
+        InFlightRequest(int connectionId, IPlatform.ResponseCallback callback) {
+            mConnectionId = connectionId;
+            mCallback = callback;
+        }
+    }
+
+    /**
+     * Creates the native platform for platform.
+     *
+     * @param platform transport and Keystore the native side reaches the remote devices through
+     * @param nativeLock lock held around the calls to the native side
+     * @throws IllegalStateException if the native platform can't be created
+     * @hide
+     */
+    public NativeRemoteAuthJavaPlatform(final IPlatform platform, final Object nativeLock) {
+        mPlatform = platform;
+        mNativeLock = nativeLock;
+        synchronized (mNativeLock) {
+            mPlatformHandle = native_create_platform(this, null);
+        }
+        if (mPlatformHandle == INVALID_HANDLE) {
+            throw new IllegalStateException("Failed to create the native platform");
+        }
+    }
+
+    /**
+     * Returns the handle of the native platform, or -1 once it is closed.
+     *
+     * @hide
+     */
+    public long getPlatformHandle() {
+        return mPlatformHandle;
+    }
+
+    /**
+     * Registers this platform as the way to reach a remote device over a transport.
+     *
+     * @hide
+     */
+    public void registerDevice(String deviceId, int transport) throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            native_register_platform(mPlatformHandle, deviceId, transport);
+        }
+    }
+
+    /**
+     * Cancels a request that has no response yet.
+     *
+     * @return false if there is no such request.
+     * @hide
+     */
+    public boolean cancelRequest(int connectionId, long responseHandle)
+            throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            return native_cancel_request(mPlatformHandle, connectionId, responseHandle);
+        }
+    }
+
+    /**
+     * Delivers a message the remote device pushed without a request.
+     *
+     * @hide
+     */
+    public void onMessageReceived(int connectionId, byte[] payload)
+            throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            native_on_message_received(connectionId, payload, mPlatformHandle);
+        }
+    }
+
+    /**
+     * Reports a connection going up (0), down (1) or degraded (2).
+     *
+     * @hide
+     */
+    public void onConnectionStateChanged(int connectionId, int state)
+            throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            native_on_connection_state_changed(connectionId, state, mPlatformHandle);
+        }
+    }
+
+    /**
+     * Reports the transport of a connection congested, or clear again.
+     *
+     * @hide
+     */
+    public void onFlowControl(int connectionId, boolean paused) throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            native_on_flow_control(connectionId, paused, mPlatformHandle);
+        }
+    }
+
+    /**
+     * Returns the smoothed round-trip time of a connection in milliseconds, or -1 if it was never
+     * pinged successfully.
+     *
+     * @hide
+     */
+    public long getConnectionRttMillis(int connectionId) throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            return native_get_connection_rtt_millis(mPlatformHandle, connectionId);
+        }
+    }
+
+    /**
+     * Drains the in-flight requests, waiting at most timeoutMillis for them, then releases the
+     * native platform.
+     *
+     * @hide
+     */
+    public void shutdown(long timeoutMillis) throws PlatformBadHandleException {
+        synchronized (mNativeLock) {
+            native_shutdown_platform(mPlatformHandle, timeoutMillis);
+        }
+        destroy();
+    }
+
+    /**
+     * Releases the native platform, failing its pending requests.
+     *
+     * @hide
+     */
+    public void destroy() throws PlatformBadHandleException {
+        final long platformHandle = mPlatformHandle;
+        if (platformHandle == INVALID_HANDLE) {
+            return;
+        }
+        mPlatformHandle = INVALID_HANDLE;
+        mRequests.clear();
+        synchronized (mNativeLock) {
+            native_destroy_platform(platformHandle);
+        }
+    }
+
+    /* Upcalls from the native side, on its dispatcher threads. */
+
+    @Keep
+    private void sendRequest(
+            int connectionId,
+            byte[] request,
+            long responseHandle,
+            long platformHandle,
+            long timeoutMillis,
+            long traceId) {
+        Log.d(TAG, String.format(
+                "sendRequest with connectionId: %d, rh: %d, ph: %d, timeout: %d, trace: %016x",
+                connectionId, responseHandle, platformHandle, timeoutMillis, traceId));
+        final IPlatform.ResponseCallback callback =
+                new IPlatform.ResponseCallback() {
+                    @Override
+                    public void onSuccess(byte[] response) {
+                        onSuccess(response, TRANSPORT_UNKNOWN, REMOTE_STATUS_NONE);
+                    }
+
+                    @Override
+                    public void onSuccess(byte[] response, int transport, int remoteStatus) {
+                        if (mRequests.remove(responseHandle) == null) {
+                            return;
+                        }
+                        synchronized (mNativeLock) {
+                            try {
+                                native_on_send_request_success(
+                                        response,
+                                        platformHandle,
+                                        responseHandle,
+                                        transport,
+                                        remoteStatus);
+                            } catch (MessageTooLargeException e) {
+                                // The request was failed natively.
+                                Log.w(TAG, "Response of " + response.length + " bytes rejected", e);
+                            }
+                        }
+                    }
+
+                    @Override
+                    public void onProgress(long bytesSent, long total) {
+                        if (!mRequests.containsKey(responseHandle)) {
+                            return;
+                        }
+                        synchronized (mNativeLock) {
+                            native_on_send_request_progress(
+                                    bytesSent, total, platformHandle, responseHandle);
+                        }
+                    }
+
+                    @Override
+                    public void onChunk(byte[] chunk) {
+                        if (!mRequests.containsKey(responseHandle)) {
+                            return;
+                        }
+                        synchronized (mNativeLock) {
+                            try {
+                                native_on_send_request_chunk(chunk, platformHandle, responseHandle);
+                            } catch (MessageTooLargeException e) {
+                                // The request was failed natively.
+                                mRequests.remove(responseHandle);
+                                Log.w(TAG, "Chunk of " + chunk.length + " bytes rejected", e);
+                            }
+                        }
+                    }
+
+                    @Override
+                    public void onComplete() {
+                        if (mRequests.remove(responseHandle) == null) {
+                            return;
+                        }
+                        synchronized (mNativeLock) {
+                            try {
+                                native_on_send_request_complete(platformHandle, responseHandle);
+                            } catch (MessageTooLargeException e) {
+                                // The request was failed natively.
+                                Log.w(TAG, "Streamed response rejected", e);
+                            }
+                        }
+                    }
+
+                    @Override
+                    public void onFailure(int errorCode) {
+                        if (mRequests.remove(responseHandle) == null) {
+                            return;
+                        }
+                        synchronized (mNativeLock) {
+                            native_on_send_request_error(errorCode, platformHandle, responseHandle);
+                        }
+                    }
+                };
+        mRequests.put(responseHandle, new InFlightRequest(connectionId, callback));
+        if (!mPlatform.sendRequest(connectionId, request, timeoutMillis, callback)) {
+            callback.onFailure(ERROR_UNKNOWN);
+        }
+    }
+
+    @Keep
+    private void onSendRequestTimeout(long responseHandle, long platformHandle) {
+        Log.d(TAG, String.format(
+                "onSendRequestTimeout with rh: %d, ph: %d", responseHandle, platformHandle));
+        final InFlightRequest request = mRequests.remove(responseHandle);
+        if (request != null) {
+            mPlatform.cancelRequest(request.mConnectionId, request.mCallback);
+        }
+    }
+
+    @Keep
+    private void cancelRequest(int connectionId, long responseHandle, long platformHandle) {
+        Log.d(TAG, String.format(
+                "cancelRequest with connectionId: %d, rh: %d, ph: %d",
+                connectionId, responseHandle, platformHandle));
+        final InFlightRequest request = mRequests.remove(responseHandle);
+        if (request != null) {
+            mPlatform.cancelRequest(connectionId, request.mCallback);
+        }
+    }
+
+    @Keep
+    private void onRequestProgress(long responseHandle, long traceId, long bytesSent, long total) {
+        mPlatform.onRequestProgress(traceId, bytesSent, total);
+    }
+
+    @Keep
+    private void onPlatformIdleClosed(long platformHandle) {
+        Log.i(TAG, String.format("onPlatformIdleClosed with ph: %d", platformHandle));
+        mPlatformHandle = INVALID_HANDLE;
+        mRequests.clear();
+        mPlatform.onIdleClosed();
+    }
+
+    @Keep
+    private void sendNotification(int connectionId, byte[] payload, long platformHandle) {
+        if (!mPlatform.sendNotification(connectionId, payload)) {
+            Log.w(TAG, String.format(
+                    "Failed to send notification on connectionId: %d, ph: %d",
+                    connectionId, platformHandle));
+        }
+    }
+
+    @Keep
+    private int[] getConnectionInfo(int connectionId, long platformHandle) {
+        return mPlatform.getConnectionInfo(connectionId);
+    }
+
+    @Keep
+    private void openConnection(String deviceId, long operationHandle, long platformHandle) {
+        mPlatform.openConnection(deviceId, newOperationCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void closeConnection(int connectionId, long operationHandle, long platformHandle) {
+        mPlatform.closeConnection(
+                connectionId, newOperationCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void signWithDeviceKey(
+            String alias, byte[] data, long operationHandle, long platformHandle) {
+        mPlatform.signWithDeviceKey(
+                alias, data, newKeystoreCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void getDevicePublicKey(String alias, long operationHandle, long platformHandle) {
+        mPlatform.getDevicePublicKey(alias, newKeystoreCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void generateKeyPair(
+            String alias, boolean strongBox, long operationHandle, long platformHandle) {
+        mPlatform.generateKeyPair(
+                alias, strongBox, newKeystoreCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void storeEnrollment(
+            String deviceId, byte[] record, long operationHandle, long platformHandle) {
+        mPlatform.storeEnrollment(
+                deviceId, record, newKeystoreCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void deleteEnrollment(String deviceId, long operationHandle, long platformHandle) {
+        mPlatform.deleteEnrollment(deviceId, newKeystoreCallback(operationHandle, platformHandle));
+    }
+
+    @Keep
+    private void deriveBackupSecret(byte[] salt, long operationHandle, long platformHandle) {
+        mPlatform.deriveBackupSecret(salt, newKeystoreCallback(operationHandle, platformHandle));
+    }
+
+    private IPlatform.OperationCallback newOperationCallback(
+            long operationHandle, long platformHandle) {
+        return new IPlatform.OperationCallback() {
+            @Override
+            public void onSuccess(int connectionId) {
+                synchronized (mNativeLock) {
+                    native_on_connection_operation_success(
+                            connectionId, platformHandle, operationHandle);
+                }
+            }
+
+            @Override
+            public void onFailure(int errorCode) {
+                synchronized (mNativeLock) {
+                    native_on_connection_operation_error(
+                            errorCode, platformHandle, operationHandle);
+                }
+            }
+        };
+    }
+
+    private IPlatform.KeystoreCallback newKeystoreCallback(
+            long operationHandle, long platformHandle) {
+        return new IPlatform.KeystoreCallback() {
+            @Override
+            public void onSuccess(byte[] result) {
+                synchronized (mNativeLock) {
+                    native_on_keystore_operation_success(result, platformHandle, operationHandle);
+                }
+            }
+
+            @Override
+            public void onFailure(int errorCode) {
+                synchronized (mNativeLock) {
+                    native_on_keystore_operation_error(errorCode, platformHandle, operationHandle);
+                }
+            }
+        };
+    }
+
+    /* Native functions implemented in JNI */
+    // These functions are implemented in remoteauth_jni_android_protocol
+    static native boolean native_init(Object config);
+
+    static native boolean native_reset();
+
+    static native boolean native_set_config(String key, String value);
+
+    static native int native_load_enrollments(byte[][] records);
+
+    static native String[] native_get_enrolled_devices();
+
+    static native String[] native_get_enrolled_device_metadata(String deviceId);
+
+    static native boolean native_is_enrolled(String deviceId);
+
+    static native String native_redeem_unlock_token(byte[] token);
+
+    static native String native_dump_audit_log(long since);
+
+    // These functions are implemented in remoteauth_jni_android_platform
+    private static native long native_create_platform(Object javaPlatform, Object config);
+
+    static native void native_start_platform_sweeper(
+            long intervalMillis, long idleThresholdMillis, boolean evict);
+
+    static native int native_get_platform_count();
+
+    private static native void native_on_send_request_success(
+            byte[] appResponse,
+            long platformHandle,
+            long responseHandle,
+            int transport,
+            int remoteStatus);
+
+    private static native void native_on_send_request_chunk(
+            byte[] chunk, long platformHandle, long responseHandle);
+
+    private static native void native_on_send_request_progress(
+            long bytesSent, long total, long platformHandle, long responseHandle);
+
+    private static native void native_on_send_request_complete(
+            long platformHandle, long responseHandle);
+
+    private static native void native_on_send_request_error(
+            int errorCode, long platformHandle, long responseHandle);
+
+    private static native void native_on_message_received(
+            int connectionId, byte[] payload, long platformHandle)
+            throws PlatformBadHandleException;
+
+    private static native void native_on_connection_state_changed(
+            int connectionId, int state, long platformHandle)
+            throws PlatformBadHandleException;
+
+    private static native void native_on_flow_control(
+            int connectionId, boolean paused, long platformHandle)
+            throws PlatformBadHandleException;
+
+    private static native void native_on_connection_operation_success(
+            int connectionId, long platformHandle, long operationHandle);
+
+    private static native void native_on_connection_operation_error(
+            int errorCode, long platformHandle, long operationHandle);
+
+    private static native void native_on_keystore_operation_success(
+            byte[] result, long platformHandle, long operationHandle);
+
+    private static native void native_on_keystore_operation_error(
+            int errorCode, long platformHandle, long operationHandle);
+
+    private static native boolean native_cancel_request(
+            long platformHandle, int connectionId, long responseHandle)
+            throws PlatformBadHandleException;
+
+    private static native void native_register_platform(
+            long platformHandle, String deviceId, int transport)
+            throws PlatformBadHandleException;
+
+    private static native void native_destroy_platform(long platformHandle)
+            throws PlatformBadHandleException;
+
+    private static native void native_shutdown_platform(long platformHandle, long timeoutMillis)
+            throws PlatformBadHandleException;
+
+    private static native long native_get_connection_rtt_millis(
+            long platformHandle, int connectionId)
+            throws PlatformBadHandleException;
+}
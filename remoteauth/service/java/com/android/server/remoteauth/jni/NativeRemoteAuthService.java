@@ -18,7 +18,6 @@ package com.android.server.remoteauth.jni;
 
 import android.util.Log;
 
-import com.android.internal.annotations.Keep;
 import com.android.server.remoteauth.jni.INativeRemoteAuthService.IPlatform;
 
 /**
@@ -30,7 +29,7 @@ import com.android.server.remoteauth.jni.INativeRemoteAuthService.IPlatform;
 public class NativeRemoteAuthService {
     private static final String TAG = NativeRemoteAuthService.class.getSimpleName();
 
-    private IPlatform mPlatform;
+    private NativeRemoteAuthJavaPlatform mJavaPlatform;
     public final Object mNativeLock = new Object();
 
     // Constructor should receive pointers to:
@@ -38,58 +37,27 @@ public class NativeRemoteAuthService {
     public NativeRemoteAuthService() {
         System.loadLibrary("remoteauth_jni_rust");
         synchronized (mNativeLock) {
-            native_init();
+            if (!NativeRemoteAuthJavaPlatform.native_init(null)) {
+                Log.e(TAG, "Failed to initialize the native library");
+            }
         }
     }
 
+    /**
+     * Creates the native platform reaching remote devices through platform.
+     *
+     * @hide
+     */
     public void setDeviceListener(final IPlatform platform) {
-        mPlatform = platform;
+        mJavaPlatform = new NativeRemoteAuthJavaPlatform(platform, mNativeLock);
     }
 
     /**
-     * Sends message to the remote authenticator
+     * Returns the native platform created by {@link #setDeviceListener}, or null.
      *
-     * @param connectionId connection ID of the {@link android.remoteauth.RemoteAuthenticator}
-     * @param request payload of the request
-     * @param responseHandle a handle associated with the request, used to pass the response to the
-     *     platform
-     * @param platformHandle a handle associated with the platform object, used to pass the response
-     *     to the specific platform
      * @hide
      */
-    @Keep
-    public void sendRequest(
-            int connectionId, byte[] request, long responseHandle, long platformHandle) {
-        Log.d(TAG, String.format("sendRequest with connectionId: %d, rh: %d, ph: %d",
-                connectionId, responseHandle, platformHandle));
-        mPlatform.sendRequest(
-                connectionId,
-                request,
-                new IPlatform.ResponseCallback() {
-                    @Override
-                    public void onSuccess(byte[] response) {
-                        synchronized (mNativeLock) {
-                            native_on_send_request_success(
-                                    response, platformHandle, responseHandle);
-                        }
-                    }
-
-                    @Override
-                    public void onFailure(int errorCode) {
-                        synchronized (mNativeLock) {
-                            native_on_send_request_error(errorCode, platformHandle, responseHandle);
-                        }
-                    }
-                });
+    public NativeRemoteAuthJavaPlatform getJavaPlatform() {
+        return mJavaPlatform;
     }
-
-    /* Native functions implemented in JNI */
-    // This function should be implemented in remoteauth_jni_android_protocol
-    private native boolean native_init();
-
-    private native void native_on_send_request_success(
-            byte[] appResponse, long platformHandle, long responseHandle);
-
-    private native void native_on_send_request_error(
-            int errorCode, long platformHandle, long responseHandle);
 }
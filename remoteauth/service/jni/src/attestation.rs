@@ -0,0 +1,935 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Validation of Android Key Attestation certificate chains, run by enrollment.
+//!
+//! A remote device proves its long-term key lives in its Keystore by sending the attestation
+//! chain of the key, leaf first. The chain must end in a pinned root, every certificate must be
+//! valid at the time of the check and signed by the next one, issuers must be CAs, and the leaf
+//! must carry the attestation extension with the challenge of the enrollment. Policy may further
+//! require the key to be hardware-backed, i.e. in a TEE or StrongBox.
+//!
+//! Only the DER needed for this is parsed here. Signatures go through `cose::Verifier`, built
+//! from the public keys of the chain through `KeyVerifiers`, in production
+//! `crypto::AttestationVerifiers`; ECDSA signatures are handed to it as the fixed-size `r || s`
+//! COSE uses rather than DER. RSA keys are listed as `Rs256`, and verify the PSS signatures of
+//! their certificates as `Ps256`.
+
+use crate::cose::{Algorithm, Verifier};
+use crate::kdf::{sha256, HASH_LEN};
+use crate::secret::constant_time_eq;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Longest chain accepted, root included.
+pub const MAX_CHAIN_LEN: usize = 8;
+
+// DER tags.
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const ENUMERATED: u8 = 0x0a;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const VERSION: u8 = 0xa0;
+const ISSUER_UNIQUE_ID: u8 = 0x81;
+const SUBJECT_UNIQUE_ID: u8 = 0x82;
+const EXTENSIONS: u8 = 0xa3;
+
+// Contents of the object identifiers used.
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const P256_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384_OID: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const ECDSA_SHA256_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_SHA384_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ED25519_OID: &[u8] = &[0x2b, 0x65, 0x70];
+const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const RSASSA_PSS_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+const SHA256_WITH_RSA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+// Content of the `RSASSA-PSS-params` of PS256: SHA-256, MGF1 with SHA-256, and a 32-byte salt.
+const PSS_SHA256_PARAMS: &[u8] = &[
+    0xa0, 0x0f, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0xa1, 0x1c, 0x30, 0x1a, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08,
+    0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0xa2,
+    0x03, 0x02, 0x01, 0x20,
+];
+const KEY_USAGE_OID: &[u8] = &[0x55, 0x1d, 0x0f];
+const BASIC_CONSTRAINTS_OID: &[u8] = &[0x55, 0x1d, 0x13];
+const KEY_ATTESTATION_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x01, 0x11];
+
+// Key usage bits, in the first byte of the bit string.
+const DIGITAL_SIGNATURE: u8 = 0x80;
+const KEY_CERT_SIGN: u8 = 0x04;
+
+/// Length of each of `r` and `s` in an ES256 signature.
+const P256_SCALAR_LEN: usize = 32;
+/// Length of each of `r` and `s` in an ES384 signature.
+const P384_SCALAR_LEN: usize = 48;
+
+/// Where Keystore keeps a key, from the attestation extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SecurityLevel {
+    /// In the Android system, with no hardware protection.
+    Software,
+    /// In a trusted execution environment.
+    TrustedEnvironment,
+    /// In a dedicated secure element.
+    StrongBox,
+}
+
+impl SecurityLevel {
+    /// Returns the level of `value`, as encoded in the attestation extension.
+    pub fn from_value(value: i64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Software),
+            1 => Some(Self::TrustedEnvironment),
+            2 => Some(Self::StrongBox),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the level, as encoded in the attestation extension.
+    pub fn value(self) -> i64 {
+        match self {
+            Self::Software => 0,
+            Self::TrustedEnvironment => 1,
+            Self::StrongBox => 2,
+        }
+    }
+
+    /// Returns whether the key is protected by hardware.
+    pub fn is_hardware(self) -> bool {
+        self != Self::Software
+    }
+}
+
+/// Why an attestation chain was rejected. Indices count certificates from the leaf.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum AttestationError {
+    /// The chain is empty or longer than `MAX_CHAIN_LEN`.
+    #[error("chain of {0} certificates")]
+    ChainLength(usize),
+    /// A certificate is not valid DER or not the expected X.509 structure.
+    #[error("malformed certificate {index}: {reason}")]
+    Malformed {
+        /// Index of the certificate.
+        index: usize,
+        /// What is wrong with it.
+        reason: &'static str,
+    },
+    /// The root is not pinned.
+    #[error("untrusted root")]
+    UntrustedRoot,
+    /// The issuer of a certificate is not the subject of the next one.
+    #[error("certificate {0} is not issued by the next one")]
+    IssuerMismatch(usize),
+    /// A certificate uses a signature or key algorithm not supported here.
+    #[error("certificate {0} uses an unsupported algorithm")]
+    UnsupportedAlgorithm(usize),
+    /// The signature of a certificate doesn't verify.
+    #[error("bad signature on certificate {0}")]
+    BadSignature(usize),
+    /// A certificate is not valid yet.
+    #[error("certificate {0} is not valid yet")]
+    NotYetValid(usize),
+    /// A certificate has expired.
+    #[error("certificate {0} has expired")]
+    Expired(usize),
+    /// A certificate may not be used for what the chain uses it for.
+    #[error("key usage of certificate {0} forbids its use")]
+    KeyUsage(usize),
+    /// A certificate has a critical extension not understood here.
+    #[error("certificate {0} has an unknown critical extension")]
+    UnknownCriticalExtension(usize),
+    /// The leaf has no attestation extension.
+    #[error("leaf has no attestation extension")]
+    MissingAttestation,
+    /// The attestation is for another challenge.
+    #[error("attestation challenge mismatch")]
+    ChallengeMismatch,
+    /// Policy requires a hardware-backed key.
+    #[error("key is not hardware-backed: {0:?}")]
+    NotHardwareBacked(SecurityLevel),
+}
+
+/// Builds signature verifiers from the public keys of a chain.
+pub trait KeyVerifiers {
+    /// Returns a verifier for `public_key`, the subject public key of a certificate for
+    /// `algorithm`, or None if the key is not supported.
+    fn verifier(&self, algorithm: Algorithm, public_key: &[u8]) -> Option<Box<dyn Verifier>>;
+}
+
+/// What chains are accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttestationPolicy {
+    /// SHA-256 of the DER SubjectPublicKeyInfo of each trusted root.
+    pub pinned_roots: Vec<[u8; HASH_LEN]>,
+    /// Whether both the attestation and the key must be in a TEE or StrongBox.
+    pub require_hardware: bool,
+}
+
+/// The attested key of a validated chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    /// Algorithm of the key.
+    pub algorithm: Algorithm,
+    /// Subject public key of the leaf, e.g. an uncompressed P-256 point, or a DER
+    /// `RSAPublicKey`.
+    pub public_key: Vec<u8>,
+    /// Version of the attestation format.
+    pub attestation_version: i64,
+    /// Where the attestation was generated.
+    pub attestation_security_level: SecurityLevel,
+    /// Where the key is kept.
+    pub key_security_level: SecurityLevel,
+    /// Unique id of the device, if the app was allowed to attest it, or empty.
+    pub unique_id: Vec<u8>,
+}
+
+/// Reads DER elements one at a time. Errors are the reason the structure is malformed.
+struct Der<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.bytes.first().copied()
+    }
+
+    /// Returns the tag, the content and the whole encoding of the next element.
+    fn read(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), &'static str> {
+        let (&tag, rest) = self.bytes.split_first().ok_or("truncated")?;
+        if tag & 0x1f == 0x1f {
+            return Err("unsupported tag");
+        }
+        let (&first, mut rest) = rest.split_first().ok_or("truncated")?;
+        let len = match first {
+            0..=0x7f => usize::from(first),
+            0x81..=0x84 => {
+                let count = usize::from(first & 0x7f);
+                if rest.len() < count {
+                    return Err("truncated");
+                }
+                let (len, after) = rest.split_at(count);
+                rest = after;
+                len.iter().fold(0, |len, &byte| len << 8 | usize::from(byte))
+            }
+            _ => return Err("unsupported length"),
+        };
+        if rest.len() < len {
+            return Err("truncated");
+        }
+        let header_len = self.bytes.len() - rest.len();
+        let (element, after) = self.bytes.split_at(header_len + len);
+        self.bytes = after;
+        Ok((tag, &element[header_len..], element))
+    }
+
+    /// Returns the content of the next element, which must have tag `tag`.
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], &'static str> {
+        match self.read()? {
+            (actual, content, _) if actual == tag => Ok(content),
+            _ => Err("unexpected tag"),
+        }
+    }
+
+    /// Returns the content of the next element if it has tag `tag`.
+    fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, &'static str> {
+        if self.peek_tag() == Some(tag) {
+            self.expect(tag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn small_int(content: &[u8]) -> Result<i64, &'static str> {
+    if content.is_empty() || content.len() > 8 {
+        return Err("bad integer");
+    }
+    let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+    Ok(content.iter().fold(sign, |value, &byte| value << 8 | i64::from(byte)))
+}
+
+/// Returns the bits of a bit string with no unused bits.
+fn whole_bits(content: &[u8]) -> Result<&[u8], &'static str> {
+    match content.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err("bad bit string"),
+    }
+}
+
+fn digits(bytes: &[u8]) -> Result<i64, &'static str> {
+    bytes.iter().try_fold(0, |value, &byte| match byte {
+        b'0'..=b'9' => Ok(value * 10 + i64::from(byte - b'0')),
+        _ => Err("bad time"),
+    })
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parses a UTCTime or GeneralizedTime in the `Z` form X.509 requires. Times before 1970 are
+/// clamped to it.
+fn parse_time(tag: u8, content: &[u8]) -> Result<SystemTime, &'static str> {
+    let (year, rest) = match (tag, content.len()) {
+        (UTC_TIME, 13) => match digits(&content[..2])? {
+            year @ 0..=49 => (2000 + year, &content[2..]),
+            year => (1900 + year, &content[2..]),
+        },
+        (GENERALIZED_TIME, 15) => (digits(&content[..4])?, &content[4..]),
+        _ => return Err("bad time"),
+    };
+    if rest[10] != b'Z' {
+        return Err("bad time");
+    }
+    let [month, day, hour, minute, second] =
+        [0, 2, 4, 6, 8].map(|start| digits(&rest[start..start + 2]));
+    let (month, day, hour, minute, second) = (month?, day?, hour?, minute?, second?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err("bad time");
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+fn parse_signature_algorithm(content: &[u8]) -> Result<Option<Algorithm>, &'static str> {
+    let mut identifier = Der::new(content);
+    let algorithm = match identifier.expect(OBJECT_IDENTIFIER)? {
+        ECDSA_SHA256_OID => Some(Algorithm::Es256),
+        ECDSA_SHA384_OID => Some(Algorithm::Es384),
+        ED25519_OID => Some(Algorithm::EdDsa),
+        SHA256_WITH_RSA_OID if identifier.expect(NULL)?.is_empty() => Some(Algorithm::Rs256),
+        RSASSA_PSS_OID if identifier.expect(SEQUENCE)? == PSS_SHA256_PARAMS => {
+            Some(Algorithm::Ps256)
+        }
+        _ => None,
+    };
+    // Past the parameters of RSA, those of all are absent.
+    if algorithm.is_some() && !identifier.is_empty() {
+        return Err("unexpected parameters");
+    }
+    Ok(algorithm)
+}
+
+fn parse_key_algorithm(content: &[u8]) -> Result<Option<Algorithm>, &'static str> {
+    let mut identifier = Der::new(content);
+    Ok(match identifier.expect(OBJECT_IDENTIFIER)? {
+        EC_PUBLIC_KEY_OID => match identifier.expect(OBJECT_IDENTIFIER)? {
+            P256_OID => Some(Algorithm::Es256),
+            P384_OID => Some(Algorithm::Es384),
+            _ => None,
+        },
+        ED25519_OID if identifier.is_empty() => Some(Algorithm::EdDsa),
+        RSA_ENCRYPTION_OID if identifier.expect(NULL)?.is_empty() => Some(Algorithm::Rs256),
+        _ => None,
+    })
+}
+
+/// Converts a DER `Ecdsa-Sig-Value` to `r || s`, each `scalar_len` bytes long.
+fn ecdsa_signature(der: &[u8], scalar_len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut outer = Der::new(der);
+    let mut value = Der::new(outer.expect(SEQUENCE)?);
+    let mut signature = vec![0; 2 * scalar_len];
+    for half in signature.chunks_mut(scalar_len) {
+        let integer = value.expect(INTEGER)?;
+        let start = integer.iter().position(|&byte| byte != 0).unwrap_or(integer.len());
+        let integer = &integer[start..];
+        if integer.len() > scalar_len {
+            return Err("bad signature");
+        }
+        half[scalar_len - integer.len()..].copy_from_slice(integer);
+    }
+    if !outer.is_empty() || !value.is_empty() {
+        return Err("bad signature");
+    }
+    Ok(signature)
+}
+
+fn security_level(der: &mut Der) -> Result<SecurityLevel, &'static str> {
+    SecurityLevel::from_value(small_int(der.expect(ENUMERATED)?)?).ok_or("bad security level")
+}
+
+/// The `KeyDescription` of the attestation extension, up to the authorization lists.
+struct KeyDescription {
+    attestation_version: i64,
+    attestation_security_level: SecurityLevel,
+    key_security_level: SecurityLevel,
+    challenge: Vec<u8>,
+    unique_id: Vec<u8>,
+}
+
+impl KeyDescription {
+    fn parse(content: &[u8]) -> Result<Self, &'static str> {
+        let mut outer = Der::new(content);
+        let mut description = Der::new(outer.expect(SEQUENCE)?);
+        let attestation_version = small_int(description.expect(INTEGER)?)?;
+        let attestation_security_level = security_level(&mut description)?;
+        description.expect(INTEGER)?;
+        let key_security_level = security_level(&mut description)?;
+        Ok(Self {
+            attestation_version,
+            attestation_security_level,
+            key_security_level,
+            challenge: description.expect(OCTET_STRING)?.to_vec(),
+            unique_id: description.expect(OCTET_STRING)?.to_vec(),
+        })
+    }
+}
+
+/// The parts of an X.509 certificate checked here.
+struct Certificate<'a> {
+    tbs: &'a [u8],
+    signature_algorithm: Option<Algorithm>,
+    signature: &'a [u8],
+    issuer: &'a [u8],
+    subject: &'a [u8],
+    not_before: SystemTime,
+    not_after: SystemTime,
+    subject_public_key_info: &'a [u8],
+    key_algorithm: Option<Algorithm>,
+    public_key: &'a [u8],
+    key_usage: Option<u8>,
+    ca: bool,
+    path_len: Option<i64>,
+    key_description: Option<KeyDescription>,
+    unknown_critical_extension: bool,
+}
+
+impl<'a> Certificate<'a> {
+    fn parse(der: &'a [u8]) -> Result<Self, &'static str> {
+        let mut outer = Der::new(der);
+        let mut certificate = Der::new(outer.expect(SEQUENCE)?);
+        let (tag, tbs_content, tbs) = certificate.read()?;
+        if tag != SEQUENCE {
+            return Err("unexpected tag");
+        }
+        let signature_algorithm = parse_signature_algorithm(certificate.expect(SEQUENCE)?)?;
+        let signature = whole_bits(certificate.expect(BIT_STRING)?)?;
+        if !outer.is_empty() || !certificate.is_empty() {
+            return Err("trailing data");
+        }
+
+        let mut tbs_fields = Der::new(tbs_content);
+        tbs_fields.optional(VERSION)?;
+        tbs_fields.expect(INTEGER)?;
+        if parse_signature_algorithm(tbs_fields.expect(SEQUENCE)?)? != signature_algorithm {
+            return Err("signature algorithm mismatch");
+        }
+        let (_, _, issuer) = tbs_fields.read()?;
+        let mut validity = Der::new(tbs_fields.expect(SEQUENCE)?);
+        let (tag, content, _) = validity.read()?;
+        let not_before = parse_time(tag, content)?;
+        let (tag, content, _) = validity.read()?;
+        let not_after = parse_time(tag, content)?;
+        let (_, _, subject) = tbs_fields.read()?;
+        let (tag, key_info_content, subject_public_key_info) = tbs_fields.read()?;
+        if tag != SEQUENCE {
+            return Err("unexpected tag");
+        }
+        let mut key_info = Der::new(key_info_content);
+        let key_algorithm = parse_key_algorithm(key_info.expect(SEQUENCE)?)?;
+        let public_key = whole_bits(key_info.expect(BIT_STRING)?)?;
+        tbs_fields.optional(ISSUER_UNIQUE_ID)?;
+        tbs_fields.optional(SUBJECT_UNIQUE_ID)?;
+
+        let mut parsed = Self {
+            tbs,
+            signature_algorithm,
+            signature,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            subject_public_key_info,
+            key_algorithm,
+            public_key,
+            key_usage: None,
+            ca: false,
+            path_len: None,
+            key_description: None,
+            unknown_critical_extension: false,
+        };
+        if let Some(extensions) = tbs_fields.optional(EXTENSIONS)? {
+            let mut outer = Der::new(extensions);
+            let mut extensions = Der::new(outer.expect(SEQUENCE)?);
+            while !extensions.is_empty() {
+                let mut extension = Der::new(extensions.expect(SEQUENCE)?);
+                let id = extension.expect(OBJECT_IDENTIFIER)?;
+                let critical = extension.optional(BOOLEAN)?.is_some_and(|value| value != [0]);
+                let value = extension.expect(OCTET_STRING)?;
+                parsed.parse_extension(id, critical, value)?;
+            }
+        }
+        if !tbs_fields.is_empty() {
+            return Err("trailing data");
+        }
+        Ok(parsed)
+    }
+
+    fn parse_extension(
+        &mut self,
+        id: &[u8],
+        critical: bool,
+        value: &[u8],
+    ) -> Result<(), &'static str> {
+        let mut value = Der::new(value);
+        match id {
+            KEY_USAGE_OID => {
+                let bits = value.expect(BIT_STRING)?;
+                self.key_usage = Some(bits.get(1).copied().unwrap_or(0));
+            }
+            BASIC_CONSTRAINTS_OID => {
+                let mut constraints = Der::new(value.expect(SEQUENCE)?);
+                self.ca = constraints.optional(BOOLEAN)?.is_some_and(|value| value != [0]);
+                self.path_len = constraints.optional(INTEGER)?.map(small_int).transpose()?;
+            }
+            KEY_ATTESTATION_OID => {
+                self.key_description = Some(KeyDescription::parse(value.bytes)?);
+            }
+            _ => self.unknown_critical_extension |= critical,
+        }
+        Ok(())
+    }
+
+    /// Returns whether the key usage, if present, allows all of `usage`.
+    fn allows(&self, usage: u8) -> bool {
+        self.key_usage.is_none_or(|key_usage| key_usage & usage == usage)
+    }
+}
+
+/// Splits `concatenated` DER certificates, as sent by remote devices, into a chain.
+pub fn split_chain(concatenated: &[u8]) -> Result<Vec<Vec<u8>>, AttestationError> {
+    let mut certificates = Der::new(concatenated);
+    let mut chain = Vec::new();
+    while !certificates.is_empty() {
+        if chain.len() == MAX_CHAIN_LEN {
+            return Err(AttestationError::ChainLength(chain.len() + 1));
+        }
+        let index = chain.len();
+        let (tag, _, certificate) =
+            certificates.read().map_err(|reason| AttestationError::Malformed { index, reason })?;
+        if tag != SEQUENCE {
+            return Err(AttestationError::Malformed { index, reason: "unexpected tag" });
+        }
+        chain.push(certificate.to_vec());
+    }
+    Ok(chain)
+}
+
+/// Validates `chain`, DER certificates from the leaf to the root, at `now`, for `challenge`.
+///
+/// On success, returns the attested key of the leaf.
+pub fn validate_chain(
+    chain: &[Vec<u8>],
+    challenge: &[u8],
+    policy: &AttestationPolicy,
+    verifiers: &dyn KeyVerifiers,
+    now: SystemTime,
+) -> Result<Attestation, AttestationError> {
+    if chain.is_empty() || chain.len() > MAX_CHAIN_LEN {
+        return Err(AttestationError::ChainLength(chain.len()));
+    }
+    let certificates = chain
+        .iter()
+        .enumerate()
+        .map(|(index, der)| {
+            Certificate::parse(der).map_err(|reason| AttestationError::Malformed { index, reason })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let root = &certificates[certificates.len() - 1];
+    let root_hash = sha256(root.subject_public_key_info);
+    if !policy.pinned_roots.iter().any(|pinned| constant_time_eq(pinned, &root_hash)) {
+        return Err(AttestationError::UntrustedRoot);
+    }
+
+    for (index, certificate) in certificates.iter().enumerate() {
+        // The root signs itself.
+        let issuer = certificates.get(index + 1).unwrap_or(certificate);
+        if certificate.issuer != issuer.subject {
+            return Err(AttestationError::IssuerMismatch(index));
+        }
+        if certificate.unknown_critical_extension {
+            return Err(AttestationError::UnknownCriticalExtension(index));
+        }
+        if now < certificate.not_before {
+            return Err(AttestationError::NotYetValid(index));
+        }
+        if now > certificate.not_after {
+            return Err(AttestationError::Expired(index));
+        }
+        // Issuers must be CAs, with no more CAs below them than their path length allows.
+        if index > 0 {
+            let below = index as i64 - 1;
+            if !certificate.ca
+                || !certificate.allows(KEY_CERT_SIGN)
+                || certificate.path_len.is_some_and(|path_len| below > path_len)
+            {
+                return Err(AttestationError::KeyUsage(index));
+            }
+        }
+
+        let (Some(algorithm), Some(key_algorithm)) =
+            (certificate.signature_algorithm, issuer.key_algorithm)
+        else {
+            return Err(AttestationError::UnsupportedAlgorithm(index));
+        };
+        // RSA keys sign with either padding.
+        let key_algorithm = match (key_algorithm, algorithm) {
+            (Algorithm::Rs256, Algorithm::Ps256) => Algorithm::Ps256,
+            (key_algorithm, _) => key_algorithm,
+        };
+        let verifier = verifiers
+            .verifier(key_algorithm, issuer.public_key)
+            .ok_or(AttestationError::UnsupportedAlgorithm(index))?;
+        let signature = match algorithm {
+            Algorithm::Es256 => ecdsa_signature(certificate.signature, P256_SCALAR_LEN),
+            Algorithm::Es384 => ecdsa_signature(certificate.signature, P384_SCALAR_LEN),
+            _ => Ok(certificate.signature.to_vec()),
+        }
+        .map_err(|reason| AttestationError::Malformed { index, reason })?;
+        if verifier.algorithm() != algorithm || !verifier.verify(certificate.tbs, &signature) {
+            return Err(AttestationError::BadSignature(index));
+        }
+    }
+
+    let leaf = &certificates[0];
+    if !leaf.allows(DIGITAL_SIGNATURE) {
+        return Err(AttestationError::KeyUsage(0));
+    }
+    let description = leaf.key_description.as_ref().ok_or(AttestationError::MissingAttestation)?;
+    if !constant_time_eq(&description.challenge, challenge) {
+        return Err(AttestationError::ChallengeMismatch);
+    }
+    if policy.require_hardware {
+        let level = description.attestation_security_level.min(description.key_security_level);
+        if !level.is_hardware() {
+            return Err(AttestationError::NotHardwareBacked(level));
+        }
+    }
+    Ok(Attestation {
+        algorithm: leaf.key_algorithm.ok_or(AttestationError::UnsupportedAlgorithm(0))?,
+        public_key: leaf.public_key.to_vec(),
+        attestation_version: description.attestation_version,
+        attestation_security_level: description.attestation_security_level,
+        key_security_level: description.key_security_level,
+        unique_id: description.unique_id.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32c::crc32c;
+
+    const CHALLENGE: &[u8] = b"enrollment challenge";
+
+    // Not cryptography: checksums keyed with a byte stand in for the keys.
+    struct FakeKey(u8);
+
+    impl FakeKey {
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            crc32c(&[&[self.0], data]).to_be_bytes().to_vec()
+        }
+    }
+
+    impl Verifier for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::EdDsa
+        }
+
+        fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+            self.sign(data) == signature
+        }
+    }
+
+    struct FakeVerifiers;
+
+    impl KeyVerifiers for FakeVerifiers {
+        fn verifier(&self, algorithm: Algorithm, public_key: &[u8]) -> Option<Box<dyn Verifier>> {
+            match (algorithm, public_key) {
+                (Algorithm::EdDsa, &[key]) => Some(Box::new(FakeKey(key))),
+                _ => None,
+            }
+        }
+    }
+
+    fn der(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+        let content = parts.concat();
+        let mut encoded = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => encoded.push(len as u8),
+            len @ 0x80..=0xff => encoded.extend_from_slice(&[0x81, len as u8]),
+            len => encoded.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        encoded.extend_from_slice(&content);
+        encoded
+    }
+
+    fn name(common_name: &str) -> Vec<u8> {
+        let attribute = der(
+            SEQUENCE,
+            &[
+                &der(OBJECT_IDENTIFIER, &[&[0x55, 0x04, 0x03]]),
+                &der(0x0c, &[common_name.as_bytes()]),
+            ],
+        );
+        der(SEQUENCE, &[&der(0x31, &[&attribute])])
+    }
+
+    fn key_info(key: u8) -> Vec<u8> {
+        der(
+            SEQUENCE,
+            &[
+                &der(SEQUENCE, &[&der(OBJECT_IDENTIFIER, &[ED25519_OID])]),
+                &der(BIT_STRING, &[&[0, key]]),
+            ],
+        )
+    }
+
+    fn extension(id: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+        let critical = if critical { der(BOOLEAN, &[&[0xff]]) } else { vec![] };
+        der(SEQUENCE, &[&der(OBJECT_IDENTIFIER, &[id]), &critical, &der(OCTET_STRING, &[value])])
+    }
+
+    fn key_description(level: u8, challenge: &[u8]) -> Vec<u8> {
+        let level = der(ENUMERATED, &[&[level]]);
+        let authorizations = der(SEQUENCE, &[]);
+        der(
+            SEQUENCE,
+            &[
+                &der(INTEGER, &[&[100]]),
+                &level,
+                &der(INTEGER, &[&[100]]),
+                &level,
+                &der(OCTET_STRING, &[challenge]),
+                &der(OCTET_STRING, &[]),
+                &authorizations,
+                &authorizations,
+            ],
+        )
+    }
+
+    struct Spec {
+        issuer: &'static str,
+        subject: &'static str,
+        key: u8,
+        signer: u8,
+        not_after: &'static str,
+        extensions: Vec<Vec<u8>>,
+    }
+
+    impl Spec {
+        fn ca(issuer: &'static str, subject: &'static str, key: u8, signer: u8) -> Self {
+            let constraints = der(SEQUENCE, &[&der(BOOLEAN, &[&[0xff]])]);
+            let key_usage = der(BIT_STRING, &[&[1, KEY_CERT_SIGN]]);
+            Self {
+                issuer,
+                subject,
+                key,
+                signer,
+                not_after: "20500101000000Z",
+                extensions: vec![
+                    extension(BASIC_CONSTRAINTS_OID, true, &constraints),
+                    extension(KEY_USAGE_OID, true, &key_usage),
+                ],
+            }
+        }
+
+        fn leaf(level: u8, challenge: &[u8]) -> Self {
+            let key_usage = der(BIT_STRING, &[&[7, DIGITAL_SIGNATURE]]);
+            Self {
+                issuer: "intermediate",
+                subject: "Android Keystore Key",
+                key: 3,
+                signer: 2,
+                not_after: "20500101000000Z",
+                extensions: vec![
+                    extension(KEY_USAGE_OID, true, &key_usage),
+                    extension(KEY_ATTESTATION_OID, false, &key_description(level, challenge)),
+                ],
+            }
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            let algorithm = der(SEQUENCE, &[&der(OBJECT_IDENTIFIER, &[ED25519_OID])]);
+            let validity = der(
+                SEQUENCE,
+                &[
+                    &der(UTC_TIME, &[b"200101000000Z"]),
+                    &der(GENERALIZED_TIME, &[self.not_after.as_bytes()]),
+                ],
+            );
+            let tbs = der(
+                SEQUENCE,
+                &[
+                    &der(VERSION, &[&der(INTEGER, &[&[2]])]),
+                    &der(INTEGER, &[&[self.key]]),
+                    &algorithm,
+                    &name(self.issuer),
+                    &validity,
+                    &name(self.subject),
+                    &key_info(self.key),
+                    &der(EXTENSIONS, &[&der(SEQUENCE, &[&self.extensions.concat()])]),
+                ],
+            );
+            let signature = FakeKey(self.signer).sign(&tbs);
+            der(SEQUENCE, &[&tbs, &algorithm, &der(BIT_STRING, &[&[0], &signature])])
+        }
+    }
+
+    fn chain(leaf: Spec) -> Vec<Vec<u8>> {
+        vec![
+            leaf.encode(),
+            Spec::ca("root", "intermediate", 2, 1).encode(),
+            Spec::ca("root", "root", 1, 1).encode(),
+        ]
+    }
+
+    fn policy() -> AttestationPolicy {
+        AttestationPolicy { pinned_roots: vec![sha256(&key_info(1))], require_hardware: true }
+    }
+
+    fn validate(
+        chain: &[Vec<u8>],
+        policy: &AttestationPolicy,
+    ) -> Result<Attestation, AttestationError> {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        validate_chain(chain, CHALLENGE, policy, &FakeVerifiers, now)
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time(UTC_TIME, b"240101000000Z"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_704_067_200))
+        );
+        assert_eq!(
+            parse_time(GENERALIZED_TIME, b"20240229123456Z"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_709_210_096))
+        );
+        assert_eq!(parse_time(UTC_TIME, b"690101000000Z"), Ok(UNIX_EPOCH));
+        assert!(parse_time(UTC_TIME, b"241301000000Z").is_err());
+        assert!(parse_time(UTC_TIME, b"2401010000+0100").is_err());
+    }
+
+    #[test]
+    fn test_ecdsa_signature() {
+        let encoded = der(SEQUENCE, &[&der(INTEGER, &[&[0, 0x80]]), &der(INTEGER, &[&[1; 32]])]);
+        let signature = ecdsa_signature(&encoded, P256_SCALAR_LEN).unwrap();
+        assert_eq!(signature[..32], [[0; 31].as_slice(), &[0x80]].concat());
+        assert_eq!(signature[32..], [1; 32]);
+        assert!(ecdsa_signature(&encoded[..encoded.len() - 1], P256_SCALAR_LEN).is_err());
+
+        let signature = ecdsa_signature(&encoded, P384_SCALAR_LEN).unwrap();
+        assert_eq!(signature[..48], [[0; 47].as_slice(), &[0x80]].concat());
+        assert_eq!(signature[48..], [[0; 16].as_slice(), &[1; 32]].concat());
+        let long = der(SEQUENCE, &[&der(INTEGER, &[&[1; 33]]), &der(INTEGER, &[&[1]])]);
+        assert!(ecdsa_signature(&long, P256_SCALAR_LEN).is_err());
+    }
+
+    #[test]
+    fn test_validate_chain() {
+        let attestation = validate(&chain(Spec::leaf(2, CHALLENGE)), &policy()).unwrap();
+        assert_eq!(attestation.algorithm, Algorithm::EdDsa);
+        assert_eq!(attestation.public_key, [3]);
+        assert_eq!(attestation.attestation_version, 100);
+        assert_eq!(attestation.key_security_level, SecurityLevel::StrongBox);
+    }
+
+    #[test]
+    fn test_split_chain() {
+        let chain = chain(Spec::leaf(1, CHALLENGE));
+        assert_eq!(split_chain(&chain.concat()), Ok(chain.clone()));
+        assert_eq!(split_chain(&[]), Ok(vec![]));
+        let truncated = chain.concat();
+        assert!(matches!(
+            split_chain(&truncated[..truncated.len() - 1]),
+            Err(AttestationError::Malformed { index: 2, .. })
+        ));
+        assert_eq!(
+            split_chain(&chain.concat().repeat(3)),
+            Err(AttestationError::ChainLength(MAX_CHAIN_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn test_reject() {
+        let err = |chain: &[Vec<u8>]| validate(chain, &policy()).unwrap_err();
+
+        assert_eq!(err(&[]), AttestationError::ChainLength(0));
+        assert_eq!(
+            validate(&chain(Spec::leaf(2, CHALLENGE)), &AttestationPolicy::default()),
+            Err(AttestationError::UntrustedRoot)
+        );
+        assert_eq!(err(&chain(Spec::leaf(2, b"other"))), AttestationError::ChallengeMismatch);
+        assert_eq!(
+            err(&chain(Spec::leaf(0, CHALLENGE))),
+            AttestationError::NotHardwareBacked(SecurityLevel::Software)
+        );
+        let software = AttestationPolicy { require_hardware: false, ..policy() };
+        assert!(validate(&chain(Spec::leaf(0, CHALLENGE)), &software).is_ok());
+
+        let mut forged = chain(Spec::leaf(1, CHALLENGE));
+        forged[0] = Spec { signer: 4, ..Spec::leaf(1, CHALLENGE) }.encode();
+        assert_eq!(err(&forged), AttestationError::BadSignature(0));
+        let expired = Spec { not_after: "20230101000000Z", ..Spec::leaf(1, CHALLENGE) };
+        assert_eq!(err(&chain(expired)), AttestationError::Expired(0));
+        let mut unknown = Spec::leaf(1, CHALLENGE);
+        unknown.extensions.push(extension(&[0x2a, 0x03], true, &[]));
+        assert_eq!(err(&chain(unknown)), AttestationError::UnknownCriticalExtension(0));
+        let mut missing = Spec::leaf(1, CHALLENGE);
+        missing.extensions.pop();
+        assert_eq!(err(&chain(missing)), AttestationError::MissingAttestation);
+
+        // The intermediate is not a CA.
+        let mut not_ca = chain(Spec::leaf(1, CHALLENGE));
+        not_ca[1] = Spec { extensions: vec![], ..Spec::ca("root", "intermediate", 2, 1) }.encode();
+        assert_eq!(err(&not_ca), AttestationError::KeyUsage(1));
+        // The chain skips the intermediate.
+        let mut skipped = chain(Spec::leaf(1, CHALLENGE));
+        skipped.remove(1);
+        assert_eq!(err(&skipped), AttestationError::IssuerMismatch(0));
+        let mut truncated = chain(Spec::leaf(1, CHALLENGE));
+        truncated[0].pop();
+        assert!(matches!(err(&truncated), AttestationError::Malformed { index: 0, .. }));
+    }
+}
@@ -0,0 +1,403 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Append-only audit log of the security-relevant events, for security bug reports.
+//!
+//! Enrollments, revocations, failed verifications of remote devices and pin mismatches are each
+//! appended as an entry, numbered in order. Entries are chained: the hash of each is an
+//! HMAC-SHA256 covering the hash of the entry before it, so that editing, dropping or reordering
+//! entries breaks the chain from there on, see `AuditLog::verify_chain`. The HMAC key is drawn at
+//! random when the log is created and never leaves the process: whoever alters a dump can't
+//! recompute the chain, but only the log itself can verify it, and dropping the newest entries
+//! goes unnoticed unless a `head` kept apart is compared. The log keeps its `capacity` newest
+//! entries; the oldest kept then chains to the hash of the last one evicted, which stays as the
+//! anchor of the chain.
+//!
+//! The components logging the events record into `audit_log` unless given another log, and Java
+//! dumps it through JNI, see `native_dump_audit_log`.
+
+use crate::device_identity::DeviceId;
+use crate::kdf::{hmac_sha256, HASH_LEN};
+use crate::rng::{OsRng, SecureRng};
+use crate::secret::Secret;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Entries kept by default.
+pub const DEFAULT_AUDIT_CAPACITY: usize = 256;
+/// Longest detail of an entry kept, in bytes: longer ones are truncated.
+pub const MAX_AUDIT_DETAIL_LEN: usize = 256;
+
+// Prefixed to each entry before hashing, so that the HMAC can't be of anything else.
+const CHAIN_CONTEXT: &[u8] = b"remoteauth audit v1";
+// Hash the first entry ever appended chains to.
+const GENESIS: [u8; HASH_LEN] = [0; HASH_LEN];
+
+static AUDIT_LOG: OnceLock<Arc<AuditLog>> = OnceLock::new();
+
+/// Why an audit log can't be created, or entries don't form a chain.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum AuditError {
+    /// The entry doesn't follow the one before it, or its hash is not of its content.
+    #[error("audit entry {0} breaks the chain")]
+    Broken(u64),
+    /// No randomness for the key of the chain.
+    #[error("no randomness for the audit key")]
+    Random,
+}
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEventKind {
+    /// A device was enrolled.
+    Enrolled,
+    /// The enrollment of a device was revoked.
+    Revoked,
+    /// A device failed to prove its identity or presence, e.g. a bad attestation or signature.
+    VerificationFailed,
+    /// A device presented an identity other than the pinned one.
+    PinMismatch,
+}
+
+impl AuditEventKind {
+    /// Returns the id of the kind, in the hash of the entries.
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Enrolled => 1,
+            Self::Revoked => 2,
+            Self::VerificationFailed => 3,
+            Self::PinMismatch => 4,
+        }
+    }
+
+    /// Returns the name of the kind, in dumps.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Enrolled => "enrolled",
+            Self::Revoked => "revoked",
+            Self::VerificationFailed => "verification_failed",
+            Self::PinMismatch => "pin_mismatch",
+        }
+    }
+}
+
+/// An event of the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Number of the entry, from 0.
+    pub sequence: u64,
+    /// When it happened.
+    pub at: SystemTime,
+    /// What happened.
+    pub kind: AuditEventKind,
+    /// Device it happened to, if known.
+    pub device_id: Option<DeviceId>,
+    /// What else is worth knowing, e.g. why a verification failed.
+    pub detail: String,
+    /// Hash of the entry before it.
+    pub previous: [u8; HASH_LEN],
+    /// Hash of the entry, chained to `previous`.
+    pub hash: [u8; HASH_LEN],
+}
+
+impl AuditEntry {
+    /// Returns the HMAC under `key` of the content of the entry, chained to `previous`.
+    fn compute_hash(&self, key: &[u8]) -> [u8; HASH_LEN] {
+        let device_id = self.device_id.as_ref().map_or("", DeviceId::as_str);
+        // Ids and details are short, see `MAX_AUDIT_DETAIL_LEN`.
+        let (device_id_len, detail_len) =
+            ((device_id.len() as u32).to_be_bytes(), (self.detail.len() as u32).to_be_bytes());
+        hmac_sha256(
+            key,
+            &[
+                CHAIN_CONTEXT,
+                &self.previous,
+                &self.sequence.to_be_bytes(),
+                &millis(self.at).to_be_bytes(),
+                &[self.kind.id()],
+                &device_id_len,
+                device_id.as_bytes(),
+                &detail_len,
+                self.detail.as_bytes(),
+            ],
+        )
+    }
+}
+
+struct Chain {
+    entries: VecDeque<AuditEntry>,
+    next_sequence: u64,
+    /// Hash of the last entry appended.
+    head: [u8; HASH_LEN],
+}
+
+/// Audit log of the security-relevant events, bounded to its newest entries.
+pub struct AuditLog {
+    key: Secret<[u8; HASH_LEN]>,
+    chain: Mutex<Chain>,
+    capacity: usize,
+}
+
+impl AuditLog {
+    /// Creates an empty log keeping `DEFAULT_AUDIT_CAPACITY` entries, chained under a fresh
+    /// random key from `OsRng`.
+    pub fn new() -> Result<Self, AuditError> {
+        Self::with_rng(DEFAULT_AUDIT_CAPACITY, &OsRng)
+    }
+
+    /// Creates an empty log keeping `capacity` entries, at least one, chained under a key drawn
+    /// from `rng`.
+    pub fn with_rng(capacity: usize, rng: &dyn SecureRng) -> Result<Self, AuditError> {
+        let mut key = Secret::new([0; HASH_LEN]);
+        rng.fill(key.expose_mut()).map_err(|_| AuditError::Random)?;
+        let chain = Chain { entries: VecDeque::new(), next_sequence: 0, head: GENESIS };
+        Ok(Self { key, chain: Mutex::new(chain), capacity: capacity.max(1) })
+    }
+
+    /// Appends an event of `kind` of `device_id` that happened now, and returns its sequence
+    /// number. `detail` is truncated to `MAX_AUDIT_DETAIL_LEN` bytes.
+    pub fn record(&self, kind: AuditEventKind, device_id: Option<&DeviceId>, detail: &str) -> u64 {
+        self.record_at(kind, device_id, detail, SystemTime::now())
+    }
+
+    fn record_at(
+        &self,
+        kind: AuditEventKind,
+        device_id: Option<&DeviceId>,
+        detail: &str,
+        at: SystemTime,
+    ) -> u64 {
+        let mut end = detail.len().min(MAX_AUDIT_DETAIL_LEN);
+        while !detail.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut chain = self.chain.lock().unwrap();
+        let mut entry = AuditEntry {
+            sequence: chain.next_sequence,
+            at,
+            kind,
+            device_id: device_id.cloned(),
+            detail: detail[..end].to_string(),
+            previous: chain.head,
+            hash: GENESIS,
+        };
+        entry.hash = entry.compute_hash(self.key.expose());
+        chain.head = entry.hash;
+        chain.next_sequence += 1;
+        if chain.entries.len() == self.capacity {
+            chain.entries.pop_front();
+        }
+        chain.entries.push_back(entry);
+        chain.next_sequence - 1
+    }
+
+    /// Returns the entries kept, oldest first.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.chain.lock().unwrap().entries.iter().cloned().collect()
+    }
+
+    /// Returns the entries kept numbered `sequence` or later, oldest first.
+    pub fn since(&self, sequence: u64) -> Vec<AuditEntry> {
+        let chain = self.chain.lock().unwrap();
+        chain.entries.iter().filter(|entry| entry.sequence >= sequence).cloned().collect()
+    }
+
+    /// Returns the hash of the last entry appended, which vouches for every entry before it.
+    pub fn head(&self) -> [u8; HASH_LEN] {
+        self.chain.lock().unwrap().head
+    }
+
+    /// Checks that `entries` chain, in order, each to the hash of the one before it, under the
+    /// key of this log. The first entry is taken as is: only a hash of it kept apart, e.g. a
+    /// former `head`, vouches for it.
+    pub fn verify_chain(&self, entries: &[AuditEntry]) -> Result<(), AuditError> {
+        let mut previous = None;
+        for entry in entries {
+            let follows = match previous {
+                None => true,
+                Some((sequence, hash)) => entry.sequence == sequence + 1 && entry.previous == hash,
+            };
+            if !follows || entry.compute_hash(self.key.expose()) != entry.hash {
+                return Err(AuditError::Broken(entry.sequence));
+            }
+            previous = Some((entry.sequence, entry.hash));
+        }
+        Ok(())
+    }
+
+    /// Checks the chain of the entries kept, see `verify_chain`.
+    pub fn verify(&self) -> Result<(), AuditError> {
+        self.verify_chain(&self.entries())
+    }
+
+    /// Returns the entries kept numbered `sequence` or later as text, one per line after a
+    /// header with the head of the chain and whether it verifies, for a bug report.
+    pub fn dump(&self, sequence: u64) -> String {
+        let entries = self.entries();
+        let status = match self.verify_chain(&entries) {
+            Ok(()) => "verified".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let mut dump = format!(
+            "audit log: {} entries kept, head {}, {}\n",
+            entries.len(),
+            hex(&self.head()),
+            status
+        );
+        for entry in entries.iter().filter(|entry| entry.sequence >= sequence) {
+            // Writing to a String doesn't fail.
+            let _ = writeln!(
+                dump,
+                "{} {} {} {} {:?} {}",
+                entry.sequence,
+                millis(entry.at),
+                entry.kind.name(),
+                entry.device_id.as_ref().map_or("-", DeviceId::as_str),
+                entry.detail,
+                hex(&entry.hash)
+            );
+        }
+        dump
+    }
+}
+
+/// Returns the audit log Java dumps, created on first use.
+pub fn audit_log() -> Result<&'static Arc<AuditLog>, AuditError> {
+    if let Some(log) = AUDIT_LOG.get() {
+        return Ok(log);
+    }
+    let log = Arc::new(AuditLog::new()?);
+    Ok(AUDIT_LOG.get_or_init(|| log))
+}
+
+fn millis(at: SystemTime) -> u64 {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RngError;
+
+    // Fills with its byte, so that logs chain under the same key.
+    struct FixedRng(u8);
+
+    impl SecureRng for FixedRng {
+        fn fill(&self, buf: &mut [u8]) -> Result<(), RngError> {
+            buf.fill(self.0);
+            Ok(())
+        }
+    }
+
+    struct BrokenRng;
+
+    impl SecureRng for BrokenRng {
+        fn fill(&self, _buf: &mut [u8]) -> Result<(), RngError> {
+            Err(RngError::Unavailable(5))
+        }
+    }
+
+    fn filled(capacity: usize) -> AuditLog {
+        let log = AuditLog::with_rng(capacity, &FixedRng(7)).unwrap();
+        let (watch, phone) = (DeviceId::new("watch").unwrap(), DeviceId::new("phone").unwrap());
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        log.record_at(AuditEventKind::Enrolled, Some(&watch), "StrongBox", at);
+        log.record_at(AuditEventKind::VerificationFailed, None, "bad attestation", at);
+        log.record_at(AuditEventKind::PinMismatch, Some(&phone), "connection 4", at);
+        log.record_at(AuditEventKind::Revoked, Some(&watch), "notified", at);
+        log
+    }
+
+    #[test]
+    fn test_chain() {
+        let log = filled(DEFAULT_AUDIT_CAPACITY);
+        let entries = log.entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].previous, GENESIS);
+        assert_eq!(entries[3].hash, log.head());
+        assert_eq!(log.verify(), Ok(()));
+        assert_eq!(log.since(2).iter().map(|e| e.sequence).collect::<Vec<_>>(), [2, 3]);
+
+        let mut edited = entries.clone();
+        edited[1].detail = "good attestation".to_string();
+        assert_eq!(log.verify_chain(&edited), Err(AuditError::Broken(1)));
+        let mut rehashed = edited.clone();
+        rehashed[1].hash = rehashed[1].compute_hash(log.key.expose());
+        assert_eq!(log.verify_chain(&rehashed), Err(AuditError::Broken(2)));
+        let dropped = [&entries[..1], &entries[2..]].concat();
+        assert_eq!(log.verify_chain(&dropped), Err(AuditError::Broken(2)));
+        let mut reordered = entries.clone();
+        reordered.swap(1, 2);
+        assert_eq!(log.verify_chain(&reordered), Err(AuditError::Broken(2)));
+    }
+
+    #[test]
+    fn test_keyed() {
+        let log = filled(DEFAULT_AUDIT_CAPACITY);
+        let entries = log.entries();
+        // Rehashing the whole chain after an edit takes the key.
+        let mut forged = entries.clone();
+        forged[1].detail = "good attestation".to_string();
+        let mut previous = forged[0].hash;
+        for entry in &mut forged[1..] {
+            entry.previous = previous;
+            entry.hash = entry.compute_hash(&[0; HASH_LEN]);
+            previous = entry.hash;
+        }
+        assert_eq!(log.verify_chain(&forged), Err(AuditError::Broken(1)));
+
+        let other = AuditLog::with_rng(DEFAULT_AUDIT_CAPACITY, &FixedRng(8)).unwrap();
+        assert_eq!(other.verify_chain(&entries), Err(AuditError::Broken(0)));
+        assert!(matches!(
+            AuditLog::with_rng(DEFAULT_AUDIT_CAPACITY, &BrokenRng),
+            Err(AuditError::Random)
+        ));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let log = filled(2);
+        let full = filled(DEFAULT_AUDIT_CAPACITY);
+        let entries = log.entries();
+        assert_eq!(entries.iter().map(|e| e.sequence).collect::<Vec<_>>(), [2, 3]);
+        // The oldest kept chains to the anchor, the hash of the last entry evicted.
+        assert_eq!(entries[0].previous, full.entries()[1].hash);
+        assert_eq!(log.head(), full.head());
+        assert_eq!(log.verify(), Ok(()));
+
+        let long = "x".repeat(MAX_AUDIT_DETAIL_LEN - 1) + "é";
+        let sequence = log.record(AuditEventKind::Enrolled, None, &long);
+        assert_eq!(log.since(sequence)[0].detail.len(), MAX_AUDIT_DETAIL_LEN - 1);
+    }
+
+    #[test]
+    fn test_dump() {
+        let dump = filled(DEFAULT_AUDIT_CAPACITY).dump(3);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("audit log: 4 entries kept, head "));
+        assert!(lines[0].ends_with(", verified"));
+        assert!(lines[1].starts_with("3 1700000000000 revoked watch \"notified\" "));
+    }
+}
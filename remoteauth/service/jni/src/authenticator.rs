@@ -0,0 +1,804 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Challenge-response authentication of enrolled remote authenticators.
+//!
+//! The remote device proves it is present by signing a random nonce, fresh for each attempt,
+//! with its identity key, whose public key was attested when it enrolled. `Authentication` is
+//! the state machine of one attempt: it hands out the `Challenge` to send, then checks the
+//! `ChallengeResponse` against the enrolled key, within the freshness window. `Authenticator`
+//! runs it on a connection, the platform only carrying the two messages, and `respond` answers
+//! a challenge on the side of the remote device. A device unlocking gets an unlock token once
+//! authenticated, see `unlock_token`.
+//!
+//! Unlocking also requires the remote device to be close, against relay attacks: it answers an
+//! `UnlockChallenge` with the ranging session it measured the distance in and the age of the
+//! measurement, under its signature, and responses whose measurement is older than allowed, or
+//! from another session, are rejected.
+
+use crate::attestation::KeyVerifiers;
+use crate::audit::{audit_log, AuditError, AuditEventKind, AuditLog};
+use crate::cose::Algorithm;
+use crate::device_identity::{DeviceId, LocalIdentity};
+use crate::enrollment::{EnrollmentRecord, EnrollmentRegistry};
+use crate::messages::{
+    Challenge, ChallengeResponse, DecodeError, Message, Reader, Request, TypedPlatform, Writer,
+};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::request_metadata::RequestMetadata;
+use crate::rng::{OsRng, SecureRng};
+use crate::schema::{Field, Rule, Schema};
+use crate::secret::{constant_time_eq, Secret};
+use crate::unlock_token::{UnlockToken, UnlockTokens};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Length of the nonce of a challenge.
+pub const AUTHENTICATION_NONCE_LEN: usize = 32;
+/// How long the remote device has to answer a challenge by default.
+pub const DEFAULT_FRESHNESS: Duration = Duration::from_secs(10);
+/// How old the ranging measurement of an unlock may be by default.
+pub const DEFAULT_MAX_RANGING_AGE: Duration = Duration::from_secs(2);
+/// Longest ranging session id accepted.
+pub const MAX_RANGING_SESSION_ID_LEN: usize = 32;
+
+// Prefixed to the nonce before signing, so that the signature can't be of anything else.
+const SIGNATURE_CONTEXT: &[u8] = b"remoteauth challenge v1";
+// Prefixed to the nonce and the ranging evidence of an unlock, so that neither kind of response
+// passes for the other.
+const UNLOCK_SIGNATURE_CONTEXT: &[u8] = b"remoteauth unlock v1";
+
+const UNLOCK_NONCE_RULE: Rule = Rule::Bytes { min_len: 1, max_len: 64 };
+
+/// Where an authentication stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthenticationState {
+    /// The challenge is not sent yet.
+    Idle,
+    /// Waiting for the response to the challenge.
+    Challenged,
+    /// The response was valid.
+    Authenticated,
+    /// Failed, for good.
+    Failed,
+}
+
+/// Why an authentication failed, or an event it can't handle in its state.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum AuthenticationError {
+    /// The event doesn't apply to the state of the authentication, e.g. a second response.
+    #[error("authentication is {0:?}")]
+    InvalidState(AuthenticationState),
+    /// The device has no enrollment, or it was revoked.
+    #[error("device {0} is not enrolled")]
+    NotEnrolled(DeviceId),
+    /// The enrolled key of the device is not supported.
+    #[error("unsupported key of device {0}")]
+    UnsupportedKey(DeviceId),
+    /// No random nonce could be drawn.
+    #[error("no randomness for the nonce")]
+    Random,
+    /// The response answers another challenge.
+    #[error("response to another challenge")]
+    NonceMismatch,
+    /// The response came after the freshness window.
+    #[error("response after {0:?}")]
+    Stale(Duration),
+    /// The signature of the response is not by the enrolled key.
+    #[error("bad signature")]
+    BadSignature,
+    /// The response carries no ranging evidence, which unlocking requires.
+    #[error("no ranging evidence")]
+    RangingRequired,
+    /// The ranging evidence is of another session than the one with the device.
+    #[error("ranging evidence of another session")]
+    RangingSession,
+    /// The ranging measurement is older than allowed.
+    #[error("ranging measurement of {0:?} ago")]
+    StaleRanging(Duration),
+}
+
+/// Challenge of an unlock, to be answered with ranging evidence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnlockChallenge {
+    /// Random value, fresh for each challenge.
+    pub nonce: Vec<u8>,
+}
+
+/// Last distance measurement of the remote device, bound into its response to an unlock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangingEvidence {
+    /// Id of the ranging session between the devices.
+    pub session_id: Vec<u8>,
+    /// Time between the measurement and the response, in milliseconds, so that it needs no
+    /// clock shared between the devices.
+    pub age_ms: u32,
+}
+
+/// Signed answer to an `UnlockChallenge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnlockResponse {
+    /// Nonce of the challenge answered.
+    pub nonce: Vec<u8>,
+    /// Signature of the nonce and of the evidence by the identity key, see
+    /// `unlock_signed_data`.
+    pub signature: Vec<u8>,
+    /// Last distance measurement.
+    pub ranging: RangingEvidence,
+}
+
+impl Message for UnlockChallenge {
+    const TYPE: u8 = 23;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "unlock_challenge",
+        fields: &[Field::new(1, "nonce", UNLOCK_NONCE_RULE)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.nonce);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { nonce: reader.bytes()? })
+    }
+}
+
+impl Request for UnlockChallenge {
+    type Response = UnlockResponse;
+}
+
+impl Message for UnlockResponse {
+    const TYPE: u8 = 24;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "unlock_response",
+        fields: &[
+            Field::new(1, "nonce", UNLOCK_NONCE_RULE),
+            Field::new(2, "signature", Rule::Bytes { min_len: 1, max_len: 512 }),
+            Field::new(
+                3,
+                "session_id",
+                Rule::Bytes { min_len: 1, max_len: MAX_RANGING_SESSION_ID_LEN },
+            ),
+            Field::new(4, "age_ms", Rule::U32),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.nonce);
+        writer.put_bytes(&self.signature);
+        writer.put_bytes(&self.ranging.session_id);
+        writer.put_u32(self.ranging.age_ms);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            nonce: reader.bytes()?,
+            signature: reader.bytes()?,
+            ranging: RangingEvidence { session_id: reader.bytes()?, age_ms: reader.u32()? },
+        })
+    }
+}
+
+/// Ranging evidence an unlock requires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangingPolicy {
+    /// Id of the ranging session with the device.
+    pub session_id: Vec<u8>,
+    /// How old the measurement may be.
+    pub max_age: Duration,
+}
+
+/// Returns what the remote device signs to answer a challenge of `nonce`.
+pub fn signed_data(nonce: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, nonce].concat()
+}
+
+/// Answers `challenge` as `local`, signing it with the identity key.
+pub async fn respond(
+    local: &LocalIdentity,
+    challenge: &Challenge,
+) -> anyhow::Result<ChallengeResponse> {
+    let signature = local.sign(&signed_data(&challenge.nonce)).await?;
+    Ok(ChallengeResponse { nonce: challenge.nonce.clone(), signature })
+}
+
+/// Returns what the remote device signs to answer an unlock challenge of `nonce` with
+/// `ranging`.
+pub fn unlock_signed_data(nonce: &[u8], ranging: &RangingEvidence) -> Vec<u8> {
+    // Session ids are at most `MAX_RANGING_SESSION_ID_LEN` bytes long.
+    let session_id_len = [ranging.session_id.len() as u8];
+    [
+        UNLOCK_SIGNATURE_CONTEXT,
+        nonce,
+        &session_id_len,
+        &ranging.session_id,
+        &ranging.age_ms.to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// Answers `challenge` as `local` with `ranging`, the last distance measurement, signing both
+/// with the identity key.
+pub async fn respond_unlock(
+    local: &LocalIdentity,
+    challenge: &UnlockChallenge,
+    ranging: RangingEvidence,
+) -> anyhow::Result<UnlockResponse> {
+    let signature = local.sign(&unlock_signed_data(&challenge.nonce, &ranging)).await?;
+    Ok(UnlockResponse { nonce: challenge.nonce.clone(), signature, ranging })
+}
+
+/// One authentication of an enrolled device, event by event.
+pub struct Authentication {
+    device_id: DeviceId,
+    algorithm: Algorithm,
+    public_key: Vec<u8>,
+    verifiers: Arc<dyn KeyVerifiers + Send + Sync>,
+    nonce: Secret<Vec<u8>>,
+    freshness: Duration,
+    ranging: Option<RangingPolicy>,
+    state: AuthenticationState,
+    challenged_at: Option<Instant>,
+}
+
+impl Authentication {
+    /// Creates an idle authentication of the device of `record`, challenging it with `nonce`,
+    /// and expecting its response within `freshness`. Fails with `UnsupportedKey` unless
+    /// `verifiers` supports its enrolled key.
+    pub fn new(
+        record: &EnrollmentRecord,
+        verifiers: Arc<dyn KeyVerifiers + Send + Sync>,
+        nonce: Vec<u8>,
+        freshness: Duration,
+    ) -> Result<Self, AuthenticationError> {
+        let identity = &record.identity;
+        if verifiers.verifier(identity.algorithm, &identity.public_key).is_none() {
+            return Err(AuthenticationError::UnsupportedKey(identity.device_id.clone()));
+        }
+        Ok(Self {
+            device_id: identity.device_id.clone(),
+            algorithm: identity.algorithm,
+            public_key: identity.public_key.clone(),
+            verifiers,
+            nonce: Secret::new(nonce),
+            freshness,
+            ranging: None,
+            state: AuthenticationState::Idle,
+            challenged_at: None,
+        })
+    }
+
+    /// Requires the response to carry ranging evidence `policy` accepts, as an unlock does.
+    pub fn with_ranging(mut self, policy: RangingPolicy) -> Self {
+        self.ranging = Some(policy);
+        self
+    }
+
+    /// Returns the device authenticated.
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    /// Returns where the authentication stands.
+    pub fn state(&self) -> AuthenticationState {
+        self.state
+    }
+
+    /// Returns the challenge to send, sent at `now`.
+    pub fn challenge(&mut self, now: Instant) -> Result<Challenge, AuthenticationError> {
+        if self.state != AuthenticationState::Idle {
+            return Err(AuthenticationError::InvalidState(self.state));
+        }
+        self.state = AuthenticationState::Challenged;
+        self.challenged_at = Some(now);
+        Ok(Challenge { nonce: self.nonce.expose().clone() })
+    }
+
+    /// Handles `response`, received at `now`. Any invalid response fails the authentication,
+    /// and so does any response if ranging evidence is required.
+    pub fn receive(
+        &mut self,
+        response: &ChallengeResponse,
+        now: Instant,
+    ) -> Result<(), AuthenticationError> {
+        let signed = signed_data(self.nonce.expose());
+        self.receive_signed(&response.nonce, &signed, &response.signature, None, now)
+    }
+
+    /// Handles `response` to an unlock challenge, received at `now`. Any invalid response fails
+    /// the authentication.
+    pub fn receive_unlock(
+        &mut self,
+        response: &UnlockResponse,
+        now: Instant,
+    ) -> Result<(), AuthenticationError> {
+        let signed = unlock_signed_data(self.nonce.expose(), &response.ranging);
+        self.receive_signed(
+            &response.nonce,
+            &signed,
+            &response.signature,
+            Some(&response.ranging),
+            now,
+        )
+    }
+
+    fn receive_signed(
+        &mut self,
+        nonce: &[u8],
+        signed: &[u8],
+        signature: &[u8],
+        ranging: Option<&RangingEvidence>,
+        now: Instant,
+    ) -> Result<(), AuthenticationError> {
+        let (AuthenticationState::Challenged, Some(challenged_at)) =
+            (self.state, self.challenged_at)
+        else {
+            return Err(AuthenticationError::InvalidState(self.state));
+        };
+        let elapsed = now.saturating_duration_since(challenged_at);
+        let result = self.check(nonce, signed, signature, ranging, elapsed);
+        self.state = match result {
+            Ok(()) => AuthenticationState::Authenticated,
+            Err(_) => AuthenticationState::Failed,
+        };
+        result
+    }
+
+    fn check(
+        &self,
+        nonce: &[u8],
+        signed: &[u8],
+        signature: &[u8],
+        ranging: Option<&RangingEvidence>,
+        elapsed: Duration,
+    ) -> Result<(), AuthenticationError> {
+        if elapsed > self.freshness {
+            return Err(AuthenticationError::Stale(elapsed));
+        }
+        if !constant_time_eq(nonce, self.nonce.expose()) {
+            return Err(AuthenticationError::NonceMismatch);
+        }
+        match (&self.ranging, ranging) {
+            (None, _) => {}
+            (Some(_), None) => return Err(AuthenticationError::RangingRequired),
+            (Some(policy), Some(evidence)) => {
+                if evidence.session_id != policy.session_id {
+                    return Err(AuthenticationError::RangingSession);
+                }
+                let age = Duration::from_millis(evidence.age_ms.into());
+                if age > policy.max_age {
+                    return Err(AuthenticationError::StaleRanging(age));
+                }
+            }
+        }
+        let verifier = self
+            .verifiers
+            .verifier(self.algorithm, &self.public_key)
+            .ok_or_else(|| AuthenticationError::UnsupportedKey(self.device_id.clone()))?;
+        if !verifier.verify(signed, signature) {
+            return Err(AuthenticationError::BadSignature);
+        }
+        Ok(())
+    }
+}
+
+/// Authenticates enrolled devices.
+pub struct Authenticator {
+    registry: Arc<EnrollmentRegistry>,
+    verifiers: Arc<dyn KeyVerifiers + Send + Sync>,
+    rng: Arc<dyn SecureRng>,
+    freshness: Duration,
+    max_ranging_age: Duration,
+    audit: Arc<AuditLog>,
+}
+
+impl Authenticator {
+    /// Creates an authenticator of the devices enrolled in `registry`, whose keys `verifiers`
+    /// supports, drawing nonces from `OsRng`, giving them `DEFAULT_FRESHNESS` to answer, and
+    /// accepting ranging measurements of up to `DEFAULT_MAX_RANGING_AGE` to unlock. Failed
+    /// verifications are recorded in `audit_log`.
+    pub fn new(
+        registry: Arc<EnrollmentRegistry>,
+        verifiers: Arc<dyn KeyVerifiers + Send + Sync>,
+    ) -> Result<Self, AuditError> {
+        Ok(Self {
+            registry,
+            verifiers,
+            rng: Arc::new(OsRng),
+            freshness: DEFAULT_FRESHNESS,
+            max_ranging_age: DEFAULT_MAX_RANGING_AGE,
+            audit: Arc::clone(audit_log()?),
+        })
+    }
+
+    /// Draws the nonces of the challenges from `rng`.
+    pub fn with_rng(mut self, rng: Arc<dyn SecureRng>) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Sets how long a device has to answer a challenge.
+    pub fn with_freshness(mut self, freshness: Duration) -> Self {
+        self.freshness = freshness;
+        self
+    }
+
+    /// Sets how old the ranging measurement of an unlock may be.
+    pub fn with_max_ranging_age(mut self, max_ranging_age: Duration) -> Self {
+        self.max_ranging_age = max_ranging_age;
+        self
+    }
+
+    /// Records failed verifications in `audit` instead.
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Starts an authentication of `device_id`, with a fresh random nonce. Fails with
+    /// `NotEnrolled` if the device has no enrollment.
+    pub fn begin(&self, device_id: &DeviceId) -> Result<Authentication, AuthenticationError> {
+        let record = self
+            .registry
+            .get(device_id)
+            .ok_or_else(|| AuthenticationError::NotEnrolled(device_id.clone()))?;
+        let mut nonce = vec![0; AUTHENTICATION_NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|e| {
+            warn!("failed to draw the nonce of a challenge: {}", e);
+            AuthenticationError::Random
+        })?;
+        Authentication::new(&record, Arc::clone(&self.verifiers), nonce, self.freshness)
+    }
+
+    /// Authenticates `device_id`, the remote device of `connection_id`: sends it a challenge,
+    /// and checks its response. A device whose enrollment is revoked or replaced meanwhile
+    /// fails with `NotEnrolled`.
+    pub async fn authenticate<T: Platform + ?Sized>(
+        &self,
+        platform: &TypedPlatform<T>,
+        connection_id: i32,
+        device_id: &DeviceId,
+    ) -> anyhow::Result<()> {
+        let mut authentication = self.begin(device_id)?;
+        let challenge = authentication.challenge(Instant::now())?;
+        // No point waiting past the freshness window.
+        let response = platform
+            .send(connection_id, &challenge, RequestMetadata::new(), Some(self.freshness))
+            .await?;
+        if let Err(e) = authentication.receive(&response, Instant::now()) {
+            warn!("authentication of device {} failed: {}", device_id, e);
+            let detail = format!("authentication on connection {}: {}", connection_id, e);
+            self.audit.record(AuditEventKind::VerificationFailed, Some(device_id), &detail);
+            return Err(e.into());
+        }
+        self.check_enrolled(&authentication)?;
+        info!("connection {} authenticated device {}", connection_id, device_id);
+        Ok(())
+    }
+
+    /// Authenticates `device_id`, the remote device of `connection_id`, to unlock: sends it an
+    /// unlock challenge, and checks its response, whose ranging evidence must be of
+    /// `ranging_session_id`, the session with the device, and recent enough. Returns a token of
+    /// the device from `tokens`, for Java to unlock with.
+    pub async fn unlock<T: Platform + ?Sized>(
+        &self,
+        platform: &TypedPlatform<T>,
+        connection_id: i32,
+        device_id: &DeviceId,
+        ranging_session_id: &[u8],
+        tokens: &UnlockTokens,
+    ) -> anyhow::Result<UnlockToken> {
+        let policy = RangingPolicy {
+            session_id: ranging_session_id.to_vec(),
+            max_age: self.max_ranging_age,
+        };
+        let mut authentication = self.begin(device_id)?.with_ranging(policy);
+        let challenge = UnlockChallenge { nonce: authentication.challenge(Instant::now())?.nonce };
+        let response = platform
+            .send(connection_id, &challenge, RequestMetadata::new(), Some(self.freshness))
+            .await?;
+        if let Err(e) = authentication.receive_unlock(&response, Instant::now()) {
+            warn!("unlock by device {} failed: {}", device_id, e);
+            let detail = format!("unlock on connection {}: {}", connection_id, e);
+            self.audit.record(AuditEventKind::VerificationFailed, Some(device_id), &detail);
+            return Err(e.into());
+        }
+        self.check_enrolled(&authentication)?;
+        info!("connection {} authenticated device {} to unlock", connection_id, device_id);
+        Ok(tokens.issue(device_id)?)
+    }
+
+    // Fails with `NotEnrolled` if the enrollment of the device of `authentication` was revoked
+    // or replaced while it waited for the response.
+    fn check_enrolled(&self, authentication: &Authentication) -> Result<(), AuthenticationError> {
+        let device_id = &authentication.device_id;
+        match self.registry.get(device_id) {
+            Some(record) if record.identity.public_key == authentication.public_key => Ok(()),
+            _ => Err(AuthenticationError::NotEnrolled(device_id.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::SecurityLevel;
+    use crate::cose::Verifier;
+    use crate::crc32c::crc32c;
+    use crate::crypto::AttestationVerifiers;
+    use crate::device_identity::{DeviceMetadata, RemoteIdentity};
+    use crate::keystore::Keystore;
+    use crate::rng::RngError;
+    use async_trait::async_trait;
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    const NONCE: [u8; AUTHENTICATION_NONCE_LEN] = [9; AUTHENTICATION_NONCE_LEN];
+    const FRESHNESS: Duration = Duration::from_secs(10);
+
+    // Not cryptography: checksums keyed with a byte stand in for the keys.
+    struct FakeKey(u8);
+
+    impl FakeKey {
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            crc32c(&[&[self.0], data]).to_be_bytes().to_vec()
+        }
+    }
+
+    impl Verifier for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::EdDsa
+        }
+
+        fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+            self.sign(data) == signature
+        }
+    }
+
+    struct BrokenRng;
+
+    impl SecureRng for BrokenRng {
+        fn fill(&self, _: &mut [u8]) -> Result<(), RngError> {
+            Err(RngError::HealthCheck("stuck output"))
+        }
+    }
+
+    struct FakeVerifiers;
+
+    impl KeyVerifiers for FakeVerifiers {
+        fn verifier(&self, algorithm: Algorithm, public_key: &[u8]) -> Option<Box<dyn Verifier>> {
+            match (algorithm, public_key) {
+                (Algorithm::EdDsa, &[key]) => Some(Box::new(FakeKey(key))),
+                _ => None,
+            }
+        }
+    }
+
+    fn record(public_key: &[u8]) -> EnrollmentRecord {
+        EnrollmentRecord {
+            identity: RemoteIdentity {
+                device_id: DeviceId::new("watch").unwrap(),
+                algorithm: Algorithm::EdDsa,
+                public_key: public_key.to_vec(),
+                metadata: DeviceMetadata { name: "watch".to_string(), model: "W1".to_string() },
+            },
+            pairing_key: Secret::new(vec![7; 32]),
+            security_level: SecurityLevel::StrongBox,
+            enrolled_at: UNIX_EPOCH,
+            key_epochs: BTreeMap::new(),
+            identity_pin: None,
+        }
+    }
+
+    fn authentication() -> Authentication {
+        Authentication::new(&record(&[1]), Arc::new(FakeVerifiers), NONCE.to_vec(), FRESHNESS)
+            .unwrap()
+    }
+
+    fn response(key: u8, nonce: &[u8]) -> ChallengeResponse {
+        ChallengeResponse {
+            nonce: nonce.to_vec(),
+            signature: FakeKey(key).sign(&signed_data(nonce)),
+        }
+    }
+
+    #[test]
+    fn test_authentication() {
+        let now = Instant::now();
+        let mut authentication = authentication();
+        let challenge = authentication.challenge(now).unwrap();
+        assert_eq!(challenge.nonce, NONCE);
+        assert_eq!(authentication.state(), AuthenticationState::Challenged);
+        assert_eq!(
+            authentication.challenge(now),
+            Err(AuthenticationError::InvalidState(AuthenticationState::Challenged))
+        );
+        assert_eq!(authentication.receive(&response(1, &NONCE), now + FRESHNESS), Ok(()));
+        assert_eq!(authentication.state(), AuthenticationState::Authenticated);
+        assert_eq!(
+            authentication.receive(&response(1, &NONCE), now + FRESHNESS),
+            Err(AuthenticationError::InvalidState(AuthenticationState::Authenticated))
+        );
+    }
+
+    #[test]
+    fn test_reject() {
+        let now = Instant::now();
+        let mut idle = authentication();
+        assert_eq!(
+            idle.receive(&response(1, &NONCE), now),
+            Err(AuthenticationError::InvalidState(AuthenticationState::Idle))
+        );
+
+        let late = now + FRESHNESS + Duration::from_millis(1);
+        let failures = [
+            (response(1, &[8; AUTHENTICATION_NONCE_LEN]), now, AuthenticationError::NonceMismatch),
+            (response(2, &NONCE), now, AuthenticationError::BadSignature),
+            (response(1, &NONCE), late, AuthenticationError::Stale(late - now)),
+        ];
+        for (response, at, error) in failures {
+            let mut authentication = authentication();
+            authentication.challenge(now).unwrap();
+            assert_eq!(authentication.receive(&response, at), Err(error));
+            assert_eq!(authentication.state(), AuthenticationState::Failed);
+        }
+
+        assert!(matches!(
+            Authentication::new(
+                &record(&[1, 2]),
+                Arc::new(FakeVerifiers),
+                NONCE.to_vec(),
+                FRESHNESS
+            ),
+            Err(AuthenticationError::UnsupportedKey(_))
+        ));
+    }
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    // A P-256 identity key, and its signature of `signed_data(&NONCE)` in DER, as Keystore signs.
+    const ES256_PUBLIC_KEY: &str = concat!(
+        "045d4c270fba306afe9eca3776366abe6279ee2b036d7f16c8d1eb22d2df63df74",
+        "8297bcd36f670e19860f8792d2de1176f661470945a9caa5ab53225fb532cc69"
+    );
+    const ES256_DER_SIGNATURE: &str = concat!(
+        "304402200a6dbeccb190e93eb24918413555ece3f1cb37f2faaaf5cbc7473b229f982d86",
+        "02201bf654eafbd9671682213c779531cc1a81b9e954da336a49d07882a91d58140f"
+    );
+
+    struct Es256Keystore;
+
+    #[async_trait]
+    impl Keystore for Es256Keystore {
+        async fn sign_with_device_key(&self, _: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            assert_eq!(data, signed_data(&NONCE));
+            Ok(hex(ES256_DER_SIGNATURE))
+        }
+
+        async fn get_device_public_key(&self, _: &str) -> anyhow::Result<Vec<u8>> {
+            Ok(hex(ES256_PUBLIC_KEY))
+        }
+
+        async fn generate_key_pair(&self, _: &str, _: bool) -> anyhow::Result<Vec<u8>> {
+            unimplemented!()
+        }
+
+        async fn derive_backup_secret(&self, _: &[u8]) -> anyhow::Result<Secret<Vec<u8>>> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_keystore_signature() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let local = runtime
+            .block_on(LocalIdentity::load(Arc::new(Es256Keystore), DeviceMetadata::default()))
+            .unwrap();
+        let mut record = record(local.public_key());
+        record.identity.algorithm = Algorithm::Es256;
+        let authentication = || {
+            Authentication::new(&record, Arc::new(AttestationVerifiers), NONCE.to_vec(), FRESHNESS)
+                .unwrap()
+        };
+
+        let now = Instant::now();
+        let mut accepted = authentication();
+        let challenge = accepted.challenge(now).unwrap();
+        let response = runtime.block_on(respond(&local, &challenge)).unwrap();
+        assert_eq!(response.signature.len(), 64);
+        assert_eq!(accepted.receive(&response, now), Ok(()));
+
+        // Signatures in DER, as Keystore returns them, aren't in the format of the protocol.
+        let mut der = authentication();
+        der.challenge(now).unwrap();
+        let response =
+            ChallengeResponse { nonce: NONCE.to_vec(), signature: hex(ES256_DER_SIGNATURE) };
+        assert_eq!(der.receive(&response, now), Err(AuthenticationError::BadSignature));
+    }
+
+    fn unlock_response(key: u8, session_id: &[u8], age_ms: u32) -> UnlockResponse {
+        let ranging = RangingEvidence { session_id: session_id.to_vec(), age_ms };
+        let signature = FakeKey(key).sign(&unlock_signed_data(&NONCE, &ranging));
+        UnlockResponse { nonce: NONCE.to_vec(), signature, ranging }
+    }
+
+    #[test]
+    fn test_unlock() {
+        let accepted = unlock_response(1, b"uwb", 500);
+        assert_eq!(UnlockResponse::decode(&accepted.encode()), Ok(accepted.clone()));
+
+        let now = Instant::now();
+        let policy = RangingPolicy { session_id: b"uwb".to_vec(), max_age: Duration::from_secs(1) };
+        let mut altered = accepted.clone();
+        altered.ranging.age_ms = 0;
+        let cases = [
+            (accepted, Ok(())),
+            (unlock_response(1, b"relay", 500), Err(AuthenticationError::RangingSession)),
+            (
+                unlock_response(1, b"uwb", 1001),
+                Err(AuthenticationError::StaleRanging(Duration::from_millis(1001))),
+            ),
+            (altered, Err(AuthenticationError::BadSignature)),
+        ];
+        for (response, result) in cases {
+            let mut authentication = authentication().with_ranging(policy.clone());
+            authentication.challenge(now).unwrap();
+            assert_eq!(authentication.receive_unlock(&response, now), result);
+        }
+
+        // Neither kind of response passes for the other.
+        let mut ranged = authentication().with_ranging(policy);
+        ranged.challenge(now).unwrap();
+        assert_eq!(
+            ranged.receive(&response(1, &NONCE), now),
+            Err(AuthenticationError::RangingRequired)
+        );
+        let mut plain = authentication();
+        plain.challenge(now).unwrap();
+        let mut forged = unlock_response(1, b"uwb", 500);
+        forged.signature = response(1, &NONCE).signature;
+        assert_eq!(plain.receive_unlock(&forged, now), Err(AuthenticationError::BadSignature));
+    }
+
+    #[test]
+    fn test_begin() {
+        let registry = Arc::new(EnrollmentRegistry::new());
+        let authenticator = Authenticator::new(registry.clone(), Arc::new(FakeVerifiers)).unwrap();
+        let watch = DeviceId::new("watch").unwrap();
+        assert!(matches!(authenticator.begin(&watch), Err(AuthenticationError::NotEnrolled(_))));
+
+        registry.insert(record(&[1]));
+        let mut first = authenticator.begin(&watch).unwrap();
+        let mut second = authenticator.begin(&watch).unwrap();
+        let now = Instant::now();
+        let nonce = first.challenge(now).unwrap().nonce;
+        assert_eq!(nonce.len(), AUTHENTICATION_NONCE_LEN);
+        assert_ne!(nonce, second.challenge(now).unwrap().nonce);
+        assert_eq!(first.receive(&response(1, &nonce), now), Ok(()));
+
+        let broken = Authenticator::new(registry, Arc::new(FakeVerifiers))
+            .unwrap()
+            .with_rng(Arc::new(BrokenRng));
+        assert!(matches!(broken.begin(&watch), Err(AuthenticationError::Random)));
+    }
+}
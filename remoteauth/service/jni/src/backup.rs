@@ -0,0 +1,455 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Encrypted exports of the enrollments, for cloud backup and restore of the pairings.
+//!
+//! An export carries the persisted records and tombstones of the registry, sealed as a
+//! `COSE_Encrypt0` under a key derived from a secret Java derives from the credential of the
+//! user, see `Keystore::derive_backup_secret`, and a salt drawn for each export. It is a
+//! versioned record of the salt and the sealed records, so that the format can evolve like the
+//! persisted records do.
+//!
+//! Importing opens the export with the credential, which authenticates it, and reads every
+//! record back before restoring any: a tampered export, one sealed under another credential or
+//! one holding an unreadable record is refused as a whole. The newer of the local and the
+//! exported state of each device wins, and a device revoked locally is never brought back.
+
+use crate::codec::cbor::{self, DecodeLimits, Value};
+use crate::cose::CoseEncrypt0;
+use crate::device_identity::DeviceId;
+use crate::enrollment::{EnrollmentRecord, EnrollmentRegistry, EnrollmentStore, Tombstone};
+use crate::kdf::{derive_key, Hkdf, KeyPurpose, ENCRYPTION_KEY_LEN, IV_LEN};
+use crate::keystore::Keystore;
+use crate::migration::{malformed, Migration, MigrationError, VersionedRecord};
+use crate::noise::AeadFactory;
+use crate::rng::{OsRng, SecureRng};
+use crate::secret::Secret;
+use log::{info, warn};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Length of the salt drawn for each export.
+pub const BACKUP_SALT_LEN: usize = 16;
+/// Longest export accepted.
+pub const MAX_BACKUP_LEN: usize = 256 * 1024;
+/// Additional data authenticated with the sealed records.
+const BACKUP_AAD: &[u8] = b"RemoteAuth enrollment backup";
+
+// Keys of the fields of an export.
+const BACKUP_SALT_KEY: i64 = 1;
+const BACKUP_SEALED_KEY: i64 = 2;
+
+/// Why enrollments could not be exported or imported.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum BackupError {
+    /// No random salt or IV could be drawn.
+    #[error("no randomness for the backup")]
+    Random,
+    /// The key of the credential failed to seal the records.
+    #[error("failed to seal backup")]
+    Seal,
+    /// The export isn't one, or was written by a newer release.
+    #[error("invalid backup: {0}")]
+    Format(MigrationError),
+    /// The export doesn't open with the key of the credential: it was tampered with, or sealed
+    /// under another credential.
+    #[error("backup failed integrity verification")]
+    Integrity,
+    /// A sealed record can't be read.
+    #[error("unreadable record in backup: {0}")]
+    Record(MigrationError),
+}
+
+/// An export, as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EnrollmentBackup {
+    /// Salt the key was derived with.
+    salt: Vec<u8>,
+    /// `COSE_Encrypt0` of the CBOR array of the persisted records.
+    sealed: Vec<u8>,
+}
+
+impl VersionedRecord for EnrollmentBackup {
+    const NAME: &'static str = "enrollment_backup";
+    const SCHEMA_VERSION: u32 = 1;
+    const MIGRATIONS: &'static [Migration] = &[];
+
+    fn to_value(&self) -> Value {
+        Value::Map(vec![
+            (Value::Integer(BACKUP_SALT_KEY.into()), Value::Bytes(self.salt.clone())),
+            (Value::Integer(BACKUP_SEALED_KEY.into()), Value::Bytes(self.sealed.clone())),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, MigrationError> {
+        let Value::Map(entries) = value else {
+            return Err(malformed::<Self>("expected a map"));
+        };
+        let field = |key: i64| {
+            entries
+                .iter()
+                .find(|(k, _)| *k == Value::Integer(key.into()))
+                .map(|(_, value)| value.clone())
+                .ok_or(malformed::<Self>("missing field"))
+        };
+        let (Value::Bytes(salt), Value::Bytes(sealed)) =
+            (field(BACKUP_SALT_KEY)?, field(BACKUP_SEALED_KEY)?)
+        else {
+            return Err(malformed::<Self>("field of the wrong type"));
+        };
+        if salt.len() != BACKUP_SALT_LEN {
+            return Err(malformed::<Self>("bad salt"));
+        }
+        Ok(Self { salt, sealed })
+    }
+}
+
+/// Exports the enrollments of a registry, and imports exports back into it.
+pub struct EnrollmentBackups<F: AeadFactory> {
+    keystore: Arc<dyn Keystore>,
+    factory: F,
+    store: Arc<dyn EnrollmentStore>,
+    registry: Arc<EnrollmentRegistry>,
+    rng: Arc<dyn SecureRng>,
+}
+
+impl<F: AeadFactory> EnrollmentBackups<F> {
+    /// Creates the backups of `registry`, sealed with keys of `factory` derived from the
+    /// credential of the user through `keystore`. Imported records are persisted in `store`.
+    pub fn new(
+        keystore: Arc<dyn Keystore>,
+        factory: F,
+        store: Arc<dyn EnrollmentStore>,
+        registry: Arc<EnrollmentRegistry>,
+    ) -> Self {
+        Self { keystore, factory, store, registry, rng: Arc::new(OsRng) }
+    }
+
+    /// Draws the salts and IVs from `rng`, rather than `OsRng`.
+    pub fn with_rng(mut self, rng: Arc<dyn SecureRng>) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Returns the key sealing the exports salted with `salt`.
+    async fn key(&self, salt: &[u8]) -> anyhow::Result<F::Key> {
+        let secret = self.keystore.derive_backup_secret(salt).await?;
+        let derived = derive_key(
+            &Hkdf::new(),
+            KeyPurpose::Backup,
+            secret.expose(),
+            &[salt],
+            ENCRYPTION_KEY_LEN,
+        )?;
+        let mut key = Secret::new([0; ENCRYPTION_KEY_LEN]);
+        key.expose_mut().copy_from_slice(derived.expose());
+        Ok(self.factory.key(key.expose()))
+    }
+
+    /// Returns an export of the records and tombstones of the registry.
+    pub async fn export(&self) -> anyhow::Result<Vec<u8>> {
+        let (mut salt, mut iv) = (vec![0; BACKUP_SALT_LEN], [0; IV_LEN]);
+        self.rng.fill(&mut salt).map_err(|_| BackupError::Random)?;
+        self.rng.fill(&mut iv).map_err(|_| BackupError::Random)?;
+        let records = self.registry.persisted();
+        let count = records.len();
+        let plaintext = Secret::new(cbor::encode(&Value::Array(
+            records.into_iter().map(Value::Bytes).collect(),
+        )));
+        let key = self.key(&salt).await?;
+        let sealed = CoseEncrypt0::encrypt(&key, None, &iv, plaintext.expose(), BACKUP_AAD)
+            .map_err(|_| BackupError::Seal)?;
+        info!("exported {} enrollment records", count);
+        Ok(EnrollmentBackup { salt, sealed: sealed.to_bytes() }.to_bytes())
+    }
+
+    /// Verifies `backup` and restores its enrollments, persisting what it changes. A record
+    /// replaces an older local one, and a tombstone revokes an older local enrollment; a device
+    /// revoked locally stays revoked. Returns the number of devices restored.
+    pub async fn import(&self, backup: &[u8]) -> anyhow::Result<usize> {
+        let limits = DecodeLimits { max_len: MAX_BACKUP_LEN, ..DecodeLimits::default() };
+        let backup = EnrollmentBackup::from_bytes(backup, limits).map_err(BackupError::Format)?;
+        let key = self.key(&backup.salt).await?;
+        let plaintext = CoseEncrypt0::from_bytes(&backup.sealed, limits)
+            .and_then(|sealed| sealed.decrypt(&key, BACKUP_AAD))
+            .map(Secret::new)
+            .map_err(|_| BackupError::Integrity)?;
+        let (records, tombstones) = read_records(plaintext.expose(), limits)?;
+
+        for tombstone in tombstones {
+            let enrolled_at = self.registry.get(&tombstone.device_id).map(|r| r.enrolled_at);
+            if enrolled_at.is_some_and(|enrolled_at| enrolled_at <= tombstone.revoked_at) {
+                self.store
+                    .store_enrollment(tombstone.device_id.as_str(), &tombstone.to_bytes())
+                    .await?;
+                self.registry.revoke(&tombstone.device_id, tombstone.revoked_at);
+                info!("backup revoked the enrollment of device {}", tombstone.device_id);
+            }
+        }
+        let mut restored = 0;
+        for record in records {
+            let device_id = record.identity.device_id.clone();
+            if !self.restores(&device_id, &record) {
+                continue;
+            }
+            self.store.store_enrollment(device_id.as_str(), &record.to_bytes()).await?;
+            self.registry.insert(record);
+            restored += 1;
+        }
+        info!("restored {} enrollments from backup", restored);
+        Ok(restored)
+    }
+
+    /// Returns whether `record` of `device_id` is newer than its local state.
+    fn restores(&self, device_id: &DeviceId, record: &EnrollmentRecord) -> bool {
+        if let Some(revoked_at) = self.registry.revoked_at(device_id) {
+            info!("not restoring device {}, revoked at {:?}", device_id, revoked_at);
+            return false;
+        }
+        match self.registry.get(device_id) {
+            Some(local) => local.enrolled_at < record.enrolled_at,
+            None => true,
+        }
+    }
+}
+
+/// Reads the records and the tombstones sealed in an export, failing on any unreadable one.
+fn read_records(
+    plaintext: &[u8],
+    limits: DecodeLimits,
+) -> Result<(Vec<EnrollmentRecord>, Vec<Tombstone>), BackupError> {
+    let Ok(Value::Array(items)) = cbor::decode(plaintext, limits) else {
+        return Err(BackupError::Record(malformed::<EnrollmentBackup>("expected an array")));
+    };
+    let (mut records, mut tombstones) = (Vec::new(), Vec::new());
+    for item in items {
+        let Value::Bytes(bytes) = item else {
+            return Err(BackupError::Record(malformed::<EnrollmentBackup>("expected bytes")));
+        };
+        let bytes = Secret::new(bytes);
+        match EnrollmentRecord::from_bytes(bytes.expose(), limits) {
+            Ok(record) => records.push(record),
+            Err(e) => match Tombstone::from_bytes(bytes.expose(), limits) {
+                Ok(tombstone) => tombstones.push(tombstone),
+                Err(_) => {
+                    warn!("unreadable record in backup: {}", e);
+                    return Err(BackupError::Record(e));
+                }
+            },
+        }
+    }
+    Ok((records, tombstones))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::SecurityLevel;
+    use crate::cose::{Aead, Algorithm};
+    use crate::crc32c::crc32c;
+    use crate::device_identity::{DeviceMetadata, RemoteIdentity};
+    use crate::rng::RngError;
+    use async_trait::async_trait;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Not cryptography: the backup secret is the salt keyed with a credential byte.
+    struct FakeKeystore(u8);
+
+    #[async_trait]
+    impl Keystore for FakeKeystore {
+        async fn sign_with_device_key(&self, _: &str, _: &[u8]) -> anyhow::Result<Vec<u8>> {
+            unimplemented!()
+        }
+
+        async fn get_device_public_key(&self, _: &str) -> anyhow::Result<Vec<u8>> {
+            unimplemented!()
+        }
+
+        async fn generate_key_pair(&self, _: &str, _: bool) -> anyhow::Result<Vec<u8>> {
+            unimplemented!()
+        }
+
+        async fn derive_backup_secret(&self, salt: &[u8]) -> anyhow::Result<Secret<Vec<u8>>> {
+            Ok(Secret::new([&[self.0], salt].concat()))
+        }
+    }
+
+    // Not cryptography: XORs with the first key byte, tagged with a CRC of the whole key.
+    struct FakeKey([u8; ENCRYPTION_KEY_LEN]);
+
+    impl Aead for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::A256Gcm
+        }
+
+        fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let mut sealed: Vec<u8> = plaintext.iter().map(|byte| byte ^ self.0[0]).collect();
+            sealed.extend_from_slice(&crc32c(&[&self.0, iv, aad, plaintext]).to_be_bytes());
+            Ok(sealed)
+        }
+
+        fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (sealed, tag) = ciphertext.split_at(ciphertext.len().checked_sub(4)?);
+            let plaintext: Vec<u8> = sealed.iter().map(|byte| byte ^ self.0[0]).collect();
+            (crc32c(&[&self.0, iv, aad, &plaintext]).to_be_bytes() == tag).then_some(plaintext)
+        }
+    }
+
+    struct FakeFactory;
+
+    impl AeadFactory for FakeFactory {
+        type Key = FakeKey;
+
+        fn key(&self, key: &[u8; ENCRYPTION_KEY_LEN]) -> FakeKey {
+            FakeKey(*key)
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeStore {
+        records: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl EnrollmentStore for FakeStore {
+        async fn store_enrollment(&self, device_id: &str, record: &[u8]) -> anyhow::Result<()> {
+            self.records.lock().unwrap().insert(device_id.to_string(), record.to_vec());
+            Ok(())
+        }
+
+        async fn delete_enrollment(&self, device_id: &str) -> anyhow::Result<()> {
+            self.records.lock().unwrap().remove(device_id);
+            Ok(())
+        }
+    }
+
+    // Not random: counts the draws.
+    #[derive(Default)]
+    struct FakeRng(AtomicU8);
+
+    impl SecureRng for FakeRng {
+        fn fill(&self, buf: &mut [u8]) -> Result<(), RngError> {
+            buf.fill(self.0.fetch_add(1, Ordering::Relaxed));
+            Ok(())
+        }
+    }
+
+    fn record(device_id: &str, enrolled_at: u64) -> EnrollmentRecord {
+        EnrollmentRecord {
+            identity: RemoteIdentity {
+                device_id: DeviceId::new(device_id).unwrap(),
+                algorithm: Algorithm::Es256,
+                public_key: vec![4; 65],
+                metadata: DeviceMetadata { name: "watch".to_string(), model: "W1".to_string() },
+            },
+            pairing_key: Secret::new(vec![7; 32]),
+            security_level: SecurityLevel::StrongBox,
+            enrolled_at: UNIX_EPOCH + Duration::from_secs(enrolled_at),
+            key_epochs: BTreeMap::new(),
+            identity_pin: None,
+        }
+    }
+
+    fn backups(
+        credential: u8,
+        registry: &Arc<EnrollmentRegistry>,
+    ) -> EnrollmentBackups<FakeFactory> {
+        EnrollmentBackups::new(
+            Arc::new(FakeKeystore(credential)),
+            FakeFactory,
+            Arc::new(FakeStore::default()),
+            Arc::clone(registry),
+        )
+        .with_rng(Arc::new(FakeRng::default()))
+    }
+
+    fn error(result: anyhow::Result<usize>) -> Option<BackupError> {
+        result.unwrap_err().downcast_ref().cloned()
+    }
+
+    #[test]
+    fn test_backup() {
+        let source = Arc::new(EnrollmentRegistry::new());
+        source.insert(record("watch", 100));
+        source.insert(record("phone", 100));
+        source.revoke(&DeviceId::new("tablet").unwrap(), UNIX_EPOCH + Duration::from_secs(300));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let export = backups(1, &source).export().await.unwrap();
+            let (watch, phone, tablet) = (
+                DeviceId::new("watch").unwrap(),
+                DeviceId::new("phone").unwrap(),
+                DeviceId::new("tablet").unwrap(),
+            );
+
+            // Newer locally, or revoked locally: kept as is.
+            let target = Arc::new(EnrollmentRegistry::new());
+            let mut newer = record("phone", 200);
+            newer.pairing_key = Secret::new(vec![8; 32]);
+            target.insert(newer.clone());
+            target.insert(record("tablet", 400));
+            target.revoke(&watch, UNIX_EPOCH + Duration::from_secs(50));
+            assert_eq!(backups(1, &target).import(&export).await.unwrap(), 0);
+            assert_eq!(target.get(&phone), Some(newer));
+            assert_eq!(target.get(&watch), None);
+            assert_eq!(target.get(&tablet), Some(record("tablet", 400)));
+
+            let target = Arc::new(EnrollmentRegistry::new());
+            target.insert(record("tablet", 200));
+            let store = Arc::new(FakeStore::default());
+            let keystore = Arc::new(FakeKeystore(1));
+            let restoring =
+                EnrollmentBackups::new(keystore, FakeFactory, store.clone(), target.clone());
+            assert_eq!(restoring.import(&export).await.unwrap(), 2);
+            assert_eq!(target.get(&watch), Some(record("watch", 100)));
+            assert_eq!(target.get(&phone), Some(record("phone", 100)));
+            assert_eq!(target.get(&tablet), None);
+            assert_eq!(target.revoked_at(&tablet), Some(UNIX_EPOCH + Duration::from_secs(300)));
+            assert_eq!(store.records.lock().unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn test_backup_integrity() {
+        let source = Arc::new(EnrollmentRegistry::new());
+        source.insert(record("watch", 100));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let export = backups(1, &source).export().await.unwrap();
+            let target = Arc::new(EnrollmentRegistry::new());
+            assert_eq!(
+                error(backups(2, &target).import(&export).await),
+                Some(BackupError::Integrity)
+            );
+            let mut tampered = export.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 1;
+            assert_eq!(
+                error(backups(1, &target).import(&tampered).await),
+                Some(BackupError::Integrity)
+            );
+            assert!(matches!(
+                error(backups(1, &target).import(&[0xa0]).await),
+                Some(BackupError::Format(_))
+            ));
+            assert!(target.device_ids().is_empty());
+            assert_eq!(backups(1, &target).import(&export).await.unwrap(), 1);
+        });
+    }
+}
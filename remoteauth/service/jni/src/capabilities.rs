@@ -0,0 +1,448 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Capabilities of the remote device of a connection.
+//!
+//! Right after version negotiation, each side sends the capabilities it supports, and both use
+//! the common ones from then on: framing, crypto and policy query them here instead of assuming
+//! features. Ciphers and wire formats are listed in order of preference; the common ones keep
+//! the order of the native side.
+
+use crate::codec::WireFormat;
+use crate::config;
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::messages::{
+    decode_response, DecodeError, Encoding, Message, Reader, Request, TypedPlatform, Writer,
+};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::schema::{Field, Rule, Schema};
+use log::{error, info};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+
+const RANGING_FLAG: u32 = 1 << 0;
+const STREAMING_FLAG: u32 = 1 << 1;
+const COMPRESSION_FLAG: u32 = 1 << 2;
+const DELTA_FLAG: u32 = 1 << 3;
+const NOISE_FLAG: u32 = 1 << 4;
+const INTEGRITY_FLAG: u32 = 1 << 5;
+
+/// Why capabilities could not be agreed on.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// The two sides share no wire format.
+    #[error("no common wire format")]
+    NoCommonWireFormat,
+}
+
+/// Handshake setting up the secure channel of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureHandshake {
+    /// X25519 agreement of ephemeral and static keys, with `ecdh::handshake_secret`.
+    Native,
+    /// Noise XX, for remote devices that already implement Noise.
+    NoiseXx,
+}
+
+/// Protection of the payloads of the secure channel of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelProtection {
+    /// Sealed with AES-256-GCM.
+    Aead,
+    /// Authenticated with HMAC-SHA256 only, the transport encrypting them.
+    Integrity,
+}
+
+/// Authenticated cipher protecting the messages of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cipher {
+    /// AES-128 in GCM mode.
+    Aes128Gcm,
+    /// AES-256 in GCM mode.
+    Aes256Gcm,
+    /// ChaCha20 with Poly1305.
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// All ciphers, in order of preference.
+    pub const ALL: [Cipher; 3] = [Self::Aes256Gcm, Self::ChaCha20Poly1305, Self::Aes128Gcm];
+
+    fn id(self) -> u8 {
+        match self {
+            Self::Aes128Gcm => 1,
+            Self::Aes256Gcm => 2,
+            Self::ChaCha20Poly1305 => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|cipher| cipher.id() == id)
+    }
+}
+
+fn wire_format_id(format: WireFormat) -> u8 {
+    match format {
+        WireFormat::Cbor => 0,
+        WireFormat::Proto => 1,
+    }
+}
+
+fn wire_format_from_id(id: u8) -> Option<WireFormat> {
+    [WireFormat::Cbor, WireFormat::Proto].into_iter().find(|format| wire_format_id(*format) == id)
+}
+
+/// Features a device supports, and the message exchanging them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Supported ciphers, in order of preference.
+    pub ciphers: Vec<Cipher>,
+    /// Supported wire formats, in order of preference.
+    pub wire_formats: Vec<WireFormat>,
+    /// Largest payload accepted in a single transport packet, in bytes.
+    pub max_payload: u32,
+    /// Whether the device supports ranging, e.g. over UWB.
+    pub ranging: bool,
+    /// Whether the device streams large responses in chunks.
+    pub streaming: bool,
+    /// Whether the device decompresses the large messages compressed by framing.
+    pub compression: bool,
+    /// Whether the device applies state syncs delta-encoded against an earlier sync.
+    pub delta: bool,
+    /// Whether the device sets up the secure channel with the Noise XX handshake.
+    pub noise: bool,
+    /// Whether the device accepts secure channel payloads authenticated but not encrypted, on
+    /// transports that encrypt.
+    pub integrity: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of the native side, accepting payloads up to the configured max payload size.
+    pub fn local() -> Self {
+        Self {
+            ciphers: Cipher::ALL.to_vec(),
+            wire_formats: vec![WireFormat::Cbor, WireFormat::Proto],
+            max_payload: config::snapshot().max_payload_size.try_into().unwrap_or(u32::MAX),
+            ranging: false,
+            streaming: true,
+            compression: true,
+            delta: true,
+            noise: true,
+            integrity: true,
+        }
+    }
+
+    /// Returns the capabilities of both `self` and `peer`, in the order of preference of
+    /// `self`.
+    pub fn intersect(&self, peer: &Capabilities) -> Capabilities {
+        Capabilities {
+            ciphers: self.ciphers.iter().copied().filter(|c| peer.ciphers.contains(c)).collect(),
+            wire_formats: self
+                .wire_formats
+                .iter()
+                .copied()
+                .filter(|format| peer.wire_formats.contains(format))
+                .collect(),
+            max_payload: self.max_payload.min(peer.max_payload),
+            ranging: self.ranging && peer.ranging,
+            streaming: self.streaming && peer.streaming,
+            compression: self.compression && peer.compression,
+            delta: self.delta && peer.delta,
+            noise: self.noise && peer.noise,
+            integrity: self.integrity && peer.integrity,
+        }
+    }
+
+    /// Returns the preferred cipher, if any.
+    pub fn cipher(&self) -> Option<Cipher> {
+        self.ciphers.first().copied()
+    }
+
+    /// Returns the preferred wire format, if any.
+    pub fn wire_format(&self) -> Option<WireFormat> {
+        self.wire_formats.first().copied()
+    }
+}
+
+impl Message for Capabilities {
+    const TYPE: u8 = 7;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "capabilities",
+        fields: &[
+            Field::new(1, "ciphers", Rule::Bytes { min_len: 0, max_len: 16 }),
+            Field::new(2, "wire_formats", Rule::Bytes { min_len: 0, max_len: 16 }),
+            Field::new(3, "max_payload", Rule::U32),
+            Field::new(4, "flags", Rule::U32),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        let ciphers: Vec<u8> = self.ciphers.iter().map(|cipher| cipher.id()).collect();
+        let wire_formats: Vec<u8> = self.wire_formats.iter().copied().map(wire_format_id).collect();
+        let mut flags = 0;
+        if self.ranging {
+            flags |= RANGING_FLAG;
+        }
+        if self.streaming {
+            flags |= STREAMING_FLAG;
+        }
+        if self.compression {
+            flags |= COMPRESSION_FLAG;
+        }
+        if self.delta {
+            flags |= DELTA_FLAG;
+        }
+        if self.noise {
+            flags |= NOISE_FLAG;
+        }
+        if self.integrity {
+            flags |= INTEGRITY_FLAG;
+        }
+        writer.put_bytes(&ciphers);
+        writer.put_bytes(&wire_formats);
+        writer.put_u32(self.max_payload);
+        writer.put_u32(flags);
+    }
+
+    /// Ciphers, wire formats and flags unknown to this side are ignored.
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let ciphers = reader.bytes()?.into_iter().filter_map(Cipher::from_id).collect();
+        let wire_formats = reader.bytes()?.into_iter().filter_map(wire_format_from_id).collect();
+        let max_payload = reader.u32()?;
+        let flags = reader.u32()?;
+        Ok(Self {
+            ciphers,
+            wire_formats,
+            max_payload,
+            ranging: flags & RANGING_FLAG != 0,
+            streaming: flags & STREAMING_FLAG != 0,
+            compression: flags & COMPRESSION_FLAG != 0,
+            delta: flags & DELTA_FLAG != 0,
+            noise: flags & NOISE_FLAG != 0,
+            integrity: flags & INTEGRITY_FLAG != 0,
+        })
+    }
+}
+
+impl Request for Capabilities {
+    type Response = Capabilities;
+}
+
+/// Common capabilities of each connection, once exchanged.
+#[derive(Default)]
+pub struct CapabilityCache {
+    connections: Mutex<HashMap<i32, Capabilities>>,
+}
+
+impl CapabilityCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the common capabilities of `connection_id`, or None before their exchange.
+    pub fn get(&self, connection_id: i32) -> Option<Capabilities> {
+        self.connections.lock().unwrap().get(&connection_id).cloned()
+    }
+
+    /// Returns the largest payload the remote device of `connection_id` accepts, if known.
+    pub fn max_payload(&self, connection_id: i32) -> Option<usize> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&connection_id).and_then(|c| usize::try_from(c.max_payload).ok())
+    }
+
+    /// Whether the remote device of `connection_id` decompresses messages, if known.
+    pub fn compression(&self, connection_id: i32) -> bool {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&connection_id).is_some_and(|c| c.compression)
+    }
+
+    /// Whether the remote device of `connection_id` applies delta-encoded state syncs, if known.
+    pub fn delta(&self, connection_id: i32) -> bool {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&connection_id).is_some_and(|c| c.delta)
+    }
+
+    /// Returns the handshake setting up the secure channel of `connection_id`: Noise XX if its
+    /// remote device supports it, or else the native one.
+    pub fn secure_handshake(&self, connection_id: i32) -> SecureHandshake {
+        let connections = self.connections.lock().unwrap();
+        match connections.get(&connection_id).is_some_and(|c| c.noise) {
+            true => SecureHandshake::NoiseXx,
+            false => SecureHandshake::Native,
+        }
+    }
+
+    /// Returns the protection of the secure channel of `connection_id`: integrity only if its
+    /// transport encrypts, per `transport_encrypts`, and its remote device supports it, or else
+    /// AES-256-GCM.
+    pub fn protection(&self, connection_id: i32, transport_encrypts: bool) -> ChannelProtection {
+        let connections = self.connections.lock().unwrap();
+        match transport_encrypts && connections.get(&connection_id).is_some_and(|c| c.integrity) {
+            true => ChannelProtection::Integrity,
+            false => ChannelProtection::Aead,
+        }
+    }
+
+    pub(crate) fn insert(&self, connection_id: i32, capabilities: Capabilities) {
+        self.connections.lock().unwrap().insert(connection_id, capabilities);
+    }
+
+    /// Forgets the capabilities of `connection_id`, e.g. once it is closed.
+    pub fn remove(&self, connection_id: i32) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+}
+
+/// Handshake sending the local capabilities, and intersecting them with the remote device's.
+pub struct CapabilityExchange {
+    local: Capabilities,
+    encoding: Encoding,
+}
+
+impl CapabilityExchange {
+    /// Creates an exchange of `local` capabilities, in `encoding`.
+    pub fn new(local: Capabilities, encoding: Encoding) -> Self {
+        Self { local, encoding }
+    }
+}
+
+impl Handshake for CapabilityExchange {
+    type Output = Capabilities;
+    const NAME: &'static str = "capability exchange";
+
+    fn first_message(&mut self) -> Vec<u8> {
+        self.local.encode_as(self.encoding)
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<Capabilities>> {
+        let peer: Capabilities = decode_response(reply, self.encoding)?;
+        let common = self.local.intersect(&peer);
+        if common.wire_format().is_none() {
+            return Err(CapabilityError::NoCommonWireFormat.into());
+        }
+        Ok(Step::Done(common))
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Exchanges `local` capabilities with the remote device of `connection_id`, right after
+    /// version negotiation, then caches the common ones and switches the connection to their
+    /// preferred wire format. Returns the common capabilities.
+    pub async fn exchange_capabilities(
+        &self,
+        connection_id: i32,
+        local: &Capabilities,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Capabilities> {
+        let exchange = CapabilityExchange::new(local.clone(), self.encoding(connection_id));
+        let mut machine = HandshakeMachine::new(exchange);
+        let common = match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(common) => common,
+            Err(e) => {
+                error!("capability exchange of connection {} failed: {:#}", connection_id, e);
+                return Err(e);
+            }
+        };
+        // Not None, or the exchange would have failed.
+        let wire_format = common.wire_format().unwrap();
+        info!("connection {} capabilities: {:?}", connection_id, common);
+        self.set_wire_format(connection_id, wire_format);
+        self.platform().set_max_message_size(connection_id, common.max_payload as usize);
+        self.capability_cache().insert(connection_id, common.clone());
+        Ok(common)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intersect() {
+        let local = Capabilities {
+            ciphers: vec![Cipher::Aes256Gcm, Cipher::Aes128Gcm],
+            wire_formats: vec![WireFormat::Cbor, WireFormat::Proto],
+            max_payload: 512,
+            ranging: true,
+            streaming: true,
+            compression: true,
+            delta: true,
+            noise: true,
+            integrity: true,
+        };
+        let peer = Capabilities {
+            ciphers: vec![Cipher::Aes128Gcm, Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm],
+            wire_formats: vec![WireFormat::Proto],
+            max_payload: 244,
+            ranging: true,
+            streaming: false,
+            compression: true,
+            delta: false,
+            noise: true,
+            integrity: false,
+        };
+        let common = local.intersect(&peer);
+        assert_eq!(common.cipher(), Some(Cipher::Aes256Gcm));
+        assert_eq!(common.ciphers, vec![Cipher::Aes256Gcm, Cipher::Aes128Gcm]);
+        assert_eq!(common.wire_format(), Some(WireFormat::Proto));
+        assert_eq!(common.max_payload, 244);
+        assert!(common.ranging);
+        assert!(!common.streaming);
+        assert!(common.compression);
+        assert!(!common.delta);
+        assert!(common.noise);
+        assert!(!common.integrity);
+    }
+
+    #[test]
+    fn test_decode_ignores_unknown() {
+        let local = Capabilities::local();
+        assert_eq!(Capabilities::decode(&local.encode()), Ok(local));
+        // Cipher 9, wire format 7 and flag 64 are unknown.
+        let bytes = [
+            0xa5, 0x00, 0x07, 0x01, 0x42, 0x09, 0x01, 0x02, 0x42, 0x07, 0x00, 0x03, 0x18, 0xf4,
+            0x04, 0x18, 0x42,
+        ];
+        assert_eq!(
+            Capabilities::decode(&bytes),
+            Ok(Capabilities {
+                ciphers: vec![Cipher::Aes128Gcm],
+                wire_formats: vec![WireFormat::Cbor],
+                max_payload: 244,
+                ranging: false,
+                streaming: true,
+                compression: false,
+                delta: false,
+                noise: false,
+                integrity: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_protection() {
+        let cache = CapabilityCache::default();
+        cache.insert(1, Capabilities::local());
+        cache.insert(2, Capabilities { integrity: false, ..Capabilities::local() });
+        assert_eq!(cache.protection(1, true), ChannelProtection::Integrity);
+        assert_eq!(cache.protection(1, false), ChannelProtection::Aead);
+        assert_eq!(cache.protection(2, true), ChannelProtection::Aead);
+        assert_eq!(cache.protection(3, true), ChannelProtection::Aead);
+    }
+}
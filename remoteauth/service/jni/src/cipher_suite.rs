@@ -0,0 +1,373 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Negotiation of the cipher suite of a connection.
+//!
+//! A cipher suite fixes the key agreement, the hash and the authenticated cipher of the secure
+//! channel together, see `CIPHER_SUITES`. After the capability exchange, the native side offers
+//! the suites it allows, in order of preference, and the remote device selects one of them, or
+//! refuses. The offer and the selection are recorded in the transcript of the connection like
+//! every handshake exchange, which the key agreement is bound to, so a man-in-the-middle stripping
+//! the strong suites from the offer makes the key agreement fail, see `transcript`. The handshake
+//! and the secure channel of the connection then run the negotiated suite, see `noise_handshake`
+//! and `SecureChannel::establish`, so only the suites they implement are listed.
+//!
+//! Suites marked deprecated, none so far, are still offered and accepted, last, until the
+//! `allow_deprecated_suites` config value is false: from then on they are neither offered nor
+//! selected, and a remote device offering nothing else is refused.
+
+use crate::capabilities::Cipher;
+use crate::config;
+use crate::cose::Algorithm;
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::messages::{
+    decode_response, DecodeError, Encoding, Message, Reader, Request, TypedPlatform, Writer,
+};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::schema::{Field, Rule, Schema};
+use log::{error, info, warn};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Id of a `SuiteSelect` refusing the offer.
+const REFUSED: u8 = 0;
+/// Most suites an offer lists.
+const MAX_OFFERED_SUITES: usize = 16;
+
+/// Why no cipher suite could be agreed on.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum SuiteError {
+    /// The remote device supports none of the suites offered.
+    #[error("remote device refused cipher suites {0:?}")]
+    Refused(Vec<u8>),
+    /// The remote device selected a suite outside of the offer.
+    #[error("remote device selected cipher suite {0}, not offered")]
+    NotOffered(u8),
+    /// The connection has not negotiated a cipher suite yet.
+    #[error("connection {0} negotiated no cipher suite")]
+    NotNegotiated(i32),
+    /// The handshake doesn't implement the negotiated suite.
+    #[error("cipher suite {0} not supported by the handshake")]
+    Unsupported(u8),
+}
+
+/// Hash of a cipher suite, for the transcript and key derivation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuiteHash {
+    /// SHA-256.
+    Sha256,
+    /// SHA-512.
+    Sha512,
+}
+
+/// Key agreement of a cipher suite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyExchange {
+    /// ECDH over Curve25519.
+    X25519,
+    /// ECDH over NIST P-256.
+    P256,
+}
+
+/// Key agreement, hash and authenticated cipher of a secure channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CipherSuite {
+    /// Id of the suite in offers, never 0.
+    pub id: u8,
+    /// Name of the suite, for logs.
+    pub name: &'static str,
+    /// Key agreement.
+    pub key_exchange: KeyExchange,
+    /// Hash.
+    pub hash: SuiteHash,
+    /// Authenticated cipher.
+    pub cipher: Cipher,
+    /// Whether the suite is only kept for older remote devices, see `allow_deprecated_suites`.
+    pub deprecated: bool,
+}
+
+/// Cipher suites supported, in order of preference, the deprecated ones last.
+pub const CIPHER_SUITES: [CipherSuite; 1] = [CipherSuite {
+    id: 1,
+    name: "X25519_AES_256_GCM_SHA256",
+    key_exchange: KeyExchange::X25519,
+    hash: SuiteHash::Sha256,
+    cipher: Cipher::Aes256Gcm,
+    deprecated: false,
+}];
+
+impl CipherSuite {
+    /// Returns the supported suite of `id`.
+    pub fn from_id(id: u8) -> Option<Self> {
+        CIPHER_SUITES.into_iter().find(|suite| suite.id == id)
+    }
+
+    /// Returns the supported suites, in order of preference, without the deprecated ones unless
+    /// `allow_deprecated`.
+    pub fn allowed(allow_deprecated: bool) -> Vec<Self> {
+        allowed_of(&CIPHER_SUITES, allow_deprecated)
+    }
+
+    /// Returns the COSE algorithm of the keys of the secure channel.
+    pub fn algorithm(&self) -> Algorithm {
+        match self.cipher {
+            Cipher::Aes128Gcm => Algorithm::A128Gcm,
+            Cipher::Aes256Gcm => Algorithm::A256Gcm,
+            Cipher::ChaCha20Poly1305 => Algorithm::ChaCha20Poly1305,
+        }
+    }
+}
+
+fn allowed_of(suites: &[CipherSuite], allow_deprecated: bool) -> Vec<CipherSuite> {
+    suites.iter().copied().filter(|suite| allow_deprecated || !suite.deprecated).collect()
+}
+
+/// Offer of cipher suites, in order of preference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuiteOffer {
+    /// Ids of the suites offered, including those unknown to this side.
+    pub suites: Vec<u8>,
+}
+
+/// Answer to a `SuiteOffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuiteSelect {
+    /// Id of the suite selected, or 0 if none.
+    pub suite: u8,
+}
+
+impl SuiteSelect {
+    /// Returns the id of the selected suite, or None if the offer was refused.
+    pub fn suite(&self) -> Option<u8> {
+        Some(self.suite).filter(|suite| *suite != REFUSED)
+    }
+}
+
+impl Message for SuiteOffer {
+    const TYPE: u8 = 27;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "suite_offer",
+        fields: &[Field::new(1, "suites", Rule::Bytes { min_len: 1, max_len: MAX_OFFERED_SUITES })],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.suites);
+    }
+
+    /// Suites unknown to this side are kept, so that the offer reads back as it was sent.
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { suites: reader.bytes()? })
+    }
+}
+
+impl Request for SuiteOffer {
+    type Response = SuiteSelect;
+}
+
+impl Message for SuiteSelect {
+    const TYPE: u8 = 28;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "suite_select",
+        fields: &[Field::new(1, "suite", Rule::Uint { min: 0, max: u8::MAX as u64 })],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.suite.into());
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let suite = reader.u32()?;
+        Ok(Self { suite: u8::try_from(suite).map_err(|_| DecodeError::InvalidField(1))? })
+    }
+}
+
+/// Answers an offer of the remote device with the suite of it allowed here, per
+/// `allow_deprecated`, that this side prefers: a deprecated suite only if the offer has no other.
+/// Refuses it if there is none, e.g. because it only lists deprecated suites and they are not
+/// allowed.
+pub fn answer_offer(offer: &SuiteOffer, allow_deprecated: bool) -> SuiteSelect {
+    select(offer, &CIPHER_SUITES, allow_deprecated)
+}
+
+fn select(offer: &SuiteOffer, suites: &[CipherSuite], allow_deprecated: bool) -> SuiteSelect {
+    let allowed = allowed_of(suites, allow_deprecated);
+    let selected = allowed.iter().find(|suite| offer.suites.contains(&suite.id));
+    match selected {
+        Some(suite) => {
+            log_deprecated(suite);
+            SuiteSelect { suite: suite.id }
+        }
+        None => {
+            warn!(
+                "refusing cipher suites {:?} (deprecated allowed: {})",
+                offer.suites, allow_deprecated
+            );
+            SuiteSelect { suite: REFUSED }
+        }
+    }
+}
+
+fn log_deprecated(suite: &CipherSuite) {
+    if suite.deprecated {
+        warn!("falling back to deprecated cipher suite {}", suite.name);
+    }
+}
+
+/// Handshake offering the allowed suites, and checking the one the remote device selects.
+pub struct SuiteNegotiation {
+    suites: Vec<CipherSuite>,
+    offer: SuiteOffer,
+    encoding: Encoding,
+}
+
+impl SuiteNegotiation {
+    /// Creates a negotiation offering the suites allowed per `allow_deprecated`, in `encoding`.
+    pub fn new(allow_deprecated: bool, encoding: Encoding) -> Self {
+        Self::offering(allowed_of(&CIPHER_SUITES, allow_deprecated), encoding)
+    }
+
+    fn offering(suites: Vec<CipherSuite>, encoding: Encoding) -> Self {
+        let offer = SuiteOffer { suites: suites.iter().map(|suite| suite.id).collect() };
+        Self { suites, offer, encoding }
+    }
+}
+
+impl Handshake for SuiteNegotiation {
+    type Output = CipherSuite;
+    const NAME: &'static str = "cipher suite negotiation";
+
+    fn first_message(&mut self) -> Vec<u8> {
+        self.offer.encode_as(self.encoding)
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<CipherSuite>> {
+        let select: SuiteSelect = decode_response(reply, self.encoding)?;
+        let Some(id) = select.suite() else {
+            return Err(SuiteError::Refused(self.offer.suites.clone()).into());
+        };
+        // Only suites of the offer, all supported, are accepted.
+        match self.suites.iter().find(|suite| suite.id == id) {
+            Some(suite) => Ok(Step::Done(*suite)),
+            None => Err(SuiteError::NotOffered(id).into()),
+        }
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Negotiates the cipher suite of `connection_id`, after its capability exchange, offering
+    /// the deprecated suites only if the config allows them. Returns the suite, and keeps it for
+    /// `cipher_suite`. On failure, the connection should be closed: it would only be secured with
+    /// a suite that is not allowed, if at all.
+    pub async fn negotiate_cipher_suite(
+        &self,
+        connection_id: i32,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<CipherSuite> {
+        let allow_deprecated = config::snapshot().allow_deprecated_suites;
+        let negotiation = SuiteNegotiation::new(allow_deprecated, self.encoding(connection_id));
+        let mut machine = HandshakeMachine::new(negotiation);
+        let suite = match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(suite) => suite,
+            Err(e) => {
+                error!("cipher suite negotiation of connection {} failed: {:#}", connection_id, e);
+                return Err(e);
+            }
+        };
+        log_deprecated(&suite);
+        info!("connection {} uses cipher suite {}", connection_id, suite.name);
+        self.set_cipher_suite(connection_id, suite);
+        Ok(suite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Not implemented: stands for a suite kept for older remote devices.
+    const LEGACY: CipherSuite = CipherSuite {
+        id: 4,
+        name: "P256_AES_128_GCM_SHA256",
+        key_exchange: KeyExchange::P256,
+        hash: SuiteHash::Sha256,
+        cipher: Cipher::Aes128Gcm,
+        deprecated: true,
+    };
+
+    #[test]
+    fn test_registry() {
+        for suite in CIPHER_SUITES {
+            assert_ne!(suite.id, REFUSED);
+            assert_eq!(CipherSuite::from_id(suite.id), Some(suite));
+        }
+        // Deprecated suites come last, so that they are never preferred.
+        let allowed = CipherSuite::allowed(false);
+        assert_eq!(CipherSuite::allowed(true)[..allowed.len()], allowed[..]);
+        assert!(allowed.iter().all(|suite| !suite.deprecated));
+        assert_eq!(CipherSuite::from_id(LEGACY.id), None);
+        assert_eq!(CipherSuite::from_id(1).unwrap().algorithm(), Algorithm::A256Gcm);
+    }
+
+    #[test]
+    fn test_answer_offer() {
+        let offer = SuiteOffer { suites: vec![9, 4, 1] };
+        assert_eq!(SuiteOffer::decode(&offer.encode()), Ok(offer.clone()));
+        assert_eq!(answer_offer(&offer, true).suite(), Some(1));
+        assert_eq!(answer_offer(&offer, false).suite(), Some(1));
+        // Suites not implemented here are never selected.
+        assert_eq!(answer_offer(&SuiteOffer { suites: vec![2, 3, 4] }, true).suite(), None);
+        assert_eq!(answer_offer(&SuiteOffer { suites: vec![9] }, true).suite(), None);
+
+        // The preference of this side wins over the order of the offer.
+        let suites = [CIPHER_SUITES[0], LEGACY];
+        assert_eq!(select(&SuiteOffer { suites: vec![4, 1] }, &suites, true).suite(), Some(1));
+        let deprecated_only = SuiteOffer { suites: vec![4] };
+        assert_eq!(select(&deprecated_only, &suites, true).suite(), Some(4));
+        assert_eq!(select(&deprecated_only, &suites, false).suite(), None);
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let reply = |suite| SuiteSelect { suite }.encode();
+        let mut negotiation = SuiteNegotiation::new(true, Encoding::default());
+        let offer = SuiteOffer::decode(&negotiation.first_message()).unwrap();
+        assert_eq!(offer.suites, vec![1]);
+        assert_eq!(
+            negotiation.on_reply(&reply(1)).unwrap(),
+            Step::Done(CipherSuite::from_id(1).unwrap())
+        );
+        // Suites not implemented are neither offered nor accepted.
+        let error = negotiation.on_reply(&reply(2)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&SuiteError::NotOffered(2)));
+        let error = negotiation.on_reply(&reply(REFUSED)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&SuiteError::Refused(vec![1])));
+
+        // A deprecated suite, not offered, is refused even though it is supported.
+        let suites = [CIPHER_SUITES[0], LEGACY];
+        let mut negotiation =
+            SuiteNegotiation::offering(allowed_of(&suites, false), Encoding::default());
+        assert_eq!(SuiteOffer::decode(&negotiation.first_message()).unwrap().suites, vec![1]);
+        let error = negotiation.on_reply(&reply(4)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&SuiteError::NotOffered(4)));
+
+        let mut negotiation =
+            SuiteNegotiation::offering(allowed_of(&suites, true), Encoding::default());
+        assert_eq!(SuiteOffer::decode(&negotiation.first_message()).unwrap().suites, vec![1, 4]);
+        assert!(
+            matches!(negotiation.on_reply(&reply(4)), Ok(Step::Done(suite)) if suite.deprecated)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Deterministic CBOR (RFC 8949), the wire format of typed messages.
+//!
+//! Encoding is canonical: shortest integers and lengths, definite lengths, and map entries sorted
+//! by the encoding of their key. Decoding only accepts canonical input within `DecodeLimits`, so
+//! that a value has a single encoding, e.g. to be signed.
+
+use thiserror::Error;
+
+pub use ciborium::value::{Integer, Value};
+
+/// Limits on the input of `decode`, bounding the work of decoding untrusted bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Longest input accepted, in bytes.
+    pub max_len: usize,
+    /// Deepest nesting of arrays, maps and tags accepted.
+    pub max_depth: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self { max_len: 64 * 1024, max_depth: 16 }
+    }
+}
+
+/// Why bytes could not be decoded as CBOR.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum CborError {
+    /// The input is longer than `DecodeLimits::max_len`.
+    #[error("input of {0} bytes is too long")]
+    TooLong(usize),
+    /// The input nests deeper than `DecodeLimits::max_depth`.
+    #[error("input nests too deep")]
+    TooDeep,
+    /// The input is not well-formed CBOR.
+    #[error("invalid CBOR: {0}")]
+    Invalid(String),
+    /// Bytes are left after the value.
+    #[error("{0} trailing bytes after the value")]
+    TrailingBytes(usize),
+    /// The value is well-formed, but not in its canonical encoding.
+    #[error("non-canonical encoding")]
+    NotCanonical,
+    /// A map has the same key more than once.
+    #[error("duplicate map key")]
+    DuplicateKey,
+}
+
+/// Sorts the map entries of `value` and its children by the encoding of their key.
+fn canonicalize(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(canonicalize),
+        Value::Tag(_, inner) => canonicalize(inner),
+        Value::Map(entries) => {
+            for (key, value) in entries.iter_mut() {
+                canonicalize(key);
+                canonicalize(value);
+            }
+            entries.sort_by_cached_key(|(key, _)| encode_canonical(key));
+        }
+        _ => {}
+    }
+}
+
+/// Fails if a map of `value` or its children has the same key twice.
+fn check_unique_keys(value: &Value) -> Result<(), CborError> {
+    match value {
+        Value::Array(items) => items.iter().try_for_each(check_unique_keys),
+        Value::Tag(_, inner) => check_unique_keys(inner),
+        Value::Map(entries) => {
+            // The entries of a canonical map are sorted, so equal keys are next to each other.
+            if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(CborError::DuplicateKey);
+            }
+            entries.iter().try_for_each(|(key, value)| {
+                check_unique_keys(key)?;
+                check_unique_keys(value)
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Encodes a value whose maps are already sorted.
+fn encode_canonical(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes).expect("writing to a Vec doesn't fail");
+    bytes
+}
+
+/// Encodes `value` canonically.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut value = value.clone();
+    canonicalize(&mut value);
+    encode_canonical(&value)
+}
+
+/// Decodes the single value of `bytes`, which must be its canonical encoding without duplicate map
+/// keys.
+pub fn decode(bytes: &[u8], limits: DecodeLimits) -> Result<Value, CborError> {
+    if bytes.len() > limits.max_len {
+        return Err(CborError::TooLong(bytes.len()));
+    }
+    let mut reader = bytes;
+    let value: Value =
+        ciborium::de::from_reader_with_recursion_limit(&mut reader, limits.max_depth).map_err(
+            |e| match e {
+                ciborium::de::Error::RecursionLimitExceeded => CborError::TooDeep,
+                e => CborError::Invalid(format!("{:?}", e)),
+            },
+        )?;
+    if !reader.is_empty() {
+        return Err(CborError::TrailingBytes(reader.len()));
+    }
+    if encode(&value) != bytes {
+        return Err(CborError::NotCanonical);
+    }
+    check_unique_keys(&value)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let value = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Bytes(vec![1, 2])),
+            (Value::Integer(0.into()), Value::Array(vec![Value::Text("a".into())])),
+        ]);
+        let bytes = encode(&value);
+        assert_eq!(bytes, vec![0xa2, 0x00, 0x81, 0x61, b'a', 0x01, 0x42, 1, 2]);
+        let decoded = decode(&bytes, DecodeLimits::default()).unwrap();
+        assert_eq!(encode(&decoded), bytes);
+    }
+
+    #[test]
+    fn test_decode_strict() {
+        let limits = DecodeLimits::default();
+        // 1 on two bytes instead of one.
+        assert_eq!(decode(&[0x18, 0x01], limits), Err(CborError::NotCanonical));
+        // Unsorted map keys.
+        assert_eq!(decode(&[0xa2, 0x01, 0x00, 0x00, 0x00], limits), Err(CborError::NotCanonical));
+        // Indefinite-length array.
+        assert_eq!(decode(&[0x9f, 0x00, 0xff], limits), Err(CborError::NotCanonical));
+        assert_eq!(decode(&[0x00, 0x00], limits), Err(CborError::TrailingBytes(1)));
+        assert!(matches!(decode(&[0x42, 0x01], limits), Err(CborError::Invalid(_))));
+        assert_eq!(
+            decode(&[0x00], DecodeLimits { max_len: 0, ..limits }),
+            Err(CborError::TooLong(1))
+        );
+        let mut nested = vec![0x81; 20];
+        nested.push(0x80);
+        assert_eq!(decode(&nested, limits), Err(CborError::TooDeep));
+    }
+
+    #[test]
+    fn test_decode_duplicate_key() {
+        let limits = DecodeLimits::default();
+        // {0: 0, 0: 1}
+        assert_eq!(decode(&[0xa2, 0x00, 0x00, 0x00, 0x01], limits), Err(CborError::DuplicateKey));
+        // [{"a": 0, "a": 0}]
+        assert_eq!(
+            decode(&[0x81, 0xa2, 0x61, b'a', 0x00, 0x61, b'a', 0x00], limits),
+            Err(CborError::DuplicateKey)
+        );
+    }
+}
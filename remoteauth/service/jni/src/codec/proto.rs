@@ -0,0 +1,122 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Protobuf, the alternative wire format of typed messages for devices already speaking it.
+//!
+//! Messages travel as the `Envelope` of `proto/remoteauth.proto`.
+
+use protobuf::Message;
+use thiserror::Error;
+
+pub use remoteauth_proto::remoteauth::{envelope_field, Envelope, EnvelopeField};
+
+/// Number of the `fields` of an envelope, the messages it embeds.
+const FIELDS_NUMBER: u64 = 2;
+
+/// Why bytes could not be decoded as an envelope.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ProtoError {
+    /// The input is longer than the limit.
+    #[error("input of {0} bytes is too long")]
+    TooLong(usize),
+    /// The input is not a valid envelope.
+    #[error("invalid protobuf: {0}")]
+    Invalid(String),
+}
+
+/// Encodes `envelope`.
+pub fn encode(envelope: &Envelope) -> Vec<u8> {
+    envelope.write_to_bytes().expect("writing an envelope to a Vec doesn't fail")
+}
+
+/// Decodes an envelope from `bytes`, of at most `max_len` bytes.
+pub fn decode(bytes: &[u8], max_len: usize) -> Result<Envelope, ProtoError> {
+    if bytes.len() > max_len {
+        return Err(ProtoError::TooLong(bytes.len()));
+    }
+    // protobuf takes an embedded message cut short for one ending with the input.
+    if !fields_fit(bytes, &[FIELDS_NUMBER]) {
+        return Err(ProtoError::Invalid("truncated field".to_string()));
+    }
+    Envelope::parse_from_bytes(bytes).map_err(|e| ProtoError::Invalid(e.to_string()))
+}
+
+/// Whether every field of `bytes` fits in it, and so do the fields of the messages embedded in
+/// the fields numbered `embedded`. Groups, which the envelope doesn't use, don't.
+fn fields_fit(mut bytes: &[u8], embedded: &[u64]) -> bool {
+    while !bytes.is_empty() {
+        let Some(key) = take_varint(&mut bytes) else { return false };
+        let fits = match key & 7 {
+            0 => take_varint(&mut bytes).is_some(),
+            1 => take(&mut bytes, 8).is_some(),
+            5 => take(&mut bytes, 4).is_some(),
+            2 => match take_varint(&mut bytes).and_then(|len| take(&mut bytes, len)) {
+                Some(field) => !embedded.contains(&(key >> 3)) || fields_fit(field, &[]),
+                None => false,
+            },
+            _ => false,
+        };
+        if !fits {
+            return false;
+        }
+    }
+    true
+}
+
+/// Takes the varint at the front of `bytes`.
+fn take_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Takes the `len` bytes at the front of `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], len: u64) -> Option<&'a [u8]> {
+    let len = usize::try_from(len).ok().filter(|len| *len <= bytes.len())?;
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Some(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut field = EnvelopeField::new();
+        field.number = 1;
+        field.value = Some(envelope_field::Value::Bytes(vec![1, 2]));
+        let mut envelope = Envelope::new();
+        envelope.message_type = 4;
+        envelope.fields.push(field);
+        let bytes = encode(&envelope);
+        assert_eq!(bytes, vec![0x08, 0x04, 0x12, 0x06, 0x08, 0x01, 0x1a, 0x02, 1, 2]);
+        assert_eq!(decode(&bytes, 64), Ok(envelope));
+        assert_eq!(decode(&bytes, 4), Err(ProtoError::TooLong(10)));
+        // Fields cut short, of the envelope then of the field it embeds.
+        for truncated in [&bytes[..4], &bytes[..9], &[0x12, 0x04, 0x08, 0x01, 0x1a, 0x05]] {
+            assert!(matches!(decode(truncated, 64), Err(ProtoError::Invalid(_))));
+        }
+    }
+}
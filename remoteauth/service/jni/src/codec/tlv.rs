@@ -0,0 +1,207 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tag-length-value encoding of compact control frames, e.g. keepalive, flow control or error
+//! frames, too small to be worth CBOR or protobuf.
+//!
+//! An element is a tag byte, a big-endian `u16` length, and that many bytes of value. Tags with
+//! `CONSTRUCTED` set hold a sequence of elements instead of bytes. Decoding never panics and
+//! bounds its work by `MAX_DEPTH` and `MAX_ELEMENTS`, whatever the input.
+
+use thiserror::Error;
+
+/// Bit of the tags of elements holding nested elements.
+pub const CONSTRUCTED: u8 = 0x80;
+/// Length of the tag and length of an element.
+pub const HEADER_LEN: usize = 3;
+/// Deepest nesting of constructed elements accepted.
+pub const MAX_DEPTH: usize = 4;
+/// Most elements accepted in a frame, nested ones included.
+pub const MAX_ELEMENTS: usize = 64;
+
+/// Why elements could not be encoded or decoded.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TlvError {
+    /// The value of an element is longer than a length can tell.
+    #[error("value of tag {0:#04x} is too long")]
+    ValueTooLong(u8),
+    /// The input ends in the middle of an element.
+    #[error("truncated element at offset {0}")]
+    Truncated(usize),
+    /// Constructed elements nest deeper than `MAX_DEPTH`.
+    #[error("elements nest too deep")]
+    TooDeep,
+    /// The input holds more than `MAX_ELEMENTS` elements.
+    #[error("too many elements")]
+    TooManyElements,
+}
+
+/// Value of an element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlvValue {
+    /// Bytes of a primitive element.
+    Bytes(Vec<u8>),
+    /// Elements of a constructed element.
+    Nested(Vec<Tlv>),
+}
+
+/// An element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+    tag: u8,
+    value: TlvValue,
+}
+
+impl Tlv {
+    /// Creates a primitive element.
+    ///
+    /// # Panics
+    ///
+    /// If `tag` has `CONSTRUCTED` set.
+    pub fn bytes(tag: u8, value: &[u8]) -> Self {
+        assert_eq!(tag & CONSTRUCTED, 0, "primitive tag {:#04x} is constructed", tag);
+        Self { tag, value: TlvValue::Bytes(value.to_vec()) }
+    }
+
+    /// Creates a constructed element, setting `CONSTRUCTED` in `tag`.
+    pub fn nested(tag: u8, elements: Vec<Tlv>) -> Self {
+        Self { tag: tag | CONSTRUCTED, value: TlvValue::Nested(elements) }
+    }
+
+    /// Returns the tag, `CONSTRUCTED` included.
+    pub fn tag(&self) -> u8 {
+        self.tag
+    }
+
+    /// Returns the value.
+    pub fn value(&self) -> &TlvValue {
+        &self.value
+    }
+
+    /// Returns the bytes of a primitive element.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match &self.value {
+            TlvValue::Bytes(bytes) => Some(bytes),
+            TlvValue::Nested(_) => None,
+        }
+    }
+
+    /// Returns the first nested element with `tag`, if this element is constructed.
+    pub fn find(&self, tag: u8) -> Option<&Tlv> {
+        match &self.value {
+            TlvValue::Bytes(_) => None,
+            TlvValue::Nested(elements) => elements.iter().find(|element| element.tag == tag),
+        }
+    }
+
+    fn encode_into(&self, bytes: &mut Vec<u8>) -> Result<(), TlvError> {
+        bytes.push(self.tag);
+        let length_at = bytes.len();
+        bytes.extend_from_slice(&[0, 0]);
+        match &self.value {
+            TlvValue::Bytes(value) => bytes.extend_from_slice(value),
+            TlvValue::Nested(elements) => {
+                for element in elements {
+                    element.encode_into(bytes)?;
+                }
+            }
+        }
+        let len = u16::try_from(bytes.len() - length_at - 2)
+            .map_err(|_| TlvError::ValueTooLong(self.tag))?;
+        bytes[length_at..length_at + 2].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Encodes `elements` one after the other.
+pub fn encode(elements: &[Tlv]) -> Result<Vec<u8>, TlvError> {
+    let mut bytes = Vec::new();
+    for element in elements {
+        element.encode_into(&mut bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// Decodes the elements of `bytes`, which must end with the last one.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Tlv>, TlvError> {
+    let mut count = 0;
+    decode_at(bytes, 0, 0, &mut count)
+}
+
+/// Decodes the elements of `bytes`, found at `offset` of the input at nesting `depth`.
+fn decode_at(
+    bytes: &[u8],
+    offset: usize,
+    depth: usize,
+    count: &mut usize,
+) -> Result<Vec<Tlv>, TlvError> {
+    let mut elements = Vec::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        *count += 1;
+        if *count > MAX_ELEMENTS {
+            return Err(TlvError::TooManyElements);
+        }
+        let header = bytes.get(at..at + HEADER_LEN).ok_or(TlvError::Truncated(offset + at))?;
+        let tag = header[0];
+        let len = usize::from(u16::from_be_bytes([header[1], header[2]]));
+        let value_at = at + HEADER_LEN;
+        let value = bytes.get(value_at..value_at + len).ok_or(TlvError::Truncated(offset + at))?;
+        let value = if tag & CONSTRUCTED == 0 {
+            TlvValue::Bytes(value.to_vec())
+        } else if depth == MAX_DEPTH {
+            return Err(TlvError::TooDeep);
+        } else {
+            TlvValue::Nested(decode_at(value, offset + value_at, depth + 1, count)?)
+        };
+        elements.push(Tlv { tag, value });
+        at = value_at + len;
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let elements = vec![
+            Tlv::bytes(0x01, &[]),
+            Tlv::nested(0x02, vec![Tlv::bytes(0x03, &[7, 8]), Tlv::nested(0x04, vec![])]),
+        ];
+        let bytes = encode(&elements).unwrap();
+        assert_eq!(bytes, vec![0x01, 0, 0, 0x82, 0, 8, 0x03, 0, 2, 7, 8, 0x84, 0, 0]);
+        assert_eq!(decode(&bytes), Ok(elements.clone()));
+        assert_eq!(elements[1].find(0x03).and_then(Tlv::as_bytes), Some(&[7, 8][..]));
+        assert_eq!(encode(&[Tlv::bytes(0x01, &[0; 65_536])]), Err(TlvError::ValueTooLong(0x01)));
+    }
+
+    #[test]
+    fn test_decode_strict() {
+        assert_eq!(decode(&[]), Ok(vec![]));
+        assert_eq!(decode(&[0x01, 0]), Err(TlvError::Truncated(0)));
+        assert_eq!(decode(&[0x01, 0, 0, 0x01, 0, 2, 7]), Err(TlvError::Truncated(3)));
+        assert_eq!(decode(&[0x81, 0, 3, 0x01, 0, 1]), Err(TlvError::Truncated(3)));
+        let mut nested = Tlv::bytes(0x01, &[]);
+        for _ in 0..=MAX_DEPTH {
+            nested = Tlv::nested(0x01, vec![nested]);
+        }
+        assert_eq!(decode(&encode(&[nested]).unwrap()), Err(TlvError::TooDeep));
+        let many = vec![Tlv::bytes(0x01, &[]); MAX_ELEMENTS + 1];
+        assert_eq!(decode(&encode(&many).unwrap()), Err(TlvError::TooManyElements));
+    }
+}
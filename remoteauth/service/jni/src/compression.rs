@@ -0,0 +1,78 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Deflate compression of large messages, used by framing on connections whose capabilities
+//! allow it.
+//!
+//! Decompression stops at a maximum length, so that a small message can't expand into a
+//! decompression bomb.
+
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+use std::io::Read;
+
+/// Shortest message worth compressing, in bytes.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Why a message could not be decompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DecompressError {
+    /// The message is not a deflate stream.
+    Invalid,
+    /// The message decompresses to more than the maximum length.
+    TooLarge,
+}
+
+/// Compresses `message` if it is at least `COMPRESSION_THRESHOLD` long, and returns it if
+/// shorter than `message`.
+pub(crate) fn compress(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    let mut compressed = Vec::with_capacity(message.len());
+    DeflateEncoder::new(message, Compression::default()).read_to_end(&mut compressed).ok()?;
+    (compressed.len() < message.len()).then_some(compressed)
+}
+
+/// Decompresses `compressed`, into at most `max_len` bytes.
+pub(crate) fn decompress(compressed: &[u8], max_len: usize) -> Result<Vec<u8>, DecompressError> {
+    let limit = u64::try_from(max_len).unwrap_or(u64::MAX).saturating_add(1);
+    let mut message = Vec::new();
+    DeflateDecoder::new(compressed)
+        .take(limit)
+        .read_to_end(&mut message)
+        .map_err(|_| DecompressError::Invalid)?;
+    if message.len() > max_len {
+        return Err(DecompressError::TooLarge);
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        assert_eq!(compress(&[0; COMPRESSION_THRESHOLD - 1]), None);
+        let message = [7; 4096];
+        let compressed = compress(&message).unwrap();
+        assert!(compressed.len() < 64);
+        assert_eq!(decompress(&compressed, 4096), Ok(message.to_vec()));
+        assert_eq!(decompress(&compressed, 4095), Err(DecompressError::TooLarge));
+        assert_eq!(decompress(&[0xff; 8], 4096), Err(DecompressError::Invalid));
+    }
+}
@@ -0,0 +1,225 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Native values that can be tuned while the process runs.
+//!
+//! Readers take a snapshot: an `Arc` of the whole config, so the values they see are consistent
+//! with each other even if Java updates them in the meantime. Updates replace the snapshot.
+
+use log::LevelFilter;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Key of the timeout of requests that don't specify one, in milliseconds.
+pub const DEFAULT_TIMEOUT_MILLIS_KEY: &str = "default_timeout_millis";
+/// Key of the largest request payload accepted, in bytes.
+pub const MAX_PAYLOAD_SIZE_KEY: &str = "max_payload_size";
+/// Key of the maximum log level, as a `log::LevelFilter` name, e.g. "info".
+pub const LOG_LEVEL_KEY: &str = "log_level";
+/// Key of the number of times a failed request is retried.
+pub const MAX_RETRIES_KEY: &str = "max_retries";
+/// Key of the number of requests outstanding per connection of new platforms, 0 for no limit.
+pub const PIPELINE_WINDOW_KEY: &str = "pipeline_window";
+/// Key of the sizes secure channel payloads are padded to, comma-separated, e.g. "64,256". Empty
+/// for no padding.
+pub const PADDING_BUCKETS_KEY: &str = "padding_buckets";
+/// Key of the number of payloads a ratcheting secure channel seals before rotating its key, 0
+/// for no limit.
+pub const REKEY_MESSAGES_KEY: &str = "rekey_messages";
+/// Key of the time a ratcheting secure channel keeps a key before rotating it, in seconds, 0 for
+/// no limit.
+pub const REKEY_INTERVAL_SECS_KEY: &str = "rekey_interval_secs";
+/// Key of whether deprecated cipher suites are still offered and accepted, "true" or "false".
+pub const ALLOW_DEPRECATED_SUITES_KEY: &str = "allow_deprecated_suites";
+
+/// Runtime-tunable values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunableConfig {
+    /// Timeout of requests that don't specify one.
+    pub default_timeout: Duration,
+    /// Largest request payload accepted, in bytes.
+    pub max_payload_size: usize,
+    /// Maximum level of the logs emitted by the library.
+    pub log_level: LevelFilter,
+    /// Number of times a failed request is retried.
+    pub max_retries: u32,
+    /// Requests outstanding per connection of platforms created from now on, unless they set a
+    /// limit of their own. 0 for no limit.
+    pub pipeline_window: usize,
+    /// Sizes secure channel payloads are padded to, ascending, without 0. Empty for no padding.
+    pub padding_buckets: Vec<usize>,
+    /// Payloads a ratcheting secure channel seals before rotating its key. 0 for no limit.
+    pub rekey_messages: u32,
+    /// Time a ratcheting secure channel keeps a key before rotating it. 0 for no limit.
+    pub rekey_interval: Duration,
+    /// Whether connections negotiated from now on may use deprecated cipher suites.
+    pub allow_deprecated_suites: bool,
+}
+
+impl Default for TunableConfig {
+    fn default() -> Self {
+        Self {
+            default_timeout: Duration::from_secs(10),
+            max_payload_size: 64 * 1024,
+            log_level: LevelFilter::Trace,
+            max_retries: 0,
+            pipeline_window: 0,
+            padding_buckets: Vec::new(),
+            rekey_messages: 1 << 16,
+            rekey_interval: Duration::from_secs(10 * 60),
+            allow_deprecated_suites: true,
+        }
+    }
+}
+
+/// Rejected configuration update.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The key is not a tunable value.
+    #[error("unknown config key {0}")]
+    UnknownKey(String),
+    /// The value can't be parsed for the key.
+    #[error("invalid value {value:?} for config key {key}")]
+    InvalidValue {
+        /// Key being updated.
+        key: String,
+        /// Rejected value.
+        value: String,
+    },
+}
+
+impl TunableConfig {
+    /// Returns a copy of the config with `key` set to `value`.
+    pub fn with_value(&self, key: &str, value: &str) -> Result<Self, ConfigError> {
+        let invalid =
+            || ConfigError::InvalidValue { key: key.to_string(), value: value.to_string() };
+        let mut config = self.clone();
+        match key {
+            DEFAULT_TIMEOUT_MILLIS_KEY => {
+                config.default_timeout =
+                    Duration::from_millis(value.trim().parse().map_err(|_| invalid())?)
+            }
+            MAX_PAYLOAD_SIZE_KEY => {
+                config.max_payload_size = value.trim().parse().map_err(|_| invalid())?
+            }
+            LOG_LEVEL_KEY => {
+                config.log_level = LevelFilter::from_str(value.trim()).map_err(|_| invalid())?
+            }
+            MAX_RETRIES_KEY => config.max_retries = value.trim().parse().map_err(|_| invalid())?,
+            PIPELINE_WINDOW_KEY => {
+                config.pipeline_window = value.trim().parse().map_err(|_| invalid())?
+            }
+            PADDING_BUCKETS_KEY => {
+                let mut buckets = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|bucket| !bucket.is_empty())
+                    .map(|bucket| bucket.parse().map_err(|_| invalid()))
+                    .collect::<Result<Vec<usize>, _>>()?;
+                buckets.retain(|bucket| *bucket > 0);
+                buckets.sort_unstable();
+                buckets.dedup();
+                config.padding_buckets = buckets
+            }
+            REKEY_MESSAGES_KEY => {
+                config.rekey_messages = value.trim().parse().map_err(|_| invalid())?
+            }
+            REKEY_INTERVAL_SECS_KEY => {
+                config.rekey_interval =
+                    Duration::from_secs(value.trim().parse().map_err(|_| invalid())?)
+            }
+            ALLOW_DEPRECATED_SUITES_KEY => {
+                config.allow_deprecated_suites = value.trim().parse().map_err(|_| invalid())?
+            }
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(config)
+    }
+}
+
+static CONFIG: Mutex<Option<Arc<TunableConfig>>> = Mutex::new(None);
+
+/// Returns the current values.
+pub fn snapshot() -> Arc<TunableConfig> {
+    Arc::clone(CONFIG.lock().unwrap().get_or_insert_with(Default::default))
+}
+
+/// Replaces all values at once.
+pub(crate) fn replace(config: TunableConfig) -> Arc<TunableConfig> {
+    let config = Arc::new(config);
+    *CONFIG.lock().unwrap() = Some(Arc::clone(&config));
+    config
+}
+
+/// Sets `key` to `value`. Returns the old and new snapshots.
+pub(crate) fn set(
+    key: &str,
+    value: &str,
+) -> Result<(Arc<TunableConfig>, Arc<TunableConfig>), ConfigError> {
+    let mut current = CONFIG.lock().unwrap();
+    let old = Arc::clone(current.get_or_insert_with(Default::default));
+    let new = Arc::new(old.with_value(key, value)?);
+    *current = Some(Arc::clone(&new));
+    Ok((old, new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_value() {
+        let config = TunableConfig::default();
+        assert_eq!(
+            config.with_value(DEFAULT_TIMEOUT_MILLIS_KEY, "2500").unwrap().default_timeout,
+            Duration::from_millis(2500)
+        );
+        assert_eq!(config.with_value(MAX_PAYLOAD_SIZE_KEY, " 512 ").unwrap().max_payload_size, 512);
+        assert_eq!(config.with_value(LOG_LEVEL_KEY, "warn").unwrap().log_level, LevelFilter::Warn);
+        assert_eq!(config.with_value(MAX_RETRIES_KEY, "3").unwrap().max_retries, 3);
+        assert_eq!(config.with_value(PIPELINE_WINDOW_KEY, "4").unwrap().pipeline_window, 4);
+        assert_eq!(
+            config.with_value(PADDING_BUCKETS_KEY, "256, 64,0,64").unwrap().padding_buckets,
+            vec![64, 256]
+        );
+        assert_eq!(config.with_value(PADDING_BUCKETS_KEY, "").unwrap().padding_buckets, vec![]);
+        assert_eq!(config.with_value(REKEY_MESSAGES_KEY, "1000").unwrap().rekey_messages, 1000);
+        assert_eq!(
+            config.with_value(REKEY_INTERVAL_SECS_KEY, "0").unwrap().rekey_interval,
+            Duration::ZERO
+        );
+        let strict = config.with_value(ALLOW_DEPRECATED_SUITES_KEY, "false").unwrap();
+        assert!(!strict.allow_deprecated_suites);
+    }
+
+    #[test]
+    fn test_with_value_rejects_bad_input() {
+        let config = TunableConfig::default();
+        assert_eq!(
+            config.with_value("unknown", "1"),
+            Err(ConfigError::UnknownKey(String::from("unknown")))
+        );
+        assert_eq!(
+            config.with_value(MAX_RETRIES_KEY, "-1"),
+            Err(ConfigError::InvalidValue {
+                key: String::from(MAX_RETRIES_KEY),
+                value: String::from("-1")
+            })
+        );
+    }
+}
@@ -0,0 +1,71 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Transport connection state changes reported by Java.
+//!
+//! Each platform broadcasts them, so native subsystems such as retries or keepalive can react
+//! to a connection going away without polling Java.
+
+use jni::sys::jint;
+
+/// Events a subscriber may fall behind by before it starts missing the oldest ones.
+pub(crate) const CONNECTION_EVENTS_CAPACITY: usize = 16;
+
+/// State of a transport connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection is up.
+    Connected,
+    /// The connection is down: requests on it will not get a response.
+    Disconnected,
+    /// The connection is up but unreliable, e.g. with a weak signal.
+    Degraded,
+}
+
+impl ConnectionState {
+    /// Converts the value Java passes, or returns None if it is unknown.
+    pub(crate) fn from_java(value: jint) -> Option<Self> {
+        match value {
+            0 => Some(Self::Connected),
+            1 => Some(Self::Disconnected),
+            2 => Some(Self::Degraded),
+            _ => None,
+        }
+    }
+}
+
+/// A connection changed state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionEvent {
+    /// Connection id, as passed to `send_request`.
+    pub id: i32,
+    /// New state of the connection.
+    pub state: ConnectionState,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_from_java() {
+        assert_eq!(ConnectionState::from_java(0), Some(ConnectionState::Connected));
+        assert_eq!(ConnectionState::from_java(1), Some(ConnectionState::Disconnected));
+        assert_eq!(ConnectionState::from_java(2), Some(ConnectionState::Degraded));
+        assert_eq!(ConnectionState::from_java(3), None);
+        assert_eq!(ConnectionState::from_java(-1), None);
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Properties of a transport connection, queried from Java.
+//!
+//! Java returns them as an int array, `[mtu, transport, rssi, latencyMillis]`, with
+//! `Integer.MIN_VALUE` for an unknown RSSI and a negative latency when there is no estimate.
+
+use jni::sys::jint;
+use std::time::Duration;
+
+const MTU_INDEX: usize = 0;
+const TRANSPORT_INDEX: usize = 1;
+const RSSI_INDEX: usize = 2;
+const LATENCY_MILLIS_INDEX: usize = 3;
+/// Number of values in the array Java returns.
+pub(crate) const CONNECTION_INFO_LEN: usize = 4;
+
+/// Transport a connection runs over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Bluetooth Low Energy.
+    Ble,
+    /// Wi-Fi Aware.
+    WifiAware,
+    /// Ultra-wideband.
+    Uwb,
+}
+
+impl Transport {
+    /// Converts the value Java passes, or returns None if it is unknown.
+    pub(crate) fn from_java(value: jint) -> Option<Self> {
+        match value {
+            0 => Some(Self::Ble),
+            1 => Some(Self::WifiAware),
+            2 => Some(Self::Uwb),
+            _ => None,
+        }
+    }
+}
+
+/// Properties of a connection, for layers adapting payload sizes or proximity decisions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Largest payload a single transport packet carries, in bytes.
+    pub mtu: usize,
+    /// Transport of the connection.
+    pub transport: Transport,
+    /// Signal strength in dBm, if the transport reports it.
+    pub rssi: Option<i32>,
+    /// Estimated one-way latency of the link, if known.
+    pub latency: Option<Duration>,
+}
+
+impl ConnectionInfo {
+    /// Converts the array Java returns, or returns None if it is malformed.
+    pub(crate) fn from_java(values: &[jint]) -> Option<Self> {
+        if values.len() != CONNECTION_INFO_LEN {
+            return None;
+        }
+        Some(Self {
+            mtu: values[MTU_INDEX].try_into().ok()?,
+            transport: Transport::from_java(values[TRANSPORT_INDEX])?,
+            rssi: Some(values[RSSI_INDEX]).filter(|rssi| *rssi != jint::MIN),
+            latency: u64::try_from(values[LATENCY_MILLIS_INDEX]).ok().map(Duration::from_millis),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_info_from_java() {
+        assert_eq!(
+            ConnectionInfo::from_java(&[247, 0, -60, 15]),
+            Some(ConnectionInfo {
+                mtu: 247,
+                transport: Transport::Ble,
+                rssi: Some(-60),
+                latency: Some(Duration::from_millis(15)),
+            })
+        );
+        assert_eq!(
+            ConnectionInfo::from_java(&[1400, 2, jint::MIN, -1]),
+            Some(ConnectionInfo {
+                mtu: 1400,
+                transport: Transport::Uwb,
+                rssi: None,
+                latency: None
+            })
+        );
+        assert_eq!(ConnectionInfo::from_java(&[247, 3, -60, 15]), None);
+        assert_eq!(ConnectionInfo::from_java(&[-1, 0, -60, 15]), None);
+        assert_eq!(ConnectionInfo::from_java(&[247, 0, -60]), None);
+    }
+}
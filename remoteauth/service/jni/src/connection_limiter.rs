@@ -0,0 +1,358 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Per-connection limit of outstanding requests.
+//!
+//! Each connection gets a fixed number of slots, acting as a semaphore. A request that finds
+//! them all taken is either rejected or queued, and a queued request takes over the slot of the
+//! next request to complete on its connection. Queued requests are sent by priority, then in
+//! the order they were queued.
+//!
+//! An ordered limiter instead sends the requests of each connection one at a time, strictly in
+//! the order they were admitted, for protocols that can't handle them out of order.
+//!
+//! A paused connection, e.g. congested, queues every request until it is resumed, whatever its
+//! limit.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// What happens to a request sent on a connection that has no slot left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Fails the request right away with `Busy`.
+    Reject,
+    /// Sends the request once an earlier one on the same connection completes.
+    Queue,
+}
+
+/// Urgency of a request, deciding which queued request of a congested connection goes next.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Traffic nobody is waiting on, e.g. background sync.
+    Background,
+    /// Regular requests.
+    #[default]
+    Normal,
+    /// Requests on the unlock path, with a user waiting.
+    UnlockCritical,
+}
+
+/// Outcome of `ConnectionLimiter::admit`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Admission<T> {
+    /// A slot was taken: the request can be sent now.
+    Granted(T),
+    /// No slot was left, the request was queued.
+    Queued,
+    /// No slot was left and the connection doesn't queue.
+    Busy(T),
+}
+
+#[derive(Debug)]
+struct Slots<T> {
+    in_flight: usize,
+    // Ordered by decreasing priority, FIFO within a priority.
+    queued: VecDeque<(Priority, T)>,
+    paused: bool,
+}
+
+impl<T> Slots<T> {
+    fn new() -> Self {
+        Self { in_flight: 0, queued: VecDeque::new(), paused: false }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight == 0 && self.queued.is_empty() && !self.paused
+    }
+
+    fn enqueue(&mut self, priority: Priority, request: T, by_priority: bool) {
+        if !by_priority {
+            self.queued.push_back((priority, request));
+            return;
+        }
+        let position = self.queued.partition_point(|(queued, _)| *queued >= priority);
+        self.queued.insert(position, (priority, request));
+    }
+}
+
+/// Hands out request slots per connection.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter<T> {
+    limit: Option<usize>,
+    backpressure: Backpressure,
+    // Whether queued requests may overtake those of lower priority.
+    by_priority: bool,
+    connections: Mutex<HashMap<i32, Slots<T>>>,
+}
+
+impl<T> ConnectionLimiter<T> {
+    /// Allows `limit` outstanding requests per connection, or any number if None.
+    pub(crate) fn new(limit: Option<usize>, backpressure: Backpressure) -> Self {
+        Self {
+            limit: limit.map(|limit| limit.max(1)),
+            backpressure,
+            by_priority: true,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allows a single outstanding request per connection, queuing the others in the order they
+    /// are admitted whatever their priority.
+    pub(crate) fn ordered() -> Self {
+        Self { by_priority: false, ..Self::new(Some(1), Backpressure::Queue) }
+    }
+
+    /// Takes a slot of `connection_id` for `request`, or queues it at `priority` or rejects it if
+    /// there is none.
+    pub(crate) fn admit(&self, connection_id: i32, priority: Priority, request: T) -> Admission<T> {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(slots) = connections.get_mut(&connection_id).filter(|slots| slots.paused) {
+            slots.enqueue(priority, request, self.by_priority);
+            return Admission::Queued;
+        }
+        let Some(limit) = self.limit else {
+            return Admission::Granted(request);
+        };
+        let slots = connections.entry(connection_id).or_insert_with(Slots::new);
+        if slots.in_flight < limit {
+            slots.in_flight += 1;
+            return Admission::Granted(request);
+        }
+        match self.backpressure {
+            Backpressure::Reject => Admission::Busy(request),
+            Backpressure::Queue => {
+                slots.enqueue(priority, request, self.by_priority);
+                Admission::Queued
+            }
+        }
+    }
+
+    /// Takes a slot of `connection_id` for `request` if one is free, or hands the request back,
+    /// whatever the backpressure. A paused connection has no free slot.
+    pub(crate) fn try_admit(&self, connection_id: i32, request: T) -> Result<T, T> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.get(&connection_id).is_some_and(|slots| slots.paused) {
+            return Err(request);
+        }
+        let Some(limit) = self.limit else {
+            return Ok(request);
+        };
+        let slots = connections.entry(connection_id).or_insert_with(Slots::new);
+        if slots.in_flight < limit {
+            slots.in_flight += 1;
+            return Ok(request);
+        }
+        if slots.is_idle() {
+            connections.remove(&connection_id);
+        }
+        Err(request)
+    }
+
+    /// Frees a slot of `connection_id`. Returns the next queued request, which takes the slot
+    /// over and must be sent, or released in turn if that fails.
+    pub(crate) fn release(&self, connection_id: i32) -> Option<T> {
+        self.limit?;
+        let mut connections = self.connections.lock().unwrap();
+        let slots = connections.get_mut(&connection_id)?;
+        if !slots.paused {
+            if let Some((_, next)) = slots.queued.pop_front() {
+                return Some(next);
+            }
+        }
+        slots.in_flight = slots.in_flight.saturating_sub(1);
+        if slots.is_idle() {
+            connections.remove(&connection_id);
+        }
+        None
+    }
+
+    /// Queues the requests of `connection_id` from now on, until it is resumed.
+    pub(crate) fn pause(&self, connection_id: i32) {
+        self.connections.lock().unwrap().entry(connection_id).or_insert_with(Slots::new).paused =
+            true;
+    }
+
+    /// Resumes `connection_id`. Returns the queued requests that got a slot, in order, which
+    /// must be sent, or released in turn if that fails.
+    pub(crate) fn resume(&self, connection_id: i32) -> Vec<T> {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(slots) = connections.get_mut(&connection_id) else {
+            return Vec::new();
+        };
+        slots.paused = false;
+        let mut resumed = Vec::new();
+        while self.limit.is_none_or(|limit| slots.in_flight < limit) {
+            let Some((_, next)) = slots.queued.pop_front() else {
+                break;
+            };
+            if self.limit.is_some() {
+                slots.in_flight += 1;
+            }
+            resumed.push(next);
+        }
+        if slots.is_idle() {
+            connections.remove(&connection_id);
+        }
+        resumed
+    }
+
+    /// Whether `connection_id` is paused.
+    pub(crate) fn is_paused(&self, connection_id: i32) -> bool {
+        self.connections.lock().unwrap().get(&connection_id).is_some_and(|slots| slots.paused)
+    }
+
+    /// Removes the queued requests matching `expired`.
+    pub(crate) fn take_queued_if(&self, expired: impl Fn(&T) -> bool) -> Vec<T> {
+        let mut connections = self.connections.lock().unwrap();
+        let mut taken = Vec::new();
+        for slots in connections.values_mut() {
+            let (matching, kept): (Vec<_>, Vec<_>) =
+                slots.queued.drain(..).partition(|(_, request)| expired(request));
+            slots.queued = kept.into();
+            taken.extend(matching.into_iter().map(|(_, request)| request));
+        }
+        taken
+    }
+
+    /// Number of queued requests over all connections.
+    pub(crate) fn queued_len(&self) -> usize {
+        self.connections.lock().unwrap().values().map(|slots| slots.queued.len()).sum()
+    }
+
+    /// Whether a queued request matches `pred`.
+    pub(crate) fn any_queued(&self, pred: impl Fn(&T) -> bool) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .any(|slots| slots.queued.iter().any(|(_, request)| pred(request)))
+    }
+
+    /// Frees every slot and returns all queued requests.
+    pub(crate) fn drain(&self) -> Vec<T> {
+        let mut connections = self.connections.lock().unwrap();
+        connections
+            .drain()
+            .flat_map(|(_, slots)| slots.queued.into_iter().map(|(_, request)| request))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_when_busy() {
+        let limiter = ConnectionLimiter::new(Some(1), Backpressure::Reject);
+        assert_eq!(limiter.admit(1, Priority::Normal, "a"), Admission::Granted("a"));
+        assert_eq!(limiter.admit(1, Priority::Normal, "b"), Admission::Busy("b"));
+        // Other connections have slots of their own.
+        assert_eq!(limiter.admit(2, Priority::Normal, "c"), Admission::Granted("c"));
+        assert_eq!(limiter.release(1), None);
+        assert_eq!(limiter.admit(1, Priority::Normal, "d"), Admission::Granted("d"));
+    }
+
+    #[test]
+    fn test_queue_when_busy() {
+        let limiter = ConnectionLimiter::new(Some(1), Backpressure::Queue);
+        assert_eq!(limiter.admit(1, Priority::Normal, "a"), Admission::Granted("a"));
+        assert_eq!(limiter.admit(1, Priority::Normal, "b"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::Normal, "c"), Admission::Queued);
+        // Queued requests take the slot over in order.
+        assert_eq!(limiter.release(1), Some("b"));
+        assert_eq!(limiter.admit(1, Priority::Normal, "d"), Admission::Queued);
+        assert_eq!(limiter.queued_len(), 2);
+        assert!(limiter.any_queued(|request| *request == "d"));
+        assert_eq!(limiter.take_queued_if(|request| *request == "d"), vec!["d"]);
+        assert_eq!(limiter.release(1), Some("c"));
+        assert_eq!(limiter.release(1), None);
+        assert_eq!(limiter.admit(1, Priority::Normal, "e"), Admission::Granted("e"));
+        assert!(limiter.drain().is_empty());
+    }
+
+    #[test]
+    fn test_queue_by_priority() {
+        let limiter = ConnectionLimiter::new(Some(1), Backpressure::Queue);
+        assert_eq!(limiter.admit(1, Priority::Normal, "a"), Admission::Granted("a"));
+        assert_eq!(limiter.admit(1, Priority::Background, "sync"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::Normal, "b"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::UnlockCritical, "unlock"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::Normal, "c"), Admission::Queued);
+        assert_eq!(limiter.release(1), Some("unlock"));
+        assert_eq!(limiter.release(1), Some("b"));
+        assert_eq!(limiter.release(1), Some("c"));
+        assert_eq!(limiter.release(1), Some("sync"));
+        assert_eq!(limiter.release(1), None);
+    }
+
+    #[test]
+    fn test_ordered() {
+        let limiter = ConnectionLimiter::ordered();
+        assert_eq!(limiter.admit(1, Priority::Normal, "a"), Admission::Granted("a"));
+        assert_eq!(limiter.admit(1, Priority::Background, "b"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::UnlockCritical, "c"), Admission::Queued);
+        assert_eq!(limiter.release(1), Some("b"));
+        assert_eq!(limiter.release(1), Some("c"));
+        assert_eq!(limiter.release(1), None);
+    }
+
+    #[test]
+    fn test_try_admit() {
+        let limiter = ConnectionLimiter::new(Some(2), Backpressure::Queue);
+        assert_eq!(limiter.try_admit(1, "a"), Ok("a"));
+        assert_eq!(limiter.try_admit(1, "b"), Ok("b"));
+        // A full window hands the request back instead of queuing it.
+        assert_eq!(limiter.try_admit(1, "c"), Err("c"));
+        assert_eq!(limiter.queued_len(), 0);
+        assert_eq!(limiter.release(1), None);
+        assert_eq!(limiter.try_admit(1, "c"), Ok("c"));
+        limiter.pause(2);
+        assert_eq!(limiter.try_admit(2, "d"), Err("d"));
+    }
+
+    #[test]
+    fn test_pause() {
+        let limiter = ConnectionLimiter::new(Some(2), Backpressure::Reject);
+        assert_eq!(limiter.admit(1, Priority::Normal, "a"), Admission::Granted("a"));
+        limiter.pause(1);
+        assert!(limiter.is_paused(1));
+        // A paused connection queues even with slots left, and doesn't hand them over.
+        assert_eq!(limiter.admit(1, Priority::Normal, "b"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::Normal, "c"), Admission::Queued);
+        assert_eq!(limiter.admit(1, Priority::Normal, "d"), Admission::Queued);
+        assert_eq!(limiter.release(1), None);
+        assert_eq!(limiter.resume(1), vec!["b", "c"]);
+        assert!(!limiter.is_paused(1));
+        assert_eq!(limiter.release(1), Some("d"));
+
+        let unlimited = ConnectionLimiter::new(None, Backpressure::Reject);
+        unlimited.pause(1);
+        assert_eq!(unlimited.admit(1, Priority::Normal, "a"), Admission::Queued);
+        assert_eq!(unlimited.resume(1), vec!["a"]);
+        assert_eq!(unlimited.admit(1, Priority::Normal, "b"), Admission::Granted("b"));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = ConnectionLimiter::new(None, Backpressure::Reject);
+        for request in 0..100 {
+            assert_eq!(limiter.admit(1, Priority::Normal, request), Admission::Granted(request));
+        }
+        assert_eq!(limiter.release(1), None);
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Round-trip times measured by pinging connections.
+//!
+//! The smoothed RTT follows TCP (RFC 6298): each sample moves it by an eighth of the difference,
+//! so a single slow ping doesn't make a connection look degraded.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Weight of a new sample in the smoothed RTT, as 1 / RTT_SMOOTHING.
+const RTT_SMOOTHING: u32 = 8;
+
+/// Round-trip time statistics of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    /// Most recent round-trip time.
+    pub last: Duration,
+    /// Smoothed round-trip time.
+    pub smoothed: Duration,
+    /// Smallest round-trip time seen.
+    pub min: Duration,
+    /// Number of pings answered.
+    pub samples: u32,
+    /// Number of pings that failed, e.g. timed out.
+    pub failures: u32,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    stats: Option<RttStats>,
+    failures: u32,
+}
+
+/// Keeps the round-trip times of the connections of a platform.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionQualityTracker {
+    connections: Mutex<HashMap<i32, Entry>>,
+}
+
+impl ConnectionQualityTracker {
+    /// Records a ping of `connection_id` answered after `rtt`.
+    pub(crate) fn record_rtt(&self, connection_id: i32, rtt: Duration) {
+        let mut connections = self.connections.lock().unwrap();
+        let entry = connections.entry(connection_id).or_default();
+        let stats = match entry.stats {
+            Some(stats) => RttStats {
+                last: rtt,
+                smoothed: if rtt > stats.smoothed {
+                    stats.smoothed + (rtt - stats.smoothed) / RTT_SMOOTHING
+                } else {
+                    stats.smoothed - (stats.smoothed - rtt) / RTT_SMOOTHING
+                },
+                min: stats.min.min(rtt),
+                samples: stats.samples.saturating_add(1),
+                failures: entry.failures,
+            },
+            None => RttStats {
+                last: rtt,
+                smoothed: rtt,
+                min: rtt,
+                samples: 1,
+                failures: entry.failures,
+            },
+        };
+        entry.stats = Some(stats);
+    }
+
+    /// Records a ping of `connection_id` that got no answer.
+    pub(crate) fn record_failure(&self, connection_id: i32) {
+        let mut connections = self.connections.lock().unwrap();
+        let entry = connections.entry(connection_id).or_default();
+        entry.failures = entry.failures.saturating_add(1);
+        if let Some(stats) = entry.stats.as_mut() {
+            stats.failures = entry.failures;
+        }
+    }
+
+    /// Returns the statistics of `connection_id`, or None if no ping was answered on it yet.
+    pub(crate) fn stats(&self, connection_id: i32) -> Option<RttStats> {
+        self.connections.lock().unwrap().get(&connection_id)?.stats
+    }
+
+    /// Forgets `connection_id`, e.g. once it is closed.
+    pub(crate) fn remove(&self, connection_id: i32) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoothed_rtt() {
+        let tracker = ConnectionQualityTracker::default();
+        assert_eq!(tracker.stats(1), None);
+        tracker.record_rtt(1, Duration::from_millis(80));
+        tracker.record_rtt(1, Duration::from_millis(160));
+        tracker.record_failure(1);
+        tracker.record_rtt(1, Duration::from_millis(40));
+        assert_eq!(
+            tracker.stats(1),
+            Some(RttStats {
+                last: Duration::from_millis(40),
+                smoothed: Duration::from_micros(83_750),
+                min: Duration::from_millis(40),
+                samples: 3,
+                failures: 1,
+            })
+        );
+        tracker.remove(1);
+        assert_eq!(tracker.stats(1), None);
+    }
+}
@@ -0,0 +1,457 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! COSE (RFC 9052) single-signer and single-recipient structures over the CBOR codec.
+//!
+//! `CoseSign1` signs and `CoseEncrypt0` encrypts a payload, both in the format credential
+//! verifiers and other platform components understand. Keys stay with the caller: signing and
+//! encryption go through `Signer`, `Verifier` and `Aead`. `crypto` implements the last two with
+//! BoringSSL, while signing keys stay in Android Keystore.
+
+use crate::codec::cbor::{self, CborError, DecodeLimits, Value};
+use thiserror::Error;
+
+/// Tag of a `COSE_Sign1` structure.
+pub const COSE_SIGN1_TAG: u64 = 18;
+/// Tag of a `COSE_Encrypt0` structure.
+pub const COSE_ENCRYPT0_TAG: u64 = 16;
+
+// Header labels.
+const ALG_LABEL: i64 = 1;
+const KID_LABEL: i64 = 4;
+const IV_LABEL: i64 = 5;
+
+/// Algorithms of the COSE registry used by RemoteAuth, with their COSE ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// ECDSA with SHA-256 on P-256.
+    Es256,
+    /// ECDSA with SHA-384 on P-384.
+    Es384,
+    /// EdDSA, e.g. Ed25519.
+    EdDsa,
+    /// RSASSA-PKCS1-v1_5 with SHA-256.
+    Rs256,
+    /// RSASSA-PSS with SHA-256, MGF1 with SHA-256 and a 32-byte salt.
+    Ps256,
+    /// AES-GCM with a 128-bit key.
+    A128Gcm,
+    /// AES-GCM with a 256-bit key.
+    A256Gcm,
+    /// ChaCha20/Poly1305 with a 256-bit key.
+    ChaCha20Poly1305,
+}
+
+impl Algorithm {
+    /// Returns the COSE id of the algorithm.
+    pub fn id(self) -> i64 {
+        match self {
+            Self::Es256 => -7,
+            Self::Es384 => -35,
+            Self::EdDsa => -8,
+            Self::Rs256 => -257,
+            Self::Ps256 => -37,
+            Self::A128Gcm => 1,
+            Self::A256Gcm => 3,
+            Self::ChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Returns the algorithm with COSE id `id`, or None if unsupported.
+    pub fn from_id(id: i64) -> Option<Self> {
+        match id {
+            -7 => Some(Self::Es256),
+            -35 => Some(Self::Es384),
+            -8 => Some(Self::EdDsa),
+            -257 => Some(Self::Rs256),
+            -37 => Some(Self::Ps256),
+            1 => Some(Self::A128Gcm),
+            3 => Some(Self::A256Gcm),
+            24 => Some(Self::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// Why a COSE structure could not be built, decoded or checked.
+#[derive(Debug, Error)]
+pub enum CoseError {
+    /// The bytes are not valid CBOR.
+    #[error(transparent)]
+    Cbor(#[from] CborError),
+    /// The CBOR value is not the expected COSE structure.
+    #[error("malformed COSE structure: {0}")]
+    Malformed(&'static str),
+    /// The structure uses an algorithm not supported here.
+    #[error("unsupported algorithm {0}")]
+    UnsupportedAlgorithm(i64),
+    /// The structure uses another algorithm than the key.
+    #[error("algorithm {actual:?} doesn't match the key's {expected:?}")]
+    AlgorithmMismatch {
+        /// Algorithm of the key.
+        expected: Algorithm,
+        /// Algorithm of the structure.
+        actual: Algorithm,
+    },
+    /// The signature doesn't verify.
+    #[error("bad signature")]
+    BadSignature,
+    /// The ciphertext doesn't decrypt, e.g. it was tampered with.
+    #[error("decryption failed")]
+    Decrypt,
+    /// The key failed to sign or encrypt.
+    #[error("key operation failed")]
+    Key(#[source] anyhow::Error),
+}
+
+/// Signs with a private key.
+pub trait Signer {
+    /// Algorithm of the key.
+    fn algorithm(&self) -> Algorithm;
+
+    /// Returns the signature of `data`.
+    fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Verifies signatures with a public key.
+pub trait Verifier {
+    /// Algorithm of the key.
+    fn algorithm(&self) -> Algorithm;
+
+    /// Returns whether `signature` is a signature of `data`.
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Encrypts and decrypts with a symmetric key, authenticating additional data.
+pub trait Aead {
+    /// Algorithm of the key.
+    fn algorithm(&self) -> Algorithm;
+
+    /// Returns `plaintext` encrypted with `iv`, with its tag, authenticating `aad`.
+    fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns `ciphertext` decrypted with `iv`, or None if it or `aad` don't authenticate.
+    fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+fn label(label: i64) -> Value {
+    Value::Integer(label.into())
+}
+
+/// Returns the encoded protected header, holding only the algorithm.
+fn protected_header(algorithm: Algorithm) -> Vec<u8> {
+    cbor::encode(&Value::Map(vec![(label(ALG_LABEL), label(algorithm.id()))]))
+}
+
+fn parse_protected_header(bytes: &[u8], limits: DecodeLimits) -> Result<Algorithm, CoseError> {
+    let Value::Map(entries) = cbor::decode(bytes, limits)? else {
+        return Err(CoseError::Malformed("protected header is not a map"));
+    };
+    let id = entries
+        .iter()
+        .find(|(key, _)| *key == label(ALG_LABEL))
+        .and_then(|(_, value)| value.as_integer())
+        .and_then(|id| i64::try_from(id).ok())
+        .ok_or(CoseError::Malformed("no algorithm"))?;
+    Algorithm::from_id(id).ok_or(CoseError::UnsupportedAlgorithm(id))
+}
+
+fn unprotected_header(key_id: Option<&[u8]>, iv: Option<&[u8]>) -> Value {
+    let mut entries = Vec::new();
+    if let Some(key_id) = key_id {
+        entries.push((label(KID_LABEL), Value::Bytes(key_id.to_vec())));
+    }
+    if let Some(iv) = iv {
+        entries.push((label(IV_LABEL), Value::Bytes(iv.to_vec())));
+    }
+    Value::Map(entries)
+}
+
+fn header_bytes(header: &[(Value, Value)], header_label: i64) -> Option<Vec<u8>> {
+    header.iter().find(|(key, _)| *key == label(header_label)).and_then(|(_, value)| match value {
+        Value::Bytes(bytes) => Some(bytes.clone()),
+        _ => None,
+    })
+}
+
+/// Returns the 4 or 3 items of a COSE structure, tagged with `tag` or untagged.
+fn parse_structure(
+    bytes: &[u8],
+    limits: DecodeLimits,
+    tag: u64,
+    len: usize,
+) -> Result<Vec<Value>, CoseError> {
+    let value = match cbor::decode(bytes, limits)? {
+        Value::Tag(actual, value) if actual == tag => *value,
+        Value::Tag(..) => return Err(CoseError::Malformed("unexpected tag")),
+        value => value,
+    };
+    match value {
+        Value::Array(items) if items.len() == len => Ok(items),
+        _ => Err(CoseError::Malformed("not an array of the expected length")),
+    }
+}
+
+/// The items of a COSE structure, in order.
+struct Items(std::vec::IntoIter<Value>);
+
+impl Items {
+    fn bytes(&mut self) -> Result<Vec<u8>, CoseError> {
+        match self.0.next() {
+            Some(Value::Bytes(bytes)) => Ok(bytes),
+            _ => Err(CoseError::Malformed("expected a byte string")),
+        }
+    }
+
+    fn map(&mut self) -> Result<Vec<(Value, Value)>, CoseError> {
+        match self.0.next() {
+            Some(Value::Map(entries)) => Ok(entries),
+            _ => Err(CoseError::Malformed("expected a map")),
+        }
+    }
+}
+
+/// A payload signed by a single signer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoseSign1 {
+    /// Signature algorithm, protected by the signature.
+    pub algorithm: Algorithm,
+    /// Id of the signing key, if the signer named it.
+    pub key_id: Option<Vec<u8>>,
+    /// Signed payload.
+    pub payload: Vec<u8>,
+    /// Signature of the `Sig_structure` of the payload.
+    pub signature: Vec<u8>,
+}
+
+impl CoseSign1 {
+    /// Returns the bytes signed for `payload`, also authenticating `external_aad`.
+    fn to_be_signed(algorithm: Algorithm, payload: &[u8], external_aad: &[u8]) -> Vec<u8> {
+        cbor::encode(&Value::Array(vec![
+            Value::Text(String::from("Signature1")),
+            Value::Bytes(protected_header(algorithm)),
+            Value::Bytes(external_aad.to_vec()),
+            Value::Bytes(payload.to_vec()),
+        ]))
+    }
+
+    /// Signs `payload` with `signer`, also authenticating `external_aad`, which the verifier
+    /// must know.
+    pub fn sign(
+        signer: &dyn Signer,
+        key_id: Option<&[u8]>,
+        payload: &[u8],
+        external_aad: &[u8],
+    ) -> Result<Self, CoseError> {
+        let algorithm = signer.algorithm();
+        let signature = signer
+            .sign(&Self::to_be_signed(algorithm, payload, external_aad))
+            .map_err(CoseError::Key)?;
+        Ok(Self {
+            algorithm,
+            key_id: key_id.map(<[u8]>::to_vec),
+            payload: payload.to_vec(),
+            signature,
+        })
+    }
+
+    /// Checks the signature with `verifier`, authenticating `external_aad` too.
+    pub fn verify(&self, verifier: &dyn Verifier, external_aad: &[u8]) -> Result<(), CoseError> {
+        if verifier.algorithm() != self.algorithm {
+            return Err(CoseError::AlgorithmMismatch {
+                expected: verifier.algorithm(),
+                actual: self.algorithm,
+            });
+        }
+        let to_be_signed = Self::to_be_signed(self.algorithm, &self.payload, external_aad);
+        if !verifier.verify(&to_be_signed, &self.signature) {
+            return Err(CoseError::BadSignature);
+        }
+        Ok(())
+    }
+
+    /// Encodes the structure, tagged.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        cbor::encode(&Value::Tag(
+            COSE_SIGN1_TAG,
+            Box::new(Value::Array(vec![
+                Value::Bytes(protected_header(self.algorithm)),
+                unprotected_header(self.key_id.as_deref(), None),
+                Value::Bytes(self.payload.clone()),
+                Value::Bytes(self.signature.clone()),
+            ])),
+        ))
+    }
+
+    /// Decodes a structure, tagged or not, without verifying it.
+    pub fn from_bytes(bytes: &[u8], limits: DecodeLimits) -> Result<Self, CoseError> {
+        let mut items = Items(parse_structure(bytes, limits, COSE_SIGN1_TAG, 4)?.into_iter());
+        let algorithm = parse_protected_header(&items.bytes()?, limits)?;
+        let key_id = header_bytes(&items.map()?, KID_LABEL);
+        Ok(Self { algorithm, key_id, payload: items.bytes()?, signature: items.bytes()? })
+    }
+}
+
+/// A payload encrypted for a single recipient holding the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoseEncrypt0 {
+    /// Encryption algorithm, authenticated with the ciphertext.
+    pub algorithm: Algorithm,
+    /// Id of the key, if the sender named it.
+    pub key_id: Option<Vec<u8>>,
+    /// Initialization vector, unique for each encryption with the key.
+    pub iv: Vec<u8>,
+    /// Encrypted payload, with its tag.
+    pub ciphertext: Vec<u8>,
+}
+
+impl CoseEncrypt0 {
+    /// Returns the additional data authenticated with the ciphertext.
+    fn enc_structure(algorithm: Algorithm, external_aad: &[u8]) -> Vec<u8> {
+        cbor::encode(&Value::Array(vec![
+            Value::Text(String::from("Encrypt0")),
+            Value::Bytes(protected_header(algorithm)),
+            Value::Bytes(external_aad.to_vec()),
+        ]))
+    }
+
+    /// Encrypts `plaintext` with `aead` and `iv`, also authenticating `external_aad`, which the
+    /// recipient must know.
+    pub fn encrypt(
+        aead: &dyn Aead,
+        key_id: Option<&[u8]>,
+        iv: &[u8],
+        plaintext: &[u8],
+        external_aad: &[u8],
+    ) -> Result<Self, CoseError> {
+        let algorithm = aead.algorithm();
+        let ciphertext = aead
+            .seal(iv, &Self::enc_structure(algorithm, external_aad), plaintext)
+            .map_err(CoseError::Key)?;
+        Ok(Self { algorithm, key_id: key_id.map(<[u8]>::to_vec), iv: iv.to_vec(), ciphertext })
+    }
+
+    /// Decrypts the payload with `aead`, authenticating `external_aad` too.
+    pub fn decrypt(&self, aead: &dyn Aead, external_aad: &[u8]) -> Result<Vec<u8>, CoseError> {
+        if aead.algorithm() != self.algorithm {
+            return Err(CoseError::AlgorithmMismatch {
+                expected: aead.algorithm(),
+                actual: self.algorithm,
+            });
+        }
+        aead.open(&self.iv, &Self::enc_structure(self.algorithm, external_aad), &self.ciphertext)
+            .ok_or(CoseError::Decrypt)
+    }
+
+    /// Encodes the structure, tagged.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        cbor::encode(&Value::Tag(
+            COSE_ENCRYPT0_TAG,
+            Box::new(Value::Array(vec![
+                Value::Bytes(protected_header(self.algorithm)),
+                unprotected_header(self.key_id.as_deref(), Some(&self.iv)),
+                Value::Bytes(self.ciphertext.clone()),
+            ])),
+        ))
+    }
+
+    /// Decodes a structure, tagged or not, without decrypting it.
+    pub fn from_bytes(bytes: &[u8], limits: DecodeLimits) -> Result<Self, CoseError> {
+        let mut items = Items(parse_structure(bytes, limits, COSE_ENCRYPT0_TAG, 3)?.into_iter());
+        let algorithm = parse_protected_header(&items.bytes()?, limits)?;
+        let unprotected = items.map()?;
+        let iv = header_bytes(&unprotected, IV_LABEL).ok_or(CoseError::Malformed("no IV"))?;
+        let key_id = header_bytes(&unprotected, KID_LABEL);
+        Ok(Self { algorithm, key_id, iv, ciphertext: items.bytes()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32c::crc32c;
+
+    // Not cryptography: checksums keyed with a byte stand in for the keys.
+    struct FakeKey(u8);
+
+    impl Signer for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::EdDsa
+        }
+
+        fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(crc32c(&[&[self.0], data]).to_be_bytes().to_vec())
+        }
+    }
+
+    impl Verifier for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::EdDsa
+        }
+
+        fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+            self.sign(data).unwrap() == signature
+        }
+    }
+
+    impl Aead for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::A256Gcm
+        }
+
+        fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let mut sealed: Vec<u8> = plaintext.iter().map(|byte| byte ^ self.0).collect();
+            let tag = crc32c(&[&[self.0], iv, aad, &sealed]);
+            sealed.extend_from_slice(&tag.to_be_bytes());
+            Ok(sealed)
+        }
+
+        fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (sealed, tag) = ciphertext.split_at(ciphertext.len().checked_sub(4)?);
+            (crc32c(&[&[self.0], iv, aad, sealed]).to_be_bytes() == tag)
+                .then(|| sealed.iter().map(|byte| byte ^ self.0).collect())
+        }
+    }
+
+    #[test]
+    fn test_sign1() {
+        let key = FakeKey(7);
+        let signed = CoseSign1::sign(&key, Some(b"kid"), b"payload", b"aad").unwrap();
+        let bytes = signed.to_bytes();
+        // Tag 18, then an array of 4 items starting with the protected {1: -8}.
+        assert_eq!(bytes[..6], [0xd2, 0x84, 0x43, 0xa1, 0x01, 0x27]);
+        let decoded = CoseSign1::from_bytes(&bytes, DecodeLimits::default()).unwrap();
+        assert_eq!(decoded, signed);
+        decoded.verify(&key, b"aad").unwrap();
+        assert!(matches!(decoded.verify(&key, b"other"), Err(CoseError::BadSignature)));
+        assert!(matches!(decoded.verify(&FakeKey(8), b"aad"), Err(CoseError::BadSignature)));
+    }
+
+    #[test]
+    fn test_encrypt0() {
+        let key = FakeKey(7);
+        let encrypted = CoseEncrypt0::encrypt(&key, None, &[1; 12], b"secret", b"").unwrap();
+        let decoded =
+            CoseEncrypt0::from_bytes(&encrypted.to_bytes(), DecodeLimits::default()).unwrap();
+        assert_eq!(decoded, encrypted);
+        assert_eq!(decoded.decrypt(&key, b"").unwrap(), b"secret");
+        assert!(matches!(decoded.decrypt(&key, b"aad"), Err(CoseError::Decrypt)));
+        assert!(matches!(
+            CoseSign1::from_bytes(&encrypted.to_bytes(), DecodeLimits::default()),
+            Err(CoseError::Malformed(_))
+        ));
+    }
+}
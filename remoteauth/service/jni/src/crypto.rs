@@ -0,0 +1,834 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Implementations of the crypto traits of RemoteAuth with BoringSSL.
+//!
+//! The protocol modules take their primitives through traits, e.g. `cose::Aead`, so that tests
+//! can run them with fakes. The keys here back those traits in production.
+//!
+//! `bssl_crypto` has no arithmetic on points, so the P-256 group of SPAKE2+ calls the EC API of
+//! BoringSSL through `bssl_sys`, with each point and number owned by a wrapper freeing it.
+
+use crate::attestation::KeyVerifiers;
+use crate::cose::{Aead, Algorithm, Verifier};
+use crate::kdf::{sha256, HASH_LEN};
+use crate::noise::AeadFactory;
+use crate::pairing::Group;
+use anyhow::{anyhow, ensure};
+use bssl_crypto::aead::{Aead as _, Aes256Gcm};
+use bssl_crypto::{ec, ecdsa, ed25519};
+use bssl_sys::{point_conversion_form_t, BIGNUM, BN_CTX, EC_GROUP, EC_POINT, RSA};
+use std::ffi::c_int;
+use std::ptr;
+
+/// Length of an AES-256-GCM key.
+pub const AES_256_GCM_KEY_LEN: usize = 32;
+/// Length of an AES-256-GCM nonce.
+pub const AES_GCM_NONCE_LEN: usize = 12;
+/// Length of an encoded P-256 scalar.
+pub const P256_SCALAR_LEN: usize = 32;
+/// Length of an encoded P-384 scalar.
+pub const P384_SCALAR_LEN: usize = 48;
+/// Shortest RSA modulus accepted, in bits.
+pub const MIN_RSA_BITS: u32 = 2048;
+/// Length of an uncompressed P-256 point.
+pub const P256_POINT_LEN: usize = 65;
+/// Length of the bytes reduced into a P-256 scalar: 64 bits more than the order, for a uniform
+/// scalar.
+pub const P256_SEED_LEN: usize = 40;
+/// Length of an Ed25519 public key.
+pub const ED25519_PUBLIC_KEY_LEN: usize = 32;
+/// Length of an Ed25519 signature.
+pub const ED25519_SIGNATURE_LEN: usize = 64;
+/// Encoding of the identity of P-256, which has no SEC1 point.
+pub const P256_IDENTITY: [u8; 1] = [0];
+
+// M and N of the P256-SHA256-HKDF-HMAC-SHA256 ciphersuite of SPAKE2+ (RFC 9383), compressed.
+const SPAKE2PLUS_M: [u8; 33] = [
+    0x02, 0x88, 0x6e, 0x2f, 0x97, 0xac, 0xe4, 0x6e, 0x55, 0xba, 0x9d, 0xd7, 0x24, 0x25, 0x79, 0xf2,
+    0x99, 0x3b, 0x64, 0xe1, 0x6e, 0xf3, 0xdc, 0xab, 0x95, 0xaf, 0xd4, 0x97, 0x33, 0x3d, 0x8f, 0xa1,
+    0x2f,
+];
+const SPAKE2PLUS_N: [u8; 33] = [
+    0x03, 0xd8, 0xbb, 0xd6, 0xc6, 0x39, 0xc6, 0x29, 0x37, 0xb0, 0x4d, 0x99, 0x7f, 0x38, 0xc3, 0x77,
+    0x07, 0x19, 0xc6, 0x29, 0xd7, 0x01, 0x4d, 0x49, 0xa2, 0x4b, 0x4f, 0x98, 0xba, 0xa1, 0x29, 0x2b,
+    0x49,
+];
+
+/// AES-256-GCM key, with 96-bit nonces and 128-bit tags.
+pub struct Aes256GcmKey(Aes256Gcm);
+
+impl Aes256GcmKey {
+    /// Returns the key of `key`.
+    pub fn new(key: &[u8; AES_256_GCM_KEY_LEN]) -> Self {
+        Self(Aes256Gcm::new(key))
+    }
+}
+
+impl Aead for Aes256GcmKey {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::A256Gcm
+    }
+
+    fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce: &[u8; AES_GCM_NONCE_LEN] = iv
+            .try_into()
+            .map_err(|_| anyhow!("IV of {} bytes instead of {}", iv.len(), AES_GCM_NONCE_LEN))?;
+        Ok(self.0.seal(nonce, plaintext, aad))
+    }
+
+    fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        self.0.open(iv.try_into().ok()?, ciphertext, aad)
+    }
+}
+
+/// Creates `Aes256GcmKey`s, e.g. for the Noise handshake and the secure channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aes256GcmFactory;
+
+impl AeadFactory for Aes256GcmFactory {
+    type Key = Aes256GcmKey;
+
+    fn key(&self, key: &[u8; AES_256_GCM_KEY_LEN]) -> Aes256GcmKey {
+        Aes256GcmKey::new(key)
+    }
+}
+
+/// ECDSA public key on P-256, verifying ES256 signatures in the COSE format: `r || s`.
+pub struct Es256PublicKey(ecdsa::PublicKey<ec::P256>);
+
+impl Es256PublicKey {
+    /// Returns the key of `point`, an uncompressed SEC1 point, or None if it isn't on P-256.
+    pub fn from_uncompressed(point: &[u8]) -> Option<Self> {
+        ecdsa::PublicKey::from_x962_uncompressed(point).map(Self)
+    }
+}
+
+impl Verifier for Es256PublicKey {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Es256
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        der_signature(signature, P256_SCALAR_LEN)
+            .is_some_and(|der| self.0.verify(data, &der).is_ok())
+    }
+}
+
+/// ECDSA public key on P-384, verifying ES384 signatures in the COSE format: `r || s`.
+pub struct Es384PublicKey(ecdsa::PublicKey<ec::P384>);
+
+impl Es384PublicKey {
+    /// Returns the key of `point`, an uncompressed SEC1 point, or None if it isn't on P-384.
+    pub fn from_uncompressed(point: &[u8]) -> Option<Self> {
+        ecdsa::PublicKey::from_x962_uncompressed(point).map(Self)
+    }
+}
+
+impl Verifier for Es384PublicKey {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Es384
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        der_signature(signature, P384_SCALAR_LEN)
+            .is_some_and(|der| self.0.verify(data, &der).is_ok())
+    }
+}
+
+/// Returns the DER `ECDSA-Sig-Value` of the signature `r || s`, or None if it isn't the length
+/// of a signature of `scalar_len` scalars.
+pub(crate) fn der_signature(signature: &[u8], scalar_len: usize) -> Option<Vec<u8>> {
+    if signature.len() != 2 * scalar_len {
+        return None;
+    }
+    let mut value = Vec::new();
+    for integer in signature.chunks(scalar_len) {
+        // Minimal and positive: no leading zeros, but one before a set top bit.
+        let start = integer.iter().position(|&byte| byte != 0).unwrap_or(scalar_len - 1);
+        let integer = &integer[start..];
+        let pad = integer[0] & 0x80 != 0;
+        value.extend_from_slice(&[0x02, (usize::from(pad) + integer.len()) as u8]);
+        if pad {
+            value.push(0);
+        }
+        value.extend_from_slice(integer);
+    }
+    // Both integers take at most 102 bytes, even on P-384, so the lengths take a byte.
+    let mut der = vec![0x30, value.len() as u8];
+    der.extend(value);
+    Some(der)
+}
+
+/// Returns the ES256 signature `r || s` of `der`, a DER `ECDSA-Sig-Value` as Android Keystore
+/// signs, or None if it isn't the strict encoding of a P-256 signature.
+pub fn es256_signature(der: &[u8]) -> Option<Vec<u8>> {
+    let [0x30, len, value @ ..] = der else {
+        return None;
+    };
+    if *len & 0x80 != 0 || usize::from(*len) != value.len() {
+        return None;
+    }
+    let mut signature = vec![0; 2 * P256_SCALAR_LEN];
+    let mut rest = value;
+    for scalar in signature.chunks_mut(P256_SCALAR_LEN) {
+        let [0x02, len, tail @ ..] = rest else {
+            return None;
+        };
+        let integer = tail.get(..usize::from(*len))?;
+        rest = &tail[integer.len()..];
+        // Minimal and positive: a leading zero only before a set top bit.
+        let integer = match integer {
+            [] => return None,
+            [top, ..] if top & 0x80 != 0 => return None,
+            [0, next, ..] if next & 0x80 == 0 => return None,
+            [0, unpadded @ ..] if !unpadded.is_empty() => unpadded,
+            integer => integer,
+        };
+        let start = P256_SCALAR_LEN.checked_sub(integer.len())?;
+        scalar[start..].copy_from_slice(integer);
+    }
+    rest.is_empty().then_some(signature)
+}
+
+/// Ed25519 public key, verifying EdDSA signatures.
+pub struct Ed25519PublicKey(ed25519::PublicKey);
+
+impl Ed25519PublicKey {
+    /// Returns the key of `bytes`, or None if it isn't the length of an Ed25519 key.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(ed25519::PublicKey::from_bytes(bytes.try_into().ok()?)))
+    }
+}
+
+impl Verifier for Ed25519PublicKey {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::EdDsa
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let Ok(signature) = <&[u8; ED25519_SIGNATURE_LEN]>::try_from(signature) else {
+            return false;
+        };
+        self.0.verify(data, signature).is_ok()
+    }
+}
+
+/// RSA public key, verifying RS256 or PS256 signatures, freed when dropped.
+///
+/// `bssl_crypto` has no PSS, so both paddings call the RSA API of BoringSSL through `bssl_sys`.
+pub struct RsaPublicKey {
+    rsa: *mut RSA,
+    algorithm: Algorithm,
+}
+
+impl RsaPublicKey {
+    /// Returns the key of `der`, a DER `RSAPublicKey`, verifying signatures of `algorithm`,
+    /// `Rs256` or `Ps256`. None if it isn't such a key of at least `MIN_RSA_BITS`.
+    pub fn from_der(der: &[u8], algorithm: Algorithm) -> Option<Self> {
+        if !matches!(algorithm, Algorithm::Rs256 | Algorithm::Ps256) {
+            return None;
+        }
+        // Safety: `der` is valid for its length. A null key is a parse failure.
+        let rsa = unsafe { bssl_sys::RSA_public_key_from_bytes(der.as_ptr(), der.len()) };
+        if rsa.is_null() {
+            return None;
+        }
+        let key = Self { rsa, algorithm };
+        // Safety: the key is valid.
+        (unsafe { bssl_sys::RSA_bits(key.rsa) } >= MIN_RSA_BITS).then_some(key)
+    }
+}
+
+impl Drop for RsaPublicKey {
+    fn drop(&mut self) {
+        // Safety: the key is valid, and owned by this wrapper.
+        unsafe { bssl_sys::RSA_free(self.rsa) }
+    }
+}
+
+impl Verifier for RsaPublicKey {
+    fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let digest = sha256(data);
+        // Safety: the key is valid, and the digest and the signature are valid for their length.
+        let verified = unsafe {
+            match self.algorithm {
+                Algorithm::Rs256 => bssl_sys::RSA_verify(
+                    bssl_sys::NID_sha256 as c_int,
+                    digest.as_ptr(),
+                    digest.len(),
+                    signature.as_ptr(),
+                    signature.len(),
+                    self.rsa,
+                ),
+                _ => bssl_sys::RSA_verify_pss_mgf1(
+                    self.rsa,
+                    digest.as_ptr(),
+                    digest.len(),
+                    bssl_sys::EVP_sha256(),
+                    bssl_sys::EVP_sha256(),
+                    HASH_LEN as c_int,
+                    signature.as_ptr(),
+                    signature.len(),
+                ),
+            }
+        };
+        verified == 1
+    }
+}
+
+/// Verifiers for the ES256, ES384, Ed25519 and RSA keys of attestation chains.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AttestationVerifiers;
+
+impl KeyVerifiers for AttestationVerifiers {
+    fn verifier(&self, algorithm: Algorithm, public_key: &[u8]) -> Option<Box<dyn Verifier>> {
+        match algorithm {
+            Algorithm::Es256 => Some(Box::new(Es256PublicKey::from_uncompressed(public_key)?)),
+            Algorithm::Es384 => Some(Box::new(Es384PublicKey::from_uncompressed(public_key)?)),
+            Algorithm::EdDsa => Some(Box::new(Ed25519PublicKey::from_bytes(public_key)?)),
+            Algorithm::Rs256 | Algorithm::Ps256 => {
+                Some(Box::new(RsaPublicKey::from_der(public_key, algorithm)?))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn p256() -> *const EC_GROUP {
+    // Safety: the group is static, and never freed.
+    unsafe { bssl_sys::EC_group_p256() }
+}
+
+/// A P-256 point, freed when dropped.
+struct Point(*mut EC_POINT);
+
+impl Point {
+    /// Returns the identity.
+    fn new() -> anyhow::Result<Self> {
+        // Safety: the group is valid. A null point is an allocation failure.
+        let point = unsafe { bssl_sys::EC_POINT_new(p256()) };
+        ensure!(!point.is_null(), "failed to allocate a P-256 point");
+        Ok(Self(point))
+    }
+
+    /// Decodes `bytes`, a SEC1 point, compressed or not, or `P256_IDENTITY`. Fails if `bytes`
+    /// isn't on the curve.
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let point = Self::new()?;
+        // Safety: the point is valid, and `bytes` is valid for its length.
+        let decoded = unsafe {
+            if bytes == P256_IDENTITY {
+                bssl_sys::EC_POINT_set_to_infinity(p256(), point.0)
+            } else {
+                bssl_sys::EC_POINT_oct2point(
+                    p256(),
+                    point.0,
+                    bytes.as_ptr(),
+                    bytes.len(),
+                    ptr::null_mut(),
+                )
+            }
+        };
+        ensure!(decoded == 1, "not a P-256 point");
+        Ok(point)
+    }
+
+    /// Returns the uncompressed SEC1 point, or `P256_IDENTITY`.
+    fn encode(&self) -> Vec<u8> {
+        // Safety: the point is valid.
+        if unsafe { bssl_sys::EC_POINT_is_at_infinity(p256(), self.0) } == 1 {
+            return P256_IDENTITY.to_vec();
+        }
+        let mut bytes = vec![0; P256_POINT_LEN];
+        // Safety: the point is valid, and `bytes` is valid for its length.
+        let len = unsafe {
+            bssl_sys::EC_POINT_point2oct(
+                p256(),
+                self.0,
+                point_conversion_form_t::POINT_CONVERSION_UNCOMPRESSED,
+                bytes.as_mut_ptr(),
+                bytes.len(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(len, P256_POINT_LEN, "failed to encode a P-256 point");
+        bytes
+    }
+
+    /// Returns `scalar` times `self`, or times the generator if `self` is None.
+    fn mul(this: Option<&Self>, scalar: &[u8]) -> anyhow::Result<Self> {
+        let scalar = BigNum::from_bytes(scalar)?;
+        let result = Self::new()?;
+        // Safety: the points and the number are valid. EC_POINT_mul skips null terms.
+        let multiplied = unsafe {
+            match this {
+                Some(point) => bssl_sys::EC_POINT_mul(
+                    p256(),
+                    result.0,
+                    ptr::null(),
+                    point.0,
+                    scalar.0,
+                    ptr::null_mut(),
+                ),
+                None => bssl_sys::EC_POINT_mul(
+                    p256(),
+                    result.0,
+                    scalar.0,
+                    ptr::null(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                ),
+            }
+        };
+        ensure!(multiplied == 1, "P-256 multiplication failed");
+        Ok(result)
+    }
+
+    /// Returns `self + other`, or `self - other` if `negate`.
+    fn add(&self, other: Self, negate: bool) -> anyhow::Result<Self> {
+        let result = Self::new()?;
+        // Safety: the points are valid, and `other` is owned here.
+        let added = unsafe {
+            (!negate || bssl_sys::EC_POINT_invert(p256(), other.0, ptr::null_mut()) == 1)
+                && bssl_sys::EC_POINT_add(p256(), result.0, self.0, other.0, ptr::null_mut()) == 1
+        };
+        ensure!(added, "P-256 addition failed");
+        Ok(result)
+    }
+}
+
+impl Drop for Point {
+    fn drop(&mut self) {
+        // Safety: the point was allocated by EC_POINT_new, and is only freed here.
+        unsafe { bssl_sys::EC_POINT_free(self.0) }
+    }
+}
+
+/// A big number, freed when dropped.
+struct BigNum(*mut BIGNUM);
+
+impl BigNum {
+    /// Returns the number of the big-endian `bytes`.
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        // Safety: `bytes` is valid for its length. A null number is an allocation failure.
+        let number = unsafe { bssl_sys::BN_bin2bn(bytes.as_ptr(), bytes.len(), ptr::null_mut()) };
+        ensure!(!number.is_null(), "failed to allocate a number");
+        Ok(Self(number))
+    }
+}
+
+impl Drop for BigNum {
+    fn drop(&mut self) {
+        // Safety: the number was allocated by BN_bin2bn, and is only freed here.
+        unsafe { bssl_sys::BN_free(self.0) }
+    }
+}
+
+/// Returns `bytes` reduced modulo the order of P-256, as an encoded scalar.
+fn reduce(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let number = BigNum::from_bytes(bytes)?;
+    let reduced = BigNum::from_bytes(&[])?;
+    // Safety: returns an owned context, or null on allocation failure.
+    let ctx: *mut BN_CTX = unsafe { bssl_sys::BN_CTX_new() };
+    ensure!(!ctx.is_null(), "failed to allocate a number context");
+    let mut scalar = vec![0; P256_SCALAR_LEN];
+    // Safety: the numbers and the context are valid, the order is static, and `scalar` is valid
+    // for its length. The context is freed once done with.
+    let done = unsafe {
+        let order = bssl_sys::EC_GROUP_get0_order(p256());
+        let done = bssl_sys::BN_nnmod(reduced.0, number.0, order, ctx) == 1
+            && bssl_sys::BN_bn2bin_padded(scalar.as_mut_ptr(), scalar.len(), reduced.0) == 1;
+        bssl_sys::BN_CTX_free(ctx);
+        done
+    };
+    ensure!(done, "failed to reduce a P-256 scalar");
+    Ok(scalar)
+}
+
+/// P-256 with the M and N of RFC 9383, the group of SPAKE2+ in production. Scalars are
+/// big-endian, and elements uncompressed SEC1 points.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct P256;
+
+impl Group for P256 {
+    fn seed_len(&self) -> usize {
+        P256_SEED_LEN
+    }
+
+    fn scalar(&self, bytes: &[u8]) -> Vec<u8> {
+        reduce(bytes).expect("P-256 scalar reduction failed")
+    }
+
+    fn base_mul(&self, scalar: &[u8]) -> Vec<u8> {
+        Point::mul(None, scalar).expect("P-256 base multiplication failed").encode()
+    }
+
+    fn mul(&self, scalar: &[u8], element: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(Point::mul(Some(&Point::decode(element)?), scalar)?.encode())
+    }
+
+    fn add(&self, a: &[u8], b: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(Point::decode(a)?.add(Point::decode(b)?, false)?.encode())
+    }
+
+    fn sub(&self, a: &[u8], b: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(Point::decode(a)?.add(Point::decode(b)?, true)?.encode())
+    }
+
+    fn is_identity(&self, element: &[u8]) -> bool {
+        element == P256_IDENTITY
+    }
+
+    fn m(&self) -> Vec<u8> {
+        Point::decode(&SPAKE2PLUS_M).expect("M is a P-256 point").encode()
+    }
+
+    fn n(&self) -> Vec<u8> {
+        Point::decode(&SPAKE2PLUS_N).expect("N is a P-256 point").encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::{validate_chain, AttestationError, AttestationPolicy, SecurityLevel};
+    use crate::codec::cbor::DecodeLimits;
+    use crate::cose::{CoseEncrypt0, CoseError};
+    use crate::pairing::{self, Identities, PairingError, PairingStart, Prover};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_aes_256_gcm() {
+        // The Galois/Counter Mode of Operation, test cases 13 and 14.
+        let key = Aes256GcmKey::new(&[0; AES_256_GCM_KEY_LEN]);
+        let iv = [0; AES_GCM_NONCE_LEN];
+        assert_eq!(key.seal(&iv, &[], &[]).unwrap(), hex("530f8afbc74536b9a963b4f1c4cb738b"));
+        assert_eq!(
+            key.seal(&iv, &[], &[0; 16]).unwrap(),
+            hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")
+        );
+
+        // Test case 16.
+        let key = Aes256GcmKey::new(
+            &hex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308")
+                .try_into()
+                .unwrap(),
+        );
+        let iv = hex("cafebabefacedbaddecaf888");
+        let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let plaintext = hex(concat!(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+            "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39"
+        ));
+        let sealed = hex(concat!(
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa",
+            "8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+            "76fc6ece0f4e1768cddf8853bb2d551b"
+        ));
+        assert_eq!(key.seal(&iv, &aad, &plaintext).unwrap(), sealed);
+        assert_eq!(key.open(&iv, &aad, &sealed), Some(plaintext));
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert_eq!(key.open(&iv, &aad, &tampered), None);
+        assert_eq!(key.open(&iv, b"other", &sealed), None);
+        assert_eq!(key.open(&iv[..8], &aad, &sealed), None);
+        assert!(key.seal(&iv[..8], &aad, &[]).is_err());
+    }
+
+    #[test]
+    fn test_aes_256_gcm_factory() {
+        let key = Aes256GcmFactory.key(&[0; AES_256_GCM_KEY_LEN]);
+        assert_eq!(key.algorithm(), Algorithm::A256Gcm);
+        assert_eq!(
+            key.seal(&[0; AES_GCM_NONCE_LEN], &[], &[]).unwrap(),
+            hex("530f8afbc74536b9a963b4f1c4cb738b")
+        );
+    }
+
+    #[test]
+    fn test_es256() {
+        // RFC 6979, appendix A.2.5: ECDSA on P-256 with SHA-256, of "sample".
+        let key = Es256PublicKey::from_uncompressed(&hex(concat!(
+            "0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6",
+            "7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299"
+        )))
+        .unwrap();
+        let signature = hex(concat!(
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716",
+            "f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"
+        ));
+        assert_eq!(key.algorithm(), Algorithm::Es256);
+        assert!(key.verify(b"sample", &signature));
+        assert!(!key.verify(b"test", &signature));
+        let mut tampered = signature.clone();
+        tampered[63] ^= 1;
+        assert!(!key.verify(b"sample", &tampered));
+        assert!(!key.verify(b"sample", &signature[..63]));
+        // As Keystore signs, in DER: verifies once converted.
+        let der = der_signature(&signature, P256_SCALAR_LEN).unwrap();
+        assert!(!key.verify(b"sample", &der));
+        assert_eq!(es256_signature(&der), Some(signature));
+        assert!(Es256PublicKey::from_uncompressed(&[4; P256_POINT_LEN]).is_none());
+    }
+
+    #[test]
+    fn test_der_signature() {
+        let mut signature = vec![0; 2 * P256_SCALAR_LEN];
+        signature[31] = 1;
+        signature[32] = 0x80;
+        let mut der = vec![0x30, 0x26, 0x02, 0x01, 0x01, 0x02, 0x21, 0x00, 0x80];
+        der.extend([0; 31]);
+        assert_eq!(der_signature(&signature, P256_SCALAR_LEN), Some(der.clone()));
+        assert_eq!(der_signature(&signature[1..], P256_SCALAR_LEN), None);
+
+        assert_eq!(es256_signature(&der), Some(signature));
+        let mut trailing = der.clone();
+        trailing[1] += 1;
+        trailing.push(0);
+        assert_eq!(es256_signature(&trailing), None);
+        // Integers padded with a needless zero, negative, or longer than a scalar are refused.
+        assert_eq!(es256_signature(&[0x30, 0x07, 0x02, 0x02, 0x00, 0x01, 0x02, 0x01, 0x01]), None);
+        assert_eq!(es256_signature(&[0x30, 0x06, 0x02, 0x01, 0x80, 0x02, 0x01, 0x01]), None);
+        let mut long = vec![0x30, 0x26, 0x02, 0x21, 0x01];
+        long.extend([0; 32]);
+        long.extend([0x02, 0x01, 0x01]);
+        assert_eq!(es256_signature(&long), None);
+        assert_eq!(es256_signature(&der[..der.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_ed25519() {
+        // RFC 8032, section 7.1, tests 1 and 2.
+        let key = Ed25519PublicKey::from_bytes(&hex(
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+        ))
+        .unwrap();
+        let signature = hex(concat!(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555",
+            "fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+        ));
+        assert_eq!(key.algorithm(), Algorithm::EdDsa);
+        assert!(key.verify(b"", &signature));
+        assert!(!key.verify(b"r", &signature));
+        assert!(!key.verify(b"", &signature[1..]));
+
+        let key = Ed25519PublicKey::from_bytes(&hex(
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+        ))
+        .unwrap();
+        let signature = hex(concat!(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+            "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"
+        ));
+        assert!(key.verify(&[0x72], &signature));
+        assert!(Ed25519PublicKey::from_bytes(&[0; 31]).is_none());
+    }
+
+    #[test]
+    fn test_attestation_verifiers() {
+        let es256 = hex(concat!(
+            "0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6",
+            "7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299"
+        ));
+        let verifier = AttestationVerifiers.verifier(Algorithm::Es256, &es256).unwrap();
+        assert_eq!(verifier.algorithm(), Algorithm::Es256);
+        let signature = hex(concat!(
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716",
+            "f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"
+        ));
+        assert!(verifier.verify(b"sample", &signature));
+
+        let ed25519 = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let verifier = AttestationVerifiers.verifier(Algorithm::EdDsa, &ed25519).unwrap();
+        assert_eq!(verifier.algorithm(), Algorithm::EdDsa);
+
+        assert!(AttestationVerifiers.verifier(Algorithm::Es256, &ed25519).is_none());
+        assert!(AttestationVerifiers.verifier(Algorithm::EdDsa, &es256).is_none());
+        assert!(AttestationVerifiers.verifier(Algorithm::A256Gcm, &ed25519).is_none());
+        assert!(AttestationVerifiers.verifier(Algorithm::Es384, &es256).is_none());
+        assert!(AttestationVerifiers.verifier(Algorithm::Rs256, &es256).is_none());
+        assert!(RsaPublicKey::from_der(&es256, Algorithm::Es256).is_none());
+    }
+
+    // An attestation chain in the format of Keystore, with the algorithms of production roots: an
+    // RSA root signs itself with PKCS#1 v1.5, and the P-384 intermediate with PSS, which signs the
+    // P-256 leaf.
+    const LEAF: &str = concat!(
+        "3082019230820119a003020102020103300a06082a8648ce3d04030330173115301306035504030c0c696e74",
+        "65726d6564696174653020170d3234303130313030303030305a180f32303530303130313030303030305a30",
+        "1f311d301b06035504030c14416e64726f6964204b657973746f7265204b65793059301306072a8648ce3d02",
+        "0106082a8648ce3d03010703420004a7de2f993971f90168a067be790f7b7dfc95abb531018ace1ee3b36aa3",
+        "d3e5e8773b1daa8df7e6fe9c6d4815ca31a1e8161e6fd9575bb1c8b5b00dd38ec703e2a34c304a300e060355",
+        "1d0f0101ff0404030207803038060a2b06010401d679020111042a30280201640a01010201640a0101041465",
+        "6e726f6c6c6d656e74206368616c6c656e6765040030003000300a06082a8648ce3d04030303670030640230",
+        "08e4534c103c38e1ccd7f81f91c4a39da840171169d250bb7ccee0e6e095028f0020e6e0b999865badbbbed5",
+        "0f7c1c3c023038c0237276f68e72a301f5fcab38ef74e7d62da1943cb6e658b3688a57664effa4288de5ec5f",
+        "80e6c4d7e58c528881a0",
+    );
+
+    const INTERMEDIATE: &str = concat!(
+        "3082028030820134a003020102020102304106092a864886f70d01010a3034a00f300d060960864801650304",
+        "02010500a11c301a06092a864886f70d010108300d06096086480165030402010500a203020120300f310d30",
+        "0b06035504030c04726f6f743020170d3234303130313030303030305a180f32303530303130313030303030",
+        "305a30173115301306035504030c0c696e7465726d6564696174653076301006072a8648ce3d020106052b81",
+        "04002203620004a9e66b5b0ce8b7976a3d906e1159e3b154cd2fc3d6bb021f577e2fdff200838c4229179ac0",
+        "f15d8bbbdd326aa35ed9710538b230d63db3ddb8d59b07fa28cb0789c522b5df4c181fc3f307642b7afe22d6",
+        "80079c9ab56100b328ffa31b1fce00a3233021300f0603551d130101ff040530030101ff300e0603551d0f01",
+        "01ff040403020204304106092a864886f70d01010a3034a00f300d06096086480165030402010500a11c301a",
+        "06092a864886f70d010108300d06096086480165030402010500a2030201200382010100691907837281f699",
+        "3e10ba88f10ad3452f705ac986b748cabf6a5ca8d7ae712c7086ce0dac82006cfcb2dddd4eaf06a6a0c30528",
+        "9e896e4a49ebcc4bf226e0ff7ad39f34411fa95c827bc1ba3275049b50e918f37e94b717368510bf7796e8da",
+        "c5d6fa5049c4529df57430ae63ad858354773489ca5995ef87fc82168239a034357a38eec4b045ef1752b07d",
+        "2950a28a161f1204c4d3c18f3c48e0cf83f83c9c4d3fd720a2877aa48e4ae7cdb0a790f892bb1659b615e820",
+        "b6e8353e51780c39ba9fbf19cfc8539c770f4eaa87e98634db2c5871f03577c41032edfaef6a12009a6b6664",
+        "8eefddfbcde06b9742157b338635559f6cd8300d2be7c4136ba6a600",
+    );
+
+    const ROOT: &str = concat!(
+        "308202be308201a6a003020102020101300d06092a864886f70d01010b0500300f310d300b06035504030c04",
+        "726f6f743020170d3234303130313030303030305a180f32303530303130313030303030305a300f310d300b",
+        "06035504030c04726f6f7430820122300d06092a864886f70d01010105000382010f003082010a0282010100",
+        "975c86bd161ae804aae755bbd5c1ecd585a41bae7265fbad93adc3ff61a930adb95bca04e762ec66a54a43b0",
+        "9e98ac88f330cf72f6085803f4905bf07b0b5e47f3cf00c91c9db97558447be361c7f9b90a856a40b3510c85",
+        "c5b7d61e1eb65ce91976d22d6bf0229e958d87313e26c8b73fc9a2f664329f13cd2317fbefb00ec664fe023c",
+        "34fc7a464ee3c13e92a875cb8e0be493e2455398e986b36dff970ee4b34b8f4db23ac6758f9f39d4d82b9c5a",
+        "e7b9099f30fea021c8fc3ad5d92a1778a9c5591047d61a0efdd605158ee2402ce362d1195b7d405eaf6d94d9",
+        "09a06caa12c90fb2630ab77596faa5ab0c87dadb801a276b3cae06a11c5f130ab19661990203010001a32330",
+        "21300f0603551d130101ff040530030101ff300e0603551d0f0101ff040403020204300d06092a864886f70d",
+        "01010b0500038201010079918febcd29785c11035581150d25a8e69a28e4ca326989b118e935d38fad69099a",
+        "60af3105fd0894d2e9e9694503bd60dde1d8187ab1dd81cdd5ec020aa5d06fb1f8ca8e7df9b501403bd7cc95",
+        "2749584745a9f0380e98d04982c8d19d90fbffbbae3645397b54461eda7b0f3503f09bc771c0514577ed4fb8",
+        "d12445936c1527f7633e9a240f0a0110d458186d446e4c6222c276ccae22f53a6c2e5c59a5a057035a2c43f7",
+        "268ee4f89231e92f3c4756348d6fef930b42379886aaefba0979ee6c4ac8d9eb6f77761c26c14aa953a4b489",
+        "d675e5a701df8244173664c8e87b3d73e85e590dd75039387918220bd74efad0eaa4a292257a4e0d6844996b",
+        "81d8",
+    );
+    const ROOT_HASH: &str = "238190b2428b2e4d1fc11044362a5a62e3c03452d0362a2f6450d99bb2e99cac";
+
+    #[test]
+    fn test_attestation_chain() {
+        let chain = [LEAF, INTERMEDIATE, ROOT].map(hex).to_vec();
+        let policy = AttestationPolicy {
+            pinned_roots: vec![hex(ROOT_HASH).try_into().unwrap()],
+            require_hardware: true,
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_750_000_000);
+        let validate = |chain: &[Vec<u8>]| {
+            validate_chain(chain, b"enrollment challenge", &policy, &AttestationVerifiers, now)
+        };
+        let attestation = validate(&chain).unwrap();
+        assert_eq!(attestation.algorithm, Algorithm::Es256);
+        assert_eq!(attestation.public_key.len(), P256_POINT_LEN);
+        assert_eq!(attestation.key_security_level, SecurityLevel::TrustedEnvironment);
+
+        // Signatures come last: altering one breaks the certificate it signs.
+        for index in 0..chain.len() {
+            let mut tampered = chain.clone();
+            *tampered[index].last_mut().unwrap() ^= 1;
+            assert_eq!(validate(&tampered), Err(AttestationError::BadSignature(index)));
+        }
+    }
+
+    #[test]
+    fn test_cose_encrypt0() {
+        let key = Aes256GcmKey::new(&[7; AES_256_GCM_KEY_LEN]);
+        let iv = [1; AES_GCM_NONCE_LEN];
+        let encrypted =
+            CoseEncrypt0::encrypt(&key, Some(b"key"), &iv, b"payload", b"context").unwrap();
+        let decoded =
+            CoseEncrypt0::from_bytes(&encrypted.to_bytes(), DecodeLimits::default()).unwrap();
+        assert_eq!(decoded.decrypt(&key, b"context").unwrap(), b"payload");
+        assert!(matches!(decoded.decrypt(&key, b"other"), Err(CoseError::Decrypt)));
+    }
+
+    #[test]
+    fn test_p256() {
+        let generator = hex(concat!(
+            "046b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+            "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5"
+        ));
+        let order = hex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551");
+        let mut one = vec![0; P256_SCALAR_LEN];
+        one[P256_SCALAR_LEN - 1] = 1;
+        assert_eq!(P256.base_mul(&one), generator);
+        // The order plus one reduces to one.
+        let mut seed = vec![0; P256_SEED_LEN];
+        seed[P256_SEED_LEN - P256_SCALAR_LEN..].copy_from_slice(&order);
+        seed[P256_SEED_LEN - 1] += 1;
+        assert_eq!(P256.scalar(&seed), one);
+
+        let mut two = one.clone();
+        two[P256_SCALAR_LEN - 1] = 2;
+        assert_eq!(P256.add(&generator, &generator).unwrap(), P256.base_mul(&two));
+        assert_eq!(P256.mul(&two, &generator).unwrap(), P256.base_mul(&two));
+        assert_eq!(P256.sub(&P256.base_mul(&two), &generator).unwrap(), generator);
+        let identity = P256.sub(&generator, &generator).unwrap();
+        assert!(P256.is_identity(&identity));
+        assert_eq!(P256.add(&identity, &generator).unwrap(), generator);
+        assert!(!P256.is_identity(&generator));
+
+        let mut off_curve = generator.clone();
+        off_curve[P256_POINT_LEN - 1] ^= 1;
+        assert!(P256.mul(&one, &off_curve).is_err());
+        assert!(P256.add(&generator, &off_curve).is_err());
+        assert!(P256.mul(&one, &[]).is_err());
+        // The points of RFC 9383, uncompressed.
+        assert_eq!(
+            P256.m(),
+            hex(concat!(
+                "04886e2f97ace46e55ba9dd7242579f2993b64e16ef3dcab95afd497333d8fa12f",
+                "5ff355163e43ce224e0b0e65ff02ac8e5c7be09419c785e0ca547d55a12e2d20"
+            ))
+        );
+        assert_eq!(
+            P256.n(),
+            hex(concat!(
+                "04d8bbd6c639c62937b04d997f38c3770719c629d7014d49a24b4f98baa1292b49",
+                "07d60aa6bfade45008a636337f5168c64d9bd36034808cd564490b1e656edbe7"
+            ))
+        );
+    }
+
+    fn pair(prover_pin: &[u8], verifier_pin: &[u8]) -> Result<(), PairingError> {
+        let ids = Identities { prover: b"phone".to_vec(), verifier: b"watch".to_vec() };
+        let prover = Prover::new(P256, prover_pin, ids.clone(), &[1; P256_SEED_LEN]).unwrap();
+        let mut verifier =
+            pairing::Verifier::new(P256, verifier_pin, ids, &[2; P256_SEED_LEN]).unwrap();
+        let reply = verifier.reply(&PairingStart { share: prover.share().to_vec() })?;
+        let (confirm, keys) = prover.finish(&reply)?;
+        assert_eq!(verifier.confirm(&confirm)?, keys);
+        Ok(())
+    }
+
+    #[test]
+    fn test_p256_pairing() {
+        assert_eq!(pair(b"123456", b"123456"), Ok(()));
+        assert_eq!(pair(b"123456", b"654321"), Err(PairingError::Confirmation));
+    }
+}
@@ -0,0 +1,1137 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! CTAP2 messages over RemoteAuth, for the remote device to act as a platform authenticator.
+//!
+//! Like a hybrid (caBLE) authenticator, the remote device answers the CTAP2 commands of a
+//! WebAuthn client on this device, here over an established `SecureChannel` rather than a tunnel
+//! service. A CTAP2 message is a command or status byte followed by its parameters, a CBOR map
+//! keyed by integers. It travels whole as the payload of a `CtapRequest`, or of the
+//! `CtapResponse` answering it, sealed with the session keys like any payload, and split by
+//! `framing` when larger than a packet; multiplexed connections carry it on `CTAP_CHANNEL`.
+//!
+//! `CtapClient` sends the commands and decodes their responses, failing with `Status` on an error
+//! status. On the remote device, `respond` decodes the command of a `CtapRequest` for a
+//! `CtapAuthenticator` and answers with its response, or with the status of its error: a
+//! malformed command gets the CTAP2 status of what is wrong with it.
+//!
+//! Parameters are encoded in deterministic CBOR, as CTAP2 requires, and unknown parameters are
+//! ignored, as it allows. Only the commands of a hybrid authenticator are supported:
+//! `authenticatorClientPIN` and the others are answered with `INVALID_COMMAND`.
+
+use crate::codec::cbor::{self, CborError, DecodeLimits, Value};
+use crate::cose::Aead;
+use crate::kdf::HASH_LEN;
+use crate::messages::{decode_response, DecodeError, Encoding, Message, Reader, Request, Writer};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::request_metadata::RequestMetadata;
+use crate::schema::{Field, Rule, Schema};
+use crate::secure_channel::SecureChannel;
+use async_trait::async_trait;
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Longest CTAP2 message accepted, command or status byte included.
+pub const MAX_CTAP_MESSAGE_LEN: usize = 16 * 1024;
+/// How long the remote device has to answer a command by default, user presence included.
+pub const DEFAULT_CTAP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Length of an AAGUID.
+pub const AAGUID_LEN: usize = 16;
+
+const MAKE_CREDENTIAL: u8 = 0x01;
+const GET_ASSERTION: u8 = 0x02;
+const GET_INFO: u8 = 0x04;
+const GET_NEXT_ASSERTION: u8 = 0x08;
+const SELECTION: u8 = 0x0b;
+
+// The only credential type of WebAuthn.
+const PUBLIC_KEY: &str = "public-key";
+
+const CTAP_MESSAGE_RULE: Rule = Rule::Bytes { min_len: 1, max_len: MAX_CTAP_MESSAGE_LEN };
+
+/// Status byte of a CTAP2 response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CtapStatus(pub u8);
+
+impl CtapStatus {
+    /// The command succeeded.
+    pub const OK: Self = Self(0x00);
+    /// The command is not supported.
+    pub const INVALID_COMMAND: Self = Self(0x01);
+    /// A parameter is invalid.
+    pub const INVALID_PARAMETER: Self = Self(0x02);
+    /// The message has an invalid length, e.g. none.
+    pub const INVALID_LENGTH: Self = Self(0x03);
+    /// A parameter has the wrong CBOR type.
+    pub const CBOR_UNEXPECTED_TYPE: Self = Self(0x11);
+    /// The parameters are not valid CBOR.
+    pub const INVALID_CBOR: Self = Self(0x12);
+    /// A required parameter is missing.
+    pub const MISSING_PARAMETER: Self = Self(0x14);
+    /// The authenticator already holds a credential of the exclude list.
+    pub const CREDENTIAL_EXCLUDED: Self = Self(0x19);
+    /// None of the algorithms requested is supported.
+    pub const UNSUPPORTED_ALGORITHM: Self = Self(0x26);
+    /// The user declined.
+    pub const OPERATION_DENIED: Self = Self(0x27);
+    /// No credential of the allow list is held.
+    pub const NO_CREDENTIALS: Self = Self(0x2e);
+    /// The user didn't act in time.
+    pub const USER_ACTION_TIMEOUT: Self = Self(0x2f);
+    /// The command is not allowed now, e.g. no assertion is left.
+    pub const NOT_ALLOWED: Self = Self(0x30);
+    /// Any other failure.
+    pub const OTHER: Self = Self(0x7f);
+}
+
+impl std::fmt::Display for CtapStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CTAP2 status {:#04x}", self.0)
+    }
+}
+
+/// Why a CTAP2 message could not be decoded, or a command failed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum CtapError {
+    /// The authenticator answered with an error status.
+    #[error("authenticator answered with {0}")]
+    Status(CtapStatus),
+    /// The message has no command or status byte.
+    #[error("empty CTAP2 message")]
+    Empty,
+    /// The command is not supported.
+    #[error("unsupported CTAP2 command {0:#04x}")]
+    UnsupportedCommand(u8),
+    /// The parameters are not canonical CBOR.
+    #[error("invalid parameters: {0}")]
+    Cbor(#[from] CborError),
+    /// A required parameter is missing, or a required member of it.
+    #[error("missing parameter {0}")]
+    MissingParameter(i64),
+    /// A parameter, or a member of it, has the wrong type. 0 is the parameters themselves.
+    #[error("parameter {0} of the wrong type")]
+    UnexpectedType(i64),
+    /// A parameter has the right type, but an invalid value.
+    #[error("invalid parameter {0}")]
+    InvalidParameter(i64),
+}
+
+impl CtapError {
+    /// Returns the status an authenticator answers a command failing with this error with.
+    pub fn status(&self) -> CtapStatus {
+        match self {
+            Self::Status(status) => *status,
+            Self::Empty => CtapStatus::INVALID_LENGTH,
+            Self::UnsupportedCommand(_) => CtapStatus::INVALID_COMMAND,
+            Self::Cbor(_) => CtapStatus::INVALID_CBOR,
+            Self::MissingParameter(_) => CtapStatus::MISSING_PARAMETER,
+            Self::UnexpectedType(_) => CtapStatus::CBOR_UNEXPECTED_TYPE,
+            Self::InvalidParameter(_) => CtapStatus::INVALID_PARAMETER,
+        }
+    }
+}
+
+/// Relying party a credential is scoped to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelyingParty {
+    /// Its id, a domain.
+    pub id: String,
+    /// Its name, for display.
+    pub name: Option<String>,
+}
+
+/// User account a credential is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    /// Its handle, opaque to the authenticator.
+    pub id: Vec<u8>,
+    /// Its name, e.g. an email address.
+    pub name: Option<String>,
+    /// Its name for display.
+    pub display_name: Option<String>,
+}
+
+/// Public key credential, by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CredentialDescriptor {
+    /// Id of the credential.
+    pub id: Vec<u8>,
+}
+
+/// Options of a command, each left to the authenticator if unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CtapOptions {
+    /// Whether the credential must be discoverable.
+    pub resident_key: Option<bool>,
+    /// Whether the user must be present.
+    pub user_presence: Option<bool>,
+    /// Whether the user must be verified.
+    pub user_verification: Option<bool>,
+}
+
+/// Parameters of `authenticatorMakeCredential`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MakeCredentialRequest {
+    /// Hash of the client data.
+    pub client_data_hash: [u8; HASH_LEN],
+    /// Relying party of the credential.
+    pub rp: RelyingParty,
+    /// User of the credential.
+    pub user: User,
+    /// COSE ids of the algorithms acceptable for the credential, most preferred first.
+    pub algorithms: Vec<i64>,
+    /// Credentials whose authenticator must not create another.
+    pub exclude_list: Vec<CredentialDescriptor>,
+    /// Options of the command.
+    pub options: CtapOptions,
+}
+
+/// Response to `authenticatorMakeCredential`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MakeCredentialResponse {
+    /// Format of the attestation statement, e.g. "packed" or "none".
+    pub fmt: String,
+    /// Authenticator data, with the attested credential.
+    pub auth_data: Vec<u8>,
+    /// Attestation statement, in the format of `fmt`.
+    pub att_stmt: Value,
+}
+
+/// Parameters of `authenticatorGetAssertion`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetAssertionRequest {
+    /// Id of the relying party.
+    pub rp_id: String,
+    /// Hash of the client data.
+    pub client_data_hash: [u8; HASH_LEN],
+    /// Credentials acceptable to the relying party, any discoverable one if empty.
+    pub allow_list: Vec<CredentialDescriptor>,
+    /// Options of the command.
+    pub options: CtapOptions,
+}
+
+/// Response to `authenticatorGetAssertion` or `authenticatorGetNextAssertion`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetAssertionResponse {
+    /// Credential asserted.
+    pub credential: CredentialDescriptor,
+    /// Authenticator data.
+    pub auth_data: Vec<u8>,
+    /// Signature of the authenticator data and of the client data hash.
+    pub signature: Vec<u8>,
+    /// User of a discoverable credential.
+    pub user: Option<User>,
+    /// Number of credentials of the relying party, to get with `GetNextAssertion`, in the first
+    /// response if more than one.
+    pub number_of_credentials: Option<u32>,
+}
+
+/// Response to `authenticatorGetInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetInfoResponse {
+    /// Versions supported, e.g. "FIDO_2_0".
+    pub versions: Vec<String>,
+    /// Extensions supported.
+    pub extensions: Vec<String>,
+    /// Model of the authenticator.
+    pub aaguid: [u8; AAGUID_LEN],
+    /// Options supported, by id, e.g. "rk", and whether they are on.
+    pub options: BTreeMap<String, bool>,
+    /// Longest message supported, in bytes.
+    pub max_msg_size: Option<u32>,
+    /// COSE ids of the algorithms supported, most preferred first.
+    pub algorithms: Vec<i64>,
+}
+
+/// A CTAP2 command, with its parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CtapCommand {
+    /// Creates a credential.
+    MakeCredential(MakeCredentialRequest),
+    /// Signs with a credential.
+    GetAssertion(GetAssertionRequest),
+    /// Describes the authenticator.
+    GetInfo,
+    /// Signs with the next credential of the last `GetAssertion`.
+    GetNextAssertion,
+    /// Asks the user whether to use the authenticator.
+    Selection,
+}
+
+impl CtapCommand {
+    /// Returns the command byte.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::MakeCredential(_) => MAKE_CREDENTIAL,
+            Self::GetAssertion(_) => GET_ASSERTION,
+            Self::GetInfo => GET_INFO,
+            Self::GetNextAssertion => GET_NEXT_ASSERTION,
+            Self::Selection => SELECTION,
+        }
+    }
+
+    /// Encodes the command byte, then the parameters if any.
+    pub fn encode(&self) -> Vec<u8> {
+        let parameters = match self {
+            Self::MakeCredential(request) => Some(request.to_value()),
+            Self::GetAssertion(request) => Some(request.to_value()),
+            Self::GetInfo | Self::GetNextAssertion | Self::Selection => None,
+        };
+        encode_message(self.code(), parameters)
+    }
+
+    /// Decodes a command from `bytes`. Fails with `UnsupportedCommand` on the commands other
+    /// than those of a hybrid authenticator.
+    pub fn decode(bytes: &[u8]) -> Result<Self, CtapError> {
+        let (&code, parameters) = bytes.split_first().ok_or(CtapError::Empty)?;
+        let parameters = decode_parameters(parameters)?;
+        match code {
+            MAKE_CREDENTIAL => Ok(Self::MakeCredential(Parameters::from_value(parameters)?)),
+            GET_ASSERTION => Ok(Self::GetAssertion(Parameters::from_value(parameters)?)),
+            GET_INFO => Ok(Self::GetInfo),
+            GET_NEXT_ASSERTION => Ok(Self::GetNextAssertion),
+            SELECTION => Ok(Self::Selection),
+            code => Err(CtapError::UnsupportedCommand(code)),
+        }
+    }
+}
+
+/// CTAP2 command, sent to the remote device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtapRequest {
+    /// Encoded command, see `CtapCommand`.
+    pub message: Vec<u8>,
+}
+
+/// CTAP2 response of the remote device to a `CtapRequest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtapResponse {
+    /// Status byte, then the parameters of the response if any.
+    pub message: Vec<u8>,
+}
+
+impl Message for CtapRequest {
+    const TYPE: u8 = 25;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "ctap_request",
+        fields: &[Field::new(1, "message", CTAP_MESSAGE_RULE)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.message);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { message: reader.bytes()? })
+    }
+}
+
+impl Request for CtapRequest {
+    type Response = CtapResponse;
+}
+
+impl Message for CtapResponse {
+    const TYPE: u8 = 26;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "ctap_response",
+        fields: &[Field::new(1, "message", CTAP_MESSAGE_RULE)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.message);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { message: reader.bytes()? })
+    }
+}
+
+/// Parameters of a command or of a response, as a CBOR map.
+trait Parameters: Sized {
+    fn to_value(&self) -> Value;
+
+    fn from_value(value: Value) -> Result<Self, CtapError>;
+}
+
+/// Members of a CBOR map, taken out one by one.
+struct Fields {
+    entries: Vec<(Value, Value)>,
+    /// Parameter the map is, reported in errors: 0 for the parameters themselves.
+    context: i64,
+}
+
+impl Fields {
+    fn new(value: Value, parameter: i64) -> Result<Self, CtapError> {
+        match value {
+            Value::Map(entries) => Ok(Self { entries, context: parameter }),
+            _ => Err(CtapError::UnexpectedType(parameter)),
+        }
+    }
+
+    fn take(&mut self, key: &Value) -> Option<Value> {
+        let at = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.swap_remove(at).1)
+    }
+
+    /// Takes parameter `key`, of a command or of a response.
+    fn parameter(&mut self, key: i64) -> Option<Value> {
+        self.take(&Value::Integer(key.into()))
+    }
+
+    /// Takes parameter `key`, failing with `MissingParameter` if it is absent.
+    fn required(&mut self, key: i64) -> Result<Value, CtapError> {
+        self.parameter(key).ok_or(CtapError::MissingParameter(key))
+    }
+
+    /// Takes member `key`, of a map inside a parameter.
+    fn member(&mut self, key: &str) -> Option<Value> {
+        self.take(&Value::Text(key.to_string()))
+    }
+
+    /// Takes member `key`, failing with `MissingParameter` if it is absent.
+    fn required_member(&mut self, key: &str) -> Result<Value, CtapError> {
+        self.member(key).ok_or(CtapError::MissingParameter(self.context))
+    }
+}
+
+fn as_bytes(value: Value, parameter: i64) -> Result<Vec<u8>, CtapError> {
+    match value {
+        Value::Bytes(bytes) => Ok(bytes),
+        _ => Err(CtapError::UnexpectedType(parameter)),
+    }
+}
+
+fn as_text(value: Value, parameter: i64) -> Result<String, CtapError> {
+    match value {
+        Value::Text(text) => Ok(text),
+        _ => Err(CtapError::UnexpectedType(parameter)),
+    }
+}
+
+fn as_bool(value: Value, parameter: i64) -> Result<bool, CtapError> {
+    match value {
+        Value::Bool(value) => Ok(value),
+        _ => Err(CtapError::UnexpectedType(parameter)),
+    }
+}
+
+fn as_integer(value: Value, parameter: i64) -> Result<i64, CtapError> {
+    match value {
+        Value::Integer(value) => {
+            i64::try_from(value).map_err(|_| CtapError::InvalidParameter(parameter))
+        }
+        _ => Err(CtapError::UnexpectedType(parameter)),
+    }
+}
+
+fn as_array(value: Value, parameter: i64) -> Result<Vec<Value>, CtapError> {
+    match value {
+        Value::Array(items) => Ok(items),
+        _ => Err(CtapError::UnexpectedType(parameter)),
+    }
+}
+
+fn as_hash(value: Value, parameter: i64) -> Result<[u8; HASH_LEN], CtapError> {
+    as_bytes(value, parameter)?
+        .as_slice()
+        .try_into()
+        .map_err(|_| CtapError::InvalidParameter(parameter))
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+/// Returns the map of the members of `entries` that are set.
+fn text_map<'a>(entries: impl IntoIterator<Item = (&'a str, Option<Value>)>) -> Value {
+    Value::Map(entries.into_iter().filter_map(|(key, value)| Some((text(key), value?))).collect())
+}
+
+/// Returns the map of the parameters of `entries` that are set.
+fn parameter_map(entries: impl IntoIterator<Item = (i64, Option<Value>)>) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .filter_map(|(key, value)| Some((Value::Integer(key.into()), value?)))
+            .collect(),
+    )
+}
+
+impl RelyingParty {
+    fn to_value(&self) -> Value {
+        text_map([("id", Some(text(&self.id))), ("name", self.name.as_deref().map(text))])
+    }
+
+    fn from_value(value: Value, parameter: i64) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, parameter)?;
+        Ok(Self {
+            id: as_text(fields.required_member("id")?, parameter)?,
+            name: fields.member("name").map(|name| as_text(name, parameter)).transpose()?,
+        })
+    }
+}
+
+impl User {
+    fn to_value(&self) -> Value {
+        text_map([
+            ("id", Some(Value::Bytes(self.id.clone()))),
+            ("name", self.name.as_deref().map(text)),
+            ("displayName", self.display_name.as_deref().map(text)),
+        ])
+    }
+
+    fn from_value(value: Value, parameter: i64) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, parameter)?;
+        let mut optional_text =
+            |key| fields.member(key).map(|value| as_text(value, parameter)).transpose();
+        let (name, display_name) = (optional_text("name")?, optional_text("displayName")?);
+        Ok(Self { id: as_bytes(fields.required_member("id")?, parameter)?, name, display_name })
+    }
+}
+
+impl CredentialDescriptor {
+    fn to_value(&self) -> Value {
+        text_map([("id", Some(Value::Bytes(self.id.clone()))), ("type", Some(text(PUBLIC_KEY)))])
+    }
+
+    /// Returns the descriptor of `value`, or None if its credential is not a public key one.
+    fn from_value(value: Value, parameter: i64) -> Result<Option<Self>, CtapError> {
+        let mut fields = Fields::new(value, parameter)?;
+        if as_text(fields.required_member("type")?, parameter)? != PUBLIC_KEY {
+            return Ok(None);
+        }
+        Ok(Some(Self { id: as_bytes(fields.required_member("id")?, parameter)? }))
+    }
+}
+
+/// Returns the array of the descriptors of `credentials`, or None if empty.
+fn descriptors_to_value(credentials: &[CredentialDescriptor]) -> Option<Value> {
+    (!credentials.is_empty())
+        .then(|| Value::Array(credentials.iter().map(CredentialDescriptor::to_value).collect()))
+}
+
+/// Returns the public key descriptors of the array `value`, skipping the others.
+fn descriptors_from_value(
+    value: Value,
+    parameter: i64,
+) -> Result<Vec<CredentialDescriptor>, CtapError> {
+    as_array(value, parameter)?
+        .into_iter()
+        .filter_map(|value| CredentialDescriptor::from_value(value, parameter).transpose())
+        .collect()
+}
+
+/// Returns the array of the public key credential parameters of `algorithms`.
+fn algorithms_to_value(algorithms: &[i64]) -> Value {
+    Value::Array(
+        algorithms
+            .iter()
+            .map(|algorithm| {
+                text_map([
+                    ("alg", Some(Value::Integer((*algorithm).into()))),
+                    ("type", Some(text(PUBLIC_KEY))),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Returns the algorithms of the public key credential parameters of the array `value`,
+/// skipping the parameters of other credential types.
+fn algorithms_from_value(value: Value, parameter: i64) -> Result<Vec<i64>, CtapError> {
+    let mut algorithms = Vec::new();
+    for item in as_array(value, parameter)? {
+        let mut fields = Fields::new(item, parameter)?;
+        if as_text(fields.required_member("type")?, parameter)? == PUBLIC_KEY {
+            algorithms.push(as_integer(fields.required_member("alg")?, parameter)?);
+        }
+    }
+    Ok(algorithms)
+}
+
+impl CtapOptions {
+    /// Returns the map of the options set, or None if none is.
+    fn to_value(self) -> Option<Value> {
+        let options =
+            [("rk", self.resident_key), ("up", self.user_presence), ("uv", self.user_verification)];
+        options
+            .iter()
+            .any(|(_, value)| value.is_some())
+            .then(|| text_map(options.map(|(key, value)| (key, value.map(Value::Bool)))))
+    }
+
+    fn from_value(value: Value, parameter: i64) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, parameter)?;
+        let mut option =
+            |key| fields.member(key).map(|value| as_bool(value, parameter)).transpose();
+        Ok(Self {
+            resident_key: option("rk")?,
+            user_presence: option("up")?,
+            user_verification: option("uv")?,
+        })
+    }
+}
+
+impl Parameters for MakeCredentialRequest {
+    fn to_value(&self) -> Value {
+        parameter_map([
+            (1, Some(Value::Bytes(self.client_data_hash.to_vec()))),
+            (2, Some(self.rp.to_value())),
+            (3, Some(self.user.to_value())),
+            (4, Some(algorithms_to_value(&self.algorithms))),
+            (5, descriptors_to_value(&self.exclude_list)),
+            (7, self.options.to_value()),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, 0)?;
+        Ok(Self {
+            client_data_hash: as_hash(fields.required(1)?, 1)?,
+            rp: RelyingParty::from_value(fields.required(2)?, 2)?,
+            user: User::from_value(fields.required(3)?, 3)?,
+            algorithms: algorithms_from_value(fields.required(4)?, 4)?,
+            exclude_list: fields
+                .parameter(5)
+                .map(|value| descriptors_from_value(value, 5))
+                .transpose()?
+                .unwrap_or_default(),
+            options: fields
+                .parameter(7)
+                .map(|value| CtapOptions::from_value(value, 7))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl Parameters for MakeCredentialResponse {
+    fn to_value(&self) -> Value {
+        parameter_map([
+            (1, Some(text(&self.fmt))),
+            (2, Some(Value::Bytes(self.auth_data.clone()))),
+            (3, Some(self.att_stmt.clone())),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, 0)?;
+        let att_stmt = fields.required(3)?;
+        if !matches!(att_stmt, Value::Map(_)) {
+            return Err(CtapError::UnexpectedType(3));
+        }
+        Ok(Self {
+            fmt: as_text(fields.required(1)?, 1)?,
+            auth_data: as_bytes(fields.required(2)?, 2)?,
+            att_stmt,
+        })
+    }
+}
+
+impl Parameters for GetAssertionRequest {
+    fn to_value(&self) -> Value {
+        parameter_map([
+            (1, Some(text(&self.rp_id))),
+            (2, Some(Value::Bytes(self.client_data_hash.to_vec()))),
+            (3, descriptors_to_value(&self.allow_list)),
+            (5, self.options.to_value()),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, 0)?;
+        Ok(Self {
+            rp_id: as_text(fields.required(1)?, 1)?,
+            client_data_hash: as_hash(fields.required(2)?, 2)?,
+            allow_list: fields
+                .parameter(3)
+                .map(|value| descriptors_from_value(value, 3))
+                .transpose()?
+                .unwrap_or_default(),
+            options: fields
+                .parameter(5)
+                .map(|value| CtapOptions::from_value(value, 5))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl Parameters for GetAssertionResponse {
+    fn to_value(&self) -> Value {
+        parameter_map([
+            (1, Some(self.credential.to_value())),
+            (2, Some(Value::Bytes(self.auth_data.clone()))),
+            (3, Some(Value::Bytes(self.signature.clone()))),
+            (4, self.user.as_ref().map(User::to_value)),
+            (5, self.number_of_credentials.map(|count| Value::Integer(count.into()))),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, 0)?;
+        Ok(Self {
+            credential: CredentialDescriptor::from_value(fields.required(1)?, 1)?
+                .ok_or(CtapError::InvalidParameter(1))?,
+            auth_data: as_bytes(fields.required(2)?, 2)?,
+            signature: as_bytes(fields.required(3)?, 3)?,
+            user: fields.parameter(4).map(|value| User::from_value(value, 4)).transpose()?,
+            number_of_credentials: fields
+                .parameter(5)
+                .map(|value| {
+                    u32::try_from(as_integer(value, 5)?).map_err(|_| CtapError::InvalidParameter(5))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+impl Parameters for GetInfoResponse {
+    fn to_value(&self) -> Value {
+        let texts = |texts: &[String]| Value::Array(texts.iter().map(|t| text(t)).collect());
+        parameter_map([
+            (1, Some(texts(&self.versions))),
+            (2, (!self.extensions.is_empty()).then(|| texts(&self.extensions))),
+            (3, Some(Value::Bytes(self.aaguid.to_vec()))),
+            (
+                4,
+                (!self.options.is_empty()).then(|| {
+                    Value::Map(
+                        self.options
+                            .iter()
+                            .map(|(option, on)| (text(option), Value::Bool(*on)))
+                            .collect(),
+                    )
+                }),
+            ),
+            (5, self.max_msg_size.map(|size| Value::Integer(size.into()))),
+            (10, (!self.algorithms.is_empty()).then(|| algorithms_to_value(&self.algorithms))),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, CtapError> {
+        let mut fields = Fields::new(value, 0)?;
+        let texts = |value, parameter| -> Result<Vec<String>, CtapError> {
+            as_array(value, parameter)?.into_iter().map(|item| as_text(item, parameter)).collect()
+        };
+        let options = match fields.parameter(4) {
+            None => BTreeMap::new(),
+            Some(value) => Fields::new(value, 4)?
+                .entries
+                .into_iter()
+                .map(|(option, on)| Ok((as_text(option, 4)?, as_bool(on, 4)?)))
+                .collect::<Result<_, CtapError>>()?,
+        };
+        Ok(Self {
+            versions: texts(fields.required(1)?, 1)?,
+            extensions: fields
+                .parameter(2)
+                .map(|value| texts(value, 2))
+                .transpose()?
+                .unwrap_or_default(),
+            aaguid: as_bytes(fields.required(3)?, 3)?
+                .as_slice()
+                .try_into()
+                .map_err(|_| CtapError::InvalidParameter(3))?,
+            options,
+            max_msg_size: fields
+                .parameter(5)
+                .map(|value| {
+                    u32::try_from(as_integer(value, 5)?).map_err(|_| CtapError::InvalidParameter(5))
+                })
+                .transpose()?,
+            algorithms: fields
+                .parameter(10)
+                .map(|value| algorithms_from_value(value, 10))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Encodes the command or status byte `code`, then `parameters` if any.
+fn encode_message(code: u8, parameters: Option<Value>) -> Vec<u8> {
+    let mut message = vec![code];
+    if let Some(parameters) = parameters {
+        message.extend(cbor::encode(&parameters));
+    }
+    message
+}
+
+/// Decodes the parameters of a message, an empty map if it has none.
+fn decode_parameters(bytes: &[u8]) -> Result<Value, CtapError> {
+    if bytes.is_empty() {
+        return Ok(Value::Map(Vec::new()));
+    }
+    let limits = DecodeLimits { max_len: MAX_CTAP_MESSAGE_LEN, ..Default::default() };
+    match cbor::decode(bytes, limits)? {
+        parameters @ Value::Map(_) => Ok(parameters),
+        _ => Err(CtapError::UnexpectedType(0)),
+    }
+}
+
+/// Decodes the parameters of the response `bytes`, failing with `Status` on an error status.
+fn decode_reply(bytes: &[u8]) -> Result<Value, CtapError> {
+    let (&status, parameters) = bytes.split_first().ok_or(CtapError::Empty)?;
+    if status != CtapStatus::OK.0 {
+        return Err(CtapError::Status(CtapStatus(status)));
+    }
+    decode_parameters(parameters)
+}
+
+/// Sends CTAP2 commands to the remote devices of a secure channel.
+pub struct CtapClient<P: Platform + ?Sized, A: Aead> {
+    channel: Arc<SecureChannel<P, A>>,
+    timeout: Duration,
+}
+
+impl<P: Platform + ?Sized, A: Aead> CtapClient<P, A> {
+    /// Sends the commands on the established connections of `channel`, giving the remote device
+    /// `DEFAULT_CTAP_TIMEOUT` to answer each.
+    pub fn new(channel: Arc<SecureChannel<P, A>>) -> Self {
+        Self { channel, timeout: DEFAULT_CTAP_TIMEOUT }
+    }
+
+    /// Sets how long the remote device has to answer a command.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Creates a credential on the remote device of `connection_id`.
+    pub async fn make_credential(
+        &self,
+        connection_id: i32,
+        request: MakeCredentialRequest,
+    ) -> anyhow::Result<MakeCredentialResponse> {
+        let reply = self.transact(connection_id, &CtapCommand::MakeCredential(request)).await?;
+        Ok(MakeCredentialResponse::from_value(reply)?)
+    }
+
+    /// Signs with a credential of the remote device of `connection_id`.
+    pub async fn get_assertion(
+        &self,
+        connection_id: i32,
+        request: GetAssertionRequest,
+    ) -> anyhow::Result<GetAssertionResponse> {
+        let reply = self.transact(connection_id, &CtapCommand::GetAssertion(request)).await?;
+        Ok(GetAssertionResponse::from_value(reply)?)
+    }
+
+    /// Signs with the next credential of the last `get_assertion` on `connection_id`.
+    pub async fn get_next_assertion(
+        &self,
+        connection_id: i32,
+    ) -> anyhow::Result<GetAssertionResponse> {
+        let reply = self.transact(connection_id, &CtapCommand::GetNextAssertion).await?;
+        Ok(GetAssertionResponse::from_value(reply)?)
+    }
+
+    /// Describes the remote device of `connection_id` as an authenticator.
+    pub async fn get_info(&self, connection_id: i32) -> anyhow::Result<GetInfoResponse> {
+        let reply = self.transact(connection_id, &CtapCommand::GetInfo).await?;
+        Ok(GetInfoResponse::from_value(reply)?)
+    }
+
+    /// Asks the user of the remote device of `connection_id` whether to use it.
+    pub async fn selection(&self, connection_id: i32) -> anyhow::Result<()> {
+        self.transact(connection_id, &CtapCommand::Selection).await?;
+        Ok(())
+    }
+
+    /// Sends `command` sealed on `connection_id`, and returns the parameters of its response.
+    async fn transact(&self, connection_id: i32, command: &CtapCommand) -> anyhow::Result<Value> {
+        let request = CtapRequest { message: command.encode() };
+        let response = self
+            .channel
+            .send_request(
+                connection_id,
+                &request.encode(),
+                RequestMetadata::new(),
+                Some(self.timeout),
+            )
+            .await?;
+        let response: CtapResponse = decode_response(&response.payload, Encoding::default())?;
+        decode_reply(&response.message).map_err(|e| {
+            warn!(
+                "CTAP2 command {:#04x} on connection {} failed: {}",
+                command.code(),
+                connection_id,
+                e
+            );
+            e.into()
+        })
+    }
+}
+
+/// Authenticator answering CTAP2 commands, on the remote device. Each command fails with the
+/// status to answer it with.
+#[async_trait]
+pub trait CtapAuthenticator: Send + Sync {
+    /// Creates a credential, once the user agreed.
+    async fn make_credential(
+        &self,
+        request: MakeCredentialRequest,
+    ) -> Result<MakeCredentialResponse, CtapStatus>;
+
+    /// Signs with a credential, once the user agreed.
+    async fn get_assertion(
+        &self,
+        request: GetAssertionRequest,
+    ) -> Result<GetAssertionResponse, CtapStatus>;
+
+    /// Signs with the next credential of the last `get_assertion`.
+    async fn get_next_assertion(&self) -> Result<GetAssertionResponse, CtapStatus>;
+
+    /// Describes the authenticator.
+    async fn get_info(&self) -> Result<GetInfoResponse, CtapStatus>;
+
+    /// Asks the user whether to use the authenticator.
+    async fn selection(&self) -> Result<(), CtapStatus>;
+}
+
+/// Answers `request` with `authenticator`, on the remote device.
+pub async fn respond(authenticator: &dyn CtapAuthenticator, request: &CtapRequest) -> CtapResponse {
+    let reply = match CtapCommand::decode(&request.message) {
+        Ok(command) => run(authenticator, command).await,
+        Err(e) => {
+            warn!("rejecting CTAP2 command: {}", e);
+            Err(e.status())
+        }
+    };
+    let message = match reply {
+        Ok(parameters) => encode_message(CtapStatus::OK.0, parameters),
+        Err(status) => vec![status.0],
+    };
+    CtapResponse { message }
+}
+
+async fn run(
+    authenticator: &dyn CtapAuthenticator,
+    command: CtapCommand,
+) -> Result<Option<Value>, CtapStatus> {
+    Ok(match command {
+        CtapCommand::MakeCredential(request) => {
+            Some(authenticator.make_credential(request).await?.to_value())
+        }
+        CtapCommand::GetAssertion(request) => {
+            Some(authenticator.get_assertion(request).await?.to_value())
+        }
+        CtapCommand::GetNextAssertion => Some(authenticator.get_next_assertion().await?.to_value()),
+        CtapCommand::GetInfo => Some(authenticator.get_info().await?.to_value()),
+        CtapCommand::Selection => {
+            authenticator.selection().await?;
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_credential_request() -> MakeCredentialRequest {
+        MakeCredentialRequest {
+            client_data_hash: [1; HASH_LEN],
+            rp: RelyingParty { id: "example.com".to_string(), name: Some("Example".to_string()) },
+            user: User { id: vec![2; 16], name: Some("ada".to_string()), display_name: None },
+            algorithms: vec![-7, -8],
+            exclude_list: vec![CredentialDescriptor { id: vec![3; 16] }],
+            options: CtapOptions { resident_key: Some(true), ..Default::default() },
+        }
+    }
+
+    fn assertion() -> GetAssertionResponse {
+        GetAssertionResponse {
+            credential: CredentialDescriptor { id: vec![3; 16] },
+            auth_data: vec![4; 37],
+            signature: vec![5; 64],
+            user: None,
+            number_of_credentials: Some(2),
+        }
+    }
+
+    fn info() -> GetInfoResponse {
+        GetInfoResponse {
+            versions: vec!["FIDO_2_0".to_string(), "FIDO_2_1".to_string()],
+            extensions: Vec::new(),
+            aaguid: [6; AAGUID_LEN],
+            options: BTreeMap::from([("rk".to_string(), true), ("uv".to_string(), false)]),
+            max_msg_size: Some(MAX_CTAP_MESSAGE_LEN as u32),
+            algorithms: vec![-7],
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let commands = [
+            CtapCommand::MakeCredential(make_credential_request()),
+            CtapCommand::GetAssertion(GetAssertionRequest {
+                rp_id: "example.com".to_string(),
+                client_data_hash: [1; HASH_LEN],
+                allow_list: Vec::new(),
+                options: CtapOptions::default(),
+            }),
+            CtapCommand::GetInfo,
+            CtapCommand::GetNextAssertion,
+            CtapCommand::Selection,
+        ];
+        for command in commands {
+            let bytes = command.encode();
+            assert_eq!(bytes[0], command.code());
+            assert_eq!(CtapCommand::decode(&bytes), Ok(command));
+        }
+        assert_eq!(CtapCommand::GetInfo.encode(), [0x04]);
+
+        let request = CtapRequest { message: CtapCommand::GetInfo.encode() };
+        assert_eq!(CtapRequest::decode(&request.encode()), Ok(request));
+    }
+
+    #[test]
+    fn test_responses() {
+        let created = MakeCredentialResponse {
+            fmt: "none".to_string(),
+            auth_data: vec![4; 37],
+            att_stmt: Value::Map(Vec::new()),
+        };
+        assert_eq!(MakeCredentialResponse::from_value(created.to_value()), Ok(created));
+        let mut assertion = assertion();
+        assertion.user = Some(User { id: vec![2; 16], name: None, display_name: None });
+        assert_eq!(GetAssertionResponse::from_value(assertion.to_value()), Ok(assertion));
+        assert_eq!(GetInfoResponse::from_value(info().to_value()), Ok(info()));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let invalid = [
+            (vec![], CtapError::Empty, CtapStatus::INVALID_LENGTH),
+            (vec![0x06], CtapError::UnsupportedCommand(0x06), CtapStatus::INVALID_COMMAND),
+            (vec![0x01, 0x80], CtapError::UnexpectedType(0), CtapStatus::CBOR_UNEXPECTED_TYPE),
+        ];
+        for (bytes, error, status) in invalid {
+            assert_eq!(CtapCommand::decode(&bytes), Err(error.clone()));
+            assert_eq!(error.status(), status);
+        }
+        assert_eq!(
+            CtapCommand::decode(&[0x01, 0xa1, 0x18]).map_err(|e| e.status()),
+            Err(CtapStatus::INVALID_CBOR)
+        );
+
+        let mut without_rp = make_credential_request().to_value();
+        let Value::Map(entries) = &mut without_rp else { unreachable!() };
+        entries.retain(|(key, _)| *key != Value::Integer(2.into()));
+        let bytes = encode_message(MAKE_CREDENTIAL, Some(without_rp));
+        assert_eq!(CtapCommand::decode(&bytes), Err(CtapError::MissingParameter(2)));
+
+        let short_hash = parameter_map([
+            (1, Some(text("example.com"))),
+            (2, Some(Value::Bytes(vec![1; HASH_LEN - 1]))),
+        ]);
+        let bytes = encode_message(GET_ASSERTION, Some(short_hash));
+        assert_eq!(CtapCommand::decode(&bytes), Err(CtapError::InvalidParameter(2)));
+
+        // Credentials of other types are skipped, unknown parameters ignored.
+        let mut request = make_credential_request().to_value();
+        let Value::Map(entries) = &mut request else { unreachable!() };
+        for (key, value) in entries.iter_mut() {
+            if *key == Value::Integer(5.into()) {
+                let Value::Array(items) = value else { unreachable!() };
+                items.push(text_map([
+                    ("id", Some(Value::Bytes(vec![9]))),
+                    ("type", Some(text("x"))),
+                ]));
+            }
+        }
+        entries.push((Value::Integer(20.into()), Value::Null));
+        let bytes = encode_message(MAKE_CREDENTIAL, Some(request));
+        assert_eq!(
+            CtapCommand::decode(&bytes),
+            Ok(CtapCommand::MakeCredential(make_credential_request()))
+        );
+    }
+
+    struct FakeAuthenticator;
+
+    #[async_trait]
+    impl CtapAuthenticator for FakeAuthenticator {
+        async fn make_credential(
+            &self,
+            request: MakeCredentialRequest,
+        ) -> Result<MakeCredentialResponse, CtapStatus> {
+            if !request.algorithms.contains(&-7) {
+                return Err(CtapStatus::UNSUPPORTED_ALGORITHM);
+            }
+            Ok(MakeCredentialResponse {
+                fmt: "none".to_string(),
+                auth_data: request.client_data_hash.to_vec(),
+                att_stmt: Value::Map(Vec::new()),
+            })
+        }
+
+        async fn get_assertion(
+            &self,
+            _: GetAssertionRequest,
+        ) -> Result<GetAssertionResponse, CtapStatus> {
+            Ok(assertion())
+        }
+
+        async fn get_next_assertion(&self) -> Result<GetAssertionResponse, CtapStatus> {
+            Err(CtapStatus::NOT_ALLOWED)
+        }
+
+        async fn get_info(&self) -> Result<GetInfoResponse, CtapStatus> {
+            Ok(info())
+        }
+
+        async fn selection(&self) -> Result<(), CtapStatus> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_respond() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let answer = |command: &[u8]| {
+            let request = CtapRequest { message: command.to_vec() };
+            runtime.block_on(respond(&FakeAuthenticator, &request)).message
+        };
+
+        let reply = decode_reply(&answer(&CtapCommand::GetInfo.encode())).unwrap();
+        assert_eq!(GetInfoResponse::from_value(reply), Ok(info()));
+        let request = make_credential_request();
+        let reply = decode_reply(&answer(&CtapCommand::MakeCredential(request).encode()));
+        assert_eq!(MakeCredentialResponse::from_value(reply.unwrap()).unwrap().auth_data, [1; 32]);
+        assert_eq!(answer(&CtapCommand::Selection.encode()), [0x00]);
+        assert_eq!(decode_reply(&[0x00]), Ok(Value::Map(Vec::new())));
+
+        let mut unsupported = make_credential_request();
+        unsupported.algorithms = vec![-8];
+        assert_eq!(
+            decode_reply(&answer(&CtapCommand::MakeCredential(unsupported).encode())),
+            Err(CtapError::Status(CtapStatus::UNSUPPORTED_ALGORITHM))
+        );
+        assert_eq!(
+            decode_reply(&answer(&CtapCommand::GetNextAssertion.encode())),
+            Err(CtapError::Status(CtapStatus::NOT_ALLOWED))
+        );
+        // authenticatorClientPIN.
+        assert_eq!(answer(&[0x06]), [CtapStatus::INVALID_COMMAND.0]);
+        assert_eq!(answer(&[]), [CtapStatus::INVALID_LENGTH.0]);
+    }
+}
@@ -0,0 +1,315 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Delta encoding of periodic state syncs.
+//!
+//! State syncs change little from one to the next. On connections whose remote device supports
+//! it, a sync is sent as the id of a baseline, an earlier sync the remote device applied, and a
+//! binary diff against it instead of in full, cutting radio time and battery on wearables.
+//!
+//! A diff is a sequence of operations: `COPY` of a range of the baseline, as its big-endian `u32`
+//! offset and length, or `INSERT` of literal bytes, as their big-endian `u32` length then the
+//! bytes. The receiver keeps the last state it applied as its baseline, and refuses a delta
+//! against another one: the sender then falls back to a full sync.
+
+use crate::messages::{DecodeError, Message, Reader, Request, Status, TypedPlatform, Writer};
+use crate::remoteauth_jni_android_platform::{Platform, RequestMetadata};
+use crate::schema::{Field, Rule, Schema};
+use log::warn;
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Shortest run of baseline bytes a diff copies instead of inserting them.
+pub const MIN_MATCH_LEN: usize = 8;
+/// Longest state synced.
+pub const MAX_STATE_LEN: usize = 64 * 1024;
+/// Baseline id of a full sync.
+pub const FULL_SYNC: u32 = 0;
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+/// Why a sync could not be applied.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaError {
+    /// The delta is against a baseline the receiver doesn't have.
+    #[error("unknown baseline {0}")]
+    UnknownBaseline(u32),
+    /// The diff is truncated, or copies outside of the baseline.
+    #[error("malformed diff")]
+    Malformed,
+    /// The state would exceed its maximum length.
+    #[error("state too large")]
+    TooLarge,
+}
+
+/// A state sync, in full or as a diff against a baseline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSync {
+    /// Id of the sync, never `FULL_SYNC`, to refer to it as a baseline.
+    pub sync_id: u32,
+    /// Id of the baseline of the diff, or `FULL_SYNC` if `data` is the state itself.
+    pub baseline_id: u32,
+    /// State, or diff.
+    pub data: Vec<u8>,
+}
+
+impl StateSync {
+    /// Whether the sync is a diff.
+    pub fn is_delta(&self) -> bool {
+        self.baseline_id != FULL_SYNC
+    }
+}
+
+impl Message for StateSync {
+    const TYPE: u8 = 14;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "state_sync",
+        fields: &[
+            Field::new(1, "sync_id", Rule::Uint { min: 1, max: u32::MAX as u64 }),
+            Field::new(2, "baseline_id", Rule::U32),
+            Field::new(3, "data", Rule::Bytes { min_len: 0, max_len: MAX_STATE_LEN }),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.sync_id);
+        writer.put_u32(self.baseline_id);
+        writer.put_bytes(&self.data);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { sync_id: reader.u32()?, baseline_id: reader.u32()?, data: reader.bytes()? })
+    }
+}
+
+impl Request for StateSync {
+    type Response = Status;
+}
+
+fn push_insert(diff: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        diff.push(INSERT);
+        diff.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        diff.extend_from_slice(bytes);
+    }
+}
+
+/// Returns the diff turning `baseline` into `target`.
+pub fn diff(baseline: &[u8], target: &[u8]) -> Vec<u8> {
+    // First offset of each run of MIN_MATCH_LEN bytes of the baseline.
+    let mut runs = HashMap::new();
+    for (offset, run) in baseline.windows(MIN_MATCH_LEN).enumerate() {
+        runs.entry(run).or_insert(offset);
+    }
+    let mut diff = Vec::new();
+    let (mut inserted, mut at) = (0, 0);
+    while at + MIN_MATCH_LEN <= target.len() {
+        let Some(&offset) = runs.get(&target[at..at + MIN_MATCH_LEN]) else {
+            at += 1;
+            continue;
+        };
+        let len = baseline[offset..].iter().zip(&target[at..]).take_while(|(a, b)| a == b).count();
+        push_insert(&mut diff, &target[inserted..at]);
+        diff.push(COPY);
+        diff.extend_from_slice(&(offset as u32).to_be_bytes());
+        diff.extend_from_slice(&(len as u32).to_be_bytes());
+        at += len;
+        inserted = at;
+    }
+    push_insert(&mut diff, &target[inserted..]);
+    diff
+}
+
+/// Returns `baseline` with `diff` applied, of at most `max_len` bytes.
+pub fn apply(baseline: &[u8], diff: &[u8], max_len: usize) -> Result<Vec<u8>, DeltaError> {
+    let mut state = Vec::new();
+    let mut rest = diff;
+    let number = |rest: &mut &[u8]| -> Result<usize, DeltaError> {
+        let (bytes, tail) = rest.split_first_chunk::<4>().ok_or(DeltaError::Malformed)?;
+        *rest = tail;
+        Ok(u32::from_be_bytes(*bytes) as usize)
+    };
+    while let Some((&op, tail)) = rest.split_first() {
+        rest = tail;
+        let bytes = match op {
+            COPY => {
+                let offset = number(&mut rest)?;
+                let len = number(&mut rest)?;
+                baseline.get(offset..offset.saturating_add(len)).ok_or(DeltaError::Malformed)?
+            }
+            INSERT => {
+                let len = number(&mut rest)?;
+                if rest.len() < len {
+                    return Err(DeltaError::Malformed);
+                }
+                let (bytes, tail) = rest.split_at(len);
+                rest = tail;
+                bytes
+            }
+            _ => return Err(DeltaError::Malformed),
+        };
+        if state.len() + bytes.len() > max_len {
+            return Err(DeltaError::TooLarge);
+        }
+        state.extend_from_slice(bytes);
+    }
+    Ok(state)
+}
+
+/// Encodes the syncs of a state, sender side.
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    last_sync_id: u32,
+    /// Last state the remote device applied, with its sync id.
+    baseline: Option<(u32, Vec<u8>)>,
+}
+
+impl DeltaEncoder {
+    /// Creates an encoder without baseline: its first sync is full.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the sync of `state`, as a diff against the baseline if `delta` and the diff is
+    /// smaller, or else in full.
+    pub fn encode(&mut self, state: &[u8], delta: bool) -> StateSync {
+        self.last_sync_id = self.last_sync_id.checked_add(1).unwrap_or(1);
+        let sync_id = self.last_sync_id;
+        if let Some((baseline_id, baseline)) = self.baseline.as_ref().filter(|_| delta) {
+            let data = diff(baseline, state);
+            if data.len() < state.len() {
+                return StateSync { sync_id, baseline_id: *baseline_id, data };
+            }
+        }
+        StateSync { sync_id, baseline_id: FULL_SYNC, data: state.to_vec() }
+    }
+
+    /// Makes `state`, sent as `sync_id`, the baseline of the next syncs, once the remote device
+    /// applied it.
+    pub fn acknowledge(&mut self, sync_id: u32, state: Vec<u8>) {
+        self.baseline = Some((sync_id, state));
+    }
+
+    /// Forgets the baseline, so that the next sync is full.
+    pub fn reset(&mut self) {
+        self.baseline = None;
+    }
+}
+
+/// Applies the syncs of a state, receiver side.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    /// Last state applied, with its sync id.
+    baseline: Option<(u32, Vec<u8>)>,
+}
+
+impl DeltaDecoder {
+    /// Creates a decoder without baseline: it only applies full syncs until one.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the state of `sync`, which becomes the baseline of the next ones.
+    pub fn apply(&mut self, sync: &StateSync) -> Result<Vec<u8>, DeltaError> {
+        let state = match &self.baseline {
+            _ if !sync.is_delta() => {
+                if sync.data.len() > MAX_STATE_LEN {
+                    return Err(DeltaError::TooLarge);
+                }
+                sync.data.clone()
+            }
+            Some((id, baseline)) if *id == sync.baseline_id => {
+                apply(baseline, &sync.data, MAX_STATE_LEN)?
+            }
+            _ => return Err(DeltaError::UnknownBaseline(sync.baseline_id)),
+        };
+        self.baseline = Some((sync.sync_id, state.clone()));
+        Ok(state)
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Syncs `state` to the remote device of `connection_id`, as a diff against the last state
+    /// it applied if it supports it. A diff it refuses, e.g. having lost its baseline, is sent
+    /// again in full. Returns its status.
+    pub async fn sync_state(
+        &self,
+        connection_id: i32,
+        encoder: &mut DeltaEncoder,
+        state: &[u8],
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Status> {
+        let delta = self.capability_cache().delta(connection_id);
+        let mut sync = encoder.encode(state, delta);
+        let mut status = self.send(connection_id, &sync, RequestMetadata::new(), timeout).await?;
+        if !status.is_ok() && sync.is_delta() {
+            warn!(
+                "connection {} refused delta sync {}, syncing in full",
+                connection_id, status.code
+            );
+            encoder.reset();
+            sync = encoder.encode(state, false);
+            status = self.send(connection_id, &sync, RequestMetadata::new(), timeout).await?;
+        }
+        if status.is_ok() {
+            encoder.acknowledge(sync.sync_id, state.to_vec());
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let baseline: Vec<u8> = (0..64).collect();
+        let mut target = baseline.clone();
+        target[20] = 0xff;
+        target.extend_from_slice(&[1, 2, 3]);
+        let diff = diff(&baseline, &target);
+        assert!(diff.len() < target.len() / 2);
+        assert_eq!(apply(&baseline, &diff, MAX_STATE_LEN), Ok(target.clone()));
+        assert_eq!(apply(&baseline, &diff, 10), Err(DeltaError::TooLarge));
+        assert_eq!(apply(&[], &[COPY, 0, 0, 0, 0, 0, 0, 0, 1], 10), Err(DeltaError::Malformed));
+        assert_eq!(apply(&[], &[INSERT, 0, 0, 0, 2, 1], 10), Err(DeltaError::Malformed));
+        assert_eq!(apply(&baseline, &super::diff(&baseline, &[]), 10), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let first: Vec<u8> = (0..64).collect();
+        let sync = encoder.encode(&first, true);
+        assert!(!sync.is_delta());
+        assert_eq!(decoder.apply(&sync), Ok(first.clone()));
+        encoder.acknowledge(sync.sync_id, first.clone());
+
+        let mut second = first.clone();
+        second[5] = 0;
+        let sync = encoder.encode(&second, true);
+        assert_eq!(sync.baseline_id, 1);
+        assert_eq!(StateSync::decode(&sync.encode()), Ok(sync.clone()));
+        assert_eq!(decoder.apply(&sync), Ok(second.clone()));
+        // The decoder moved on to the second state.
+        assert_eq!(decoder.apply(&sync), Err(DeltaError::UnknownBaseline(1)));
+        assert!(!encoder.encode(&second, false).is_delta());
+    }
+}
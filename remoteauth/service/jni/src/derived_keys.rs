@@ -0,0 +1,210 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Derived key manager: subkeys of the pairing key, one per purpose.
+//!
+//! The pairing key of an enrollment is the root of the subkeys of each `SubkeyPurpose`, never
+//! used directly. A subkey is derived for `KeyPurpose::Subkey` in the context of the label of
+//! its purpose and of its rotation epoch, and optionally bound to a connection by the
+//! transcript hash of its session. Subkeys of different purposes, epochs or connections are thus
+//! independent: a compromised telemetry key exposes neither the unlock key nor the keys of its
+//! other epochs.
+//!
+//! Each purpose rotates on its own. The current epoch of each is kept in the enrollment record,
+//! see `Enroller::rotate_subkey`, so that both devices derive the same subkeys across restarts.
+
+use crate::enrollment::EnrollmentRecord;
+use crate::kdf::{derive_key, Hkdf, KeyPurpose};
+use crate::secret::Secret;
+use crate::transcript::Kdf;
+use std::sync::Arc;
+
+/// Length of a subkey.
+pub const SUBKEY_LEN: usize = 32;
+
+/// What a subkey is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SubkeyPurpose {
+    /// Unlocking the device.
+    Unlock,
+    /// Sealing the telemetry the remote device reports.
+    Telemetry,
+    /// Syncing keys between the devices.
+    KeySync,
+}
+
+impl SubkeyPurpose {
+    /// Every purpose.
+    pub const ALL: [SubkeyPurpose; 3] = [Self::Unlock, Self::Telemetry, Self::KeySync];
+
+    /// Returns the label of the subkeys, in their HKDF info.
+    pub fn label(self) -> &'static [u8] {
+        match self {
+            Self::Unlock => b"unlock",
+            Self::Telemetry => b"telemetry",
+            Self::KeySync => b"key-sync",
+        }
+    }
+
+    /// Returns the id of the purpose in persisted records.
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Unlock => 1,
+            Self::Telemetry => 2,
+            Self::KeySync => 3,
+        }
+    }
+
+    /// Returns the purpose of `id`.
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|purpose| purpose.id() == id)
+    }
+}
+
+/// A subkey, with what it was derived for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subkey {
+    /// Purpose of the subkey.
+    pub purpose: SubkeyPurpose,
+    /// Rotation epoch of the subkey.
+    pub epoch: u32,
+    /// Key material.
+    pub key: Secret<[u8; SUBKEY_LEN]>,
+}
+
+/// Derives the subkeys of the pairing keys of enrollments.
+pub struct DerivedKeyManager {
+    kdf: Arc<dyn Kdf + Send + Sync>,
+}
+
+impl Default for DerivedKeyManager {
+    fn default() -> Self {
+        Self { kdf: Arc::new(Hkdf::new()) }
+    }
+}
+
+impl DerivedKeyManager {
+    /// Creates a manager deriving with HKDF-SHA256.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Derives with `kdf` instead, e.g. in tests.
+    pub fn with_kdf(mut self, kdf: Arc<dyn Kdf + Send + Sync>) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Returns the subkey of `purpose` of the pairing of `record`, at its current epoch. The
+    /// subkey is bound to the connection whose session has the transcript hash `connection`, if
+    /// any, and else shared by all the connections of the pairing.
+    pub fn subkey(
+        &self,
+        record: &EnrollmentRecord,
+        purpose: SubkeyPurpose,
+        connection: Option<&[u8]>,
+    ) -> anyhow::Result<Subkey> {
+        self.subkey_at(record, purpose, record.key_epoch(purpose), connection)
+    }
+
+    /// Like `subkey`, at `epoch`, e.g. to open what the remote device sealed before it learned
+    /// of a rotation.
+    pub fn subkey_at(
+        &self,
+        record: &EnrollmentRecord,
+        purpose: SubkeyPurpose,
+        epoch: u32,
+        connection: Option<&[u8]>,
+    ) -> anyhow::Result<Subkey> {
+        let epoch_bytes = epoch.to_be_bytes();
+        let mut context: Vec<&[u8]> = vec![purpose.label(), &epoch_bytes];
+        match connection {
+            Some(transcript_hash) => context.extend([&b"connection"[..], transcript_hash]),
+            None => context.push(b"pairing"),
+        }
+        let key = derive_key(
+            self.kdf.as_ref(),
+            KeyPurpose::Subkey,
+            record.pairing_key.expose(),
+            &context,
+            SUBKEY_LEN,
+        )?;
+        Ok(Subkey { purpose, epoch, key: Secret::new(key.expose().as_slice().try_into()?) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::SecurityLevel;
+    use crate::cose::Algorithm;
+    use crate::device_identity::{DeviceId, DeviceMetadata, RemoteIdentity};
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    fn record(pairing_key: u8) -> EnrollmentRecord {
+        EnrollmentRecord {
+            identity: RemoteIdentity {
+                device_id: DeviceId::new("watch").unwrap(),
+                algorithm: Algorithm::Es256,
+                public_key: vec![4; 65],
+                metadata: DeviceMetadata { name: "watch".to_string(), model: "W1".to_string() },
+            },
+            pairing_key: Secret::new(vec![pairing_key; 32]),
+            security_level: SecurityLevel::StrongBox,
+            enrolled_at: UNIX_EPOCH,
+            key_epochs: BTreeMap::new(),
+            identity_pin: None,
+        }
+    }
+
+    #[test]
+    fn test_subkeys() {
+        let manager = DerivedKeyManager::new();
+        let mut enrollment = record(7);
+        let key = |record: &EnrollmentRecord, purpose, connection: Option<&[u8]>| {
+            manager.subkey(record, purpose, connection).unwrap().key
+        };
+        let unlock = key(&enrollment, SubkeyPurpose::Unlock, None);
+        assert_eq!(unlock, key(&enrollment, SubkeyPurpose::Unlock, None));
+        assert_ne!(unlock, key(&enrollment, SubkeyPurpose::Telemetry, None));
+        assert_ne!(unlock, key(&enrollment, SubkeyPurpose::KeySync, None));
+        assert_ne!(unlock, key(&enrollment, SubkeyPurpose::Unlock, Some(&[1; 32])));
+        assert_ne!(
+            key(&enrollment, SubkeyPurpose::Unlock, Some(&[1; 32])),
+            key(&enrollment, SubkeyPurpose::Unlock, Some(&[2; 32]))
+        );
+        assert_ne!(unlock, key(&record(8), SubkeyPurpose::Unlock, None));
+
+        // Rotating a purpose leaves the others be.
+        let telemetry = key(&enrollment, SubkeyPurpose::Telemetry, None);
+        assert_eq!(enrollment.rotate_key(SubkeyPurpose::Unlock), 1);
+        let rotated = manager.subkey(&enrollment, SubkeyPurpose::Unlock, None).unwrap();
+        assert_eq!(rotated.epoch, 1);
+        assert_ne!(rotated.key, unlock);
+        assert_eq!(key(&enrollment, SubkeyPurpose::Telemetry, None), telemetry);
+        let previous = manager.subkey_at(&enrollment, SubkeyPurpose::Unlock, 0, None).unwrap();
+        assert_eq!(previous.key, unlock);
+    }
+
+    #[test]
+    fn test_purpose_ids() {
+        for purpose in SubkeyPurpose::ALL {
+            assert_eq!(SubkeyPurpose::from_id(purpose.id()), Some(purpose));
+        }
+        assert_eq!(SubkeyPurpose::from_id(0), None);
+    }
+}
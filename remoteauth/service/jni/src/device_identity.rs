@@ -0,0 +1,304 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Identities of the local device and of the remote devices it knows.
+//!
+//! A device is known by its `DeviceId`, stable across connections, transports and reboots,
+//! rather than by the id of the connection it happens to use. The local id derives from the
+//! identity key kept in Keystore, so it lasts as long as the key does; remote ids derive the
+//! same way from the key their attestation proved at enrollment. `ConnectionIdentities` tracks
+//! which device each open connection reaches, for routing and the secure channel.
+
+use crate::attestation::Attestation;
+use crate::cose::Algorithm;
+use crate::error::PlatformError;
+use crate::kdf::sha256;
+use crate::keystore::{DeviceKey, Keystore, STRONGBOX_UNAVAILABLE};
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// Keystore alias of the identity key of the local device.
+pub const IDENTITY_KEY_ALIAS: &str = "remoteauth_identity";
+/// Longest device id accepted.
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+
+/// Bytes of the public key hash making up a derived device id.
+const DERIVED_ID_LEN: usize = 16;
+
+/// Why an identity could not be used.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum IdentityError {
+    /// The device id is empty, too long, or not printable ASCII.
+    #[error("invalid device id")]
+    InvalidId,
+    /// The connection is not bound to a device.
+    #[error("connection {0} has no identity")]
+    Unidentified(i32),
+    /// No usable connection reaches the device.
+    #[error("no connection to device {0}")]
+    NotConnected(DeviceId),
+}
+
+/// Stable id of a device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(String);
+
+impl DeviceId {
+    /// Returns the device id `id`, e.g. received from Java.
+    pub fn new(id: impl Into<String>) -> Result<Self, IdentityError> {
+        let id = id.into();
+        if id.is_empty()
+            || id.len() > MAX_DEVICE_ID_LEN
+            || !id.bytes().all(|byte| byte.is_ascii_graphic())
+        {
+            return Err(IdentityError::InvalidId);
+        }
+        Ok(Self(id))
+    }
+
+    /// Returns the id of the device with the identity key `public_key`: the hex of the start of
+    /// its SHA-256.
+    pub fn from_public_key(public_key: &[u8]) -> Self {
+        let hash = sha256(public_key);
+        Self(hash[..DERIVED_ID_LEN].iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Returns the id as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What a device says about itself, shown to the user.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceMetadata {
+    /// Name given by the user, e.g. "Alex's watch".
+    pub name: String,
+    /// Model of the device.
+    pub model: String,
+}
+
+/// Identity of the local device, with its identity key in Keystore.
+pub struct LocalIdentity {
+    device_id: DeviceId,
+    public_key: Vec<u8>,
+    key: DeviceKey,
+    metadata: DeviceMetadata,
+}
+
+impl LocalIdentity {
+    /// Loads the identity key from `keystore`, generating it on first use in StrongBox, or in
+    /// the TEE on devices without StrongBox.
+    pub async fn load(
+        keystore: Arc<dyn Keystore>,
+        metadata: DeviceMetadata,
+    ) -> anyhow::Result<Self> {
+        let mut key = DeviceKey::new(Arc::clone(&keystore), IDENTITY_KEY_ALIAS, true);
+        let public_key = match key.public_key().await {
+            Err(e)
+                if e.downcast_ref()
+                    == Some(&PlatformError::KeystoreFailed(STRONGBOX_UNAVAILABLE)) =>
+            {
+                warn!("StrongBox unavailable, generating the identity key in the TEE");
+                key = DeviceKey::new(keystore, IDENTITY_KEY_ALIAS, false);
+                key.public_key().await?
+            }
+            result => result?,
+        };
+        Ok(Self { device_id: DeviceId::from_public_key(&public_key), public_key, key, metadata })
+    }
+
+    /// Returns the id of the local device.
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    /// Returns the encoded public identity key.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Returns the metadata of the local device.
+    pub fn metadata(&self) -> &DeviceMetadata {
+        &self.metadata
+    }
+
+    /// Returns the signature of `data` with the identity key.
+    pub async fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.key.sign(data).await
+    }
+}
+
+/// Identity of a remote device, as enrolled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteIdentity {
+    /// Id of the device.
+    pub device_id: DeviceId,
+    /// Algorithm of its identity key.
+    pub algorithm: Algorithm,
+    /// Its encoded public identity key.
+    pub public_key: Vec<u8>,
+    /// What it says about itself.
+    pub metadata: DeviceMetadata,
+}
+
+impl RemoteIdentity {
+    /// Returns the identity of the device whose identity key `attestation` proved.
+    pub fn from_attestation(attestation: &Attestation, metadata: DeviceMetadata) -> Self {
+        Self {
+            device_id: DeviceId::from_public_key(&attestation.public_key),
+            algorithm: attestation.algorithm,
+            public_key: attestation.public_key.clone(),
+            metadata,
+        }
+    }
+}
+
+/// Which device each open connection reaches.
+#[derive(Debug, Default)]
+pub struct ConnectionIdentities {
+    devices: Mutex<HashMap<i32, DeviceId>>,
+}
+
+impl ConnectionIdentities {
+    /// Records that `connection_id` reaches `device_id`, e.g. once its handshake authenticated
+    /// the device. Returns the device it reached before, if any.
+    pub fn bind(&self, connection_id: i32, device_id: DeviceId) -> Option<DeviceId> {
+        self.devices.lock().unwrap().insert(connection_id, device_id)
+    }
+
+    /// Forgets the device of `connection_id`, once it is closed. Returns it, if any.
+    pub fn unbind(&self, connection_id: i32) -> Option<DeviceId> {
+        self.devices.lock().unwrap().remove(&connection_id)
+    }
+
+    /// Returns the device `connection_id` reaches.
+    pub fn device(&self, connection_id: i32) -> Option<DeviceId> {
+        self.devices.lock().unwrap().get(&connection_id).cloned()
+    }
+
+    /// Returns the connections reaching `device_id`, oldest id first.
+    pub fn connections(&self, device_id: &DeviceId) -> Vec<i32> {
+        let devices = self.devices.lock().unwrap();
+        let mut connections: Vec<i32> = devices
+            .iter()
+            .filter(|(_, device)| *device == device_id)
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+        connections.sort_unstable();
+        connections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{der_signature, P256_SCALAR_LEN};
+    use crate::secret::Secret;
+    use async_trait::async_trait;
+
+    // Not cryptography: the public key is the alias, the signature the alias and the data,
+    // left-padded to the length of a P-256 signature.
+    #[derive(Default)]
+    struct FakeKeystore {
+        keys: Mutex<HashMap<String, bool>>,
+    }
+
+    #[async_trait]
+    impl Keystore for FakeKeystore {
+        async fn sign_with_device_key(&self, alias: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let signed = [alias.as_bytes(), data].concat();
+            let mut signature = vec![0; 2 * P256_SCALAR_LEN];
+            signature[2 * P256_SCALAR_LEN - signed.len()..].copy_from_slice(&signed);
+            Ok(der_signature(&signature, P256_SCALAR_LEN).unwrap())
+        }
+
+        async fn get_device_public_key(&self, alias: &str) -> anyhow::Result<Vec<u8>> {
+            match self.keys.lock().unwrap().contains_key(alias) {
+                true => Ok(alias.as_bytes().to_vec()),
+                false => Err(PlatformError::KeystoreFailed(crate::keystore::KEY_NOT_FOUND).into()),
+            }
+        }
+
+        async fn generate_key_pair(&self, alias: &str, strongbox: bool) -> anyhow::Result<Vec<u8>> {
+            if strongbox {
+                return Err(PlatformError::KeystoreFailed(STRONGBOX_UNAVAILABLE).into());
+            }
+            self.keys.lock().unwrap().insert(alias.to_string(), strongbox);
+            Ok(alias.as_bytes().to_vec())
+        }
+
+        async fn derive_backup_secret(&self, salt: &[u8]) -> anyhow::Result<Secret<Vec<u8>>> {
+            Ok(Secret::new(salt.to_vec()))
+        }
+    }
+
+    #[test]
+    fn test_device_id() {
+        assert_eq!(DeviceId::new("watch-1").unwrap().as_str(), "watch-1");
+        assert_eq!(DeviceId::new(""), Err(IdentityError::InvalidId));
+        assert_eq!(DeviceId::new("a b"), Err(IdentityError::InvalidId));
+        assert_eq!(DeviceId::new("a".repeat(MAX_DEVICE_ID_LEN + 1)), Err(IdentityError::InvalidId));
+        // SHA-256("abc") starts with ba7816bf8f01cfea414140de5dae2223.
+        let derived = DeviceId::from_public_key(b"abc");
+        assert_eq!(derived.to_string(), "ba7816bf8f01cfea414140de5dae2223");
+        assert_eq!(DeviceId::new(derived.as_str()), Ok(derived));
+    }
+
+    #[test]
+    fn test_local_identity() {
+        let keystore = Arc::new(FakeKeystore::default());
+        let metadata = DeviceMetadata { name: "phone".to_string(), model: "Pixel".to_string() };
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let identity = LocalIdentity::load(keystore.clone(), metadata.clone()).await.unwrap();
+            // Generated in the TEE, without StrongBox.
+            assert_eq!(keystore.keys.lock().unwrap().get(IDENTITY_KEY_ALIAS), Some(&false));
+            assert_eq!(identity.public_key(), IDENTITY_KEY_ALIAS.as_bytes());
+            assert_eq!(identity.device_id(), &DeviceId::from_public_key(identity.public_key()));
+            assert_eq!(identity.metadata(), &metadata);
+            let signature = identity.sign(b"!").await.unwrap();
+            assert_eq!(signature[44..], *b"remoteauth_identity!");
+            assert_eq!(signature[..44], [0; 44]);
+
+            let reloaded = LocalIdentity::load(keystore, DeviceMetadata::default()).await.unwrap();
+            assert_eq!(reloaded.device_id(), identity.device_id());
+        });
+    }
+
+    #[test]
+    fn test_connection_identities() {
+        let identities = ConnectionIdentities::default();
+        let watch = DeviceId::new("watch").unwrap();
+        assert_eq!(identities.bind(2, watch.clone()), None);
+        assert_eq!(identities.bind(1, watch.clone()), None);
+        assert_eq!(identities.bind(3, DeviceId::new("phone").unwrap()), None);
+        assert_eq!(identities.device(2), Some(watch.clone()));
+        assert_eq!(identities.connections(&watch), [1, 2]);
+        assert_eq!(identities.unbind(1), Some(watch.clone()));
+        assert_eq!(identities.connections(&watch), [2]);
+        assert_eq!(identities.device(1), None);
+    }
+}
@@ -0,0 +1,115 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Dedicated threads making the upcalls into Java.
+//!
+//! A slow Java callback would otherwise stall the runtime worker calling it. Upcalls are queued
+//! instead, on a bounded queue so that a stuck Java side applies backpressure rather than
+//! accumulating work, and run on a few threads attached to the JavaVM once, when they start.
+
+use crate::error::PlatformError;
+use crate::jvm_attach::attached_env;
+use crate::unique_jvm;
+use anyhow::anyhow;
+use jni::objects::JObject;
+use jni::{JNIEnv, JavaVM};
+use log::{error, info};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+const DISPATCHER_THREADS: usize = 2;
+const DISPATCH_QUEUE_CAPACITY: usize = 64;
+const DISPATCHER_THREAD_NAME: &str = "remoteauth-dispatch";
+// Local references a single upcall may create before they are released.
+const LOCAL_FRAME_CAPACITY: i32 = 16;
+
+/// An upcall, run on a dispatcher thread with its JNIEnv.
+pub(crate) type Upcall = Box<dyn FnOnce(&JNIEnv) + Send>;
+
+struct Dispatcher {
+    sender: SyncSender<Upcall>,
+}
+
+static DISPATCHER: OnceLock<Dispatcher> = OnceLock::new();
+
+impl Dispatcher {
+    fn start(vm: &'static Arc<JavaVM>) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(DISPATCH_QUEUE_CAPACITY);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..DISPATCHER_THREADS {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("{}-{}", DISPATCHER_THREAD_NAME, index))
+                .spawn(move || run_upcalls(vm, &receiver))?;
+        }
+        info!("started {} dispatcher threads", DISPATCHER_THREADS);
+        Ok(Self { sender })
+    }
+}
+
+fn run_upcalls(vm: &'static JavaVM, receiver: &Mutex<Receiver<Upcall>>) {
+    let env = match attached_env(vm) {
+        Ok(env) => env,
+        Err(e) => {
+            error!("dispatcher thread failed to attach: {:?}", e);
+            return;
+        }
+    };
+    loop {
+        // The lock is only held while waiting, so other threads pick the next upcall up.
+        let upcall = match receiver.lock().unwrap().recv() {
+            Ok(upcall) => upcall,
+            Err(_) => return,
+        };
+        // Nothing returns to Java on this thread: local references are released per upcall.
+        let result = env.with_local_frame(LOCAL_FRAME_CAPACITY, || {
+            if panic::catch_unwind(AssertUnwindSafe(|| upcall(&env))).is_err() {
+                error!("upcall panicked");
+            }
+            Ok(JObject::null())
+        });
+        if let Err(e) = result {
+            error!("upcall failed to get a local frame: {:?}", e);
+        }
+        // An exception thrown by Java would break every later JNI call on this thread.
+        if env.exception_check().unwrap_or(false) {
+            let _ = env.exception_describe();
+            let _ = env.exception_clear();
+        }
+    }
+}
+
+fn get_dispatcher() -> anyhow::Result<&'static Dispatcher> {
+    if let Some(dispatcher) = DISPATCHER.get() {
+        return Ok(dispatcher);
+    }
+    let vm = unique_jvm::get_static_ref().ok_or_else(|| anyhow!("JavaVM is not set"))?;
+    let dispatcher = Dispatcher::start(vm)?;
+    // A dispatcher started concurrently wins: the threads of this one exit with its sender.
+    Ok(DISPATCHER.get_or_init(|| dispatcher))
+}
+
+/// Queues `upcall` for a dispatcher thread. Fails with `DispatchQueueFull` if Java is not
+/// keeping up.
+pub(crate) fn submit(upcall: impl FnOnce(&JNIEnv) + Send + 'static) -> anyhow::Result<()> {
+    match get_dispatcher()?.sender.try_send(Box::new(upcall)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(PlatformError::DispatchQueueFull.into()),
+        Err(TrySendError::Disconnected(_)) => Err(anyhow!("dispatcher threads exited")),
+    }
+}
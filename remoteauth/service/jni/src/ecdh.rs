@@ -0,0 +1,212 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! X25519 key agreement of the secure channel handshake, as specified by RFC 7748.
+//!
+//! Each side has a static key pair, whose public key the other side learned at enrollment, and
+//! an ephemeral one generated for the handshake. The handshake secret combines three agreements:
+//! both ephemeral keys, for forward secrecy, then the ephemeral key of the initiator with the
+//! static key of the responder and the other way around, authenticating both sides. It is the
+//! `secret` the session keys are derived from with `TypedPlatform::derive_session_key`.
+//!
+//! The scalar multiplications are BoringSSL's X25519. Ephemeral secret keys are drawn from a
+//! `SecureRng` with `KeyPair::generate`.
+
+use crate::rng::{RngError, SecureRng};
+use crate::secret::Secret;
+use bssl_crypto::x25519::PrivateKey;
+use thiserror::Error;
+
+/// Length of the secret and public keys, and of an agreed secret.
+pub const KEY_LEN: usize = 32;
+/// Length of the handshake secret.
+pub const HANDSHAKE_SECRET_LEN: usize = 3 * KEY_LEN;
+/// u-coordinate of the base point.
+pub const BASE_POINT: [u8; KEY_LEN] = {
+    let mut point = [0; KEY_LEN];
+    point[0] = 9;
+    point
+};
+
+/// Why a key agreement failed.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum EcdhError {
+    /// The public key of the remote device is of small order, agreeing on an all-zero secret
+    /// whatever the secret key.
+    #[error("public key of small order")]
+    SmallOrder,
+}
+
+/// X25519 key pair, static or ephemeral.
+pub struct KeyPair {
+    secret: Secret<[u8; KEY_LEN]>,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    /// Returns the key pair of `secret`, random bytes.
+    pub fn from_secret(secret: [u8; KEY_LEN]) -> Self {
+        Self { public: PrivateKey(secret).to_public(), secret: Secret::new(secret) }
+    }
+
+    /// Returns a fresh key pair, whose secret key is drawn from `rng`.
+    pub fn generate(rng: &dyn SecureRng) -> Result<Self, RngError> {
+        let mut secret = Secret::new([0; KEY_LEN]);
+        rng.fill(secret.expose_mut())?;
+        Ok(Self::from_secret(*secret.expose()))
+    }
+
+    /// Returns the public key.
+    pub fn public(&self) -> &[u8; KEY_LEN] {
+        &self.public
+    }
+
+    /// Returns the secret agreed with the owner of `public`. Fails with `SmallOrder` if the
+    /// secret is all zeros, which the remote device could have forced.
+    pub fn agree(&self, public: &[u8; KEY_LEN]) -> Result<Secret<[u8; KEY_LEN]>, EcdhError> {
+        PrivateKey(*self.secret.expose())
+            .compute_shared_key(public)
+            .map(Secret::new)
+            .ok_or(EcdhError::SmallOrder)
+    }
+}
+
+/// Side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Sends the first handshake message: the native side, for RemoteAuth.
+    Initiator,
+    /// Answers the first handshake message: the remote device.
+    Responder,
+}
+
+/// Returns the handshake secret of `role`, from its own `ephemeral` and `static_key` pairs and
+/// the public keys of the other side. Both sides get the same secret.
+pub fn handshake_secret(
+    role: Role,
+    ephemeral: &KeyPair,
+    static_key: &KeyPair,
+    remote_ephemeral: &[u8; KEY_LEN],
+    remote_static: &[u8; KEY_LEN],
+) -> Result<Secret<Vec<u8>>, EcdhError> {
+    let ee = ephemeral.agree(remote_ephemeral)?;
+    // The ephemeral key of the initiator with the static key of the responder, then the other
+    // way around.
+    let (es, se) = match role {
+        Role::Initiator => (ephemeral.agree(remote_static)?, static_key.agree(remote_ephemeral)?),
+        Role::Responder => (static_key.agree(remote_ephemeral)?, ephemeral.agree(remote_static)?),
+    };
+    let parts = [ee.expose().as_slice(), es.expose().as_slice(), se.expose().as_slice()];
+    Ok(Secret::new(parts.concat()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::OsRng;
+
+    fn hex(hex: &str) -> [u8; KEY_LEN] {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    fn x25519(scalar: &[u8; KEY_LEN], u: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        PrivateKey(*scalar).compute_shared_key(u).unwrap()
+    }
+
+    #[test]
+    fn test_x25519() {
+        // RFC 7748, section 5.2.
+        assert_eq!(
+            x25519(
+                &hex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                &hex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c"),
+            ),
+            hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"),
+        );
+        // One then a thousand iterations of k, u = x25519(k, u), k.
+        let (mut k, mut u) = (BASE_POINT, BASE_POINT);
+        for iteration in 1..=1000 {
+            (k, u) = (x25519(&k, &u), k);
+            if iteration == 1 {
+                assert_eq!(
+                    k,
+                    hex("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079")
+                );
+            }
+        }
+        assert_eq!(k, hex("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51"));
+    }
+
+    #[test]
+    fn test_agree() {
+        // RFC 7748, section 6.1.
+        let alice = KeyPair::from_secret(hex(
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+        ));
+        let bob = KeyPair::from_secret(hex(
+            "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+        ));
+        assert_eq!(
+            alice.public(),
+            &hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        assert_eq!(
+            bob.public(),
+            &hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+        );
+        let shared = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(alice.agree(bob.public()), Ok(Secret::new(shared)));
+        assert_eq!(bob.agree(alice.public()), Ok(Secret::new(shared)));
+        assert_eq!(alice.agree(&[0; KEY_LEN]), Err(EcdhError::SmallOrder));
+    }
+
+    #[test]
+    fn test_generate() {
+        let (first, second) =
+            (KeyPair::generate(&OsRng).unwrap(), KeyPair::generate(&OsRng).unwrap());
+        assert_ne!(first.public(), second.public());
+        assert!(first.agree(second.public()).is_ok());
+    }
+
+    #[test]
+    fn test_handshake_secret() {
+        let keys = |byte| KeyPair::from_secret([byte; KEY_LEN]);
+        let (initiator_ephemeral, initiator_static) = (keys(1), keys(2));
+        let (responder_ephemeral, responder_static) = (keys(3), keys(4));
+        let initiator = handshake_secret(
+            Role::Initiator,
+            &initiator_ephemeral,
+            &initiator_static,
+            responder_ephemeral.public(),
+            responder_static.public(),
+        )
+        .unwrap();
+        let responder = handshake_secret(
+            Role::Responder,
+            &responder_ephemeral,
+            &responder_static,
+            initiator_ephemeral.public(),
+            initiator_static.public(),
+        )
+        .unwrap();
+        assert_eq!(initiator.expose().len(), HANDSHAKE_SECRET_LEN);
+        assert_eq!(initiator, responder);
+    }
+}
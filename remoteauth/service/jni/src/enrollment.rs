@@ -0,0 +1,912 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Enrollment of remote authenticators, and the registry of the enrolled ones.
+//!
+//! Enrolling pairs with the remote device with the PIN the user typed, see `pairing`, then asks
+//! it for the attestation chain of its identity key, bound to a fresh challenge, and validates
+//! it, see `attestation`. The record of the enrollment, with the pairing key and the identity
+//! and metadata of the device, is then persisted by Java through `EnrollmentStore`, encrypting
+//! it with a Keystore key, and kept in the registry. At startup, Java hands the persisted records
+//! back to load the registry, and it queries the registry through JNI.
+//!
+//! Revoking an enrollment tells the remote device with `Unenroll` if it is reachable, forgets
+//! the keys shared with it, and replaces its persisted record with a tombstone, so that a stale
+//! copy of the record can't bring it back. Only enrolling the device again does.
+
+use crate::attestation::{
+    split_chain, validate_chain, AttestationPolicy, KeyVerifiers, SecurityLevel,
+};
+use crate::audit::{audit_log, AuditError, AuditEventKind, AuditLog};
+use crate::codec::cbor::{DecodeLimits, Value};
+use crate::cose::{Aead, Algorithm};
+use crate::derived_keys::SubkeyPurpose;
+use crate::device_identity::{
+    ConnectionIdentities, DeviceId, DeviceMetadata, LocalIdentity, RemoteIdentity,
+};
+use crate::messages::{DecodeError, Message, Reader, Request, TypedPlatform, Writer};
+use crate::migration::{malformed, Migration, MigrationError, VersionedRecord};
+use crate::pairing::{Group, Identities};
+use crate::pinning::{IdentityPin, PinKind, PresentedIdentity};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::request_metadata::RequestMetadata;
+use crate::resumption::TicketCache;
+use crate::rng::{OsRng, SecureRng};
+use crate::schema::{Field, Rule, Schema};
+use crate::secret::Secret;
+use crate::secure_channel::SecureChannel;
+use async_trait::async_trait;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+/// Length of the attestation challenges drawn.
+pub const CHALLENGE_LEN: usize = 32;
+/// Shortest attestation challenge accepted.
+pub const MIN_CHALLENGE_LEN: usize = 16;
+/// Longest attestation challenge accepted.
+pub const MAX_CHALLENGE_LEN: usize = 64;
+/// Longest attestation chain accepted, in bytes.
+pub const MAX_CHAIN_BYTES: usize = 16 * 1024;
+/// Longest name or model of a device accepted, in bytes.
+pub const MAX_METADATA_LEN: usize = 128;
+
+const CHALLENGE_RULE: Rule = Rule::Bytes { min_len: MIN_CHALLENGE_LEN, max_len: MAX_CHALLENGE_LEN };
+const METADATA_RULE: Rule = Rule::Bytes { min_len: 0, max_len: MAX_METADATA_LEN };
+
+// Keys of the fields of a persisted record.
+const RECORD_DEVICE_ID_KEY: i64 = 1;
+const RECORD_ALGORITHM_KEY: i64 = 2;
+const RECORD_PUBLIC_KEY_KEY: i64 = 3;
+const RECORD_NAME_KEY: i64 = 4;
+const RECORD_MODEL_KEY: i64 = 5;
+const RECORD_PAIRING_KEY_KEY: i64 = 6;
+const RECORD_SECURITY_LEVEL_KEY: i64 = 7;
+const RECORD_ENROLLED_AT_KEY: i64 = 8;
+const RECORD_REVOKED_AT_KEY: i64 = 9;
+const RECORD_KEY_EPOCHS_KEY: i64 = 10;
+const RECORD_PIN_KEY: i64 = 11;
+
+const REVOCATION_EVENTS_CAPACITY: usize = 16;
+
+static ENROLLMENT_REGISTRY: OnceLock<Arc<EnrollmentRegistry>> = OnceLock::new();
+
+/// Errors of enrollments.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum EnrollmentError {
+    /// The device has no enrollment.
+    #[error("device {0} is not enrolled")]
+    NotEnrolled(DeviceId),
+}
+
+/// Asks the remote device for the attestation of its identity key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationRequest {
+    /// Random value the attestation must carry, fresh for each enrollment.
+    pub challenge: Vec<u8>,
+}
+
+/// Attestation of the identity key of the remote device, with what it says about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationReply {
+    /// Concatenated DER certificates, from the leaf to the root.
+    pub chain: Vec<u8>,
+    /// Name and model of the device.
+    pub metadata: DeviceMetadata,
+}
+
+impl Message for AttestationRequest {
+    const TYPE: u8 = 20;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "attestation_request",
+        fields: &[Field::new(1, "challenge", CHALLENGE_RULE)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.challenge);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { challenge: reader.bytes()? })
+    }
+}
+
+impl Request for AttestationRequest {
+    type Response = AttestationReply;
+}
+
+/// Tells the remote device its enrollment was revoked, so that it forgets the pairing too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unenroll;
+
+impl Message for Unenroll {
+    const TYPE: u8 = 22;
+    const SCHEMA: Option<Schema> = Some(Schema { name: "unenroll", fields: &[] });
+
+    fn encode_fields(&self, _: &mut Writer) {}
+
+    fn decode_fields(_: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self)
+    }
+}
+
+impl Message for AttestationReply {
+    const TYPE: u8 = 21;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "attestation_reply",
+        fields: &[
+            Field::new(1, "chain", Rule::Bytes { min_len: 1, max_len: MAX_CHAIN_BYTES }),
+            Field::new(2, "name", METADATA_RULE),
+            Field::new(3, "model", METADATA_RULE),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.chain);
+        writer.put_bytes(self.metadata.name.as_bytes());
+        writer.put_bytes(self.metadata.model.as_bytes());
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let chain = reader.bytes()?;
+        let name = String::from_utf8(reader.bytes()?).map_err(|_| DecodeError::InvalidField(2))?;
+        let model = String::from_utf8(reader.bytes()?).map_err(|_| DecodeError::InvalidField(3))?;
+        Ok(Self { chain, metadata: DeviceMetadata { name, model } })
+    }
+}
+
+/// What is kept of an enrolled remote authenticator.
+///
+/// Version 1 has no key epochs, version 2 keeps them in a map by purpose id, under
+/// `RECORD_KEY_EPOCHS_KEY`. Version 3 adds the identity pin under `RECORD_PIN_KEY`: null, or
+/// the id of its kind and its fingerprint.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EnrollmentRecord {
+    /// Identity of the device.
+    pub identity: RemoteIdentity,
+    /// Key of the pairing, authenticating the devices to each other.
+    pub pairing_key: Secret<Vec<u8>>,
+    /// Where the device keeps its identity key, as attested.
+    pub security_level: SecurityLevel,
+    /// When the device was enrolled.
+    pub enrolled_at: SystemTime,
+    /// Rotation epoch of the subkeys of each purpose, see `derived_keys`: 0 if never rotated.
+    pub key_epochs: BTreeMap<SubkeyPurpose, u32>,
+    /// Pin of the identity of the device, see `pinning`, if it was pinned at enrollment.
+    pub identity_pin: Option<IdentityPin>,
+}
+
+impl EnrollmentRecord {
+    /// Returns the current rotation epoch of the subkeys of `purpose`.
+    pub fn key_epoch(&self, purpose: SubkeyPurpose) -> u32 {
+        self.key_epochs.get(&purpose).copied().unwrap_or_default()
+    }
+
+    /// Moves the subkeys of `purpose` to their next rotation epoch, and returns it.
+    pub fn rotate_key(&mut self, purpose: SubkeyPurpose) -> u32 {
+        let epoch = self.key_epochs.entry(purpose).or_default();
+        *epoch += 1;
+        *epoch
+    }
+}
+
+impl std::fmt::Debug for EnrollmentRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnrollmentRecord")
+            .field("identity", &self.identity)
+            .field("security_level", &self.security_level)
+            .field("enrolled_at", &self.enrolled_at)
+            .field("key_epochs", &self.key_epochs)
+            .field("identity_pin", &self.identity_pin)
+            .finish_non_exhaustive()
+    }
+}
+
+fn migrate_v1_to_v2(value: Value) -> Result<Value, MigrationError> {
+    let Value::Map(mut entries) = value else {
+        return Err(malformed::<EnrollmentRecord>("expected a map"));
+    };
+    entries.push((Value::Integer(RECORD_KEY_EPOCHS_KEY.into()), Value::Map(Vec::new())));
+    Ok(Value::Map(entries))
+}
+
+fn migrate_v2_to_v3(value: Value) -> Result<Value, MigrationError> {
+    let Value::Map(mut entries) = value else {
+        return Err(malformed::<EnrollmentRecord>("expected a map"));
+    };
+    entries.push((Value::Integer(RECORD_PIN_KEY.into()), Value::Null));
+    Ok(Value::Map(entries))
+}
+
+impl VersionedRecord for EnrollmentRecord {
+    const NAME: &'static str = "enrollment";
+    const SCHEMA_VERSION: u32 = 3;
+    const MIGRATIONS: &'static [Migration] = &[migrate_v1_to_v2, migrate_v2_to_v3];
+
+    fn to_value(&self) -> Value {
+        let enrolled_at = self.enrolled_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let identity = &self.identity;
+        let entries = [
+            (RECORD_DEVICE_ID_KEY, Value::Text(identity.device_id.as_str().to_string())),
+            (RECORD_ALGORITHM_KEY, Value::Integer(identity.algorithm.id().into())),
+            (RECORD_PUBLIC_KEY_KEY, Value::Bytes(identity.public_key.clone())),
+            (RECORD_NAME_KEY, Value::Text(identity.metadata.name.clone())),
+            (RECORD_MODEL_KEY, Value::Text(identity.metadata.model.clone())),
+            (RECORD_PAIRING_KEY_KEY, Value::Bytes(self.pairing_key.expose().clone())),
+            (RECORD_SECURITY_LEVEL_KEY, Value::Integer(self.security_level.value().into())),
+            (RECORD_ENROLLED_AT_KEY, Value::Integer(enrolled_at.as_secs().into())),
+            (
+                RECORD_KEY_EPOCHS_KEY,
+                Value::Map(
+                    self.key_epochs
+                        .iter()
+                        .map(|(purpose, epoch)| {
+                            (Value::Integer(purpose.id().into()), Value::Integer((*epoch).into()))
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                RECORD_PIN_KEY,
+                self.identity_pin.map_or(Value::Null, |pin| {
+                    Value::Array(vec![
+                        Value::Integer(pin.kind.id().into()),
+                        Value::Bytes(pin.fingerprint.to_vec()),
+                    ])
+                }),
+            ),
+        ];
+        Value::Map(
+            entries.into_iter().map(|(key, value)| (Value::Integer(key.into()), value)).collect(),
+        )
+    }
+
+    fn from_value(value: Value) -> Result<Self, MigrationError> {
+        let Value::Map(entries) = value else {
+            return Err(malformed::<Self>("expected a map"));
+        };
+        let field = |key: i64| {
+            entries
+                .iter()
+                .find(|(k, _)| *k == Value::Integer(key.into()))
+                .map(|(_, value)| value.clone())
+                .ok_or(malformed::<Self>("missing field"))
+        };
+        let (
+            Value::Text(device_id),
+            Value::Integer(algorithm),
+            Value::Bytes(public_key),
+            Value::Text(name),
+            Value::Text(model),
+            Value::Bytes(pairing_key),
+            Value::Integer(security_level),
+            Value::Integer(enrolled_at),
+            Value::Map(key_epochs),
+        ) = (
+            field(RECORD_DEVICE_ID_KEY)?,
+            field(RECORD_ALGORITHM_KEY)?,
+            field(RECORD_PUBLIC_KEY_KEY)?,
+            field(RECORD_NAME_KEY)?,
+            field(RECORD_MODEL_KEY)?,
+            field(RECORD_PAIRING_KEY_KEY)?,
+            field(RECORD_SECURITY_LEVEL_KEY)?,
+            field(RECORD_ENROLLED_AT_KEY)?,
+            field(RECORD_KEY_EPOCHS_KEY)?,
+        )
+        else {
+            return Err(malformed::<Self>("field of the wrong type"));
+        };
+        let device_id = DeviceId::new(device_id).map_err(|_| malformed::<Self>("bad device id"))?;
+        let algorithm = i64::try_from(algorithm)
+            .ok()
+            .and_then(Algorithm::from_id)
+            .ok_or(malformed::<Self>("bad algorithm"))?;
+        let security_level = i64::try_from(security_level)
+            .ok()
+            .and_then(SecurityLevel::from_value)
+            .ok_or(malformed::<Self>("bad security level"))?;
+        let enrolled_at =
+            u64::try_from(enrolled_at).map_err(|_| malformed::<Self>("bad enrollment time"))?;
+        let key_epochs = key_epochs
+            .into_iter()
+            .map(|entry| match entry {
+                (Value::Integer(purpose), Value::Integer(epoch)) => Some((
+                    u8::try_from(purpose).ok().and_then(SubkeyPurpose::from_id)?,
+                    u32::try_from(epoch).ok()?,
+                )),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .ok_or(malformed::<Self>("bad key epoch"))?;
+        let identity_pin = match field(RECORD_PIN_KEY)? {
+            Value::Null => None,
+            Value::Array(pin) => match pin.as_slice() {
+                [Value::Integer(kind), Value::Bytes(fingerprint)] => Some(IdentityPin {
+                    kind: u8::try_from(*kind)
+                        .ok()
+                        .and_then(PinKind::from_id)
+                        .ok_or(malformed::<Self>("bad pin kind"))?,
+                    fingerprint: fingerprint
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| malformed::<Self>("bad pin fingerprint"))?,
+                }),
+                _ => return Err(malformed::<Self>("bad pin")),
+            },
+            _ => return Err(malformed::<Self>("field of the wrong type")),
+        };
+        Ok(Self {
+            identity: RemoteIdentity {
+                device_id,
+                algorithm,
+                public_key,
+                metadata: DeviceMetadata { name, model },
+            },
+            pairing_key: Secret::new(pairing_key),
+            security_level,
+            enrolled_at: UNIX_EPOCH + Duration::from_secs(enrolled_at),
+            key_epochs,
+            identity_pin,
+        })
+    }
+}
+
+/// What is kept of a revoked enrollment, in place of its record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    /// Device whose enrollment was revoked.
+    pub device_id: DeviceId,
+    /// When it was revoked.
+    pub revoked_at: SystemTime,
+}
+
+impl VersionedRecord for Tombstone {
+    const NAME: &'static str = "enrollment_tombstone";
+    const SCHEMA_VERSION: u32 = 1;
+    const MIGRATIONS: &'static [Migration] = &[];
+
+    fn to_value(&self) -> Value {
+        let revoked_at = self.revoked_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Value::Map(vec![
+            (
+                Value::Integer(RECORD_DEVICE_ID_KEY.into()),
+                Value::Text(self.device_id.as_str().to_string()),
+            ),
+            (
+                Value::Integer(RECORD_REVOKED_AT_KEY.into()),
+                Value::Integer(revoked_at.as_secs().into()),
+            ),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, MigrationError> {
+        let Value::Map(entries) = value else {
+            return Err(malformed::<Self>("expected a map"));
+        };
+        let field = |key: i64| {
+            entries
+                .iter()
+                .find(|(k, _)| *k == Value::Integer(key.into()))
+                .map(|(_, value)| value.clone())
+                .ok_or(malformed::<Self>("missing field"))
+        };
+        let (Value::Text(device_id), Value::Integer(revoked_at)) =
+            (field(RECORD_DEVICE_ID_KEY)?, field(RECORD_REVOKED_AT_KEY)?)
+        else {
+            return Err(malformed::<Self>("field of the wrong type"));
+        };
+        let device_id = DeviceId::new(device_id).map_err(|_| malformed::<Self>("bad device id"))?;
+        let revoked_at =
+            u64::try_from(revoked_at).map_err(|_| malformed::<Self>("bad revocation time"))?;
+        Ok(Self { device_id, revoked_at: UNIX_EPOCH + Duration::from_secs(revoked_at) })
+    }
+}
+
+/// Revocation of an enrollment, broadcast to the listeners of the enroller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevocationEvent {
+    /// Device whose enrollment was revoked.
+    pub device_id: DeviceId,
+    /// When it was revoked.
+    pub revoked_at: SystemTime,
+    /// Whether the device was reachable and told with `Unenroll`.
+    pub notified: bool,
+}
+
+/// Counts of the enrollments and revocations of an enroller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnrollmentStats {
+    /// Devices enrolled.
+    pub enrolled: u64,
+    /// Enrollments revoked.
+    pub revoked: u64,
+    /// Revoked devices that were told with `Unenroll`.
+    pub notified: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EnrollmentCounters {
+    enrolled: AtomicU64,
+    revoked: AtomicU64,
+    notified: AtomicU64,
+}
+
+impl EnrollmentCounters {
+    fn record_revocation(&self, notified: bool) {
+        self.revoked.fetch_add(1, Ordering::Relaxed);
+        if notified {
+            self.notified.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> EnrollmentStats {
+        EnrollmentStats {
+            enrolled: self.enrolled.load(Ordering::Relaxed),
+            revoked: self.revoked.load(Ordering::Relaxed),
+            notified: self.notified.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Persists enrollment records, implemented by Java.
+#[async_trait]
+pub trait EnrollmentStore: Send + Sync {
+    /// Persists `record` of `device_id`, replacing its previous one.
+    async fn store_enrollment(&self, device_id: &str, record: &[u8]) -> anyhow::Result<()>;
+
+    /// Deletes the record of `device_id`, if any.
+    async fn delete_enrollment(&self, device_id: &str) -> anyhow::Result<()>;
+}
+
+/// Enrolled remote authenticators, by device id, with the tombstones of the revoked ones.
+#[derive(Debug, Default)]
+pub struct EnrollmentRegistry {
+    records: Mutex<Records>,
+}
+
+#[derive(Debug, Default)]
+struct Records {
+    enrolled: BTreeMap<DeviceId, EnrollmentRecord>,
+    revoked: BTreeMap<DeviceId, SystemTime>,
+}
+
+impl EnrollmentRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the records with the persisted `records`, e.g. at startup. Records that can't be
+    /// read are skipped, and a tombstone wins over a record of the same device. Returns the
+    /// number of devices enrolled.
+    pub fn load(&self, records: &[Vec<u8>]) -> usize {
+        let mut loaded = Records::default();
+        for bytes in records {
+            let limits = DecodeLimits::default();
+            match EnrollmentRecord::from_bytes(bytes, limits) {
+                Ok(record) => {
+                    loaded.enrolled.insert(record.identity.device_id.clone(), record);
+                }
+                Err(e) => match Tombstone::from_bytes(bytes, limits) {
+                    Ok(tombstone) => {
+                        loaded.revoked.insert(tombstone.device_id, tombstone.revoked_at);
+                    }
+                    Err(_) => warn!("skipping unreadable enrollment record: {}", e),
+                },
+            }
+        }
+        let Records { enrolled, revoked } = &mut loaded;
+        enrolled.retain(|device_id, _| !revoked.contains_key(device_id));
+        let count = loaded.enrolled.len();
+        *self.records.lock().unwrap() = loaded;
+        count
+    }
+
+    /// Adds `record`, replacing the record or the tombstone of the same device. Returns the
+    /// replaced record.
+    pub fn insert(&self, record: EnrollmentRecord) -> Option<EnrollmentRecord> {
+        let device_id = record.identity.device_id.clone();
+        let mut records = self.records.lock().unwrap();
+        records.revoked.remove(&device_id);
+        records.enrolled.insert(device_id, record)
+    }
+
+    /// Removes the record of `device_id`, and returns it.
+    pub fn remove(&self, device_id: &DeviceId) -> Option<EnrollmentRecord> {
+        self.records.lock().unwrap().enrolled.remove(device_id)
+    }
+
+    /// Replaces the record of `device_id` with the tombstone of its revocation at `revoked_at`,
+    /// and returns the record.
+    pub fn revoke(&self, device_id: &DeviceId, revoked_at: SystemTime) -> Option<EnrollmentRecord> {
+        let mut records = self.records.lock().unwrap();
+        records.revoked.insert(device_id.clone(), revoked_at);
+        records.enrolled.remove(device_id)
+    }
+
+    /// Returns the record of `device_id`.
+    pub fn get(&self, device_id: &DeviceId) -> Option<EnrollmentRecord> {
+        self.records.lock().unwrap().enrolled.get(device_id).cloned()
+    }
+
+    /// Returns when the enrollment of `device_id` was revoked, unless it is enrolled again.
+    pub fn revoked_at(&self, device_id: &DeviceId) -> Option<SystemTime> {
+        self.records.lock().unwrap().revoked.get(device_id).copied()
+    }
+
+    /// Returns the ids of the enrolled devices, in order.
+    pub fn device_ids(&self) -> Vec<DeviceId> {
+        self.records.lock().unwrap().enrolled.keys().cloned().collect()
+    }
+
+    /// Returns the records then the tombstones, encoded as `load` reads them back.
+    pub fn persisted(&self) -> Vec<Vec<u8>> {
+        let records = self.records.lock().unwrap();
+        let tombstones = records.revoked.iter().map(|(device_id, revoked_at)| {
+            Tombstone { device_id: device_id.clone(), revoked_at: *revoked_at }.to_bytes()
+        });
+        records.enrolled.values().map(VersionedRecord::to_bytes).chain(tombstones).collect()
+    }
+}
+
+/// Returns the registry loaded by Java at startup.
+pub fn enrollment_registry() -> &'static Arc<EnrollmentRegistry> {
+    ENROLLMENT_REGISTRY.get_or_init(Arc::default)
+}
+
+/// The PIN the user typed, and the group to pair with it in.
+pub struct EnrollmentParams<'a, G: Group> {
+    /// Group of the PIN pairing.
+    pub group: G,
+    /// PIN the user typed.
+    pub pin: &'a [u8],
+}
+
+/// Enrolls remote authenticators, and revokes their enrollments.
+pub struct Enroller {
+    policy: AttestationPolicy,
+    verifiers: Arc<dyn KeyVerifiers + Send + Sync>,
+    store: Arc<dyn EnrollmentStore>,
+    registry: Arc<EnrollmentRegistry>,
+    identities: Arc<ConnectionIdentities>,
+    tickets: Option<Arc<TicketCache>>,
+    pin_kind: Option<PinKind>,
+    rng: Arc<dyn SecureRng>,
+    revocations: broadcast::Sender<RevocationEvent>,
+    counters: EnrollmentCounters,
+    audit: Arc<AuditLog>,
+}
+
+impl Enroller {
+    /// Creates an enroller accepting the attestations `policy` allows, persisting records in
+    /// `store` and keeping them in `registry`. Enrolled connections are bound to their device in
+    /// `identities`. Enrollments, revocations and rejected attestations are recorded in
+    /// `audit_log`.
+    pub fn new(
+        policy: AttestationPolicy,
+        verifiers: Arc<dyn KeyVerifiers + Send + Sync>,
+        store: Arc<dyn EnrollmentStore>,
+        registry: Arc<EnrollmentRegistry>,
+        identities: Arc<ConnectionIdentities>,
+    ) -> Result<Self, AuditError> {
+        Ok(Self {
+            policy,
+            verifiers,
+            store,
+            registry,
+            identities,
+            tickets: None,
+            pin_kind: None,
+            rng: Arc::new(OsRng),
+            revocations: broadcast::channel(REVOCATION_EVENTS_CAPACITY).0,
+            counters: EnrollmentCounters::default(),
+            audit: Arc::clone(audit_log()?),
+        })
+    }
+
+    /// Also forgets the resumption tickets of the devices in `tickets` when revoking them.
+    pub fn with_tickets(mut self, tickets: Arc<TicketCache>) -> Self {
+        self.tickets = Some(tickets);
+        self
+    }
+
+    /// Pins the `kind` of identity of the devices it enrolls, for `IdentityPinning` to check
+    /// on every secure channel established with them.
+    pub fn with_pinning(mut self, kind: PinKind) -> Self {
+        self.pin_kind = Some(kind);
+        self
+    }
+
+    /// Draws the pairing seeds and the attestation challenges from `rng`, rather than `OsRng`.
+    pub fn with_rng(mut self, rng: Arc<dyn SecureRng>) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Records enrollments, revocations and rejected attestations in `audit` instead.
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Returns a receiver of the revocations.
+    pub fn revocations(&self) -> broadcast::Receiver<RevocationEvent> {
+        self.revocations.subscribe()
+    }
+
+    /// Returns the counts of the enrollments and revocations so far.
+    pub fn stats(&self) -> EnrollmentStats {
+        self.counters.stats()
+    }
+
+    /// Enrolls the remote device of `connection_id`, as `local`, and returns its identity once
+    /// its record is persisted. Enrolling a device again replaces its record.
+    pub async fn enroll<T: Platform + ?Sized, G: Group>(
+        &self,
+        platform: &TypedPlatform<T>,
+        connection_id: i32,
+        local: &LocalIdentity,
+        params: EnrollmentParams<'_, G>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<RemoteIdentity> {
+        let ids = Identities {
+            prover: local.device_id().as_str().as_bytes().to_vec(),
+            verifier: Vec::new(),
+        };
+        let mut seed = Secret::new(vec![0; params.group.seed_len()]);
+        self.rng.fill(seed.expose_mut())?;
+        let mut challenge = vec![0; CHALLENGE_LEN];
+        self.rng.fill(&mut challenge)?;
+        let keys = platform
+            .pair(connection_id, params.group, params.pin, ids, seed.expose(), timeout)
+            .await?;
+        let request = AttestationRequest { challenge: challenge.clone() };
+        let reply = platform.send(connection_id, &request, RequestMetadata::new(), timeout).await?;
+        let attestation = split_chain(&reply.chain).and_then(|mut chain| {
+            let attestation = validate_chain(
+                &chain,
+                &challenge,
+                &self.policy,
+                self.verifiers.as_ref(),
+                SystemTime::now(),
+            )?;
+            // The chain is ordered leaf first.
+            Ok((attestation, chain.swap_remove(0)))
+        });
+        let (attestation, leaf) = match attestation {
+            Ok(validated) => validated,
+            Err(e) => {
+                warn!("attestation of connection {} rejected: {}", connection_id, e);
+                let detail = format!("attestation of connection {}: {}", connection_id, e);
+                self.audit.record(AuditEventKind::VerificationFailed, None, &detail);
+                return Err(e.into());
+            }
+        };
+
+        let identity = RemoteIdentity::from_attestation(&attestation, reply.metadata);
+        let presented =
+            PresentedIdentity { public_key: &identity.public_key, certificate: Some(&leaf) };
+        let record = EnrollmentRecord {
+            identity: identity.clone(),
+            pairing_key: keys.pairing_key,
+            security_level: attestation.key_security_level,
+            enrolled_at: SystemTime::now(),
+            key_epochs: BTreeMap::new(),
+            identity_pin: self.pin_kind.and_then(|kind| IdentityPin::new(kind, &presented)),
+        };
+        self.store.store_enrollment(identity.device_id.as_str(), &record.to_bytes()).await?;
+        self.registry.insert(record);
+        self.identities.bind(connection_id, identity.device_id.clone());
+        self.counters.enrolled.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "connection {} enrolled device {} ({:?})",
+            connection_id, identity.device_id, attestation.key_security_level
+        );
+        let detail = format!("connection {}, {:?}", connection_id, attestation.key_security_level);
+        self.audit.record(AuditEventKind::Enrolled, Some(&identity.device_id), &detail);
+        Ok(identity)
+    }
+
+    /// Rotates the subkeys of `purpose` of the pairing of `device_id`, leaving the subkeys of
+    /// the other purposes be, and returns their new epoch once the record is persisted. Fails
+    /// with `NotEnrolled` if the device has no enrollment.
+    pub async fn rotate_subkey(
+        &self,
+        device_id: &DeviceId,
+        purpose: SubkeyPurpose,
+    ) -> anyhow::Result<u32> {
+        let Some(mut record) = self.registry.get(device_id) else {
+            return Err(EnrollmentError::NotEnrolled(device_id.clone()).into());
+        };
+        let epoch = record.rotate_key(purpose);
+        self.store.store_enrollment(device_id.as_str(), &record.to_bytes()).await?;
+        self.registry.insert(record);
+        info!("rotated the {:?} subkeys of device {} to epoch {}", purpose, device_id, epoch);
+        Ok(epoch)
+    }
+
+    /// Revokes the enrollment of `device_id`. The device is told with `Unenroll` on `channel`
+    /// if it is reachable, then its persisted record is replaced with a tombstone, and the keys
+    /// shared with it are forgotten: the pairing key, the session keys of its connections and
+    /// its resumption tickets. `channel` must share the identities of the enroller. Fails with
+    /// `NotEnrolled` if the device has no enrollment.
+    pub async fn revoke_enrollment<P: Platform + ?Sized, A: Aead>(
+        &self,
+        device_id: &DeviceId,
+        channel: &SecureChannel<P, A>,
+    ) -> anyhow::Result<()> {
+        if self.registry.get(device_id).is_none() {
+            return Err(EnrollmentError::NotEnrolled(device_id.clone()).into());
+        }
+        // Told first, while the session keys are still there.
+        let notified = match channel.send_notification_to(device_id, &Unenroll.encode()) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("device {} not told of its revocation: {:#}", device_id, e);
+                false
+            }
+        };
+
+        let tombstone = Tombstone { device_id: device_id.clone(), revoked_at: SystemTime::now() };
+        self.store.store_enrollment(device_id.as_str(), &tombstone.to_bytes()).await?;
+        self.registry.revoke(device_id, tombstone.revoked_at);
+        for connection_id in self.identities.connections(device_id) {
+            channel.forget_connection(connection_id);
+        }
+        if let Some(tickets) = &self.tickets {
+            tickets.remove(device_id.as_str());
+        }
+        self.counters.record_revocation(notified);
+        info!("revoked the enrollment of device {} (notified: {})", device_id, notified);
+        let detail = format!("notified: {}", notified);
+        self.audit.record(AuditEventKind::Revoked, Some(device_id), &detail);
+        // Nobody may be listening.
+        let _ = self.revocations.send(RevocationEvent {
+            device_id: tombstone.device_id,
+            revoked_at: tombstone.revoked_at,
+            notified,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::cbor;
+
+    fn record(device_id: &str) -> EnrollmentRecord {
+        EnrollmentRecord {
+            identity: RemoteIdentity {
+                device_id: DeviceId::new(device_id).unwrap(),
+                algorithm: Algorithm::Es256,
+                public_key: vec![4; 65],
+                metadata: DeviceMetadata { name: "watch".to_string(), model: "W1".to_string() },
+            },
+            pairing_key: Secret::new(vec![7; 32]),
+            security_level: SecurityLevel::StrongBox,
+            enrolled_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            key_epochs: BTreeMap::new(),
+            identity_pin: None,
+        }
+    }
+
+    #[test]
+    fn test_attestation_messages() {
+        let reply = AttestationReply {
+            chain: vec![0x30, 0x00],
+            metadata: DeviceMetadata { name: "watch".to_string(), model: "W1".to_string() },
+        };
+        assert_eq!(AttestationReply::decode(&reply.encode()), Ok(reply));
+        let short = AttestationRequest { challenge: vec![1; MIN_CHALLENGE_LEN - 1] };
+        assert!(AttestationRequest::decode(&short.encode()).is_err());
+        assert_eq!(Unenroll::decode(&Unenroll.encode()), Ok(Unenroll));
+    }
+
+    #[test]
+    fn test_record() {
+        let record = record("watch");
+        let bytes = record.to_bytes();
+        assert_eq!(
+            EnrollmentRecord::from_bytes(&bytes, DecodeLimits::default()),
+            Ok(record.clone())
+        );
+        assert!(!format!("{:?}", record).contains("pairing_key"));
+
+        let mut rotated = record.clone();
+        rotated.rotate_key(SubkeyPurpose::Telemetry);
+        assert_eq!(rotated.rotate_key(SubkeyPurpose::Telemetry), 2);
+        assert_eq!(rotated.key_epoch(SubkeyPurpose::Unlock), 0);
+        assert_eq!(
+            EnrollmentRecord::from_bytes(&rotated.to_bytes(), DecodeLimits::default()),
+            Ok(rotated)
+        );
+
+        let mut pinned = record.clone();
+        pinned.identity_pin =
+            Some(IdentityPin { kind: PinKind::Certificate, fingerprint: [9; 32] });
+        assert_eq!(
+            EnrollmentRecord::from_bytes(&pinned.to_bytes(), DecodeLimits::default()),
+            Ok(pinned)
+        );
+
+        // Records persisted before the key epochs or the pins were added still read, never
+        // rotated nor pinned.
+        let Value::Map(mut entries) = record.to_value() else { unreachable!() };
+        entries.retain(|(key, _)| *key != Value::Integer(RECORD_PIN_KEY.into()));
+        let v2 = cbor::encode(&Value::Array(vec![
+            Value::Integer(2.into()),
+            Value::Map(entries.clone()),
+        ]));
+        assert_eq!(EnrollmentRecord::from_bytes(&v2, DecodeLimits::default()), Ok(record.clone()));
+        entries.retain(|(key, _)| *key != Value::Integer(RECORD_KEY_EPOCHS_KEY.into()));
+        let v1 = cbor::encode(&Value::Array(vec![Value::Integer(1.into()), Value::Map(entries)]));
+        assert_eq!(EnrollmentRecord::from_bytes(&v1, DecodeLimits::default()), Ok(record));
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = EnrollmentRegistry::new();
+        registry.insert(record("stale"));
+        let stored = [record("watch").to_bytes(), vec![0xff], record("phone").to_bytes()];
+        assert_eq!(registry.load(&stored), 2);
+        let ids: Vec<String> = registry.device_ids().iter().map(ToString::to_string).collect();
+        assert_eq!(ids, ["phone", "watch"]);
+
+        let watch = DeviceId::new("watch").unwrap();
+        assert_eq!(registry.get(&watch), Some(record("watch")));
+        let mut replacement = record("watch");
+        replacement.pairing_key = Secret::new(vec![8; 32]);
+        assert_eq!(registry.insert(replacement.clone()), Some(record("watch")));
+        assert_eq!(registry.remove(&watch), Some(replacement));
+        assert_eq!(registry.get(&watch), None);
+    }
+
+    #[test]
+    fn test_tombstone() {
+        let tombstone = Tombstone {
+            device_id: DeviceId::new("watch").unwrap(),
+            revoked_at: UNIX_EPOCH + Duration::from_secs(1_800_000_000),
+        };
+        let bytes = tombstone.to_bytes();
+        assert_eq!(Tombstone::from_bytes(&bytes, DecodeLimits::default()), Ok(tombstone.clone()));
+        assert!(EnrollmentRecord::from_bytes(&bytes, DecodeLimits::default()).is_err());
+
+        // A stale record of a revoked device doesn't bring it back.
+        let registry = EnrollmentRegistry::new();
+        let stored = [bytes, record("watch").to_bytes(), record("phone").to_bytes()];
+        assert_eq!(registry.load(&stored), 1);
+        let watch = DeviceId::new("watch").unwrap();
+        assert_eq!(registry.get(&watch), None);
+        assert_eq!(registry.revoked_at(&watch), Some(tombstone.revoked_at));
+
+        let phone = DeviceId::new("phone").unwrap();
+        assert_eq!(registry.revoke(&phone, tombstone.revoked_at), Some(record("phone")));
+        assert!(registry.device_ids().is_empty());
+        registry.insert(record("phone"));
+        assert_eq!(registry.revoked_at(&phone), None);
+        assert_eq!(registry.get(&phone), Some(record("phone")));
+
+        let restored = EnrollmentRegistry::new();
+        assert_eq!(restored.load(&registry.persisted()), 1);
+        assert_eq!(restored.revoked_at(&watch), Some(tombstone.revoked_at));
+        assert_eq!(restored.get(&phone), Some(record("phone")));
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Errors raised by the native side of the RemoteAuth platform.
+
+use crate::remoteauth_jni_android_platform::State;
+use thiserror::Error;
+
+/// Failures detected natively, as opposed to error codes reported by the Java transport.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum PlatformError {
+    /// The platform was destroyed while the request was still waiting for a response.
+    #[error("platform was destroyed")]
+    PlatformDestroyed,
+    /// The platform is shutting down and no longer accepts or waits for requests.
+    #[error("platform shutdown in progress")]
+    ShutdownInProgress,
+    /// Java passed a handle that can never have been generated natively.
+    #[error("invalid handle {0}")]
+    InvalidHandle(i64),
+    /// No response arrived in time.
+    #[error("request timed out")]
+    Timeout,
+    /// The platform already has as many pending requests as it is configured to allow.
+    #[error("too many pending requests")]
+    TooManyPendingRequests,
+    /// The platform is not in a state accepting requests.
+    #[error("platform is {0:?}")]
+    InvalidState(State),
+    /// The Java platform object, held through a weak reference, was garbage collected.
+    #[error("Java platform object was collected")]
+    PlatformGone,
+    /// Every handle value is in use.
+    #[error("no handle available")]
+    HandlesExhausted,
+    /// The connection already has as many outstanding requests as it is configured to allow.
+    #[error("connection is busy")]
+    Busy,
+    /// The request could not be handed over to the Java transport.
+    #[error("failed to send request")]
+    SendFailed,
+    /// The request payload is larger than the configured maximum.
+    #[error("payload of {0} bytes is too large")]
+    PayloadTooLarge(usize),
+    /// Upcalls to Java are queued faster than Java handles them.
+    #[error("dispatch queue is full")]
+    DispatchQueueFull,
+    /// The request was cancelled before its response arrived.
+    #[error("request cancelled")]
+    Cancelled,
+    /// A streamed response arrives faster than its chunks are read.
+    #[error("response stream is full")]
+    StreamFull,
+    /// Java doesn't know the connection.
+    #[error("unknown connection {0}")]
+    UnknownConnection(i32),
+    /// The transport failed to open or close a connection, with its error code.
+    #[error("connection operation failed with {0}")]
+    ConnectionFailed(i32),
+    /// The request was not handed over to Java by its send-by deadline, and was dropped.
+    #[error("request expired before it was sent")]
+    ExpiredBeforeSend,
+    /// The response is larger than its connection allows.
+    #[error("response of {0} bytes is too large")]
+    ResponseTooLarge(usize),
+    /// Java failed a keystore operation, with its error code.
+    #[error("keystore operation failed with {0}")]
+    KeystoreFailed(i32),
+}
+
+impl PlatformError {
+    /// Error code passed to `ResponseCallback::on_error`.
+    ///
+    /// Native codes are negative so they never collide with codes coming from the transport.
+    pub fn error_code(&self) -> i32 {
+        match self {
+            PlatformError::PlatformDestroyed => -1,
+            PlatformError::ShutdownInProgress => -2,
+            PlatformError::InvalidHandle(_) => -3,
+            PlatformError::Timeout => -4,
+            PlatformError::TooManyPendingRequests => -5,
+            PlatformError::InvalidState(_) => -6,
+            PlatformError::PlatformGone => -7,
+            PlatformError::HandlesExhausted => -8,
+            PlatformError::Busy => -9,
+            PlatformError::SendFailed => -10,
+            PlatformError::PayloadTooLarge(_) => -11,
+            PlatformError::DispatchQueueFull => -12,
+            PlatformError::Cancelled => -13,
+            PlatformError::StreamFull => -14,
+            PlatformError::UnknownConnection(_) => -15,
+            PlatformError::ConnectionFailed(_) => -16,
+            PlatformError::ExpiredBeforeSend => -17,
+            PlatformError::ResponseTooLarge(_) => -18,
+            PlatformError::KeystoreFailed(_) => -19,
+        }
+    }
+}
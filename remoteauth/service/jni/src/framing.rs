@@ -0,0 +1,992 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Splits messages larger than a transport packet into fragments, and reassembles them.
+//!
+//! Each fragment starts with a header: the id of its message as a big-endian `u16`, a flags
+//! byte, the id of the logical channel of the message as a byte, then its index and the number of
+//! fragments of the message, as big-endian `u16` too, its sequence number as a big-endian `u32`,
+//! and last the big-endian CRC-32C of the rest of the header and of the data. Fragments are sized
+//! to the `max_payload` of the connection, header included.
+//!
+//! Channels let independent subsystems share a connection, see `Multiplexer`. Messages sent
+//! without one use `DEFAULT_CHANNEL`.
+//!
+//! Each connection numbers its fragments from 0, and receivers drop the fragments whose number
+//! is a duplicate or too old for their replay window.
+//!
+//! A corrupted fragment is answered with a NACK frame, flagged `NACK` with the id and index of
+//! the fragment, so that its sender retransmits it: the remote device answers a corrupted request
+//! fragment with a NACK, and each side sends a NACK notification for corrupted notification
+//! fragments.
+//!
+//! On a `FramedPlatform` with reliability, each fragment is also answered with an ACK frame,
+//! flagged `ACK` with the ranges of sequence numbers received lately as pairs of big-endian `u32`,
+//! so that its sender retransmits the fragments lost on the way, see `ReliabilityConfig`.
+//!
+//! Messages of `COMPRESSION_THRESHOLD` bytes or more are compressed before being split, on
+//! connections whose remote device supports it, and their fragments flagged `COMPRESSED`.
+
+use crate::capabilities::CapabilityCache;
+use crate::compression::{self, DecompressError};
+use crate::config;
+use crate::crc32c::crc32c;
+use crate::error::PlatformError;
+use crate::reliability::{Delivery, Reliability, ReliabilityConfig, MAX_ACK_RANGES};
+use crate::remoteauth_jni_android_platform::{
+    MessageStream, OneshotCallback, Platform, RequestMetadata, Response,
+};
+use crate::replay_window::{Replay, ReplayCounters, ReplayWindow};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+pub use crate::compression::COMPRESSION_THRESHOLD;
+pub use crate::replay_window::ReplayStats;
+
+/// Length of the header starting each fragment.
+pub const FRAGMENT_HEADER_LEN: usize = 16;
+/// Channel of the messages sent without one, and of NACK and ACK frames.
+pub const DEFAULT_CHANNEL: u8 = 0;
+/// Flag of the fragments of a compressed message.
+pub const COMPRESSED: u8 = 1 << 0;
+/// Flag of NACK frames.
+pub const NACK: u8 = 1 << 1;
+/// Flag of ACK frames.
+pub const ACK: u8 = 1 << 2;
+/// Times a fragment NACKed by the remote device is retransmitted before giving up.
+pub const MAX_RETRANSMISSIONS: usize = 3;
+/// Number of the last notification messages kept per `FramedPlatform` to retransmit their
+/// fragments if NACKed.
+const RETAINED_NOTIFICATIONS: usize = 8;
+/// Number of messages a `Reassembler` reassembles at once. Receiving the first fragment of
+/// another message drops the oldest partial one.
+pub const MAX_PARTIAL_MESSAGES: usize = 4;
+/// Delay after its first fragment by which a message must be complete, before its fragments are
+/// dropped.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A fragment whose checksum doesn't match its content.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("fragment {index} of message {message_id} is corrupted")]
+pub struct IntegrityError {
+    /// Id of the message of the fragment, as the header tells.
+    pub message_id: u16,
+    /// Index of the fragment, as the header tells.
+    pub index: u16,
+}
+
+/// Why a message could not be fragmented or reassembled.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum FramingError {
+    /// The max payload leaves no room for data after the header.
+    #[error("max payload {0} is too small for a fragment")]
+    MaxPayloadTooSmall(usize),
+    /// The message needs more fragments than the header counts.
+    #[error("message of {0} bytes needs too many fragments")]
+    TooManyFragments(usize),
+    /// The fragment is shorter than its header.
+    #[error("fragment of {0} bytes is truncated")]
+    Truncated(usize),
+    /// The fragment is corrupted, or still was after `MAX_RETRANSMISSIONS`.
+    #[error(transparent)]
+    Integrity(#[from] IntegrityError),
+    /// The sequence number of the fragment was already received.
+    #[error("duplicate fragment {0}")]
+    Duplicate(u32),
+    /// The sequence number of the fragment is too old for the replay window.
+    #[error("stale fragment {0}")]
+    Stale(u32),
+    /// The index of the fragment is not below its total, or the total is 0.
+    #[error("fragment {index} of {total} is invalid")]
+    InvalidIndex {
+        /// Index in the header.
+        index: u16,
+        /// Total in the header.
+        total: u16,
+    },
+    /// The header has flags this side doesn't know.
+    #[error("fragment has unknown flags {0:#04x}")]
+    UnknownFlags(u8),
+    /// The fragment disagrees with earlier fragments of its message on their flags or number.
+    #[error("fragment of message {0} has a different header")]
+    HeaderMismatch(u16),
+    /// The reassembled message would exceed its maximum length.
+    #[error("message {0} exceeds the maximum length")]
+    MessageTooLarge(u16),
+    /// The message is flagged compressed, but isn't a deflate stream.
+    #[error("message {0} fails to decompress")]
+    InvalidCompression(u16),
+    /// A fragment of the message was still not ACKed after its retransmissions.
+    #[error("message {0} was not delivered")]
+    Undelivered(u16),
+    /// Delivery needs a `FramedPlatform` with reliability.
+    #[error("reliability is not enabled")]
+    Unreliable,
+    /// A fragment of a request failed with a `ResponseCallback` error code.
+    #[error("fragment {index} failed with {error_code}")]
+    FragmentFailed {
+        /// Index of the fragment.
+        index: u16,
+        /// Error code of the fragment.
+        error_code: i32,
+    },
+}
+
+/// Header starting each fragment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FragmentHeader {
+    message_id: u16,
+    flags: u8,
+    channel: u8,
+    index: u16,
+    total: u16,
+    sequence: u32,
+}
+
+/// Offset of the checksum in the header.
+const CRC_OFFSET: usize = 12;
+
+impl FragmentHeader {
+    /// Returns the frame of `data` with this header.
+    fn frame(&self, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(FRAGMENT_HEADER_LEN + data.len());
+        frame.extend_from_slice(&self.message_id.to_be_bytes());
+        frame.push(self.flags);
+        frame.push(self.channel);
+        frame.extend_from_slice(&self.index.to_be_bytes());
+        frame.extend_from_slice(&self.total.to_be_bytes());
+        frame.extend_from_slice(&self.sequence.to_be_bytes());
+        let crc = crc32c(&[&frame, data]);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Reads the header of `frame`, NACK or fragment, and checks its checksum.
+    fn read_frame(frame: &[u8]) -> Result<(Self, &[u8]), FramingError> {
+        if frame.len() < FRAGMENT_HEADER_LEN {
+            return Err(FramingError::Truncated(frame.len()));
+        }
+        let field = |at: usize| u16::from_be_bytes([frame[at], frame[at + 1]]);
+        let long_field = |at: usize| {
+            u32::from_be_bytes([frame[at], frame[at + 1], frame[at + 2], frame[at + 3]])
+        };
+        let header = Self {
+            message_id: field(0),
+            flags: frame[2],
+            channel: frame[3],
+            index: field(4),
+            total: field(6),
+            sequence: long_field(8),
+        };
+        let crc = long_field(CRC_OFFSET);
+        let data = &frame[FRAGMENT_HEADER_LEN..];
+        if crc32c(&[&frame[..CRC_OFFSET], data]) != crc {
+            let error = IntegrityError { message_id: header.message_id, index: header.index };
+            return Err(error.into());
+        }
+        Ok((header, data))
+    }
+
+    /// Reads the header of the fragment `frame`.
+    fn read(frame: &[u8]) -> Result<(Self, &[u8]), FramingError> {
+        let (header, data) = Self::read_frame(frame)?;
+        if header.flags & !COMPRESSED != 0 {
+            return Err(FramingError::UnknownFlags(header.flags));
+        }
+        if header.index >= header.total {
+            return Err(FramingError::InvalidIndex { index: header.index, total: header.total });
+        }
+        Ok((header, data))
+    }
+}
+
+/// Returns the NACK frame asking to retransmit fragment `index` of message `message_id`. NACKs
+/// are not numbered.
+pub fn nack(message_id: u16, index: u16) -> Vec<u8> {
+    FragmentHeader {
+        message_id,
+        flags: NACK,
+        channel: DEFAULT_CHANNEL,
+        index,
+        total: 0,
+        sequence: 0,
+    }
+    .frame(&[])
+}
+
+/// Returns the message id and fragment index `frame` NACKs, if it is an intact NACK frame.
+pub fn read_nack(frame: &[u8]) -> Option<(u16, u16)> {
+    match FragmentHeader::read_frame(frame) {
+        Ok((header, [])) if header.flags == NACK => Some((header.message_id, header.index)),
+        _ => None,
+    }
+}
+
+/// Returns the ACK frame of the sequence number `ranges`. ACKs are not numbered.
+pub fn ack(ranges: &[RangeInclusive<u32>]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ranges.len() * 8);
+    for range in ranges {
+        data.extend_from_slice(&range.start().to_be_bytes());
+        data.extend_from_slice(&range.end().to_be_bytes());
+    }
+    FragmentHeader {
+        message_id: 0,
+        flags: ACK,
+        channel: DEFAULT_CHANNEL,
+        index: 0,
+        total: 0,
+        sequence: 0,
+    }
+    .frame(&data)
+}
+
+/// Returns the sequence number ranges `frame` ACKs, if it is an intact ACK frame of valid ranges.
+pub fn read_ack(frame: &[u8]) -> Option<Vec<RangeInclusive<u32>>> {
+    let (header, data) = FragmentHeader::read_frame(frame).ok()?;
+    if header.flags != ACK || data.len() % 8 != 0 {
+        return None;
+    }
+    let number = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap());
+    let ranges: Vec<_> =
+        data.chunks(8).map(|range| number(&range[..4])..=number(&range[4..])).collect();
+    ranges.iter().all(|range| !range.is_empty()).then_some(ranges)
+}
+
+/// Splits `message` of `channel` into fragments of at most `max_payload` bytes, header included,
+/// compressing it first if `compress` and it is long enough. An empty message still takes one
+/// fragment. The fragments are numbered from `first_sequence`.
+pub fn fragment(
+    channel: u8,
+    message_id: u16,
+    message: &[u8],
+    max_payload: usize,
+    compress: bool,
+    first_sequence: u32,
+) -> Result<Vec<Vec<u8>>, FramingError> {
+    let compressed = compress.then(|| compression::compress(message)).flatten();
+    let flags = if compressed.is_some() { COMPRESSED } else { 0 };
+    let message = compressed.as_deref().unwrap_or(message);
+    let chunk_len = max_payload
+        .checked_sub(FRAGMENT_HEADER_LEN)
+        .filter(|len| *len > 0)
+        .ok_or(FramingError::MaxPayloadTooSmall(max_payload))?;
+    let total = message.len().div_ceil(chunk_len).max(1);
+    let total = u16::try_from(total).map_err(|_| FramingError::TooManyFragments(message.len()))?;
+    let chunks = message.chunks(chunk_len);
+    let chunks: Box<dyn Iterator<Item = &[u8]>> =
+        if message.is_empty() { Box::new(std::iter::once(message)) } else { Box::new(chunks) };
+    Ok(chunks
+        .zip(0..)
+        .map(|(chunk, index)| {
+            let sequence = first_sequence.wrapping_add(u32::from(index));
+            FragmentHeader { message_id, flags, channel, index, total, sequence }.frame(chunk)
+        })
+        .collect())
+}
+
+/// Fragments received so far of a message.
+struct PartialMessage {
+    /// When the first fragment arrived.
+    started: Instant,
+    flags: u8,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    len: usize,
+}
+
+/// Reassembles the messages of a stream of fragments, which may arrive out of order and
+/// interleaved with the fragments of other messages.
+///
+/// Fragments arriving before their predecessors are held until the message is complete, for up
+/// to `MAX_PARTIAL_MESSAGES` messages at once. A message still missing fragments after its
+/// timeout fails, and its fragments are dropped. Messages are told apart by their channel and id.
+pub struct Reassembler {
+    max_message_len: usize,
+    timeout: Duration,
+    window: ReplayWindow,
+    partial: HashMap<(u8, u16), PartialMessage>,
+    /// Channels and ids of the partial messages, oldest first.
+    order: VecDeque<(u8, u16)>,
+}
+
+impl Reassembler {
+    /// Creates a reassembler of messages of at most `max_message_len` bytes, before and after
+    /// decompression.
+    pub fn new(max_message_len: usize) -> Self {
+        Self {
+            max_message_len,
+            timeout: REASSEMBLY_TIMEOUT,
+            window: ReplayWindow::default(),
+            partial: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Sets the delay after its first fragment by which a message must be complete, instead of
+    /// `REASSEMBLY_TIMEOUT`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns when the oldest partial message times out, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let oldest = self.partial.get(self.order.front()?)?;
+        Some(oldest.started + self.timeout)
+    }
+
+    /// Drops the partial messages timed out at `now`, and returns their ids.
+    pub fn expire(&mut self, now: Instant) -> Vec<u16> {
+        let mut expired = Vec::new();
+        while self.next_deadline().is_some_and(|deadline| deadline <= now) {
+            let key = self.order.pop_front().unwrap();
+            self.partial.remove(&key);
+            expired.push(key.1);
+        }
+        expired
+    }
+
+    /// Adds a received fragment. Returns the message once all its fragments are in. A fragment
+    /// received twice under different sequence numbers replaces the first copy.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, FramingError> {
+        Ok(self.push_at(fragment, Instant::now())?.map(|(_, message)| message))
+    }
+
+    /// Like `push`, also returning the channel of the message.
+    pub fn push_with_channel(
+        &mut self,
+        fragment: &[u8],
+    ) -> Result<Option<(u8, Vec<u8>)>, FramingError> {
+        self.push_at(fragment, Instant::now())
+    }
+
+    fn push_at(
+        &mut self,
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<(u8, Vec<u8>)>, FramingError> {
+        let (header, data) = FragmentHeader::read(fragment)?;
+        match self.window.check(header.sequence) {
+            Ok(()) => {}
+            Err(Replay::Duplicate) => return Err(FramingError::Duplicate(header.sequence)),
+            Err(Replay::Stale) => return Err(FramingError::Stale(header.sequence)),
+        }
+        let key = (header.channel, header.message_id);
+        if !self.partial.contains_key(&key) {
+            if self.order.len() == MAX_PARTIAL_MESSAGES {
+                if let Some((channel, message_id)) = self.order.pop_front() {
+                    warn!("dropping partial message {} of channel {}", message_id, channel);
+                    self.partial.remove(&(channel, message_id));
+                }
+            }
+            self.order.push_back(key);
+            self.partial.insert(
+                key,
+                PartialMessage {
+                    started: now,
+                    flags: header.flags,
+                    fragments: vec![None; usize::from(header.total)],
+                    received: 0,
+                    len: 0,
+                },
+            );
+        }
+        let partial = self.partial.get_mut(&key).unwrap();
+        if partial.fragments.len() != usize::from(header.total) || partial.flags != header.flags {
+            self.forget(key);
+            return Err(FramingError::HeaderMismatch(header.message_id));
+        }
+        let slot = &mut partial.fragments[usize::from(header.index)];
+        match slot.replace(data.to_vec()) {
+            Some(previous) => partial.len -= previous.len(),
+            None => partial.received += 1,
+        }
+        partial.len += data.len();
+        if partial.len > self.max_message_len {
+            self.forget(key);
+            return Err(FramingError::MessageTooLarge(header.message_id));
+        }
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+        let partial = self.forget(key).unwrap();
+        let mut message = Vec::with_capacity(partial.len);
+        partial.fragments.into_iter().flatten().for_each(|data| message.extend(data));
+        if partial.flags & COMPRESSED == 0 {
+            return Ok(Some((header.channel, message)));
+        }
+        match compression::decompress(&message, self.max_message_len) {
+            Ok(message) => Ok(Some((header.channel, message))),
+            Err(DecompressError::Invalid) => {
+                Err(FramingError::InvalidCompression(header.message_id))
+            }
+            Err(DecompressError::TooLarge) => Err(FramingError::MessageTooLarge(header.message_id)),
+        }
+    }
+
+    /// Returns the ranges of sequence numbers received lately, newest first.
+    fn received_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        self.window.received_ranges(MAX_ACK_RANGES)
+    }
+
+    fn forget(&mut self, key: (u8, u16)) -> Option<PartialMessage> {
+        self.order.retain(|partial| *partial != key);
+        self.partial.remove(&key)
+    }
+}
+
+/// Connection id, message id and fragments of a message sent.
+type SentMessage = (i32, u16, Vec<Vec<u8>>);
+
+/// Fragments of the last notifications sent, to retransmit those NACKed.
+#[derive(Default)]
+struct SentNotifications {
+    /// Oldest first.
+    messages: Mutex<VecDeque<SentMessage>>,
+}
+
+impl SentNotifications {
+    fn retain(&self, connection_id: i32, message_id: u16, fragments: Vec<Vec<u8>>) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == RETAINED_NOTIFICATIONS {
+            messages.pop_front();
+        }
+        messages.push_back((connection_id, message_id, fragments));
+    }
+
+    fn fragment(&self, connection_id: i32, message_id: u16, index: u16) -> Option<Vec<u8>> {
+        let messages = self.messages.lock().unwrap();
+        let (_, _, fragments) =
+            messages.iter().rev().find(|(c, m, _)| (*c, *m) == (connection_id, message_id))?;
+        fragments.get(usize::from(index)).cloned()
+    }
+}
+
+/// Sends a frame on a connection.
+type SendFrame = Box<dyn Fn(&[u8]) -> anyhow::Result<()> + Send + Sync>;
+
+/// Messages of a `FramedPlatform` subscription, reassembled from their fragments.
+pub struct FramedMessageStream {
+    inner: MessageStream,
+    reassembler: Reassembler,
+    /// Sends a frame on the connection, as a notification.
+    send: SendFrame,
+    sent: Arc<SentNotifications>,
+    replay_counters: Arc<ReplayCounters>,
+    reliability: Option<Arc<Reliability>>,
+}
+
+impl FramedMessageStream {
+    /// Returns the connection the messages come from.
+    pub fn connection_id(&self) -> i32 {
+        self.inner.connection_id()
+    }
+
+    /// Waits for the next complete message, or returns None once the platform is gone. Malformed
+    /// fragments are dropped, and corrupted ones NACKed. NACKs of the notifications of the
+    /// `FramedPlatform` are answered with the fragment, if still retained. With reliability,
+    /// fragments are ACKed, and those of the `FramedPlatform` retransmitted as ACKs and timeouts
+    /// tell, while subscribed.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        self.next_with_channel().await.map(|(_, message)| message)
+    }
+
+    /// Like `next`, also returning the channel of the message.
+    pub async fn next_with_channel(&mut self) -> Option<(u8, Vec<u8>)> {
+        let connection_id = self.inner.connection_id();
+        loop {
+            let retransmit_deadline =
+                self.reliability.as_ref().and_then(|r| r.next_deadline(connection_id));
+            let deadline = match (self.reassembler.next_deadline(), retransmit_deadline) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            // Wakes up now and then to catch fragments sent while waiting.
+            let deadline = deadline
+                .or_else(|| self.reliability.as_ref().map(|r| Instant::now() + r.ack_timeout()));
+            let frame = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline.into(), self.inner.next()).await {
+                        Ok(frame) => frame?,
+                        Err(_) => {
+                            let now = Instant::now();
+                            for message_id in self.reassembler.expire(now) {
+                                warn!(
+                                    "message {} from connection {} timed out incomplete",
+                                    message_id, connection_id
+                                );
+                            }
+                            if let Some(reliability) = &self.reliability {
+                                self.retransmit(reliability.expire(connection_id, now));
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => self.inner.next().await?,
+            };
+            if let Some(ranges) = read_ack(&frame) {
+                if let Some(reliability) = &self.reliability {
+                    self.retransmit(reliability.on_ack(connection_id, &ranges));
+                }
+                continue;
+            }
+            if let Some((message_id, index)) = read_nack(&frame) {
+                let Some(fragment) = self.sent.fragment(connection_id, message_id, index) else {
+                    warn!("fragment {} of message {} NACKed, but not retained", index, message_id);
+                    continue;
+                };
+                if let Err(e) = (self.send)(&fragment) {
+                    warn!("failed to retransmit on connection {}: {:?}", connection_id, e);
+                }
+                continue;
+            }
+            let pushed = self.reassembler.push_with_channel(&frame);
+            if self.reliability.is_some()
+                && matches!(pushed, Ok(_) | Err(FramingError::Duplicate(_)))
+            {
+                // Duplicates are ACKed again, in case the ACK was lost.
+                if let Err(e) = (self.send)(&ack(&self.reassembler.received_ranges())) {
+                    warn!("failed to ACK on connection {}: {:?}", connection_id, e);
+                }
+            }
+            match pushed {
+                Ok(Some(message)) => return Some(message),
+                Ok(None) => {}
+                Err(FramingError::Integrity(IntegrityError { message_id, index })) => {
+                    warn!("NACKing corrupted fragment from connection {}", connection_id);
+                    if let Err(e) = (self.send)(&nack(message_id, index)) {
+                        warn!("failed to NACK on connection {}: {:?}", connection_id, e);
+                    }
+                }
+                Err(e) => {
+                    match e {
+                        FramingError::Duplicate(_) => {
+                            self.replay_counters.record(Replay::Duplicate)
+                        }
+                        FramingError::Stale(_) => self.replay_counters.record(Replay::Stale),
+                        _ => {}
+                    }
+                    warn!("dropping fragment from connection {}: {}", connection_id, e)
+                }
+            }
+        }
+    }
+
+    fn retransmit(&self, fragments: Vec<Vec<u8>>) {
+        for fragment in fragments {
+            if let Err(e) = (self.send)(&fragment) {
+                warn!("failed to retransmit on connection {}: {:?}", self.connection_id(), e);
+            }
+        }
+    }
+}
+
+/// Sends messages of any size over a platform, split to the max payload of their connection.
+///
+/// The fragments of a request are sent one after the other, each once the previous one is
+/// acknowledged: the response to the last fragment is the response to the request. A fragment
+/// answered with a NACK is retransmitted, up to `MAX_RETRANSMISSIONS` times.
+pub struct FramedPlatform<T: Platform + ?Sized> {
+    platform: Arc<T>,
+    next_message_id: AtomicU16,
+    capability_cache: Option<Arc<CapabilityCache>>,
+    reliability: Option<Arc<Reliability>>,
+    sent_notifications: Arc<SentNotifications>,
+    /// Sequence number of the next fragment of each connection.
+    sequences: Mutex<HashMap<i32, u32>>,
+    replay_counters: Arc<ReplayCounters>,
+}
+
+impl<T: Platform + ?Sized> FramedPlatform<T> {
+    /// Wraps `platform`.
+    pub fn new(platform: Arc<T>) -> Self {
+        Self {
+            platform,
+            next_message_id: AtomicU16::new(0),
+            capability_cache: None,
+            reliability: None,
+            sent_notifications: Arc::default(),
+            sequences: Mutex::new(HashMap::new()),
+            replay_counters: Arc::default(),
+        }
+    }
+
+    /// Also limits fragments to the max payload the remote device accepts, and compresses large
+    /// messages if it supports it, once exchanged in its capabilities.
+    pub fn with_capability_cache(mut self, capability_cache: Arc<CapabilityCache>) -> Self {
+        self.capability_cache = Some(capability_cache);
+        self
+    }
+
+    /// Also ACKs the fragments received on its subscriptions, and retransmits its notification
+    /// fragments until ACKed, for lossy transports.
+    pub fn with_reliability(mut self, config: ReliabilityConfig) -> Self {
+        self.reliability = Some(Arc::new(Reliability::new(config)));
+        self
+    }
+
+    /// Returns the wrapped platform.
+    pub fn platform(&self) -> &Arc<T> {
+        &self.platform
+    }
+
+    /// Returns the number of fragments its subscriptions dropped as replays.
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.replay_counters.stats()
+    }
+
+    /// Forgets the sequence numbers and the fragments not ACKed of `connection_id`, once it is
+    /// closed.
+    pub fn forget_connection(&self, connection_id: i32) {
+        self.sequences.lock().unwrap().remove(&connection_id);
+        if let Some(reliability) = &self.reliability {
+            reliability.forget(connection_id);
+        }
+    }
+
+    /// Returns the message id, the sequence number of the first fragment and the fragments of
+    /// `message` of `channel`.
+    async fn fragment(
+        &self,
+        connection_id: i32,
+        channel: u8,
+        message: &[u8],
+    ) -> anyhow::Result<(u16, u32, Vec<Vec<u8>>)> {
+        let max_payload = match self.platform.max_payload(connection_id) {
+            Some(max_payload) => max_payload,
+            None => self.platform.discover_mtu(connection_id).await?,
+        };
+        let peer_max_payload =
+            self.capability_cache.as_ref().and_then(|cache| cache.max_payload(connection_id));
+        let max_payload = peer_max_payload.map_or(max_payload, |peer| max_payload.min(peer));
+        let compress =
+            self.capability_cache.as_ref().is_some_and(|cache| cache.compression(connection_id));
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        // Numbers the fragments under the lock, so that they are consecutive.
+        let mut sequences = self.sequences.lock().unwrap();
+        let sequence = sequences.entry(connection_id).or_default();
+        let first_sequence = *sequence;
+        let fragments =
+            fragment(channel, message_id, message, max_payload, compress, first_sequence)?;
+        *sequence = sequence.wrapping_add(fragments.len() as u32);
+        Ok((message_id, first_sequence, fragments))
+    }
+
+    async fn send_fragment(
+        &self,
+        connection_id: i32,
+        fragment: &[u8],
+        index: u16,
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let callback = Box::new(OneshotCallback { sender: Some(sender) });
+        self.platform.send_request(connection_id, fragment, metadata, timeout, callback)?;
+        match receiver.await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(error_code)) => Err(FramingError::FragmentFailed { index, error_code }.into()),
+            Err(_) => Err(PlatformError::PlatformDestroyed.into()),
+        }
+    }
+
+    /// Sends `request` on `connection_id` in fragments, and returns the response to the last
+    /// one. Fails with `FragmentFailed` on the first fragment failing, or `Integrity` on the
+    /// first still NACKed after its retransmissions, without sending the others.
+    pub async fn send_request(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        self.send_request_on_channel(connection_id, DEFAULT_CHANNEL, request, metadata, timeout)
+            .await
+    }
+
+    /// Like `send_request`, on `channel`.
+    pub async fn send_request_on_channel(
+        &self,
+        connection_id: i32,
+        channel: u8,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        let (message_id, _, fragments) = self.fragment(connection_id, channel, request).await?;
+        let mut response = None;
+        for (fragment, index) in fragments.iter().zip(0..) {
+            let mut retransmissions = 0;
+            let fragment_response = loop {
+                let fragment_response =
+                    self.send_fragment(connection_id, fragment, index, metadata, timeout).await?;
+                if read_nack(&fragment_response.payload) != Some((message_id, index)) {
+                    break fragment_response;
+                }
+                if retransmissions == MAX_RETRANSMISSIONS {
+                    return Err(FramingError::from(IntegrityError { message_id, index }).into());
+                }
+                retransmissions += 1;
+                warn!("retransmitting fragment {} of message {}", index, message_id);
+            };
+            response = Some(fragment_response);
+        }
+        Ok(response.expect("a message has at least one fragment"))
+    }
+
+    /// Sends `payload` on `connection_id` as one-way fragments, retained to be retransmitted if
+    /// NACKed on a subscription to the connection, or until ACKed with reliability.
+    pub async fn send_notification(
+        &self,
+        connection_id: i32,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        self.send_notification_on_channel(connection_id, DEFAULT_CHANNEL, payload).await
+    }
+
+    /// Like `send_notification`, on `channel`.
+    pub async fn send_notification_on_channel(
+        &self,
+        connection_id: i32,
+        channel: u8,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        self.notify(connection_id, channel, payload, None).await
+    }
+
+    /// Sends `payload` on `channel` of `connection_id` like `send_notification_on_channel`, and
+    /// waits for all its fragments to be ACKed. Fails with `Undelivered` if one is still not
+    /// ACKed after its retransmissions, which needs a subscription to the connection to tell.
+    /// The remote device may receive the message even then, or twice.
+    pub async fn deliver(
+        &self,
+        connection_id: i32,
+        channel: u8,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.reliability.is_none() {
+            return Err(FramingError::Unreliable.into());
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.notify(connection_id, channel, payload, Some(sender)).await?;
+        match receiver.await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(PlatformError::PlatformDestroyed.into()),
+        }
+    }
+
+    async fn notify(
+        &self,
+        connection_id: i32,
+        channel: u8,
+        payload: &[u8],
+        delivery: Option<Delivery>,
+    ) -> anyhow::Result<()> {
+        let (message_id, first_sequence, fragments) =
+            self.fragment(connection_id, channel, payload).await?;
+        if let Some(reliability) = &self.reliability {
+            reliability.track(connection_id, message_id, first_sequence, &fragments, delivery);
+        }
+        for fragment in &fragments {
+            self.platform.send_notification(connection_id, fragment)?;
+        }
+        self.sent_notifications.retain(connection_id, message_id, fragments);
+        Ok(())
+    }
+
+    /// Subscribes to the messages pushed on `connection_id`, reassembled from their fragments up
+    /// to the configured max payload size.
+    pub fn subscribe(&self, connection_id: i32) -> anyhow::Result<FramedMessageStream>
+    where
+        T: 'static,
+    {
+        let platform = Arc::clone(&self.platform);
+        Ok(FramedMessageStream {
+            inner: self.platform.subscribe(connection_id)?,
+            reassembler: Reassembler::new(config::snapshot().max_payload_size),
+            send: Box::new(move |frame| platform.send_notification(connection_id, frame)),
+            sent: Arc::clone(&self.sent_notifications),
+            replay_counters: Arc::clone(&self.replay_counters),
+            reliability: self.reliability.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(
+        message_id: u16,
+        flags: u8,
+        index: u16,
+        total: u16,
+        sequence: u32,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let channel = DEFAULT_CHANNEL;
+        FragmentHeader { message_id, flags, channel, index, total, sequence }.frame(data)
+    }
+
+    #[test]
+    fn test_fragment() {
+        let fragments = fragment(0, 7, &[1, 2, 3, 4, 5], 19, false, 10).unwrap();
+        assert_eq!(
+            fragments,
+            vec![frame(7, 0, 0, 2, 10, &[1, 2, 3]), frame(7, 0, 1, 2, 11, &[4, 5])]
+        );
+        assert_eq!(&fragments[1][..CRC_OFFSET], &[0, 7, 0, 0, 0, 1, 0, 2, 0, 0, 0, 11]);
+        assert_eq!(fragment(0, 7, &[], 19, false, 0).unwrap(), vec![frame(7, 0, 0, 1, 0, &[])]);
+        assert_eq!(fragment(0, 7, &[1], 16, false, 0), Err(FramingError::MaxPayloadTooSmall(16)));
+        assert_eq!(
+            fragment(0, 7, &vec![0; 70_000], 17, false, 0),
+            Err(FramingError::TooManyFragments(70_000))
+        );
+        assert_eq!(fragment(3, 7, &[1], 17, false, 0).unwrap()[0][3], 3);
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let mut reassembler = Reassembler::new(1024);
+        let first = fragment(0, 1, &[1, 2, 3, 4, 5], 18, false, 0).unwrap();
+        let second = fragment(0, 2, &[6], 18, false, 3).unwrap();
+        assert_eq!(reassembler.push(&first[2]), Ok(None));
+        assert_eq!(reassembler.push(&second[0]), Ok(Some(vec![6])));
+        assert_eq!(reassembler.push(&first[0]), Ok(None));
+        assert_eq!(reassembler.push(&first[0]), Err(FramingError::Duplicate(0)));
+        assert_eq!(reassembler.push(&first[1]), Ok(Some(vec![1, 2, 3, 4, 5])));
+
+        assert_eq!(reassembler.push(&[0, 1]), Err(FramingError::Truncated(2)));
+        assert_eq!(
+            reassembler.push(&frame(1, 0, 2, 2, 4, &[])),
+            Err(FramingError::InvalidIndex { index: 2, total: 2 })
+        );
+        assert_eq!(
+            reassembler.push(&frame(1, 4, 0, 1, 5, &[])),
+            Err(FramingError::UnknownFlags(4))
+        );
+        assert_eq!(reassembler.push(&frame(3, 0, 0, 2, 6, &[1])), Ok(None));
+        assert_eq!(
+            reassembler.push(&frame(3, 0, 1, 3, 7, &[1])),
+            Err(FramingError::HeaderMismatch(3))
+        );
+        assert_eq!(reassembler.push(&frame(9, 0, 0, 1, 100, &[])), Ok(Some(vec![])));
+        assert_eq!(reassembler.push(&frame(10, 0, 0, 1, 30, &[])), Err(FramingError::Stale(30)));
+
+        let mut reassembler = Reassembler::new(3);
+        assert_eq!(reassembler.push(&frame(4, 0, 0, 2, 0, &[1, 2])), Ok(None));
+        assert_eq!(
+            reassembler.push(&frame(4, 0, 1, 2, 1, &[3, 4])),
+            Err(FramingError::MessageTooLarge(4))
+        );
+    }
+
+    #[test]
+    fn test_integrity() {
+        let mut reassembler = Reassembler::new(1024);
+        let mut corrupted = frame(5, 0, 1, 2, 0, &[1, 2, 3]);
+        corrupted[FRAGMENT_HEADER_LEN] ^= 0x10;
+        assert_eq!(
+            reassembler.push(&corrupted),
+            Err(FramingError::Integrity(IntegrityError { message_id: 5, index: 1 }))
+        );
+        assert_eq!(read_nack(&nack(5, 1)), Some((5, 1)));
+        assert_eq!(read_nack(&frame(5, 0, 1, 2, 0, &[1, 2, 3])), None);
+        let mut corrupted = nack(5, 1);
+        corrupted[0] ^= 0x01;
+        assert_eq!(read_nack(&corrupted), None);
+        assert_eq!(reassembler.push(&nack(5, 1)), Err(FramingError::UnknownFlags(NACK)));
+    }
+
+    #[test]
+    fn test_ack() {
+        assert_eq!(read_ack(&ack(&[7..=9, 2..=2])), Some(vec![7..=9, 2..=2]));
+        assert_eq!(read_ack(&ack(&[])), Some(vec![]));
+        assert_eq!(read_ack(&nack(5, 1)), None);
+        assert_eq!(read_ack(&ack(&[RangeInclusive::new(9, 7)])), None);
+        let mut corrupted = ack(&[7..=9]);
+        corrupted[FRAGMENT_HEADER_LEN] ^= 0x01;
+        assert_eq!(read_ack(&corrupted), None);
+        assert_eq!(Reassembler::new(1024).push(&ack(&[])), Err(FramingError::UnknownFlags(ACK)));
+    }
+
+    #[test]
+    fn test_compression() {
+        let message = [7; 4096];
+        assert!(fragment(0, 1, &message, 40, false, 0).unwrap().iter().all(|f| f[2] == 0));
+        let fragments = fragment(0, 1, &message, 40, true, 0).unwrap();
+        assert!(fragments.iter().all(|fragment| fragment[2] == COMPRESSED));
+        let mut reassembler = Reassembler::new(4096);
+        let (last, rest) = fragments.split_last().unwrap();
+        rest.iter().for_each(|fragment| assert_eq!(reassembler.push(fragment), Ok(None)));
+        assert_eq!(reassembler.push(last), Ok(Some(message.to_vec())));
+
+        let mut reassembler = Reassembler::new(4095);
+        rest.iter().for_each(|fragment| assert_eq!(reassembler.push(fragment), Ok(None)));
+        assert_eq!(reassembler.push(last), Err(FramingError::MessageTooLarge(1)));
+        assert_eq!(
+            reassembler.push(&frame(5, COMPRESSED, 0, 1, 100, &[0xff])),
+            Err(FramingError::InvalidCompression(5))
+        );
+    }
+
+    #[test]
+    fn test_reassembly_timeout() {
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(1024).with_timeout(Duration::from_secs(1));
+        assert_eq!(reassembler.next_deadline(), None);
+        assert_eq!(reassembler.push_at(&frame(1, 0, 1, 2, 1, &[2]), start), Ok(None));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(reassembler.push_at(&frame(2, 0, 1, 2, 3, &[4]), later), Ok(None));
+        assert_eq!(reassembler.next_deadline(), Some(start + Duration::from_secs(1)));
+        assert_eq!(reassembler.expire(later), Vec::<u16>::new());
+        assert_eq!(reassembler.expire(start + Duration::from_secs(1)), vec![1]);
+        assert_eq!(reassembler.next_deadline(), Some(later + Duration::from_secs(1)));
+        assert_eq!(reassembler.push(&frame(2, 0, 0, 2, 2, &[3])), Ok(Some(vec![3, 4])));
+        assert_eq!(reassembler.push(&frame(1, 0, 0, 2, 0, &[1])), Ok(None));
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut reassembler = Reassembler::new(1024);
+        // The same message id on two channels are two messages.
+        let auth = fragment(1, 5, &[1, 2], 17, false, 0).unwrap();
+        let telemetry = fragment(3, 5, &[3, 4], 17, false, 2).unwrap();
+        assert_eq!(reassembler.push_with_channel(&auth[0]), Ok(None));
+        assert_eq!(reassembler.push_with_channel(&telemetry[0]), Ok(None));
+        assert_eq!(reassembler.push_with_channel(&auth[1]), Ok(Some((1, vec![1, 2]))));
+        assert_eq!(reassembler.push_with_channel(&telemetry[1]), Ok(Some((3, vec![3, 4]))));
+    }
+
+    #[test]
+    fn test_drop_oldest_partial_message() {
+        let mut reassembler = Reassembler::new(1024);
+        for message_id in 0..=MAX_PARTIAL_MESSAGES as u16 {
+            let fragment = frame(message_id, 0, 0, 2, message_id.into(), &[message_id as u8]);
+            assert_eq!(reassembler.push(&fragment), Ok(None));
+        }
+        assert_eq!(reassembler.push(&frame(0, 0, 1, 2, 10, &[9])), Ok(None));
+        assert_eq!(reassembler.push(&frame(2, 0, 1, 2, 11, &[9])), Ok(Some(vec![2, 9])));
+    }
+}
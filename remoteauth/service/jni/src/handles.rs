@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Strongly-typed handles shared with Java.
+//!
+//! Platforms, responses and connection operations are identified on the Java side by plain `long` values. Wrapping them
+//! in distinct types makes passing one where the other is expected a compile error, and
+//! validates values coming back from Java before they are used as map keys.
+
+use crate::error::PlatformError;
+use jni::sys::jlong;
+use log::warn;
+use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+macro_rules! handle_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(i64);
+
+        impl $name {
+            /// Smallest valid value.
+            pub const MIN: $name = $name(0);
+            /// Largest valid value.
+            pub const MAX: $name = $name(i64::MAX);
+
+            /// Wraps a natively generated value. Panics if it is out of range.
+            pub(crate) fn new(value: i64) -> Self {
+                assert!(value >= 0, "{} out of range: {}", stringify!($name), value);
+                Self(value)
+            }
+
+            /// Returns the value passed to Java.
+            pub fn as_jlong(self) -> jlong {
+                self.0
+            }
+        }
+
+        impl TryFrom<jlong> for $name {
+            type Error = PlatformError;
+
+            /// Validates a value received from Java.
+            fn try_from(value: jlong) -> Result<Self, Self::Error> {
+                if value >= 0 {
+                    Ok(Self(value))
+                } else {
+                    Err(PlatformError::InvalidHandle(value))
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+handle_type!(
+    /// Identifies a JavaPlatform in HANDLE_MAPPING.
+    PlatformHandle
+);
+
+handle_type!(
+    /// Identifies a pending request of a JavaPlatform.
+    ResponseHandle
+);
+
+handle_type!(
+    /// Identifies a pending connection open or close of a JavaPlatform.
+    OperationHandle
+);
+
+/// Hands out handle values in `0..=max`, wrapping around to 0 once `max` is reached.
+///
+/// Values still in use when the counter comes back around are skipped, so a long-lived process
+/// never hands out a handle that is already live.
+#[derive(Debug)]
+pub(crate) struct HandleAllocator {
+    next: AtomicI64,
+    max: i64,
+}
+
+impl HandleAllocator {
+    /// Creates an allocator covering every valid handle.
+    pub(crate) const fn new() -> Self {
+        Self::with_max(i64::MAX)
+    }
+
+    /// Creates an allocator covering `0..=max`.
+    pub(crate) const fn with_max(max: i64) -> Self {
+        Self { next: AtomicI64::new(0), max }
+    }
+
+    /// Returns the next value for which `in_use` is false.
+    ///
+    /// `live` is the number of values currently in use: once that many values were skipped, all
+    /// of them have been seen and the next candidate is free, so the search is bounded.
+    pub(crate) fn allocate(
+        &self,
+        live: usize,
+        in_use: impl Fn(i64) -> bool,
+    ) -> Result<i64, PlatformError> {
+        if live as u64 > self.max as u64 {
+            return Err(PlatformError::HandlesExhausted);
+        }
+        for _ in 0..=live {
+            let value = self.next_value();
+            if !in_use(value) {
+                return Ok(value);
+            }
+        }
+        Err(PlatformError::HandlesExhausted)
+    }
+
+    /// Starts over from 0.
+    pub(crate) fn reset(&self) {
+        self.next.store(0, Ordering::SeqCst);
+    }
+
+    fn next_value(&self) -> i64 {
+        let max = self.max;
+        let value = self
+            .next
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(if value >= max { 0 } else { value + 1 })
+            })
+            .unwrap();
+        if value == max {
+            warn!("Handle counter reached {}, wrapping around to 0", max);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_from_jlong_accepts_valid_range() {
+        assert_eq!(PlatformHandle::try_from(0), Ok(PlatformHandle::MIN));
+        assert_eq!(PlatformHandle::try_from(i64::MAX), Ok(PlatformHandle::MAX));
+        assert_eq!(ResponseHandle::try_from(42).map(ResponseHandle::as_jlong), Ok(42));
+    }
+
+    #[test]
+    fn test_try_from_jlong_rejects_negative() {
+        assert_eq!(PlatformHandle::try_from(-1), Err(PlatformError::InvalidHandle(-1)));
+        assert_eq!(ResponseHandle::try_from(i64::MIN), Err(PlatformError::InvalidHandle(i64::MIN)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_new_rejects_negative() {
+        PlatformHandle::new(-5);
+    }
+
+    #[test]
+    fn test_allocator_wraps_around_at_i64_max() {
+        let allocator = HandleAllocator::new();
+        allocator.next.store(i64::MAX - 1, Ordering::SeqCst);
+        assert_eq!(allocator.allocate(0, |_| false), Ok(i64::MAX - 1));
+        assert_eq!(allocator.allocate(0, |_| false), Ok(i64::MAX));
+        assert_eq!(allocator.allocate(0, |_| false), Ok(0));
+    }
+
+    #[test]
+    fn test_allocator_skips_values_in_use() {
+        let allocator = HandleAllocator::new();
+        allocator.next.store(i64::MAX, Ordering::SeqCst);
+        let in_use = [i64::MAX, 0, 1];
+        assert_eq!(allocator.allocate(in_use.len(), |v| in_use.contains(&v)), Ok(2));
+    }
+
+    #[test]
+    fn test_allocator_reuses_released_values() {
+        let allocator = HandleAllocator::with_max(2);
+        let mut in_use = Vec::new();
+        for expected in 0..=2 {
+            let value = allocator.allocate(in_use.len(), |v| in_use.contains(&v)).unwrap();
+            assert_eq!(value, expected);
+            in_use.push(value);
+        }
+        assert_eq!(
+            allocator.allocate(in_use.len(), |v| in_use.contains(&v)),
+            Err(PlatformError::HandlesExhausted)
+        );
+        // Destroying the platform holding 1 makes it available again.
+        in_use.retain(|&v| v != 1);
+        assert_eq!(allocator.allocate(in_use.len(), |v| in_use.contains(&v)), Ok(1));
+    }
+}
@@ -0,0 +1,297 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Event-driven state machine of the handshakes run on a connection, e.g. version negotiation,
+//! capability exchange or secure channel setup.
+//!
+//! A `Handshake` only knows its messages: what to send first, and what to do with each reply.
+//! `HandshakeMachine` tracks where it stands: it resends the last message when a reply times out,
+//! and can resume from that message after a reconnection instead of starting over.
+//!
+//! Each exchange is recorded in the transcript of the connection, which session keys are bound
+//! to.
+
+use crate::error::PlatformError;
+use crate::messages::{MessageError, TypedPlatform};
+use crate::remoteauth_jni_android_platform::{Platform, RequestMetadata};
+use log::{info, warn};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Timeouts of a single message before a handshake fails.
+pub const MAX_HANDSHAKE_TIMEOUTS: u32 = 2;
+
+/// What a handshake does after a reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step<O> {
+    /// Sends another message and waits for its reply.
+    Send(Vec<u8>),
+    /// Completes with an outcome.
+    Done(O),
+}
+
+/// Messages of a handshake, each answered by a reply of the remote device.
+pub trait Handshake {
+    /// Outcome of the completed handshake.
+    type Output;
+
+    /// Name of the handshake, for logs.
+    const NAME: &'static str;
+
+    /// Returns the first message.
+    fn first_message(&mut self) -> Vec<u8>;
+
+    /// Handles the reply to the last message sent. Fails if the reply is invalid, which fails
+    /// the handshake.
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<Self::Output>>;
+}
+
+/// Where a handshake stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    /// Not started.
+    Idle,
+    /// Waiting for the reply to a message.
+    Awaiting {
+        /// Number of the message, from 1.
+        round: u32,
+        /// Times its reply timed out.
+        timeouts: u32,
+    },
+    /// Completed.
+    Complete,
+    /// Failed, for good.
+    Failed,
+}
+
+/// Why a handshake failed, or an event it can't handle in its state.
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    /// The event doesn't apply to the state of the handshake, e.g. a reply before starting.
+    #[error("handshake is {0:?}")]
+    InvalidState(HandshakeState),
+    /// The reply to a message timed out too many times.
+    #[error("no reply to message {round}")]
+    Timeout {
+        /// Number of the message, from 1.
+        round: u32,
+    },
+    /// The remote device replied with an invalid message.
+    #[error("invalid reply to message {round}")]
+    InvalidReply {
+        /// Number of the message, from 1.
+        round: u32,
+        /// What is wrong with the reply.
+        #[source]
+        error: anyhow::Error,
+    },
+}
+
+/// Runs a handshake, event by event.
+pub struct HandshakeMachine<H: Handshake> {
+    handshake: H,
+    state: HandshakeState,
+    max_timeouts: u32,
+    // Message waiting for a reply, sent again on timeout or resume.
+    last_sent: Vec<u8>,
+}
+
+impl<H: Handshake> HandshakeMachine<H> {
+    /// Creates an idle machine, failing after `MAX_HANDSHAKE_TIMEOUTS` timeouts of a message.
+    pub fn new(handshake: H) -> Self {
+        Self {
+            handshake,
+            state: HandshakeState::Idle,
+            max_timeouts: MAX_HANDSHAKE_TIMEOUTS,
+            last_sent: Vec::new(),
+        }
+    }
+
+    /// Sets how many times the reply to a message may time out before the handshake fails.
+    pub fn with_max_timeouts(mut self, max_timeouts: u32) -> Self {
+        self.max_timeouts = max_timeouts;
+        self
+    }
+
+    /// Returns where the handshake stands.
+    pub fn state(&self) -> HandshakeState {
+        self.state
+    }
+
+    /// Starts the handshake, returning its first message to send.
+    pub fn start(&mut self) -> Result<Vec<u8>, HandshakeError> {
+        if self.state != HandshakeState::Idle {
+            return Err(HandshakeError::InvalidState(self.state));
+        }
+        self.last_sent = self.handshake.first_message();
+        self.state = HandshakeState::Awaiting { round: 1, timeouts: 0 };
+        Ok(self.last_sent.clone())
+    }
+
+    /// Handles the reply to the last message, returning the next message or the outcome.
+    pub fn receive(&mut self, reply: &[u8]) -> Result<Step<H::Output>, HandshakeError> {
+        let HandshakeState::Awaiting { round, .. } = self.state else {
+            return Err(HandshakeError::InvalidState(self.state));
+        };
+        match self.handshake.on_reply(reply) {
+            Ok(Step::Send(message)) => {
+                self.last_sent = message.clone();
+                self.state = HandshakeState::Awaiting { round: round + 1, timeouts: 0 };
+                Ok(Step::Send(message))
+            }
+            Ok(Step::Done(output)) => {
+                self.last_sent = Vec::new();
+                self.state = HandshakeState::Complete;
+                Ok(Step::Done(output))
+            }
+            Err(error) => {
+                self.state = HandshakeState::Failed;
+                Err(HandshakeError::InvalidReply { round, error })
+            }
+        }
+    }
+
+    /// Handles the reply to the last message timing out, returning the message to send again,
+    /// or failing once it timed out too many times.
+    pub fn timeout(&mut self) -> Result<Vec<u8>, HandshakeError> {
+        let HandshakeState::Awaiting { round, timeouts } = self.state else {
+            return Err(HandshakeError::InvalidState(self.state));
+        };
+        if timeouts >= self.max_timeouts {
+            self.state = HandshakeState::Failed;
+            return Err(HandshakeError::Timeout { round });
+        }
+        self.state = HandshakeState::Awaiting { round, timeouts: timeouts + 1 };
+        Ok(self.last_sent.clone())
+    }
+
+    /// Returns the message to send again to resume the handshake, e.g. after a reconnection.
+    pub fn resume(&self) -> Result<Vec<u8>, HandshakeError> {
+        match self.state {
+            HandshakeState::Awaiting { .. } => Ok(self.last_sent.clone()),
+            state => Err(HandshakeError::InvalidState(state)),
+        }
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Runs the handshake of `machine` on `connection_id` to completion, starting it, or resuming
+    /// it if it was interrupted, e.g. by a disconnect. Replies timing out after `timeout` are
+    /// waited for again as `machine` allows; other failures to send leave it to be resumed.
+    pub async fn run_handshake<H: Handshake>(
+        &self,
+        connection_id: i32,
+        machine: &mut HandshakeMachine<H>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<H::Output> {
+        let mut message = match machine.state() {
+            HandshakeState::Idle => machine.start()?,
+            _ => {
+                info!("resuming {} on connection {}", H::NAME, connection_id);
+                machine.resume()?
+            }
+        };
+        let timed_out = MessageError::Failed(PlatformError::Timeout.error_code());
+        loop {
+            match self.send_raw(connection_id, &message, RequestMetadata::new(), timeout).await {
+                Ok(reply) => {
+                    self.record_exchange(connection_id, &message, &reply);
+                    match machine.receive(&reply)? {
+                        Step::Send(next) => message = next,
+                        Step::Done(output) => return Ok(output),
+                    }
+                }
+                Err(e) if e.downcast_ref::<MessageError>() == Some(&timed_out) => {
+                    warn!("{} on connection {} timed out", H::NAME, connection_id);
+                    message = machine.timeout()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends 1 and 2, expecting each to be echoed back.
+    struct Echo {
+        next: u8,
+    }
+
+    impl Handshake for Echo {
+        type Output = &'static str;
+        const NAME: &'static str = "echo";
+
+        fn first_message(&mut self) -> Vec<u8> {
+            self.next = 1;
+            vec![1]
+        }
+
+        fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<Self::Output>> {
+            anyhow::ensure!(reply == [self.next], "unexpected reply {:?}", reply);
+            if self.next == 2 {
+                return Ok(Step::Done("done"));
+            }
+            self.next = 2;
+            Ok(Step::Send(vec![2]))
+        }
+    }
+
+    fn machine() -> HandshakeMachine<Echo> {
+        HandshakeMachine::new(Echo { next: 0 }).with_max_timeouts(1)
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut machine = machine();
+        assert!(matches!(machine.receive(&[1]), Err(HandshakeError::InvalidState(_))));
+        assert!(matches!(machine.resume(), Err(HandshakeError::InvalidState(_))));
+        assert_eq!(machine.start().unwrap(), vec![1]);
+        assert!(matches!(machine.start(), Err(HandshakeError::InvalidState(_))));
+        assert_eq!(machine.receive(&[1]).unwrap(), Step::Send(vec![2]));
+        assert_eq!(machine.state(), HandshakeState::Awaiting { round: 2, timeouts: 0 });
+        assert_eq!(machine.resume().unwrap(), vec![2]);
+        assert_eq!(machine.timeout().unwrap(), vec![2]);
+        assert_eq!(machine.receive(&[2]).unwrap(), Step::Done("done"));
+        assert_eq!(machine.state(), HandshakeState::Complete);
+        assert!(matches!(machine.timeout(), Err(HandshakeError::InvalidState(_))));
+        assert!(matches!(machine.receive(&[2]), Err(HandshakeError::InvalidState(_))));
+    }
+
+    #[test]
+    fn test_invalid_reply() {
+        let mut machine = machine();
+        machine.start().unwrap();
+        assert!(matches!(
+            machine.receive(&[7]),
+            Err(HandshakeError::InvalidReply { round: 1, .. })
+        ));
+        assert_eq!(machine.state(), HandshakeState::Failed);
+        assert!(matches!(machine.resume(), Err(HandshakeError::InvalidState(_))));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut machine = machine();
+        machine.start().unwrap();
+        assert_eq!(machine.timeout().unwrap(), vec![1]);
+        assert_eq!(machine.state(), HandshakeState::Awaiting { round: 1, timeouts: 1 });
+        assert!(matches!(machine.timeout(), Err(HandshakeError::Timeout { round: 1 })));
+        assert_eq!(machine.state(), HandshakeState::Failed);
+    }
+}
@@ -13,5 +13,61 @@
 // limitations under the License.
 
 //! Name of java classes and methods for RemoteAuth platform:
+pub(crate) const PLATFORM_CLASS: &str =
+    "com/android/server/remoteauth/jni/NativeRemoteAuthJavaPlatform";
+pub(crate) const NATIVE_CONFIG_LOG_LEVEL_FNAME: &str = "logLevel";
+pub(crate) const NATIVE_CONFIG_CHANNEL_CAPACITY_FNAME: &str = "channelCapacity";
+pub(crate) const NATIVE_CONFIG_DEFAULT_TIMEOUT_FNAME: &str = "defaultTimeoutMillis";
+pub(crate) const NATIVE_CONFIG_WORKER_THREADS_FNAME: &str = "workerThreads";
+pub(crate) const NATIVE_CONFIG_THREAD_NAME_PREFIX_FNAME: &str = "threadNamePrefix";
+pub(crate) const NATIVE_CONFIG_MAX_BLOCKING_THREADS_FNAME: &str = "maxBlockingThreads";
+pub(crate) const NATIVE_CONFIG_USE_CURRENT_THREAD_FNAME: &str = "useCurrentThreadRuntime";
+pub(crate) const PLATFORM_CONFIG_RESPONSE_CHANNEL_CAPACITY_FNAME: &str = "responseChannelCapacity";
+pub(crate) const PLATFORM_CONFIG_REQUEST_TIMEOUT_FNAME: &str = "requestTimeoutMillis";
+pub(crate) const PLATFORM_CONFIG_LOG_TAG_SUFFIX_FNAME: &str = "logTagSuffix";
+pub(crate) const PLATFORM_CONFIG_MAX_CONCURRENT_REQUESTS_FNAME: &str = "maxConcurrentRequests";
+pub(crate) const PLATFORM_CONFIG_IDLE_TIMEOUT_FNAME: &str = "idleTimeoutMillis";
+pub(crate) const PLATFORM_CONFIG_WEAK_PLATFORM_REF_FNAME: &str = "weakPlatformReference";
+pub(crate) const PLATFORM_CONFIG_MAX_REQUESTS_PER_CONNECTION_FNAME: &str =
+    "maxRequestsPerConnection";
+pub(crate) const PLATFORM_CONFIG_QUEUE_WHEN_BUSY_FNAME: &str = "queueWhenBusy";
+pub(crate) const PLATFORM_CONFIG_ORDERED_REQUESTS_FNAME: &str = "orderedRequests";
+pub(crate) const PLATFORM_CONFIG_FORWARD_PROGRESS_FNAME: &str = "forwardProgress";
+pub(crate) const BAD_HANDLE_EXCEPTION_CLASS: &str =
+    "com/android/server/remoteauth/jni/PlatformBadHandleException";
+pub(crate) const ILLEGAL_STATE_EXCEPTION_CLASS: &str = "java/lang/IllegalStateException";
+pub(crate) const ILLEGAL_ARGUMENT_EXCEPTION_CLASS: &str = "java/lang/IllegalArgumentException";
+pub(crate) const NATIVE_EXCEPTION_CLASS: &str =
+    "com/android/server/remoteauth/jni/RemoteAuthNativeException";
+pub(crate) const MESSAGE_TOO_LARGE_EXCEPTION_CLASS: &str =
+    "com/android/server/remoteauth/jni/MessageTooLargeException";
 pub(crate) const SEND_REQUEST_MNAME: &str = "sendRequest";
-pub(crate) const SEND_REQUEST_MSIG: &str = "(I[BII)V";
+pub(crate) const SEND_REQUEST_MSIG: &str = "(I[BJJJJ)V";
+pub(crate) const ON_PLATFORM_IDLE_CLOSED_MNAME: &str = "onPlatformIdleClosed";
+pub(crate) const ON_PLATFORM_IDLE_CLOSED_MSIG: &str = "(J)V";
+pub(crate) const ON_SEND_REQUEST_TIMEOUT_MNAME: &str = "onSendRequestTimeout";
+pub(crate) const ON_SEND_REQUEST_TIMEOUT_MSIG: &str = "(JJ)V";
+pub(crate) const CANCEL_REQUEST_MNAME: &str = "cancelRequest";
+pub(crate) const CANCEL_REQUEST_MSIG: &str = "(IJJ)V";
+pub(crate) const SEND_NOTIFICATION_MNAME: &str = "sendNotification";
+pub(crate) const SEND_NOTIFICATION_MSIG: &str = "(I[BJ)V";
+pub(crate) const GET_CONNECTION_INFO_MNAME: &str = "getConnectionInfo";
+pub(crate) const GET_CONNECTION_INFO_MSIG: &str = "(IJ)[I";
+pub(crate) const OPEN_CONNECTION_MNAME: &str = "openConnection";
+pub(crate) const OPEN_CONNECTION_MSIG: &str = "(Ljava/lang/String;JJ)V";
+pub(crate) const CLOSE_CONNECTION_MNAME: &str = "closeConnection";
+pub(crate) const CLOSE_CONNECTION_MSIG: &str = "(IJJ)V";
+pub(crate) const ON_REQUEST_PROGRESS_MNAME: &str = "onRequestProgress";
+pub(crate) const ON_REQUEST_PROGRESS_MSIG: &str = "(JJJJ)V";
+pub(crate) const SIGN_WITH_DEVICE_KEY_MNAME: &str = "signWithDeviceKey";
+pub(crate) const SIGN_WITH_DEVICE_KEY_MSIG: &str = "(Ljava/lang/String;[BJJ)V";
+pub(crate) const GET_DEVICE_PUBLIC_KEY_MNAME: &str = "getDevicePublicKey";
+pub(crate) const GET_DEVICE_PUBLIC_KEY_MSIG: &str = "(Ljava/lang/String;JJ)V";
+pub(crate) const GENERATE_KEY_PAIR_MNAME: &str = "generateKeyPair";
+pub(crate) const GENERATE_KEY_PAIR_MSIG: &str = "(Ljava/lang/String;ZJJ)V";
+pub(crate) const STORE_ENROLLMENT_MNAME: &str = "storeEnrollment";
+pub(crate) const STORE_ENROLLMENT_MSIG: &str = "(Ljava/lang/String;[BJJ)V";
+pub(crate) const DELETE_ENROLLMENT_MNAME: &str = "deleteEnrollment";
+pub(crate) const DELETE_ENROLLMENT_MSIG: &str = "(Ljava/lang/String;JJ)V";
+pub(crate) const DERIVE_BACKUP_SECRET_MNAME: &str = "deriveBackupSecret";
+pub(crate) const DERIVE_BACKUP_SECRET_MSIG: &str = "([BJJ)V";
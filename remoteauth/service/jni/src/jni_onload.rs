@@ -0,0 +1,256 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! JNI_OnLoad hook.
+//!
+//! Captures the JavaVM and resolves the Java classes and methods used by the library once, when
+//! the library is loaded, so that a missing class or a mismatched signature fails the load
+//! instead of a request in the middle of an authentication. The health checks of the RNG run
+//! then too, so that a broken one fails the load rather than drawing weak keys.
+
+use crate::jnames::{
+    BAD_HANDLE_EXCEPTION_CLASS, CANCEL_REQUEST_MNAME, CANCEL_REQUEST_MSIG, CLOSE_CONNECTION_MNAME,
+    CLOSE_CONNECTION_MSIG, DELETE_ENROLLMENT_MNAME, DELETE_ENROLLMENT_MSIG,
+    DERIVE_BACKUP_SECRET_MNAME, DERIVE_BACKUP_SECRET_MSIG, GENERATE_KEY_PAIR_MNAME,
+    GENERATE_KEY_PAIR_MSIG, GET_CONNECTION_INFO_MNAME, GET_CONNECTION_INFO_MSIG,
+    GET_DEVICE_PUBLIC_KEY_MNAME, GET_DEVICE_PUBLIC_KEY_MSIG, NATIVE_EXCEPTION_CLASS,
+    ON_PLATFORM_IDLE_CLOSED_MNAME, ON_PLATFORM_IDLE_CLOSED_MSIG, ON_REQUEST_PROGRESS_MNAME,
+    ON_REQUEST_PROGRESS_MSIG, ON_SEND_REQUEST_TIMEOUT_MNAME, ON_SEND_REQUEST_TIMEOUT_MSIG,
+    OPEN_CONNECTION_MNAME, OPEN_CONNECTION_MSIG, PLATFORM_CLASS, SEND_NOTIFICATION_MNAME,
+    SEND_NOTIFICATION_MSIG, SEND_REQUEST_MNAME, SEND_REQUEST_MSIG, SIGN_WITH_DEVICE_KEY_MNAME,
+    SIGN_WITH_DEVICE_KEY_MSIG, STORE_ENROLLMENT_MNAME, STORE_ENROLLMENT_MSIG,
+};
+use crate::rng;
+use crate::unique_jvm;
+use crate::utils::{init_logger, install_panic_hook};
+use anyhow::anyhow;
+use jni::objects::{GlobalRef, JClass, JMethodID};
+use jni::signature::TypeSignature;
+use jni::sys::{jint, JNI_ERR, JNI_VERSION_1_6};
+use jni::{JNIEnv, JavaVM};
+use log::{error, info};
+use std::ffi::c_void;
+use std::panic;
+use std::sync::OnceLock;
+
+/// Method IDs of the Java platform class.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PlatformMethods {
+    /// `sendRequest`: sends a request to the remote device.
+    pub(crate) send_request: JMethodID,
+    /// `onPlatformIdleClosed`: reports a platform closed for being idle.
+    pub(crate) on_platform_idle_closed: JMethodID,
+    /// `onSendRequestTimeout`: reports a request abandoned after timing out.
+    pub(crate) on_send_request_timeout: JMethodID,
+    /// `cancelRequest`: tells the transport to stop processing a cancelled request.
+    pub(crate) cancel_request: JMethodID,
+    /// `sendNotification`: sends a message to the remote device, expecting no response.
+    pub(crate) send_notification: JMethodID,
+    /// `getConnectionInfo`: returns the properties of a connection.
+    pub(crate) get_connection_info: JMethodID,
+    /// `openConnection`: connects to a device, completing asynchronously.
+    pub(crate) open_connection: JMethodID,
+    /// `closeConnection`: disconnects a connection, completing asynchronously.
+    pub(crate) close_connection: JMethodID,
+    /// `onRequestProgress`: forwards the progress of a large request, e.g. to the UI.
+    pub(crate) on_request_progress: JMethodID,
+    /// `signWithDeviceKey`: signs with a Keystore key, completing asynchronously.
+    pub(crate) sign_with_device_key: JMethodID,
+    /// `getDevicePublicKey`: returns the public key of a Keystore key, asynchronously.
+    pub(crate) get_device_public_key: JMethodID,
+    /// `generateKeyPair`: generates a Keystore key pair, completing asynchronously.
+    pub(crate) generate_key_pair: JMethodID,
+    /// `storeEnrollment`: persists an enrollment record, completing asynchronously.
+    pub(crate) store_enrollment: JMethodID,
+    /// `deleteEnrollment`: deletes an enrollment record, completing asynchronously.
+    pub(crate) delete_enrollment: JMethodID,
+    /// `deriveBackupSecret`: derives a secret from the user credential, asynchronously.
+    pub(crate) derive_backup_secret: JMethodID,
+}
+
+impl PlatformMethods {
+    const SIGNATURES: &'static [(&'static str, &'static str)] = &[
+        (SEND_REQUEST_MNAME, SEND_REQUEST_MSIG),
+        (ON_PLATFORM_IDLE_CLOSED_MNAME, ON_PLATFORM_IDLE_CLOSED_MSIG),
+        (ON_SEND_REQUEST_TIMEOUT_MNAME, ON_SEND_REQUEST_TIMEOUT_MSIG),
+        (CANCEL_REQUEST_MNAME, CANCEL_REQUEST_MSIG),
+        (SEND_NOTIFICATION_MNAME, SEND_NOTIFICATION_MSIG),
+        (GET_CONNECTION_INFO_MNAME, GET_CONNECTION_INFO_MSIG),
+        (OPEN_CONNECTION_MNAME, OPEN_CONNECTION_MSIG),
+        (CLOSE_CONNECTION_MNAME, CLOSE_CONNECTION_MSIG),
+        (ON_REQUEST_PROGRESS_MNAME, ON_REQUEST_PROGRESS_MSIG),
+        (SIGN_WITH_DEVICE_KEY_MNAME, SIGN_WITH_DEVICE_KEY_MSIG),
+        (GET_DEVICE_PUBLIC_KEY_MNAME, GET_DEVICE_PUBLIC_KEY_MSIG),
+        (GENERATE_KEY_PAIR_MNAME, GENERATE_KEY_PAIR_MSIG),
+        (STORE_ENROLLMENT_MNAME, STORE_ENROLLMENT_MSIG),
+        (DELETE_ENROLLMENT_MNAME, DELETE_ENROLLMENT_MSIG),
+        (DERIVE_BACKUP_SECRET_MNAME, DERIVE_BACKUP_SECRET_MSIG),
+    ];
+
+    /// Validates that all method signatures parse.
+    fn validate_signatures() -> anyhow::Result<()> {
+        for (name, sig) in Self::SIGNATURES {
+            TypeSignature::from_str(sig)
+                .map_err(|e| anyhow!("JNI: Invalid type signature of {} {}: {:?}", name, sig, e))?;
+        }
+        Ok(())
+    }
+
+    /// Looks the methods up in `platform_class`.
+    pub(crate) fn resolve(env: &JNIEnv, platform_class: JClass) -> jni::errors::Result<Self> {
+        Ok(Self {
+            send_request: env.get_method_id(
+                platform_class,
+                SEND_REQUEST_MNAME,
+                SEND_REQUEST_MSIG,
+            )?,
+            on_platform_idle_closed: env.get_method_id(
+                platform_class,
+                ON_PLATFORM_IDLE_CLOSED_MNAME,
+                ON_PLATFORM_IDLE_CLOSED_MSIG,
+            )?,
+            on_send_request_timeout: env.get_method_id(
+                platform_class,
+                ON_SEND_REQUEST_TIMEOUT_MNAME,
+                ON_SEND_REQUEST_TIMEOUT_MSIG,
+            )?,
+            cancel_request: env.get_method_id(
+                platform_class,
+                CANCEL_REQUEST_MNAME,
+                CANCEL_REQUEST_MSIG,
+            )?,
+            send_notification: env.get_method_id(
+                platform_class,
+                SEND_NOTIFICATION_MNAME,
+                SEND_NOTIFICATION_MSIG,
+            )?,
+            get_connection_info: env.get_method_id(
+                platform_class,
+                GET_CONNECTION_INFO_MNAME,
+                GET_CONNECTION_INFO_MSIG,
+            )?,
+            open_connection: env.get_method_id(
+                platform_class,
+                OPEN_CONNECTION_MNAME,
+                OPEN_CONNECTION_MSIG,
+            )?,
+            close_connection: env.get_method_id(
+                platform_class,
+                CLOSE_CONNECTION_MNAME,
+                CLOSE_CONNECTION_MSIG,
+            )?,
+            on_request_progress: env.get_method_id(
+                platform_class,
+                ON_REQUEST_PROGRESS_MNAME,
+                ON_REQUEST_PROGRESS_MSIG,
+            )?,
+            sign_with_device_key: env.get_method_id(
+                platform_class,
+                SIGN_WITH_DEVICE_KEY_MNAME,
+                SIGN_WITH_DEVICE_KEY_MSIG,
+            )?,
+            get_device_public_key: env.get_method_id(
+                platform_class,
+                GET_DEVICE_PUBLIC_KEY_MNAME,
+                GET_DEVICE_PUBLIC_KEY_MSIG,
+            )?,
+            generate_key_pair: env.get_method_id(
+                platform_class,
+                GENERATE_KEY_PAIR_MNAME,
+                GENERATE_KEY_PAIR_MSIG,
+            )?,
+            store_enrollment: env.get_method_id(
+                platform_class,
+                STORE_ENROLLMENT_MNAME,
+                STORE_ENROLLMENT_MSIG,
+            )?,
+            delete_enrollment: env.get_method_id(
+                platform_class,
+                DELETE_ENROLLMENT_MNAME,
+                DELETE_ENROLLMENT_MSIG,
+            )?,
+            derive_backup_secret: env.get_method_id(
+                platform_class,
+                DERIVE_BACKUP_SECRET_MNAME,
+                DERIVE_BACKUP_SECRET_MSIG,
+            )?,
+        })
+    }
+}
+
+/// Java classes and method IDs resolved at load time.
+pub(crate) struct JniCache {
+    /// Class of the Java platform object native requests are sent to.
+    pub(crate) platform_class: GlobalRef,
+    /// Exception thrown when Java passes an unknown platform handle.
+    pub(crate) bad_handle_exception_class: GlobalRef,
+    /// Exception thrown when the native side fails unexpectedly, e.g. panics.
+    pub(crate) native_exception_class: GlobalRef,
+    /// Methods of the Java platform class.
+    pub(crate) platform_methods: PlatformMethods,
+}
+
+static JNI_CACHE: OnceLock<JniCache> = OnceLock::new();
+
+/// Gets the cache populated by JNI_OnLoad. Returns None if the library was not loaded by a JVM.
+pub(crate) fn get_jni_cache() -> Option<&'static JniCache> {
+    JNI_CACHE.get()
+}
+
+/// Called by the JVM when the library is loaded with `System.loadLibrary`.
+#[no_mangle]
+pub extern "system" fn JNI_OnLoad(vm: *mut jni::sys::JavaVM, _reserved: *mut c_void) -> jint {
+    init_logger(log::LevelFilter::Trace);
+    install_panic_hook();
+    // There is no pending JNI call to throw into yet, so a panic simply fails the load.
+    match panic::catch_unwind(|| on_load(vm)) {
+        Ok(Ok(())) => {
+            info!("JNI_OnLoad: remoteauth native library loaded");
+            JNI_VERSION_1_6
+        }
+        Ok(Err(e)) => {
+            error!("JNI_OnLoad failed with {:?}", e);
+            JNI_ERR
+        }
+        Err(_) => {
+            error!("JNI_OnLoad panicked");
+            JNI_ERR
+        }
+    }
+}
+
+fn on_load(raw_vm: *mut jni::sys::JavaVM) -> anyhow::Result<()> {
+    // Safety: raw_vm is handed over by the JVM and stays valid for the lifetime of the process.
+    let vm = unsafe { JavaVM::from_raw(raw_vm) }?;
+    rng::check_health()?;
+    let cache = {
+        let env = vm.get_env()?;
+        // Validates that the signatures parse before looking the methods up.
+        PlatformMethods::validate_signatures()?;
+        let platform_class = env.find_class(PLATFORM_CLASS)?;
+        let platform_methods = PlatformMethods::resolve(&env, platform_class)?;
+        let bad_handle_exception_class = env.find_class(BAD_HANDLE_EXCEPTION_CLASS)?;
+        let native_exception_class = env.find_class(NATIVE_EXCEPTION_CLASS)?;
+        JniCache {
+            platform_class: env.new_global_ref(platform_class)?,
+            bad_handle_exception_class: env.new_global_ref(bad_handle_exception_class)?,
+            native_exception_class: env.new_global_ref(native_exception_class)?,
+            platform_methods,
+        }
+    };
+    unique_jvm::set_once(vm)?;
+    JNI_CACHE.set(cache).map_err(|_| anyhow!("JNI_OnLoad called more than once"))
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Thread attachment cache.
+//!
+//! Native threads calling into Java (mostly the runtime workers) are attached to the JavaVM once,
+//! as daemons, and stay attached until they exit, instead of paying for an attach/detach pair on
+//! every call.
+
+use jni::errors::Result;
+use jni::{JNIEnv, JavaVM};
+use log::debug;
+use std::cell::Cell;
+
+thread_local! {
+    static ATTACHED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns the JNIEnv of the current thread, attaching the thread as a daemon on first use.
+///
+/// The jni crate detaches the thread when it exits.
+pub(crate) fn attached_env(vm: &JavaVM) -> Result<JNIEnv<'_>> {
+    if ATTACHED.with(Cell::get) {
+        return vm.get_env();
+    }
+    // Java threads calling down into native code are already attached.
+    if let Ok(env) = vm.get_env() {
+        ATTACHED.with(|attached| attached.set(true));
+        return Ok(env);
+    }
+    let env = vm.attach_current_thread_as_daemon()?;
+    ATTACHED.with(|attached| attached.set(true));
+    debug!("attached thread {:?} to the JavaVM", std::thread::current().name());
+    Ok(env)
+}
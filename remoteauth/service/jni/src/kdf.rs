@@ -0,0 +1,353 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Key schedule: every key of RemoteAuth is derived here, with HKDF-SHA256 (RFC 5869).
+//!
+//! Each key is derived for a `KeyPurpose`, whose label starts the HKDF info, followed by the
+//! context of the key, each part prefixed with its length as a big-endian `u32`. Keys of different
+//! purposes or contexts are thus independent, even from the same secret, and the whole schedule
+//! can be audited from this module.
+//!
+//! The session secret comes from the handshake, e.g. `ecdh::handshake_secret`, and is bound to
+//! the negotiation transcript. The secure channel keys of either direction are derived from it.
+//!
+//! SHA-256, SHA-512 and HMAC-SHA256 are BoringSSL's, and HKDF is built on that HMAC.
+
+use crate::ecdh::Role;
+use crate::secret::Secret;
+use crate::transcript::{Digest, Kdf};
+use bssl_crypto::digest::Sha512;
+use bssl_crypto::hmac::HmacSha256;
+use thiserror::Error;
+
+/// Length of a SHA-256 hash, and of an HKDF pseudorandom key.
+pub const HASH_LEN: usize = 32;
+/// Longest output of HKDF-SHA256.
+pub const MAX_OUTPUT_LEN: usize = 255 * HASH_LEN;
+/// Length of an AES-256-GCM key of the secure channel.
+pub const ENCRYPTION_KEY_LEN: usize = 32;
+/// Length of an IV of the secure channel.
+pub const IV_LEN: usize = 12;
+
+/// Why a key could not be derived.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum KdfError {
+    /// More key material was asked for than HKDF-SHA256 outputs.
+    #[error("{0} bytes exceed the HKDF output limit")]
+    TooLong(usize),
+}
+
+/// What a key is for, separating the keys of different uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPurpose {
+    /// Secret of a session, bound to its negotiation transcript.
+    Session,
+    /// Secure channel encryption keys and IVs.
+    Encryption,
+    /// Message authentication keys.
+    Mac,
+    /// Session secret of a resumed session.
+    Resumption,
+    /// Keys sealing tokens and tickets.
+    Token,
+    /// Secrets of a PIN pairing, and the pairing keys it produces.
+    Pairing,
+    /// Keys sealing backups of the enrollments.
+    Backup,
+    /// Subkeys of a pairing key, one per purpose, see `derived_keys`.
+    Subkey,
+}
+
+impl KeyPurpose {
+    /// Returns the label starting the HKDF info of the keys.
+    pub fn label(self) -> &'static [u8] {
+        match self {
+            Self::Session => b"RemoteAuth session key",
+            Self::Encryption => b"RemoteAuth encryption key",
+            Self::Mac => b"RemoteAuth MAC key",
+            Self::Resumption => b"RemoteAuth resumed key",
+            Self::Token => b"RemoteAuth token key",
+            Self::Pairing => b"RemoteAuth pairing key",
+            Self::Backup => b"RemoteAuth backup key",
+            Self::Subkey => b"RemoteAuth subkey",
+        }
+    }
+}
+
+/// Incremental SHA-256.
+pub use bssl_crypto::digest::Sha256;
+
+/// Returns the SHA-256 hash of `data`.
+pub fn sha256(data: &[u8]) -> [u8; HASH_LEN] {
+    Sha256::hash(data)
+}
+
+/// Length of a SHA-512 hash.
+pub const SHA512_LEN: usize = 64;
+
+/// Returns the SHA-512 hash of `data`, e.g. for UKEY2 commitments.
+pub fn sha512(data: &[u8]) -> [u8; SHA512_LEN] {
+    Sha512::hash(data)
+}
+
+/// SHA-256, e.g. of transcripts.
+pub struct Sha256Digest;
+
+impl Digest for Sha256Digest {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        sha256(data).to_vec()
+    }
+}
+
+/// Returns the HMAC-SHA256 of the concatenation of `parts` with `key`.
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut hmac = HmacSha256::new_from_slice(key);
+    for part in parts {
+        hmac.update(part);
+    }
+    hmac.digest()
+}
+
+/// Returns the pseudorandom key extracted from `secret` with `salt`.
+pub fn hkdf_extract(salt: &[u8], secret: &[u8]) -> [u8; HASH_LEN] {
+    hmac_sha256(salt, &[secret])
+}
+
+/// Returns `len` bytes of key material expanded from the pseudorandom key `prk` with `info`.
+pub fn hkdf_expand(prk: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, KdfError> {
+    if len > MAX_OUTPUT_LEN {
+        return Err(KdfError::TooLong(len));
+    }
+    let mut output = Vec::with_capacity(len);
+    let mut block: &[u8] = &[];
+    let mut previous;
+    for counter in 1..=len.div_ceil(HASH_LEN) as u8 {
+        previous = hmac_sha256(prk, &[block, info, &[counter]]);
+        block = &previous;
+        output.extend_from_slice(&block[..HASH_LEN.min(len - output.len())]);
+    }
+    Ok(output)
+}
+
+/// HKDF-SHA256 with a fixed salt, empty by default.
+#[derive(Debug, Clone, Default)]
+pub struct Hkdf {
+    salt: Vec<u8>,
+}
+
+impl Hkdf {
+    /// Creates an HKDF with an empty salt.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extracts with `salt` instead.
+    pub fn with_salt(salt: &[u8]) -> Self {
+        Self { salt: salt.to_vec() }
+    }
+}
+
+impl Kdf for Hkdf {
+    fn derive(&self, secret: &[u8], info: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(hkdf_expand(&hkdf_extract(&self.salt, secret), info, len)?)
+    }
+}
+
+/// Returns the HKDF info of a key for `purpose`, in `context`.
+pub fn info(purpose: KeyPurpose, context: &[&[u8]]) -> Vec<u8> {
+    let mut info = purpose.label().to_vec();
+    for part in context {
+        info.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        info.extend_from_slice(part);
+    }
+    info
+}
+
+/// Derives a key of `len` bytes for `purpose` in `context` from `secret`.
+pub fn derive_key(
+    kdf: &dyn Kdf,
+    purpose: KeyPurpose,
+    secret: &[u8],
+    context: &[&[u8]],
+    len: usize,
+) -> anyhow::Result<Secret<Vec<u8>>> {
+    Ok(Secret::new(kdf.derive(secret, &info(purpose, context), len)?))
+}
+
+/// Key and IV of one direction of the secure channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectionSecret {
+    /// AES-256-GCM key.
+    pub key: Secret<[u8; ENCRYPTION_KEY_LEN]>,
+    /// IV, XORed with the counter of each payload into its nonce.
+    pub iv: Secret<[u8; IV_LEN]>,
+}
+
+/// Keys and IVs of both directions of the secure channel, from the point of view of a side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelSecrets {
+    /// Secret sealing the payloads the side sends.
+    pub outbound: DirectionSecret,
+    /// Secret opening the payloads the side receives.
+    pub inbound: DirectionSecret,
+}
+
+/// HMAC-SHA256 keys of both directions of a secure channel that only authenticates, from the
+/// point of view of a side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacSecrets {
+    /// Key authenticating the payloads the side sends.
+    pub outbound: Secret<[u8; HASH_LEN]>,
+    /// Key checking the payloads the side receives.
+    pub inbound: Secret<[u8; HASH_LEN]>,
+}
+
+/// Returns the secure channel secrets of `role` from the session secret of `session_secret`.
+pub fn channel_secrets(
+    kdf: &dyn Kdf,
+    session_secret: &[u8],
+    role: Role,
+) -> anyhow::Result<ChannelSecrets> {
+    let direction = |sender: &[u8]| -> anyhow::Result<DirectionSecret> {
+        let material = derive_key(
+            kdf,
+            KeyPurpose::Encryption,
+            session_secret,
+            &[sender],
+            ENCRYPTION_KEY_LEN + IV_LEN,
+        )?;
+        let (key, iv) = material.expose().split_at(ENCRYPTION_KEY_LEN);
+        Ok(DirectionSecret { key: Secret::new(key.try_into()?), iv: Secret::new(iv.try_into()?) })
+    };
+    let (initiator, responder) = (direction(b"initiator")?, direction(b"responder")?);
+    Ok(match role {
+        Role::Initiator => ChannelSecrets { outbound: initiator, inbound: responder },
+        Role::Responder => ChannelSecrets { outbound: responder, inbound: initiator },
+    })
+}
+
+/// Returns the secure channel MAC keys of `role` from the session secret of `session_secret`.
+pub fn mac_secrets(kdf: &dyn Kdf, session_secret: &[u8], role: Role) -> anyhow::Result<MacSecrets> {
+    let direction = |sender: &[u8]| -> anyhow::Result<Secret<[u8; HASH_LEN]>> {
+        let key = derive_key(kdf, KeyPurpose::Mac, session_secret, &[sender], HASH_LEN)?;
+        Ok(Secret::new(key.expose().as_slice().try_into()?))
+    };
+    let (initiator, responder) = (direction(b"initiator")?, direction(b"responder")?);
+    Ok(match role {
+        Role::Initiator => MacSecrets { outbound: initiator, inbound: responder },
+        Role::Responder => MacSecrets { outbound: responder, inbound: initiator },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b"").to_vec(),
+            hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(
+            sha256(b"abc").to_vec(),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        let mut hash = Sha256::new();
+        hash.update(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnop");
+        hash.update(b"nopq");
+        assert_eq!(
+            hash.digest().to_vec(),
+            hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+    }
+
+    #[test]
+    fn test_sha512() {
+        assert_eq!(
+            sha512(b"abc").to_vec(),
+            hex(concat!(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+                "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            ))
+        );
+        // Two blocks, once padded.
+        assert_eq!(
+            sha512(&[0x61; 112]).to_vec(),
+            hex(concat!(
+                "c01d080efd492776a1c43bd23dd99d0a2e626d481e16782e75d54c2503b5dc32",
+                "bd05f0f1ba33e568b88fd2d970929b719ecbb152f58f130a407c8830604b70ca"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_hmac() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]).to_vec(),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+    }
+
+    #[test]
+    fn test_hkdf() {
+        // RFC 5869, test case 1.
+        let prk = hkdf_extract(&hex("000102030405060708090a0b0c"), &[0x0b; 22]);
+        assert_eq!(
+            prk.to_vec(),
+            hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5")
+        );
+        assert_eq!(
+            hkdf_expand(&prk, &hex("f0f1f2f3f4f5f6f7f8f9"), 42).unwrap(),
+            hex(concat!(
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf",
+                "34007208d5b887185865"
+            ))
+        );
+        assert_eq!(hkdf_expand(&prk, &[], MAX_OUTPUT_LEN + 1), Err(KdfError::TooLong(8161)));
+    }
+
+    #[test]
+    fn test_domain_separation() {
+        let key = |purpose, context: &[&[u8]]| {
+            derive_key(&Hkdf::new(), purpose, b"secret", context, 32).unwrap()
+        };
+        assert_ne!(key(KeyPurpose::Encryption, &[]), key(KeyPurpose::Mac, &[]));
+        // Length prefixes keep contexts from running into each other.
+        assert_ne!(key(KeyPurpose::Token, &[b"ab", b"c"]), key(KeyPurpose::Token, &[b"a", b"bc"]));
+
+        let initiator = channel_secrets(&Hkdf::new(), b"secret", Role::Initiator).unwrap();
+        let responder = channel_secrets(&Hkdf::new(), b"secret", Role::Responder).unwrap();
+        assert_eq!(initiator.outbound, responder.inbound);
+        assert_eq!(initiator.inbound, responder.outbound);
+        assert_ne!(initiator.outbound.key, initiator.inbound.key);
+
+        let initiator = mac_secrets(&Hkdf::new(), b"secret", Role::Initiator).unwrap();
+        let responder = mac_secrets(&Hkdf::new(), b"secret", Role::Responder).unwrap();
+        assert_eq!(initiator.outbound, responder.inbound);
+        assert_ne!(initiator.outbound, initiator.inbound);
+        assert_eq!(initiator.inbound, responder.outbound);
+        assert_ne!(initiator.inbound, responder.inbound);
+    }
+}
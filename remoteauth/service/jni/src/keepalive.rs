@@ -0,0 +1,275 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Heartbeats keeping idle connections alive and detecting the ones that went away silently.
+//!
+//! Each connected connection gets a task pinging it once it has been idle for an interval.
+//! Consecutive pings without an answer are misses: after a few, the connection is reported
+//! degraded, after more, lost, so that failover and policy can react before requests fail.
+
+use crate::remoteauth_jni_android_platform::{ConnectionState, Platform};
+use crate::runtime::get_runtime;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+/// Events a subscriber may fall behind by before it starts missing the oldest ones.
+const KEEPALIVE_EVENTS_CAPACITY: usize = 16;
+
+/// When heartbeats are sent, and how many misses make a connection degraded or lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// Idle time before a heartbeat is sent, and how long it may go unanswered.
+    pub interval: Duration,
+    /// Consecutive misses after which the connection is degraded.
+    pub degraded_after: u32,
+    /// Consecutive misses after which the connection is lost.
+    pub lost_after: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(15), degraded_after: 1, lost_after: 3 }
+    }
+}
+
+/// A connection stopped answering heartbeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveEvent {
+    /// Heartbeats of the connection went unanswered, it may be going away.
+    ConnectionDegraded {
+        /// Connection id, as passed to `send_request`.
+        id: i32,
+        /// Consecutive heartbeats missed.
+        misses: u32,
+    },
+    /// Heartbeats of the connection went unanswered long enough to consider it gone. Its
+    /// heartbeats stop until it connects again.
+    ConnectionLost {
+        /// Connection id, as passed to `send_request`.
+        id: i32,
+    },
+}
+
+/// Heartbeat state of a connection.
+struct Connection {
+    last_activity: Instant,
+    misses: u32,
+    task: AbortHandle,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Counts the consecutive misses of a connection, returning the event a heartbeat causes.
+fn record_heartbeat(
+    misses: &mut u32,
+    answered: bool,
+    id: i32,
+    config: &KeepaliveConfig,
+) -> Option<KeepaliveEvent> {
+    if answered {
+        *misses = 0;
+        return None;
+    }
+    *misses = misses.saturating_add(1);
+    if *misses >= config.lost_after {
+        Some(KeepaliveEvent::ConnectionLost { id })
+    } else if *misses >= config.degraded_after {
+        Some(KeepaliveEvent::ConnectionDegraded { id, misses: *misses })
+    } else {
+        None
+    }
+}
+
+/// Sends heartbeats on the connections of a platform.
+pub struct Keepalive<T: Platform + ?Sized> {
+    platform: Arc<T>,
+    config: KeepaliveConfig,
+    connections: Mutex<HashMap<i32, Connection>>,
+    events: broadcast::Sender<KeepaliveEvent>,
+    // Starts and stops heartbeats as connections come and go.
+    watcher: Mutex<Option<AbortHandle>>,
+}
+
+impl<T: Platform + ?Sized + 'static> Keepalive<T> {
+    /// Creates heartbeats for `platform`, started on each connection once it is connected.
+    pub fn new(platform: Arc<T>, config: KeepaliveConfig) -> Arc<Self> {
+        let keepalive = Arc::new(Self {
+            platform,
+            config,
+            connections: Mutex::new(HashMap::new()),
+            events: broadcast::channel(KEEPALIVE_EVENTS_CAPACITY).0,
+            watcher: Mutex::new(None),
+        });
+        let mut connection_events = keepalive.platform.connection_events();
+        let weak = Arc::downgrade(&keepalive);
+        let watcher = get_runtime().spawn(async move {
+            loop {
+                let event = match connection_events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("keepalive missed {} connection events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let Some(keepalive) = weak.upgrade() else { return };
+                match event.state {
+                    ConnectionState::Connected => keepalive.start(event.id),
+                    ConnectionState::Disconnected => keepalive.stop(event.id),
+                    ConnectionState::Degraded => {}
+                }
+            }
+        });
+        *keepalive.watcher.lock().unwrap() = Some(watcher.abort_handle());
+        keepalive
+    }
+
+    /// Returns a receiver of the heartbeat events of the connections.
+    pub fn events(&self) -> broadcast::Receiver<KeepaliveEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends heartbeats on `connection_id` when it is idle, e.g. if it was connected before.
+    pub fn start(self: &Arc<Self>, connection_id: i32) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(&connection_id) {
+            return;
+        }
+        let task = get_runtime().spawn(Self::run(Arc::downgrade(self), connection_id));
+        connections.insert(
+            connection_id,
+            Connection { last_activity: Instant::now(), misses: 0, task: task.abort_handle() },
+        );
+    }
+
+    /// Stops the heartbeats of `connection_id`, e.g. once it is closed.
+    pub fn stop(&self, connection_id: i32) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+
+    /// Delays the next heartbeat of `connection_id`, which just exchanged a message.
+    pub fn record_activity(&self, connection_id: i32) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&connection_id) {
+            connection.last_activity = Instant::now();
+            connection.misses = 0;
+        }
+    }
+
+    /// Returns the heartbeats `connection_id` missed in a row, or None without heartbeats.
+    pub fn misses(&self, connection_id: i32) -> Option<u32> {
+        Some(self.connections.lock().unwrap().get(&connection_id)?.misses)
+    }
+
+    /// Returns when `connection_id` is due a heartbeat, or None once it is stopped.
+    fn next_heartbeat(&self, connection_id: i32) -> Option<Instant> {
+        let connections = self.connections.lock().unwrap();
+        Some(connections.get(&connection_id)?.last_activity + self.config.interval)
+    }
+
+    /// Heartbeats of `connection_id`, until stopped or lost. Holds the keepalive weakly, so that
+    /// dropping it stops them.
+    async fn run(keepalive: Weak<Self>, connection_id: i32) {
+        loop {
+            let Some(due) = keepalive.upgrade().and_then(|k| k.next_heartbeat(connection_id))
+            else {
+                return;
+            };
+            if Instant::now() < due {
+                tokio::time::sleep_until(due.into()).await;
+                continue;
+            }
+            let Some(keepalive) = keepalive.upgrade() else { return };
+            let answered = tokio::time::timeout(
+                keepalive.config.interval,
+                keepalive.platform.ping(connection_id),
+            )
+            .await
+            .is_ok_and(|result| result.is_ok());
+            let event = {
+                let mut connections = keepalive.connections.lock().unwrap();
+                let Some(connection) = connections.get_mut(&connection_id) else { return };
+                // Missed heartbeats are sent again an interval later.
+                connection.last_activity = Instant::now();
+                let event = record_heartbeat(
+                    &mut connection.misses,
+                    answered,
+                    connection_id,
+                    &keepalive.config,
+                );
+                if let Some(KeepaliveEvent::ConnectionLost { .. }) = event {
+                    // Aborts this task, which returns below anyway.
+                    connections.remove(&connection_id);
+                }
+                event
+            };
+            let Some(event) = event else { continue };
+            let lost = matches!(event, KeepaliveEvent::ConnectionLost { .. });
+            if lost {
+                info!("connection {} stopped answering heartbeats", connection_id);
+            } else {
+                warn!("connection {} missed heartbeats: {:?}", connection_id, event);
+            }
+            // Sending only fails when nobody listens.
+            let _ = keepalive.events.send(event);
+            if lost {
+                return;
+            }
+        }
+    }
+}
+
+impl<T: Platform + ?Sized> Drop for Keepalive<T> {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.watcher.get_mut().unwrap().take() {
+            watcher.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_heartbeat() {
+        let config =
+            KeepaliveConfig { interval: Duration::from_secs(1), degraded_after: 2, lost_after: 3 };
+        let mut misses = 0;
+        assert_eq!(record_heartbeat(&mut misses, false, 7, &config), None);
+        assert_eq!(
+            record_heartbeat(&mut misses, false, 7, &config),
+            Some(KeepaliveEvent::ConnectionDegraded { id: 7, misses: 2 })
+        );
+        // An answer resets the count.
+        assert_eq!(record_heartbeat(&mut misses, true, 7, &config), None);
+        assert_eq!(misses, 0);
+        for _ in 0..2 {
+            record_heartbeat(&mut misses, false, 7, &config);
+        }
+        assert_eq!(
+            record_heartbeat(&mut misses, false, 7, &config),
+            Some(KeepaliveEvent::ConnectionLost { id: 7 })
+        );
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Long-term keys of the device, kept in Android Keystore.
+//!
+//! Private keys never reach native code: Java generates them, in StrongBox when available, and
+//! signs with them on request, while the protocol deciding what to sign stays here. Each call is
+//! an upcall completed asynchronously by Java, like a request: the platform passes an operation
+//! handle that Java hands back with the result, or with an error code.
+//!
+//! Backups of the enrollments are sealed with a secret Java derives from the credential of the
+//! user instead, so that they can be restored on another device with the same credential.
+
+use crate::crypto::es256_signature;
+use crate::error::PlatformError;
+use crate::secret::Secret;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Error code of Java when the device has no key under the alias.
+pub const KEY_NOT_FOUND: i32 = 1;
+/// Error code of Java when StrongBox was required but is unavailable.
+pub const STRONGBOX_UNAVAILABLE: i32 = 2;
+
+/// Keys of the device, kept by Java.
+#[async_trait]
+pub trait Keystore: Send + Sync {
+    /// Returns the DER ECDSA signature of `data` with the private key under `alias`.
+    async fn sign_with_device_key(&self, alias: &str, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns the encoded public key under `alias`. Fails with `KeystoreFailed(KEY_NOT_FOUND)`
+    /// if there is none.
+    async fn get_device_public_key(&self, alias: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Generates a key pair under `alias`, replacing any previous one, in StrongBox if
+    /// `strongbox`, and returns its encoded public key.
+    async fn generate_key_pair(&self, alias: &str, strongbox: bool) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns a secret derived from the lock screen credential of the user and `salt`, the same
+    /// on every device of the user.
+    async fn derive_backup_secret(&self, salt: &[u8]) -> anyhow::Result<Secret<Vec<u8>>>;
+}
+
+/// Key of the device under an alias, generated on first use.
+pub struct DeviceKey {
+    keystore: Arc<dyn Keystore>,
+    alias: String,
+    strongbox: bool,
+}
+
+impl DeviceKey {
+    /// Returns the key under `alias` in `keystore`, to generate in StrongBox if `strongbox`.
+    pub fn new(keystore: Arc<dyn Keystore>, alias: impl Into<String>, strongbox: bool) -> Self {
+        Self { keystore, alias: alias.into(), strongbox }
+    }
+
+    /// Returns the alias of the key.
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    /// Returns the encoded public key, generating the key pair if the device has none yet.
+    pub async fn public_key(&self) -> anyhow::Result<Vec<u8>> {
+        match self.keystore.get_device_public_key(&self.alias).await {
+            Err(e) if e.downcast_ref() == Some(&PlatformError::KeystoreFailed(KEY_NOT_FOUND)) => {
+                self.keystore.generate_key_pair(&self.alias, self.strongbox).await
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the ES256 signature of `data` with the private key, in the COSE format `r || s`
+    /// rather than in the DER of Keystore.
+    pub async fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let der = self.keystore.sign_with_device_key(&self.alias, data).await?;
+        es256_signature(&der).ok_or_else(|| anyhow!("malformed signature of key {}", self.alias))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32c::crc32c;
+    use crate::crypto::{der_signature, P256_SCALAR_LEN};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // Not cryptography: the public key is the alias, the signature a CRC of it and the data,
+    // repeated to the length of a P-256 signature.
+    #[derive(Default)]
+    struct FakeKeystore {
+        keys: Mutex<HashMap<String, bool>>,
+    }
+
+    #[async_trait]
+    impl Keystore for FakeKeystore {
+        async fn sign_with_device_key(&self, alias: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            self.get_device_public_key(alias).await?;
+            let signature = crc32c(&[alias.as_bytes(), data]).to_be_bytes().repeat(16);
+            Ok(der_signature(&signature, P256_SCALAR_LEN).unwrap())
+        }
+
+        async fn get_device_public_key(&self, alias: &str) -> anyhow::Result<Vec<u8>> {
+            match self.keys.lock().unwrap().contains_key(alias) {
+                true => Ok(alias.as_bytes().to_vec()),
+                false => Err(PlatformError::KeystoreFailed(KEY_NOT_FOUND).into()),
+            }
+        }
+
+        async fn generate_key_pair(&self, alias: &str, strongbox: bool) -> anyhow::Result<Vec<u8>> {
+            self.keys.lock().unwrap().insert(alias.to_string(), strongbox);
+            Ok(alias.as_bytes().to_vec())
+        }
+
+        async fn derive_backup_secret(&self, salt: &[u8]) -> anyhow::Result<Secret<Vec<u8>>> {
+            Ok(Secret::new(crc32c(&[b"credential", salt]).to_be_bytes().to_vec()))
+        }
+    }
+
+    #[test]
+    fn test_device_key() {
+        let keystore = Arc::new(FakeKeystore::default());
+        let key = DeviceKey::new(keystore.clone(), "identity", true);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let error = key.sign(b"challenge").await.unwrap_err();
+            assert_eq!(error.downcast_ref(), Some(&PlatformError::KeystoreFailed(KEY_NOT_FOUND)));
+            assert_eq!(key.public_key().await.unwrap(), b"identity");
+            assert_eq!(keystore.keys.lock().unwrap().get("identity"), Some(&true));
+            assert_eq!(key.public_key().await.unwrap(), b"identity");
+            let crc = crc32c(&[b"identity", b"challenge"]).to_be_bytes();
+            assert_eq!(key.sign(b"challenge").await.unwrap(), crc.repeat(16));
+        });
+    }
+}
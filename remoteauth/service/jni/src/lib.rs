@@ -17,11 +17,118 @@
 //! This library takes the JNI calls from RemoteAuthService to the remoteauth protocol library
 //! and from protocol library to platform (Java interface)
 
+mod compression;
+mod connection_events;
+mod connection_info;
+mod connection_limiter;
+mod connection_quality;
+mod crc32c;
+mod dispatch;
 mod jnames;
+mod jni_onload;
+mod jvm_attach;
+mod nonce;
+mod platform_ref;
+mod replay_window;
+mod request_metadata;
+mod response_cache;
+mod retry_policy;
+mod runtime;
+mod supervisor;
 mod unique_jvm;
 mod utils;
 
+/// Validation of key attestation certificate chains.
+pub mod attestation;
+/// Tamper-evident audit log of the security events.
+pub mod audit;
+/// Challenge-response authentication of enrolled remote authenticators.
+pub mod authenticator;
+/// Encrypted exports of the enrollments, for backup and restore.
+pub mod backup;
+/// Capabilities of the remote device of each connection.
+pub mod capabilities;
+/// Negotiation of the cipher suite of each connection.
+pub mod cipher_suite;
+/// Wire encodings of structured messages.
+pub mod codec;
+/// Runtime-tunable native configuration.
+pub mod config;
+/// COSE signing and encryption of protected payloads.
+pub mod cose;
+/// Implementations of the crypto traits with BoringSSL.
+pub mod crypto;
+/// CTAP2 messages, for the remote device to act as a platform authenticator.
+pub mod ctap2;
+/// Delta encoding of periodic state syncs.
+pub mod delta;
+/// Per-purpose subkeys of the pairing keys, with rotation epochs.
+pub mod derived_keys;
+/// Identities of the local and remote devices.
+pub mod device_identity;
+/// X25519 key agreement of the secure channel handshake.
+pub mod ecdh;
+/// Enrollment of remote authenticators.
+pub mod enrollment;
+/// Errors raised by the native platform.
+pub mod error;
+/// Fragmentation of messages larger than a transport packet.
+pub mod framing;
+/// Typed handles shared with Java.
+pub mod handles;
+/// State machine of the handshakes run on a connection.
+pub mod handshake;
+/// Key schedule of every key, with HKDF-SHA256.
+pub mod kdf;
+/// Heartbeats detecting connections that went away.
+pub mod keepalive;
+/// Long-term keys of the device, kept in Android Keystore.
+pub mod keystore;
+/// Typed messages over the raw byte platform.
+pub mod messages;
+/// Schema versions of persisted records, and their migrations.
+pub mod migration;
+/// Logical channels over one physical connection.
+pub mod multiplexer;
+/// Noise XX handshake of the secure channel.
+pub mod noise;
+/// Length padding of secure channel payloads.
+pub mod padding;
+/// PIN pairing with SPAKE2+.
+pub mod pairing;
+/// Pinning of the identities of remote devices.
+pub mod pinning;
+/// Registry of the platforms of each remote device.
+pub mod platform_registry;
+/// Hash ratchet rotating the keys of secure channel sessions.
+pub mod ratchet;
+/// ACKs and retransmissions for lossy transports.
+pub mod reliability;
+/// Errors the remote device returns instead of a response.
+pub mod remote_error;
 /// Implementation of JNI platform functionality.
 pub mod remoteauth_jni_android_platform;
 /// Implementation of JNI protocol functionality.
 pub mod remoteauth_jni_android_protocol;
+/// Session resumption tickets.
+pub mod resumption;
+/// Health-checked random bytes of nonces, challenges and keys.
+pub mod rng;
+/// Routing of inbound messages to handlers by message type.
+pub mod router;
+/// Schemas validating inbound messages.
+pub mod schema;
+/// Secret material, wiped when dropped and compared in constant time.
+pub mod secret;
+/// Encryption of the traffic of a connection, end-to-end above the transport.
+pub mod secure_channel;
+/// Binding of the negotiation transcript into session keys.
+pub mod transcript;
+/// Resumable transfers of large payloads in chunks.
+pub mod transfer;
+/// UKEY2 handshake, for peers speaking the Nearby one.
+pub mod ukey2;
+/// Single-use tokens of authenticated devices, to unlock with.
+pub mod unlock_token;
+/// Negotiation of the protocol version of each connection.
+pub mod versioning;
@@ -0,0 +1,695 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Typed RemoteAuth messages, over the raw bytes of a platform.
+//!
+//! A message is a type and fields numbered from 1, in order. In CBOR, it is encoded canonically
+//! as a map from field numbers to fields, with the type under 0; in protobuf, as an `Envelope`.
+//! Decoding is strict: a wrong type, or a field breaking the `Schema` of the message fail the
+//! whole message, as do unknown fields before protocol version `UNKNOWN_FIELDS_VERSION`.
+
+use crate::capabilities::CapabilityCache;
+use crate::cipher_suite::CipherSuite;
+use crate::codec::cbor::{self, CborError, DecodeLimits, Value};
+use crate::codec::proto::{self, envelope_field, Envelope, EnvelopeField, ProtoError};
+use crate::codec::WireFormat;
+use crate::config;
+use crate::error::PlatformError;
+use crate::remote_error::{ErrorFrame, RemoteError};
+use crate::remoteauth_jni_android_platform::{OneshotCallback, Platform, RequestMetadata};
+use crate::schema::{Field, Rule, Schema, SchemaError, Violation};
+use crate::transcript::Transcript;
+use crate::versioning::{MIN_PROTOCOL_VERSION, UNKNOWN_FIELDS_VERSION};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+/// Field number of the message type, in CBOR.
+const TYPE_FIELD: u32 = 0;
+/// Challenge nonces.
+const NONCE_RULE: Rule = Rule::Bytes { min_len: 1, max_len: 64 };
+
+/// Why bytes could not be decoded as a message.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes are not canonical CBOR.
+    #[error("invalid CBOR: {0}")]
+    Cbor(#[from] CborError),
+    /// The bytes are not a protobuf envelope.
+    #[error("invalid protobuf: {0}")]
+    Proto(#[from] ProtoError),
+    /// A field breaks the schema of the message.
+    #[error("invalid message: {0}")]
+    Schema(#[from] SchemaError),
+    /// The bytes decode, but not to a type and numbered fields.
+    #[error("not a message")]
+    NotAMessage,
+    /// The bytes encode another type of message.
+    #[error("expected message type {expected}, got {actual}")]
+    UnexpectedType {
+        /// Type of the message being decoded.
+        expected: u8,
+        /// Type found in the bytes.
+        actual: u8,
+    },
+    /// A field of the message is missing.
+    #[error("missing field {0}")]
+    MissingField(u32),
+    /// A field of the message has the wrong type or is out of range.
+    #[error("invalid field {0}")]
+    InvalidField(u32),
+    /// Fields are left after the last field of the message.
+    #[error("{0} unknown fields")]
+    UnknownFields(usize),
+}
+
+/// Failure of a typed request.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    /// The response could not be decoded.
+    #[error("invalid response: {0}")]
+    Decode(#[from] DecodeError),
+    /// The request failed with a `ResponseCallback` error code, e.g. on the transport.
+    #[error("request failed with {0}")]
+    Failed(i32),
+    /// The remote device answered with an error frame.
+    #[error(transparent)]
+    Remote(RemoteError),
+}
+
+/// How the messages of a connection are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    /// Protocol version agreed with the remote device.
+    pub version: u32,
+    /// Wire format agreed with the remote device.
+    pub format: WireFormat,
+}
+
+impl Default for Encoding {
+    /// The encoding of a connection before negotiation.
+    fn default() -> Self {
+        Self { version: MIN_PROTOCOL_VERSION, format: WireFormat::default() }
+    }
+}
+
+/// Value of a field, whatever the wire format.
+enum FieldValue {
+    Integer(u64),
+    Bytes(Vec<u8>),
+    /// A value no message field has, e.g. a negative integer.
+    Other,
+}
+
+impl FieldValue {
+    fn from_cbor(value: Value) -> Self {
+        match value {
+            Value::Integer(value) => u64::try_from(value).map_or(Self::Other, Self::Integer),
+            Value::Bytes(value) => Self::Bytes(value),
+            _ => Self::Other,
+        }
+    }
+
+    fn into_cbor(self) -> Value {
+        match self {
+            Self::Integer(value) => Value::Integer(value.into()),
+            Self::Bytes(value) => Value::Bytes(value),
+            Self::Other => Value::Null,
+        }
+    }
+
+    /// Fails with `InvalidField` on values of a kind added to the envelope after this side.
+    fn from_proto(number: u32, value: Option<envelope_field::Value>) -> Result<Self, DecodeError> {
+        match value {
+            Some(envelope_field::Value::Integer(value)) => Ok(Self::Integer(value)),
+            Some(envelope_field::Value::Bytes(value)) => Ok(Self::Bytes(value)),
+            None => Ok(Self::Other),
+            Some(_) => Err(DecodeError::InvalidField(number)),
+        }
+    }
+
+    fn into_proto(self) -> Option<envelope_field::Value> {
+        match self {
+            Self::Integer(value) => Some(envelope_field::Value::Integer(value)),
+            Self::Bytes(value) => Some(envelope_field::Value::Bytes(value)),
+            Self::Other => None,
+        }
+    }
+}
+
+fn field_number(number: usize) -> u32 {
+    u32::try_from(number + 1).expect("too many fields")
+}
+
+/// Returns the type and numbered fields of a message.
+fn decode_fields(
+    bytes: &[u8],
+    format: WireFormat,
+) -> Result<(u64, Vec<(u32, FieldValue)>), DecodeError> {
+    let max_len = config::snapshot().max_payload_size;
+    match format {
+        WireFormat::Cbor => {
+            let limits = DecodeLimits { max_len, ..Default::default() };
+            let Value::Map(entries) = cbor::decode(bytes, limits)? else {
+                return Err(DecodeError::NotAMessage);
+            };
+            let mut fields = entries.into_iter().map(|(number, value)| {
+                let number = number.as_integer().and_then(|number| u32::try_from(number).ok());
+                Ok::<_, DecodeError>((
+                    number.ok_or(DecodeError::NotAMessage)?,
+                    FieldValue::from_cbor(value),
+                ))
+            });
+            let message_type = match fields.next().transpose()? {
+                Some((TYPE_FIELD, FieldValue::Integer(message_type))) => message_type,
+                _ => return Err(DecodeError::NotAMessage),
+            };
+            Ok((message_type, fields.collect::<Result<_, _>>()?))
+        }
+        WireFormat::Proto => {
+            let envelope = proto::decode(bytes, max_len)?;
+            let fields = envelope
+                .fields
+                .into_iter()
+                .map(|field| Ok((field.number, FieldValue::from_proto(field.number, field.value)?)))
+                .collect::<Result<_, DecodeError>>()?;
+            Ok((envelope.message_type.into(), fields))
+        }
+    }
+}
+
+/// Checks `fields` against `schema`.
+fn validate(schema: &Schema, fields: &[(u32, FieldValue)]) -> Result<(), SchemaError> {
+    schema.validate(|field| match fields.iter().find(|(number, _)| *number == field.number) {
+        None => Err(Violation::Missing),
+        Some((_, FieldValue::Integer(value))) => field.rule.check_uint(*value),
+        Some((_, FieldValue::Bytes(value))) => field.rule.check_bytes(value.len()),
+        Some((_, FieldValue::Other)) => {
+            Err(Violation::WrongType { expected: field.rule.cddl_type() })
+        }
+    })
+}
+
+/// Returns the type of the message in `bytes`, encoded with `encoding`, without decoding it as a
+/// message of that type.
+pub fn message_type(bytes: &[u8], encoding: Encoding) -> Result<u8, DecodeError> {
+    let (message_type, _) = decode_fields(bytes, encoding.format)?;
+    u8::try_from(message_type).map_err(|_| DecodeError::NotAMessage)
+}
+
+/// A message with a wire encoding.
+pub trait Message: Sized {
+    /// Type of the message, encoded before its fields.
+    const TYPE: u8;
+
+    /// Schema inbound messages of this type are validated against before `decode_fields`.
+    const SCHEMA: Option<Schema> = None;
+
+    /// Appends the fields of the message to `writer`.
+    fn encode_fields(&self, writer: &mut Writer);
+
+    /// Reads the fields of the message from `reader`.
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    /// Encodes the message with the default encoding.
+    fn encode(&self) -> Vec<u8> {
+        self.encode_as(Encoding::default())
+    }
+
+    /// Decodes a message of this type with the default encoding from `bytes`, of at most the
+    /// configured max payload size.
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_as(bytes, Encoding::default())
+    }
+
+    /// Encodes the message with `encoding`.
+    fn encode_as(&self, encoding: Encoding) -> Vec<u8> {
+        let mut writer = Writer { fields: Vec::new() };
+        self.encode_fields(&mut writer);
+        let fields = writer.fields.into_iter().enumerate();
+        match encoding.format {
+            WireFormat::Cbor => {
+                let fields = fields.map(|(number, value)| {
+                    (Value::Integer(field_number(number).into()), value.into_cbor())
+                });
+                let type_field =
+                    (Value::Integer(TYPE_FIELD.into()), Value::Integer(Self::TYPE.into()));
+                cbor::encode(&Value::Map(std::iter::once(type_field).chain(fields).collect()))
+            }
+            WireFormat::Proto => {
+                let mut envelope = Envelope::new();
+                envelope.message_type = Self::TYPE.into();
+                envelope.fields = fields
+                    .map(|(number, value)| {
+                        let mut field = EnvelopeField::new();
+                        field.number = field_number(number);
+                        field.value = value.into_proto();
+                        field
+                    })
+                    .collect();
+                proto::encode(&envelope)
+            }
+        }
+    }
+
+    /// Decodes a message of this type with `encoding` from `bytes`, of at most the configured
+    /// max payload size.
+    fn decode_as(bytes: &[u8], encoding: Encoding) -> Result<Self, DecodeError> {
+        let (actual, fields) = decode_fields(bytes, encoding.format)?;
+        let actual = u8::try_from(actual).map_err(|_| DecodeError::NotAMessage)?;
+        if actual != Self::TYPE {
+            return Err(DecodeError::UnexpectedType { expected: Self::TYPE, actual });
+        }
+        if let Some(schema) = &Self::SCHEMA {
+            validate(schema, &fields)?;
+        }
+        let mut reader = Reader { fields: fields.into_iter(), next: 1 };
+        let message = Self::decode_fields(&mut reader)?;
+        match reader.fields.len() {
+            unknown if unknown > 0 && encoding.version < UNKNOWN_FIELDS_VERSION => {
+                Err(DecodeError::UnknownFields(unknown))
+            }
+            _ => Ok(message),
+        }
+    }
+}
+
+/// A message sent as a request, answered with a message of type `Response`.
+pub trait Request: Message {
+    /// Message the remote device answers with.
+    type Response: Message;
+}
+
+/// Appends the fields of a message, numbered in order.
+pub struct Writer {
+    fields: Vec<FieldValue>,
+}
+
+impl Writer {
+    /// Appends a 32-bit integer.
+    pub fn put_u32(&mut self, value: u32) {
+        self.fields.push(FieldValue::Integer(value.into()));
+    }
+
+    /// Appends a byte string.
+    pub fn put_bytes(&mut self, value: &[u8]) {
+        self.fields.push(FieldValue::Bytes(value.to_vec()));
+    }
+}
+
+/// Reads the fields of a message, in order.
+pub struct Reader {
+    fields: std::vec::IntoIter<(u32, FieldValue)>,
+    /// Number of the next field.
+    next: u32,
+}
+
+impl Reader {
+    fn take(&mut self) -> Result<(u32, FieldValue), DecodeError> {
+        let number = self.next;
+        match self.fields.as_slice().first() {
+            Some((next, _)) if *next == number => {}
+            _ => return Err(DecodeError::MissingField(number)),
+        }
+        self.next += 1;
+        Ok(self.fields.next().unwrap())
+    }
+
+    /// Reads a 32-bit integer.
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        match self.take()? {
+            (number, FieldValue::Integer(value)) => {
+                u32::try_from(value).map_err(|_| DecodeError::InvalidField(number))
+            }
+            (number, _) => Err(DecodeError::InvalidField(number)),
+        }
+    }
+
+    /// Reads a byte string.
+    pub fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        match self.take()? {
+            (_, FieldValue::Bytes(value)) => Ok(value),
+            (number, _) => Err(DecodeError::InvalidField(number)),
+        }
+    }
+}
+
+/// Challenge the authenticator must sign to prove its presence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// Random value, fresh for each challenge.
+    pub nonce: Vec<u8>,
+}
+
+/// Signed answer to a `Challenge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeResponse {
+    /// Nonce of the challenge answered.
+    pub nonce: Vec<u8>,
+    /// Signature of the nonce by the key of the authenticator.
+    pub signature: Vec<u8>,
+}
+
+/// Public key shared with the remote device, e.g. during enrollment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySync {
+    /// Identifies the key among those of the device.
+    pub key_id: u32,
+    /// Encoded public key.
+    pub public_key: Vec<u8>,
+}
+
+/// Outcome of a request that carries no other answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// `Status::OK`, or an error code of the remote device.
+    pub code: u32,
+}
+
+impl Status {
+    /// Code of a successful request.
+    pub const OK: u32 = 0;
+
+    /// Whether the request succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.code == Self::OK
+    }
+}
+
+impl Message for Challenge {
+    const TYPE: u8 = 1;
+    const SCHEMA: Option<Schema> =
+        Some(Schema { name: "challenge", fields: &[Field::new(1, "nonce", NONCE_RULE)] });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.nonce);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { nonce: reader.bytes()? })
+    }
+}
+
+impl Request for Challenge {
+    type Response = ChallengeResponse;
+}
+
+impl Message for ChallengeResponse {
+    const TYPE: u8 = 2;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "challenge_response",
+        fields: &[
+            Field::new(1, "nonce", NONCE_RULE),
+            Field::new(2, "signature", Rule::Bytes { min_len: 1, max_len: 512 }),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.nonce);
+        writer.put_bytes(&self.signature);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { nonce: reader.bytes()?, signature: reader.bytes()? })
+    }
+}
+
+impl Message for KeySync {
+    const TYPE: u8 = 3;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "key_sync",
+        fields: &[
+            Field::new(1, "key_id", Rule::U32),
+            Field::new(2, "public_key", Rule::Bytes { min_len: 1, max_len: 1024 }),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.key_id);
+        writer.put_bytes(&self.public_key);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { key_id: reader.u32()?, public_key: reader.bytes()? })
+    }
+}
+
+impl Request for KeySync {
+    type Response = Status;
+}
+
+impl Message for Status {
+    const TYPE: u8 = 4;
+    const SCHEMA: Option<Schema> =
+        Some(Schema { name: "status", fields: &[Field::new(1, "code", Rule::U32)] });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.code);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { code: reader.u32()? })
+    }
+}
+
+/// Decodes the response of a request, or the error frame the remote device answered instead.
+pub(crate) fn decode_response<M: Message>(
+    payload: &[u8],
+    encoding: Encoding,
+) -> Result<M, MessageError> {
+    match M::decode_as(payload, encoding) {
+        Ok(response) => Ok(response),
+        Err(DecodeError::UnexpectedType { actual, .. }) if actual == ErrorFrame::TYPE => {
+            let frame = ErrorFrame::decode_as(payload, encoding)?;
+            Err(MessageError::Remote(frame.into()))
+        }
+        Err(e) => Err(MessageError::Decode(e)),
+    }
+}
+
+/// Sends typed messages over a platform, in the encoding of each connection.
+pub struct TypedPlatform<T: Platform + ?Sized> {
+    platform: Arc<T>,
+    /// Encodings other than the default, by connection id.
+    encodings: Mutex<HashMap<i32, Encoding>>,
+    capability_cache: Arc<CapabilityCache>,
+    /// Handshake exchanges, by connection id.
+    transcripts: Mutex<HashMap<i32, Transcript>>,
+    /// Negotiated cipher suites, by connection id.
+    cipher_suites: Mutex<HashMap<i32, CipherSuite>>,
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Wraps `platform`.
+    pub fn new(platform: Arc<T>) -> Self {
+        Self {
+            platform,
+            encodings: Mutex::new(HashMap::new()),
+            capability_cache: Arc::new(CapabilityCache::new()),
+            transcripts: Mutex::new(HashMap::new()),
+            cipher_suites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cache of the capabilities exchanged on each connection, e.g. to share it
+    /// with a `FramedPlatform`.
+    pub fn capability_cache(&self) -> &Arc<CapabilityCache> {
+        &self.capability_cache
+    }
+
+    /// Returns the encoding of `connection_id`, the default one until negotiated otherwise.
+    pub fn encoding(&self, connection_id: i32) -> Encoding {
+        self.encodings.lock().unwrap().get(&connection_id).copied().unwrap_or_default()
+    }
+
+    /// Returns the wire format of `connection_id`, CBOR unless negotiated otherwise.
+    pub fn wire_format(&self, connection_id: i32) -> WireFormat {
+        self.encoding(connection_id).format
+    }
+
+    /// Sets the wire format of `connection_id`, as agreed with the remote device.
+    pub fn set_wire_format(&self, connection_id: i32, format: WireFormat) {
+        self.encodings.lock().unwrap().entry(connection_id).or_default().format = format;
+    }
+
+    /// Sets the protocol version of `connection_id`, once negotiated.
+    pub(crate) fn set_protocol_version(&self, connection_id: i32, version: u32) {
+        self.encodings.lock().unwrap().entry(connection_id).or_default().version = version;
+    }
+
+    /// Returns the handshake exchanges of `connection_id` since its version negotiation.
+    pub fn transcript(&self, connection_id: i32) -> Transcript {
+        self.transcripts.lock().unwrap().get(&connection_id).cloned().unwrap_or_default()
+    }
+
+    /// Appends the exchange of `message` and `reply` to the transcript of `connection_id`.
+    pub(crate) fn record_exchange(&self, connection_id: i32, message: &[u8], reply: &[u8]) {
+        self.transcripts.lock().unwrap().entry(connection_id).or_default().record(message, reply);
+    }
+
+    /// Starts the transcript of `connection_id` over, e.g. before negotiating again.
+    pub(crate) fn reset_transcript(&self, connection_id: i32) {
+        self.transcripts.lock().unwrap().remove(&connection_id);
+    }
+
+    /// Returns the cipher suite negotiated on `connection_id`, or None before its negotiation.
+    pub fn cipher_suite(&self, connection_id: i32) -> Option<CipherSuite> {
+        self.cipher_suites.lock().unwrap().get(&connection_id).copied()
+    }
+
+    pub(crate) fn set_cipher_suite(&self, connection_id: i32, suite: CipherSuite) {
+        self.cipher_suites.lock().unwrap().insert(connection_id, suite);
+    }
+
+    /// Forgets the encoding, capabilities, cipher suite and transcript of `connection_id`, e.g.
+    /// once it is closed.
+    pub fn forget_connection(&self, connection_id: i32) {
+        self.encodings.lock().unwrap().remove(&connection_id);
+        self.capability_cache.remove(connection_id);
+        self.cipher_suites.lock().unwrap().remove(&connection_id);
+        self.reset_transcript(connection_id);
+    }
+
+    /// Returns the wrapped platform, e.g. to send raw bytes.
+    pub fn platform(&self) -> &Arc<T> {
+        &self.platform
+    }
+
+    /// Sends `request` on `connection_id` and decodes the response, in the encoding of the
+    /// connection. Fails with a `MessageError`, or the error of `send_request`.
+    pub async fn send<M: Request>(
+        &self,
+        connection_id: i32,
+        request: &M,
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<M::Response> {
+        self.send_as(connection_id, request, self.encoding(connection_id), metadata, timeout).await
+    }
+
+    /// Like `send`, in `encoding` whatever the connection negotiated.
+    pub(crate) async fn send_as<M: Request>(
+        &self,
+        connection_id: i32,
+        request: &M,
+        encoding: Encoding,
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<M::Response> {
+        let payload =
+            self.send_raw(connection_id, &request.encode_as(encoding), metadata, timeout).await?;
+        Ok(decode_response(&payload, encoding)?)
+    }
+
+    /// Sends an encoded request and returns the payload of its response. Fails with
+    /// `MessageError::Failed`, or the error of `send_request`.
+    pub(crate) async fn send_raw(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        let callback = Box::new(OneshotCallback { sender: Some(sender) });
+        self.platform.send_request(connection_id, request, metadata, timeout, callback)?;
+        match receiver.await {
+            Ok(Ok(response)) => Ok(response.payload),
+            Ok(Err(error_code)) => Err(MessageError::Failed(error_code).into()),
+            Err(_) => Err(PlatformError::PlatformDestroyed.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let challenge = Challenge { nonce: vec![1, 2, 3] };
+        assert_eq!(challenge.encode(), vec![0xa2, 0x00, 0x01, 0x01, 0x43, 1, 2, 3]);
+        assert_eq!(Challenge::decode(&challenge.encode()), Ok(challenge));
+        let response = ChallengeResponse { nonce: vec![1], signature: vec![9; 64] };
+        assert_eq!(ChallengeResponse::decode(&response.encode()), Ok(response));
+        let key_sync = KeySync { key_id: 7, public_key: vec![4; 65] };
+        assert_eq!(KeySync::decode(&key_sync.encode()), Ok(key_sync));
+        let status = Status { code: Status::OK };
+        assert_eq!(status.encode(), vec![0xa2, 0x00, 0x04, 0x01, 0x00]);
+        assert!(Status::decode(&status.encode()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_proto_round_trip() {
+        let proto = Encoding { format: WireFormat::Proto, ..Default::default() };
+        let key_sync = KeySync { key_id: 7, public_key: vec![4; 65] };
+        let bytes = key_sync.encode_as(proto);
+        assert_eq!(&bytes[..2], &[0x08, 0x03]);
+        assert_eq!(KeySync::decode_as(&bytes, proto), Ok(key_sync));
+        assert_eq!(
+            Status::decode_as(&bytes, proto),
+            Err(DecodeError::UnexpectedType { expected: 4, actual: 3 })
+        );
+        assert!(matches!(
+            KeySync::decode_as(&[0x12], proto),
+            Err(DecodeError::Proto(ProtoError::Invalid(_)))
+        ));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(Status::decode(&[]), Err(DecodeError::Cbor(CborError::Invalid(_)))));
+        assert_eq!(
+            Status::decode(&[0xa2, 0x00, 0x18, 0x04, 0x01, 0x00]),
+            Err(DecodeError::Cbor(CborError::NotCanonical))
+        );
+        assert_eq!(Status::decode(&[0x80]), Err(DecodeError::NotAMessage));
+        assert_eq!(
+            Status::decode(&Challenge { nonce: vec![] }.encode()),
+            Err(DecodeError::UnexpectedType { expected: 4, actual: 1 })
+        );
+        let nonce_error = |violation| {
+            DecodeError::Schema(SchemaError {
+                message: "challenge",
+                field: "nonce",
+                number: 1,
+                violation,
+            })
+        };
+        assert_eq!(Challenge::decode(&[0xa1, 0x00, 0x01]), Err(nonce_error(Violation::Missing)));
+        assert_eq!(
+            Challenge::decode(&[0xa2, 0x00, 0x01, 0x01, 0x00]),
+            Err(nonce_error(Violation::WrongType { expected: "bstr" }))
+        );
+        assert_eq!(
+            Challenge::decode(&Challenge { nonce: vec![0; 65] }.encode()),
+            Err(nonce_error(Violation::BadLength { len: 65, min_len: 1, max_len: 64 }))
+        );
+        assert_eq!(
+            Status::decode(&[0xa3, 0x00, 0x04, 0x01, 0x00, 0x02, 0x00]),
+            Err(DecodeError::UnknownFields(1))
+        );
+        assert_eq!(
+            Status::decode_as(
+                &[0xa3, 0x00, 0x04, 0x01, 0x00, 0x02, 0x00],
+                Encoding { version: UNKNOWN_FIELDS_VERSION, ..Default::default() }
+            ),
+            Ok(Status { code: Status::OK })
+        );
+    }
+}
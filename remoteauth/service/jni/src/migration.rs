@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Schema versions of persisted records, and their migrations.
+//!
+//! A record is stored as a CBOR array of its `schema_version` and its value. Reading a record of
+//! an older version runs the migrations from that version up to the current one before decoding
+//! it, so that records written by earlier releases, e.g. by already-enrolled devices, stay
+//! readable as their format evolves. Records written before they were versioned are version 1.
+//!
+//! Wire messages evolve with the protocol version of their connection instead, see `versioning`.
+
+use crate::codec::cbor::{self, CborError, DecodeLimits, Value};
+use thiserror::Error;
+
+/// Migrates the value of a record to the next schema version.
+pub type Migration = fn(Value) -> Result<Value, MigrationError>;
+
+/// Why a record could not be read.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum MigrationError {
+    /// The bytes are not valid CBOR.
+    #[error(transparent)]
+    Cbor(#[from] CborError),
+    /// The record was written by a newer release.
+    #[error("{name} version {version} is newer than {supported}")]
+    TooNew {
+        /// Name of the record.
+        name: &'static str,
+        /// Version of the record.
+        version: u32,
+        /// Newest version supported.
+        supported: u32,
+    },
+    /// The version is 0, or has no migration to the next one.
+    #[error("{name} version {version} is unsupported")]
+    Unsupported {
+        /// Name of the record.
+        name: &'static str,
+        /// Version of the record.
+        version: u32,
+    },
+    /// The value doesn't match its version.
+    #[error("malformed {name}: {reason}")]
+    Malformed {
+        /// Name of the record.
+        name: &'static str,
+        /// What is wrong with the value.
+        reason: &'static str,
+    },
+}
+
+/// A persisted record with a schema version.
+pub trait VersionedRecord: Sized {
+    /// Name of the record, for errors and logs.
+    const NAME: &'static str;
+    /// Version written by this release.
+    const SCHEMA_VERSION: u32;
+    /// Migrations from version 1, in order: the first migrates version 1 to 2, and so on. There
+    /// must be `SCHEMA_VERSION - 1` of them.
+    const MIGRATIONS: &'static [Migration];
+
+    /// Returns the value of the record, in the current version.
+    fn to_value(&self) -> Value;
+
+    /// Decodes a value of the current version.
+    fn from_value(value: Value) -> Result<Self, MigrationError>;
+
+    /// Encodes the record with its schema version.
+    fn to_bytes(&self) -> Vec<u8> {
+        cbor::encode(&Value::Array(vec![
+            Value::Integer(Self::SCHEMA_VERSION.into()),
+            self.to_value(),
+        ]))
+    }
+
+    /// Decodes a record of any supported version, migrating it to the current one.
+    fn from_bytes(bytes: &[u8], limits: DecodeLimits) -> Result<Self, MigrationError> {
+        let (version, value) = match cbor::decode(bytes, limits)? {
+            Value::Array(mut items)
+                if items.len() == 2 && matches!(items[0], Value::Integer(_)) =>
+            {
+                let value = items.pop().unwrap();
+                let Some(Value::Integer(version)) = items.pop() else { unreachable!() };
+                let version = u32::try_from(version)
+                    .map_err(|_| malformed::<Self>("schema version out of range"))?;
+                (version, value)
+            }
+            legacy => (1, legacy),
+        };
+        Self::from_value(migrate::<Self>(version, value)?)
+    }
+}
+
+/// Returns a `Malformed` error of `R`.
+pub fn malformed<R: VersionedRecord>(reason: &'static str) -> MigrationError {
+    MigrationError::Malformed { name: R::NAME, reason }
+}
+
+/// Migrates `value` of `version` to the current version of `R`.
+fn migrate<R: VersionedRecord>(version: u32, value: Value) -> Result<Value, MigrationError> {
+    if version > R::SCHEMA_VERSION {
+        return Err(MigrationError::TooNew {
+            name: R::NAME,
+            version,
+            supported: R::SCHEMA_VERSION,
+        });
+    }
+    let unsupported = MigrationError::Unsupported { name: R::NAME, version };
+    let first = usize::try_from(version).ok().and_then(|v| v.checked_sub(1)).ok_or(unsupported)?;
+    let last = R::SCHEMA_VERSION as usize - 1;
+    let migrations = R::MIGRATIONS
+        .get(first..last)
+        .ok_or(MigrationError::Unsupported { name: R::NAME, version })?;
+    migrations.iter().try_fold(value, |value, migration| migration(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Version 1 is a name, version 2 adds a count, version 3 makes the name a map entry.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Record {
+        name: String,
+        count: u32,
+    }
+
+    fn migrate_v1_to_v2(value: Value) -> Result<Value, MigrationError> {
+        Ok(Value::Array(vec![value, Value::Integer(0.into())]))
+    }
+
+    fn migrate_v2_to_v3(value: Value) -> Result<Value, MigrationError> {
+        let Value::Array(mut items) = value else {
+            return Err(malformed::<Record>("expected an array"));
+        };
+        let count = items.pop().ok_or(malformed::<Record>("no count"))?;
+        let name = items.pop().ok_or(malformed::<Record>("no name"))?;
+        Ok(Value::Map(vec![
+            (Value::Text(String::from("name")), name),
+            (Value::Text(String::from("count")), count),
+        ]))
+    }
+
+    impl VersionedRecord for Record {
+        const NAME: &'static str = "record";
+        const SCHEMA_VERSION: u32 = 3;
+        const MIGRATIONS: &'static [Migration] = &[migrate_v1_to_v2, migrate_v2_to_v3];
+
+        fn to_value(&self) -> Value {
+            Value::Map(vec![
+                (Value::Text(String::from("name")), Value::Text(self.name.clone())),
+                (Value::Text(String::from("count")), Value::Integer(self.count.into())),
+            ])
+        }
+
+        fn from_value(value: Value) -> Result<Self, MigrationError> {
+            match value.into_map().as_deref() {
+                Ok([(_, Value::Text(name)), (_, Value::Integer(count))]) => Ok(Self {
+                    name: name.clone(),
+                    count: u32::try_from(*count).map_err(|_| malformed::<Self>("bad count"))?,
+                }),
+                _ => Err(malformed::<Self>("expected name and count")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        let limits = DecodeLimits::default();
+        let record = Record { name: String::from("a"), count: 2 };
+        assert_eq!(Record::from_bytes(&record.to_bytes(), limits), Ok(record));
+
+        let migrated = Record { name: String::from("a"), count: 0 };
+        let legacy = cbor::encode(&Value::Text(String::from("a")));
+        assert_eq!(Record::from_bytes(&legacy, limits), Ok(migrated));
+        let v2 = Value::Array(vec![Value::Text(String::from("a")), Value::Integer(5.into())]);
+        let v2 = cbor::encode(&Value::Array(vec![Value::Integer(2.into()), v2]));
+        assert_eq!(
+            Record::from_bytes(&v2, limits),
+            Ok(Record { name: String::from("a"), count: 5 })
+        );
+
+        let v4 = cbor::encode(&Value::Array(vec![Value::Integer(4.into()), Value::Null]));
+        assert_eq!(
+            Record::from_bytes(&v4, limits),
+            Err(MigrationError::TooNew { name: "record", version: 4, supported: 3 })
+        );
+        let v0 = cbor::encode(&Value::Array(vec![Value::Integer(0.into()), Value::Null]));
+        assert_eq!(
+            Record::from_bytes(&v0, limits),
+            Err(MigrationError::Unsupported { name: "record", version: 0 })
+        );
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Logical channels multiplexed over one physical connection.
+//!
+//! Each fragment carries the id of its channel, so that independent subsystems, e.g. auth, key
+//! sync and telemetry, each open their own stream on a connection instead of sharing a single
+//! request namespace. A channel has its own window of outstanding requests and its own queue of
+//! inbound messages: a subsystem filling either doesn't hold back the others.
+
+use crate::framing::{FramedMessageStream, FramedPlatform, DEFAULT_CHANNEL};
+use crate::remoteauth_jni_android_platform::{Platform, RequestMetadata, Response};
+use crate::runtime::get_runtime;
+use log::warn;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::AbortHandle;
+
+/// Channel of the auth exchanges.
+pub const AUTH_CHANNEL: u8 = 1;
+/// Channel of key sync.
+pub const KEY_SYNC_CHANNEL: u8 = 2;
+/// Channel of telemetry.
+pub const TELEMETRY_CHANNEL: u8 = 3;
+/// Channel of CTAP2 commands, see `ctap2`.
+pub const CTAP_CHANNEL: u8 = 4;
+/// Inbound messages a channel may queue before dropping the newest ones.
+pub const CHANNEL_QUEUE_CAPACITY: usize = 16;
+
+/// Why a channel could not be opened.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum MultiplexError {
+    /// The channel is reserved for the messages sent without one.
+    #[error("channel {0} is reserved")]
+    Reserved(u8),
+    /// The channel is already open on the connection.
+    #[error("channel {channel} of connection {connection_id} is already open")]
+    InUse {
+        /// Connection of the channel.
+        connection_id: i32,
+        /// Id of the channel.
+        channel: u8,
+    },
+    /// A window must allow at least one request.
+    #[error("empty request window")]
+    EmptyWindow,
+}
+
+/// Demultiplexing state of a connection.
+struct Connection {
+    channels: HashMap<u8, mpsc::Sender<Vec<u8>>>,
+    task: AbortHandle,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Opens channels over the connections of a `FramedPlatform`.
+pub struct Multiplexer<T: Platform + ?Sized> {
+    framed: Arc<FramedPlatform<T>>,
+    connections: Mutex<HashMap<i32, Connection>>,
+}
+
+impl<T: Platform + ?Sized + 'static> Multiplexer<T> {
+    /// Multiplexes the connections of `framed`.
+    pub fn new(framed: Arc<FramedPlatform<T>>) -> Arc<Self> {
+        Arc::new(Self { framed, connections: Mutex::new(HashMap::new()) })
+    }
+
+    /// Opens `channel` on `connection_id`, allowing `window` outstanding requests on it. The
+    /// channel is closed once dropped. Messages pushed on a channel that isn't open are dropped.
+    pub fn open(
+        self: &Arc<Self>,
+        connection_id: i32,
+        channel: u8,
+        window: usize,
+    ) -> anyhow::Result<Channel<T>> {
+        if channel == DEFAULT_CHANNEL {
+            return Err(MultiplexError::Reserved(channel).into());
+        }
+        if window == 0 {
+            return Err(MultiplexError::EmptyWindow.into());
+        }
+        let mut connections = self.connections.lock().unwrap();
+        if connections.get(&connection_id).is_some_and(|c| c.channels.contains_key(&channel)) {
+            return Err(MultiplexError::InUse { connection_id, channel }.into());
+        }
+        let connection = match connections.entry(connection_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let stream = self.framed.subscribe(connection_id)?;
+                let task = get_runtime().spawn(Self::demultiplex(Arc::downgrade(self), stream));
+                entry.insert(Connection { channels: HashMap::new(), task: task.abort_handle() })
+            }
+        };
+        let (sender, inbound) = mpsc::channel(CHANNEL_QUEUE_CAPACITY);
+        connection.channels.insert(channel, sender);
+        Ok(Channel {
+            multiplexer: Arc::clone(self),
+            connection_id,
+            id: channel,
+            window: Semaphore::new(window),
+            inbound,
+        })
+    }
+
+    /// Closes all the channels of `connection_id`, e.g. once it is closed.
+    pub fn forget_connection(&self, connection_id: i32) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+
+    fn close(&self, connection_id: i32, channel: u8) {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(connection) = connections.get_mut(&connection_id) else { return };
+        connection.channels.remove(&channel);
+        if connection.channels.is_empty() {
+            connections.remove(&connection_id);
+        }
+    }
+
+    /// Hands the messages of a connection to their channel. Holds the multiplexer weakly, so
+    /// that dropping it stops demultiplexing.
+    async fn demultiplex(multiplexer: Weak<Self>, mut stream: FramedMessageStream) {
+        let connection_id = stream.connection_id();
+        while let Some((channel, message)) = stream.next_with_channel().await {
+            let Some(multiplexer) = multiplexer.upgrade() else { return };
+            let connections = multiplexer.connections.lock().unwrap();
+            let Some(sender) =
+                connections.get(&connection_id).and_then(|c| c.channels.get(&channel))
+            else {
+                warn!(
+                    "dropping message of closed channel {} of connection {}",
+                    channel, connection_id
+                );
+                continue;
+            };
+            if sender.try_send(message).is_err() {
+                warn!(
+                    "dropping message of full channel {} of connection {}",
+                    channel, connection_id
+                );
+            }
+        }
+    }
+}
+
+/// A logical stream over a connection, opened with `Multiplexer::open`.
+pub struct Channel<T: Platform + ?Sized + 'static> {
+    multiplexer: Arc<Multiplexer<T>>,
+    connection_id: i32,
+    id: u8,
+    window: Semaphore,
+    inbound: mpsc::Receiver<Vec<u8>>,
+}
+
+impl<T: Platform + ?Sized + 'static> Channel<T> {
+    /// Returns the connection of the channel.
+    pub fn connection_id(&self) -> i32 {
+        self.connection_id
+    }
+
+    /// Returns the id of the channel.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Sends `request` on the channel, once it has fewer outstanding requests than its window,
+    /// and returns the response.
+    pub async fn send_request(
+        &self,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        let _permit = self.window.acquire().await?;
+        self.multiplexer
+            .framed
+            .send_request_on_channel(self.connection_id, self.id, request, metadata, timeout)
+            .await
+    }
+
+    /// Sends `payload` on the channel, one-way.
+    pub async fn send_notification(&self, payload: &[u8]) -> anyhow::Result<()> {
+        self.multiplexer
+            .framed
+            .send_notification_on_channel(self.connection_id, self.id, payload)
+            .await
+    }
+
+    /// Waits for the next message pushed on the channel, or returns None once the connection is
+    /// forgotten.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        self.inbound.recv().await
+    }
+}
+
+impl<T: Platform + ?Sized + 'static> Drop for Channel<T> {
+    fn drop(&mut self) {
+        self.multiplexer.close(self.connection_id, self.id);
+    }
+}
@@ -0,0 +1,572 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Noise XX handshake of the secure channel, for remote devices that already implement Noise.
+//!
+//! `Noise_XX_25519_AESGCM_SHA256`, so only on connections that negotiated the cipher suite
+//! `X25519_AES_256_GCM_SHA256`, see `cipher_suite`: the native side, as initiator, sends an
+//! ephemeral key; the remote device answers with its ephemeral key and its static key,
+//! encrypted; the native side answers with its static key, encrypted. Both static keys are
+//! authenticated, and the remote one is checked against the key learned at enrollment. The
+//! handshake runs when both sides list it in their capabilities, as told by
+//! `CapabilityCache::secure_handshake`.
+//!
+//! The prologue is `PROLOGUE` followed by the hash of the transcript of the connection, see
+//! `transcript`: if a man-in-the-middle rewrote the negotiation, e.g. the cipher suite offer, the
+//! two sides hash different prologues, and the first encrypted handshake message fails to
+//! decrypt.
+//!
+//! Each handshake message is carried by a `NoiseMessage`, the last one answered by a `Status`.
+//! The split keys then seal the secure channel, with IVs of zeros so that the nonce of each
+//! payload is its counter, as in Noise transport messages.
+
+use crate::capabilities::Cipher;
+use crate::cipher_suite::{CipherSuite, KeyExchange, SuiteError, SuiteHash};
+use crate::cose::Aead;
+use crate::ecdh::{KeyPair, KEY_LEN};
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::kdf::{hkdf_expand, hkdf_extract, Sha256, Sha256Digest, HASH_LEN};
+use crate::messages::{
+    decode_response, DecodeError, Encoding, Message, Reader, Request, Status, TypedPlatform, Writer,
+};
+use crate::ratchet::RatchetKeys;
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::rng::SecureRng;
+use crate::schema::{Field, Rule, Schema};
+use crate::secret::Secret;
+use crate::secure_channel::{DirectionKey, SessionKeys, NONCE_LEN};
+use crate::transcript::TranscriptError;
+use log::{info, warn};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Name of the protocol, hashed into the handshake.
+pub const PROTOCOL_NAME: &[u8] = b"Noise_XX_25519_AESGCM_SHA256";
+/// Start of the prologue of the handshake, binding it to RemoteAuth.
+pub const PROLOGUE: &[u8] = b"RemoteAuth Noise v1";
+/// Length of an AES-GCM tag.
+pub const TAG_LEN: usize = 16;
+/// Longest handshake message.
+pub const MAX_NOISE_MESSAGE_LEN: usize = 256;
+
+/// Why a Noise handshake failed.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseError {
+    /// A handshake message is too short for its tokens.
+    #[error("malformed handshake message")]
+    Malformed,
+    /// A handshake message doesn't decrypt.
+    #[error("handshake message doesn't decrypt")]
+    Decrypt,
+    /// The static key of the remote device isn't the enrolled one.
+    #[error("unknown static key")]
+    UnknownStatic,
+    /// The remote device refused the last handshake message, with its status code.
+    #[error("remote device refused the handshake with {0}")]
+    Refused(u32),
+}
+
+/// Creates AES-256-GCM keys from key bytes, e.g. `crypto::Aes256GcmFactory`.
+pub trait AeadFactory {
+    /// Type of the keys.
+    type Key: Aead;
+
+    /// Returns the AES-256-GCM key of `key`.
+    fn key(&self, key: &[u8; KEY_LEN]) -> Self::Key;
+}
+
+/// Handshake message of Noise XX.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoiseMessage {
+    /// Message, as specified by Noise.
+    pub message: Vec<u8>,
+}
+
+impl Message for NoiseMessage {
+    const TYPE: u8 = 18;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "noise_message",
+        fields: &[Field::new(
+            1,
+            "message",
+            Rule::Bytes { min_len: 0, max_len: MAX_NOISE_MESSAGE_LEN },
+        )],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.message);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { message: reader.bytes()? })
+    }
+}
+
+impl Request for NoiseMessage {
+    type Response = NoiseMessage;
+}
+
+/// Chaining key, hash and cipher key of a handshake in progress, as specified by Noise.
+struct SymmetricState {
+    ck: Secret<[u8; HASH_LEN]>,
+    h: [u8; HASH_LEN],
+    key: Option<Secret<[u8; KEY_LEN]>>,
+    nonce: u64,
+}
+
+impl SymmetricState {
+    fn new(prologue: &[u8]) -> Self {
+        // The protocol name fits the hash, so it is the initial hash, padded with zeros.
+        let mut h = [0; HASH_LEN];
+        h[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+        let mut state = Self { ck: Secret::new(h), h, key: None, nonce: 0 };
+        state.mix_hash(prologue);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hash = Sha256::new();
+        hash.update(&self.h);
+        hash.update(data);
+        self.h = hash.digest();
+    }
+
+    /// Returns the two outputs of the Noise HKDF of the chaining key with `ikm`.
+    fn hkdf(&self, ikm: &[u8]) -> (Secret<[u8; HASH_LEN]>, Secret<[u8; HASH_LEN]>) {
+        let prk = Secret::new(hkdf_extract(self.ck.expose(), ikm));
+        let output = Secret::new(
+            hkdf_expand(prk.expose(), &[], 2 * HASH_LEN).expect("two hashes fit the HKDF output"),
+        );
+        let (first, second) = output.expose().split_at(HASH_LEN);
+        (Secret::new(first.try_into().unwrap()), Secret::new(second.try_into().unwrap()))
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, key) = self.hkdf(ikm);
+        self.ck = ck;
+        self.key = Some(key);
+        self.nonce = 0;
+    }
+
+    /// Returns the nonce of the next message: 4 zeros then the counter, big-endian.
+    fn nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.nonce.to_be_bytes());
+        nonce
+    }
+
+    fn encrypt_and_hash<F: AeadFactory>(
+        &mut self,
+        factory: &F,
+        plaintext: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let ciphertext = match &self.key {
+            Some(key) => {
+                let ciphertext =
+                    factory.key(key.expose()).seal(&self.nonce(), &self.h, plaintext)?;
+                self.nonce += 1;
+                ciphertext
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash<F: AeadFactory>(
+        &mut self,
+        factory: &F,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, NoiseError> {
+        let plaintext = match &self.key {
+            Some(key) => {
+                let plaintext = factory
+                    .key(key.expose())
+                    .open(&self.nonce(), &self.h, ciphertext)
+                    .ok_or(NoiseError::Decrypt)?;
+                self.nonce += 1;
+                plaintext
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Returns the keys of the initiator and of the responder.
+    fn split(&self) -> (Secret<[u8; KEY_LEN]>, Secret<[u8; KEY_LEN]>) {
+        self.hkdf(&[])
+    }
+}
+
+/// Outcome of a Noise handshake.
+pub struct NoiseSession {
+    /// Key of the payloads the native side sends.
+    pub outbound_key: Secret<[u8; KEY_LEN]>,
+    /// Key of the payloads the native side receives.
+    pub inbound_key: Secret<[u8; KEY_LEN]>,
+    /// Static key of the remote device.
+    pub remote_static: [u8; KEY_LEN],
+    /// Hash of the handshake, e.g. to bind a later authentication to the session.
+    pub handshake_hash: [u8; HASH_LEN],
+}
+
+impl std::fmt::Debug for NoiseSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseSession").finish_non_exhaustive()
+    }
+}
+
+impl NoiseSession {
+    /// Returns the keys of the secure channel, created by `factory`.
+    pub fn session_keys<F: AeadFactory>(&self, factory: &F) -> SessionKeys<F::Key> {
+        SessionKeys {
+            outbound: DirectionKey {
+                key: factory.key(self.outbound_key.expose()),
+                iv: Secret::new([0; NONCE_LEN]),
+            },
+            inbound: DirectionKey {
+                key: factory.key(self.inbound_key.expose()),
+                iv: Secret::new([0; NONCE_LEN]),
+            },
+        }
+    }
+
+    /// Returns the chain keys of a ratcheting secure channel.
+    pub fn ratchet_keys(&self) -> RatchetKeys {
+        RatchetKeys::new(*self.outbound_key.expose(), *self.inbound_key.expose())
+    }
+}
+
+/// Noise XX handshake, as the initiator.
+pub struct NoiseXx<F: AeadFactory> {
+    factory: F,
+    static_key: KeyPair,
+    ephemeral: KeyPair,
+    /// Enrolled static key of the remote device, if any.
+    remote_static: Option<[u8; KEY_LEN]>,
+    state: SymmetricState,
+    encoding: Encoding,
+    /// Outcome of the handshake, once the last message sent.
+    session: Option<NoiseSession>,
+}
+
+impl<F: AeadFactory> NoiseXx<F> {
+    /// Creates a handshake with `static_key`, a fresh `ephemeral` key pair, and the enrolled
+    /// `remote_static` key of the remote device if any, bound to the `transcript_hash` of the
+    /// negotiation of the connection, in `encoding`.
+    pub fn new(
+        factory: F,
+        static_key: KeyPair,
+        ephemeral: KeyPair,
+        remote_static: Option<[u8; KEY_LEN]>,
+        transcript_hash: &[u8],
+        encoding: Encoding,
+    ) -> Self {
+        Self {
+            factory,
+            static_key,
+            ephemeral,
+            remote_static,
+            state: SymmetricState::new(&[PROLOGUE, transcript_hash].concat()),
+            encoding,
+            session: None,
+        }
+    }
+
+    /// Reads `<- e, ee, s, es` and returns `-> s, se`.
+    fn read_reply(&mut self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if message.len() < 2 * KEY_LEN + 2 * TAG_LEN {
+            return Err(NoiseError::Malformed.into());
+        }
+        let (remote_ephemeral, rest) = message.split_at(KEY_LEN);
+        let remote_ephemeral: [u8; KEY_LEN] = remote_ephemeral.try_into()?;
+        self.state.mix_hash(&remote_ephemeral);
+        self.state.mix_key(self.ephemeral.agree(&remote_ephemeral)?.expose());
+        let (encrypted_static, payload) = rest.split_at(KEY_LEN + TAG_LEN);
+        let remote_static: [u8; KEY_LEN] = self
+            .state
+            .decrypt_and_hash(&self.factory, encrypted_static)?
+            .try_into()
+            .map_err(|_| NoiseError::Malformed)?;
+        self.state.mix_key(self.ephemeral.agree(&remote_static)?.expose());
+        // The payload is empty, but still authenticates the handshake so far.
+        self.state.decrypt_and_hash(&self.factory, payload)?;
+        if self.remote_static.is_some_and(|enrolled| enrolled != remote_static) {
+            return Err(NoiseError::UnknownStatic.into());
+        }
+
+        let mut reply = self.state.encrypt_and_hash(&self.factory, self.static_key.public())?;
+        self.state.mix_key(self.static_key.agree(&remote_ephemeral)?.expose());
+        reply.extend(self.state.encrypt_and_hash(&self.factory, &[])?);
+        let (outbound_key, inbound_key) = self.state.split();
+        self.session = Some(NoiseSession {
+            outbound_key,
+            inbound_key,
+            remote_static,
+            handshake_hash: self.state.h,
+        });
+        Ok(reply)
+    }
+}
+
+impl<F: AeadFactory> Handshake for NoiseXx<F> {
+    type Output = NoiseSession;
+    const NAME: &'static str = "Noise XX";
+
+    /// Returns `-> e`, with an empty payload.
+    fn first_message(&mut self) -> Vec<u8> {
+        self.state.mix_hash(self.ephemeral.public());
+        self.state.mix_hash(&[]);
+        NoiseMessage { message: self.ephemeral.public().to_vec() }.encode_as(self.encoding)
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<NoiseSession>> {
+        if let Some(session) = self.session.take() {
+            let status: Status = decode_response(reply, self.encoding)?;
+            return match status.is_ok() {
+                true => Ok(Step::Done(session)),
+                false => Err(NoiseError::Refused(status.code).into()),
+            };
+        }
+        let reply: NoiseMessage = decode_response(reply, self.encoding)?;
+        let message = self.read_reply(&reply.message)?;
+        Ok(Step::Send(NoiseMessage { message }.encode_as(self.encoding)))
+    }
+}
+
+/// Returns `suite`, negotiated on `connection_id`, if it is the one of `PROTOCOL_NAME`.
+fn noise_suite(connection_id: i32, suite: Option<CipherSuite>) -> Result<CipherSuite, SuiteError> {
+    let suite = suite.ok_or(SuiteError::NotNegotiated(connection_id))?;
+    match (suite.key_exchange, suite.hash, suite.cipher) {
+        (KeyExchange::X25519, SuiteHash::Sha256, Cipher::Aes256Gcm) => Ok(suite),
+        _ => Err(SuiteError::Unsupported(suite.id)),
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Runs the Noise XX handshake on `connection_id` as the initiator, with `static_key` and an
+    /// ephemeral key pair drawn from `rng`, bound to the transcript of its negotiation. Fails with
+    /// `UnknownStatic` if the remote device doesn't have the enrolled `remote_static` key, if
+    /// any, with `TranscriptError::Missing` if the connection didn't negotiate, and with
+    /// `SuiteError` unless it negotiated the cipher suite of the protocol.
+    pub async fn noise_handshake<F: AeadFactory>(
+        &self,
+        connection_id: i32,
+        factory: F,
+        static_key: KeyPair,
+        rng: &dyn SecureRng,
+        remote_static: Option<[u8; KEY_LEN]>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<NoiseSession> {
+        noise_suite(connection_id, self.cipher_suite(connection_id))?;
+        let transcript = self.transcript(connection_id);
+        if transcript.is_empty() {
+            return Err(TranscriptError::Missing(connection_id).into());
+        }
+        let transcript_hash = transcript.hash(&Sha256Digest);
+        let ephemeral = KeyPair::generate(rng)?;
+        let encoding = self.encoding(connection_id);
+        let handshake =
+            NoiseXx::new(factory, static_key, ephemeral, remote_static, &transcript_hash, encoding);
+        let mut machine = HandshakeMachine::new(handshake);
+        match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(session) => {
+                info!("connection {} completed the Noise handshake", connection_id);
+                Ok(session)
+            }
+            Err(e) => {
+                warn!("Noise handshake of connection {} failed: {:#}", connection_id, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher_suite::{SuiteOffer, SuiteSelect};
+    use crate::cose::Algorithm;
+    use crate::crc32c::crc32c;
+    use crate::transcript::Transcript;
+
+    // Not cryptography: XORs with the first key byte, tagged with a CRC of the whole key.
+    struct FakeKey([u8; KEY_LEN]);
+
+    impl Aead for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::A256Gcm
+        }
+
+        fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let mut sealed: Vec<u8> = plaintext.iter().map(|byte| byte ^ self.0[0]).collect();
+            let tag = crc32c(&[&self.0, iv, aad, plaintext]).to_be_bytes();
+            sealed.extend(tag.iter().cycle().take(TAG_LEN));
+            Ok(sealed)
+        }
+
+        fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (sealed, tag) = ciphertext.split_at(ciphertext.len().checked_sub(TAG_LEN)?);
+            let plaintext: Vec<u8> = sealed.iter().map(|byte| byte ^ self.0[0]).collect();
+            let expected = crc32c(&[&self.0, iv, aad, &plaintext]).to_be_bytes();
+            expected.iter().cycle().take(TAG_LEN).eq(tag).then_some(plaintext)
+        }
+    }
+
+    struct FakeFactory;
+
+    impl AeadFactory for FakeFactory {
+        type Key = FakeKey;
+
+        fn key(&self, key: &[u8; KEY_LEN]) -> FakeKey {
+            FakeKey(*key)
+        }
+    }
+
+    fn keys(byte: u8) -> KeyPair {
+        KeyPair::from_secret([byte; KEY_LEN])
+    }
+
+    /// Hash of the transcript both sides agree on, in the tests.
+    const TRANSCRIPT_HASH: &[u8] = b"transcript hash";
+
+    /// Answers `-> e` like a responder with `static_key` and `ephemeral`, that saw a negotiation
+    /// with `transcript_hash`, returning its state.
+    fn respond(
+        static_key: &KeyPair,
+        ephemeral: &KeyPair,
+        transcript_hash: &[u8],
+        first: &[u8],
+    ) -> (SymmetricState, Vec<u8>) {
+        let mut state = SymmetricState::new(&[PROLOGUE, transcript_hash].concat());
+        let remote_ephemeral: [u8; KEY_LEN] = first.try_into().unwrap();
+        state.mix_hash(&remote_ephemeral);
+        state.mix_hash(&[]);
+        state.mix_hash(ephemeral.public());
+        let mut reply = ephemeral.public().to_vec();
+        state.mix_key(ephemeral.agree(&remote_ephemeral).unwrap().expose());
+        reply.extend(state.encrypt_and_hash(&FakeFactory, static_key.public()).unwrap());
+        state.mix_key(static_key.agree(&remote_ephemeral).unwrap().expose());
+        reply.extend(state.encrypt_and_hash(&FakeFactory, &[]).unwrap());
+        (state, reply)
+    }
+
+    fn message(message: Vec<u8>) -> Vec<u8> {
+        NoiseMessage { message }.encode_as(Encoding::default())
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (responder_static, responder_ephemeral) = (keys(3), keys(4));
+        let mut initiator = NoiseXx::new(
+            FakeFactory,
+            keys(1),
+            keys(2),
+            Some(*responder_static.public()),
+            TRANSCRIPT_HASH,
+            Encoding::default(),
+        );
+        let first: NoiseMessage =
+            decode_response(&initiator.first_message(), Encoding::default()).unwrap();
+        let (mut state, reply) =
+            respond(&responder_static, &responder_ephemeral, TRANSCRIPT_HASH, &first.message);
+        let Step::Send(last) = initiator.on_reply(&message(reply)).unwrap() else {
+            panic!("the handshake has a third message");
+        };
+
+        let last: NoiseMessage = decode_response(&last, Encoding::default()).unwrap();
+        let (encrypted_static, payload) = last.message.split_at(KEY_LEN + TAG_LEN);
+        let initiator_static: [u8; KEY_LEN] =
+            state.decrypt_and_hash(&FakeFactory, encrypted_static).unwrap().try_into().unwrap();
+        assert_eq!(&initiator_static, keys(1).public());
+        state.mix_key(responder_ephemeral.agree(&initiator_static).unwrap().expose());
+        state.decrypt_and_hash(&FakeFactory, payload).unwrap();
+        let (initiator_key, responder_key) = state.split();
+
+        let status = Status { code: Status::OK }.encode_as(Encoding::default());
+        let Step::Done(session) = initiator.on_reply(&status).unwrap() else {
+            panic!("the handshake completes on the status");
+        };
+        assert_eq!(session.outbound_key, initiator_key);
+        assert_eq!(session.inbound_key, responder_key);
+        assert_ne!(session.outbound_key, session.inbound_key);
+        assert_eq!(&session.remote_static, responder_static.public());
+        assert_eq!(session.handshake_hash, state.h);
+    }
+
+    #[test]
+    fn test_unknown_static() {
+        let mut initiator = NoiseXx::new(
+            FakeFactory,
+            keys(1),
+            keys(2),
+            Some([7; KEY_LEN]),
+            TRANSCRIPT_HASH,
+            Encoding::default(),
+        );
+        let first: NoiseMessage =
+            decode_response(&initiator.first_message(), Encoding::default()).unwrap();
+        let (_, reply) = respond(&keys(3), &keys(4), TRANSCRIPT_HASH, &first.message);
+        let error = initiator.on_reply(&message(reply.clone())).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&NoiseError::UnknownStatic));
+
+        let mut initiator =
+            NoiseXx::new(FakeFactory, keys(1), keys(2), None, TRANSCRIPT_HASH, Encoding::default());
+        initiator.first_message();
+        let mut tampered = reply;
+        tampered[KEY_LEN] ^= 1;
+        let error = initiator.on_reply(&message(tampered)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&NoiseError::Decrypt));
+    }
+
+    #[test]
+    fn test_tampered_negotiation() {
+        // A man-in-the-middle rewrote the offer the native side sent, so the remote device saw
+        // another one: their transcripts differ.
+        let suite_offer = |suites: &[u8]| SuiteOffer { suites: suites.to_vec() }.encode();
+        let select = SuiteSelect { suite: 1 }.encode();
+        let mut sent = Transcript::new();
+        sent.record(&suite_offer(&[1]), &select);
+        let mut received = Transcript::new();
+        received.record(&suite_offer(&[1, 2]), &select);
+
+        let mut initiator = NoiseXx::new(
+            FakeFactory,
+            keys(1),
+            keys(2),
+            None,
+            &sent.hash(&Sha256Digest),
+            Encoding::default(),
+        );
+        let first: NoiseMessage =
+            decode_response(&initiator.first_message(), Encoding::default()).unwrap();
+        let (_, reply) = respond(&keys(3), &keys(4), &received.hash(&Sha256Digest), &first.message);
+        let error = initiator.on_reply(&message(reply)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&NoiseError::Decrypt));
+    }
+
+    #[test]
+    fn test_suite() {
+        let suite = CipherSuite::from_id(1).unwrap();
+        assert_eq!(noise_suite(7, Some(suite)), Ok(suite));
+        assert_eq!(noise_suite(7, None), Err(SuiteError::NotNegotiated(7)));
+        let p256 = CipherSuite { id: 3, key_exchange: KeyExchange::P256, ..suite };
+        assert_eq!(noise_suite(7, Some(p256)), Err(SuiteError::Unsupported(3)));
+        let chacha = CipherSuite { id: 2, cipher: Cipher::ChaCha20Poly1305, ..suite };
+        assert_eq!(noise_suite(7, Some(chacha)), Err(SuiteError::Unsupported(2)));
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Nonces of the keys of the secure channel.
+//!
+//! Each key of a direction takes its nonces from a `NonceManager`: the IV of the key XORed with
+//! a counter, which only moves forward, so that no nonce is used twice with a key. A counter is
+//! consumed even if sealing with its nonce then fails. The last `reserve` counters of a key are
+//! kept for the frames rotating it: past them, data fails with `RekeyRequired`, and past the
+//! last counter everything fails with `Exhausted`, rather than wrapping around.
+
+use crate::secret::{constant_time_eq, Secret};
+use crate::secure_channel::{COUNTER_LEN, NONCE_LEN};
+
+/// Number of counters of a key.
+const COUNTERS: u64 = 1 << (8 * COUNTER_LEN);
+
+/// Why no nonce could be handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NonceError {
+    /// Only the counters reserved to rotate the key are left.
+    RekeyRequired,
+    /// All the counters of the key were used.
+    Exhausted,
+    /// The new key of the direction came with the IV of the previous one.
+    Reused,
+}
+
+/// Returns the nonce of the payload numbered `counter` with `iv`.
+pub(crate) fn nonce(iv: &[u8; NONCE_LEN], counter: u32) -> [u8; NONCE_LEN] {
+    let mut nonce = *iv;
+    for (byte, counter_byte) in
+        nonce[NONCE_LEN - COUNTER_LEN..].iter_mut().zip(counter.to_be_bytes())
+    {
+        *byte ^= counter_byte;
+    }
+    nonce
+}
+
+/// Nonces of the current key of a direction.
+#[derive(Debug)]
+pub(crate) struct NonceManager {
+    iv: Secret<[u8; NONCE_LEN]>,
+    /// Counter of the next nonce, `COUNTERS` once exhausted.
+    next: u64,
+    reserve: u64,
+}
+
+impl NonceManager {
+    /// Starts the nonces of a key with `iv`, keeping its last `reserve` counters to rotate it.
+    pub(crate) fn new(iv: [u8; NONCE_LEN], reserve: u32) -> Self {
+        Self { iv: Secret::new(iv), next: 0, reserve: reserve.into() }
+    }
+
+    /// Returns the number of nonces handed out with the current key.
+    pub(crate) fn used(&self) -> u64 {
+        self.next
+    }
+
+    /// Whether only the reserved counters are left: the key must rotate before sealing data.
+    pub(crate) fn rekey_required(&self) -> bool {
+        self.next + self.reserve >= COUNTERS
+    }
+
+    /// Returns the counter and nonce of the next data payload.
+    pub(crate) fn next(&mut self) -> Result<(u32, [u8; NONCE_LEN]), NonceError> {
+        if self.next < COUNTERS && self.rekey_required() {
+            return Err(NonceError::RekeyRequired);
+        }
+        self.next_reserved()
+    }
+
+    /// Returns the counter and nonce of the next frame rotating the key, which may use the
+    /// reserved counters.
+    pub(crate) fn next_reserved(&mut self) -> Result<(u32, [u8; NONCE_LEN]), NonceError> {
+        let counter = u32::try_from(self.next).map_err(|_| NonceError::Exhausted)?;
+        self.next += 1;
+        Ok((counter, nonce(self.iv.expose(), counter)))
+    }
+
+    /// Restarts the counter for the next key of the direction, whose IV is `iv`. Fails with
+    /// `Reused`, leaving the current key exhausted, if `iv` is the IV of the current key.
+    pub(crate) fn rekey(&mut self, iv: [u8; NONCE_LEN]) -> Result<(), NonceError> {
+        if constant_time_eq(&iv, self.iv.expose()) {
+            self.next = COUNTERS;
+            return Err(NonceError::Reused);
+        }
+        *self = Self { iv: Secret::new(iv), next: 0, reserve: self.reserve };
+        Ok(())
+    }
+
+    /// Moves the counter to `next`, to reach the boundaries in tests.
+    #[cfg(test)]
+    pub(crate) fn skip_to(&mut self, next: u64) {
+        self.next = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonce() {
+        assert_eq!(nonce(&[0; NONCE_LEN], 0x01020304)[8..], [1, 2, 3, 4]);
+        assert_eq!(nonce(&[0xff; NONCE_LEN], 1)[..], [[0xff; 11].as_slice(), &[0xfe]].concat());
+    }
+
+    #[test]
+    fn test_next() {
+        let mut nonces = NonceManager::new([0; NONCE_LEN], 0);
+        assert_eq!(nonces.next(), Ok((0, [0; NONCE_LEN])));
+        assert_eq!(nonces.next().unwrap().0, 1);
+        assert_eq!(nonces.used(), 2);
+
+        nonces.skip_to(u64::from(u32::MAX));
+        assert!(!nonces.rekey_required());
+        assert_eq!(nonces.next().unwrap().0, u32::MAX);
+        assert!(nonces.rekey_required());
+        assert_eq!(nonces.next(), Err(NonceError::Exhausted));
+        assert_eq!(nonces.next_reserved(), Err(NonceError::Exhausted));
+        // Exhaustion sticks, rather than wrapping around.
+        assert_eq!(nonces.next(), Err(NonceError::Exhausted));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut nonces = NonceManager::new([0; NONCE_LEN], 2);
+        nonces.skip_to(COUNTERS - 3);
+        assert_eq!(nonces.next().unwrap().0, u32::MAX - 2);
+        assert!(nonces.rekey_required());
+        assert_eq!(nonces.next(), Err(NonceError::RekeyRequired));
+        assert_eq!(nonces.next_reserved().unwrap().0, u32::MAX - 1);
+        assert_eq!(nonces.next_reserved().unwrap().0, u32::MAX);
+        assert_eq!(nonces.next_reserved(), Err(NonceError::Exhausted));
+
+        nonces.rekey([1; NONCE_LEN]).unwrap();
+        assert_eq!(nonces.next(), Ok((0, [1; NONCE_LEN])));
+    }
+
+    #[test]
+    fn test_rekey_with_same_iv() {
+        let mut nonces = NonceManager::new([1; NONCE_LEN], 1);
+        nonces.next().unwrap();
+        assert_eq!(nonces.rekey([1; NONCE_LEN]), Err(NonceError::Reused));
+        assert_eq!(nonces.next(), Err(NonceError::Exhausted));
+        assert_eq!(nonces.next_reserved(), Err(NonceError::Exhausted));
+    }
+}
@@ -0,0 +1,86 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Length padding of secure channel payloads, against traffic analysis.
+//!
+//! An observer of the radio link sees the length of each message, enough to tell an unlock
+//! challenge from a heartbeat. Padding rounds each payload up to the smallest configured bucket
+//! that fits it, or to a multiple of the largest one, so that messages of a bucket look alike.
+//!
+//! A padded payload is the payload, a `0x80` marker, then zeros: the marker is always there, even
+//! without buckets, so that the receiver strips the padding whatever the sender's buckets.
+
+use thiserror::Error;
+
+/// Byte ending the payload, before the zeros of the padding.
+const MARKER: u8 = 0x80;
+
+/// The padding of a payload is malformed.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("malformed padding")]
+pub struct PaddingError;
+
+/// Returns the length `len` bytes are padded to with `buckets`, sorted ascending.
+fn padded_len(len: usize, buckets: &[usize]) -> usize {
+    match (buckets.iter().find(|bucket| **bucket >= len), buckets.last()) {
+        (Some(bucket), _) => *bucket,
+        (None, Some(largest)) => len.div_ceil(*largest) * largest,
+        (None, None) => len,
+    }
+}
+
+/// Returns `payload` padded to a bucket of `buckets`, sorted ascending without 0.
+pub fn pad(payload: &[u8], buckets: &[usize]) -> Vec<u8> {
+    let mut padded = Vec::with_capacity(padded_len(payload.len() + 1, buckets));
+    padded.extend_from_slice(payload);
+    padded.push(MARKER);
+    padded.resize(padded.capacity().max(padded.len()), 0);
+    padded
+}
+
+/// Returns the payload of `padded`, without its padding.
+pub fn unpad(padded: &[u8]) -> Result<&[u8], PaddingError> {
+    let end = padded.iter().rposition(|byte| *byte != 0).ok_or(PaddingError)?;
+    match padded[end] {
+        MARKER => Ok(&padded[..end]),
+        _ => Err(PaddingError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad() {
+        let buckets = [8, 32];
+        assert_eq!(pad(&[1, 2], &buckets), vec![1, 2, 0x80, 0, 0, 0, 0, 0]);
+        assert_eq!(pad(&[1; 7], &buckets).len(), 8);
+        assert_eq!(pad(&[1; 8], &buckets).len(), 32);
+        assert_eq!(pad(&[1; 40], &buckets).len(), 64);
+        assert_eq!(pad(&[1, 2], &[]), vec![1, 2, 0x80]);
+        for payload in [&[][..], &[0, 0], &[0x80], &[1; 40]] {
+            assert_eq!(unpad(&pad(payload, &buckets)), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn test_unpad_rejects_malformed() {
+        assert_eq!(unpad(&[]), Err(PaddingError));
+        assert_eq!(unpad(&[0, 0]), Err(PaddingError));
+        assert_eq!(unpad(&[1, 2, 0]), Err(PaddingError));
+    }
+}
@@ -0,0 +1,539 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! PIN pairing with SPAKE2+ (RFC 9383), authenticating the initial enrollment.
+//!
+//! One device displays a short PIN, and the user types it on the other. Both derive the SPAKE2+
+//! secrets `w0` and `w1` from it, then run the exchange: the native side, as prover, sends its
+//! share; the remote device, as verifier, answers with its share and a confirmation MAC; the
+//! prover checks it and answers with its own confirmation. A wrong PIN fails the confirmation,
+//! and each run gives an attacker a single guess. The shared key of the exchange then gives the
+//! long-term pairing key, stored by enrollment.
+//!
+//! The prime-order group is a `Group`: `crypto::P256` in production. Hashes, MACs and key
+//! derivation are SHA-256, HMAC-SHA256 and HKDF-SHA256 from `kdf`. As the PIN is displayed
+//! for a single pairing, `w0` and `w1` are derived from it with HKDF rather than a memory-hard
+//! function: offline guessing needs the transcript of an exchange the attacker took part in,
+//! which the confirmation already failed.
+
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::kdf::{derive_key, hkdf_expand, hkdf_extract, hmac_sha256, sha256, Hkdf, KeyPurpose};
+use crate::messages::{
+    decode_response, DecodeError, Encoding, Message, Reader, Request, Status, TypedPlatform, Writer,
+};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::schema::{Field, Rule, Schema};
+use crate::secret::{constant_time_eq, Secret};
+use log::{info, warn};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Context of the SPAKE2+ transcript, binding it to RemoteAuth pairing.
+pub const PAIRING_CONTEXT: &[u8] = b"RemoteAuth pairing v1";
+/// Length of a confirmation MAC.
+pub const CONFIRMATION_LEN: usize = 32;
+/// Longest encoded group element accepted.
+pub const MAX_ELEMENT_LEN: usize = 133;
+/// Length of the long-term pairing key.
+pub const PAIRING_KEY_LEN: usize = 32;
+
+const SHARE_RULE: Rule = Rule::Bytes { min_len: 1, max_len: MAX_ELEMENT_LEN };
+const CONFIRMATION_RULE: Rule =
+    Rule::Bytes { min_len: CONFIRMATION_LEN, max_len: CONFIRMATION_LEN };
+
+/// Why a pairing failed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum PairingError {
+    /// A share isn't a valid element of the group.
+    #[error("invalid share")]
+    InvalidShare,
+    /// A confirmation MAC didn't verify: the PINs differ, or the exchange was tampered with.
+    #[error("confirmation failed")]
+    Confirmation,
+    /// The remote device refused the confirmation, with its status code.
+    #[error("remote device refused the pairing with {0}")]
+    Refused(u32),
+}
+
+/// Prime-order group of SPAKE2+, e.g. P-256, in additive notation, over encoded scalars and
+/// elements.
+pub trait Group {
+    /// Length of the bytes reduced into a uniform scalar, e.g. 40 for P-256.
+    fn seed_len(&self) -> usize;
+
+    /// Returns the scalar of `bytes`, of `seed_len` bytes, reduced modulo the group order.
+    fn scalar(&self, bytes: &[u8]) -> Vec<u8>;
+
+    /// Returns `scalar` times the generator `P`.
+    fn base_mul(&self, scalar: &[u8]) -> Vec<u8>;
+
+    /// Returns `scalar` times `element`. Fails if `element` isn't an element of the group.
+    fn mul(&self, scalar: &[u8], element: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns `a + b`. Fails if either isn't an element of the group.
+    fn add(&self, a: &[u8], b: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns `a - b`. Fails if either isn't an element of the group.
+    fn sub(&self, a: &[u8], b: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns whether `element` is the identity.
+    fn is_identity(&self, element: &[u8]) -> bool;
+
+    /// Returns the point `M` of the ciphersuite.
+    fn m(&self) -> Vec<u8>;
+
+    /// Returns the point `N` of the ciphersuite.
+    fn n(&self) -> Vec<u8>;
+}
+
+/// First message of a pairing, from the prover.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingStart {
+    /// Share of the prover, `shareP`.
+    pub share: Vec<u8>,
+}
+
+/// Reply of the verifier to `PairingStart`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingReply {
+    /// Share of the verifier, `shareV`.
+    pub share: Vec<u8>,
+    /// Confirmation MAC of the verifier, `confirmV`.
+    pub confirmation: Vec<u8>,
+}
+
+/// Confirmation of the prover, completing a pairing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingConfirm {
+    /// Confirmation MAC of the prover, `confirmP`.
+    pub confirmation: Vec<u8>,
+}
+
+impl Message for PairingStart {
+    const TYPE: u8 = 15;
+    const SCHEMA: Option<Schema> =
+        Some(Schema { name: "pairing_start", fields: &[Field::new(1, "share", SHARE_RULE)] });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.share);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { share: reader.bytes()? })
+    }
+}
+
+impl Request for PairingStart {
+    type Response = PairingReply;
+}
+
+impl Message for PairingReply {
+    const TYPE: u8 = 16;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "pairing_reply",
+        fields: &[
+            Field::new(1, "share", SHARE_RULE),
+            Field::new(2, "confirmation", CONFIRMATION_RULE),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.share);
+        writer.put_bytes(&self.confirmation);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { share: reader.bytes()?, confirmation: reader.bytes()? })
+    }
+}
+
+impl Message for PairingConfirm {
+    const TYPE: u8 = 17;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "pairing_confirm",
+        fields: &[Field::new(1, "confirmation", CONFIRMATION_RULE)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.confirmation);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { confirmation: reader.bytes()? })
+    }
+}
+
+impl Request for PairingConfirm {
+    type Response = Status;
+}
+
+/// Long-term keys of a completed pairing, for enrollment to store.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PairingKeys {
+    /// Key authenticating the devices to each other from then on.
+    pub pairing_key: Secret<Vec<u8>>,
+}
+
+impl std::fmt::Debug for PairingKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PairingKeys").finish_non_exhaustive()
+    }
+}
+
+/// Identities of the prover and of the verifier, bound into the exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identities {
+    /// Identity of the prover, e.g. its device id.
+    pub prover: Vec<u8>,
+    /// Identity of the verifier.
+    pub verifier: Vec<u8>,
+}
+
+/// The scalars `w0` and `w1` derived from a PIN.
+type PinScalars = (Secret<Vec<u8>>, Secret<Vec<u8>>);
+
+/// Returns the scalars `w0` and `w1` of `pin`.
+fn pin_scalars(group: &dyn Group, pin: &[u8], ids: &Identities) -> anyhow::Result<PinScalars> {
+    let seed_len = group.seed_len();
+    let context: [&[u8]; 3] = [b"pin", &ids.prover, &ids.verifier];
+    let seeds = derive_key(&Hkdf::new(), KeyPurpose::Pairing, pin, &context, 2 * seed_len)?;
+    let (w0, w1) = seeds.expose().split_at(seed_len);
+    Ok((Secret::new(group.scalar(w0)), Secret::new(group.scalar(w1))))
+}
+
+/// Keys of an exchange, from its transcript.
+struct ExchangeKeys {
+    confirm_prover: Secret<Vec<u8>>,
+    confirm_verifier: Secret<Vec<u8>>,
+    shared: Secret<Vec<u8>>,
+}
+
+/// Returns the keys of the exchange of `share_p` and `share_v`, with the elements `z` and `v`.
+fn exchange_keys(
+    group: &dyn Group,
+    ids: &Identities,
+    share_p: &[u8],
+    share_v: &[u8],
+    z: &[u8],
+    v: &[u8],
+    w0: &[u8],
+) -> anyhow::Result<ExchangeKeys> {
+    let mut transcript = Vec::new();
+    let (m, n) = (group.m(), group.n());
+    let parts: [&[u8]; 10] =
+        [PAIRING_CONTEXT, &ids.prover, &ids.verifier, &m, &n, share_p, share_v, z, v, w0];
+    for part in parts {
+        transcript.extend_from_slice(&(part.len() as u64).to_le_bytes());
+        transcript.extend_from_slice(part);
+    }
+    let transcript = Secret::new(transcript);
+    let prk = Secret::new(hkdf_extract(&[], &sha256(transcript.expose())));
+    let confirmation =
+        Secret::new(hkdf_expand(prk.expose(), b"ConfirmationKeys", 2 * CONFIRMATION_LEN)?);
+    let (confirm_prover, confirm_verifier) = confirmation.expose().split_at(CONFIRMATION_LEN);
+    Ok(ExchangeKeys {
+        confirm_prover: Secret::new(confirm_prover.to_vec()),
+        confirm_verifier: Secret::new(confirm_verifier.to_vec()),
+        shared: Secret::new(hkdf_expand(prk.expose(), b"SharedKey", PAIRING_KEY_LEN)?),
+    })
+}
+
+impl ExchangeKeys {
+    fn pairing_keys(&self) -> anyhow::Result<PairingKeys> {
+        let pairing_key = derive_key(
+            &Hkdf::new(),
+            KeyPurpose::Pairing,
+            self.shared.expose(),
+            &[b"long-term"],
+            PAIRING_KEY_LEN,
+        )?;
+        Ok(PairingKeys { pairing_key })
+    }
+}
+
+/// Prover side of a pairing: the native side.
+pub struct Prover<G: Group> {
+    group: G,
+    ids: Identities,
+    w0: Secret<Vec<u8>>,
+    w1: Secret<Vec<u8>>,
+    x: Secret<Vec<u8>>,
+    share: Vec<u8>,
+}
+
+impl<G: Group> Prover<G> {
+    /// Starts a pairing with `pin`, and `seed` random bytes of `group.seed_len()`.
+    pub fn new(group: G, pin: &[u8], ids: Identities, seed: &[u8]) -> anyhow::Result<Self> {
+        let (w0, w1) = pin_scalars(&group, pin, &ids)?;
+        let x = Secret::new(group.scalar(seed));
+        // shareP = x*P + w0*M
+        let share = group.add(&group.base_mul(x.expose()), &group.mul(w0.expose(), &group.m())?)?;
+        Ok(Self { group, ids, w0, w1, x, share })
+    }
+
+    /// Returns the share to send in `PairingStart`.
+    pub fn share(&self) -> &[u8] {
+        &self.share
+    }
+
+    /// Checks the reply of the verifier, and returns the confirmation to send and the keys the
+    /// pairing produces once the verifier accepts it.
+    pub fn finish(
+        &self,
+        reply: &PairingReply,
+    ) -> Result<(PairingConfirm, PairingKeys), PairingError> {
+        let group = &self.group;
+        let invalid = |_| PairingError::InvalidShare;
+        // Removes the PIN blinding of shareV: y*P.
+        let unblinded = group
+            .sub(&reply.share, &group.mul(self.w0.expose(), &group.n()).map_err(invalid)?)
+            .map_err(invalid)?;
+        if group.is_identity(&unblinded) {
+            return Err(PairingError::InvalidShare);
+        }
+        let z = Secret::new(group.mul(self.x.expose(), &unblinded).map_err(invalid)?);
+        let v = Secret::new(group.mul(self.w1.expose(), &unblinded).map_err(invalid)?);
+        let keys = exchange_keys(
+            group,
+            &self.ids,
+            &self.share,
+            &reply.share,
+            z.expose(),
+            v.expose(),
+            self.w0.expose(),
+        )
+        .map_err(invalid)?;
+        let expected = hmac_sha256(keys.confirm_verifier.expose(), &[&self.share]);
+        if !constant_time_eq(&expected, &reply.confirmation) {
+            return Err(PairingError::Confirmation);
+        }
+        let confirmation = hmac_sha256(keys.confirm_prover.expose(), &[&reply.share]).to_vec();
+        Ok((PairingConfirm { confirmation }, keys.pairing_keys().map_err(invalid)?))
+    }
+}
+
+/// Verifier side of a pairing: the remote device, here for tests and companion apps.
+pub struct Verifier<G: Group> {
+    group: G,
+    ids: Identities,
+    w0: Secret<Vec<u8>>,
+    l: Vec<u8>,
+    y: Secret<Vec<u8>>,
+    /// Keys of the exchange, once replied.
+    keys: Option<(Vec<u8>, ExchangeKeys)>,
+}
+
+impl<G: Group> Verifier<G> {
+    /// Waits for a pairing with `pin`, and `seed` random bytes of `group.seed_len()`.
+    pub fn new(group: G, pin: &[u8], ids: Identities, seed: &[u8]) -> anyhow::Result<Self> {
+        let (w0, w1) = pin_scalars(&group, pin, &ids)?;
+        let l = group.base_mul(w1.expose());
+        let y = Secret::new(group.scalar(seed));
+        Ok(Self { group, ids, w0, l, y, keys: None })
+    }
+
+    /// Returns the reply to `start`.
+    pub fn reply(&mut self, start: &PairingStart) -> Result<PairingReply, PairingError> {
+        let group = &self.group;
+        let invalid = |_| PairingError::InvalidShare;
+        // shareV = y*P + w0*N
+        let (y, w0) = (self.y.expose(), self.w0.expose());
+        let share = group
+            .add(&group.base_mul(y), &group.mul(w0, &group.n()).map_err(invalid)?)
+            .map_err(invalid)?;
+        let unblinded =
+            group.sub(&start.share, &group.mul(w0, &group.m()).map_err(invalid)?).map_err(invalid)?;
+        if group.is_identity(&unblinded) {
+            return Err(PairingError::InvalidShare);
+        }
+        let z = Secret::new(group.mul(y, &unblinded).map_err(invalid)?);
+        let v = Secret::new(group.mul(y, &self.l).map_err(invalid)?);
+        let keys = exchange_keys(group, &self.ids, &start.share, &share, z.expose(), v.expose(), w0)
+            .map_err(invalid)?;
+        let confirmation = hmac_sha256(keys.confirm_verifier.expose(), &[&start.share]).to_vec();
+        self.keys = Some((share.clone(), keys));
+        Ok(PairingReply { share, confirmation })
+    }
+
+    /// Checks the confirmation of the prover, and returns the keys of the pairing.
+    pub fn confirm(&self, confirm: &PairingConfirm) -> Result<PairingKeys, PairingError> {
+        let (share, keys) = self.keys.as_ref().ok_or(PairingError::Confirmation)?;
+        let expected = hmac_sha256(keys.confirm_prover.expose(), &[share]);
+        if !constant_time_eq(&expected, &confirm.confirmation) {
+            return Err(PairingError::Confirmation);
+        }
+        keys.pairing_keys().map_err(|_| PairingError::Confirmation)
+    }
+}
+
+/// Handshake pairing as the prover.
+pub struct Pairing<G: Group> {
+    prover: Prover<G>,
+    encoding: Encoding,
+    /// Keys of the pairing, once the reply of the verifier checked.
+    keys: Option<PairingKeys>,
+}
+
+impl<G: Group> Pairing<G> {
+    /// Creates a pairing of `prover`, in `encoding`.
+    pub fn new(prover: Prover<G>, encoding: Encoding) -> Self {
+        Self { prover, encoding, keys: None }
+    }
+}
+
+impl<G: Group> Handshake for Pairing<G> {
+    type Output = PairingKeys;
+    const NAME: &'static str = "PIN pairing";
+
+    fn first_message(&mut self) -> Vec<u8> {
+        PairingStart { share: self.prover.share().to_vec() }.encode_as(self.encoding)
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<PairingKeys>> {
+        if let Some(keys) = self.keys.take() {
+            let status: Status = decode_response(reply, self.encoding)?;
+            return match status.is_ok() {
+                true => Ok(Step::Done(keys)),
+                false => Err(PairingError::Refused(status.code).into()),
+            };
+        }
+        let reply: PairingReply = decode_response(reply, self.encoding)?;
+        let (confirm, keys) = self.prover.finish(&reply)?;
+        self.keys = Some(keys);
+        Ok(Step::Send(confirm.encode_as(self.encoding)))
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Pairs with the remote device of `connection_id` as the prover, with the `pin` the user
+    /// typed, and returns the keys of the pairing for enrollment to store. `seed` is random
+    /// bytes of `group.seed_len()`. Fails with `Confirmation` if the PINs differ.
+    pub async fn pair<G: Group>(
+        &self,
+        connection_id: i32,
+        group: G,
+        pin: &[u8],
+        ids: Identities,
+        seed: &[u8],
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<PairingKeys> {
+        let prover = Prover::new(group, pin, ids, seed)?;
+        let mut machine = HandshakeMachine::new(Pairing::new(prover, self.encoding(connection_id)));
+        match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(keys) => {
+                info!("connection {} paired", connection_id);
+                Ok(keys)
+            }
+            Err(e) => {
+                warn!("pairing of connection {} failed: {:#}", connection_id, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDER: u64 = (1 << 61) - 1;
+
+    // Not cryptography: integers modulo a prime under addition, where discrete logs are
+    // trivial, only check that both sides of the exchange agree.
+    struct ToyGroup;
+
+    fn element(bytes: &[u8]) -> anyhow::Result<u64> {
+        let value = u64::from_be_bytes(bytes.try_into()?);
+        anyhow::ensure!(value < ORDER, "not an element");
+        Ok(value)
+    }
+
+    fn encode(value: u128) -> Vec<u8> {
+        ((value % ORDER as u128) as u64).to_be_bytes().to_vec()
+    }
+
+    impl Group for ToyGroup {
+        fn seed_len(&self) -> usize {
+            16
+        }
+
+        fn scalar(&self, bytes: &[u8]) -> Vec<u8> {
+            encode(u128::from_be_bytes(bytes.try_into().unwrap()))
+        }
+
+        fn base_mul(&self, scalar: &[u8]) -> Vec<u8> {
+            scalar.to_vec()
+        }
+
+        fn mul(&self, scalar: &[u8], element_bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(encode(element(scalar)? as u128 * element(element_bytes)? as u128))
+        }
+
+        fn add(&self, a: &[u8], b: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(encode(element(a)? as u128 + element(b)? as u128))
+        }
+
+        fn sub(&self, a: &[u8], b: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(encode(element(a)? as u128 + ORDER as u128 - element(b)? as u128))
+        }
+
+        fn is_identity(&self, element: &[u8]) -> bool {
+            element == [0; 8]
+        }
+
+        fn m(&self) -> Vec<u8> {
+            encode(0x1234_5678)
+        }
+
+        fn n(&self) -> Vec<u8> {
+            encode(0x8765_4321)
+        }
+    }
+
+    fn ids() -> Identities {
+        Identities { prover: b"phone".to_vec(), verifier: b"watch".to_vec() }
+    }
+
+    fn pair(prover_pin: &[u8], verifier_pin: &[u8]) -> Result<PairingKeys, PairingError> {
+        let prover = Prover::new(ToyGroup, prover_pin, ids(), &[1; 16]).unwrap();
+        let mut verifier = Verifier::new(ToyGroup, verifier_pin, ids(), &[2; 16]).unwrap();
+        let start = PairingStart { share: prover.share().to_vec() };
+        let reply = verifier.reply(&PairingStart::decode(&start.encode()).unwrap())?;
+        let (confirm, keys) = prover.finish(&reply)?;
+        assert_eq!(verifier.confirm(&confirm)?, keys);
+        Ok(keys)
+    }
+
+    #[test]
+    fn test_pairing() {
+        let keys = pair(b"123456", b"123456").unwrap();
+        assert_eq!(keys.pairing_key.expose().len(), PAIRING_KEY_LEN);
+        assert_eq!(pair(b"123456", b"123457"), Err(PairingError::Confirmation));
+    }
+
+    #[test]
+    fn test_tampered_reply() {
+        let prover = Prover::new(ToyGroup, b"123456", ids(), &[1; 16]).unwrap();
+        let mut verifier = Verifier::new(ToyGroup, b"123456", ids(), &[2; 16]).unwrap();
+        let mut reply = verifier.reply(&PairingStart { share: prover.share().to_vec() }).unwrap();
+        reply.confirmation[0] ^= 1;
+        assert_eq!(prover.finish(&reply).err(), Some(PairingError::Confirmation));
+        // A share of the PIN blinding alone is refused.
+        let blinding = ToyGroup.mul(prover.w0.expose(), &ToyGroup.n()).unwrap();
+        reply.share = blinding;
+        assert_eq!(prover.finish(&reply).err(), Some(PairingError::InvalidShare));
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Pinning of the identities of remote devices.
+//!
+//! An `Enroller` configured with a `PinKind` pins each device it enrolls: its record keeps the
+//! SHA-256 fingerprint of the attested identity public key, or of the leaf certificate of the
+//! attestation chain. Every secure channel established with the device afterwards checks the
+//! identity it presented in the handshake against the pin, see `SecureChannel::identify`. A
+//! device presenting another key or certificate fails with `PinMismatch`, loses the session keys
+//! of the connection, and a `SecurityEvent` is broadcast, e.g. for Java to warn the user.
+//! Enrollments without a pin are not checked.
+
+use crate::audit::{audit_log, AuditError, AuditEventKind, AuditLog};
+use crate::device_identity::DeviceId;
+use crate::enrollment::EnrollmentRegistry;
+use crate::kdf::{sha256, HASH_LEN};
+use crate::secret::constant_time_eq;
+use log::warn;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+const SECURITY_EVENTS_CAPACITY: usize = 16;
+
+/// Why the identity of a remote device was refused.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum PinningError {
+    /// The device has no enrollment.
+    #[error("device {0} is not enrolled")]
+    NotEnrolled(DeviceId),
+    /// The device presented an identity other than the pinned one.
+    #[error("identity of device {0} doesn't match its pin")]
+    PinMismatch(DeviceId),
+}
+
+/// What is pinned of the identity of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinKind {
+    /// Its identity public key, as encoded in its certificate.
+    PublicKey,
+    /// The DER of the leaf certificate of its attestation chain.
+    Certificate,
+}
+
+impl PinKind {
+    /// Returns the id of the kind in persisted records.
+    pub fn id(self) -> u8 {
+        match self {
+            Self::PublicKey => 1,
+            Self::Certificate => 2,
+        }
+    }
+
+    /// Returns the kind of `id`.
+    pub fn from_id(id: u8) -> Option<Self> {
+        [Self::PublicKey, Self::Certificate].into_iter().find(|kind| kind.id() == id)
+    }
+}
+
+/// Identity a remote device presented while establishing a secure channel.
+#[derive(Debug, Clone, Copy)]
+pub struct PresentedIdentity<'a> {
+    /// Its identity public key.
+    pub public_key: &'a [u8],
+    /// The DER of its leaf certificate, if it presented its chain.
+    pub certificate: Option<&'a [u8]>,
+}
+
+/// Fingerprint of the identity of a device, as pinned at enrollment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentityPin {
+    /// What the fingerprint is of.
+    pub kind: PinKind,
+    /// SHA-256 of the public key or of the certificate.
+    pub fingerprint: [u8; HASH_LEN],
+}
+
+impl IdentityPin {
+    /// Returns the pin of `kind` of `identity`, unless it is of the certificate and `identity`
+    /// has none.
+    pub fn new(kind: PinKind, identity: &PresentedIdentity) -> Option<Self> {
+        let pinned = match kind {
+            PinKind::PublicKey => identity.public_key,
+            PinKind::Certificate => identity.certificate?,
+        };
+        Some(Self { kind, fingerprint: sha256(pinned) })
+    }
+
+    /// Returns whether `identity` is the pinned one, in constant time.
+    pub fn matches(&self, identity: &PresentedIdentity) -> bool {
+        Self::new(self.kind, identity)
+            .is_some_and(|presented| constant_time_eq(&presented.fingerprint, &self.fingerprint))
+    }
+}
+
+/// Event the user or the platform should know about, broadcast as it happens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityEvent {
+    /// A device presented an identity other than the pinned one.
+    PinMismatch {
+        /// Device whose pin didn't match.
+        device_id: DeviceId,
+        /// Connection the identity was presented on.
+        connection_id: i32,
+        /// What was pinned.
+        kind: PinKind,
+    },
+}
+
+/// Checks the identities remote devices present against their pins.
+pub struct IdentityPinning {
+    registry: Arc<EnrollmentRegistry>,
+    events: broadcast::Sender<SecurityEvent>,
+    mismatches: AtomicU64,
+    audit: Arc<AuditLog>,
+}
+
+impl IdentityPinning {
+    /// Checks against the pins of the records of `registry`, recording mismatches in
+    /// `audit_log`.
+    pub fn new(registry: Arc<EnrollmentRegistry>) -> Result<Self, AuditError> {
+        Ok(Self {
+            registry,
+            events: broadcast::channel(SECURITY_EVENTS_CAPACITY).0,
+            mismatches: AtomicU64::new(0),
+            audit: Arc::clone(audit_log()?),
+        })
+    }
+
+    /// Records mismatches in `audit` instead.
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Returns a receiver of the security events.
+    pub fn security_events(&self) -> broadcast::Receiver<SecurityEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns the number of mismatches so far.
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Checks `identity`, presented by `device_id` while establishing the secure channel of
+    /// `connection_id`. Fails with `NotEnrolled` if the device has no enrollment, and with
+    /// `PinMismatch`, broadcasting the event, if its enrollment is pinned to another identity.
+    pub fn verify(
+        &self,
+        connection_id: i32,
+        device_id: &DeviceId,
+        identity: &PresentedIdentity,
+    ) -> Result<(), PinningError> {
+        let Some(record) = self.registry.get(device_id) else {
+            return Err(PinningError::NotEnrolled(device_id.clone()));
+        };
+        let Some(pin) = record.identity_pin else {
+            return Ok(());
+        };
+        if pin.matches(identity) {
+            return Ok(());
+        }
+        warn!(
+            "device {} presented an identity not matching its {:?} pin on connection {}",
+            device_id, pin.kind, connection_id
+        );
+        self.mismatches.fetch_add(1, Ordering::Relaxed);
+        let detail = format!("{:?} pin on connection {}", pin.kind, connection_id);
+        self.audit.record(AuditEventKind::PinMismatch, Some(device_id), &detail);
+        // Nobody may be listening.
+        let _ = self.events.send(SecurityEvent::PinMismatch {
+            device_id: device_id.clone(),
+            connection_id,
+            kind: pin.kind,
+        });
+        Err(PinningError::PinMismatch(device_id.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::SecurityLevel;
+    use crate::cose::Algorithm;
+    use crate::device_identity::{DeviceMetadata, RemoteIdentity};
+    use crate::enrollment::EnrollmentRecord;
+    use crate::secret::Secret;
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    fn record(device_id: &str, identity_pin: Option<IdentityPin>) -> EnrollmentRecord {
+        EnrollmentRecord {
+            identity: RemoteIdentity {
+                device_id: DeviceId::new(device_id).unwrap(),
+                algorithm: Algorithm::Es256,
+                public_key: vec![4; 65],
+                metadata: DeviceMetadata::default(),
+            },
+            pairing_key: Secret::new(vec![7; 32]),
+            security_level: SecurityLevel::StrongBox,
+            enrolled_at: UNIX_EPOCH,
+            key_epochs: BTreeMap::new(),
+            identity_pin,
+        }
+    }
+
+    #[test]
+    fn test_pin() {
+        let enrolled = PresentedIdentity { public_key: &[4; 65], certificate: Some(b"leaf") };
+        let other_key = PresentedIdentity { public_key: &[5; 65], ..enrolled };
+        let other_certificate = PresentedIdentity { certificate: Some(b"other"), ..enrolled };
+        let no_certificate = PresentedIdentity { certificate: None, ..enrolled };
+
+        let key_pin = IdentityPin::new(PinKind::PublicKey, &enrolled).unwrap();
+        assert_eq!(key_pin.fingerprint, sha256(&[4; 65]));
+        assert!(key_pin.matches(&enrolled));
+        assert!(key_pin.matches(&other_certificate));
+        assert!(key_pin.matches(&no_certificate));
+        assert!(!key_pin.matches(&other_key));
+
+        let certificate_pin = IdentityPin::new(PinKind::Certificate, &enrolled).unwrap();
+        assert!(certificate_pin.matches(&enrolled));
+        assert!(!certificate_pin.matches(&other_certificate));
+        assert!(!certificate_pin.matches(&no_certificate));
+        assert_eq!(IdentityPin::new(PinKind::Certificate, &no_certificate), None);
+
+        for kind in [PinKind::PublicKey, PinKind::Certificate] {
+            assert_eq!(PinKind::from_id(kind.id()), Some(kind));
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let enrolled = PresentedIdentity { public_key: &[4; 65], certificate: None };
+        let other = PresentedIdentity { public_key: &[5; 65], certificate: None };
+        let registry = Arc::new(EnrollmentRegistry::new());
+        registry.insert(record("watch", IdentityPin::new(PinKind::PublicKey, &enrolled)));
+        registry.insert(record("phone", None));
+        let audit = Arc::new(AuditLog::new().unwrap());
+        let pinning = IdentityPinning::new(registry).unwrap().with_audit_log(Arc::clone(&audit));
+        let mut events = pinning.security_events();
+        let (watch, phone) = (DeviceId::new("watch").unwrap(), DeviceId::new("phone").unwrap());
+
+        assert_eq!(pinning.verify(1, &watch, &enrolled), Ok(()));
+        assert_eq!(pinning.verify(2, &phone, &other), Ok(()));
+        assert_eq!(
+            pinning.verify(3, &DeviceId::new("tablet").unwrap(), &enrolled),
+            Err(PinningError::NotEnrolled(DeviceId::new("tablet").unwrap()))
+        );
+        assert!(events.try_recv().is_err());
+
+        assert_eq!(
+            pinning.verify(4, &watch, &other),
+            Err(PinningError::PinMismatch(watch.clone()))
+        );
+        assert_eq!(
+            events.try_recv(),
+            Ok(SecurityEvent::PinMismatch {
+                device_id: watch,
+                connection_id: 4,
+                kind: PinKind::PublicKey
+            })
+        );
+        assert_eq!(pinning.mismatches(), 1);
+        let entries = audit.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, AuditEventKind::PinMismatch);
+        assert_eq!(entries[0].detail, "PublicKey pin on connection 4");
+    }
+}
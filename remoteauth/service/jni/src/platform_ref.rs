@@ -0,0 +1,108 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reference from a JavaPlatform to its Java counterpart.
+//!
+//! A strong reference pins the Java object for as long as the native platform lives. A weak one
+//! lets the Java object be collected; it is upgraded to a local reference for each call and the
+//! call is skipped once the object is gone.
+
+use jni::errors::Error as JNIError;
+use jni::objects::{GlobalRef, JObject};
+use jni::sys::jweak;
+use jni::JNIEnv;
+
+/// Strong or weak global reference to the Java platform object.
+pub(crate) enum PlatformRef {
+    Strong(GlobalRef),
+    Weak(jweak),
+}
+
+// Safety: weak global references, like global ones, are valid on every thread.
+unsafe impl Send for PlatformRef {}
+unsafe impl Sync for PlatformRef {}
+
+impl PlatformRef {
+    /// Creates a weak reference to `obj` if `weak` is set, a strong one otherwise.
+    pub(crate) fn new(env: &JNIEnv, obj: JObject, weak: bool) -> jni::errors::Result<Self> {
+        if !weak {
+            return Ok(Self::Strong(env.new_global_ref(obj)?));
+        }
+        let raw = env.get_native_interface();
+        // Safety: raw is the JNIEnv of the current thread and obj a live reference.
+        let weak = unsafe {
+            let new_weak = (**raw)
+                .NewWeakGlobalRef
+                .ok_or(JNIError::JNIEnvMethodNotFound("NewWeakGlobalRef"))?;
+            new_weak(raw, obj.into_raw())
+        };
+        if weak.is_null() {
+            return Err(JNIError::NullPtr("NewWeakGlobalRef"));
+        }
+        Ok(Self::Weak(weak))
+    }
+
+    /// Whether the reference is weak.
+    pub(crate) fn is_weak(&self) -> bool {
+        matches!(self, Self::Weak(_))
+    }
+
+    /// Runs `f` with the Java object. Returns None without running it if the object was collected.
+    pub(crate) fn with_object<'a, R>(
+        &self,
+        env: &JNIEnv<'a>,
+        f: impl FnOnce(JObject<'a>) -> R,
+    ) -> jni::errors::Result<Option<R>> {
+        let weak = match self {
+            Self::Strong(global) => {
+                // Safety: the global reference is kept by self, which outlives the call of f.
+                let object = unsafe { JObject::from_raw(global.as_obj().into_raw()) };
+                return Ok(Some(f(object)));
+            }
+            Self::Weak(weak) => *weak,
+        };
+        let raw = env.get_native_interface();
+        // Safety: NewLocalRef returns null if the referent of weak was collected, and a new local
+        // reference keeping it alive otherwise.
+        let local = unsafe {
+            let new_local =
+                (**raw).NewLocalRef.ok_or(JNIError::JNIEnvMethodNotFound("NewLocalRef"))?;
+            new_local(raw, weak)
+        };
+        if local.is_null() {
+            return Ok(None);
+        }
+        // Safety: local is the valid local reference created above; auto_local deletes it.
+        let local = env.auto_local(unsafe { JObject::from_raw(local) });
+        Ok(Some(f(local.as_obj())))
+    }
+
+    /// Deletes the reference. `env` must be attached to the current thread.
+    pub(crate) fn release(self, env: &JNIEnv) {
+        match self {
+            Self::Strong(global) => drop(global),
+            Self::Weak(weak) => {
+                let raw = env.get_native_interface();
+                // Safety: weak was created by NewWeakGlobalRef and is not used after this point.
+                unsafe {
+                    if let Some(delete_weak) = (**raw).DeleteWeakGlobalRef {
+                        delete_weak(raw, weak);
+                    }
+                }
+            }
+        }
+    }
+}
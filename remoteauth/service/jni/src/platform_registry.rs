@@ -0,0 +1,113 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Platforms reaching each remote device, by transport.
+//!
+//! A device may be reachable over several transports at once, each through its own platform.
+//! Routing code looks them up here by device id and transport. The registry only holds weak
+//! references: a destroyed platform disappears from it without being unregistered.
+
+use crate::remoteauth_jni_android_platform::{BoxedPlatform, Platform, Transport};
+use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+static PLATFORM_REGISTRY: OnceLock<PlatformRegistry> = OnceLock::new();
+
+/// Platforms of a device by transport.
+type Transports = HashMap<Transport, Weak<dyn Platform>>;
+
+/// Platforms by device id and transport.
+#[derive(Default)]
+pub struct PlatformRegistry {
+    devices: Mutex<HashMap<String, Transports>>,
+}
+
+impl PlatformRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `platform` as the way to reach `device_id` over `transport`, replacing the
+    /// platform previously registered for them. Returns the replaced platform, if still alive.
+    pub fn register(
+        &self,
+        device_id: &str,
+        transport: Transport,
+        platform: &BoxedPlatform,
+    ) -> Option<BoxedPlatform> {
+        info!("registering {:?} platform of device {}", transport, device_id);
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default()
+            .insert(transport, Arc::downgrade(platform))
+            .and_then(|previous| previous.upgrade())
+    }
+
+    /// Unregisters the platform of `device_id` over `transport`. Returns it, if still alive.
+    pub fn unregister(&self, device_id: &str, transport: Transport) -> Option<BoxedPlatform> {
+        let mut devices = self.devices.lock().unwrap();
+        let transports = devices.get_mut(device_id)?;
+        let platform = transports.remove(&transport);
+        if transports.is_empty() {
+            devices.remove(device_id);
+        }
+        platform.and_then(|platform| platform.upgrade())
+    }
+
+    /// Returns the platform reaching `device_id` over `transport`.
+    pub fn lookup(&self, device_id: &str, transport: Transport) -> Option<BoxedPlatform> {
+        self.devices.lock().unwrap().get(device_id)?.get(&transport)?.upgrade()
+    }
+
+    /// Returns every live platform reaching `device_id`, with its transport.
+    pub fn platforms(&self, device_id: &str) -> Vec<(Transport, BoxedPlatform)> {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(transports) = devices.get_mut(device_id) else {
+            return Vec::new();
+        };
+        // Platforms destroyed since they were registered are pruned on the way.
+        transports.retain(|_, platform| platform.strong_count() > 0);
+        let platforms = transports
+            .iter()
+            .filter_map(|(transport, platform)| Some((*transport, platform.upgrade()?)))
+            .collect();
+        if transports.is_empty() {
+            devices.remove(device_id);
+        }
+        platforms
+    }
+
+    /// Returns the first live platform reaching `device_id` over one of `transports`, in order of
+    /// preference.
+    pub fn preferred(
+        &self,
+        device_id: &str,
+        transports: &[Transport],
+    ) -> Option<(Transport, BoxedPlatform)> {
+        transports
+            .iter()
+            .find_map(|transport| Some((*transport, self.lookup(device_id, *transport)?)))
+    }
+}
+
+/// Returns the registry of the platforms created through JNI.
+pub fn platform_registry() -> &'static PlatformRegistry {
+    PLATFORM_REGISTRY.get_or_init(PlatformRegistry::new)
+}
@@ -0,0 +1,172 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Hash ratchet rotating the keys of secure channel sessions.
+//!
+//! Each direction of a session has a chain key. The AES-256-GCM key and IV of its current epoch
+//! are derived from it; rotating hashes the chain key forward into the next epoch and wipes the
+//! previous one, so that a key that leaks exposes the traffic of its epoch, not of the earlier
+//! ones. The sender rotates after `rekey_messages` payloads or `rekey_interval`, whichever comes
+//! first, and announces it with a `Rekey` frame sealed with the key it leaves; the receiver
+//! rotates the inbound chain when it opens the frame.
+
+use crate::config::TunableConfig;
+use crate::kdf::{
+    derive_key, hmac_sha256, DirectionSecret, Hkdf, KeyPurpose, ENCRYPTION_KEY_LEN, HASH_LEN,
+    IV_LEN,
+};
+use crate::messages::{DecodeError, Message, Reader, Writer};
+use crate::schema::{Field, Rule, Schema};
+use crate::secret::Secret;
+use crate::secure_channel::SecureChannelError;
+use std::time::Duration;
+
+/// Label hashing a chain key into the next one.
+const CHAIN_LABEL: &[u8] = b"RemoteAuth ratchet chain";
+
+/// Announces that the sender rotated its outbound chain to `epoch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rekey {
+    /// Epoch of the payloads sealed from now on.
+    pub epoch: u32,
+}
+
+impl Message for Rekey {
+    const TYPE: u8 = 19;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "rekey",
+        fields: &[Field::new(1, "epoch", Rule::Uint { min: 1, max: u32::MAX as u64 })],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.epoch);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { epoch: reader.u32()? })
+    }
+}
+
+/// Chain key of one direction, wiped when it moves forward or is dropped.
+pub struct ChainKey {
+    secret: Secret<[u8; HASH_LEN]>,
+    epoch: u32,
+}
+
+impl ChainKey {
+    /// Starts a chain at epoch 0 from `secret`, e.g. a key agreed by a handshake.
+    pub fn new(secret: [u8; HASH_LEN]) -> Self {
+        Self { secret: Secret::new(secret), epoch: 0 }
+    }
+
+    /// Returns the epoch of the chain.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns the key and IV of the current epoch.
+    pub fn direction_secret(&self) -> anyhow::Result<DirectionSecret> {
+        let material = derive_key(
+            &Hkdf::new(),
+            KeyPurpose::Encryption,
+            self.secret.expose(),
+            &[b"ratchet", &self.epoch.to_be_bytes()],
+            ENCRYPTION_KEY_LEN + IV_LEN,
+        )?;
+        let (key, iv) = material.expose().split_at(ENCRYPTION_KEY_LEN);
+        Ok(DirectionSecret { key: Secret::new(key.try_into()?), iv: Secret::new(iv.try_into()?) })
+    }
+
+    /// Moves the chain to the next epoch, overwriting the current chain key.
+    pub fn advance(&mut self) -> Result<(), SecureChannelError> {
+        self.epoch = self.epoch.checked_add(1).ok_or(SecureChannelError::Exhausted)?;
+        // Assigning drops, and so wipes, the chain key of the previous epoch.
+        self.secret = Secret::new(hmac_sha256(self.secret.expose(), &[CHAIN_LABEL]));
+        Ok(())
+    }
+}
+
+/// Chain keys of both directions of a session.
+pub struct RatchetKeys {
+    /// Chain of the payloads sent.
+    pub outbound: ChainKey,
+    /// Chain of the payloads received.
+    pub inbound: ChainKey,
+}
+
+impl RatchetKeys {
+    /// Starts both chains from the secrets of their direction.
+    pub fn new(outbound: [u8; HASH_LEN], inbound: [u8; HASH_LEN]) -> Self {
+        Self { outbound: ChainKey::new(outbound), inbound: ChainKey::new(inbound) }
+    }
+}
+
+/// Whether a direction that sealed `sent` payloads during `elapsed` should rotate, per `config`.
+pub fn rotation_due(sent: u64, elapsed: Duration, config: &TunableConfig) -> bool {
+    (config.rekey_messages > 0 && sent >= u64::from(config.rekey_messages))
+        || (!config.rekey_interval.is_zero() && elapsed >= config.rekey_interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+
+    #[test]
+    fn test_advance() {
+        let mut chain = ChainKey::new([7; HASH_LEN]);
+        let first = chain.direction_secret().unwrap();
+        chain.advance().unwrap();
+        assert_eq!(chain.epoch(), 1);
+        assert_ne!(chain.secret.expose(), &[7; HASH_LEN]);
+        assert_ne!(chain.direction_secret().unwrap(), first);
+
+        // Both sides of a direction move in step.
+        let mut other = ChainKey::new([7; HASH_LEN]);
+        other.advance().unwrap();
+        assert_eq!(other.secret, chain.secret);
+        assert_eq!(other.direction_secret().unwrap(), chain.direction_secret().unwrap());
+
+        chain.epoch = u32::MAX;
+        assert_eq!(chain.advance(), Err(SecureChannelError::Exhausted));
+    }
+
+    #[test]
+    fn test_drop_wipes() {
+        let mut chain = ManuallyDrop::new(ChainKey::new([7; HASH_LEN]));
+        // SAFETY: the chain is only read as plain bytes after its drop, never dropped again.
+        unsafe { ManuallyDrop::drop(&mut chain) };
+        assert_eq!(chain.secret.expose(), &[0; HASH_LEN]);
+    }
+
+    #[test]
+    fn test_rotation_due() {
+        let config = TunableConfig {
+            rekey_messages: 100,
+            rekey_interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert!(!rotation_due(99, Duration::from_secs(59), &config));
+        assert!(rotation_due(100, Duration::ZERO, &config));
+        assert!(rotation_due(0, Duration::from_secs(60), &config));
+        let never = TunableConfig {
+            rekey_messages: 0,
+            rekey_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(!rotation_due(u64::MAX, Duration::MAX, &never));
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Optional reliability layer for lossy transports.
+//!
+//! Receivers answer each fragment with an ACK frame listing the ranges of sequence numbers they
+//! received lately. Senders keep each fragment until it is ACKed: a fragment missing from the
+//! ACKed ranges while one sent after it was ACKed is retransmitted right away, and a fragment not
+//! ACKed within the ACK timeout is retransmitted then, both up to a limit, after which its message
+//! is given up.
+//!
+//! Retransmissions make delivery at least once, and receivers drop the copies by their sequence
+//! numbers, so that callers still see each message once.
+
+use crate::framing::{FramingError, MAX_RETRANSMISSIONS};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Ranges of sequence numbers an ACK frame lists at most, the newest ones.
+pub const MAX_ACK_RANGES: usize = 4;
+
+/// How long fragments wait for their ACK, and how often they are retransmitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReliabilityConfig {
+    /// Delay after which a fragment not ACKed is retransmitted.
+    pub ack_timeout: Duration,
+    /// Times a fragment is retransmitted before its message is given up.
+    pub max_retransmissions: usize,
+}
+
+impl Default for ReliabilityConfig {
+    fn default() -> Self {
+        Self { ack_timeout: Duration::from_millis(500), max_retransmissions: MAX_RETRANSMISSIONS }
+    }
+}
+
+/// Tells the sender of a message once all its fragments are ACKed, or it was given up.
+pub(crate) type Delivery = oneshot::Sender<Result<(), FramingError>>;
+
+/// A fragment sent and not ACKed yet.
+struct Unacked {
+    message_id: u16,
+    frame: Vec<u8>,
+    sent_at: Instant,
+    retransmissions: usize,
+}
+
+/// A message with fragments not ACKed yet.
+struct PendingMessage {
+    unacked: usize,
+    delivery: Option<Delivery>,
+}
+
+/// Fragments sent on a connection and not ACKed yet, by sequence number.
+pub(crate) struct SendWindow {
+    config: ReliabilityConfig,
+    frames: BTreeMap<u32, Unacked>,
+    messages: HashMap<u16, PendingMessage>,
+}
+
+impl SendWindow {
+    pub(crate) fn new(config: ReliabilityConfig) -> Self {
+        Self { config, frames: BTreeMap::new(), messages: HashMap::new() }
+    }
+
+    /// Keeps the `fragments` of `message_id`, numbered from `first_sequence`, until ACKed.
+    pub(crate) fn track(
+        &mut self,
+        message_id: u16,
+        first_sequence: u32,
+        fragments: &[Vec<u8>],
+        delivery: Option<Delivery>,
+        now: Instant,
+    ) {
+        for (frame, index) in fragments.iter().zip(0..) {
+            let unacked =
+                Unacked { message_id, frame: frame.clone(), sent_at: now, retransmissions: 0 };
+            self.frames.insert(first_sequence.wrapping_add(index), unacked);
+        }
+        self.messages.insert(message_id, PendingMessage { unacked: fragments.len(), delivery });
+    }
+
+    /// Returns when the oldest fragment not ACKed times out.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.frames.values().map(|frame| frame.sent_at + self.config.ack_timeout).min()
+    }
+
+    /// Forgets the fragments `ranges` ACK, and returns the fragments to retransmit: those within
+    /// the ranges, missing from them, and sent before one that was ACKed.
+    pub(crate) fn on_ack(&mut self, ranges: &[RangeInclusive<u32>], now: Instant) -> Vec<Vec<u8>> {
+        let mut latest_acked = None;
+        for range in ranges {
+            let acked: Vec<u32> = self.frames.range(range.clone()).map(|(s, _)| *s).collect();
+            for sequence in acked {
+                let frame = self.frames.remove(&sequence).unwrap();
+                latest_acked = latest_acked.max(Some(frame.sent_at));
+                self.acked(frame.message_id);
+            }
+        }
+        let low = ranges.iter().map(|range| *range.start()).min();
+        let high = ranges.iter().map(|range| *range.end()).max();
+        let (Some(latest_acked), Some(low), Some(high)) = (latest_acked, low, high) else {
+            return Vec::new();
+        };
+        let missing: Vec<u32> = self
+            .frames
+            .range(low..=high)
+            .filter(|(_, frame)| frame.sent_at < latest_acked)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        missing.into_iter().filter_map(|sequence| self.retransmit(sequence, now)).collect()
+    }
+
+    /// Returns the fragments to retransmit for not being ACKed in time.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let due: Vec<u32> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.sent_at + self.config.ack_timeout <= now)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        due.into_iter().filter_map(|sequence| self.retransmit(sequence, now)).collect()
+    }
+
+    fn acked(&mut self, message_id: u16) {
+        let Some(message) = self.messages.get_mut(&message_id) else { return };
+        message.unacked -= 1;
+        if message.unacked > 0 {
+            return;
+        }
+        if let Some(delivery) = self.messages.remove(&message_id).and_then(|m| m.delivery) {
+            let _ = delivery.send(Ok(()));
+        }
+    }
+
+    /// Returns fragment `sequence` to retransmit, or None if it was given up with its message.
+    fn retransmit(&mut self, sequence: u32, now: Instant) -> Option<Vec<u8>> {
+        let frame = self.frames.get_mut(&sequence)?;
+        if frame.retransmissions < self.config.max_retransmissions {
+            frame.retransmissions += 1;
+            frame.sent_at = now;
+            return Some(frame.frame.clone());
+        }
+        let message_id = frame.message_id;
+        warn!(
+            "giving up on message {} after {} retransmissions",
+            message_id, frame.retransmissions
+        );
+        self.frames.retain(|_, frame| frame.message_id != message_id);
+        if let Some(delivery) = self.messages.remove(&message_id).and_then(|m| m.delivery) {
+            let _ = delivery.send(Err(FramingError::Undelivered(message_id)));
+        }
+        None
+    }
+}
+
+/// Send windows of the connections of a `FramedPlatform`.
+pub(crate) struct Reliability {
+    config: ReliabilityConfig,
+    windows: Mutex<HashMap<i32, SendWindow>>,
+}
+
+impl Reliability {
+    pub(crate) fn new(config: ReliabilityConfig) -> Self {
+        Self { config, windows: Mutex::new(HashMap::new()) }
+    }
+
+    pub(crate) fn track(
+        &self,
+        connection_id: i32,
+        message_id: u16,
+        first_sequence: u32,
+        fragments: &[Vec<u8>],
+        delivery: Option<Delivery>,
+    ) {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(connection_id).or_insert_with(|| SendWindow::new(self.config));
+        window.track(message_id, first_sequence, fragments, delivery, Instant::now());
+    }
+
+    pub(crate) fn ack_timeout(&self) -> Duration {
+        self.config.ack_timeout
+    }
+
+    pub(crate) fn next_deadline(&self, connection_id: i32) -> Option<Instant> {
+        self.windows.lock().unwrap().get(&connection_id)?.next_deadline()
+    }
+
+    pub(crate) fn on_ack(
+        &self,
+        connection_id: i32,
+        ranges: &[RangeInclusive<u32>],
+    ) -> Vec<Vec<u8>> {
+        let mut windows = self.windows.lock().unwrap();
+        let Some(window) = windows.get_mut(&connection_id) else { return Vec::new() };
+        window.on_ack(ranges, Instant::now())
+    }
+
+    pub(crate) fn expire(&self, connection_id: i32, now: Instant) -> Vec<Vec<u8>> {
+        let mut windows = self.windows.lock().unwrap();
+        let Some(window) = windows.get_mut(&connection_id) else { return Vec::new() };
+        window.expire(now)
+    }
+
+    /// Forgets the fragments of `connection_id`, failing their deliveries.
+    pub(crate) fn forget(&self, connection_id: i32) {
+        self.windows.lock().unwrap().remove(&connection_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i]).collect()
+    }
+
+    #[test]
+    fn test_ack() {
+        let start = Instant::now();
+        let mut window = SendWindow::new(ReliabilityConfig::default());
+        let (delivery, mut delivered) = oneshot::channel();
+        window.track(1, 10, &frames(2), Some(delivery), start);
+        window.track(2, 12, &frames(1), None, start + Duration::from_millis(1));
+        // 11 is lost: 12 was sent after it and ACKed.
+        assert_eq!(window.on_ack(&[12..=12, 10..=10], start), vec![vec![1]]);
+        assert!(window.on_ack(&[12..=12, 10..=10], start).is_empty());
+        assert!(delivered.try_recv().is_err());
+        assert!(window.on_ack(&[10..=12], start).is_empty());
+        assert_eq!(delivered.try_recv(), Ok(Ok(())));
+        assert_eq!(window.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_expire() {
+        let start = Instant::now();
+        let config =
+            ReliabilityConfig { ack_timeout: Duration::from_secs(1), max_retransmissions: 1 };
+        let mut window = SendWindow::new(config);
+        let (delivery, mut delivered) = oneshot::channel();
+        window.track(1, 0, &frames(1), Some(delivery), start);
+        assert_eq!(window.next_deadline(), Some(start + Duration::from_secs(1)));
+        assert!(window.expire(start).is_empty());
+        assert_eq!(window.expire(start + Duration::from_secs(1)), vec![vec![0]]);
+        assert!(window.expire(start + Duration::from_secs(2)).is_empty());
+        assert_eq!(delivered.try_recv(), Ok(Err(FramingError::Undelivered(1))));
+        assert_eq!(window.next_deadline(), None);
+    }
+}
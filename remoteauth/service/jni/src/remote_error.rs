@@ -0,0 +1,217 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Errors the remote device returns instead of a response.
+//!
+//! The remote device answers a request it rejects with an `ErrorFrame`. Typed requests decode it
+//! into a `RemoteError`, surfaced as `MessageError::Remote`, so that callers can tell the remote
+//! device rejecting a request from the link failing, which is `MessageError::Failed`.
+
+use crate::messages::{DecodeError, Message, Reader, Writer};
+use crate::schema::{Field, Rule, Schema};
+use thiserror::Error;
+
+/// Longest message of an error frame, in bytes.
+pub const MAX_ERROR_MESSAGE_LEN: usize = 256;
+
+/// Broad reason of a remote error, as encoded in error frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The request is malformed or not allowed, e.g. an unknown key id.
+    InvalidRequest,
+    /// The requester is not trusted, e.g. its signature didn't verify.
+    Unauthenticated,
+    /// The remote device can't serve the request for now, e.g. it is locked.
+    Unavailable,
+    /// The remote device failed, or reported a category unknown to this side.
+    Internal,
+}
+
+impl ErrorCategory {
+    fn id(self) -> u32 {
+        match self {
+            Self::InvalidRequest => 1,
+            Self::Unauthenticated => 2,
+            Self::Unavailable => 3,
+            Self::Internal => 4,
+        }
+    }
+
+    /// Returns the category with `id`, unknown ones being internal errors.
+    fn from_id(id: u32) -> Self {
+        match id {
+            1 => Self::InvalidRequest,
+            2 => Self::Unauthenticated,
+            3 => Self::Unavailable,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// Wire frame of an error returned instead of a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorFrame {
+    /// Error code, specific to the remote device.
+    pub code: u32,
+    /// Broad reason of the error.
+    pub category: ErrorCategory,
+    /// Whether sending the request again may succeed.
+    pub retryable: bool,
+    /// Description of the error, for logs.
+    pub message: String,
+}
+
+impl Message for ErrorFrame {
+    const TYPE: u8 = 10;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "error_frame",
+        fields: &[
+            Field::new(1, "code", Rule::U32),
+            Field::new(2, "category", Rule::U32),
+            Field::new(3, "retryable", Rule::Uint { min: 0, max: 1 }),
+            Field::new(4, "message", Rule::Bytes { min_len: 0, max_len: MAX_ERROR_MESSAGE_LEN }),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.code);
+        writer.put_u32(self.category.id());
+        writer.put_u32(self.retryable.into());
+        writer.put_bytes(self.message.as_bytes());
+    }
+
+    /// Messages that aren't UTF-8 are decoded lossily: they are only for logs.
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            code: reader.u32()?,
+            category: ErrorCategory::from_id(reader.u32()?),
+            retryable: reader.u32()? != 0,
+            message: String::from_utf8_lossy(&reader.bytes()?).into_owned(),
+        })
+    }
+}
+
+/// Details of a remote error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteErrorDetails {
+    /// Error code, specific to the remote device.
+    pub code: u32,
+    /// Whether sending the request again may succeed.
+    pub retryable: bool,
+    /// Description of the error, for logs.
+    pub message: String,
+}
+
+/// The remote device rejected a request.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RemoteError {
+    /// The request is malformed or not allowed.
+    #[error("remote device rejected the request: {} ({})", .0.message, .0.code)]
+    InvalidRequest(RemoteErrorDetails),
+    /// The requester is not trusted.
+    #[error("remote device didn't authenticate the request: {} ({})", .0.message, .0.code)]
+    Unauthenticated(RemoteErrorDetails),
+    /// The remote device can't serve the request for now.
+    #[error("remote device is unavailable: {} ({})", .0.message, .0.code)]
+    Unavailable(RemoteErrorDetails),
+    /// The remote device failed.
+    #[error("remote device failed: {} ({})", .0.message, .0.code)]
+    Internal(RemoteErrorDetails),
+}
+
+impl RemoteError {
+    /// Returns the details of the error.
+    pub fn details(&self) -> &RemoteErrorDetails {
+        match self {
+            Self::InvalidRequest(details)
+            | Self::Unauthenticated(details)
+            | Self::Unavailable(details)
+            | Self::Internal(details) => details,
+        }
+    }
+
+    /// Returns the category of the error.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::InvalidRequest(_) => ErrorCategory::InvalidRequest,
+            Self::Unauthenticated(_) => ErrorCategory::Unauthenticated,
+            Self::Unavailable(_) => ErrorCategory::Unavailable,
+            Self::Internal(_) => ErrorCategory::Internal,
+        }
+    }
+
+    /// Whether sending the request again may succeed, as the remote device says.
+    pub fn is_retryable(&self) -> bool {
+        self.details().retryable
+    }
+}
+
+impl From<ErrorFrame> for RemoteError {
+    fn from(frame: ErrorFrame) -> Self {
+        let details = RemoteErrorDetails {
+            code: frame.code,
+            retryable: frame.retryable,
+            message: frame.message,
+        };
+        match frame.category {
+            ErrorCategory::InvalidRequest => Self::InvalidRequest(details),
+            ErrorCategory::Unauthenticated => Self::Unauthenticated(details),
+            ErrorCategory::Unavailable => Self::Unavailable(details),
+            ErrorCategory::Internal => Self::Internal(details),
+        }
+    }
+}
+
+impl From<&RemoteError> for ErrorFrame {
+    fn from(error: &RemoteError) -> Self {
+        let details = error.details();
+        Self {
+            code: details.code,
+            category: error.category(),
+            retryable: details.retryable,
+            message: details.message.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_frame() {
+        let frame = ErrorFrame {
+            code: 42,
+            category: ErrorCategory::Unavailable,
+            retryable: true,
+            message: String::from("device locked"),
+        };
+        let decoded = ErrorFrame::decode(&frame.encode()).unwrap();
+        assert_eq!(decoded, frame);
+        let error = RemoteError::from(decoded);
+        assert!(matches!(error, RemoteError::Unavailable(_)));
+        assert!(error.is_retryable());
+        assert_eq!(error.to_string(), "remote device is unavailable: device locked (42)");
+        assert_eq!(ErrorFrame::from(&error), frame);
+
+        // Unknown categories are internal errors.
+        let unknown = ErrorFrame { category: ErrorCategory::Internal, ..frame };
+        let mut bytes = unknown.encode();
+        assert_eq!(bytes[7], 0x04);
+        bytes[7] = 0x09;
+        assert_eq!(ErrorFrame::decode(&bytes), Ok(unknown));
+    }
+}
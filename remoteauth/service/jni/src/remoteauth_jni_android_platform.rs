@@ -13,21 +13,69 @@
 // limitations under the License.
 
 //! Implementation of JNI platform functionality.
-use crate::jnames::{SEND_REQUEST_MNAME, SEND_REQUEST_MSIG};
+use crate::config;
+use crate::connection_events::CONNECTION_EVENTS_CAPACITY;
+use crate::connection_info::CONNECTION_INFO_LEN;
+use crate::connection_limiter::{Admission, ConnectionLimiter};
+use crate::connection_quality::ConnectionQualityTracker;
+use crate::dispatch;
+use crate::enrollment::EnrollmentStore;
+use crate::error::PlatformError;
+use crate::handles::{HandleAllocator, OperationHandle, PlatformHandle, ResponseHandle};
+use crate::jnames::{
+    CANCEL_REQUEST_MSIG, CLOSE_CONNECTION_MSIG, DELETE_ENROLLMENT_MSIG, DERIVE_BACKUP_SECRET_MSIG,
+    GENERATE_KEY_PAIR_MSIG, GET_CONNECTION_INFO_MSIG, GET_DEVICE_PUBLIC_KEY_MSIG,
+    ON_PLATFORM_IDLE_CLOSED_MSIG, ON_REQUEST_PROGRESS_MSIG, ON_SEND_REQUEST_TIMEOUT_MSIG,
+    OPEN_CONNECTION_MSIG, PLATFORM_CONFIG_FORWARD_PROGRESS_FNAME,
+    PLATFORM_CONFIG_IDLE_TIMEOUT_FNAME, PLATFORM_CONFIG_LOG_TAG_SUFFIX_FNAME,
+    PLATFORM_CONFIG_MAX_CONCURRENT_REQUESTS_FNAME,
+    PLATFORM_CONFIG_MAX_REQUESTS_PER_CONNECTION_FNAME, PLATFORM_CONFIG_ORDERED_REQUESTS_FNAME,
+    PLATFORM_CONFIG_QUEUE_WHEN_BUSY_FNAME, PLATFORM_CONFIG_REQUEST_TIMEOUT_FNAME,
+    PLATFORM_CONFIG_RESPONSE_CHANNEL_CAPACITY_FNAME, PLATFORM_CONFIG_WEAK_PLATFORM_REF_FNAME,
+    SEND_NOTIFICATION_MSIG, SEND_REQUEST_MSIG, SIGN_WITH_DEVICE_KEY_MSIG, STORE_ENROLLMENT_MSIG,
+};
+use crate::jni_onload::{get_jni_cache, PlatformMethods};
+use crate::jvm_attach::attached_env;
+use crate::keystore::Keystore;
+use crate::platform_ref::PlatformRef;
+use crate::platform_registry::platform_registry;
+use crate::remoteauth_jni_android_protocol::get_native_config;
+use crate::response_cache::ResponseCache;
+use crate::retry_policy::RetryCounters;
+use crate::runtime::get_runtime;
+use crate::secret::Secret;
+use crate::supervisor::TaskSupervisor;
 use crate::unique_jvm;
+use crate::utils::{
+    catch_jni_panic, remaining_until_elapsed_realtime, throw_bad_handle, throw_illegal_argument,
+    throw_illegal_state, throw_message_too_large, throw_native_exception,
+};
 use anyhow::anyhow;
+use async_trait::async_trait;
 use jni::errors::Error as JNIError;
-use jni::objects::{GlobalRef, JMethodID, JObject, JValue};
+use jni::objects::{JObject, JString, JValue};
 use jni::signature::TypeSignature;
-use jni::sys::{jbyteArray, jint, jlong, jvalue};
+use jni::sys::{jboolean, jbyteArray, jint, jintArray, jlong, jvalue, JNI_FALSE, JNI_TRUE};
 use jni::{JNIEnv, JavaVM};
-use lazy_static::lazy_static;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+use std::pin::pin;
 use std::sync::{
-    atomic::{AtomicI64, Ordering},
-    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, OnceLock, Weak,
 };
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
+use tokio::task::{AbortHandle, JoinHandle};
+
+pub use crate::connection_events::{ConnectionEvent, ConnectionState};
+pub use crate::connection_info::{ConnectionInfo, Transport};
+pub use crate::connection_limiter::{Backpressure, Priority};
+pub use crate::connection_quality::RttStats;
+pub use crate::request_metadata::{IdempotencyKey, RequestMetadata, TraceId};
+pub use crate::retry_policy::{ErrorClass, RetryPolicy, RetryStats};
 
 /// Macro capturing the name of the function calling this macro.
 ///
@@ -51,207 +99,3456 @@ macro_rules! function_name {
     }};
 }
 
-lazy_static! {
-    static ref HANDLE_MAPPING: Mutex<HashMap<i64, Arc<Mutex<JavaPlatform>>>> =
-        Mutex::new(HashMap::new());
-    static ref HANDLE_RN: AtomicI64 = AtomicI64::new(0);
+static HANDLE_MAPPING: OnceLock<Mutex<HashMap<PlatformHandle, Arc<JavaPlatform>>>> =
+    OnceLock::new();
+static HANDLE_RN: HandleAllocator = HandleAllocator::new();
+static SWEEPER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+static WATCHDOG: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+static TIMED_OUT_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+fn handle_mapping() -> &'static Mutex<HashMap<PlatformHandle, Arc<JavaPlatform>>> {
+    HANDLE_MAPPING.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Initializes the global platform state. Called from native_init.
+pub(crate) fn init_platform_state() {
+    handle_mapping();
 }
 
-fn generate_platform_handle() -> i64 {
-    HANDLE_RN.fetch_add(1, Ordering::SeqCst)
+fn generate_platform_handle() -> Result<PlatformHandle, PlatformError> {
+    let mapping = handle_mapping().lock().unwrap();
+    HANDLE_RN
+        .allocate(mapping.len(), |value| mapping.contains_key(&PlatformHandle::new(value)))
+        .map(PlatformHandle::new)
 }
 
-fn insert_platform_handle(handle: i64, item: Arc<Mutex<JavaPlatform>>) {
-    if 0 == handle {
-        // Init once
-        logger::init(
-            logger::Config::default()
-                .with_tag_on_device("remoteauth")
-                .with_max_level(log::LevelFilter::Trace)
-                .with_filter("trace,jni=info"),
-        );
+fn insert_platform_handle(handle: PlatformHandle, item: Arc<JavaPlatform>) {
+    handle_mapping().lock().unwrap().insert(handle, Arc::clone(&item));
+}
+
+fn remove_platform_handle(handle: PlatformHandle) -> Option<Arc<JavaPlatform>> {
+    handle_mapping().lock().unwrap().remove(&handle)
+}
+
+fn is_past(instant: Option<Instant>) -> bool {
+    instant.is_some_and(|instant| Instant::now() >= instant)
+}
+
+fn vectored_len(bufs: &[&[u8]]) -> usize {
+    bufs.iter().map(|buf| buf.len()).sum()
+}
+
+// Copies `bufs` back to back into a single allocation of `len`, their total length.
+fn gather(bufs: &[&[u8]], len: usize) -> Vec<u8> {
+    let mut request = Vec::with_capacity(len);
+    for buf in bufs {
+        request.extend_from_slice(buf);
     }
-    HANDLE_MAPPING.lock().unwrap().insert(handle, Arc::clone(&item));
+    request
+}
+
+/// Response from remote device, with how it arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// Response payload.
+    pub payload: Vec<u8>,
+    /// Time from handing the request over to Java until the response arrived.
+    pub latency: Duration,
+    /// Transport the response arrived over, if Java reported it.
+    pub transport: Option<Transport>,
+    /// Status the remote device reported besides the payload, 0 if none.
+    pub remote_status: i32,
 }
 
+/// Payload of a ping: the transport answers an empty request with an empty response.
+pub const PING_REQUEST: &[u8] = &[];
+
 /// Reports a response from remote device.
 pub trait ResponseCallback {
     /// Invoked upon successful response
-    fn on_response(&mut self, response: Vec<u8>);
+    fn on_response(&mut self, response: Response);
     /// Invoked upon failure
     fn on_error(&mut self, error_code: i32);
+    /// Whether the callback takes each chunk of a streamed response through `on_chunk` as it
+    /// arrives. Otherwise the chunks are assembled and `on_response` gets the whole response once
+    /// it is complete.
+    fn is_streaming(&self) -> bool {
+        false
+    }
+    /// Invoked with each chunk of a streamed response, if `is_streaming`.
+    fn on_chunk(&mut self, _chunk: Vec<u8>) {}
+    /// Invoked as the transport sends a large request, with the bytes sent so far out of `total`.
+    fn on_progress(&mut self, _bytes_sent: u64, _total: u64) {}
+}
+
+/// Chunks of a streamed response, ending after the last one or after an error code.
+pub struct ResponseStream {
+    response_handle: ResponseHandle,
+    receiver: mpsc::Receiver<Result<Vec<u8>, i32>>,
+}
+
+impl ResponseStream {
+    /// Returns the handle of the request, e.g. to cancel it.
+    pub fn response_handle(&self) -> ResponseHandle {
+        self.response_handle
+    }
+
+    /// Waits for the next chunk. Returns None once the response is complete.
+    pub async fn next(&mut self) -> Option<Result<Vec<u8>, i32>> {
+        self.receiver.recv().await
+    }
+
+    /// Polls for the next chunk, e.g. to adapt the stream to another async interface.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Vec<u8>, i32>>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Messages pushed by the remote device on one connection, without a request.
+///
+/// The stream ends when the platform closes.
+pub struct MessageStream {
+    connection_id: i32,
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl MessageStream {
+    /// Returns the connection the messages arrive on.
+    pub fn connection_id(&self) -> i32 {
+        self.connection_id
+    }
+
+    /// Waits for the next message. Returns None once the platform is closed.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        self.receiver.recv().await
+    }
+
+    /// Polls for the next message, e.g. to adapt the stream to another async interface.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// How much of a large request the transport has sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes sent so far.
+    pub bytes_sent: u64,
+    /// Size of the whole request, in bytes.
+    pub total: u64,
+}
+
+/// Progress of sending a request, ending when the request completes.
+///
+/// Updates arriving faster than they are read are merged: only the latest one is kept.
+pub struct ProgressStream {
+    response_handle: ResponseHandle,
+    receiver: watch::Receiver<Progress>,
+}
+
+impl ProgressStream {
+    /// Returns the handle of the request, e.g. to cancel it.
+    pub fn response_handle(&self) -> ResponseHandle {
+        self.response_handle
+    }
+
+    /// Waits for the next update. Returns None once the request is complete.
+    pub async fn next(&mut self) -> Option<Progress> {
+        self.receiver.changed().await.ok()?;
+        Some(*self.receiver.borrow_and_update())
+    }
+}
+
+/// Publishes the progress reported to the callback it wraps to a ProgressStream.
+struct ProgressCallback {
+    inner: Box<dyn ResponseCallback + Send>,
+    sender: watch::Sender<Progress>,
+}
+
+impl ResponseCallback for ProgressCallback {
+    fn on_response(&mut self, response: Response) {
+        self.inner.on_response(response)
+    }
+
+    fn on_error(&mut self, error_code: i32) {
+        self.inner.on_error(error_code)
+    }
+
+    fn is_streaming(&self) -> bool {
+        self.inner.is_streaming()
+    }
+
+    fn on_chunk(&mut self, chunk: Vec<u8>) {
+        self.inner.on_chunk(chunk)
+    }
+
+    fn on_progress(&mut self, bytes_sent: u64, total: u64) {
+        self.inner.on_progress(bytes_sent, total);
+        self.sender.send_replace(Progress { bytes_sent, total });
+    }
+}
+
+/// Feeds the chunks of a response into a ResponseStream. The stream ends when it is dropped.
+struct StreamCallback {
+    sender: mpsc::Sender<Result<Vec<u8>, i32>>,
+    failed: bool,
+}
+
+impl ResponseCallback for StreamCallback {
+    /// A response delivered whole is a stream of a single chunk.
+    fn on_response(&mut self, response: Response) {
+        if !response.payload.is_empty() {
+            self.on_chunk(response.payload);
+        }
+    }
+
+    fn on_chunk(&mut self, chunk: Vec<u8>) {
+        if self.failed {
+            return;
+        }
+        // The last slot of the channel is kept for the error ending an overflowing stream.
+        if self.sender.capacity() > 1 {
+            let _ = self.sender.try_send(Ok(chunk));
+        } else {
+            self.on_error(PlatformError::StreamFull.error_code());
+        }
+    }
+
+    fn on_error(&mut self, error_code: i32) {
+        if !self.failed {
+            self.failed = true;
+            let _ = self.sender.try_send(Err(error_code));
+        }
+    }
+
+    fn is_streaming(&self) -> bool {
+        true
+    }
+}
+
+/// Outcomes of a request sent to several connections at once, by connection id.
+pub struct MultiResponse {
+    outcomes: Vec<(i32, MultiOutcome)>,
+}
+
+enum MultiOutcome {
+    Pending(oneshot::Receiver<Result<Response, i32>>),
+    Failed(i32),
+}
+
+impl MultiResponse {
+    /// Waits for every connection to respond or fail. Errors are `ResponseCallback` error codes.
+    pub async fn wait(self) -> Vec<(i32, Result<Response, i32>)> {
+        let mut results = Vec::with_capacity(self.outcomes.len());
+        // The requests are all in flight already: waiting in order doesn't delay any of them.
+        for (connection_id, outcome) in self.outcomes {
+            let result = match outcome {
+                MultiOutcome::Pending(receiver) => {
+                    receiver.await.unwrap_or(Err(PlatformError::PlatformDestroyed.error_code()))
+                }
+                MultiOutcome::Failed(error_code) => Err(error_code),
+            };
+            results.push((connection_id, result));
+        }
+        results
+    }
+}
+
+/// Hands the response of one of the requests of a MultiResponse over.
+pub(crate) struct OneshotCallback {
+    pub(crate) sender: Option<oneshot::Sender<Result<Response, i32>>>,
+}
+
+impl ResponseCallback for OneshotCallback {
+    fn on_response(&mut self, response: Response) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Ok(response));
+        }
+    }
+
+    fn on_error(&mut self, error_code: i32) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Err(error_code));
+        }
+    }
 }
 
 /// Trait to platform functionality
-pub trait Platform {
+///
+/// Implementations are shared between threads: requests on the same platform may be sent
+/// concurrently and are multiplexed by response handle. The trait is object-safe, so that
+/// platforms of different types can be stored together as `BoxedPlatform`.
+#[async_trait]
+pub trait Platform: Send + Sync {
     /// Send a binary message to the remote with the given connection id and return the response.
+    ///
+    /// The deadline of `metadata` is optional, and `timeout` an optional delay defaulting to the
+    /// platform request timeout, or the configured default timeout: the request fails with
+    /// `Timeout` if no response arrives by the earliest of the two.
+    ///
+    /// When the connection has no slot left, queued requests are sent in priority order. The trace
+    /// id of `metadata` is passed to Java and tags the native logs of the request. A request with
+    /// the idempotency key of a request completed recently on the same connection gets the cached
+    /// response, without being sent again.
+    ///
+    /// A request not handed over to Java by the send-by deadline of `metadata`, e.g. because its
+    /// connection is paused, fails with `ExpiredBeforeSend` instead of being sent late.
+    ///
+    /// Attempts failing on the transport are retried as allowed by the retry policy of
+    /// `metadata`, or else of the platform, until the deadline.
+    ///
+    /// Returns the response handle identifying the request, e.g. to cancel it.
     fn send_request(
-        &mut self,
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+        callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ResponseHandle>;
+
+    /// Like `send_request`, for a request made of several buffers, e.g. a header and a payload,
+    /// sent back to back as a single message. The buffers are copied once, straight into the
+    /// request handed to Java, instead of being concatenated by the caller first.
+    fn send_request_vectored(
+        &self,
+        connection_id: i32,
+        bufs: &[&[u8]],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+        callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ResponseHandle>;
+
+    /// Like `send_request`, but waits for the window of outstanding requests of `connection_id`
+    /// to have room, as long as the deadline allows, instead of failing with `Busy` or queuing.
+    async fn send_request_pipelined(
+        &self,
         connection_id: i32,
         request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
         callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ResponseHandle>;
+
+    /// Like `send_request`, for a response the transport streams in chunks, read from the
+    /// returned stream as they arrive.
+    fn send_request_streaming(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<ResponseStream>;
+
+    /// Cancels a request sent on `connection_id` that has no response yet: its callback gets
+    /// `Cancelled`, and the remote side is told to stop processing it.
+    fn cancel_request(
+        &self,
+        connection_id: i32,
+        response_handle: ResponseHandle,
     ) -> anyhow::Result<()>;
+
+    /// Sends a one-way message, e.g. a status update, without waiting for a response. Only
+    /// errors handing the message over to Java are reported.
+    fn send_notification(&self, connection_id: i32, payload: &[u8]) -> anyhow::Result<()>;
+
+    /// Subscribes to the messages the remote device pushes on `connection_id`, e.g. lock-now or
+    /// key revoked events. Every subscriber gets every message.
+    fn subscribe(&self, connection_id: i32) -> anyhow::Result<MessageStream>;
+
+    /// Returns a receiver of the state changes of all connections. A receiver falling behind
+    /// misses the oldest events.
+    fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent>;
+
+    /// Returns the MTU, transport, signal strength and latency of `connection_id`, as reported by
+    /// the transport. Fails with `UnknownConnection` if the transport doesn't know it.
+    async fn get_connection_info(&self, connection_id: i32) -> anyhow::Result<ConnectionInfo>;
+
+    /// Returns the largest payload a single transport packet of `connection_id` carries, as last
+    /// discovered, or None before discovery. Layers splitting messages size their fragments
+    /// with it.
+    fn max_payload(&self, connection_id: i32) -> Option<usize>;
+
+    /// Limits the messages sent and received on `connection_id` to `max_len` bytes, e.g. as
+    /// negotiated with the remote device. The configured `max_payload_size` still applies.
+    fn set_max_message_size(&self, connection_id: i32, max_len: usize);
+
+    /// Discovers the MTU of `connection_id` from its connection info and returns the resulting
+    /// `max_payload`.
+    async fn discover_mtu(&self, connection_id: i32) -> anyhow::Result<usize> {
+        Ok(self.get_connection_info(connection_id).await?.mtu)
+    }
+
+    /// Connects to the enrolled device `device_id`. Returns the id of the new connection once
+    /// the transport reports it open.
+    async fn open_connection(&self, device_id: &str) -> anyhow::Result<i32>;
+
+    /// Disconnects `connection_id`, returning once the transport reports it closed.
+    async fn close_connection(&self, connection_id: i32) -> anyhow::Result<()>;
+
+    /// Sends `PING_REQUEST` on `connection_id` and returns the round-trip time of its answer.
+    async fn ping(&self, connection_id: i32) -> anyhow::Result<Duration>;
+
+    /// Stops sending requests on `connection_id`, e.g. while its transport is congested. Requests
+    /// are queued meanwhile, still failing at their deadline.
+    fn pause(&self, connection_id: i32);
+
+    /// Sends the requests queued while `connection_id` was paused.
+    fn resume(&self, connection_id: i32);
+
+    /// Like `send_request`, also returning the progress of sending the request as the transport
+    /// reports it, e.g. for a large enrollment payload.
+    fn send_request_with_progress(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+        callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ProgressStream> {
+        let (sender, receiver) = watch::channel(Progress::default());
+        let callback = Box::new(ProgressCallback { inner: callback, sender });
+        let response_handle =
+            self.send_request(connection_id, request, metadata, timeout, callback)?;
+        Ok(ProgressStream { response_handle, receiver })
+    }
+
+    /// Sends a request to each connection at once, e.g. the same challenge to every enrolled
+    /// device, and returns their outcomes. A request failing to send doesn't stop the others.
+    /// The requests share `metadata`, and so the trace id of the flow.
+    fn send_request_multi(
+        &self,
+        requests: &[(i32, &[u8])],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> MultiResponse {
+        let outcomes = requests
+            .iter()
+            .map(|&(connection_id, request)| {
+                let (sender, receiver) = oneshot::channel();
+                let callback = Box::new(OneshotCallback { sender: Some(sender) });
+                let outcome =
+                    match self.send_request(connection_id, request, metadata, timeout, callback) {
+                        Ok(_) => MultiOutcome::Pending(receiver),
+                        Err(e) => MultiOutcome::Failed(
+                            e.downcast_ref::<PlatformError>()
+                                .unwrap_or(&PlatformError::SendFailed)
+                                .error_code(),
+                        ),
+                    };
+                (connection_id, outcome)
+            })
+            .collect();
+        MultiResponse { outcomes }
+    }
 }
+
+/// A platform of any type, shared between threads.
+pub type BoxedPlatform = Arc<dyn Platform>;
 //////////////////////////////////
 
+/// Lifecycle of a JavaPlatform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Constructed but not registered in HANDLE_MAPPING yet.
+    Created,
+    /// Registered and accepting requests.
+    Ready,
+    /// No longer accepting requests, waiting for pending ones to complete.
+    ShuttingDown,
+    /// Done: requests and callbacks are rejected.
+    Closed,
+}
+
+impl State {
+    /// Whether the lifecycle allows moving from `self` to `next`.
+    fn can_transition_to(self, next: State) -> bool {
+        matches!(
+            (self, next),
+            (State::Created, State::Ready)
+                | (State::Created, State::Closed)
+                | (State::Ready, State::ShuttingDown)
+                | (State::Ready, State::Closed)
+                | (State::ShuttingDown, State::Closed)
+        )
+    }
+}
+
+/// The callback of a pending request, shared so that it can be called once the lock of the
+/// pending requests is released.
+#[derive(Clone)]
+struct SharedCallback(Arc<Mutex<Box<dyn ResponseCallback + Send>>>);
+
+impl SharedCallback {
+    fn new(callback: Box<dyn ResponseCallback + Send>) -> Self {
+        Self(Arc::new(Mutex::new(callback)))
+    }
+}
+
+impl ResponseCallback for SharedCallback {
+    fn on_response(&mut self, response: Response) {
+        self.0.lock().unwrap().on_response(response)
+    }
+
+    fn on_error(&mut self, error_code: i32) {
+        self.0.lock().unwrap().on_error(error_code)
+    }
+
+    fn is_streaming(&self) -> bool {
+        self.0.lock().unwrap().is_streaming()
+    }
+
+    fn on_chunk(&mut self, chunk: Vec<u8>) {
+        self.0.lock().unwrap().on_chunk(chunk)
+    }
+
+    fn on_progress(&mut self, bytes_sent: u64, total: u64) {
+        self.0.lock().unwrap().on_progress(bytes_sent, total)
+    }
+}
+
+/// A request waiting for its response from Java.
+struct PendingRequest {
+    connection_id: i32,
+    trace_id: TraceId,
+    idempotency_key: Option<IdempotencyKey>,
+    // Kept to send the request again if an attempt fails.
+    request: Arc<Vec<u8>>,
+    retry_policy: RetryPolicy,
+    // Number of the current attempt, from 1.
+    attempt: u32,
+    // Attempts are not sent past it.
+    send_by: Option<Instant>,
+    sent_at: Instant,
+    deadline: Instant,
+    callback: SharedCallback,
+    // Whether the callback takes the response in chunks, read without locking the callback.
+    streaming: bool,
+    // Chunks received so far, for a callback taking the response whole.
+    buffered: Vec<u8>,
+    // Fails the request at its deadline; aborted when a response arrives first.
+    timer: Option<AbortHandle>,
+}
+
+impl PendingRequest {
+    /// Buffers `chunk` unless the callback takes the response in chunks. Fails with
+    /// `ResponseTooLarge` if the response would grow past `max_len`, the largest one of its
+    /// connection.
+    fn accept_chunk(&mut self, chunk: &[u8], max_len: usize) -> Result<(), PlatformError> {
+        let len = if self.streaming { chunk.len() } else { self.buffered.len() + chunk.len() };
+        if len > max_len {
+            return Err(PlatformError::ResponseTooLarge(len));
+        }
+        if !self.streaming {
+            self.buffered.extend_from_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+/// A request on its way to Java, possibly waiting for a slot of its connection.
+struct OutgoingRequest {
+    connection_id: i32,
+    response_handle: ResponseHandle,
+    trace_id: TraceId,
+    idempotency_key: Option<IdempotencyKey>,
+    retry_policy: RetryPolicy,
+    request: Vec<u8>,
+    created_at: Instant,
+    send_by: Option<Instant>,
+    deadline: Instant,
+    callback: Box<dyn ResponseCallback + Send>,
+}
+
+/// A request checked by `prepare_request`.
+enum Prepared {
+    /// Answered from the response cache, nothing to send.
+    Answered(ResponseHandle),
+    /// To be sent, at its priority.
+    Outgoing(OutgoingRequest, Priority),
+}
+
+/// Operations waiting for Java to complete them, by handle, with a `T`.
+type Operations<T> = Mutex<HashMap<OperationHandle, oneshot::Sender<Result<T, PlatformError>>>>;
+
+/// Per-instance options of a JavaPlatform.
+#[derive(Debug, Clone)]
+struct PlatformOptions {
+    response_channel_capacity: usize,
+    request_timeout: Option<Duration>,
+    log_tag_suffix: Option<String>,
+    max_concurrent_requests: Option<usize>,
+    idle_timeout: Option<Duration>,
+    weak_platform_ref: bool,
+    max_requests_per_connection: Option<usize>,
+    backpressure: Backpressure,
+    ordered: bool,
+    forward_progress: bool,
+    retry_policy: Option<RetryPolicy>,
+}
+
+/// Builds a JavaPlatform with per-instance options.
+#[derive(Debug, Clone)]
+pub struct JavaPlatformBuilder {
+    options: PlatformOptions,
+}
+
+impl Default for JavaPlatformBuilder {
+    fn default() -> Self {
+        Self {
+            options: PlatformOptions {
+                response_channel_capacity: get_native_config().channel_capacity,
+                request_timeout: None,
+                log_tag_suffix: None,
+                max_concurrent_requests: None,
+                idle_timeout: None,
+                weak_platform_ref: false,
+                max_requests_per_connection: None,
+                backpressure: Backpressure::Reject,
+                ordered: false,
+                forward_progress: false,
+                retry_policy: None,
+            },
+        }
+    }
+}
+
+impl JavaPlatformBuilder {
+    /// Creates a builder with the options from the native config.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the capacity of the channels handing responses over to waiting requests.
+    pub fn response_channel_capacity(mut self, capacity: usize) -> Self {
+        self.options.response_channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets how long requests sent without a timeout wait for a response.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.options.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Appends `suffix` to the platform id in logs, to tell platforms apart.
+    pub fn log_tag_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.options.log_tag_suffix = Some(suffix.into());
+        self
+    }
+
+    /// Limits how many requests may wait for a response at the same time.
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.options.max_concurrent_requests = Some(max);
+        self
+    }
+
+    /// Closes the platform after `timeout` without requests or callbacks.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Holds the Java platform object through a weak reference so that it can be collected while
+    /// the native platform is alive. Calls to a collected object fail with `PlatformGone`.
+    pub fn weak_platform_ref(mut self, weak: bool) -> Self {
+        self.options.weak_platform_ref = weak;
+        self
+    }
+
+    /// Limits how many requests may be outstanding on each connection. Requests beyond the limit
+    /// are rejected with `Busy` or queued, as set by `backpressure`.
+    pub fn max_requests_per_connection(mut self, max: usize, backpressure: Backpressure) -> Self {
+        self.options.max_requests_per_connection = Some(max.max(1));
+        self.options.backpressure = backpressure;
+        self
+    }
+
+    /// Sends the requests of each connection one at a time, in the order they are sent: the next
+    /// one goes to Java once the previous one has its response or failed. Overrides
+    /// `max_requests_per_connection`, and priorities don't reorder the queued requests.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.options.ordered = ordered;
+        self
+    }
+
+    /// Forwards the progress of requests to Java `onRequestProgress` with their trace id, e.g.
+    /// for the UI to show the progress of a flow.
+    pub fn forward_progress(mut self, forward: bool) -> Self {
+        self.options.forward_progress = forward;
+        self
+    }
+
+    /// Sets how requests failing on the transport are retried, unless they set a policy of their
+    /// own. Without one, requests are retried as many times as the configured `max_retries`.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.options.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// Reads the options from a Java platform config object.
+    fn from_java(env: &JNIEnv, config: JObject) -> Result<Self, JNIError> {
+        let mut builder = Self::new();
+        let capacity =
+            env.get_field(config, PLATFORM_CONFIG_RESPONSE_CHANNEL_CAPACITY_FNAME, "I")?.i()?;
+        if capacity > 0 {
+            builder = builder.response_channel_capacity(capacity as usize);
+        }
+        let timeout_millis =
+            env.get_field(config, PLATFORM_CONFIG_REQUEST_TIMEOUT_FNAME, "J")?.j()?;
+        if timeout_millis > 0 {
+            builder = builder.request_timeout(Duration::from_millis(timeout_millis as u64));
+        }
+        let suffix = env
+            .get_field(config, PLATFORM_CONFIG_LOG_TAG_SUFFIX_FNAME, "Ljava/lang/String;")?
+            .l()?;
+        if !suffix.is_null() {
+            builder = builder.log_tag_suffix(env.get_string(JString::from(suffix))?);
+        }
+        let max_requests =
+            env.get_field(config, PLATFORM_CONFIG_MAX_CONCURRENT_REQUESTS_FNAME, "I")?.i()?;
+        if max_requests > 0 {
+            builder = builder.max_concurrent_requests(max_requests as usize);
+        }
+        let idle_timeout_millis =
+            env.get_field(config, PLATFORM_CONFIG_IDLE_TIMEOUT_FNAME, "J")?.j()?;
+        if idle_timeout_millis > 0 {
+            builder = builder.idle_timeout(Duration::from_millis(idle_timeout_millis as u64));
+        }
+        let max_per_connection =
+            env.get_field(config, PLATFORM_CONFIG_MAX_REQUESTS_PER_CONNECTION_FNAME, "I")?.i()?;
+        if max_per_connection > 0 {
+            let backpressure =
+                if env.get_field(config, PLATFORM_CONFIG_QUEUE_WHEN_BUSY_FNAME, "Z")?.z()? {
+                    Backpressure::Queue
+                } else {
+                    Backpressure::Reject
+                };
+            builder =
+                builder.max_requests_per_connection(max_per_connection as usize, backpressure);
+        }
+        let ordered = env.get_field(config, PLATFORM_CONFIG_ORDERED_REQUESTS_FNAME, "Z")?.z()?;
+        let forward_progress =
+            env.get_field(config, PLATFORM_CONFIG_FORWARD_PROGRESS_FNAME, "Z")?.z()?;
+        let weak = env.get_field(config, PLATFORM_CONFIG_WEAK_PLATFORM_REF_FNAME, "Z")?.z()?;
+        Ok(builder.ordered(ordered).forward_progress(forward_progress).weak_platform_ref(weak))
+    }
+
+    /// Creates the JavaPlatform and associates it with a unique handle id.
+    pub fn build(self, java_platform_native: JObject<'_>) -> anyhow::Result<Arc<JavaPlatform>> {
+        // Anything spawned while the platform is being set up lands on the shared runtime.
+        let _guard = get_runtime().enter();
+        let platform_handle = generate_platform_handle()?;
+        let platform = Arc::new(JavaPlatform::new(
+            platform_handle,
+            unique_jvm::get_static_ref().ok_or(JNIError::InvalidCtorReturn)?,
+            java_platform_native,
+            self.options,
+        )?);
+        let _ = platform.self_ref.set(Arc::downgrade(&platform));
+        insert_platform_handle(platform_handle, Arc::clone(&platform));
+        platform.set_state(State::Ready);
+        Ok(platform)
+    }
+}
+
 /// Implementation of Platform trait
 pub struct JavaPlatform {
-    platform_handle: i64,
+    platform_handle: PlatformHandle,
+    log_tag: String,
+    options: PlatformOptions,
     vm: &'static Arc<JavaVM>,
-    platform_native_obj: GlobalRef,
-    send_request_method_id: JMethodID,
-    map_futures: Mutex<HashMap<i64, Box<dyn ResponseCallback + Send>>>,
-    atomic_handle: AtomicI64,
+    platform_native_obj: ManuallyDrop<PlatformRef>,
+    methods: PlatformMethods,
+    map_futures: Mutex<HashMap<ResponseHandle, PendingRequest>>,
+    connection_limiter: ConnectionLimiter<OutgoingRequest>,
+    // Wakes up requests waiting for room in the window of their connection.
+    window_released: Notify,
+    response_handles: HandleAllocator,
+    subscribers: Mutex<HashMap<i32, Vec<mpsc::Sender<Vec<u8>>>>>,
+    // Connection opens and closes waiting for Java to complete them, with the connection id.
+    connection_operations: Operations<i32>,
+    // Keystore operations waiting for Java to complete them, with a signature or public key.
+    keystore_operations: Operations<Vec<u8>>,
+    operation_handles: HandleAllocator,
+    connection_events: broadcast::Sender<ConnectionEvent>,
+    connection_quality: ConnectionQualityTracker,
+    // MTU of each connection, from its latest connection info.
+    mtus: Mutex<HashMap<i32, usize>>,
+    // Largest message of each connection that limits it below the configured maximum.
+    max_message_sizes: Mutex<HashMap<i32, usize>>,
+    // Responses of completed requests that had an idempotency key.
+    response_cache: ResponseCache<Response>,
+    retry_counters: RetryCounters,
+    state: Mutex<State>,
+    requests_drained: Arc<Notify>,
+    last_activity: Mutex<Instant>,
+    tasks: TaskSupervisor,
+    // Lets upcalls running on dispatcher threads find their way back to the platform.
+    self_ref: OnceLock<Weak<JavaPlatform>>,
 }
 
 impl JavaPlatform {
     /// Creates JavaPlatform and associates with unique handle id
-    pub fn create(
-        java_platform_native: JObject<'_>,
-    ) -> Result<Arc<Mutex<impl Platform>>, JNIError> {
-        let platform_handle = generate_platform_handle();
-        let platform = Arc::new(Mutex::new(JavaPlatform::new(
-            platform_handle,
-            unique_jvm::get_static_ref().ok_or(JNIError::InvalidCtorReturn)?,
-            java_platform_native,
-        )?));
-        insert_platform_handle(platform_handle, Arc::clone(&platform));
-        Ok(Arc::clone(&platform))
+    pub fn create(java_platform_native: JObject<'_>) -> anyhow::Result<BoxedPlatform> {
+        Ok(JavaPlatformBuilder::new().build(java_platform_native)?)
+    }
+
+    /// Returns the handle Java uses to refer to this platform.
+    pub fn handle(&self) -> PlatformHandle {
+        self.platform_handle
+    }
+
+    /// Returns the current lifecycle state.
+    pub fn state(&self) -> State {
+        *self.state.lock().unwrap()
+    }
+
+    /// Moves to `next`, returning false if the lifecycle does not allow it.
+    fn set_state(&self, next: State) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if *state == next {
+            return true;
+        }
+        if !state.can_transition_to(next) {
+            warn!(
+                "{} platform {}: invalid transition {:?} -> {:?}",
+                function_name!(),
+                self.log_tag,
+                *state,
+                next
+            );
+            return false;
+        }
+        debug!("{} platform {}: {:?} -> {:?}", function_name!(), self.log_tag, *state, next);
+        *state = next;
+        // Pipelined requests waiting for room give up.
+        self.window_released.notify_waiters();
+        if next == State::Closed {
+            self.tasks.shutdown();
+            // Dropping the senders ends the message streams.
+            self.subscribers.lock().unwrap().clear();
+        }
+        true
+    }
+
+    /// Spawns a task scoped to this platform: it is aborted when the platform closes.
+    ///
+    /// The task must not hold a strong reference to the platform, or the platform would only be
+    /// released once the task completes.
+    pub fn spawn_task<F>(&self, task: F) -> bool
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task).is_some()
+    }
+
+    /// Returns the counts of retried requests.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.retry_counters.stats()
+    }
+
+    /// Returns the round-trip times measured by pinging `connection_id`, or None if no ping was
+    /// answered on it yet.
+    pub fn rtt_stats(&self, connection_id: i32) -> Option<RttStats> {
+        self.connection_quality.stats(connection_id)
+    }
+
+    /// Returns the capacity of the channels handing streamed response chunks and inbound messages
+    /// over to readers.
+    pub fn response_channel_capacity(&self) -> usize {
+        self.options.response_channel_capacity
     }
 
     fn new(
-        platform_handle: i64,
+        platform_handle: PlatformHandle,
         vm: &'static Arc<JavaVM>,
         java_platform_native: JObject,
+        options: PlatformOptions,
     ) -> Result<JavaPlatform, JNIError> {
-        vm.attach_current_thread().and_then(|env| {
-            let platform_class = env.get_object_class(java_platform_native)?;
-            let platform_native_obj = env.new_global_ref(java_platform_native)?;
-            let send_request_method: JMethodID =
-                env.get_method_id(platform_class, SEND_REQUEST_MNAME, SEND_REQUEST_MSIG)?;
+        let log_tag = match &options.log_tag_suffix {
+            Some(suffix) => format!("{}/{}", platform_handle, suffix),
+            None => platform_handle.to_string(),
+        };
+        attached_env(vm).and_then(|env| {
+            if let Some(cache) = get_jni_cache() {
+                if !env.is_instance_of(java_platform_native, cache.platform_class.as_obj())? {
+                    error!(
+                        "{} platform {} has an unexpected class",
+                        function_name!(),
+                        platform_handle
+                    );
+                    return Err(JNIError::InvalidCtorReturn);
+                }
+            }
+            let platform_native_obj =
+                PlatformRef::new(&env, java_platform_native, options.weak_platform_ref)?;
+            // Method IDs are resolved once in JNI_OnLoad; only look them up here if the library
+            // was loaded without it (e.g. from a native test harness).
+            let methods = match get_jni_cache() {
+                Some(cache) => cache.platform_methods,
+                None => {
+                    let platform_class = env.get_object_class(java_platform_native)?;
+                    PlatformMethods::resolve(&env, platform_class)?
+                }
+            };
+            let connection_limiter = if options.ordered {
+                ConnectionLimiter::ordered()
+            } else {
+                let window = Some(config::snapshot().pipeline_window).filter(|window| *window > 0);
+                ConnectionLimiter::new(
+                    options.max_requests_per_connection.or(window),
+                    options.backpressure,
+                )
+            };
 
             Ok(Self {
                 platform_handle,
+                log_tag,
+                options,
                 vm,
-                platform_native_obj,
-                send_request_method_id: send_request_method,
+                platform_native_obj: ManuallyDrop::new(platform_native_obj),
+                methods,
                 map_futures: Mutex::new(HashMap::new()),
-                atomic_handle: AtomicI64::new(0),
+                connection_limiter,
+                window_released: Notify::new(),
+                response_handles: HandleAllocator::new(),
+                subscribers: Mutex::new(HashMap::new()),
+                connection_operations: Mutex::new(HashMap::new()),
+                keystore_operations: Mutex::new(HashMap::new()),
+                operation_handles: HandleAllocator::new(),
+                connection_events: broadcast::channel(CONNECTION_EVENTS_CAPACITY).0,
+                connection_quality: ConnectionQualityTracker::default(),
+                mtus: Mutex::new(HashMap::new()),
+                max_message_sizes: Mutex::new(HashMap::new()),
+                response_cache: ResponseCache::default(),
+                retry_counters: RetryCounters::default(),
+                state: Mutex::new(State::Created),
+                requests_drained: Arc::new(Notify::new()),
+                last_activity: Mutex::new(Instant::now()),
+                tasks: TaskSupervisor::new(),
+                self_ref: OnceLock::new(),
             })
         })
     }
+
+    /// Stops accepting new requests and waits until `deadline` for outstanding ones to complete.
+    ///
+    /// Requests still pending at the deadline are failed with `ShutdownInProgress`. Returns the
+    /// number of requests that had to be failed.
+    pub async fn shutdown(&self, deadline: Instant) -> usize {
+        self.set_state(State::ShuttingDown);
+        info!("{} shutting down platform {}", function_name!(), self.log_tag);
+        loop {
+            let drained = self.requests_drained.notified();
+            if self.map_futures.lock().unwrap().is_empty() {
+                break;
+            }
+            if tokio::time::timeout_at(deadline.into(), drained).await.is_err() {
+                break;
+            }
+        }
+        let pending = self.map_futures.lock().unwrap().len();
+        self.fail_pending_requests(PlatformError::ShutdownInProgress);
+        self.set_state(State::Closed);
+        pending
+    }
 }
 
+#[async_trait]
 impl Platform for JavaPlatform {
     fn send_request(
-        &mut self,
+        &self,
         connection_id: i32,
         request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
         callback: Box<dyn ResponseCallback + Send>,
-    ) -> anyhow::Result<()> {
-        let type_signature = TypeSignature::from_str(SEND_REQUEST_MSIG)
-            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+    ) -> anyhow::Result<ResponseHandle> {
+        self.send_request_vectored(connection_id, &[request], metadata, timeout, callback)
+    }
 
-        let response_handle = self.atomic_handle.fetch_add(1, Ordering::SeqCst);
-        self.map_futures.lock().unwrap().insert(response_handle, callback);
-        self.vm
-            .attach_current_thread()
-            .and_then(|env| {
-                let request_jbytearray = env.byte_array_from_slice(request)?;
-                // Safety: request_jbytearray is safely instantiated above.
-                let request_jobject = unsafe { JObject::from_raw(request_jbytearray) };
-
-                let _ = env.call_method_unchecked(
-                    self.platform_native_obj.as_obj(),
-                    self.send_request_method_id,
-                    type_signature.ret,
-                    &[
-                        jvalue::from(JValue::Int(connection_id)),
-                        jvalue::from(JValue::Object(request_jobject)),
-                        jvalue::from(JValue::Long(response_handle)),
-                        jvalue::from(JValue::Long(self.platform_handle)),
-                    ],
-                );
-                Ok(info!(
-                    "{} successfully sent-message, waiting for response {}:{}",
+    fn send_request_vectored(
+        &self,
+        connection_id: i32,
+        bufs: &[&[u8]],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+        callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ResponseHandle> {
+        let (outgoing, priority) =
+            match self.prepare_request(connection_id, bufs, metadata, timeout, callback)? {
+                Prepared::Answered(response_handle) => return Ok(response_handle),
+                Prepared::Outgoing(outgoing, priority) => (outgoing, priority),
+            };
+        let (response_handle, trace_id) = (outgoing.response_handle, outgoing.trace_id);
+        let outgoing = match self.connection_limiter.admit(connection_id, priority, outgoing) {
+            Admission::Granted(outgoing) => outgoing,
+            Admission::Queued => {
+                debug!(
+                    "{} {}:{} [trace {}]: connection {} is busy or paused, request queued at {:?}",
                     function_name!(),
-                    self.platform_handle,
-                    response_handle
-                ))
-            })
-            .map_err(|e| anyhow!("JNI: Failed to attach current thread: {:?}", e))?;
-        Ok(())
+                    self.log_tag,
+                    response_handle,
+                    trace_id,
+                    connection_id,
+                    priority
+                );
+                return Ok(response_handle);
+            }
+            Admission::Busy(_) => return Err(PlatformError::Busy.into()),
+        };
+        self.send_admitted(outgoing)
     }
-}
 
-impl JavaPlatform {
-    fn on_send_request_success(&mut self, response: &[u8], response_handle: i64) {
-        info!(
-            "{} completed successfully {}:{}",
-            function_name!(),
-            self.platform_handle,
-            response_handle
-        );
-        if let Some(mut callback) = self.map_futures.lock().unwrap().remove(&response_handle) {
-            callback.on_response(response.to_vec());
-        } else {
+    async fn send_request_pipelined(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+        callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ResponseHandle> {
+        let mut outgoing =
+            match self.prepare_request(connection_id, &[request], metadata, timeout, callback)? {
+                Prepared::Answered(response_handle) => return Ok(response_handle),
+                Prepared::Outgoing(outgoing, _) => outgoing,
+            };
+        loop {
+            // Registered before trying, so that a slot freed in between is not missed.
+            let mut released = pin!(self.window_released.notified());
+            released.as_mut().enable();
+            outgoing = match self.connection_limiter.try_admit(connection_id, outgoing) {
+                Ok(outgoing) => break self.send_admitted(outgoing),
+                Err(outgoing) => outgoing,
+            };
+            let deadline = outgoing.deadline;
+            if tokio::time::timeout_at(deadline.into(), released).await.is_err() {
+                return Err(PlatformError::Timeout.into());
+            }
+            match self.state() {
+                State::Ready => {}
+                State::ShuttingDown => return Err(PlatformError::ShutdownInProgress.into()),
+                state => return Err(PlatformError::InvalidState(state).into()),
+            }
+            // The handle was not reserved while waiting, another request may have taken it.
+            outgoing.response_handle = self.allocate_response_handle()?;
+        }
+    }
+
+    fn send_request_streaming(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<ResponseStream> {
+        // One more slot than the capacity, for the error ending the stream if it overflows.
+        let (sender, receiver) = mpsc::channel(self.options.response_channel_capacity + 1);
+        let callback = Box::new(StreamCallback { sender, failed: false });
+        let response_handle =
+            self.send_request(connection_id, request, metadata, timeout, callback)?;
+        Ok(ResponseStream { response_handle, receiver })
+    }
+
+    fn send_notification(&self, connection_id: i32, payload: &[u8]) -> anyhow::Result<()> {
+        match self.state() {
+            State::Ready => {}
+            State::ShuttingDown => return Err(PlatformError::ShutdownInProgress.into()),
+            state => return Err(PlatformError::InvalidState(state).into()),
+        }
+        if payload.len() > self.max_message_size(connection_id) {
+            return Err(PlatformError::PayloadTooLarge(payload.len()).into());
+        }
+        self.touch();
+        let platform = self.weak_self();
+        let payload = payload.to_vec();
+        dispatch::submit(move |env| {
+            if let Some(platform) = platform.upgrade() {
+                if let Err(e) = platform.call_send_notification(env, connection_id, &payload) {
+                    error!(
+                        "{} {}: failed to notify connection {}: {:?}",
+                        function_name!(),
+                        platform.log_tag,
+                        connection_id,
+                        e
+                    );
+                }
+            }
+        })
+    }
+
+    fn subscribe(&self, connection_id: i32) -> anyhow::Result<MessageStream> {
+        if self.state() == State::Closed {
+            return Err(PlatformError::InvalidState(State::Closed).into());
+        }
+        let (sender, receiver) = mpsc::channel(self.options.response_channel_capacity);
+        self.subscribers.lock().unwrap().entry(connection_id).or_default().push(sender);
+        debug!("{} {}: subscribed to connection {}", function_name!(), self.log_tag, connection_id);
+        Ok(MessageStream { connection_id, receiver })
+    }
+
+    fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.connection_events.subscribe()
+    }
+
+    async fn get_connection_info(&self, connection_id: i32) -> anyhow::Result<ConnectionInfo> {
+        if self.state() == State::Closed {
+            return Err(PlatformError::InvalidState(State::Closed).into());
+        }
+        let (sender, receiver) = oneshot::channel();
+        let platform = self.weak_self();
+        dispatch::submit(move |env| {
+            let info = match platform.upgrade() {
+                Some(platform) => platform.call_get_connection_info(env, connection_id),
+                None => Err(PlatformError::PlatformDestroyed.into()),
+            };
+            let _ = sender.send(info);
+        })?;
+        // The upcall is dropped without running if the dispatcher goes away.
+        let info = receiver.await.map_err(|_| PlatformError::PlatformDestroyed)??;
+        self.mtus.lock().unwrap().insert(connection_id, info.mtu);
+        Ok(info)
+    }
+
+    fn max_payload(&self, connection_id: i32) -> Option<usize> {
+        self.mtus.lock().unwrap().get(&connection_id).copied()
+    }
+
+    fn set_max_message_size(&self, connection_id: i32, max_len: usize) {
+        self.max_message_sizes.lock().unwrap().insert(connection_id, max_len);
+    }
+
+    async fn open_connection(&self, device_id: &str) -> anyhow::Result<i32> {
+        let device_id = device_id.to_string();
+        self.run_connection_operation(move |platform, env, operation_handle| {
+            platform.call_open_connection(env, &device_id, operation_handle)
+        })
+        .await
+    }
+
+    async fn ping(&self, connection_id: i32) -> anyhow::Result<Duration> {
+        let (sender, receiver) = oneshot::channel();
+        let callback = Box::new(OneshotCallback { sender: Some(sender) });
+        // A retried ping would measure the backoff too.
+        let metadata = RequestMetadata::new().with_retry_policy(RetryPolicy::none());
+        let sent = self.send_request(connection_id, PING_REQUEST, metadata, None, callback);
+        if let Err(e) = sent {
+            self.connection_quality.record_failure(connection_id);
+            return Err(e);
+        }
+        match receiver.await {
+            Ok(Ok(response)) => {
+                self.connection_quality.record_rtt(connection_id, response.latency);
+                Ok(response.latency)
+            }
+            Ok(Err(error_code)) => {
+                self.connection_quality.record_failure(connection_id);
+                Err(anyhow!("ping of connection {} failed with {}", connection_id, error_code))
+            }
+            Err(_) => Err(PlatformError::PlatformDestroyed.into()),
+        }
+    }
+
+    fn pause(&self, connection_id: i32) {
+        info!("{} {}: pausing connection {}", function_name!(), self.log_tag, connection_id);
+        self.connection_limiter.pause(connection_id);
+    }
+
+    fn resume(&self, connection_id: i32) {
+        if !self.connection_limiter.is_paused(connection_id) {
+            return;
+        }
+        info!("{} {}: resuming connection {}", function_name!(), self.log_tag, connection_id);
+        self.window_released.notify_waiters();
+        for next in self.connection_limiter.resume(connection_id) {
+            if let Err((e, mut callback)) = self.dispatch(next) {
+                error!(
+                    "{} {}: failed to send queued request on connection {}: {:?}",
+                    function_name!(),
+                    self.log_tag,
+                    connection_id,
+                    e
+                );
+                callback.on_error(
+                    e.downcast_ref::<PlatformError>()
+                        .unwrap_or(&PlatformError::SendFailed)
+                        .error_code(),
+                );
+                self.release_connection_slot(connection_id);
+            }
+        }
+    }
+
+    async fn close_connection(&self, connection_id: i32) -> anyhow::Result<()> {
+        self.run_connection_operation(move |platform, env, operation_handle| {
+            platform.call_close_connection(env, connection_id, operation_handle)
+        })
+        .await?;
+        Ok(())
+    }
+
+    fn cancel_request(
+        &self,
+        connection_id: i32,
+        response_handle: ResponseHandle,
+    ) -> anyhow::Result<()> {
+        let sent = self
+            .map_futures
+            .lock()
+            .unwrap()
+            .get(&response_handle)
+            .is_some_and(|pending| pending.connection_id == connection_id);
+        // The response may arrive between the lookup and the removal, completing the request.
+        if let Some(mut pending) = sent.then(|| self.take_pending(response_handle)).flatten() {
+            info!(
+                "{} cancelled request {}:{} [trace {}] on connection {}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                connection_id
+            );
+            pending.callback.on_error(PlatformError::Cancelled.error_code());
+            self.release_connection_slot(connection_id);
+            self.submit_cancel_request(connection_id, response_handle, pending.trace_id);
+            return Ok(());
+        }
+        // A queued request never reached Java: there is nothing to tell the remote side.
+        let queued = self.connection_limiter.take_queued_if(|queued| {
+            queued.connection_id == connection_id && queued.response_handle == response_handle
+        });
+        if queued.is_empty() {
+            return Err(PlatformError::InvalidHandle(response_handle.as_jlong()).into());
+        }
+        for mut queued in queued {
+            info!(
+                "{} cancelled queued request {}:{} [trace {}] on connection {}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                queued.trace_id,
+                connection_id
+            );
+            queued.callback.on_error(PlatformError::Cancelled.error_code());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Keystore for JavaPlatform {
+    async fn sign_with_device_key(&self, alias: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (alias, data) = (alias.to_string(), data.to_vec());
+        self.run_keystore_operation(move |platform, env, operation_handle| {
+            platform.call_sign_with_device_key(env, &alias, &data, operation_handle)
+        })
+        .await
+    }
+
+    async fn get_device_public_key(&self, alias: &str) -> anyhow::Result<Vec<u8>> {
+        let alias = alias.to_string();
+        self.run_keystore_operation(move |platform, env, operation_handle| {
+            platform.call_get_device_public_key(env, &alias, operation_handle)
+        })
+        .await
+    }
+
+    async fn generate_key_pair(&self, alias: &str, strongbox: bool) -> anyhow::Result<Vec<u8>> {
+        let alias = alias.to_string();
+        self.run_keystore_operation(move |platform, env, operation_handle| {
+            platform.call_generate_key_pair(env, &alias, strongbox, operation_handle)
+        })
+        .await
+    }
+
+    async fn derive_backup_secret(&self, salt: &[u8]) -> anyhow::Result<Secret<Vec<u8>>> {
+        let salt = salt.to_vec();
+        let secret = self
+            .run_keystore_operation(move |platform, env, operation_handle| {
+                platform.call_derive_backup_secret(env, &salt, operation_handle)
+            })
+            .await?;
+        Ok(Secret::new(secret))
+    }
+}
+
+/// Java encrypts records with a Keystore key before persisting them, and completes the upcalls
+/// like keystore operations, with an empty result.
+#[async_trait]
+impl EnrollmentStore for JavaPlatform {
+    async fn store_enrollment(&self, device_id: &str, record: &[u8]) -> anyhow::Result<()> {
+        let (device_id, record) = (device_id.to_string(), record.to_vec());
+        self.run_keystore_operation(move |platform, env, operation_handle| {
+            platform.call_store_enrollment(env, &device_id, &record, operation_handle)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_enrollment(&self, device_id: &str) -> anyhow::Result<()> {
+        let device_id = device_id.to_string();
+        self.run_keystore_operation(move |platform, env, operation_handle| {
+            platform.call_delete_enrollment(env, &device_id, operation_handle)
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+impl JavaPlatform {
+    /// Queues a request for Java. On failure, the callback is handed back with the error.
+    fn dispatch(
+        &self,
+        outgoing: OutgoingRequest,
+    ) -> Result<(), (anyhow::Error, Box<dyn ResponseCallback + Send>)> {
+        let OutgoingRequest {
+            connection_id,
+            response_handle,
+            trace_id,
+            idempotency_key,
+            retry_policy,
+            request,
+            send_by,
+            deadline,
+            callback,
+            ..
+        } = outgoing;
+        if is_past(send_by) {
+            return Err((PlatformError::ExpiredBeforeSend.into(), callback));
+        }
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => return Err((PlatformError::Timeout.into(), callback)),
+        };
+        let request = Arc::new(request);
+        let streaming = callback.is_streaming();
+        self.map_futures.lock().unwrap().insert(
+            response_handle,
+            PendingRequest {
+                connection_id,
+                trace_id,
+                idempotency_key,
+                request: Arc::clone(&request),
+                retry_policy,
+                attempt: 1,
+                send_by,
+                sent_at: Instant::now(),
+                deadline,
+                callback: SharedCallback::new(callback),
+                streaming,
+                buffered: Vec::new(),
+                timer: None,
+            },
+        );
+        let submitted = self.submit_send_request(
+            connection_id,
+            request,
+            response_handle,
+            trace_id,
+            send_by,
+            remaining,
+        );
+        if let Err(e) = submitted {
+            return match self.take_pending(response_handle) {
+                Some(pending) => Err((e, Box::new(pending.callback))),
+                None => Ok(()),
+            };
+        }
+        let platform = self.weak_self();
+        let timer = self.tasks.spawn(async move {
+            tokio::time::sleep_until(deadline.into()).await;
+            if let Some(platform) = platform.upgrade() {
+                platform.on_request_timeout(response_handle);
+            }
+        });
+        // The response may have arrived already, in which case the timer finds nothing to fail.
+        if let Some(pending) = self.map_futures.lock().unwrap().get_mut(&response_handle) {
+            pending.timer = timer;
+        }
+        Ok(())
+    }
+
+    /// Queues the upcall sending an attempt of a pending request to Java.
+    fn submit_send_request(
+        &self,
+        connection_id: i32,
+        request: Arc<Vec<u8>>,
+        response_handle: ResponseHandle,
+        trace_id: TraceId,
+        send_by: Option<Instant>,
+        remaining: Duration,
+    ) -> anyhow::Result<()> {
+        let platform = self.weak_self();
+        dispatch::submit(move |env| {
+            // A platform destroyed while the upcall was queued has failed the request already.
+            let Some(platform) = platform.upgrade() else {
+                return;
+            };
+            // The dispatch queue may have held the upcall past the send-by deadline.
+            if is_past(send_by) {
+                platform.expire_before_send(response_handle);
+            } else {
+                platform.upcall_send_request(
+                    env,
+                    connection_id,
+                    &request,
+                    response_handle,
+                    trace_id,
+                    remaining,
+                );
+            }
+        })
+    }
+
+    /// Schedules another attempt of a request that failed with `error_code`, if its retry policy
+    /// allows it and the backoff ends before its deadline. The request stays pending meanwhile,
+    /// keeping its handle and the slot of its connection.
+    fn retry_request(&self, error_code: i32, response_handle: ResponseHandle) -> bool {
+        let mut map_futures = self.map_futures.lock().unwrap();
+        let Some(pending) = map_futures.get_mut(&response_handle) else {
+            return false;
+        };
+        if !pending.retry_policy.should_retry(pending.attempt, error_code) {
+            return false;
+        }
+        let backoff = pending.retry_policy.backoff(pending.attempt);
+        if Instant::now() + backoff >= pending.deadline {
+            return false;
+        }
+        warn!(
+            "{} {}:{} [trace {}]: attempt {} failed with {}, retrying in {:?}",
+            function_name!(),
+            self.log_tag,
+            response_handle,
+            pending.trace_id,
+            pending.attempt,
+            error_code,
+            backoff
+        );
+        pending.attempt += 1;
+        let attempt = pending.attempt;
+        drop(map_futures);
+        self.retry_counters.record_retry();
+        let platform = self.weak_self();
+        self.tasks
+            .spawn(async move {
+                tokio::time::sleep(backoff).await;
+                if let Some(platform) = platform.upgrade() {
+                    platform.resend(response_handle, attempt);
+                }
+            })
+            .is_some()
+    }
+
+    /// Sends attempt `attempt` of a pending request, unless it completed in the meantime.
+    fn resend(&self, response_handle: ResponseHandle, attempt: u32) {
+        let (connection_id, request, trace_id, send_by, deadline) = {
+            let mut map_futures = self.map_futures.lock().unwrap();
+            let Some(pending) =
+                map_futures.get_mut(&response_handle).filter(|pending| pending.attempt == attempt)
+            else {
+                return;
+            };
+            pending.sent_at = Instant::now();
+            (
+                pending.connection_id,
+                Arc::clone(&pending.request),
+                pending.trace_id,
+                pending.send_by,
+                pending.deadline,
+            )
+        };
+        // Past the deadline, the timer fails the request.
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => return,
+        };
+        info!(
+            "{} {}:{} [trace {}]: sending attempt {}",
+            function_name!(),
+            self.log_tag,
+            response_handle,
+            trace_id,
+            attempt
+        );
+        let submitted = self.submit_send_request(
+            connection_id,
+            request,
+            response_handle,
+            trace_id,
+            send_by,
+            remaining,
+        );
+        if let Err(e) = submitted {
+            if let Some(mut pending) = self.take_pending(response_handle) {
+                self.retry_counters.record_outcome(pending.attempt, false);
+                pending.callback.on_error(
+                    e.downcast_ref::<PlatformError>()
+                        .unwrap_or(&PlatformError::SendFailed)
+                        .error_code(),
+                );
+                self.release_connection_slot(pending.connection_id);
+            }
+        }
+    }
+
+    /// Sends a request that got a slot of its connection, freeing the slot if that fails.
+    fn send_admitted(&self, outgoing: OutgoingRequest) -> anyhow::Result<ResponseHandle> {
+        let (connection_id, response_handle) = (outgoing.connection_id, outgoing.response_handle);
+        self.dispatch(outgoing).map_err(|(e, _)| {
+            self.release_connection_slot(connection_id);
+            e
+        })?;
+        Ok(response_handle)
+    }
+
+    /// Checks and builds a new request, unless it can be answered from the response cache.
+    fn prepare_request(
+        &self,
+        connection_id: i32,
+        bufs: &[&[u8]],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+        callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<Prepared> {
+        let RequestMetadata {
+            trace_id,
+            deadline,
+            send_by,
+            priority,
+            idempotency_key,
+            retry_policy,
+        } = metadata;
+        match self.state() {
+            State::Ready => {}
+            State::ShuttingDown => return Err(PlatformError::ShutdownInProgress.into()),
+            state => return Err(PlatformError::InvalidState(state).into()),
+        }
+        if let Some(key) = idempotency_key {
+            if let Some(response) = self.response_cache.get(connection_id, key) {
+                return self
+                    .respond_from_cache(connection_id, key, trace_id, response, callback)
+                    .map(Prepared::Answered);
+            }
+        }
+        if let Some(max) = self.options.max_concurrent_requests {
+            if self.map_futures.lock().unwrap().len() >= max {
+                return Err(PlatformError::TooManyPendingRequests.into());
+            }
+        }
+        let request_len = vectored_len(bufs);
+        if request_len > self.max_message_size(connection_id) {
+            return Err(PlatformError::PayloadTooLarge(request_len).into());
+        }
+        let deadline = match deadline {
+            Some(deadline_millis) => match remaining_until_elapsed_realtime(deadline_millis) {
+                Some(remaining) => Some(Instant::now() + remaining),
+                None => {
+                    debug!(
+                        "{} {} [trace {}]: deadline already passed",
+                        function_name!(),
+                        self.log_tag,
+                        trace_id
+                    );
+                    return Err(PlatformError::Timeout.into());
+                }
+            },
+            None => None,
+        };
+        let send_by = match send_by {
+            Some(send_by_millis) => match remaining_until_elapsed_realtime(send_by_millis) {
+                Some(remaining) => Some(Instant::now() + remaining),
+                None => return Err(PlatformError::ExpiredBeforeSend.into()),
+            },
+            None => None,
+        };
+        let timeout = timeout
+            .or(self.options.request_timeout)
+            .unwrap_or_else(|| config::snapshot().default_timeout);
+        let timeout_deadline = Instant::now() + timeout;
+        let deadline = deadline.map_or(timeout_deadline, |deadline| deadline.min(timeout_deadline));
+        self.touch();
+        let outgoing = OutgoingRequest {
+            connection_id,
+            response_handle: self.allocate_response_handle()?,
+            trace_id,
+            idempotency_key,
+            retry_policy: retry_policy.or(self.options.retry_policy).unwrap_or_else(|| {
+                RetryPolicy::new(config::snapshot().max_retries.saturating_add(1))
+            }),
+            request: gather(bufs, request_len),
+            created_at: Instant::now(),
+            send_by,
+            deadline,
+            callback,
+        };
+        Ok(Prepared::Outgoing(outgoing, priority))
+    }
+
+    /// Answers a duplicate of a completed request with its cached response, from a task so that
+    /// the callback doesn't run within `send_request`.
+    fn respond_from_cache(
+        &self,
+        connection_id: i32,
+        key: IdempotencyKey,
+        trace_id: TraceId,
+        response: Response,
+        mut callback: Box<dyn ResponseCallback + Send>,
+    ) -> anyhow::Result<ResponseHandle> {
+        self.touch();
+        let response_handle = self.allocate_response_handle()?;
+        info!(
+            "{} {}:{} [trace {}]: answering key {} on connection {} from the cache",
+            function_name!(),
+            self.log_tag,
+            response_handle,
+            trace_id,
+            key,
+            connection_id
+        );
+        self.tasks
+            .spawn(async move { callback.on_response(response) })
+            .ok_or(PlatformError::ShutdownInProgress)?;
+        Ok(response_handle)
+    }
+
+    /// Caches the response of a completed request if it has an idempotency key.
+    fn cache_response(&self, pending: &PendingRequest, response: &Response) {
+        if let Some(key) = pending.idempotency_key {
+            self.response_cache.insert(pending.connection_id, key, response.clone());
+        }
+    }
+
+    /// Allocates the handle of a new request, skipping those of sent and queued requests.
+    fn allocate_response_handle(&self) -> Result<ResponseHandle, PlatformError> {
+        let map_futures = self.map_futures.lock().unwrap();
+        let queued = self.connection_limiter.queued_len();
+        self.response_handles
+            .allocate(map_futures.len() + queued, |value| {
+                let response_handle = ResponseHandle::new(value);
+                map_futures.contains_key(&response_handle)
+                    || (queued > 0
+                        && self
+                            .connection_limiter
+                            .any_queued(|queued| queued.response_handle == response_handle))
+            })
+            .map(ResponseHandle::new)
+    }
+
+    fn weak_self(&self) -> Weak<JavaPlatform> {
+        self.self_ref.get().cloned().unwrap_or_default()
+    }
+
+    /// Returns the largest message that may be sent or received on `connection_id`: the configured
+    /// maximum, lowered by the one set for the connection if any.
+    fn max_message_size(&self, connection_id: i32) -> usize {
+        let max = config::snapshot().max_payload_size;
+        match self.max_message_sizes.lock().unwrap().get(&connection_id) {
+            Some(&connection_max) => max.min(connection_max),
+            None => max,
+        }
+    }
+
+    /// Removes a pending request, stopping its timer.
+    fn take_pending(&self, response_handle: ResponseHandle) -> Option<PendingRequest> {
+        let pending = self.map_futures.lock().unwrap().remove(&response_handle);
+        self.notify_if_drained();
+        if let Some(timer) = pending.as_ref().and_then(|pending| pending.timer.as_ref()) {
+            timer.abort();
+        }
+        pending
+    }
+
+    /// Fails a request whose timer fired before its response arrived.
+    fn on_request_timeout(&self, response_handle: ResponseHandle) {
+        if let Some(pending) = self.take_pending(response_handle) {
+            warn!(
+                "{} request {}:{} [trace {}] on connection {} timed out after {:?}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                pending.connection_id,
+                pending.sent_at.elapsed()
+            );
+            self.time_out_request(response_handle, pending);
+        }
+    }
+
+    /// Fails a pending request whose upcall was about to run past its send-by deadline.
+    fn expire_before_send(&self, response_handle: ResponseHandle) {
+        if let Some(mut pending) = self.take_pending(response_handle) {
+            warn!(
+                "{} request {}:{} [trace {}] on connection {} expired before it was sent",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                pending.connection_id
+            );
+            self.retry_counters.record_outcome(pending.attempt, false);
+            pending.callback.on_error(PlatformError::ExpiredBeforeSend.error_code());
+            self.release_connection_slot(pending.connection_id);
+        }
+    }
+
+    /// Fails a request that got no response in time, and tells Java it was abandoned so that
+    /// the transport can stop waiting for it.
+    fn time_out_request(&self, response_handle: ResponseHandle, mut pending: PendingRequest) {
+        if let Some(timer) = pending.timer.take() {
+            timer.abort();
+        }
+        TIMED_OUT_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        pending.callback.on_error(PlatformError::Timeout.error_code());
+        self.release_connection_slot(pending.connection_id);
+        let trace_id = pending.trace_id;
+        let platform = self.weak_self();
+        let submitted = dispatch::submit(move |env| {
+            if let Some(platform) = platform.upgrade() {
+                if let Err(e) = platform.notify_request_timeout(env, response_handle) {
+                    error!(
+                        "{} {}:{} [trace {}]: {:?}",
+                        function_name!(),
+                        platform.log_tag,
+                        response_handle,
+                        trace_id,
+                        e
+                    );
+                }
+            }
+        });
+        if let Err(e) = submitted {
+            error!(
+                "{} {}:{} [trace {}]: {:?}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                trace_id,
+                e
+            );
+        }
+    }
+
+    fn call_send_notification(
+        &self,
+        env: &JNIEnv,
+        connection_id: i32,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(SEND_NOTIFICATION_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let payload_jbytearray = env.byte_array_from_slice(payload)?;
+        // Safety: payload_jbytearray is safely instantiated above.
+        let payload_jobject = unsafe { JObject::from_raw(payload_jbytearray) };
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.send_notification,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Int(connection_id)),
+                        jvalue::from(JValue::Object(payload_jobject)),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_get_connection_info(
+        &self,
+        env: &JNIEnv,
+        connection_id: i32,
+    ) -> anyhow::Result<ConnectionInfo> {
+        let type_signature = TypeSignature::from_str(GET_CONNECTION_INFO_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let values = self
+            .platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.get_connection_info,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Int(connection_id)),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??
+            .l()?;
+        if values.is_null() {
+            return Err(PlatformError::UnknownConnection(connection_id).into());
+        }
+        let values: jintArray = values.into_raw();
+        let mut buf = [0; CONNECTION_INFO_LEN];
+        let len = env.get_array_length(values)?;
+        if len as usize != CONNECTION_INFO_LEN {
+            return Err(anyhow!("JNI: connection info has {} values", len));
+        }
+        env.get_int_array_region(values, 0, &mut buf)?;
+        ConnectionInfo::from_java(&buf)
+            .ok_or_else(|| anyhow!("JNI: invalid connection info {:?}", buf))
+    }
+
+    /// Starts a connection operation with `upcall` on a dispatcher thread, and waits up to the
+    /// configured default timeout for Java to complete it.
+    async fn run_connection_operation(
+        &self,
+        upcall: impl FnOnce(&JavaPlatform, &JNIEnv, OperationHandle) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<i32> {
+        self.run_operation(|platform| &platform.connection_operations, upcall).await
+    }
+
+    /// Starts a keystore operation with `upcall` on a dispatcher thread, and waits up to the
+    /// configured default timeout for Java to complete it.
+    async fn run_keystore_operation(
+        &self,
+        upcall: impl FnOnce(&JavaPlatform, &JNIEnv, OperationHandle) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.run_operation(|platform| &platform.keystore_operations, upcall).await
+    }
+
+    /// Starts an operation with `upcall` on a dispatcher thread, registered in the `operations`
+    /// of the platform, and waits up to the configured default timeout for Java to complete it.
+    async fn run_operation<T: Send + 'static>(
+        &self,
+        operations: fn(&JavaPlatform) -> &Operations<T>,
+        upcall: impl FnOnce(&JavaPlatform, &JNIEnv, OperationHandle) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<T> {
+        match self.state() {
+            State::Ready => {}
+            State::ShuttingDown => return Err(PlatformError::ShutdownInProgress.into()),
+            state => return Err(PlatformError::InvalidState(state).into()),
+        }
+        let (sender, receiver) = oneshot::channel();
+        let operation_handle = {
+            let mut operations = operations(self).lock().unwrap();
+            let operation_handle = OperationHandle::new(
+                self.operation_handles.allocate(operations.len(), |value| {
+                    operations.contains_key(&OperationHandle::new(value))
+                })?,
+            );
+            operations.insert(operation_handle, sender);
+            operation_handle
+        };
+        let platform = self.weak_self();
+        let submitted = dispatch::submit(move |env| {
+            if let Some(platform) = platform.upgrade() {
+                if let Err(e) = upcall(&platform, env, operation_handle) {
+                    error!(
+                        "{} {}: operation {} failed: {:?}",
+                        function_name!(),
+                        platform.log_tag,
+                        operation_handle,
+                        e
+                    );
+                    platform.complete_operation(
+                        operations(&platform),
+                        operation_handle,
+                        Err(PlatformError::SendFailed),
+                    );
+                }
+            }
+        });
+        if let Err(e) = submitted {
+            operations(self).lock().unwrap().remove(&operation_handle);
+            return Err(e);
+        }
+        let timeout = config::snapshot().default_timeout;
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => Ok(result?),
+            // The sender is dropped when the platform fails its operations on destruction.
+            Ok(Err(_)) => Err(PlatformError::PlatformDestroyed.into()),
+            Err(_) => {
+                operations(self).lock().unwrap().remove(&operation_handle);
+                Err(PlatformError::Timeout.into())
+            }
+        }
+    }
+
+    /// Hands the outcome of an operation of `operations` over to its caller.
+    fn complete_operation<T>(
+        &self,
+        operations: &Operations<T>,
+        operation_handle: OperationHandle,
+        result: Result<T, PlatformError>,
+    ) {
+        self.touch();
+        match operations.lock().unwrap().remove(&operation_handle) {
+            Some(sender) => {
+                let _ = sender.send(result);
+            }
+            None => error!(
+                "Failed to find operation for {} and {}:{}",
+                function_name!(),
+                self.log_tag,
+                operation_handle
+            ),
+        }
+    }
+
+    fn call_open_connection(
+        &self,
+        env: &JNIEnv,
+        device_id: &str,
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(OPEN_CONNECTION_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let device_id = env.new_string(device_id)?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.open_connection,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(device_id.into())),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_close_connection(
+        &self,
+        env: &JNIEnv,
+        connection_id: i32,
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(CLOSE_CONNECTION_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.close_connection,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Int(connection_id)),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_sign_with_device_key(
+        &self,
+        env: &JNIEnv,
+        alias: &str,
+        data: &[u8],
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(SIGN_WITH_DEVICE_KEY_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let alias = env.new_string(alias)?;
+        let data_jbytearray = env.byte_array_from_slice(data)?;
+        // Safety: data_jbytearray is safely instantiated above.
+        let data_jobject = unsafe { JObject::from_raw(data_jbytearray) };
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.sign_with_device_key,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(alias.into())),
+                        jvalue::from(JValue::Object(data_jobject)),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_get_device_public_key(
+        &self,
+        env: &JNIEnv,
+        alias: &str,
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(GET_DEVICE_PUBLIC_KEY_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let alias = env.new_string(alias)?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.get_device_public_key,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(alias.into())),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_generate_key_pair(
+        &self,
+        env: &JNIEnv,
+        alias: &str,
+        strongbox: bool,
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(GENERATE_KEY_PAIR_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let alias = env.new_string(alias)?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.generate_key_pair,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(alias.into())),
+                        jvalue::from(JValue::Bool(strongbox.into())),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_store_enrollment(
+        &self,
+        env: &JNIEnv,
+        device_id: &str,
+        record: &[u8],
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(STORE_ENROLLMENT_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let device_id = env.new_string(device_id)?;
+        let record_jbytearray = env.byte_array_from_slice(record)?;
+        // Safety: record_jbytearray is safely instantiated above.
+        let record_jobject = unsafe { JObject::from_raw(record_jbytearray) };
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.store_enrollment,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(device_id.into())),
+                        jvalue::from(JValue::Object(record_jobject)),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_delete_enrollment(
+        &self,
+        env: &JNIEnv,
+        device_id: &str,
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(DELETE_ENROLLMENT_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let device_id = env.new_string(device_id)?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.delete_enrollment,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(device_id.into())),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_derive_backup_secret(
+        &self,
+        env: &JNIEnv,
+        salt: &[u8],
+        operation_handle: OperationHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(DERIVE_BACKUP_SECRET_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let salt_jbytearray = env.byte_array_from_slice(salt)?;
+        // Safety: salt_jbytearray is safely instantiated above.
+        let salt_jobject = unsafe { JObject::from_raw(salt_jbytearray) };
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.derive_backup_secret,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Object(salt_jobject)),
+                        jvalue::from(JValue::Long(operation_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    /// Tells Java a request was cancelled, from a dispatcher thread.
+    fn submit_cancel_request(
+        &self,
+        connection_id: i32,
+        response_handle: ResponseHandle,
+        trace_id: TraceId,
+    ) {
+        let platform = self.weak_self();
+        let submitted = dispatch::submit(move |env| {
+            if let Some(platform) = platform.upgrade() {
+                if let Err(e) = platform.call_cancel_request(env, connection_id, response_handle) {
+                    error!(
+                        "{} {}:{} [trace {}]: {:?}",
+                        function_name!(),
+                        platform.log_tag,
+                        response_handle,
+                        trace_id,
+                        e
+                    );
+                }
+            }
+        });
+        if let Err(e) = submitted {
+            error!(
+                "{} {}:{} [trace {}]: {:?}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                trace_id,
+                e
+            );
+        }
+    }
+
+    fn call_cancel_request(
+        &self,
+        env: &JNIEnv,
+        connection_id: i32,
+        response_handle: ResponseHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(CANCEL_REQUEST_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.cancel_request,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Int(connection_id)),
+                        jvalue::from(JValue::Long(response_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    /// Tells Java a request was abandoned after timing out, on a dispatcher thread.
+    fn notify_request_timeout(
+        &self,
+        env: &JNIEnv,
+        response_handle: ResponseHandle,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(ON_SEND_REQUEST_TIMEOUT_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.on_send_request_timeout,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Long(response_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    fn call_on_request_progress(
+        &self,
+        env: &JNIEnv,
+        response_handle: ResponseHandle,
+        trace_id: TraceId,
+        progress: Progress,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(ON_REQUEST_PROGRESS_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.on_request_progress,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Long(response_handle.as_jlong())),
+                        jvalue::from(JValue::Long(trace_id.as_jlong())),
+                        jvalue::from(JValue::Long(
+                            progress.bytes_sent.try_into().unwrap_or(jlong::MAX),
+                        )),
+                        jvalue::from(JValue::Long(progress.total.try_into().unwrap_or(jlong::MAX))),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    /// Calls Java `sendRequest`, on a dispatcher thread. Fails the request if the call fails.
+    fn upcall_send_request(
+        &self,
+        env: &JNIEnv,
+        connection_id: i32,
+        request: &[u8],
+        response_handle: ResponseHandle,
+        trace_id: TraceId,
+        remaining: Duration,
+    ) {
+        match self.call_send_request(
+            env,
+            connection_id,
+            request,
+            response_handle,
+            trace_id,
+            remaining,
+        ) {
+            Ok(()) => info!(
+                "{} successfully sent-message, waiting for response {}:{} [trace {}]",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                trace_id
+            ),
+            Err(e) => {
+                error!(
+                    "{} failed to send {}:{} [trace {}]: {:?}",
+                    function_name!(),
+                    self.log_tag,
+                    response_handle,
+                    trace_id,
+                    e
+                );
+                // A response may have raced the failure and completed the request already.
+                if let Some(mut pending) = self.take_pending(response_handle) {
+                    pending.callback.on_error(
+                        e.downcast_ref::<PlatformError>()
+                            .unwrap_or(&PlatformError::SendFailed)
+                            .error_code(),
+                    );
+                    self.release_connection_slot(pending.connection_id);
+                }
+            }
+        }
+    }
+
+    fn call_send_request(
+        &self,
+        env: &JNIEnv,
+        connection_id: i32,
+        request: &[u8],
+        response_handle: ResponseHandle,
+        trace_id: TraceId,
+        remaining: Duration,
+    ) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(SEND_REQUEST_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        let request_jbytearray = env.byte_array_from_slice(request)?;
+        // Safety: request_jbytearray is safely instantiated above.
+        let request_jobject = unsafe { JObject::from_raw(request_jbytearray) };
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.send_request,
+                    type_signature.ret,
+                    &[
+                        jvalue::from(JValue::Int(connection_id)),
+                        jvalue::from(JValue::Object(request_jobject)),
+                        jvalue::from(JValue::Long(response_handle.as_jlong())),
+                        jvalue::from(JValue::Long(self.platform_handle.as_jlong())),
+                        jvalue::from(JValue::Long(
+                            remaining.as_millis().try_into().unwrap_or(jlong::MAX),
+                        )),
+                        jvalue::from(JValue::Long(trace_id.as_jlong())),
+                    ],
+                )
+            })?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    /// Frees the slot of a completed request on `connection_id`, sending the next queued request
+    /// of the connection in its place.
+    fn release_connection_slot(&self, connection_id: i32) {
+        // A queued request that can't be sent frees the slot in turn for the one behind it.
+        // Pipelined requests look for room again either way.
+        self.window_released.notify_waiters();
+        while let Some(next) = self.connection_limiter.release(connection_id) {
+            match self.dispatch(next) {
+                Ok(()) => return,
+                Err((e, mut callback)) => {
+                    error!(
+                        "{} {}: failed to send queued request on connection {}: {:?}",
+                        function_name!(),
+                        self.log_tag,
+                        connection_id,
+                        e
+                    );
+                    callback.on_error(
+                        e.downcast_ref::<PlatformError>()
+                            .unwrap_or(&PlatformError::SendFailed)
+                            .error_code(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl JavaPlatform {
+    /// Completes a request with its response. Fails it instead with `ResponseTooLarge` if the
+    /// response is larger than its connection allows, returning the error for Java.
+    fn on_send_request_success(
+        &self,
+        response: &[u8],
+        transport: Option<Transport>,
+        remote_status: i32,
+        response_handle: ResponseHandle,
+    ) -> Result<(), PlatformError> {
+        self.touch();
+        if let Some(mut pending) = self.take_pending(response_handle) {
+            let max_len = self.max_message_size(pending.connection_id);
+            if response.len() > max_len {
+                let error = PlatformError::ResponseTooLarge(response.len());
+                warn!(
+                    "{} request {}:{} [trace {}] got {} bytes, more than {}",
+                    function_name!(),
+                    self.log_tag,
+                    response_handle,
+                    pending.trace_id,
+                    response.len(),
+                    max_len
+                );
+                self.retry_counters.record_outcome(pending.attempt, false);
+                pending.callback.on_error(error.error_code());
+                self.release_connection_slot(pending.connection_id);
+                return Err(error);
+            }
+            let latency = pending.sent_at.elapsed();
+            info!(
+                "{} completed successfully {}:{} [trace {}] in {:?} over {:?}, status {}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                latency,
+                transport,
+                remote_status
+            );
+            let response =
+                Response { payload: response.to_vec(), latency, transport, remote_status };
+            self.cache_response(&pending, &response);
+            self.retry_counters.record_outcome(pending.attempt, true);
+            pending.callback.on_response(response);
+            self.release_connection_slot(pending.connection_id);
+        } else {
+            error!(
+                "Failed to find TX for {} and {}:{}",
+                function_name!(),
+                self.log_tag,
+                response_handle
+            );
+        }
+        Ok(())
+    }
+
+    /// Hands a chunk of a response over to its callback, or buffers it. Fails the request instead
+    /// with `ResponseTooLarge` if the response is larger than its connection allows, returning the
+    /// error for Java.
+    fn on_send_request_chunk(
+        &self,
+        chunk: &[u8],
+        response_handle: ResponseHandle,
+    ) -> Result<(), PlatformError> {
+        self.touch();
+        debug!(
+            "{} received {} bytes for {}:{}",
+            function_name!(),
+            chunk.len(),
+            self.log_tag,
+            response_handle
+        );
+        let accepted = {
+            let mut map_futures = self.map_futures.lock().unwrap();
+            match map_futures.get_mut(&response_handle) {
+                Some(pending) => {
+                    let max_len = self.max_message_size(pending.connection_id);
+                    pending
+                        .accept_chunk(chunk, max_len)
+                        .map(|()| pending.streaming.then(|| pending.callback.clone()))
+                }
+                None => {
+                    error!(
+                        "Failed to find TX for {} and {}:{}",
+                        function_name!(),
+                        self.log_tag,
+                        response_handle
+                    );
+                    return Ok(());
+                }
+            }
+        };
+        match accepted {
+            // Called once the lock is released, so the callback may reach back into the platform.
+            Ok(Some(mut callback)) => callback.on_chunk(chunk.to_vec()),
+            Ok(None) => {}
+            Err(error) => {
+                self.fail_oversized_response(response_handle, error);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails a request whose response is too large, dropping what was buffered of it.
+    fn fail_oversized_response(&self, response_handle: ResponseHandle, error: PlatformError) {
+        if let Some(mut pending) = self.take_pending(response_handle) {
+            warn!(
+                "{} request {}:{} [trace {}] failed: {}, more than {}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                error,
+                self.max_message_size(pending.connection_id)
+            );
+            self.retry_counters.record_outcome(pending.attempt, false);
+            pending.callback.on_error(error.error_code());
+            self.release_connection_slot(pending.connection_id);
+        }
+    }
+
+    /// Hands a message pushed by the remote device over to the subscribers of its connection.
+    fn on_message_received(&self, connection_id: i32, message: &[u8]) {
+        self.touch();
+        let max_len = self.max_message_size(connection_id);
+        if message.len() > max_len {
+            warn!(
+                "{} {}: dropping {} bytes from connection {}, more than {}",
+                function_name!(),
+                self.log_tag,
+                message.len(),
+                connection_id,
+                max_len
+            );
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(senders) = subscribers.get_mut(&connection_id) else {
+            info!(
+                "{} {}: no subscriber on connection {}, dropping {} bytes",
+                function_name!(),
+                self.log_tag,
+                connection_id,
+                message.len()
+            );
+            return;
+        };
+        senders.retain(|sender| match sender.try_send(message.to_vec()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!(
+                    "{} {}: subscriber of connection {} is not keeping up, dropping message",
+                    function_name!(),
+                    self.log_tag,
+                    connection_id
+                );
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+        if senders.is_empty() {
+            subscribers.remove(&connection_id);
+        }
+    }
+
+    fn on_connection_state_changed(&self, event: ConnectionEvent) {
+        info!(
+            "{} {}: connection {} is {:?}",
+            function_name!(),
+            self.log_tag,
+            event.id,
+            event.state
+        );
+        match event.state {
+            ConnectionState::Connected => self.spawn_mtu_discovery(event.id),
+            ConnectionState::Disconnected => {
+                self.connection_quality.remove(event.id);
+                self.response_cache.remove(event.id);
+                self.mtus.lock().unwrap().remove(&event.id);
+                self.max_message_sizes.lock().unwrap().remove(&event.id);
+            }
+            ConnectionState::Degraded => {}
+        }
+        // Sending only fails when nobody listens.
+        let _ = self.connection_events.send(event);
+    }
+
+    /// Discovers the MTU of a new connection, so that it is known before the first message.
+    fn spawn_mtu_discovery(&self, connection_id: i32) {
+        let platform = self.weak_self();
+        let log_tag = self.log_tag.clone();
+        self.spawn_task(async move {
+            // Only held until the upcall of the query returns.
+            let Some(platform) = platform.upgrade() else {
+                return;
+            };
+            match platform.discover_mtu(connection_id).await {
+                Ok(mtu) => debug!(
+                    "{} {}: connection {} has an MTU of {}",
+                    function_name!(),
+                    log_tag,
+                    connection_id,
+                    mtu
+                ),
+                Err(e) => warn!(
+                    "{} {}: failed to discover the MTU of connection {}: {:?}",
+                    function_name!(),
+                    log_tag,
+                    connection_id,
+                    e
+                ),
+            }
+        });
+    }
+
+    fn on_send_request_progress(&self, progress: Progress, response_handle: ResponseHandle) {
+        self.touch();
+        let (mut callback, trace_id) = {
+            let map_futures = self.map_futures.lock().unwrap();
+            let Some(pending) = map_futures.get(&response_handle) else {
+                // Progress may trail the response of the request.
+                debug!(
+                    "{} no pending request {}:{}",
+                    function_name!(),
+                    self.log_tag,
+                    response_handle
+                );
+                return;
+            };
+            (pending.callback.clone(), pending.trace_id)
+        };
+        // Called once the lock is released, so the callback may reach back into the platform.
+        callback.on_progress(progress.bytes_sent, progress.total);
+        if !self.options.forward_progress {
+            return;
+        }
+        let platform = self.weak_self();
+        let submitted = dispatch::submit(move |env| {
+            if let Some(platform) = platform.upgrade() {
+                if let Err(e) =
+                    platform.call_on_request_progress(env, response_handle, trace_id, progress)
+                {
+                    error!(
+                        "{} {}:{} [trace {}]: {:?}",
+                        function_name!(),
+                        platform.log_tag,
+                        response_handle,
+                        trace_id,
+                        e
+                    );
+                }
+            }
+        });
+        // A later update supersedes a dropped one.
+        if let Err(e) = submitted {
+            debug!(
+                "{} {}:{} [trace {}]: dropping progress: {:?}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                trace_id,
+                e
+            );
+        }
+    }
+
+    /// Completes a streamed request. Fails it instead with `ResponseTooLarge` if the buffered
+    /// response is larger than its connection allows, e.g. after its maximum was lowered,
+    /// returning the error for Java.
+    fn on_send_request_complete(
+        &self,
+        response_handle: ResponseHandle,
+    ) -> Result<(), PlatformError> {
+        self.touch();
+        let oversized =
+            self.map_futures.lock().unwrap().get(&response_handle).and_then(|pending| {
+                let len = pending.buffered.len();
+                (len > self.max_message_size(pending.connection_id))
+                    .then_some(PlatformError::ResponseTooLarge(len))
+            });
+        if let Some(error) = oversized {
+            self.fail_oversized_response(response_handle, error);
+            return Err(error);
+        }
+        if let Some(mut pending) = self.take_pending(response_handle) {
+            info!(
+                "{} completed stream {}:{} [trace {}]",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id
+            );
+            // Dropping a streaming callback ends its stream. Streamed responses are not cached,
+            // their chunks are gone.
+            if !pending.streaming {
+                let response = Response {
+                    payload: std::mem::take(&mut pending.buffered),
+                    latency: pending.sent_at.elapsed(),
+                    transport: None,
+                    remote_status: 0,
+                };
+                self.cache_response(&pending, &response);
+                pending.callback.on_response(response);
+            }
+            self.retry_counters.record_outcome(pending.attempt, true);
+            self.release_connection_slot(pending.connection_id);
+        } else {
             error!(
                 "Failed to find TX for {} and {}:{}",
                 function_name!(),
-                self.platform_handle,
+                self.log_tag,
+                response_handle
+            );
+        }
+        Ok(())
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Whether the Java platform object was collected. Always false for a strong reference.
+    fn is_java_object_gone(&self) -> bool {
+        if !self.platform_native_obj.is_weak() {
+            return false;
+        }
+        matches!(
+            attached_env(self.vm)
+                .and_then(|env| self.platform_native_obj.with_object(&env, |_| ())),
+            Ok(None)
+        )
+    }
+
+    /// Returns why the platform looks leaked by its owner, or None if it is still in use.
+    fn stale_reason(&self, now: Instant, idle_threshold: Duration) -> Option<&'static str> {
+        if self.is_java_object_gone() {
+            Some("Java object collected")
+        } else if self.state() != State::Ready && self.map_futures.lock().unwrap().is_empty() {
+            Some("shut down but never destroyed")
+        } else if now.saturating_duration_since(*self.last_activity.lock().unwrap())
+            >= idle_threshold
+        {
+            Some("idle")
+        } else {
+            None
+        }
+    }
+
+    fn notify_if_drained(&self) {
+        if self.map_futures.lock().unwrap().is_empty() {
+            self.requests_drained.notify_waiters();
+        }
+    }
+
+    fn fail_pending_requests(&self, error: PlatformError) {
+        let operations: Vec<_> = self.connection_operations.lock().unwrap().drain().collect();
+        for (_, sender) in operations {
+            let _ = sender.send(Err(error));
+        }
+        let operations: Vec<_> = self.keystore_operations.lock().unwrap().drain().collect();
+        for (_, sender) in operations {
+            let _ = sender.send(Err(error));
+        }
+        let pending: Vec<_> = self.map_futures.lock().unwrap().drain().collect();
+        for (response_handle, mut pending) in pending {
+            if let Some(timer) = pending.timer.take() {
+                timer.abort();
+            }
+            info!(
+                "{} failing request {}:{} [trace {}] with {}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                error
+            );
+            pending.callback.on_error(error.error_code());
+        }
+        for mut queued in self.connection_limiter.drain() {
+            info!(
+                "{} failing queued request {}:{} [trace {}] on connection {} with {}",
+                function_name!(),
+                self.log_tag,
+                queued.response_handle,
+                queued.trace_id,
+                queued.connection_id,
+                error
+            );
+            queued.callback.on_error(error.error_code());
+        }
+    }
+
+    /// Whether the platform has had no activity for longer than its idle timeout.
+    fn is_idle_expired(&self, now: Instant) -> bool {
+        match self.options.idle_timeout {
+            Some(timeout) => {
+                self.map_futures.lock().unwrap().is_empty()
+                    && now.saturating_duration_since(*self.last_activity.lock().unwrap()) >= timeout
+            }
+            None => false,
+        }
+    }
+
+    /// Tells Java the platform was closed for being idle, on a dispatcher thread.
+    fn notify_idle_closed(&self, env: &JNIEnv) -> anyhow::Result<()> {
+        let type_signature = TypeSignature::from_str(ON_PLATFORM_IDLE_CLOSED_MSIG)
+            .map_err(|e| anyhow!("JNI: Invalid type signature: {:?}", e))?;
+        self.platform_native_obj
+            .with_object(env, |platform_native_obj| {
+                env.call_method_unchecked(
+                    platform_native_obj,
+                    self.methods.on_platform_idle_closed,
+                    type_signature.ret,
+                    &[jvalue::from(JValue::Long(self.platform_handle.as_jlong()))],
+                )
+            })
+            .map_err(|e| anyhow!("JNI: Failed to notify idle close: {:?}", e))?
+            .ok_or(PlatformError::PlatformGone)??;
+        Ok(())
+    }
+
+    /// Fails the requests past their deadline, or that have been waiting for their response for
+    /// longer than the platform request timeout, or `threshold` if the platform has none.
+    fn expire_stuck_requests(&self, now: Instant, threshold: Duration) -> usize {
+        let threshold = self.options.request_timeout.unwrap_or(threshold);
+        let expired: Vec<_> = {
+            let mut map_futures = self.map_futures.lock().unwrap();
+            let stuck: Vec<_> = map_futures
+                .iter()
+                .filter(|(_, pending)| {
+                    now >= pending.deadline
+                        || now.saturating_duration_since(pending.sent_at) >= threshold
+                })
+                .map(|(response_handle, _)| *response_handle)
+                .collect();
+            stuck.into_iter().filter_map(|handle| map_futures.remove_entry(&handle)).collect()
+        };
+        self.notify_if_drained();
+        let count = expired.len();
+        for (response_handle, pending) in expired {
+            warn!(
+                "{} request {}:{} [trace {}] on connection {} got no response after {:?}",
+                function_name!(),
+                self.log_tag,
+                response_handle,
+                pending.trace_id,
+                pending.connection_id,
+                now.saturating_duration_since(pending.sent_at)
+            );
+            self.time_out_request(response_handle, pending);
+        }
+        // Requests stuck behind a busy connection count against the same limits.
+        let expired_queued = self.connection_limiter.take_queued_if(|queued| {
+            now >= queued.deadline
+                || queued.send_by.is_some_and(|send_by| now >= send_by)
+                || now.saturating_duration_since(queued.created_at) >= threshold
+        });
+        let count = count + expired_queued.len();
+        for mut queued in expired_queued {
+            warn!(
+                "{} queued request {}:{} [trace {}] on connection {} was not sent after {:?}",
+                function_name!(),
+                self.log_tag,
+                queued.response_handle,
+                queued.trace_id,
+                queued.connection_id,
+                now.saturating_duration_since(queued.created_at)
+            );
+            if queued.send_by.is_some_and(|send_by| now >= send_by) {
+                queued.callback.on_error(PlatformError::ExpiredBeforeSend.error_code());
+            } else {
+                TIMED_OUT_REQUESTS.fetch_add(1, Ordering::Relaxed);
+                queued.callback.on_error(PlatformError::Timeout.error_code());
+            }
+        }
+        count
+    }
+
+    fn on_send_request_error(&self, error_code: i32, response_handle: ResponseHandle) {
+        self.touch();
+        if self.retry_request(error_code, response_handle) {
+            return;
+        }
+        if let Some(mut pending) = self.take_pending(response_handle) {
+            error!(
+                "{} completed with error {} {}:{} [trace {}]",
+                function_name!(),
+                error_code,
+                self.log_tag,
+                response_handle,
+                pending.trace_id
+            );
+            self.retry_counters.record_outcome(pending.attempt, false);
+            pending.callback.on_error(error_code);
+            self.release_connection_slot(pending.connection_id);
+        } else {
+            error!(
+                "Failed to find callback for {} and {}:{}",
+                function_name!(),
+                self.log_tag,
                 response_handle
             );
         }
     }
+}
+
+impl Drop for JavaPlatform {
+    fn drop(&mut self) {
+        if self.tasks.len() > 0 {
+            debug!(
+                "{} platform {}: aborting {} tasks",
+                function_name!(),
+                self.log_tag,
+                self.tasks.len()
+            );
+        }
+        self.set_state(State::Closed);
+        self.fail_pending_requests(PlatformError::PlatformDestroyed);
+        // Make sure the thread is attached while the global reference is deleted, otherwise the
+        // JNI crate has to attach a detached thread on its own just to release it.
+        match attached_env(self.vm) {
+            Ok(env) => {
+                // Safety: platform_native_obj is not accessed again after this point.
+                unsafe { ManuallyDrop::take(&mut self.platform_native_obj) }.release(&env);
+            }
+            Err(e) => {
+                error!(
+                    "{} failed to attach thread, leaking reference to platform {}: {:?}",
+                    function_name!(),
+                    self.log_tag,
+                    e
+                );
+            }
+        }
+        debug!("{} platform {} released", function_name!(), self.log_tag);
+    }
+}
+
+/// Validates a handle received from Java, throwing PlatformBadHandleException if it is invalid.
+fn handle_from_java<H>(env: &JNIEnv, value: jlong, caller: &str) -> Option<H>
+where
+    H: TryFrom<jlong, Error = PlatformError>,
+{
+    match H::try_from(value) {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            throw_bad_handle(env, format!("{} in {}", e, caller));
+            None
+        }
+    }
+}
+
+/// Creates a platform for the Java platform object, with options from an optional Java config
+/// object. Returns the platform handle, or -1 on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_create_platform(
+    env: JNIEnv,
+    _: JObject,
+    java_platform_native: JObject,
+    config: JObject,
+) -> jlong {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), -1, |env| {
+        native_create_platform(env, java_platform_native, config)
+    })
+}
+
+fn native_create_platform(
+    env: JNIEnv<'_>,
+    java_platform_native: JObject,
+    config: JObject,
+) -> jlong {
+    let builder = if config.is_null() {
+        Ok(JavaPlatformBuilder::new())
+    } else {
+        JavaPlatformBuilder::from_java(&env, config)
+    };
+    match builder
+        .map_err(anyhow::Error::from)
+        .and_then(|builder| builder.build(java_platform_native))
+    {
+        Ok(platform) => platform.handle().as_jlong(),
+        Err(e) => {
+            throw_native_exception(
+                &env,
+                format!("Failed to create platform in {}: {:?}", function_name!(), e),
+            );
+            -1
+        }
+    }
+}
+
+/// Throws IllegalStateException if a callback arrives for a closed platform.
+fn reject_if_closed(
+    env: &JNIEnv,
+    platform: &JavaPlatform,
+    response_handle: ResponseHandle,
+    caller: &str,
+) -> bool {
+    if platform.state() != State::Closed {
+        return false;
+    }
+    error!(
+        "{} callback for {}:{} after platform was closed",
+        caller, platform.log_tag, response_handle
+    );
+    throw_illegal_state(
+        env,
+        format!(
+            "Platform {} is closed, dropping response {} in {}",
+            platform.log_tag, response_handle, caller
+        ),
+    );
+    true
+}
+
+/// Returns successful response from remote device, with the transport it arrived over (-1 if
+/// unknown) and the status the remote device reported besides it
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_send_request_success(
+    env: JNIEnv,
+    _: JObject,
+    app_response: jbyteArray,
+    platform_handle: jlong,
+    response_handle: jlong,
+    transport: jint,
+    remote_status: jint,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(response_handle) = handle_from_java(&env, response_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_send_request_success(
+            env,
+            app_response,
+            platform_handle,
+            response_handle,
+            Transport::from_java(transport),
+            remote_status,
+        );
+    })
+}
+
+fn native_on_send_request_success(
+    env: JNIEnv<'_>,
+    app_response: jbyteArray,
+    platform_handle: PlatformHandle,
+    response_handle: ResponseHandle,
+    transport: Option<Transport>,
+    remote_status: i32,
+) {
+    // Clone the platform out of the map so other platforms are not blocked by the callback.
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        let response =
+            env.convert_byte_array(app_response).map_err(|_| JNIError::InvalidCtorReturn).unwrap();
+        if reject_if_closed(&env, &platform, response_handle, function_name!()) {
+            return;
+        }
+        if let Err(e) =
+            platform.on_send_request_success(&response, transport, remote_status, response_handle)
+        {
+            throw_message_too_large(&env, format!("{} in {}", e, function_name!()));
+        }
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Delivers a chunk of a response streamed from remote device
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_send_request_chunk(
+    env: JNIEnv,
+    _: JObject,
+    chunk: jbyteArray,
+    platform_handle: jlong,
+    response_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(response_handle) = handle_from_java(&env, response_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_send_request_chunk(env, chunk, platform_handle, response_handle);
+    })
+}
+
+fn native_on_send_request_chunk(
+    env: JNIEnv<'_>,
+    chunk: jbyteArray,
+    platform_handle: PlatformHandle,
+    response_handle: ResponseHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        let chunk = env.convert_byte_array(chunk).map_err(|_| JNIError::InvalidCtorReturn).unwrap();
+        if reject_if_closed(&env, &platform, response_handle, function_name!()) {
+            return;
+        }
+        if let Err(e) = platform.on_send_request_chunk(&chunk, response_handle) {
+            throw_message_too_large(&env, format!("{} in {}", e, function_name!()));
+        }
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Reports how much of a large request the transport has sent
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_send_request_progress(
+    env: JNIEnv,
+    _: JObject,
+    bytes_sent: jlong,
+    total: jlong,
+    platform_handle: jlong,
+    response_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(response_handle) = handle_from_java(&env, response_handle, function_name!())
+        else {
+            return;
+        };
+        let (Ok(bytes_sent), Ok(total)) = (u64::try_from(bytes_sent), u64::try_from(total)) else {
+            throw_illegal_argument(
+                &env,
+                format!("Invalid progress {}/{} in {}", bytes_sent, total, function_name!()),
+            );
+            return;
+        };
+        native_on_send_request_progress(
+            env,
+            Progress { bytes_sent, total },
+            platform_handle,
+            response_handle,
+        );
+    })
+}
+
+fn native_on_send_request_progress(
+    env: JNIEnv<'_>,
+    progress: Progress,
+    platform_handle: PlatformHandle,
+    response_handle: ResponseHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        if reject_if_closed(&env, &platform, response_handle, function_name!()) {
+            return;
+        }
+        platform.on_send_request_progress(progress, response_handle);
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Notifies that the last chunk of a streamed response was delivered
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_send_request_complete(
+    env: JNIEnv,
+    _: JObject,
+    platform_handle: jlong,
+    response_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(response_handle) = handle_from_java(&env, response_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_send_request_complete(env, platform_handle, response_handle);
+    })
+}
+
+fn native_on_send_request_complete(
+    env: JNIEnv<'_>,
+    platform_handle: PlatformHandle,
+    response_handle: ResponseHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        if reject_if_closed(&env, &platform, response_handle, function_name!()) {
+            return;
+        }
+        if let Err(e) = platform.on_send_request_complete(response_handle) {
+            throw_message_too_large(&env, format!("{} in {}", e, function_name!()));
+        }
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
 
-    fn on_send_request_error(&self, error_code: i32, response_handle: i64) {
-        error!(
-            "{} completed with error {} {}:{}",
-            function_name!(),
-            error_code,
-            self.platform_handle,
-            response_handle
-        );
-        if let Some(mut callback) = self.map_futures.lock().unwrap().remove(&response_handle) {
-            callback.on_error(error_code);
-        } else {
-            error!(
-                "Failed to find callback for {} and {}:{}",
-                function_name!(),
-                self.platform_handle,
-                response_handle
+/// Delivers a message the remote device pushed without a request
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_message_received(
+    env: JNIEnv,
+    _: JObject,
+    connection_id: jint,
+    payload: jbyteArray,
+    platform_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        if let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!()) {
+            native_on_message_received(env, connection_id, payload, platform_handle);
+        }
+    })
+}
+
+fn native_on_message_received(
+    env: JNIEnv<'_>,
+    connection_id: i32,
+    payload: jbyteArray,
+    platform_handle: PlatformHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        if platform.state() == State::Closed {
+            throw_illegal_state(
+                &env,
+                format!(
+                    "Platform {} is closed, dropping message in {}",
+                    platform.log_tag,
+                    function_name!()
+                ),
             );
+            return;
         }
+        let payload =
+            env.convert_byte_array(payload).map_err(|_| JNIError::InvalidCtorReturn).unwrap();
+        platform.on_message_received(connection_id, &payload);
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
     }
 }
 
-/// Returns successful response from remote device
+/// Reports a transport connection going up, down or degraded
 #[no_mangle]
-pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_send_request_success(
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_connection_state_changed(
     env: JNIEnv,
     _: JObject,
-    app_response: jbyteArray,
+    connection_id: jint,
+    state: jint,
     platform_handle: jlong,
-    response_handle: jlong,
 ) {
     debug!("{}: enter", function_name!());
-    native_on_send_request_success(env, app_response, platform_handle, response_handle);
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(state) = ConnectionState::from_java(state) else {
+            throw_illegal_argument(
+                &env,
+                format!("Unknown connection state {} in {}", state, function_name!()),
+            );
+            return;
+        };
+        native_on_connection_state_changed(
+            env,
+            ConnectionEvent { id: connection_id, state },
+            platform_handle,
+        );
+    })
 }
 
-fn native_on_send_request_success(
+fn native_on_connection_state_changed(
     env: JNIEnv<'_>,
-    app_response: jbyteArray,
+    event: ConnectionEvent,
+    platform_handle: PlatformHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        platform.on_connection_state_changed(event);
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Reports the transport of a connection congested, or clear again
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_flow_control(
+    env: JNIEnv,
+    _: JObject,
+    connection_id: jint,
+    paused: jboolean,
     platform_handle: jlong,
-    response_handle: jlong,
 ) {
-    if let Some(platform) = HANDLE_MAPPING.lock().unwrap().get(&platform_handle) {
-        let response =
-            env.convert_byte_array(app_response).map_err(|_| JNIError::InvalidCtorReturn).unwrap();
-        let mut platform = (*platform).lock().unwrap();
-        platform.on_send_request_success(&response, response_handle);
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_flow_control(env, connection_id, paused != JNI_FALSE, platform_handle);
+    })
+}
+
+fn native_on_flow_control(
+    env: JNIEnv<'_>,
+    connection_id: i32,
+    paused: bool,
+    platform_handle: PlatformHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    match platform {
+        Some(platform) if paused => platform.pause(connection_id),
+        Some(platform) => platform.resume(connection_id),
+        None => throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        ),
+    }
+}
+
+/// Completes an open or close of a connection with the id of the connection
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_connection_operation_success(
+    env: JNIEnv,
+    _: JObject,
+    connection_id: jint,
+    platform_handle: jlong,
+    operation_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(operation_handle) = handle_from_java(&env, operation_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_connection_operation_complete(
+            env,
+            Ok(connection_id),
+            platform_handle,
+            operation_handle,
+        );
+    })
+}
+
+/// Fails an open or close of a connection with the error code of the transport
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_connection_operation_error(
+    env: JNIEnv,
+    _: JObject,
+    error_code: jint,
+    platform_handle: jlong,
+    operation_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(operation_handle) = handle_from_java(&env, operation_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_connection_operation_complete(
+            env,
+            Err(PlatformError::ConnectionFailed(error_code)),
+            platform_handle,
+            operation_handle,
+        );
+    })
+}
+
+fn native_on_connection_operation_complete(
+    env: JNIEnv<'_>,
+    result: Result<i32, PlatformError>,
+    platform_handle: PlatformHandle,
+    operation_handle: OperationHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        platform.complete_operation(&platform.connection_operations, operation_handle, result);
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Completes a keystore operation with its signature, public key or derived secret
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_keystore_operation_success(
+    env: JNIEnv,
+    _: JObject,
+    result: jbyteArray,
+    platform_handle: jlong,
+    operation_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(operation_handle) = handle_from_java(&env, operation_handle, function_name!())
+        else {
+            return;
+        };
+        let result = match env.convert_byte_array(result) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                error!("{}: invalid result: {:?}", function_name!(), e);
+                Err(PlatformError::SendFailed)
+            }
+        };
+        native_on_keystore_operation_complete(env, result, platform_handle, operation_handle);
+    })
+}
+
+/// Fails a keystore operation with the error code of Java
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_on_keystore_operation_error(
+    env: JNIEnv,
+    _: JObject,
+    error_code: jint,
+    platform_handle: jlong,
+    operation_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(operation_handle) = handle_from_java(&env, operation_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_keystore_operation_complete(
+            env,
+            Err(PlatformError::KeystoreFailed(error_code)),
+            platform_handle,
+            operation_handle,
+        );
+    })
+}
+
+fn native_on_keystore_operation_complete(
+    env: JNIEnv<'_>,
+    result: Result<Vec<u8>, PlatformError>,
+    platform_handle: PlatformHandle,
+    operation_handle: OperationHandle,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        platform.complete_operation(&platform.keystore_operations, operation_handle, result);
     } else {
-        let _ = env.throw_new(
-            "com/android/server/remoteauth/jni/BadHandleException",
+        throw_bad_handle(
+            &env,
             format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
         );
     }
@@ -267,29 +3564,449 @@ pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJa
     response_handle: jlong,
 ) {
     debug!("{}: enter", function_name!());
-    native_on_send_request_error(env, error_code, platform_handle, response_handle);
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(response_handle) = handle_from_java(&env, response_handle, function_name!())
+        else {
+            return;
+        };
+        native_on_send_request_error(env, error_code, platform_handle, response_handle);
+    })
 }
 
 fn native_on_send_request_error(
     env: JNIEnv<'_>,
     error_code: jint,
+    platform_handle: PlatformHandle,
+    response_handle: ResponseHandle,
+) {
+    // Clone the platform out of the map so other platforms are not blocked by the callback.
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        if reject_if_closed(&env, &platform, response_handle, function_name!()) {
+            return;
+        }
+        platform.on_send_request_error(error_code, response_handle);
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Cancels a request that has no response yet. Returns false if there is no such request.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_cancel_request(
+    env: JNIEnv,
+    _: JObject,
     platform_handle: jlong,
+    connection_id: jint,
     response_handle: jlong,
+) -> jboolean {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), JNI_FALSE, |env| {
+        let _guard = get_runtime().enter();
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return JNI_FALSE;
+        };
+        let Some(response_handle) = handle_from_java(&env, response_handle, function_name!())
+        else {
+            return JNI_FALSE;
+        };
+        native_cancel_request(env, platform_handle, connection_id, response_handle)
+    })
+}
+
+fn native_cancel_request(
+    env: JNIEnv<'_>,
+    platform_handle: PlatformHandle,
+    connection_id: i32,
+    response_handle: ResponseHandle,
+) -> jboolean {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    let Some(platform) = platform else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+        return JNI_FALSE;
+    };
+    match platform.cancel_request(connection_id, response_handle) {
+        Ok(()) => JNI_TRUE,
+        Err(e) => {
+            info!("{} {}:{}: {}", function_name!(), platform.log_tag, response_handle, e);
+            JNI_FALSE
+        }
+    }
+}
+
+/// Registers the platform as the way to reach a remote device over a transport
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_register_platform(
+    env: JNIEnv,
+    _: JObject,
+    platform_handle: jlong,
+    device_id: JString,
+    transport: jint,
 ) {
-    if let Some(platform) = HANDLE_MAPPING.lock().unwrap().get(&platform_handle) {
-        let platform = (*platform).lock().unwrap();
-        platform.on_send_request_error(error_code, response_handle);
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return;
+        };
+        let Some(transport) = Transport::from_java(transport) else {
+            throw_illegal_argument(
+                &env,
+                format!("Unknown transport {} in {}", transport, function_name!()),
+            );
+            return;
+        };
+        let device_id: String = match env.get_string(device_id) {
+            Ok(device_id) => device_id.into(),
+            Err(e) => {
+                throw_illegal_argument(&env, format!("Invalid device id: {:?}", e));
+                return;
+            }
+        };
+        native_register_platform(env, platform_handle, &device_id, transport);
+    })
+}
+
+fn native_register_platform(
+    env: JNIEnv<'_>,
+    platform_handle: PlatformHandle,
+    device_id: &str,
+    transport: Transport,
+) {
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        let platform: BoxedPlatform = platform;
+        if platform_registry().register(device_id, transport, &platform).is_some() {
+            info!(
+                "{} platform {} replaces the {:?} platform of {}",
+                function_name!(),
+                platform_handle,
+                transport,
+                device_id
+            );
+        }
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Releases the platform: fails its pending requests and drops the Java platform reference
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_destroy_platform(
+    env: JNIEnv,
+    _: JObject,
+    platform_handle: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        let _guard = get_runtime().enter();
+        if let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!()) {
+            native_destroy_platform(env, platform_handle);
+        }
+    })
+}
+
+fn native_destroy_platform(env: JNIEnv<'_>, platform_handle: PlatformHandle) {
+    if let Some(platform) = remove_platform_handle(platform_handle) {
+        platform.set_state(State::Closed);
+        platform.fail_pending_requests(PlatformError::PlatformDestroyed);
+        info!("{} destroyed platform {}", function_name!(), platform_handle);
+        // Dropping the last reference releases the GlobalRef to the Java platform object.
+    } else {
+        throw_bad_handle(
+            &env,
+            format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
+        );
+    }
+}
+
+/// Drains in-flight requests of the platform, waiting at most `timeout_millis` for them
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_shutdown_platform(
+    env: JNIEnv,
+    _: JObject,
+    platform_handle: jlong,
+    timeout_millis: jlong,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |env| {
+        if let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!()) {
+            native_shutdown_platform(env, platform_handle, timeout_millis);
+        }
+    })
+}
+
+fn native_shutdown_platform(
+    env: JNIEnv<'_>,
+    platform_handle: PlatformHandle,
+    timeout_millis: jlong,
+) {
+    // Clone the platform out of the map so callbacks can look it up while we wait.
+    let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+    if let Some(platform) = platform {
+        let deadline = Instant::now() + Duration::from_millis(timeout_millis.max(0) as u64);
+        let failed = get_runtime().block_on(platform.shutdown(deadline));
+        info!(
+            "{} platform {} shut down, {} requests failed",
+            function_name!(),
+            platform_handle,
+            failed
+        );
     } else {
-        let _ = env.throw_new(
-            "com/android/server/remoteauth/jni/BadHandleException",
+        throw_bad_handle(
+            &env,
             format!("Failed to find Platform with ID {} in {}", platform_handle, function_name!()),
         );
     }
 }
 
+/// Parameters of the background task looking for stale platforms in HANDLE_MAPPING.
+#[derive(Debug, Clone, Copy)]
+pub struct SweepConfig {
+    /// How often HANDLE_MAPPING is scanned.
+    pub interval: Duration,
+    /// Platforms without any request or callback for this long are considered stale.
+    pub idle_threshold: Duration,
+    /// Whether stale platforms are evicted, or only logged.
+    pub evict: bool,
+}
+
+/// Starts the periodic sweep of stale platforms, replacing a sweeper started earlier.
+pub fn start_platform_sweeper(config: SweepConfig) {
+    // tokio::time::interval panics on a zero period.
+    let period = config.interval.max(Duration::from_millis(1));
+    let sweeper = get_runtime().spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            sweep_stale_platforms(&config);
+        }
+    });
+    if let Some(previous) = SWEEPER.lock().unwrap().replace(sweeper) {
+        previous.abort();
+    }
+}
+
+/// Forgets every platform left over by a previous instance of the Java service.
+///
+/// Pending requests are failed with `PlatformDestroyed`, the sweeper is stopped and handle
+/// numbering starts over. Returns the number of platforms dropped.
+pub(crate) fn reset_platform_state() -> usize {
+    let platforms: Vec<_> = {
+        // Holding the mapping lock keeps platforms from being registered during the reset.
+        let mut mapping = handle_mapping().lock().unwrap();
+        HANDLE_RN.reset();
+        mapping.drain().map(|(_, platform)| platform).collect()
+    };
+    if let Some(sweeper) = SWEEPER.lock().unwrap().take() {
+        sweeper.abort();
+    }
+    TIMED_OUT_REQUESTS.store(0, Ordering::Relaxed);
+    let count = platforms.len();
+    for platform in platforms {
+        info!("{} dropping platform {}", function_name!(), platform.log_tag);
+        platform.set_state(State::Closed);
+        platform.fail_pending_requests(PlatformError::PlatformDestroyed);
+    }
+    count
+}
+
+/// Returns the number of platforms currently registered, for leak diagnosis.
+pub fn platform_count() -> usize {
+    handle_mapping().lock().unwrap().len()
+}
+
+fn sweep_stale_platforms(config: &SweepConfig) -> usize {
+    let now = Instant::now();
+    // Checking whether the Java object is gone attaches to the JVM: it is done without holding
+    // the mapping lock, which the JNI entry points take.
+    let (platforms, registered) = {
+        let mapping = handle_mapping().lock().unwrap();
+        let platforms: Vec<_> =
+            mapping.iter().map(|(handle, platform)| (*handle, Arc::clone(platform))).collect();
+        (platforms, mapping.len())
+    };
+    let stale: Vec<_> = platforms
+        .into_iter()
+        .filter_map(|(handle, platform)| {
+            platform
+                .stale_reason(now, config.idle_threshold)
+                .map(|reason| (handle, platform, reason))
+        })
+        .collect();
+    for (handle, _, reason) in &stale {
+        warn!(
+            "{} platform {} is stale ({}), {} platforms registered",
+            function_name!(),
+            handle,
+            reason,
+            registered
+        );
+    }
+    if config.evict {
+        let evicted: Vec<_> = {
+            let mut mapping = handle_mapping().lock().unwrap();
+            stale
+                .iter()
+                .filter_map(|(handle, platform, _)| {
+                    // The handle may have been destroyed, and reused, since it was found stale.
+                    mapping
+                        .get(handle)
+                        .is_some_and(|current| Arc::ptr_eq(current, platform))
+                        .then(|| mapping.remove(handle))
+                        .flatten()
+                })
+                .collect()
+        };
+        for platform in evicted {
+            platform.set_state(State::Closed);
+            platform.fail_pending_requests(PlatformError::PlatformDestroyed);
+        }
+    }
+    stale.len()
+}
+
+/// Starts the periodic sweep of platforms Java forgot to destroy
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_start_platform_sweeper(
+    env: JNIEnv,
+    _: JObject,
+    interval_millis: jlong,
+    idle_threshold_millis: jlong,
+    evict: jboolean,
+) {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), (), |_| {
+        start_platform_sweeper(SweepConfig {
+            interval: Duration::from_millis(interval_millis.max(0) as u64),
+            idle_threshold: Duration::from_millis(idle_threshold_millis.max(0) as u64),
+            evict: evict != 0,
+        });
+    })
+}
+
+/// Returns the number of platforms alive on the native side
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_get_platform_count(
+    env: JNIEnv,
+    _: JObject,
+) -> jint {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), -1, |_| platform_count().try_into().unwrap_or(jint::MAX))
+}
+
+/// Returns the smoothed round-trip time of a connection in milliseconds, or -1 if it was never
+/// pinged successfully
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_get_connection_rtt_millis(
+    env: JNIEnv,
+    _: JObject,
+    platform_handle: jlong,
+    connection_id: jint,
+) -> jlong {
+    debug!("{}: enter", function_name!());
+    catch_jni_panic(env, function_name!(), -1, |env| {
+        let Some(platform_handle) = handle_from_java(&env, platform_handle, function_name!())
+        else {
+            return -1;
+        };
+        let platform = handle_mapping().lock().unwrap().get(&platform_handle).map(Arc::clone);
+        let Some(platform) = platform else {
+            throw_bad_handle(
+                &env,
+                format!(
+                    "Failed to find Platform with ID {} in {}",
+                    platform_handle,
+                    function_name!()
+                ),
+            );
+            return -1;
+        };
+        platform
+            .rtt_stats(connection_id)
+            .map_or(-1, |stats| stats.smoothed.as_millis().try_into().unwrap_or(jlong::MAX))
+    })
+}
+
+/// Starts the watchdog failing requests without a response after `threshold`, replacing a
+/// watchdog started earlier.
+pub fn start_request_watchdog(threshold: Duration) {
+    // Checking a few times per threshold bounds how late a stuck request is reported.
+    let period = (threshold / 4).max(Duration::from_millis(100));
+    let watchdog = get_runtime().spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            expire_stuck_requests(threshold);
+            close_idle_platforms();
+        }
+    });
+    if let Some(previous) = WATCHDOG.lock().unwrap().replace(watchdog) {
+        previous.abort();
+    }
+}
+
+/// Returns the number of requests failed by the watchdog since the library was loaded.
+pub fn timed_out_request_count() -> u64 {
+    TIMED_OUT_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Closes the platforms that outlived their idle timeout and tells Java about it.
+fn close_idle_platforms() -> usize {
+    let now = Instant::now();
+    let idle: Vec<_> = {
+        let mut mapping = handle_mapping().lock().unwrap();
+        let handles: Vec<_> = mapping
+            .iter()
+            .filter(|(_, platform)| platform.is_idle_expired(now))
+            .map(|(handle, _)| *handle)
+            .collect();
+        handles.into_iter().filter_map(|handle| mapping.remove(&handle)).collect()
+    };
+    let count = idle.len();
+    for platform in idle {
+        info!("{} closing idle platform {}", function_name!(), platform.log_tag);
+        platform.set_state(State::Closed);
+        // Requests queued for a busy connection would otherwise wait until the platform drops.
+        platform.fail_pending_requests(PlatformError::InvalidState(State::Closed));
+        let log_tag = platform.log_tag.clone();
+        // The upcall holds the last reference to the platform: its GlobalRef is released once
+        // Java has been told.
+        if let Err(e) = dispatch::submit(move |env| {
+            if let Err(e) = platform.notify_idle_closed(env) {
+                error!("{} platform {}: {:?}", function_name!(), platform.log_tag, e);
+            }
+        }) {
+            error!("{} platform {}: {:?}", function_name!(), log_tag, e);
+        }
+    }
+    count
+}
+
+fn expire_stuck_requests(threshold: Duration) -> usize {
+    let now = Instant::now();
+    let platforms: Vec<_> = handle_mapping().lock().unwrap().values().map(Arc::clone).collect();
+    platforms.iter().map(|platform| platform.expire_stuck_requests(now, threshold)).sum()
+}
+
 #[cfg(test)]
 mod tests {
-    //use super::*;
+    use super::*;
 
     //use tokio::runtime::Builder;
 
@@ -298,4 +4015,120 @@ mod tests {
     fn test_function_name() {
         assert_eq!(function_name!(), "test_function_name");
     }
+
+    #[test]
+    fn test_state_transitions() {
+        assert!(State::Created.can_transition_to(State::Ready));
+        assert!(State::Ready.can_transition_to(State::ShuttingDown));
+        assert!(State::ShuttingDown.can_transition_to(State::Closed));
+        assert!(State::Ready.can_transition_to(State::Closed));
+        assert!(!State::Closed.can_transition_to(State::Ready));
+        assert!(!State::ShuttingDown.can_transition_to(State::Ready));
+        assert!(!State::Created.can_transition_to(State::ShuttingDown));
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let (response_sender, mut response_receiver) = oneshot::channel();
+        let (sender, receiver) = watch::channel(Progress::default());
+        let mut callback = ProgressCallback {
+            inner: Box::new(OneshotCallback { sender: Some(response_sender) }),
+            sender,
+        };
+        callback.on_progress(512, 2048);
+        assert_eq!(*receiver.borrow(), Progress { bytes_sent: 512, total: 2048 });
+        callback.on_error(7);
+        assert_eq!(response_receiver.try_recv().unwrap(), Err(7));
+    }
+
+    #[test]
+    fn test_gather() {
+        let bufs: [&[u8]; 3] = [&[1, 2], &[], &[3, 4, 5]];
+        let len = vectored_len(&bufs);
+        assert_eq!(len, 5);
+        let request = gather(&bufs, len);
+        assert_eq!(request, vec![1, 2, 3, 4, 5]);
+        assert_eq!(request.capacity(), len);
+    }
+
+    #[test]
+    fn test_multi_response_wait() {
+        let (sender, receiver) = oneshot::channel();
+        let mut callback = OneshotCallback { sender: Some(sender) };
+        let response = Response {
+            payload: vec![1, 2],
+            latency: Duration::from_millis(20),
+            transport: Some(Transport::Ble),
+            remote_status: 0,
+        };
+        callback.on_response(response.clone());
+        // Only the first outcome is kept.
+        callback.on_error(-1);
+        let (_, dropped) = oneshot::channel();
+        let multi = MultiResponse {
+            outcomes: vec![
+                (1, MultiOutcome::Pending(receiver)),
+                (2, MultiOutcome::Failed(PlatformError::Busy.error_code())),
+                (3, MultiOutcome::Pending(dropped)),
+            ],
+        };
+        let results =
+            tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(multi.wait());
+        assert_eq!(
+            results,
+            vec![
+                (1, Ok(response)),
+                (2, Err(PlatformError::Busy.error_code())),
+                (3, Err(PlatformError::PlatformDestroyed.error_code())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_accept_chunk_limit() {
+        let (sender, _receiver) = oneshot::channel();
+        let now = Instant::now();
+        let mut pending = PendingRequest {
+            connection_id: 1,
+            trace_id: TraceId::generate(),
+            idempotency_key: None,
+            request: Arc::new(vec![]),
+            retry_policy: RetryPolicy::none(),
+            attempt: 1,
+            send_by: None,
+            sent_at: now,
+            deadline: now,
+            callback: SharedCallback::new(Box::new(OneshotCallback { sender: Some(sender) })),
+            streaming: false,
+            buffered: Vec::new(),
+            timer: None,
+        };
+        assert_eq!(pending.accept_chunk(&[1, 2, 3], 4), Ok(()));
+        assert_eq!(pending.accept_chunk(&[4], 4), Ok(()));
+        assert_eq!(pending.buffered, vec![1, 2, 3, 4]);
+        // The limit is on the whole response, not on each chunk.
+        assert_eq!(pending.accept_chunk(&[5], 4), Err(PlatformError::ResponseTooLarge(5)));
+        assert_eq!(pending.buffered, vec![1, 2, 3, 4]);
+        // Streamed chunks are not buffered, each is checked on its own.
+        pending.streaming = true;
+        assert_eq!(pending.accept_chunk(&[0; 4], 4), Ok(()));
+        assert_eq!(pending.accept_chunk(&[0; 5], 4), Err(PlatformError::ResponseTooLarge(5)));
+        assert_eq!(pending.buffered.len(), 4);
+    }
+
+    #[test]
+    fn test_stream_callback_overflow() {
+        let (sender, mut receiver) = mpsc::channel(3);
+        let mut callback = StreamCallback { sender, failed: false };
+        callback.on_chunk(vec![1]);
+        callback.on_chunk(vec![2]);
+        // The last slot is taken by the error, and later chunks are dropped.
+        callback.on_chunk(vec![3]);
+        callback.on_chunk(vec![4]);
+        drop(callback);
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![1])));
+        assert_eq!(receiver.try_recv(), Ok(Ok(vec![2])));
+        assert_eq!(receiver.try_recv(), Ok(Err(PlatformError::StreamFull.error_code())));
+        assert!(receiver.try_recv().is_err());
+    }
 }
@@ -15,28 +15,383 @@
  */
 
 //! Implementation of JNI protocol functionality.
+use crate::audit::audit_log;
+use crate::config::{self, TunableConfig};
+use crate::device_identity::DeviceId;
+use crate::enrollment::enrollment_registry;
+use crate::jnames::{
+    NATIVE_CONFIG_CHANNEL_CAPACITY_FNAME, NATIVE_CONFIG_DEFAULT_TIMEOUT_FNAME,
+    NATIVE_CONFIG_LOG_LEVEL_FNAME, NATIVE_CONFIG_MAX_BLOCKING_THREADS_FNAME,
+    NATIVE_CONFIG_THREAD_NAME_PREFIX_FNAME, NATIVE_CONFIG_USE_CURRENT_THREAD_FNAME,
+    NATIVE_CONFIG_WORKER_THREADS_FNAME,
+};
+use crate::remoteauth_jni_android_platform::{
+    init_platform_state, reset_platform_state, start_request_watchdog,
+};
+use crate::runtime::{init_runtime, RuntimeConfig};
 use crate::unique_jvm;
-use crate::utils::get_boolean_result;
-use jni::objects::JObject;
-use jni::sys::jboolean;
+use crate::unique_jvm::JvmError;
+use crate::unlock_token::unlock_tokens;
+use crate::utils::{
+    catch_jni_panic, get_boolean_result, init_logger, install_panic_hook, throw_illegal_argument,
+    throw_illegal_state,
+};
+use jni::objects::{JObject, JString};
+use jni::sys::{jboolean, jbyteArray, jint, jlong, jobjectArray, jstring, JNI_FALSE, JNI_TRUE};
 use jni::JNIEnv;
+use log::{info, warn, LevelFilter};
+use std::sync::OnceLock;
+use std::time::Duration;
 
-/// Initialize native library. Captures Java VM:
+/// Configuration of the native library, passed by Java to native_init.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeConfig {
+    /// Maximum level of the logs emitted by the library.
+    pub log_level: LevelFilter,
+    /// Capacity of the channels handing responses over to waiting requests.
+    pub channel_capacity: usize,
+    /// Timeout of requests that don't specify one.
+    pub default_timeout: Duration,
+    /// Parameters of the shared runtime.
+    pub runtime: RuntimeConfig,
+}
+
+impl Default for NativeConfig {
+    fn default() -> Self {
+        Self {
+            log_level: LevelFilter::Trace,
+            channel_capacity: 16,
+            default_timeout: Duration::from_secs(10),
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+impl NativeConfig {
+    /// Reads the config from its Java counterpart.
+    fn from_java(env: &JNIEnv, config: JObject) -> anyhow::Result<Self> {
+        let log_level = env.get_field(config, NATIVE_CONFIG_LOG_LEVEL_FNAME, "I")?.i()?;
+        let channel_capacity =
+            env.get_field(config, NATIVE_CONFIG_CHANNEL_CAPACITY_FNAME, "I")?.i()?;
+        let default_timeout_millis =
+            env.get_field(config, NATIVE_CONFIG_DEFAULT_TIMEOUT_FNAME, "J")?.j()?;
+        Ok(Self {
+            log_level: log_level_from_android_priority(log_level),
+            channel_capacity: channel_capacity.max(1) as usize,
+            default_timeout: Duration::from_millis(default_timeout_millis.max(0) as u64),
+            runtime: Self::runtime_config_from_java(env, config)?,
+        })
+    }
+
+    fn runtime_config_from_java(env: &JNIEnv, config: JObject) -> anyhow::Result<RuntimeConfig> {
+        let default = RuntimeConfig::default();
+        let worker_threads = env.get_field(config, NATIVE_CONFIG_WORKER_THREADS_FNAME, "I")?.i()?;
+        let thread_name_prefix = env
+            .get_field(config, NATIVE_CONFIG_THREAD_NAME_PREFIX_FNAME, "Ljava/lang/String;")?
+            .l()?;
+        let max_blocking_threads =
+            env.get_field(config, NATIVE_CONFIG_MAX_BLOCKING_THREADS_FNAME, "I")?.i()?;
+        let use_current_thread =
+            env.get_field(config, NATIVE_CONFIG_USE_CURRENT_THREAD_FNAME, "Z")?.z()?;
+        Ok(RuntimeConfig {
+            worker_threads: worker_threads.max(0) as usize,
+            thread_name_prefix: if thread_name_prefix.is_null() {
+                default.thread_name_prefix
+            } else {
+                env.get_string(JString::from(thread_name_prefix))?.into()
+            },
+            max_blocking_threads: if max_blocking_threads > 0 {
+                max_blocking_threads as usize
+            } else {
+                default.max_blocking_threads
+            },
+            use_current_thread,
+        })
+    }
+}
+
+static NATIVE_CONFIG: OnceLock<NativeConfig> = OnceLock::new();
+
+/// Gets the config passed to native_init, or the default one if it was not called yet.
+pub(crate) fn get_native_config() -> &'static NativeConfig {
+    NATIVE_CONFIG.get_or_init(NativeConfig::default)
+}
+
+/// Maps an `android.util.Log` priority to the matching log level.
+fn log_level_from_android_priority(priority: i32) -> LevelFilter {
+    match priority {
+        i32::MIN..=2 => LevelFilter::Trace,
+        3 => LevelFilter::Debug,
+        4 => LevelFilter::Info,
+        5 => LevelFilter::Warn,
+        6 | 7 => LevelFilter::Error,
+        _ => LevelFilter::Off,
+    }
+}
+
+/// Initialize native library. Captures Java VM and sets up the global state:
 #[no_mangle]
 pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_init(
     env: JNIEnv,
     _: JObject,
+    config: JObject,
 ) -> jboolean {
-    logger::init(
-        logger::Config::default()
-            .with_tag_on_device("remoteauth")
-            .with_max_level(log::LevelFilter::Trace)
-            .with_filter("trace,jni=info"),
-    );
-    get_boolean_result(native_init(env), "native_init")
+    install_panic_hook();
+    catch_jni_panic(env, "native_init", false.into(), |env| {
+        get_boolean_result(native_init(env, config), "native_init")
+    })
 }
 
-fn native_init(env: JNIEnv) -> anyhow::Result<()> {
+fn native_init(env: JNIEnv, config: JObject) -> anyhow::Result<()> {
+    let config = if config.is_null() {
+        NativeConfig::default()
+    } else {
+        NativeConfig::from_java(&env, config)?
+    };
+    init_logger(config.log_level);
     let jvm = env.get_java_vm()?;
-    unique_jvm::set_once(jvm)
+    if let Err(e) = unique_jvm::set_once(jvm) {
+        if e.downcast_ref::<JvmError>().is_some() {
+            throw_illegal_state(&env, format!("native_init: {}", e));
+        }
+        return Err(e);
+    }
+    if NATIVE_CONFIG.set(config).is_err() {
+        warn!("native_init: already initialized, keeping {:?}", get_native_config());
+    }
+    config::replace(TunableConfig {
+        default_timeout: get_native_config().default_timeout,
+        log_level: get_native_config().log_level,
+        ..Default::default()
+    });
+    init_runtime(&get_native_config().runtime)?;
+    init_platform_state();
+    start_request_watchdog(config::snapshot().default_timeout);
+    info!("native_init: initialized with {:?}", get_native_config());
+    Ok(())
+}
+
+/// Drops the native state left over by a previous instance of the Java service, e.g. after it
+/// crashed or was updated, so that platforms can be created again from a clean slate.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_reset(
+    env: JNIEnv,
+    _: JObject,
+) -> jboolean {
+    install_panic_hook();
+    catch_jni_panic(env, "native_reset", false.into(), |_| {
+        get_boolean_result(native_reset(), "native_reset")
+    })
+}
+
+fn native_reset() -> anyhow::Result<()> {
+    init_logger(config::snapshot().log_level);
+    let dropped = reset_platform_state();
+    info!("native_reset: dropped {} platforms", dropped);
+    Ok(())
+}
+
+/// Updates a runtime-tunable value, e.g. on a DeviceConfig change. See `config` for the keys.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_set_config(
+    env: JNIEnv,
+    _: JObject,
+    key: JString,
+    value: JString,
+) -> jboolean {
+    install_panic_hook();
+    catch_jni_panic(env, "native_set_config", false.into(), |env| {
+        get_boolean_result(native_set_config(env, key, value), "native_set_config")
+    })
+}
+
+fn native_set_config(env: JNIEnv, key: JString, value: JString) -> anyhow::Result<()> {
+    let key: String = env.get_string(key)?.into();
+    let value: String = env.get_string(value)?.into();
+    let (old, new) = config::set(&key, &value)?;
+    if old.log_level != new.log_level {
+        log::set_max_level(new.log_level);
+    }
+    if old.default_timeout != new.default_timeout {
+        start_request_watchdog(new.default_timeout);
+    }
+    info!("native_set_config: {} = {}", key, value);
+    Ok(())
+}
+
+/// Loads the enrollment records Java persisted into the registry, at startup. Returns the number
+/// of records loaded, or -1 on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_load_enrollments(
+    env: JNIEnv,
+    _: JObject,
+    records: jobjectArray,
+) -> jint {
+    install_panic_hook();
+    catch_jni_panic(env, "native_load_enrollments", -1, |env| {
+        match native_load_enrollments(env, records) {
+            Ok(loaded) => jint::try_from(loaded).unwrap_or(jint::MAX),
+            Err(e) => {
+                warn!("native_load_enrollments: {:#}", e);
+                -1
+            }
+        }
+    })
+}
+
+fn native_load_enrollments(env: JNIEnv, records: jobjectArray) -> anyhow::Result<usize> {
+    let len = env.get_array_length(records)?;
+    let records = (0..len)
+        .map(|index| {
+            let record = env.get_object_array_element(records, index)?;
+            Ok(env.convert_byte_array(record.into_raw())?)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let loaded = enrollment_registry().load(&records);
+    info!("native_load_enrollments: loaded {} of {} records", loaded, records.len());
+    Ok(loaded)
+}
+
+/// Returns the ids of the enrolled devices, or null on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_get_enrolled_devices(
+    env: JNIEnv,
+    _: JObject,
+) -> jobjectArray {
+    install_panic_hook();
+    catch_jni_panic(env, "native_get_enrolled_devices", std::ptr::null_mut(), |env| {
+        native_get_enrolled_devices(env).unwrap_or_else(|e| {
+            warn!("native_get_enrolled_devices: {:#}", e);
+            std::ptr::null_mut()
+        })
+    })
+}
+
+fn native_get_enrolled_devices(env: JNIEnv) -> anyhow::Result<jobjectArray> {
+    let device_ids = enrollment_registry().device_ids();
+    let array = env.new_object_array(
+        jint::try_from(device_ids.len())?,
+        "java/lang/String",
+        JObject::null(),
+    )?;
+    for (index, device_id) in (0..).zip(&device_ids) {
+        env.set_object_array_element(array, index, env.new_string(device_id.as_str())?)?;
+    }
+    Ok(array)
+}
+
+/// Returns the name and model of the enrolled device `device_id`, or null if it isn't enrolled.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_get_enrolled_device_metadata(
+    env: JNIEnv,
+    _: JObject,
+    device_id: JString,
+) -> jobjectArray {
+    install_panic_hook();
+    catch_jni_panic(env, "native_get_enrolled_device_metadata", std::ptr::null_mut(), |env| {
+        native_get_enrolled_device_metadata(env, device_id).unwrap_or_else(|e| {
+            throw_illegal_argument(&env, format!("native_get_enrolled_device_metadata: {}", e));
+            std::ptr::null_mut()
+        })
+    })
+}
+
+fn native_get_enrolled_device_metadata(
+    env: JNIEnv,
+    device_id: JString,
+) -> anyhow::Result<jobjectArray> {
+    let device_id = DeviceId::new(String::from(env.get_string(device_id)?))?;
+    let Some(record) = enrollment_registry().get(&device_id) else {
+        return Ok(std::ptr::null_mut());
+    };
+    let metadata = &record.identity.metadata;
+    let array = env.new_object_array(2, "java/lang/String", JObject::null())?;
+    env.set_object_array_element(array, 0, env.new_string(&metadata.name)?)?;
+    env.set_object_array_element(array, 1, env.new_string(&metadata.model)?)?;
+    Ok(array)
+}
+
+/// Returns whether the device `device_id` is enrolled.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_is_enrolled(
+    env: JNIEnv,
+    _: JObject,
+    device_id: JString,
+) -> jboolean {
+    install_panic_hook();
+    catch_jni_panic(env, "native_is_enrolled", JNI_FALSE, |env| {
+        let enrolled = env
+            .get_string(device_id)
+            .ok()
+            .and_then(|device_id| DeviceId::new(String::from(device_id)).ok())
+            .is_some_and(|device_id| enrollment_registry().get(&device_id).is_some());
+        if enrolled {
+            JNI_TRUE
+        } else {
+            JNI_FALSE
+        }
+    })
+}
+
+/// Redeems the unlock token `token`, and returns the id of the device it was issued to, or null
+/// if it is invalid, expired or was redeemed before.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_redeem_unlock_token(
+    env: JNIEnv,
+    _: JObject,
+    token: jbyteArray,
+) -> jstring {
+    install_panic_hook();
+    catch_jni_panic(env, "native_redeem_unlock_token", std::ptr::null_mut(), |env| {
+        native_redeem_unlock_token(env, token).unwrap_or_else(|e| {
+            warn!("native_redeem_unlock_token: {:#}", e);
+            std::ptr::null_mut()
+        })
+    })
+}
+
+fn native_redeem_unlock_token(env: JNIEnv, token: jbyteArray) -> anyhow::Result<jstring> {
+    let token = env.convert_byte_array(token)?;
+    let device_id = unlock_tokens()?.redeem(&token)?;
+    info!("native_redeem_unlock_token: redeemed the token of device {}", device_id);
+    Ok(JObject::from(env.new_string(device_id.as_str())?).into_raw())
+}
+
+/// Returns the audit log as text, with the entries numbered `since` or later, for Java to
+/// include in security bug reports. Returns null on failure.
+#[no_mangle]
+pub extern "system" fn Java_com_android_server_remoteauth_jni_NativeRemoteAuthJavaPlatform_native_dump_audit_log(
+    env: JNIEnv,
+    _: JObject,
+    since: jlong,
+) -> jstring {
+    install_panic_hook();
+    catch_jni_panic(env, "native_dump_audit_log", std::ptr::null_mut(), |env| {
+        native_dump_audit_log(env, since).unwrap_or_else(|e| {
+            warn!("native_dump_audit_log: {:#}", e);
+            std::ptr::null_mut()
+        })
+    })
+}
+
+fn native_dump_audit_log(env: JNIEnv, since: jlong) -> anyhow::Result<jstring> {
+    // Negative numbers dump all of it.
+    let dump = audit_log()?.dump(u64::try_from(since).unwrap_or(0));
+    Ok(JObject::from(env.new_string(dump)?).into_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_level_from_android_priority() {
+        assert_eq!(log_level_from_android_priority(2), LevelFilter::Trace);
+        assert_eq!(log_level_from_android_priority(3), LevelFilter::Debug);
+        assert_eq!(log_level_from_android_priority(4), LevelFilter::Info);
+        assert_eq!(log_level_from_android_priority(5), LevelFilter::Warn);
+        assert_eq!(log_level_from_android_priority(6), LevelFilter::Error);
+        assert_eq!(log_level_from_android_priority(7), LevelFilter::Error);
+        assert_eq!(log_level_from_android_priority(100), LevelFilter::Off);
+    }
 }
@@ -0,0 +1,151 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sliding window of the sequence numbers received on a connection, dropping replayed frames.
+//!
+//! The window tracks the highest sequence number received and which of the `REPLAY_WINDOW_LEN`
+//! numbers below it were received too: a number in the window is a duplicate if already seen,
+//! and a number below the window is stale.
+
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of sequence numbers tracked below the highest one received.
+pub(crate) const REPLAY_WINDOW_LEN: u32 = u64::BITS;
+
+/// Why a sequence number was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Replay {
+    /// The number was already received.
+    Duplicate,
+    /// The number is too old to tell.
+    Stale,
+}
+
+/// Sequence numbers received on a connection.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayWindow {
+    highest: Option<u32>,
+    /// Bit `i` is set if `highest - i` was received.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Records `sequence` as received, unless it is a replay.
+    pub(crate) fn check(&mut self, sequence: u32) -> Result<(), Replay> {
+        let Some(highest) = self.highest.filter(|highest| *highest >= sequence) else {
+            let shift = self.highest.map_or(REPLAY_WINDOW_LEN, |highest| sequence - highest);
+            self.seen = self.seen.checked_shl(shift).unwrap_or(0) | 1;
+            self.highest = Some(sequence);
+            return Ok(());
+        };
+        let offset = highest - sequence;
+        if offset >= REPLAY_WINDOW_LEN {
+            return Err(Replay::Stale);
+        }
+        if self.seen & (1 << offset) != 0 {
+            return Err(Replay::Duplicate);
+        }
+        self.seen |= 1 << offset;
+        Ok(())
+    }
+
+    /// Returns the ranges of the numbers received in the window, newest first, up to
+    /// `max_ranges` of them.
+    pub(crate) fn received_ranges(&self, max_ranges: usize) -> Vec<RangeInclusive<u32>> {
+        let Some(highest) = self.highest else { return Vec::new() };
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        while offset < REPLAY_WINDOW_LEN && ranges.len() < max_ranges {
+            if self.seen & (1 << offset) == 0 {
+                offset += 1;
+                continue;
+            }
+            let end = highest - offset;
+            while offset < REPLAY_WINDOW_LEN && self.seen & (1 << offset) != 0 {
+                offset += 1;
+            }
+            ranges.push(highest - (offset - 1)..=end);
+        }
+        ranges
+    }
+}
+
+/// Frames dropped as replays, e.g. for metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Frames whose sequence number was already received.
+    pub duplicates: u64,
+    /// Frames whose sequence number was too old to tell.
+    pub stale: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ReplayCounters {
+    duplicates: AtomicU64,
+    stale: AtomicU64,
+}
+
+impl ReplayCounters {
+    pub(crate) fn record(&self, replay: Replay) {
+        let counter = match replay {
+            Replay::Duplicate => &self.duplicates,
+            Replay::Stale => &self.stale,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> ReplayStats {
+        ReplayStats {
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert_eq!(window.check(5), Ok(()));
+        assert_eq!(window.check(5), Err(Replay::Duplicate));
+        assert_eq!(window.check(3), Ok(()));
+        assert_eq!(window.check(3), Err(Replay::Duplicate));
+        assert_eq!(window.check(70), Ok(()));
+        assert_eq!(window.check(5), Err(Replay::Stale));
+        assert_eq!(window.check(7), Ok(()));
+        assert_eq!(window.check(6), Err(Replay::Stale));
+        assert_eq!(window.check(1000), Ok(()));
+        assert_eq!(window.check(70), Err(Replay::Stale));
+        assert_eq!(window.check(999), Ok(()));
+        assert_eq!(window.received_ranges(2), vec![999..=1000]);
+
+        let mut window = ReplayWindow::default();
+        assert_eq!(window.received_ranges(2), vec![]);
+        [1, 2, 4, 6].into_iter().for_each(|sequence| window.check(sequence).unwrap());
+        assert_eq!(window.received_ranges(2), vec![6..=6, 4..=4]);
+        assert_eq!(window.received_ranges(3), vec![6..=6, 4..=4, 1..=2]);
+
+        let counters = ReplayCounters::default();
+        counters.record(Replay::Duplicate);
+        counters.record(Replay::Stale);
+        counters.record(Replay::Stale);
+        assert_eq!(counters.stats(), ReplayStats { duplicates: 1, stale: 2 });
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Per-request metadata, including the trace id correlating native and Java logs.
+//!
+//! Trace ids are generated natively, from the process id and a counter, and passed to Java
+//! with the request. Requests of a single authentication flow may share one.
+//!
+//! An idempotency key, chosen by the caller, marks the retries of a request as the same request.
+
+use crate::connection_limiter::Priority;
+use crate::retry_policy::RetryPolicy;
+use jni::sys::jlong;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+static NEXT_TRACE_SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Identifies a request, or a flow of requests, in native and Java logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(u64);
+
+impl TraceId {
+    /// Generates a trace id unique on the device while the process lives.
+    pub fn generate() -> Self {
+        let sequence = NEXT_TRACE_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        Self((u64::from(std::process::id()) << 32) | u64::from(sequence))
+    }
+
+    /// Returns the value passed to Java.
+    pub fn as_jlong(self) -> jlong {
+        self.0 as jlong
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Identifies a request across its retries, so that the platform answers a retry of a completed
+/// request with the cached response instead of sending it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(u64);
+
+impl IdempotencyKey {
+    /// Wraps a key chosen by the caller, unique among its recent requests on a connection.
+    pub fn new(key: u64) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Display for IdempotencyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Describes a request beyond its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestMetadata {
+    /// Correlates the logs of the request.
+    pub trace_id: TraceId,
+    /// Optional absolute deadline, in `SystemClock.elapsedRealtime()` milliseconds.
+    pub deadline: Option<i64>,
+    /// Optional absolute deadline for handing the request over to Java, in
+    /// `SystemClock.elapsedRealtime()` milliseconds. Past it, the request is dropped unsent.
+    pub send_by: Option<i64>,
+    /// Urgency of the request when its connection is congested.
+    pub priority: Priority,
+    /// Optional key suppressing duplicates of the request.
+    pub idempotency_key: Option<IdempotencyKey>,
+    /// Optional retry policy, overriding the one of the platform.
+    pub retry_policy: Option<RetryPolicy>,
+}
+
+impl RequestMetadata {
+    /// Creates metadata with a new trace id, no deadline, normal priority, no idempotency key
+    /// and the retry policy of the platform.
+    pub fn new() -> Self {
+        Self {
+            trace_id: TraceId::generate(),
+            deadline: None,
+            send_by: None,
+            priority: Priority::Normal,
+            idempotency_key: None,
+            retry_policy: None,
+        }
+    }
+
+    /// Sets the trace id, e.g. to correlate the request with others of the same flow.
+    pub fn with_trace_id(mut self, trace_id: TraceId) -> Self {
+        self.trace_id = trace_id;
+        self
+    }
+
+    /// Sets the deadline, in `SystemClock.elapsedRealtime()` milliseconds.
+    pub fn with_deadline(mut self, deadline_millis: i64) -> Self {
+        self.deadline = Some(deadline_millis);
+        self
+    }
+
+    /// Sets the deadline for sending the request, in `SystemClock.elapsedRealtime()`
+    /// milliseconds, e.g. so that a request stuck behind a paused connection is not sent once
+    /// nobody waits for it.
+    pub fn with_send_by(mut self, send_by_millis: i64) -> Self {
+        self.send_by = Some(send_by_millis);
+        self
+    }
+
+    /// Sets the priority.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the idempotency key.
+    pub fn with_idempotency_key(mut self, key: IdempotencyKey) -> Self {
+        self.idempotency_key = Some(key);
+        self
+    }
+
+    /// Sets the retry policy of the request.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+}
+
+impl Default for RequestMetadata {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_ids_are_unique() {
+        let first = TraceId::generate();
+        let second = TraceId::generate();
+        assert_ne!(first, second);
+        assert_eq!(first.as_jlong() >> 32, i64::from(std::process::id()));
+        assert_eq!(format!("{}", TraceId(0x2a)), "000000000000002a");
+    }
+}
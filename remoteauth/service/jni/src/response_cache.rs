@@ -0,0 +1,115 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Responses of recently completed idempotent requests.
+//!
+//! A request retried over a flaky transport may have been processed by the remote device
+//! already, with only the response lost. Requests carrying an idempotency key are answered from
+//! here for a while after they complete, instead of being sent again.
+
+use crate::request_metadata::IdempotencyKey;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a response stays cached after its request completes.
+pub(crate) const RESPONSE_CACHE_TTL: Duration = Duration::from_secs(30);
+/// Number of responses cached per connection; the oldest ones make room for new ones.
+pub(crate) const RESPONSE_CACHE_CAPACITY: usize = 32;
+
+#[derive(Debug)]
+struct Entry<V> {
+    key: IdempotencyKey,
+    stored_at: Instant,
+    value: V,
+}
+
+/// Keeps the responses of idempotent requests per connection, oldest first.
+#[derive(Debug)]
+pub(crate) struct ResponseCache<V> {
+    ttl: Duration,
+    capacity: usize,
+    connections: Mutex<HashMap<i32, VecDeque<Entry<V>>>>,
+}
+
+impl<V: Clone> ResponseCache<V> {
+    /// Keeps up to `capacity` responses per connection, each for `ttl`.
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self { ttl, capacity: capacity.max(1), connections: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the response cached for `key` on `connection_id`, unless it has expired.
+    pub(crate) fn get(&self, connection_id: i32, key: IdempotencyKey) -> Option<V> {
+        let mut connections = self.connections.lock().unwrap();
+        let entries = connections.get_mut(&connection_id)?;
+        // Entries expire in the order they were stored.
+        while entries.front().is_some_and(|entry| entry.stored_at.elapsed() >= self.ttl) {
+            entries.pop_front();
+        }
+        let value = entries.iter().find(|entry| entry.key == key).map(|entry| entry.value.clone());
+        if entries.is_empty() {
+            connections.remove(&connection_id);
+        }
+        value
+    }
+
+    /// Caches `value` as the response for `key` on `connection_id`.
+    pub(crate) fn insert(&self, connection_id: i32, key: IdempotencyKey, value: V) {
+        let mut connections = self.connections.lock().unwrap();
+        let entries = connections.entry(connection_id).or_default();
+        entries.retain(|entry| entry.key != key);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Entry { key, stored_at: Instant::now(), value });
+    }
+
+    /// Forgets the responses of `connection_id`, e.g. once it is closed.
+    pub(crate) fn remove(&self, connection_id: i32) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+}
+
+impl<V: Clone> Default for ResponseCache<V> {
+    fn default() -> Self {
+        Self::new(RESPONSE_CACHE_TTL, RESPONSE_CACHE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_responses() {
+        let cache = ResponseCache::new(RESPONSE_CACHE_TTL, 2);
+        cache.insert(1, IdempotencyKey::new(1), "a");
+        cache.insert(1, IdempotencyKey::new(2), "b");
+        assert_eq!(cache.get(1, IdempotencyKey::new(1)), Some("a"));
+        // Keys are per connection.
+        assert_eq!(cache.get(2, IdempotencyKey::new(1)), None);
+        // The oldest response makes room.
+        cache.insert(1, IdempotencyKey::new(3), "c");
+        assert_eq!(cache.get(1, IdempotencyKey::new(1)), None);
+        assert_eq!(cache.get(1, IdempotencyKey::new(3)), Some("c"));
+        cache.remove(1);
+        assert_eq!(cache.get(1, IdempotencyKey::new(2)), None);
+
+        let expiring = ResponseCache::new(Duration::ZERO, 2);
+        expiring.insert(1, IdempotencyKey::new(1), "a");
+        assert_eq!(expiring.get(1, IdempotencyKey::new(1)), None);
+    }
+}
@@ -0,0 +1,544 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Session resumption tickets, skipping the full secure channel handshake after a brief
+//! disconnect.
+//!
+//! After a secure channel handshake, the side acting as server issues a `NewTicket`: the session
+//! secret and the hash of its negotiation transcript, sealed as a `COSE_Encrypt0` with a ticket
+//! key only the server holds, valid for a limited time. The client keeps the ticket with the
+//! secret, by device id. On reconnect, it presents the ticket with a fresh nonce in a single round
+//! trip: the server opens it, checks it hasn't expired, and answers with a nonce of its own. Both
+//! sides derive the resumed session key from the secret, the original transcript hash and both
+//! nonces. A ticket is used once: the server keeps the IVs of the tickets redeemed until they
+//! expire. An expired, replayed or forged one is refused with an error frame, and the client
+//! falls back to the full handshake.
+
+use crate::codec::cbor::{DecodeLimits, Value};
+use crate::cose::{Aead, CoseEncrypt0};
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::kdf::{derive_key, KeyPurpose, IV_LEN};
+use crate::messages::{
+    decode_response, DecodeError, Encoding, Message, Reader, Request, TypedPlatform, Writer,
+};
+use crate::migration::{malformed, Migration, MigrationError, VersionedRecord};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::rng::{OsRng, SecureRng};
+use crate::schema::{Field, Rule, Schema};
+use crate::secret::Secret;
+use crate::transcript::Kdf;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Lifetime of the tickets of a `TicketIssuer`, unless set otherwise.
+pub const DEFAULT_TICKET_LIFETIME: Duration = Duration::from_secs(30 * 60);
+/// Length of the nonces of a resumption.
+pub const RESUMPTION_NONCE_LEN: usize = 16;
+/// Longest ticket accepted.
+pub const MAX_TICKET_LEN: usize = 1024;
+/// Most unexpired tickets a `TicketIssuer` keeps track of as redeemed.
+pub const MAX_REDEEMED_TICKETS: usize = 256;
+/// Additional data authenticated with the sealed ticket state.
+const TICKET_AAD: &[u8] = b"RemoteAuth ticket";
+
+const NONCE_RULE: Rule =
+    Rule::Bytes { min_len: RESUMPTION_NONCE_LEN, max_len: RESUMPTION_NONCE_LEN };
+const TICKET_RULE: Rule = Rule::Bytes { min_len: 1, max_len: MAX_TICKET_LEN };
+
+/// Why a session could not be resumed.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum ResumptionError {
+    /// The ticket is past its lifetime.
+    #[error("ticket expired")]
+    Expired,
+    /// The ticket was redeemed before, or too many unexpired tickets were to keep track of it.
+    #[error("ticket already redeemed")]
+    Replayed,
+    /// The ticket doesn't open with the ticket key, or isn't a ticket.
+    #[error("invalid ticket")]
+    InvalidTicket,
+    /// The ticket key failed to seal the ticket.
+    #[error("failed to seal ticket")]
+    Seal,
+    /// No random IV could be drawn to seal the ticket with.
+    #[error("no randomness for the ticket IV")]
+    Random,
+    /// The ticket lifetime overflows its expiry time.
+    #[error("ticket lifetime too long")]
+    Lifetime,
+}
+
+/// Ticket issued by the server after a secure channel handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewTicket {
+    /// Sealed ticket, opaque to the client.
+    pub ticket: Vec<u8>,
+    /// Seconds the ticket is valid for from now.
+    pub lifetime_secs: u32,
+}
+
+/// First and only message of a resumption, presenting a ticket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeRequest {
+    /// Ticket, as issued.
+    pub ticket: Vec<u8>,
+    /// Fresh nonce of the client.
+    pub nonce: Vec<u8>,
+}
+
+/// Answer to a `ResumeRequest` accepting the ticket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeAccept {
+    /// Fresh nonce of the server.
+    pub nonce: Vec<u8>,
+}
+
+impl Message for NewTicket {
+    const TYPE: u8 = 11;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "new_ticket",
+        fields: &[Field::new(1, "ticket", TICKET_RULE), Field::new(2, "lifetime_secs", Rule::U32)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.ticket);
+        writer.put_u32(self.lifetime_secs);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { ticket: reader.bytes()?, lifetime_secs: reader.u32()? })
+    }
+}
+
+impl Message for ResumeRequest {
+    const TYPE: u8 = 12;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "resume_request",
+        fields: &[Field::new(1, "ticket", TICKET_RULE), Field::new(2, "nonce", NONCE_RULE)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.ticket);
+        writer.put_bytes(&self.nonce);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { ticket: reader.bytes()?, nonce: reader.bytes()? })
+    }
+}
+
+impl Request for ResumeRequest {
+    type Response = ResumeAccept;
+}
+
+impl Message for ResumeAccept {
+    const TYPE: u8 = 13;
+    const SCHEMA: Option<Schema> =
+        Some(Schema { name: "resume_accept", fields: &[Field::new(1, "nonce", NONCE_RULE)] });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.nonce);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { nonce: reader.bytes()? })
+    }
+}
+
+/// Session state a ticket carries, sealed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionState {
+    /// Secret of the session, agreed by the secure channel.
+    pub secret: Secret<Vec<u8>>,
+    /// Hash of the negotiation transcript of the session.
+    pub transcript_hash: Vec<u8>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Seals session states into tickets, and opens them back, server side.
+pub struct TicketIssuer<A: Aead> {
+    key: A,
+    lifetime: Duration,
+    rng: Arc<dyn SecureRng>,
+    // Expiry of the tickets redeemed, by IV.
+    redeemed: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl<A: Aead> TicketIssuer<A> {
+    /// Issues tickets sealed with `key`, under IVs drawn from `OsRng`, valid for
+    /// `DEFAULT_TICKET_LIFETIME`.
+    pub fn new(key: A) -> Self {
+        Self {
+            key,
+            lifetime: DEFAULT_TICKET_LIFETIME,
+            rng: Arc::new(OsRng),
+            redeemed: Mutex::default(),
+        }
+    }
+
+    /// Sets how long tickets are valid for.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Sets the RNG the IVs of tickets are drawn from.
+    pub fn with_rng(mut self, rng: Arc<dyn SecureRng>) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Returns a ticket of `state`, sealed under a random IV.
+    pub fn issue(
+        &self,
+        state: &SessionState,
+        now: SystemTime,
+    ) -> Result<NewTicket, ResumptionError> {
+        let mut iv = [0; IV_LEN];
+        self.rng.fill(&mut iv).map_err(|_| ResumptionError::Random)?;
+        let expires_at =
+            unix_secs(now).checked_add(self.lifetime.as_secs()).ok_or(ResumptionError::Lifetime)?;
+        let contents = TicketContents { state: state.clone(), expires_at };
+        let plaintext = Secret::new(contents.to_bytes());
+        let sealed = CoseEncrypt0::encrypt(&self.key, None, &iv, plaintext.expose(), TICKET_AAD)
+            .map_err(|_| ResumptionError::Seal)?;
+        let lifetime_secs = u32::try_from(self.lifetime.as_secs()).unwrap_or(u32::MAX);
+        Ok(NewTicket { ticket: sealed.to_bytes(), lifetime_secs })
+    }
+
+    /// Opens `ticket`, and returns its session state unless it expired. A ticket is redeemed
+    /// once: afterwards, it fails with `Replayed`.
+    pub fn redeem(&self, ticket: &[u8], now: SystemTime) -> Result<SessionState, ResumptionError> {
+        let limits = DecodeLimits { max_len: MAX_TICKET_LEN, ..DecodeLimits::default() };
+        let sealed =
+            CoseEncrypt0::from_bytes(ticket, limits).map_err(|_| ResumptionError::InvalidTicket)?;
+        let plaintext = sealed
+            .decrypt(&self.key, TICKET_AAD)
+            .map(Secret::new)
+            .map_err(|_| ResumptionError::InvalidTicket)?;
+        let contents = TicketContents::from_bytes(plaintext.expose(), limits).map_err(|e| {
+            warn!("failed to read ticket: {}", e);
+            ResumptionError::InvalidTicket
+        })?;
+        let now = unix_secs(now);
+        if now >= contents.expires_at {
+            return Err(ResumptionError::Expired);
+        }
+
+        // The IV is drawn at random for each ticket, and authenticated with it.
+        let mut redeemed = self.redeemed.lock().unwrap();
+        redeemed.retain(|_, expires_at| now < *expires_at);
+        if redeemed.len() >= MAX_REDEEMED_TICKETS || redeemed.contains_key(&sealed.iv) {
+            return Err(ResumptionError::Replayed);
+        }
+        redeemed.insert(sealed.iv, contents.expires_at);
+        Ok(contents.state)
+    }
+}
+
+/// What a ticket seals.
+///
+/// Version 1 is an array of the secret, the transcript hash and the expiry time, version 2 a
+/// map of them by `TICKET_*_KEY`, so that fields can be added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TicketContents {
+    state: SessionState,
+    /// Seconds since the Unix epoch.
+    expires_at: u64,
+}
+
+const TICKET_SECRET_KEY: i64 = 1;
+const TICKET_TRANSCRIPT_HASH_KEY: i64 = 2;
+const TICKET_EXPIRES_AT_KEY: i64 = 3;
+
+fn migrate_v1_to_v2(value: Value) -> Result<Value, MigrationError> {
+    let Value::Array(items) = value else {
+        return Err(malformed::<TicketContents>("expected an array"));
+    };
+    let keys = [TICKET_SECRET_KEY, TICKET_TRANSCRIPT_HASH_KEY, TICKET_EXPIRES_AT_KEY];
+    if items.len() != keys.len() {
+        return Err(malformed::<TicketContents>("expected 3 items"));
+    }
+    Ok(Value::Map(keys.into_iter().map(|key| Value::Integer(key.into())).zip(items).collect()))
+}
+
+impl VersionedRecord for TicketContents {
+    const NAME: &'static str = "ticket";
+    const SCHEMA_VERSION: u32 = 2;
+    const MIGRATIONS: &'static [Migration] = &[migrate_v1_to_v2];
+
+    fn to_value(&self) -> Value {
+        Value::Map(vec![
+            (
+                Value::Integer(TICKET_SECRET_KEY.into()),
+                Value::Bytes(self.state.secret.expose().clone()),
+            ),
+            (
+                Value::Integer(TICKET_TRANSCRIPT_HASH_KEY.into()),
+                Value::Bytes(self.state.transcript_hash.clone()),
+            ),
+            (Value::Integer(TICKET_EXPIRES_AT_KEY.into()), Value::Integer(self.expires_at.into())),
+        ])
+    }
+
+    fn from_value(value: Value) -> Result<Self, MigrationError> {
+        let Value::Map(entries) = value else {
+            return Err(malformed::<Self>("expected a map"));
+        };
+        let field = |key: i64| {
+            entries
+                .iter()
+                .find(|(k, _)| *k == Value::Integer(key.into()))
+                .map(|(_, value)| value.clone())
+                .ok_or(malformed::<Self>("missing field"))
+        };
+        let (Value::Bytes(secret), Value::Bytes(transcript_hash), Value::Integer(expires_at)) = (
+            field(TICKET_SECRET_KEY)?,
+            field(TICKET_TRANSCRIPT_HASH_KEY)?,
+            field(TICKET_EXPIRES_AT_KEY)?,
+        ) else {
+            return Err(malformed::<Self>("field of the wrong type"));
+        };
+        let expires_at = u64::try_from(expires_at).map_err(|_| malformed::<Self>("bad expiry"))?;
+        let state = SessionState { secret: Secret::new(secret), transcript_hash };
+        Ok(Self { state, expires_at })
+    }
+}
+
+/// A ticket kept by the client, with the state it seals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ticket {
+    /// Sealed ticket, as issued.
+    pub ticket: Vec<u8>,
+    /// Session state of the ticket.
+    pub state: SessionState,
+    /// When the ticket expires.
+    pub expires_at: SystemTime,
+}
+
+/// Tickets of the client, by device id. Each ticket is taken out to be presented, once.
+#[derive(Debug, Default)]
+pub struct TicketCache {
+    tickets: Mutex<HashMap<String, Ticket>>,
+}
+
+impl TicketCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps `new_ticket` of `device_id`, issued now for the session of `state`, replacing its
+    /// previous ticket.
+    pub fn insert(&self, device_id: &str, new_ticket: NewTicket, state: SessionState) {
+        let lifetime = Duration::from_secs(new_ticket.lifetime_secs.into());
+        let ticket =
+            Ticket { ticket: new_ticket.ticket, state, expires_at: SystemTime::now() + lifetime };
+        self.tickets.lock().unwrap().insert(device_id.to_string(), ticket);
+    }
+
+    /// Takes the ticket of `device_id`, unless it has none or it expired at `now`.
+    pub fn take(&self, device_id: &str, now: SystemTime) -> Option<Ticket> {
+        let ticket = self.tickets.lock().unwrap().remove(device_id)?;
+        (now < ticket.expires_at).then_some(ticket)
+    }
+
+    /// Forgets the ticket of `device_id`, e.g. once it is unpaired.
+    pub fn remove(&self, device_id: &str) {
+        self.tickets.lock().unwrap().remove(device_id);
+    }
+}
+
+/// Derives a resumed session key of `len` bytes from the session `state` and the nonces of the
+/// client and of the server.
+pub fn derive_resumed_key(
+    kdf: &dyn Kdf,
+    state: &SessionState,
+    client_nonce: &[u8],
+    server_nonce: &[u8],
+    len: usize,
+) -> anyhow::Result<Secret<Vec<u8>>> {
+    let context = [&state.transcript_hash[..], client_nonce, server_nonce];
+    derive_key(kdf, KeyPurpose::Resumption, state.secret.expose(), &context, len)
+}
+
+/// Handshake presenting a ticket, completing with the nonce of the server.
+pub struct Resumption {
+    request: ResumeRequest,
+    encoding: Encoding,
+}
+
+impl Resumption {
+    /// Creates a resumption presenting `ticket` with `nonce`, in `encoding`.
+    pub fn new(ticket: Vec<u8>, nonce: Vec<u8>, encoding: Encoding) -> Self {
+        Self { request: ResumeRequest { ticket, nonce }, encoding }
+    }
+}
+
+impl Handshake for Resumption {
+    type Output = Vec<u8>;
+    const NAME: &'static str = "session resumption";
+
+    fn first_message(&mut self) -> Vec<u8> {
+        self.request.encode_as(self.encoding)
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<Vec<u8>>> {
+        let accept: ResumeAccept = decode_response(reply, self.encoding)?;
+        Ok(Step::Done(accept.nonce))
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Resumes the session of `ticket` on `connection_id`, in one round trip, and returns the
+    /// resumed session key of `len` bytes, presenting a nonce drawn from `rng`. On failure, e.g.
+    /// a `RemoteError` refusing the ticket, the caller runs the full handshake instead.
+    pub async fn resume_session(
+        &self,
+        connection_id: i32,
+        ticket: Ticket,
+        rng: &dyn SecureRng,
+        kdf: &dyn Kdf,
+        len: usize,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Secret<Vec<u8>>> {
+        let mut nonce = vec![0; RESUMPTION_NONCE_LEN];
+        rng.fill(&mut nonce)?;
+        let resumption =
+            Resumption::new(ticket.ticket, nonce.clone(), self.encoding(connection_id));
+        let mut machine = HandshakeMachine::new(resumption);
+        let server_nonce = match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(server_nonce) => server_nonce,
+            Err(e) => {
+                warn!("session resumption of connection {} failed: {:#}", connection_id, e);
+                return Err(e);
+            }
+        };
+        info!("connection {} resumed its session", connection_id);
+        derive_resumed_key(kdf, &ticket.state, &nonce, &server_nonce, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::cbor;
+    use crate::cose::Algorithm;
+    use crate::crc32c::crc32c;
+
+    // Not cryptography: a checksum keyed with a byte stands in for the ticket key.
+    struct FakeKey(u8);
+
+    impl Aead for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::A256Gcm
+        }
+
+        fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let mut sealed: Vec<u8> = plaintext.iter().map(|byte| byte ^ self.0).collect();
+            sealed.extend_from_slice(&crc32c(&[&[self.0], iv, aad, plaintext]).to_be_bytes());
+            Ok(sealed)
+        }
+
+        fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (sealed, tag) = ciphertext.split_at(ciphertext.len().checked_sub(4)?);
+            let plaintext: Vec<u8> = sealed.iter().map(|byte| byte ^ self.0).collect();
+            (crc32c(&[&[self.0], iv, aad, &plaintext]).to_be_bytes() == tag).then_some(plaintext)
+        }
+    }
+
+    fn state() -> SessionState {
+        SessionState { secret: Secret::new(vec![1; 32]), transcript_hash: vec![2; 32] }
+    }
+
+    #[test]
+    fn test_ticket() {
+        let issuer = TicketIssuer::new(FakeKey(7)).with_lifetime(Duration::from_secs(60));
+        let now = SystemTime::now();
+        let new_ticket = issuer.issue(&state(), now).unwrap();
+        assert_eq!(new_ticket.lifetime_secs, 60);
+        assert_eq!(NewTicket::decode(&new_ticket.encode()), Ok(new_ticket.clone()));
+        assert_eq!(issuer.redeem(&new_ticket.ticket, now), Ok(state()));
+        assert_eq!(
+            issuer.redeem(&new_ticket.ticket, now + Duration::from_secs(60)),
+            Err(ResumptionError::Expired)
+        );
+        let unused = issuer.issue(&state(), now).unwrap();
+        assert_eq!(
+            issuer.redeem(&unused.ticket, now + Duration::from_secs(60)),
+            Err(ResumptionError::Expired)
+        );
+        let other = TicketIssuer::new(FakeKey(8));
+        assert_eq!(other.redeem(&new_ticket.ticket, now), Err(ResumptionError::InvalidTicket));
+        assert_eq!(issuer.redeem(&[0xa0], now), Err(ResumptionError::InvalidTicket));
+
+        // Tickets sealed before their contents were versioned still redeem.
+        let expires_at = Value::Integer((unix_secs(now) + 60).into());
+        let state = state();
+        let v1 = cbor::encode(&Value::Array(vec![
+            Value::Bytes(state.secret.expose().clone()),
+            Value::Bytes(state.transcript_hash.clone()),
+            expires_at,
+        ]));
+        let sealed = CoseEncrypt0::encrypt(&FakeKey(7), None, &[1; 12], &v1, TICKET_AAD).unwrap();
+        assert_eq!(issuer.redeem(&sealed.to_bytes(), now), Ok(state.clone()));
+
+        let forever = TicketIssuer::new(FakeKey(7)).with_lifetime(Duration::MAX);
+        assert_eq!(forever.issue(&state, now), Err(ResumptionError::Lifetime));
+    }
+
+    #[test]
+    fn test_ticket_replay() {
+        let issuer = TicketIssuer::new(FakeKey(7)).with_lifetime(Duration::from_secs(60));
+        let now = SystemTime::now();
+        let new_ticket = issuer.issue(&state(), now).unwrap();
+        assert_eq!(issuer.redeem(&new_ticket.ticket, now), Ok(state()));
+        assert_eq!(issuer.redeem(&new_ticket.ticket, now), Err(ResumptionError::Replayed));
+        assert_eq!(
+            issuer.redeem(&new_ticket.ticket, now + Duration::from_secs(30)),
+            Err(ResumptionError::Replayed)
+        );
+
+        // Past the limit, tickets are refused until redeemed ones expire.
+        let tickets: Vec<_> =
+            (0..MAX_REDEEMED_TICKETS).map(|_| issuer.issue(&state(), now).unwrap()).collect();
+        let redeemed =
+            tickets.iter().filter(|ticket| issuer.redeem(&ticket.ticket, now).is_ok()).count();
+        assert_eq!(redeemed, MAX_REDEEMED_TICKETS - 1);
+        let later = now + Duration::from_secs(61);
+        let fresh = issuer.issue(&state(), later).unwrap();
+        assert_eq!(issuer.redeem(&fresh.ticket, later), Ok(state()));
+    }
+
+    #[test]
+    fn test_ticket_cache() {
+        let cache = TicketCache::new();
+        let new_ticket = NewTicket { ticket: vec![9], lifetime_secs: 60 };
+        cache.insert("device", new_ticket.clone(), state());
+        let now = SystemTime::now();
+        assert_eq!(cache.take("device", now).map(|ticket| ticket.ticket), Some(vec![9]));
+        // Tickets are used once.
+        assert_eq!(cache.take("device", now), None);
+        cache.insert("device", new_ticket, state());
+        assert_eq!(cache.take("device", now + Duration::from_secs(61)), None);
+    }
+}
@@ -0,0 +1,220 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Retries of requests failing on the transport.
+//!
+//! A failed attempt is retried after an exponential backoff, plus a random jitter so that the
+//! requests failing together on a flaky transport don't all retry at the same time. All the
+//! attempts of a request share its deadline.
+
+use crate::error::PlatformError;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(2);
+const DEFAULT_JITTER: Duration = Duration::from_millis(50);
+
+/// Kind of failure, deciding whether a request is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Failure Java reports for the transport, with a non-negative code.
+    Transport,
+    /// Native `PlatformError` code passed back by Java, e.g. `Busy`.
+    Native,
+}
+
+impl ErrorClass {
+    /// Returns the class of an error code reported to `on_send_request_error`.
+    pub fn of(error_code: i32) -> Self {
+        if error_code < 0 {
+            Self::Native
+        } else {
+            Self::Transport
+        }
+    }
+
+    fn bit(self) -> u8 {
+        match self {
+            Self::Transport => 1 << 0,
+            Self::Native => 1 << 1,
+        }
+    }
+}
+
+/// How a request failing on the transport is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: Duration,
+    // Bits of the retryable error classes.
+    retryable: u8,
+}
+
+impl RetryPolicy {
+    /// Makes up to `max_attempts` attempts, retrying transport failures.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: DEFAULT_JITTER,
+            retryable: ErrorClass::Transport.bit(),
+        }
+    }
+
+    /// Makes a single attempt.
+    pub fn none() -> Self {
+        Self::new(1)
+    }
+
+    /// Waits `base_delay` before the first retry, doubling up to `max_delay` for the next ones.
+    pub fn with_backoff(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay.max(base_delay);
+        self
+    }
+
+    /// Adds a random delay of up to `jitter` to each backoff.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Also retries the errors of `class`.
+    pub fn retry_on(mut self, class: ErrorClass) -> Self {
+        self.retryable |= class.bit();
+        self
+    }
+
+    /// Returns the maximum number of attempts, the first one included.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether a request failing with `error_code` on attempt `attempt`, counted from 1, is
+    /// retried.
+    pub fn should_retry(&self, attempt: u32, error_code: i32) -> bool {
+        // Errors the platform raises itself mean the request can't be sent, not that it was lost.
+        let is_final = error_code == PlatformError::Cancelled.error_code()
+            || error_code == PlatformError::PlatformDestroyed.error_code();
+        attempt < self.max_attempts
+            && !is_final
+            && self.retryable & ErrorClass::of(error_code).bit() != 0
+    }
+
+    /// Returns the delay before retrying after attempt `attempt`, counted from 1.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff_with(attempt, random())
+    }
+
+    fn backoff_with(&self, attempt: u32, random: u64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        let jitter_nanos = u64::try_from(self.jitter.as_nanos()).unwrap_or(u64::MAX);
+        let jitter = match jitter_nanos {
+            0 => Duration::ZERO,
+            nanos => Duration::from_nanos(random % (nanos + 1)),
+        };
+        delay + jitter
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+// Each RandomState is seeded differently, which is random enough for a jitter.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Counts of the retries of a platform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryStats {
+    /// Attempts made after a failed one.
+    pub retries: u64,
+    /// Requests that succeeded after at least one retry.
+    pub recovered: u64,
+    /// Requests that failed after their last attempt.
+    pub exhausted: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RetryCounters {
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl RetryCounters {
+    pub(crate) fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of a request completed after `attempts` attempts.
+    pub(crate) fn record_outcome(&self, attempts: u32, succeeded: bool) {
+        if attempts <= 1 {
+            return;
+        }
+        let counter = if succeeded { &self.recovered } else { &self.exhausted };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> RetryStats {
+        RetryStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::new(3);
+        assert!(policy.should_retry(1, 7));
+        assert!(policy.should_retry(2, 0));
+        assert!(!policy.should_retry(3, 7));
+        assert!(!policy.should_retry(1, PlatformError::Busy.error_code()));
+        let policy = policy.retry_on(ErrorClass::Native);
+        assert!(policy.should_retry(1, PlatformError::Busy.error_code()));
+        assert!(!policy.should_retry(1, PlatformError::Cancelled.error_code()));
+        assert!(!RetryPolicy::none().should_retry(1, 7));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new(5)
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(300))
+            .with_jitter(Duration::from_millis(10));
+        assert_eq!(policy.backoff_with(1, 0), Duration::from_millis(100));
+        assert_eq!(policy.backoff_with(2, 0), Duration::from_millis(200));
+        assert_eq!(policy.backoff_with(3, 0), Duration::from_millis(300));
+        assert_eq!(policy.backoff_with(2, 10_000_000), Duration::from_millis(210));
+        assert!(policy.backoff(4) <= Duration::from_millis(310));
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Random bytes of every nonce, challenge and key drawn natively.
+//!
+//! Whatever draws random bytes takes a `SecureRng`, by default `OsRng`, so that tests can inject
+//! a deterministic one. `OsRng` reads the kernel CSPRNG with `getrandom`, once it passed health
+//! checks: a sample of its output must not be stuck on a byte, repeat the previous sample, or
+//! hold any byte value far more often than chance allows. The checks run once per process, from
+//! `JNI_OnLoad` so that a broken RNG fails the load, or else on the first draw; an RNG that
+//! failed them fails every draw with `HealthCheck`.
+
+use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Length of each sample the health checks draw.
+const SAMPLE_LEN: usize = 256;
+/// Most times a byte value may occur in two samples, 16 times the mean: a uniform RNG exceeds
+/// it with a probability below 10^-20.
+const MAX_OCCURRENCES: usize = 32;
+
+static HEALTH: OnceLock<Result<(), RngError>> = OnceLock::new();
+
+/// Why no random bytes could be drawn.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RngError {
+    /// The kernel CSPRNG failed, with its errno.
+    #[error("getrandom failed with errno {0}")]
+    Unavailable(i32),
+    /// The output of the RNG failed a health check.
+    #[error("RNG health check failed: {0}")]
+    HealthCheck(&'static str),
+}
+
+/// Source of cryptographically secure random bytes.
+pub trait SecureRng: Send + Sync {
+    /// Fills `buf` with random bytes.
+    fn fill(&self, buf: &mut [u8]) -> Result<(), RngError>;
+}
+
+/// The kernel CSPRNG, health-checked before its first draw.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsRng;
+
+impl SecureRng for OsRng {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), RngError> {
+        check_health()?;
+        getrandom(buf)
+    }
+}
+
+/// Runs the health checks of the kernel CSPRNG, once per process, and returns their outcome.
+pub fn check_health() -> Result<(), RngError> {
+    HEALTH.get_or_init(|| health_check(getrandom)).clone()
+}
+
+/// Checks two samples drawn with `fill`.
+fn health_check(fill: impl Fn(&mut [u8]) -> Result<(), RngError>) -> Result<(), RngError> {
+    let (mut first, mut second) = ([0; SAMPLE_LEN], [0; SAMPLE_LEN]);
+    fill(&mut first)?;
+    fill(&mut second)?;
+    if first.iter().all(|byte| *byte == first[0]) {
+        return Err(RngError::HealthCheck("stuck output"));
+    }
+    if first == second {
+        return Err(RngError::HealthCheck("repeated output"));
+    }
+    let mut occurrences = [0; 256];
+    for byte in first.iter().chain(&second) {
+        occurrences[usize::from(*byte)] += 1;
+    }
+    if occurrences.iter().any(|count| *count > MAX_OCCURRENCES) {
+        return Err(RngError::HealthCheck("biased output"));
+    }
+    Ok(())
+}
+
+fn getrandom(buf: &mut [u8]) -> Result<(), RngError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        // Safety: rest is a valid buffer of rest.len() bytes for getrandom to fill in.
+        let result = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            // Interrupted by a signal: retries.
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(RngError::Unavailable(error.raw_os_error().unwrap_or_default()));
+            }
+        } else {
+            filled += result as usize;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    #[test]
+    fn test_os_rng() {
+        assert_eq!(check_health(), Ok(()));
+        let (mut first, mut second) = ([0; 32], [0; 32]);
+        OsRng.fill(&mut first).unwrap();
+        OsRng.fill(&mut second).unwrap();
+        assert_ne!(first, second);
+        OsRng.fill(&mut []).unwrap();
+    }
+
+    #[test]
+    fn test_health_check() {
+        assert_eq!(health_check(getrandom), Ok(()));
+        assert_eq!(
+            health_check(|buf: &mut [u8]| {
+                buf.fill(7);
+                Ok(())
+            }),
+            Err(RngError::HealthCheck("stuck output"))
+        );
+        let repeating = |buf: &mut [u8]| {
+            buf.iter_mut().enumerate().for_each(|(at, byte)| *byte = at as u8);
+            Ok(())
+        };
+        assert_eq!(health_check(repeating), Err(RngError::HealthCheck("repeated output")));
+        let next = AtomicU16::new(0);
+        let biased = |buf: &mut [u8]| {
+            for byte in buf.iter_mut() {
+                *byte = (next.fetch_add(1, Ordering::Relaxed) % 5) as u8;
+            }
+            Ok(())
+        };
+        assert_eq!(health_check(biased), Err(RngError::HealthCheck("biased output")));
+        assert_eq!(
+            health_check(|_: &mut [u8]| Err(RngError::Unavailable(libc::EIO))),
+            Err(RngError::Unavailable(libc::EIO))
+        );
+    }
+}
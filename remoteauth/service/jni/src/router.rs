@@ -0,0 +1,333 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Routing of inbound messages to handlers, by message type.
+//!
+//! A registry maps each message type to its kind: a response, an event the remote device
+//! pushes, an error it reports, or a control message of the protocol itself. Handlers register
+//! for a kind, or for a single type to override its kind's handler, so new message types only
+//! need registering instead of new callback plumbing. Given the identities of the connections,
+//! handlers may be told the device a message comes from rather than its connection.
+
+use crate::authenticator::{UnlockChallenge, UnlockResponse};
+use crate::capabilities::Capabilities;
+use crate::cipher_suite::{SuiteOffer, SuiteSelect};
+use crate::ctap2::{CtapRequest, CtapResponse};
+use crate::delta::StateSync;
+use crate::device_identity::{ConnectionIdentities, DeviceId, IdentityError};
+use crate::enrollment::{AttestationReply, AttestationRequest, Unenroll};
+use crate::messages::{
+    self, Challenge, ChallengeResponse, DecodeError, Encoding, KeySync, Message, Status,
+    TypedPlatform,
+};
+use crate::noise::NoiseMessage;
+use crate::pairing::{PairingConfirm, PairingReply, PairingStart};
+use crate::remote_error::ErrorFrame;
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::resumption::{NewTicket, ResumeAccept, ResumeRequest};
+use crate::transfer::{TransferAck, TransferChunk};
+use crate::versioning::{VersionAccept, VersionOffer};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// What an inbound message is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    /// Answers a request sent earlier.
+    Response,
+    /// Pushed by the remote device on its own, e.g. a request of its own.
+    Event,
+    /// Reports a failure of the remote device.
+    Error,
+    /// Runs the protocol itself, e.g. version negotiation.
+    Control,
+}
+
+/// Why an inbound message was not handled.
+#[derive(Debug, Error)]
+pub enum RouteError {
+    /// The message can't be decoded far enough to find its type.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// No kind is registered for the message type.
+    #[error("unknown message type {0}")]
+    UnknownType(u8),
+    /// No handler is registered for the message type or its kind.
+    #[error("no handler for message type {message_type} ({kind:?})")]
+    NoHandler {
+        /// Type of the message.
+        message_type: u8,
+        /// Kind of the message type.
+        kind: MessageKind,
+    },
+    /// The handler failed.
+    #[error("handler failed")]
+    Handler(#[source] anyhow::Error),
+}
+
+/// A message received on a connection, before being decoded as its type.
+#[derive(Debug, Clone, Copy)]
+pub struct InboundMessage<'a> {
+    /// Connection the message arrived on.
+    pub connection_id: i32,
+    /// Device the connection reaches, if the router knows it.
+    pub device_id: Option<&'a DeviceId>,
+    /// Type of the message.
+    pub message_type: u8,
+    /// Kind of the message type.
+    pub kind: MessageKind,
+    /// Encoded message.
+    pub bytes: &'a [u8],
+    /// Encoding of the connection.
+    pub encoding: Encoding,
+}
+
+impl InboundMessage<'_> {
+    /// Decodes the message as `M`.
+    pub fn decode<M: Message>(&self) -> Result<M, DecodeError> {
+        M::decode_as(self.bytes, self.encoding)
+    }
+}
+
+/// Handles inbound messages.
+pub type Handler = Box<dyn Fn(&InboundMessage) -> anyhow::Result<()> + Send + Sync>;
+
+/// Registry of message types and of their handlers.
+#[derive(Default)]
+pub struct MessageRouter {
+    kinds: HashMap<u8, MessageKind>,
+    kind_handlers: HashMap<MessageKind, Handler>,
+    type_handlers: HashMap<u8, Handler>,
+    identities: Option<Arc<ConnectionIdentities>>,
+}
+
+impl MessageRouter {
+    /// Creates a router knowing the message types of this library, without handlers.
+    pub fn new() -> Self {
+        let mut router = Self::default();
+        router
+            .register::<VersionOffer>(MessageKind::Control)
+            .register::<VersionAccept>(MessageKind::Control)
+            .register::<Capabilities>(MessageKind::Control)
+            .register::<SuiteOffer>(MessageKind::Control)
+            .register::<SuiteSelect>(MessageKind::Control)
+            .register::<NoiseMessage>(MessageKind::Control)
+            .register::<ChallengeResponse>(MessageKind::Response)
+            .register::<Status>(MessageKind::Response)
+            .register::<TransferAck>(MessageKind::Response)
+            .register::<ResumeAccept>(MessageKind::Response)
+            .register::<PairingReply>(MessageKind::Response)
+            .register::<AttestationReply>(MessageKind::Response)
+            .register::<UnlockResponse>(MessageKind::Response)
+            .register::<CtapResponse>(MessageKind::Response)
+            .register::<Challenge>(MessageKind::Event)
+            .register::<KeySync>(MessageKind::Event)
+            .register::<TransferChunk>(MessageKind::Event)
+            .register::<NewTicket>(MessageKind::Event)
+            .register::<StateSync>(MessageKind::Event)
+            .register::<ResumeRequest>(MessageKind::Event)
+            .register::<PairingStart>(MessageKind::Event)
+            .register::<PairingConfirm>(MessageKind::Event)
+            .register::<AttestationRequest>(MessageKind::Event)
+            .register::<Unenroll>(MessageKind::Event)
+            .register::<UnlockChallenge>(MessageKind::Event)
+            .register::<CtapRequest>(MessageKind::Event)
+            .register::<ErrorFrame>(MessageKind::Error);
+        router
+    }
+
+    /// Registers the type of `M` as being of `kind`, replacing its previous kind.
+    pub fn register<M: Message>(&mut self, kind: MessageKind) -> &mut Self {
+        self.kinds.insert(M::TYPE, kind);
+        self
+    }
+
+    /// Returns the kind of `message_type`, or None if it isn't registered.
+    pub fn kind(&self, message_type: u8) -> Option<MessageKind> {
+        self.kinds.get(&message_type).copied()
+    }
+
+    /// Routes the messages of `kind` without a handler for their type to `handler`.
+    pub fn on_kind<F>(&mut self, kind: MessageKind, handler: F) -> &mut Self
+    where
+        F: Fn(&InboundMessage) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.kind_handlers.insert(kind, Box::new(handler));
+        self
+    }
+
+    /// Routes the messages of type `M`, decoded, to `handler`, with their connection id.
+    pub fn on<M, F>(&mut self, handler: F) -> &mut Self
+    where
+        M: Message,
+        F: Fn(i32, M) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.type_handlers.insert(
+            M::TYPE,
+            Box::new(move |message| handler(message.connection_id, message.decode::<M>()?)),
+        );
+        self
+    }
+
+    /// Routes the messages of type `M`, decoded, to `handler`, with the device they come from.
+    /// Messages of connections without a device fail with `IdentityError::Unidentified`.
+    pub fn on_device<M, F>(&mut self, handler: F) -> &mut Self
+    where
+        M: Message,
+        F: Fn(&DeviceId, M) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.type_handlers.insert(
+            M::TYPE,
+            Box::new(move |message| {
+                let device_id =
+                    message.device_id.ok_or(IdentityError::Unidentified(message.connection_id))?;
+                handler(device_id, message.decode::<M>()?)
+            }),
+        );
+        self
+    }
+
+    /// Resolves the device of the connection of each message with `identities`.
+    pub fn identify(&mut self, identities: Arc<ConnectionIdentities>) -> &mut Self {
+        self.identities = Some(identities);
+        self
+    }
+
+    /// Hands `bytes`, received on `connection_id` with `encoding`, to the handler of its type,
+    /// or else of its kind. Returns its kind once handled.
+    pub fn route(
+        &self,
+        connection_id: i32,
+        bytes: &[u8],
+        encoding: Encoding,
+    ) -> Result<MessageKind, RouteError> {
+        let message_type = messages::message_type(bytes, encoding)?;
+        let kind = self.kind(message_type).ok_or(RouteError::UnknownType(message_type))?;
+        let handler = self
+            .type_handlers
+            .get(&message_type)
+            .or_else(|| self.kind_handlers.get(&kind))
+            .ok_or(RouteError::NoHandler { message_type, kind })?;
+        let device_id = self.identities.as_ref().and_then(|ids| ids.device(connection_id));
+        let message = InboundMessage {
+            connection_id,
+            device_id: device_id.as_ref(),
+            message_type,
+            kind,
+            bytes,
+            encoding,
+        };
+        handler(&message).map_err(RouteError::Handler)?;
+        debug!("routed message type {} ({:?}) of connection {}", message_type, kind, connection_id);
+        Ok(kind)
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Routes the messages the remote device pushes on `connection_id` with `router`, until the
+    /// platform is closed. Messages that can't be routed are dropped.
+    pub async fn route_messages(
+        &self,
+        connection_id: i32,
+        router: Arc<MessageRouter>,
+    ) -> anyhow::Result<()> {
+        let mut stream = self.platform().subscribe(connection_id)?;
+        while let Some(bytes) = stream.next().await {
+            if let Err(e) = router.route(connection_id, &bytes, self.encoding(connection_id)) {
+                warn!("dropping message of connection {}: {:?}", connection_id, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{Reader, Writer};
+    use crate::remote_error::ErrorCategory;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_route() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut router = MessageRouter::new();
+        let statuses = Arc::clone(&events);
+        router.on::<Status, _>(move |connection_id, status| {
+            statuses.lock().unwrap().push((connection_id, status.code));
+            Ok(())
+        });
+        let encoding = Encoding::default();
+        assert_eq!(
+            router.route(3, &Status { code: 7 }.encode(), encoding).unwrap(),
+            MessageKind::Response
+        );
+        assert_eq!(*events.lock().unwrap(), vec![(3, 7)]);
+
+        let error = ErrorFrame {
+            code: 1,
+            category: ErrorCategory::Unavailable,
+            retryable: true,
+            message: String::from("busy"),
+        }
+        .encode();
+        assert!(matches!(
+            router.route(3, &error, encoding),
+            Err(RouteError::NoHandler { message_type: 10, kind: MessageKind::Error })
+        ));
+        router.on_kind(MessageKind::Error, |message| {
+            assert_eq!(message.decode::<ErrorFrame>()?.code, 1);
+            Ok(())
+        });
+        assert_eq!(router.route(3, &error, encoding).unwrap(), MessageKind::Error);
+
+        struct Unknown;
+        impl Message for Unknown {
+            const TYPE: u8 = 200;
+            fn encode_fields(&self, _: &mut Writer) {}
+            fn decode_fields(_: &mut Reader) -> Result<Self, DecodeError> {
+                Ok(Self)
+            }
+        }
+        assert!(matches!(
+            router.route(3, &Unknown.encode(), encoding),
+            Err(RouteError::UnknownType(200))
+        ));
+    }
+
+    #[test]
+    fn test_route_by_device() {
+        let devices = Arc::new(Mutex::new(Vec::new()));
+        let identities = Arc::new(ConnectionIdentities::default());
+        let watch = DeviceId::new("watch").unwrap();
+        identities.bind(3, watch.clone());
+        let mut router = MessageRouter::new();
+        let handled = Arc::clone(&devices);
+        router.identify(Arc::clone(&identities)).on_device::<Status, _>(move |device_id, _| {
+            handled.lock().unwrap().push(device_id.clone());
+            Ok(())
+        });
+        let status = Status { code: 0 }.encode();
+        router.route(3, &status, Encoding::default()).unwrap();
+        assert_eq!(*devices.lock().unwrap(), [watch]);
+        let Err(RouteError::Handler(e)) = router.route(4, &status, Encoding::default()) else {
+            panic!("routed a message of an unidentified connection");
+        };
+        assert_eq!(e.downcast_ref(), Some(&IdentityError::Unidentified(4)));
+    }
+}
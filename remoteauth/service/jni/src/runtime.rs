@@ -0,0 +1,141 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Process-wide Tokio runtime shared by all JNI entry points.
+//!
+//! Entry points enter the runtime, so that tasks spawned while they run land on it. The runtime is
+//! built once, by native_init or lazily on first use, and lives for the lifetime of the process.
+
+use anyhow::anyhow;
+use log::warn;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use tokio::runtime::{Builder, Runtime};
+
+const DRIVER_THREAD_NAME: &str = "remoteauth-rt-driver";
+
+/// Parameters of the shared runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Number of worker threads of the multi-threaded runtime. 0 uses one per CPU.
+    pub worker_threads: usize,
+    /// Prefix of the names of the runtime threads.
+    pub thread_name_prefix: String,
+    /// Upper bound of the threads spawned for blocking work.
+    pub max_blocking_threads: usize,
+    /// Runs every task on a single thread instead, for low-memory devices.
+    pub use_current_thread: bool,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: 0,
+            thread_name_prefix: String::from("remoteauth-rt"),
+            max_blocking_threads: 4,
+            use_current_thread: false,
+        }
+    }
+}
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fn build_runtime(config: &RuntimeConfig) -> anyhow::Result<Runtime> {
+    let mut builder = if config.use_current_thread {
+        Builder::new_current_thread()
+    } else {
+        let mut builder = Builder::new_multi_thread();
+        if config.worker_threads > 0 {
+            builder.worker_threads(config.worker_threads);
+        }
+        builder
+    };
+    let prefix = config.thread_name_prefix.clone();
+    let thread_id = AtomicUsize::new(0);
+    builder
+        .thread_name_fn(move || format!("{}-{}", prefix, thread_id.fetch_add(1, Ordering::Relaxed)))
+        .max_blocking_threads(config.max_blocking_threads.max(1))
+        .enable_all()
+        .build()
+        .map_err(|e| anyhow!("Failed to build the remoteauth runtime: {:?}", e))
+}
+
+/// Builds the shared runtime with `config`. Does nothing if the runtime is already built.
+pub(crate) fn init_runtime(config: &RuntimeConfig) -> anyhow::Result<&'static Runtime> {
+    if let Some(runtime) = RUNTIME.get() {
+        warn!("init_runtime: runtime already built, ignoring {:?}", config);
+        return Ok(runtime);
+    }
+    let runtime = build_runtime(config)?;
+    let mut built_here = false;
+    let runtime = RUNTIME.get_or_init(|| {
+        built_here = true;
+        runtime
+    });
+    if built_here && config.use_current_thread {
+        // A current-thread runtime only makes progress inside block_on: dedicate a thread to it
+        // so spawned tasks (timeouts, watchdogs) keep running between JNI calls.
+        thread::Builder::new()
+            .name(DRIVER_THREAD_NAME.to_string())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+    }
+    Ok(runtime)
+}
+
+/// Returns the shared runtime, building it with the default config if native_init did not.
+pub(crate) fn get_runtime() -> &'static Runtime {
+    match RUNTIME.get() {
+        Some(runtime) => runtime,
+        None => {
+            init_runtime(&RuntimeConfig::default()).expect("Failed to build the remoteauth runtime")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that every caller observes the same runtime instance.
+    #[test]
+    fn test_get_runtime_is_shared() {
+        assert!(std::ptr::eq(get_runtime(), get_runtime()));
+        assert_eq!(get_runtime().block_on(async { 1 + 1 }), 2);
+    }
+
+    #[test]
+    fn test_build_runtime_with_config() {
+        let config = RuntimeConfig {
+            worker_threads: 2,
+            thread_name_prefix: String::from("test-rt"),
+            ..Default::default()
+        };
+        let name = build_runtime(&config)
+            .unwrap()
+            .block_on(async {
+                tokio::spawn(async { thread::current().name().map(String::from) }).await
+            })
+            .unwrap();
+        assert!(name.unwrap().starts_with("test-rt-"));
+    }
+
+    #[test]
+    fn test_build_current_thread_runtime() {
+        let config = RuntimeConfig { use_current_thread: true, ..Default::default() };
+        assert_eq!(build_runtime(&config).unwrap().block_on(async { 1 + 1 }), 2);
+    }
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Schemas inbound messages are validated against before being decoded.
+//!
+//! A schema lists the fields of a message with their type and bounds, like the CDDL (RFC 8610)
+//! of its CBOR map, e.g. `challenge = { 0: 1, 1: bstr .size (1..64) }`. Validation happens before
+//! any field reaches the message, and reports the field and the rule it breaks, so that a
+//! malformed peer fails precisely instead of somewhere in business logic.
+
+use std::fmt;
+use thiserror::Error;
+
+/// Type and bounds of a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// An unsigned integer, within `min..=max`.
+    Uint {
+        /// Smallest value accepted.
+        min: u64,
+        /// Largest value accepted.
+        max: u64,
+    },
+    /// A byte string, of `min_len..=max_len` bytes.
+    Bytes {
+        /// Shortest length accepted.
+        min_len: usize,
+        /// Longest length accepted.
+        max_len: usize,
+    },
+}
+
+impl Rule {
+    /// Any unsigned integer fitting in 32 bits.
+    pub const U32: Rule = Rule::Uint { min: 0, max: u32::MAX as u64 };
+
+    /// Returns the CDDL name of the type of the rule.
+    pub(crate) fn cddl_type(&self) -> &'static str {
+        match self {
+            Rule::Uint { .. } => "uint",
+            Rule::Bytes { .. } => "bstr",
+        }
+    }
+
+    /// Checks an integer field.
+    pub(crate) fn check_uint(&self, value: u64) -> Result<(), Violation> {
+        match *self {
+            Rule::Uint { min, max } if (min..=max).contains(&value) => Ok(()),
+            Rule::Uint { min, max } => Err(Violation::OutOfRange { value, min, max }),
+            Rule::Bytes { .. } => Err(Violation::WrongType { expected: self.cddl_type() }),
+        }
+    }
+
+    /// Checks a byte string field of `len` bytes.
+    pub(crate) fn check_bytes(&self, len: usize) -> Result<(), Violation> {
+        match *self {
+            Rule::Bytes { min_len, max_len } if (min_len..=max_len).contains(&len) => Ok(()),
+            Rule::Bytes { min_len, max_len } => Err(Violation::BadLength { len, min_len, max_len }),
+            Rule::Uint { .. } => Err(Violation::WrongType { expected: self.cddl_type() }),
+        }
+    }
+}
+
+/// A field of a schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    /// Number of the field, from 1.
+    pub number: u32,
+    /// Name of the field, for errors.
+    pub name: &'static str,
+    /// Type and bounds of the field.
+    pub rule: Rule,
+}
+
+impl Field {
+    /// Creates a field.
+    pub const fn new(number: u32, name: &'static str, rule: Rule) -> Self {
+        Self { number, name, rule }
+    }
+}
+
+/// Fields of a message. All are required.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schema {
+    /// Name of the message, for errors.
+    pub name: &'static str,
+    /// Fields of the message.
+    pub fields: &'static [Field],
+}
+
+/// How a field breaks its rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The field is absent.
+    Missing,
+    /// The field has another type.
+    WrongType {
+        /// CDDL name of the type of the rule.
+        expected: &'static str,
+    },
+    /// The integer is out of bounds.
+    OutOfRange {
+        /// Value of the field.
+        value: u64,
+        /// Smallest value accepted.
+        min: u64,
+        /// Largest value accepted.
+        max: u64,
+    },
+    /// The byte string is too short or too long.
+    BadLength {
+        /// Length of the field.
+        len: usize,
+        /// Shortest length accepted.
+        min_len: usize,
+        /// Longest length accepted.
+        max_len: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Missing => write!(f, "missing"),
+            Violation::WrongType { expected } => write!(f, "not a {}", expected),
+            Violation::OutOfRange { value, min, max } => {
+                write!(f, "{} not in {}..{}", value, min, max)
+            }
+            Violation::BadLength { len, min_len, max_len } => {
+                write!(f, "{} bytes, not in {}..{}", len, min_len, max_len)
+            }
+        }
+    }
+}
+
+/// A message breaking its schema.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("{message}.{field} (field {number}): {violation}")]
+pub struct SchemaError {
+    /// Name of the message.
+    pub message: &'static str,
+    /// Name of the field.
+    pub field: &'static str,
+    /// Number of the field.
+    pub number: u32,
+    /// How the field breaks its rule.
+    pub violation: Violation,
+}
+
+impl Schema {
+    /// Checks the fields of a message, each given by its number and the result of checking its
+    /// value against its rule. Fields the schema doesn't list are left to the caller.
+    pub(crate) fn validate<F>(&self, check: F) -> Result<(), SchemaError>
+    where
+        F: Fn(&Field) -> Result<(), Violation>,
+    {
+        for field in self.fields {
+            check(field).map_err(|violation| SchemaError {
+                message: self.name,
+                field: field.name,
+                number: field.number,
+                violation,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let nonce = Rule::Bytes { min_len: 1, max_len: 64 };
+        assert_eq!(nonce.check_bytes(16), Ok(()));
+        assert_eq!(
+            nonce.check_bytes(0),
+            Err(Violation::BadLength { len: 0, min_len: 1, max_len: 64 })
+        );
+        assert_eq!(nonce.check_uint(1), Err(Violation::WrongType { expected: "bstr" }));
+        assert_eq!(Rule::U32.check_uint(u32::MAX.into()), Ok(()));
+        assert_eq!(
+            Rule::U32.check_uint(1 << 32),
+            Err(Violation::OutOfRange { value: 1 << 32, min: 0, max: u32::MAX.into() })
+        );
+        let error = SchemaError {
+            message: "challenge",
+            field: "nonce",
+            number: 1,
+            violation: Violation::Missing,
+        };
+        assert_eq!(error.to_string(), "challenge.nonce (field 1): missing");
+    }
+}
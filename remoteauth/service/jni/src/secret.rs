@@ -0,0 +1,147 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Secret material: keys, nonces and tokens.
+//!
+//! A `Secret` wipes its bytes from memory when dropped, with volatile writes the compiler can't
+//! elide, never prints them, and compares them in constant time. Any other comparison of MACs
+//! or tokens goes through `constant_time_eq`, so that the time it takes doesn't tell how many
+//! leading bytes matched.
+
+use std::hint::black_box;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Memory that can be overwritten with zeros.
+pub trait Wipe {
+    /// Overwrites the memory with zeros.
+    fn wipe(&mut self);
+}
+
+impl<const N: usize> Wipe for [u8; N] {
+    fn wipe(&mut self) {
+        wipe_bytes(self);
+    }
+}
+
+impl Wipe for Vec<u8> {
+    fn wipe(&mut self) {
+        // Also wipes the spare capacity, which may hold bytes truncated before.
+        self.resize(self.capacity(), 0);
+        wipe_bytes(self);
+        self.clear();
+    }
+}
+
+/// Secret material, wiped when dropped.
+#[derive(Clone, Default)]
+pub struct Secret<T: Wipe>(T);
+
+impl<T: Wipe> Secret<T> {
+    /// Wraps `value`.
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Returns the secret, to use it without copying it.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// Returns the secret, to update it in place.
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Wipe> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Wipe> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.wipe();
+    }
+}
+
+impl<T: Wipe> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl<T: Wipe + AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(self.0.as_ref(), other.0.as_ref())
+    }
+}
+
+impl<T: Wipe + AsRef<[u8]>> Eq for Secret<T> {}
+
+/// Compares `a` and `b` in constant time, given their lengths, which aren't secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // Ors all the differences, and hides the result from the optimizer until the end, not to
+    // stop at the first one.
+    a.len() == b.len() && black_box(a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y))) == 0
+}
+
+fn wipe_bytes(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // Safety: byte is a valid and aligned reference to a u8.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    // Keeps the writes from being moved past the memory being freed.
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wipe() {
+        let mut array = [7; 4];
+        array.wipe();
+        assert_eq!(array, [0; 4]);
+
+        let mut vec = vec![7; 8];
+        vec.truncate(2);
+        vec.wipe();
+        assert!(vec.is_empty());
+        // Safety: the capacity was initialized by the wipe.
+        unsafe { vec.set_len(8) };
+        assert_eq!(vec, [0; 8]);
+    }
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::new([7; 4]);
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+        assert_eq!(secret.expose(), &[7; 4]);
+        assert_eq!(secret, Secret::from([7; 4]));
+        assert_ne!(secret, Secret::from([7, 7, 7, 8]));
+        assert_ne!(Secret::new(vec![7; 4]), Secret::new(vec![7; 3]));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"tag", b"tag"));
+        assert!(!constant_time_eq(b"tag", b"tae"));
+        assert!(!constant_time_eq(b"tag", b"ta"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,842 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Encryption of the traffic of a connection, end-to-end above the transport.
+//!
+//! Once a handshake agreed on session keys, a `SecureChannel` seals each payload sent on the
+//! connection with the key of its direction, of the cipher of the negotiated cipher suite, see
+//! `cipher_suite`, and opens each payload received with the key of the other one. Either direction numbers its payloads with a counter, carried in
+//! clear ahead of the ciphertext: the nonce is the IV of the direction XORed with it, handed out
+//! by a `NonceManager` so that no nonce is used twice with a key, and payloads whose counter was
+//! already received are dropped as replays. Payloads are padded to the configured
+//! `padding_buckets` before sealing.
+//!
+//! A direction whose counter ran out fails with `Exhausted`: the session needs new keys, from a
+//! new handshake or a resumption. Sessions established from chain keys instead rotate their keys
+//! with a hash ratchet, see `ratchet`: before sending, a direction due to rotate sends a `Rekey`
+//! frame sealed with a label of its own, then seals with the key of the next epoch.
+//!
+//! On a transport that already encrypts, both sides may agree, through `Capabilities`, to only
+//! authenticate payloads: the AES-256-GCM ciphertext is then replaced by the padded payload
+//! followed by its HMAC-SHA256, with the same counters, additional data and replay protection.
+//!
+//! Once established, the session is bound to the device whose identity the handshake
+//! authenticated with `identify`, which checks it against the pin of its enrollment, see
+//! `pinning`: a device that doesn't match loses the session.
+
+use crate::cipher_suite::CipherSuite;
+use crate::config;
+use crate::cose::{Aead, Algorithm};
+use crate::device_identity::{ConnectionIdentities, DeviceId, IdentityError};
+use crate::error::PlatformError;
+use crate::kdf::{hmac_sha256, MacSecrets, HASH_LEN};
+use crate::messages::Message;
+use crate::noise::AeadFactory;
+use crate::nonce::{nonce, NonceError, NonceManager};
+use crate::padding::{pad, unpad};
+use crate::pinning::{IdentityPinning, PinningError, PresentedIdentity};
+use crate::ratchet::{rotation_due, RatchetKeys, Rekey};
+use crate::remoteauth_jni_android_platform::{
+    MessageStream, OneshotCallback, Platform, RequestMetadata, Response,
+};
+use crate::replay_window::ReplayWindow;
+use crate::secret::{constant_time_eq, Secret};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+/// Length of the nonces, and of the IV of each direction.
+pub const NONCE_LEN: usize = 12;
+/// Length of the counter ahead of each sealed payload.
+pub const COUNTER_LEN: usize = 4;
+
+/// Additional data of each sealed payload, besides its counter.
+const AAD_LABEL: &[u8] = b"RemoteAuth secure channel";
+/// Additional data of each sealed `Rekey` frame, besides its counter.
+const REKEY_AAD_LABEL: &[u8] = b"RemoteAuth secure channel rekey";
+
+/// Why a payload could not be sealed or opened.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum SecureChannelError {
+    /// The connection has no session keys.
+    #[error("no secure session on connection {0}")]
+    NoSession(i32),
+    /// The session keys are not of the cipher of the negotiated suite.
+    #[error("session key algorithm {0:?} is not the one of the cipher suite")]
+    Algorithm(Algorithm),
+    /// The counter of the outbound direction ran out: the session needs new keys.
+    #[error("nonces exhausted")]
+    Exhausted,
+    /// The payload is too short to hold its counter, or its padding is malformed.
+    #[error("malformed payload")]
+    Malformed,
+    /// The payload doesn't authenticate with the inbound key.
+    #[error("payload doesn't authenticate")]
+    Unauthenticated,
+    /// The counter of the payload was already received, or is too old to tell.
+    #[error("replayed payload {0}")]
+    Replayed(u32),
+    /// The request failed on the platform, with its error code.
+    #[error("request failed with {0}")]
+    Failed(i32),
+    /// The payload was a `Rekey` frame, not data: the inbound key moved to the epoch.
+    #[error("rekeyed to epoch {0}")]
+    Rekeyed(u32),
+    /// Only the counters reserved to rotate the outbound key are left: it must rotate, with
+    /// `rekey`, before sealing data.
+    #[error("outbound key must rotate")]
+    RekeyRequired,
+    /// The outbound key rotated to a key with the same IV: the session is unusable.
+    #[error("nonce reused")]
+    NonceReused,
+}
+
+impl From<NonceError> for SecureChannelError {
+    fn from(error: NonceError) -> Self {
+        match error {
+            NonceError::RekeyRequired => Self::RekeyRequired,
+            NonceError::Exhausted => Self::Exhausted,
+            NonceError::Reused => Self::NonceReused,
+        }
+    }
+}
+
+/// Key of one direction of a session.
+pub struct DirectionKey<A: Aead> {
+    /// AES-256-GCM key.
+    pub key: A,
+    /// IV, XORed with the counter of each payload into its nonce.
+    pub iv: Secret<[u8; NONCE_LEN]>,
+}
+
+/// Keys of a session, as agreed by a handshake.
+pub struct SessionKeys<A: Aead> {
+    /// Key sealing the payloads sent.
+    pub outbound: DirectionKey<A>,
+    /// Key opening the payloads received.
+    pub inbound: DirectionKey<A>,
+}
+
+/// Chain keys of a ratcheting session, and how to create the keys of their epochs.
+struct Ratchet<A: Aead> {
+    keys: RatchetKeys,
+    factory: Arc<dyn AeadFactory<Key = A> + Send + Sync>,
+    /// When the outbound key of the current epoch started.
+    since: Instant,
+}
+
+impl<A: Aead> Ratchet<A> {
+    /// Returns the key of the current epoch of the outbound chain, or of the inbound one.
+    fn direction_key(&self, outbound: bool) -> anyhow::Result<DirectionKey<A>> {
+        let chain = if outbound { &self.keys.outbound } else { &self.keys.inbound };
+        let secret = chain.direction_secret()?;
+        Ok(DirectionKey { key: self.factory.key(secret.key.expose()), iv: secret.iv })
+    }
+}
+
+/// Kind of a sealed payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frame {
+    Data,
+    Rekey,
+}
+
+impl Frame {
+    /// Returns the label of the additional data of the frames of this kind.
+    fn label(self) -> &'static [u8] {
+        match self {
+            Self::Data => AAD_LABEL,
+            Self::Rekey => REKEY_AAD_LABEL,
+        }
+    }
+}
+
+/// How the payloads of a session are protected.
+enum Protection<A: Aead> {
+    /// Sealed with AES-256-GCM keys.
+    Aead(SessionKeys<A>),
+    /// Authenticated with HMAC-SHA256 keys, and left for the transport to encrypt.
+    Integrity(MacSecrets),
+}
+
+/// Keys and counters of the session of a connection.
+struct Session<A: Aead> {
+    protection: Protection<A>,
+    /// Nonces of the outbound key, or counters only if the session is `Integrity`.
+    nonces: NonceManager,
+    received: ReplayWindow,
+    /// Chain keys of an `Aead` session rotating its keys.
+    ratchet: Option<Ratchet<A>>,
+}
+
+impl<A: Aead> Session<A> {
+    fn new(protection: Protection<A>, ratchet: Option<Ratchet<A>>) -> Self {
+        let iv = match &protection {
+            Protection::Aead(keys) => *keys.outbound.iv.expose(),
+            Protection::Integrity(_) => [0; NONCE_LEN],
+        };
+        // A ratcheting session keeps the last counter of each key for the frame rotating it.
+        let reserve = if ratchet.is_some() { 1 } else { 0 };
+        let nonces = NonceManager::new(iv, reserve);
+        Self { protection, nonces, received: ReplayWindow::default(), ratchet }
+    }
+
+    /// Replaces the outbound key of an `Aead` session, or else its inbound one.
+    fn replace_key(&mut self, outbound: bool, key: DirectionKey<A>) {
+        if let Protection::Aead(keys) = &mut self.protection {
+            *(if outbound { &mut keys.outbound } else { &mut keys.inbound }) = key;
+        }
+    }
+
+    /// Returns `payload` padded and sealed with the outbound key, as a `frame`.
+    fn seal(
+        &mut self,
+        frame: Frame,
+        payload: &[u8],
+        padding_buckets: &[usize],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (counter, nonce) = match frame {
+            Frame::Data => self.nonces.next(),
+            Frame::Rekey => self.nonces.next_reserved(),
+        }
+        .map_err(SecureChannelError::from)?;
+        let header = counter.to_be_bytes();
+        let aad = aad(frame.label(), &header);
+        let padded = pad(payload, padding_buckets);
+        let body = match &self.protection {
+            Protection::Aead(keys) => keys.outbound.key.seal(&nonce, &aad, &padded)?,
+            Protection::Integrity(secrets) => {
+                let tag = hmac_sha256(secrets.outbound.expose(), &[&aad, &padded]);
+                [&padded[..], &tag].concat()
+            }
+        };
+        Ok([&header[..], &body].concat())
+    }
+
+    /// Returns the padded payload of `body`, if it authenticates with the inbound key as the
+    /// `frame` numbered by `header`.
+    fn unseal(&self, frame: Frame, header: &[u8; COUNTER_LEN], body: &[u8]) -> Option<Vec<u8>> {
+        let aad = aad(frame.label(), header);
+        match &self.protection {
+            Protection::Aead(keys) => {
+                let nonce = nonce(keys.inbound.iv.expose(), u32::from_be_bytes(*header));
+                keys.inbound.key.open(&nonce, &aad, body)
+            }
+            Protection::Integrity(secrets) => {
+                let (padded, tag) = body.split_at(body.len().checked_sub(HASH_LEN)?);
+                let expected = hmac_sha256(secrets.inbound.expose(), &[&aad, padded]);
+                constant_time_eq(&expected, tag).then(|| padded.to_vec())
+            }
+        }
+    }
+}
+
+/// Returns the additional data of the payload numbered by the encoded `counter`, under `label`.
+fn aad(label: &[u8], counter: &[u8]) -> Vec<u8> {
+    [label, counter].concat()
+}
+
+/// Fails with `Algorithm` unless both `keys` are of the cipher of `suite`.
+fn check_algorithm<A: Aead>(
+    suite: CipherSuite,
+    keys: &SessionKeys<A>,
+) -> Result<(), SecureChannelError> {
+    for key in [&keys.outbound.key, &keys.inbound.key] {
+        if key.algorithm() != suite.algorithm() {
+            return Err(SecureChannelError::Algorithm(key.algorithm()));
+        }
+    }
+    Ok(())
+}
+
+/// Sessions of the connections of a `SecureChannel`, shared with its subscriptions.
+struct Sessions<A: Aead> {
+    sessions: Mutex<HashMap<i32, Session<A>>>,
+}
+
+impl<A: Aead> Sessions<A> {
+    fn seal(&self, connection_id: i32, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let config = config::snapshot();
+        let mut sessions = self.sessions.lock().unwrap();
+        let session =
+            sessions.get_mut(&connection_id).ok_or(SecureChannelError::NoSession(connection_id))?;
+        session.seal(Frame::Data, payload, &config.padding_buckets)
+    }
+
+    /// Rotates the outbound key of `connection_id` if it is due, or if `force`, once `send` sent
+    /// the `Rekey` frame announcing it. Returns whether it rotated: not if the session doesn't
+    /// ratchet, or isn't due. The session stays locked throughout, so that no payload is sealed
+    /// between the frame and the rotation, and a frame failing to send leaves the key as is.
+    fn rotate(
+        &self,
+        connection_id: i32,
+        force: bool,
+        send: impl FnOnce(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<bool> {
+        let config = config::snapshot();
+        let mut sessions = self.sessions.lock().unwrap();
+        let session =
+            sessions.get_mut(&connection_id).ok_or(SecureChannelError::NoSession(connection_id))?;
+        let Some(ratchet) = &session.ratchet else {
+            return Ok(false);
+        };
+        let due = session.nonces.rekey_required()
+            || rotation_due(session.nonces.used(), ratchet.since.elapsed(), &config);
+        if !force && !due {
+            return Ok(false);
+        }
+        let epoch =
+            ratchet.keys.outbound.epoch().checked_add(1).ok_or(SecureChannelError::Exhausted)?;
+        let frame =
+            session.seal(Frame::Rekey, &Rekey { epoch }.encode(), &config.padding_buckets)?;
+        send(&frame)?;
+        let ratchet = session.ratchet.as_mut().expect("checked above");
+        ratchet.keys.outbound.advance()?;
+        ratchet.since = Instant::now();
+        let outbound = ratchet.direction_key(true)?;
+        session.nonces.rekey(*outbound.iv.expose()).map_err(SecureChannelError::from)?;
+        session.replace_key(true, outbound);
+        Ok(true)
+    }
+
+    fn open(&self, connection_id: i32, sealed: &[u8]) -> Result<Vec<u8>, SecureChannelError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session =
+            sessions.get_mut(&connection_id).ok_or(SecureChannelError::NoSession(connection_id))?;
+        let (header, ciphertext) =
+            sealed.split_first_chunk::<COUNTER_LEN>().ok_or(SecureChannelError::Malformed)?;
+        let counter = u32::from_be_bytes(*header);
+        let (padded, frame) = match session.unseal(Frame::Data, header, ciphertext) {
+            Some(padded) => (padded, Frame::Data),
+            None if session.ratchet.is_some() => (
+                session
+                    .unseal(Frame::Rekey, header, ciphertext)
+                    .ok_or(SecureChannelError::Unauthenticated)?,
+                Frame::Rekey,
+            ),
+            None => return Err(SecureChannelError::Unauthenticated),
+        };
+        // Only authenticated counters move the window, so that forgeries can't push it ahead.
+        session.received.check(counter).map_err(|_| SecureChannelError::Replayed(counter))?;
+        let payload = unpad(&padded).map_err(|_| SecureChannelError::Malformed)?;
+        if frame == Frame::Data {
+            return Ok(payload.to_vec());
+        }
+        let Some(ratchet) = session.ratchet.as_mut() else {
+            return Err(SecureChannelError::Unauthenticated);
+        };
+        let rekey = Rekey::decode(payload).map_err(|_| SecureChannelError::Malformed)?;
+        if Some(rekey.epoch) != ratchet.keys.inbound.epoch().checked_add(1) {
+            return Err(SecureChannelError::Malformed);
+        }
+        ratchet.keys.inbound.advance()?;
+        let inbound = ratchet.direction_key(false).map_err(|_| SecureChannelError::Malformed)?;
+        session.replace_key(false, inbound);
+        session.received = ReplayWindow::default();
+        Err(SecureChannelError::Rekeyed(rekey.epoch))
+    }
+}
+
+/// Encrypts the traffic of the connections of a platform with their session keys.
+///
+/// A request and its response are sealed with the keys of their direction like any payload.
+/// Connections without session keys fail with `NoSession`, rather than sending in clear.
+/// Given the identities of the connections, payloads may be sent to a device rather than to
+/// one of its connections.
+pub struct SecureChannel<P: Platform + ?Sized, A: Aead> {
+    platform: Arc<P>,
+    sessions: Arc<Sessions<A>>,
+    identities: Arc<ConnectionIdentities>,
+    pinning: Option<Arc<IdentityPinning>>,
+}
+
+impl<P: Platform + ?Sized, A: Aead> SecureChannel<P, A> {
+    /// Wraps `platform`, without sessions.
+    pub fn new(platform: Arc<P>) -> Self {
+        Self::with_identities(platform, Arc::default())
+    }
+
+    /// Wraps `platform`, without sessions, with the identities of its connections, e.g. shared
+    /// with the router.
+    pub fn with_identities(platform: Arc<P>, identities: Arc<ConnectionIdentities>) -> Self {
+        Self {
+            platform,
+            sessions: Arc::new(Sessions { sessions: Mutex::default() }),
+            identities,
+            pinning: None,
+        }
+    }
+
+    /// Checks the identities of the devices against their pins with `pinning` in `identify`.
+    pub fn with_pinning(mut self, pinning: Arc<IdentityPinning>) -> Self {
+        self.pinning = Some(pinning);
+        self
+    }
+
+    /// Returns the wrapped platform.
+    pub fn platform(&self) -> &Arc<P> {
+        &self.platform
+    }
+
+    /// Returns the identities of the connections.
+    pub fn identities(&self) -> &Arc<ConnectionIdentities> {
+        &self.identities
+    }
+
+    /// Returns the connection with session keys reaching `device_id`, the oldest if several.
+    pub fn connection_to(&self, device_id: &DeviceId) -> Option<i32> {
+        let sessions = self.sessions.sessions.lock().unwrap();
+        self.identities
+            .connections(device_id)
+            .into_iter()
+            .find(|connection_id| sessions.contains_key(connection_id))
+    }
+
+    /// Starts the session of `connection_id` with `keys`, replacing its previous one and
+    /// restarting the counters. Fails with `Algorithm` unless both keys are of the cipher of
+    /// `suite`, the one negotiated on the connection, see `TypedPlatform::cipher_suite`.
+    pub fn establish(
+        &self,
+        connection_id: i32,
+        suite: CipherSuite,
+        keys: SessionKeys<A>,
+    ) -> Result<(), SecureChannelError> {
+        check_algorithm(suite, &keys)?;
+        let session = Session::new(Protection::Aead(keys), None);
+        self.sessions.sessions.lock().unwrap().insert(connection_id, session);
+        Ok(())
+    }
+
+    /// Starts the session of `connection_id` with the HMAC-SHA256 keys of `secrets`, replacing
+    /// its previous one. Payloads are authenticated but sent in clear, so only for connections
+    /// whose transport encrypts, and whose remote device agreed, see
+    /// `CapabilityCache::protection`.
+    pub fn establish_integrity(&self, connection_id: i32, secrets: MacSecrets) {
+        let session = Session::new(Protection::Integrity(secrets), None);
+        self.sessions.sessions.lock().unwrap().insert(connection_id, session);
+    }
+
+    /// Starts the session of `connection_id` at epoch 0 of the chains of `keys`, whose keys
+    /// `factory` creates, replacing its previous one. The session rotates its keys as
+    /// configured, see `ratchet`. Fails with `Algorithm` unless the keys are of the cipher of
+    /// `suite`, as `establish`.
+    pub fn establish_ratcheting(
+        &self,
+        connection_id: i32,
+        suite: CipherSuite,
+        keys: RatchetKeys,
+        factory: Arc<dyn AeadFactory<Key = A> + Send + Sync>,
+    ) -> anyhow::Result<()> {
+        let ratchet = Ratchet { keys, factory, since: Instant::now() };
+        let keys = SessionKeys {
+            outbound: ratchet.direction_key(true)?,
+            inbound: ratchet.direction_key(false)?,
+        };
+        check_algorithm(suite, &keys)?;
+        let session = Session::new(Protection::Aead(keys), Some(ratchet));
+        self.sessions.sessions.lock().unwrap().insert(connection_id, session);
+        Ok(())
+    }
+
+    /// Binds the session just established on `connection_id` to `device_id`, which presented
+    /// `identity` in the handshake. With pinning, fails with `PinMismatch` if the device is
+    /// pinned to another identity, or `NotEnrolled`, and then forgets the session keys.
+    pub fn identify(
+        &self,
+        connection_id: i32,
+        device_id: &DeviceId,
+        identity: &PresentedIdentity,
+    ) -> Result<(), PinningError> {
+        if let Some(pinning) = &self.pinning {
+            if let Err(e) = pinning.verify(connection_id, device_id, identity) {
+                self.forget_connection(connection_id);
+                return Err(e);
+            }
+        }
+        self.identities.bind(connection_id, device_id.clone());
+        Ok(())
+    }
+
+    /// Rotates the outbound key of `connection_id` now, if its session ratchets, and sends the
+    /// `Rekey` frame announcing it. Returns whether it rotated.
+    pub fn rekey(&self, connection_id: i32) -> anyhow::Result<bool> {
+        self.send_rekey(connection_id, true)
+    }
+
+    /// Rotates the outbound key of `connection_id` as due or if `force`, sending the `Rekey`
+    /// frame announcing it.
+    fn send_rekey(&self, connection_id: i32, force: bool) -> anyhow::Result<bool> {
+        let rotated = self.sessions.rotate(connection_id, force, |frame| {
+            self.platform.send_notification(connection_id, frame)
+        })?;
+        if !rotated {
+            return Ok(false);
+        }
+        info!("connection {} rotated its outbound key", connection_id);
+        Ok(true)
+    }
+
+    /// Whether `connection_id` has session keys.
+    pub fn is_established(&self, connection_id: i32) -> bool {
+        self.sessions.sessions.lock().unwrap().contains_key(&connection_id)
+    }
+
+    /// Forgets the session keys and the device of `connection_id`, once it is closed.
+    pub fn forget_connection(&self, connection_id: i32) {
+        self.sessions.sessions.lock().unwrap().remove(&connection_id);
+        self.identities.unbind(connection_id);
+    }
+
+    /// Returns `payload` padded and sealed with the outbound key of `connection_id`.
+    pub fn seal(&self, connection_id: i32, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.sessions.seal(connection_id, payload)
+    }
+
+    /// Returns the payload of `sealed`, opened with the inbound key of `connection_id`. Fails
+    /// with `Rekeyed` if it was a `Rekey` frame, which moved the inbound key forward.
+    pub fn open(&self, connection_id: i32, sealed: &[u8]) -> Result<Vec<u8>, SecureChannelError> {
+        self.sessions.open(connection_id, sealed)
+    }
+
+    /// Sends `request` on `connection_id` sealed, and returns the response opened.
+    pub async fn send_request(
+        &self,
+        connection_id: i32,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        self.send_rekey(connection_id, false)?;
+        let sealed = self.seal(connection_id, request)?;
+        let (sender, receiver) = oneshot::channel();
+        let callback = Box::new(OneshotCallback { sender: Some(sender) });
+        self.platform.send_request(connection_id, &sealed, metadata, timeout, callback)?;
+        let mut response = match receiver.await {
+            Ok(Ok(response)) => response,
+            Ok(Err(error_code)) => return Err(SecureChannelError::Failed(error_code).into()),
+            Err(_) => return Err(PlatformError::PlatformDestroyed.into()),
+        };
+        response.payload = self.open(connection_id, &response.payload)?;
+        Ok(response)
+    }
+
+    /// Sends `payload` on `connection_id` sealed, as a one-way message.
+    pub fn send_notification(&self, connection_id: i32, payload: &[u8]) -> anyhow::Result<()> {
+        self.send_rekey(connection_id, false)?;
+        let sealed = self.seal(connection_id, payload)?;
+        self.platform.send_notification(connection_id, &sealed)
+    }
+
+    /// Sends `request` sealed to `device_id`, on `connection_to` it, and returns the response
+    /// opened. Fails with `IdentityError::NotConnected` if no connection has session keys.
+    pub async fn send_request_to(
+        &self,
+        device_id: &DeviceId,
+        request: &[u8],
+        metadata: RequestMetadata,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        let connection_id = self
+            .connection_to(device_id)
+            .ok_or_else(|| IdentityError::NotConnected(device_id.clone()))?;
+        self.send_request(connection_id, request, metadata, timeout).await
+    }
+
+    /// Sends `payload` sealed to `device_id` as a one-way message, on `connection_to` it.
+    pub fn send_notification_to(&self, device_id: &DeviceId, payload: &[u8]) -> anyhow::Result<()> {
+        let connection_id = self
+            .connection_to(device_id)
+            .ok_or_else(|| IdentityError::NotConnected(device_id.clone()))?;
+        self.send_notification(connection_id, payload)
+    }
+
+    /// Subscribes to the messages pushed on `connection_id`, opened.
+    pub fn subscribe(&self, connection_id: i32) -> anyhow::Result<SecureMessageStream<A>> {
+        Ok(SecureMessageStream {
+            inner: self.platform.subscribe(connection_id)?,
+            sessions: Arc::clone(&self.sessions),
+        })
+    }
+}
+
+/// Messages of a `SecureChannel` subscription, opened.
+pub struct SecureMessageStream<A: Aead> {
+    inner: MessageStream,
+    sessions: Arc<Sessions<A>>,
+}
+
+impl<A: Aead> SecureMessageStream<A> {
+    /// Returns the connection the messages come from.
+    pub fn connection_id(&self) -> i32 {
+        self.inner.connection_id()
+    }
+
+    /// Waits for the next message, or returns None once the platform is gone. Messages that
+    /// don't open, e.g. forged, replayed or sent before the session, are dropped, as are the
+    /// `Rekey` frames once applied.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        let connection_id = self.inner.connection_id();
+        loop {
+            let sealed = self.inner.next().await?;
+            match self.sessions.open(connection_id, &sealed) {
+                Ok(payload) => return Some(payload),
+                Err(SecureChannelError::Rekeyed(epoch)) => {
+                    debug!("connection {} rotated its key to epoch {}", connection_id, epoch)
+                }
+                Err(e) => warn!("dropping message from connection {}: {}", connection_id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Cipher;
+    use crate::crc32c::crc32c;
+
+    // Not cryptography: XORs with the key byte, tagged with a CRC.
+    struct FakeKey(u8);
+
+    impl Aead for FakeKey {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::A256Gcm
+        }
+
+        fn seal(&self, iv: &[u8], aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let mut sealed: Vec<u8> = plaintext.iter().map(|byte| byte ^ self.0).collect();
+            sealed.extend_from_slice(&crc32c(&[&[self.0], iv, aad, plaintext]).to_be_bytes());
+            Ok(sealed)
+        }
+
+        fn open(&self, iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (sealed, tag) = ciphertext.split_at(ciphertext.len().checked_sub(4)?);
+            let plaintext: Vec<u8> = sealed.iter().map(|byte| byte ^ self.0).collect();
+            (crc32c(&[&[self.0], iv, aad, &plaintext]).to_be_bytes() == tag).then_some(plaintext)
+        }
+    }
+
+    fn keys(outbound: u8, inbound: u8) -> SessionKeys<FakeKey> {
+        SessionKeys {
+            outbound: DirectionKey {
+                key: FakeKey(outbound),
+                iv: Secret::new([outbound; NONCE_LEN]),
+            },
+            inbound: DirectionKey { key: FakeKey(inbound), iv: Secret::new([inbound; NONCE_LEN]) },
+        }
+    }
+
+    fn sessions(keys: SessionKeys<FakeKey>) -> Sessions<FakeKey> {
+        let session = Session::new(Protection::Aead(keys), None);
+        Sessions { sessions: Mutex::new(HashMap::from([(1, session)])) }
+    }
+
+    struct FakeFactory;
+
+    impl AeadFactory for FakeFactory {
+        type Key = FakeKey;
+
+        fn key(&self, key: &[u8; 32]) -> FakeKey {
+            FakeKey(key[0])
+        }
+    }
+
+    fn ratcheting(outbound: u8, inbound: u8) -> Sessions<FakeKey> {
+        let ratchet = Ratchet {
+            keys: RatchetKeys::new([outbound; 32], [inbound; 32]),
+            factory: Arc::new(FakeFactory),
+            since: Instant::now(),
+        };
+        let keys = SessionKeys {
+            outbound: ratchet.direction_key(true).unwrap(),
+            inbound: ratchet.direction_key(false).unwrap(),
+        };
+        let session = Session::new(Protection::Aead(keys), Some(ratchet));
+        Sessions { sessions: Mutex::new(HashMap::from([(1, session)])) }
+    }
+
+    // Rotates the outbound key of connection 1, and returns the `Rekey` frame sent.
+    fn rotate(sessions: &Sessions<FakeKey>, force: bool) -> Option<Vec<u8>> {
+        let mut sent = None;
+        let rotated = sessions
+            .rotate(1, force, |frame| {
+                sent = Some(frame.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rotated, sent.is_some());
+        sent
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let (local, remote) = (sessions(keys(1, 2)), sessions(keys(2, 1)));
+        let first = local.seal(1, b"unlock").unwrap();
+        let second = local.seal(1, b"unlock").unwrap();
+        assert_eq!(first[..COUNTER_LEN], [0, 0, 0, 0]);
+        assert_eq!(second[..COUNTER_LEN], [0, 0, 0, 1]);
+        assert_ne!(first[COUNTER_LEN..], second[COUNTER_LEN..]);
+
+        assert_eq!(remote.open(1, &second), Ok(b"unlock".to_vec()));
+        assert_eq!(remote.open(1, &first), Ok(b"unlock".to_vec()));
+        assert_eq!(remote.open(1, &first), Err(SecureChannelError::Replayed(0)));
+        // Payloads only open in their direction.
+        let reply = remote.seal(1, b"ok").unwrap();
+        assert_eq!(remote.open(1, &reply), Err(SecureChannelError::Unauthenticated));
+        assert_eq!(local.open(1, &reply), Ok(b"ok".to_vec()));
+
+        let mut tampered = local.seal(1, b"unlock").unwrap();
+        tampered[0] ^= 1;
+        assert_eq!(remote.open(1, &tampered), Err(SecureChannelError::Unauthenticated));
+        assert_eq!(remote.open(1, &[0, 0]), Err(SecureChannelError::Malformed));
+        assert_eq!(remote.open(2, &first), Err(SecureChannelError::NoSession(2)));
+    }
+
+    #[test]
+    fn test_suite() {
+        let suite = CipherSuite::from_id(1).unwrap();
+        assert_eq!(check_algorithm(suite, &keys(1, 2)), Ok(()));
+        let aes128 = CipherSuite { cipher: Cipher::Aes128Gcm, ..suite };
+        assert_eq!(
+            check_algorithm(aes128, &keys(1, 2)),
+            Err(SecureChannelError::Algorithm(Algorithm::A256Gcm))
+        );
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let local = sessions(keys(1, 2));
+        local.sessions.lock().unwrap().get_mut(&1).unwrap().nonces.skip_to(u32::MAX.into());
+        assert!(local.seal(1, b"last").is_ok());
+        let error = local.seal(1, b"one too many").unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&SecureChannelError::Exhausted));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let (local, remote) = (ratcheting(1, 2), ratcheting(2, 1));
+        // Sessions without ratchet don't rotate.
+        assert_eq!(rotate(&sessions(keys(1, 2)), true), None);
+        assert_eq!(rotate(&local, false), None);
+
+        let before = local.seal(1, b"unlock").unwrap();
+        let frame = rotate(&local, true).unwrap();
+        let after = local.seal(1, b"unlock").unwrap();
+        assert_eq!(after[..COUNTER_LEN], [0, 0, 0, 0]);
+        // Payloads sealed after the rotation only open once the frame moved the inbound key.
+        assert_eq!(remote.open(1, &after), Err(SecureChannelError::Unauthenticated));
+        assert_eq!(remote.open(1, &before), Ok(b"unlock".to_vec()));
+        assert_eq!(remote.open(1, &frame), Err(SecureChannelError::Rekeyed(1)));
+        assert_eq!(remote.open(1, &before), Err(SecureChannelError::Unauthenticated));
+        assert_eq!(remote.open(1, &after), Ok(b"unlock".to_vec()));
+        assert_eq!(remote.open(1, &frame), Err(SecureChannelError::Unauthenticated));
+
+        let sessions = local.sessions.lock().unwrap();
+        let ratchet = sessions[&1].ratchet.as_ref().unwrap();
+        assert_eq!(ratchet.keys.outbound.epoch(), 1);
+        assert_eq!(
+            remote.sessions.lock().unwrap()[&1].ratchet.as_ref().unwrap().keys.inbound.epoch(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rotate_send_failed() {
+        let (local, remote) = (ratcheting(1, 2), ratcheting(2, 1));
+        let error = local.rotate(1, true, |_| Err(PlatformError::PlatformDestroyed.into()));
+        assert!(error.is_err());
+        // The key only rotates once the frame is sent: payloads still open with the previous one.
+        let sealed = local.seal(1, b"unlock").unwrap();
+        assert_eq!(remote.open(1, &sealed), Ok(b"unlock".to_vec()));
+        assert_eq!(
+            local.sessions.lock().unwrap()[&1].ratchet.as_ref().unwrap().keys.outbound.epoch(),
+            0
+        );
+
+        let frame = rotate(&local, true).unwrap();
+        let sealed = local.seal(1, b"unlock").unwrap();
+        assert_eq!(remote.open(1, &frame), Err(SecureChannelError::Rekeyed(1)));
+        assert_eq!(remote.open(1, &sealed), Ok(b"unlock".to_vec()));
+    }
+
+    #[test]
+    fn test_rotate_when_due() {
+        let local = ratcheting(1, 2);
+        local
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&1)
+            .unwrap()
+            .nonces
+            .skip_to(config::snapshot().rekey_messages.into());
+        assert!(rotate(&local, false).is_some());
+        assert_eq!(rotate(&local, false), None);
+    }
+
+    #[test]
+    fn test_rotate_before_exhaustion() {
+        let (local, remote) = (ratcheting(1, 2), ratcheting(2, 1));
+        local.sessions.lock().unwrap().get_mut(&1).unwrap().nonces.skip_to(u32::MAX.into());
+        // The last counter of the key only seals the frame rotating it.
+        let error = local.seal(1, b"unlock").unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&SecureChannelError::RekeyRequired));
+        let frame = rotate(&local, false).unwrap();
+        assert_eq!(frame[..COUNTER_LEN], [0xff; COUNTER_LEN]);
+        let sealed = local.seal(1, b"unlock").unwrap();
+        assert_eq!(remote.open(1, &frame), Err(SecureChannelError::Rekeyed(1)));
+        assert_eq!(remote.open(1, &sealed), Ok(b"unlock".to_vec()));
+    }
+
+    #[test]
+    fn test_integrity() {
+        let secrets = |outbound: u8, inbound: u8| MacSecrets {
+            outbound: Secret::new([outbound; HASH_LEN]),
+            inbound: Secret::new([inbound; HASH_LEN]),
+        };
+        let local = Sessions::<FakeKey> {
+            sessions: Mutex::new(HashMap::from([(
+                1,
+                Session::new(Protection::Integrity(secrets(1, 2)), None),
+            )])),
+        };
+        let remote = Sessions::<FakeKey> {
+            sessions: Mutex::new(HashMap::from([(
+                1,
+                Session::new(Protection::Integrity(secrets(2, 1)), None),
+            )])),
+        };
+        let sealed = local.seal(1, b"unlock").unwrap();
+        assert_eq!(sealed[..COUNTER_LEN], [0, 0, 0, 0]);
+        // The payload stays in clear, for the transport to encrypt.
+        assert!(sealed.windows(6).any(|window| window == b"unlock"));
+        assert_eq!(remote.open(1, &sealed), Ok(b"unlock".to_vec()));
+        assert_eq!(remote.open(1, &sealed), Err(SecureChannelError::Replayed(0)));
+
+        let mut tampered = local.seal(1, b"unlock").unwrap();
+        let at = tampered.len() - HASH_LEN - 1;
+        tampered[at] ^= 1;
+        assert_eq!(remote.open(1, &tampered), Err(SecureChannelError::Unauthenticated));
+        assert_eq!(remote.open(1, &[0; COUNTER_LEN + 8]), Err(SecureChannelError::Unauthenticated));
+        // Payloads only authenticate in their direction.
+        let reply = remote.seal(1, b"ok").unwrap();
+        assert_eq!(remote.open(1, &reply), Err(SecureChannelError::Unauthenticated));
+        assert_eq!(local.open(1, &reply), Ok(b"ok".to_vec()));
+    }
+}
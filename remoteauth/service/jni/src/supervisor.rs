@@ -0,0 +1,99 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Supervision of the async tasks spawned on behalf of a single platform.
+//!
+//! Tasks should only hold weak references to their platform: the supervisor is owned by the
+//! platform, so a task keeping it alive would never be aborted.
+
+use crate::runtime::get_runtime;
+use log::debug;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Owns platform-scoped tasks and aborts them when the platform closes.
+#[derive(Debug, Default)]
+pub(crate) struct TaskSupervisor {
+    // None once the supervisor has been shut down.
+    tasks: Mutex<Option<JoinSet<()>>>,
+}
+
+impl TaskSupervisor {
+    /// Creates a supervisor accepting tasks.
+    pub(crate) fn new() -> Self {
+        Self { tasks: Mutex::new(Some(JoinSet::new())) }
+    }
+
+    /// Spawns `task` on the shared runtime. Returns None if the supervisor is shut down.
+    pub(crate) fn spawn<F>(&self, task: F) -> Option<AbortHandle>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self.tasks.lock().unwrap().as_mut() {
+            Some(tasks) => {
+                // Completed tasks keep their slot until joined, reap them as we go.
+                while tasks.try_join_next().is_some() {}
+                Some(tasks.spawn_on(task, get_runtime().handle()))
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the number of tasks that have not been reaped yet.
+    pub(crate) fn len(&self) -> usize {
+        self.tasks.lock().unwrap().as_ref().map_or(0, JoinSet::len)
+    }
+
+    /// Aborts every task and rejects new ones.
+    pub(crate) fn shutdown(&self) {
+        if let Some(mut tasks) = self.tasks.lock().unwrap().take() {
+            debug!("aborting {} supervised tasks", tasks.len());
+            tasks.abort_all();
+        }
+    }
+}
+
+impl Drop for TaskSupervisor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn test_shutdown_aborts_tasks() {
+        let supervisor = TaskSupervisor::new();
+        let (tx, rx) = oneshot::channel::<()>();
+        assert!(supervisor
+            .spawn(async move {
+                // Holds the sender until aborted.
+                let _tx = tx;
+                std::future::pending::<()>().await;
+            })
+            .is_some());
+        assert_eq!(supervisor.len(), 1);
+        supervisor.shutdown();
+        // The sender is dropped when the task is aborted.
+        assert!(get_runtime().block_on(rx).is_err());
+        assert!(supervisor.spawn(async {}).is_none());
+        assert_eq!(supervisor.len(), 0);
+    }
+}
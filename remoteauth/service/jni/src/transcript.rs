@@ -0,0 +1,171 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Downgrade protection, binding the negotiation of a connection into its session keys.
+//!
+//! Every handshake exchange of a connection, from version negotiation on, is appended to its
+//! transcript. The hash of the transcript is bound into the key agreement that follows: it ends
+//! the prologue of the Noise handshake, see `noise`, and is in the context of the keys derived
+//! with `derive_session_key`. A man-in-the-middle rewriting an offer, e.g. to force the oldest
+//! version or the weakest cipher, leaves both sides with different hashes: the key agreement fails
+//! instead of the connection being silently downgraded. UKEY2, whose messages are fixed by its
+//! peers, is not bound to the transcript. Hashing and key derivation go through `Digest` and
+//! `Kdf`, implemented with SHA-256 and HKDF by the `kdf` module.
+
+use crate::kdf::{derive_key, KeyPurpose};
+use crate::messages::TypedPlatform;
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::secret::Secret;
+use thiserror::Error;
+
+/// Why a session key could not be derived.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptError {
+    /// No negotiation was recorded on the connection, so there is nothing to bind the key to.
+    #[error("no negotiation transcript for connection {0}")]
+    Missing(i32),
+}
+
+/// Hashes a transcript, e.g. with SHA-256.
+pub trait Digest {
+    /// Returns the hash of `data`.
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Derives keys from a shared secret, e.g. with HKDF.
+pub trait Kdf {
+    /// Returns `len` bytes of key material derived from `secret` with context `info`.
+    fn derive(&self, secret: &[u8], info: &[u8], len: usize) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Exchanges of the handshakes of a connection, in order.
+///
+/// Each exchange is the message of the native side then the reply of the remote device, each
+/// prefixed with its length as a big-endian `u32`. Messages sent again after a timeout are only
+/// recorded once, with the reply that answered them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    bytes: Vec<u8>,
+}
+
+impl Transcript {
+    /// Creates an empty transcript.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the exchange of `message` and its `reply`.
+    pub fn record(&mut self, message: &[u8], reply: &[u8]) {
+        for part in [message, reply] {
+            self.bytes.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            self.bytes.extend_from_slice(part);
+        }
+    }
+
+    /// Whether no exchange was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the recorded exchanges.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the hash of the recorded exchanges.
+    pub fn hash(&self, digest: &dyn Digest) -> Vec<u8> {
+        digest.digest(&self.bytes)
+    }
+}
+
+/// Derives a session key of `len` bytes from `secret`, bound to `transcript_hash`.
+pub fn derive_session_key(
+    kdf: &dyn Kdf,
+    secret: &[u8],
+    transcript_hash: &[u8],
+    len: usize,
+) -> anyhow::Result<Secret<Vec<u8>>> {
+    derive_key(kdf, KeyPurpose::Session, secret, &[transcript_hash], len)
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Derives a session key of `len` bytes for `connection_id` from `secret`, e.g. agreed by the
+    /// secure channel, bound to the transcript of its negotiation. Fails with `Missing` if the
+    /// connection didn't negotiate.
+    pub fn derive_session_key(
+        &self,
+        connection_id: i32,
+        digest: &dyn Digest,
+        kdf: &dyn Kdf,
+        secret: &[u8],
+        len: usize,
+    ) -> anyhow::Result<Secret<Vec<u8>>> {
+        let transcript = self.transcript(connection_id);
+        if transcript.is_empty() {
+            return Err(TranscriptError::Missing(connection_id).into());
+        }
+        derive_session_key(kdf, secret, &transcript.hash(digest), len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32c::crc32c;
+
+    struct FakeDigest;
+
+    impl Digest for FakeDigest {
+        fn digest(&self, data: &[u8]) -> Vec<u8> {
+            crc32c(&[data]).to_be_bytes().to_vec()
+        }
+    }
+
+    struct FakeKdf;
+
+    impl Kdf for FakeKdf {
+        fn derive(&self, secret: &[u8], info: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+            let block = crc32c(&[secret, info]).to_be_bytes();
+            Ok(block.iter().cycle().take(len).copied().collect())
+        }
+    }
+
+    #[test]
+    fn test_transcript() {
+        let mut transcript = Transcript::new();
+        assert!(transcript.is_empty());
+        transcript.record(&[1, 2], &[3]);
+        assert_eq!(transcript.as_bytes(), &[0, 0, 0, 2, 1, 2, 0, 0, 0, 1, 3]);
+        // Moving a byte between message and reply changes the transcript.
+        let mut other = Transcript::new();
+        other.record(&[1], &[2, 3]);
+        assert_ne!(transcript.hash(&FakeDigest), other.hash(&FakeDigest));
+    }
+
+    #[test]
+    fn test_derive_session_key() {
+        let mut negotiated = Transcript::new();
+        negotiated.record(b"offer 1-2", b"accept 2");
+        let mut downgraded = Transcript::new();
+        downgraded.record(b"offer 1-1", b"accept 1");
+        let key = |transcript: &Transcript| {
+            derive_session_key(&FakeKdf, b"secret", &transcript.hash(&FakeDigest), 16).unwrap()
+        };
+        assert_eq!(key(&negotiated).expose().len(), 16);
+        assert_eq!(key(&negotiated), key(&negotiated.clone()));
+        assert_ne!(key(&negotiated), key(&downgraded));
+    }
+}
@@ -0,0 +1,334 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Transfer of payloads too large for a single message, e.g. key bundles or logs, in chunks.
+//!
+//! Each chunk is a request the receiver acknowledges with the length of the payload it holds
+//! contiguously so far. The sender checkpoints that length per transfer id, so that a transfer
+//! interrupted, e.g. by a disconnect, resumes from the last acknowledged chunk when sent again
+//! on the new connection, instead of restarting.
+
+use crate::messages::{DecodeError, Message, Reader, Request, TypedPlatform, Writer};
+use crate::remoteauth_jni_android_platform::{Platform, RequestMetadata};
+use crate::schema::{Field, Rule, Schema};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Default length of the chunks of a transfer, in bytes.
+pub const TRANSFER_CHUNK_LEN: usize = 16 * 1024;
+/// Longest payload transferred, in bytes.
+pub const MAX_TRANSFER_LEN: usize = 16 * 1024 * 1024;
+/// Lengths and offsets within a transfer.
+const TRANSFER_LEN_RULE: Rule = Rule::Uint { min: 0, max: MAX_TRANSFER_LEN as u64 };
+/// Acknowledgments in a row without progress before a transfer fails.
+const MAX_STALLED_CHUNKS: usize = 3;
+
+/// Why a transfer failed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// The payload is longer than `MAX_TRANSFER_LEN`.
+    #[error("transfer of {0} bytes is too large")]
+    TooLarge(usize),
+    /// The receiver acknowledged more than it was sent, or another transfer.
+    #[error("invalid acknowledgment of transfer {0}")]
+    InvalidAck(u32),
+    /// The receiver stopped acknowledging progress.
+    #[error("transfer {transfer_id} stalled at offset {offset}")]
+    Stalled {
+        /// Id of the transfer.
+        transfer_id: u32,
+        /// Length acknowledged last.
+        offset: u32,
+    },
+    /// A chunk doesn't fit the transfer received so far.
+    #[error("chunk at offset {offset} doesn't fit transfer {transfer_id}")]
+    InvalidChunk {
+        /// Id of the transfer.
+        transfer_id: u32,
+        /// Offset of the chunk.
+        offset: u32,
+    },
+}
+
+/// A chunk of a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferChunk {
+    /// Id of the transfer, chosen by the sender.
+    pub transfer_id: u32,
+    /// Length of the whole payload.
+    pub total_len: u32,
+    /// Offset of the chunk in the payload.
+    pub offset: u32,
+    /// Bytes of the chunk.
+    pub data: Vec<u8>,
+}
+
+/// Acknowledgment of a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferAck {
+    /// Id of the transfer.
+    pub transfer_id: u32,
+    /// Length of the payload the receiver holds contiguously from its start.
+    pub acked: u32,
+}
+
+impl Message for TransferChunk {
+    const TYPE: u8 = 8;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "transfer_chunk",
+        fields: &[
+            Field::new(1, "transfer_id", Rule::U32),
+            Field::new(2, "total_len", TRANSFER_LEN_RULE),
+            Field::new(3, "offset", TRANSFER_LEN_RULE),
+            Field::new(4, "data", Rule::Bytes { min_len: 0, max_len: MAX_TRANSFER_LEN }),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.transfer_id);
+        writer.put_u32(self.total_len);
+        writer.put_u32(self.offset);
+        writer.put_bytes(&self.data);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            transfer_id: reader.u32()?,
+            total_len: reader.u32()?,
+            offset: reader.u32()?,
+            data: reader.bytes()?,
+        })
+    }
+}
+
+impl Request for TransferChunk {
+    type Response = TransferAck;
+}
+
+impl Message for TransferAck {
+    const TYPE: u8 = 9;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "transfer_ack",
+        fields: &[
+            Field::new(1, "transfer_id", Rule::U32),
+            Field::new(2, "acked", TRANSFER_LEN_RULE),
+        ],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.transfer_id);
+        writer.put_u32(self.acked);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { transfer_id: reader.u32()?, acked: reader.u32()? })
+    }
+}
+
+/// Sends transfers over the typed messages of a platform, checkpointing their progress.
+pub struct TransferSender<T: Platform + ?Sized> {
+    typed: Arc<TypedPlatform<T>>,
+    chunk_len: usize,
+    /// Length acknowledged so far of the unfinished transfers, by transfer id.
+    checkpoints: Mutex<HashMap<u32, u32>>,
+}
+
+impl<T: Platform + ?Sized> TransferSender<T> {
+    /// Creates a sender of chunks of `TRANSFER_CHUNK_LEN` bytes.
+    pub fn new(typed: Arc<TypedPlatform<T>>) -> Self {
+        Self { typed, chunk_len: TRANSFER_CHUNK_LEN, checkpoints: Mutex::new(HashMap::new()) }
+    }
+
+    /// Sets the length of the chunks, e.g. to fit the max payload of the connections.
+    ///
+    /// # Panics
+    ///
+    /// If `chunk_len` is 0.
+    pub fn with_chunk_len(mut self, chunk_len: usize) -> Self {
+        assert!(chunk_len > 0, "chunks must not be empty");
+        self.chunk_len = chunk_len;
+        self
+    }
+
+    /// Returns the length acknowledged so far of the unfinished transfer `transfer_id`.
+    pub fn checkpoint(&self, transfer_id: u32) -> Option<u32> {
+        self.checkpoints.lock().unwrap().get(&transfer_id).copied()
+    }
+
+    /// Forgets the checkpoint of `transfer_id`, so that sending it again restarts it.
+    pub fn abandon(&self, transfer_id: u32) {
+        self.checkpoints.lock().unwrap().remove(&transfer_id);
+    }
+
+    /// Sends `payload` as transfer `transfer_id` on `connection_id`, from its checkpoint if it
+    /// was interrupted, with `timeout` for each chunk. On failure, the checkpoint is kept to
+    /// resume the transfer by sending it again, e.g. once reconnected.
+    pub async fn send(
+        &self,
+        connection_id: i32,
+        transfer_id: u32,
+        payload: &[u8],
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let total_len = u32::try_from(payload.len())
+            .ok()
+            .filter(|_| payload.len() <= MAX_TRANSFER_LEN)
+            .ok_or(TransferError::TooLarge(payload.len()))?;
+        let mut offset = self.checkpoint(transfer_id).unwrap_or(0).min(total_len);
+        if offset > 0 {
+            info!("resuming transfer {} at offset {}", transfer_id, offset);
+        }
+        let mut stalled = 0;
+        // An empty payload still takes a chunk, for the receiver to learn about it.
+        loop {
+            let start = offset as usize;
+            let end = payload.len().min(start + self.chunk_len);
+            let chunk = TransferChunk {
+                transfer_id,
+                total_len,
+                offset,
+                data: payload[start..end].to_vec(),
+            };
+            let ack =
+                self.typed.send(connection_id, &chunk, RequestMetadata::new(), timeout).await?;
+            if ack.transfer_id != transfer_id || ack.acked as usize > end {
+                return Err(TransferError::InvalidAck(transfer_id).into());
+            }
+            if ack.acked == total_len {
+                break;
+            }
+            if ack.acked <= offset {
+                stalled += 1;
+                warn!(
+                    "transfer {} acknowledged {} after offset {}",
+                    transfer_id, ack.acked, offset
+                );
+                if stalled == MAX_STALLED_CHUNKS {
+                    return Err(TransferError::Stalled { transfer_id, offset: ack.acked }.into());
+                }
+            } else {
+                stalled = 0;
+            }
+            // The receiver may hold less than checkpointed, e.g. after restarting.
+            offset = ack.acked;
+            self.checkpoints.lock().unwrap().insert(transfer_id, offset);
+        }
+        self.abandon(transfer_id);
+        Ok(())
+    }
+}
+
+/// Payload received so far of a transfer.
+struct IncomingTransfer {
+    total_len: u32,
+    data: Vec<u8>,
+}
+
+/// Reassembles the transfers sent by the remote device, from their chunks.
+#[derive(Default)]
+pub struct TransferReceiver {
+    transfers: HashMap<u32, IncomingTransfer>,
+}
+
+impl TransferReceiver {
+    /// Creates a receiver with no transfer in progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a received chunk, and returns its acknowledgment, with the payload if complete.
+    /// Chunks overlapping what was received already, e.g. resent after a disconnect, are
+    /// accepted; chunks leaving a gap are acknowledged without being kept.
+    pub fn receive(
+        &mut self,
+        chunk: TransferChunk,
+    ) -> Result<(TransferAck, Option<Vec<u8>>), TransferError> {
+        let TransferChunk { transfer_id, total_len, offset, data } = chunk;
+        if total_len as usize > MAX_TRANSFER_LEN {
+            return Err(TransferError::TooLarge(total_len as usize));
+        }
+        let invalid = TransferError::InvalidChunk { transfer_id, offset };
+        let end = (offset as usize).checked_add(data.len()).ok_or(invalid.clone())?;
+        if end > total_len as usize {
+            return Err(invalid);
+        }
+        let transfer = self
+            .transfers
+            .entry(transfer_id)
+            .or_insert_with(|| IncomingTransfer { total_len, data: Vec::new() });
+        if transfer.total_len != total_len {
+            self.transfers.remove(&transfer_id);
+            return Err(invalid);
+        }
+        let received = transfer.data.len();
+        if (offset as usize) <= received && end > received {
+            transfer.data.extend_from_slice(&data[received - offset as usize..]);
+        }
+        let acked = transfer.data.len() as u32;
+        let ack = TransferAck { transfer_id, acked };
+        if acked < total_len {
+            return Ok((ack, None));
+        }
+        let transfer = self.transfers.remove(&transfer_id).unwrap();
+        Ok((ack, Some(transfer.data)))
+    }
+
+    /// Forgets the partial payload of `transfer_id`.
+    pub fn abandon(&mut self, transfer_id: u32) {
+        self.transfers.remove(&transfer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(offset: u32, data: &[u8]) -> TransferChunk {
+        TransferChunk { transfer_id: 1, total_len: 6, offset, data: data.to_vec() }
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut receiver = TransferReceiver::new();
+        let ack = |acked| TransferAck { transfer_id: 1, acked };
+        assert_eq!(receiver.receive(chunk(0, &[1, 2])), Ok((ack(2), None)));
+        // A gap isn't kept, and an overlap only adds what's new.
+        assert_eq!(receiver.receive(chunk(4, &[5, 6])), Ok((ack(2), None)));
+        assert_eq!(receiver.receive(chunk(0, &[1, 2, 3])), Ok((ack(3), None)));
+        assert_eq!(
+            receiver.receive(chunk(3, &[4, 5, 6])),
+            Ok((ack(6), Some(vec![1, 2, 3, 4, 5, 6])))
+        );
+
+        assert_eq!(
+            receiver.receive(chunk(5, &[6, 7])),
+            Err(TransferError::InvalidChunk { transfer_id: 1, offset: 5 })
+        );
+        let chunk = TransferChunk { transfer_id: 2, total_len: u32::MAX, offset: 0, data: vec![] };
+        assert_eq!(receiver.receive(chunk), Err(TransferError::TooLarge(u32::MAX as usize)));
+    }
+
+    #[test]
+    fn test_chunk_round_trip() {
+        let chunk = chunk(2, &[3, 4]);
+        assert_eq!(TransferChunk::decode(&chunk.encode()), Ok(chunk));
+        let ack = TransferAck { transfer_id: 1, acked: 4 };
+        assert_eq!(TransferAck::decode(&ack.encode()), Ok(ack));
+    }
+}
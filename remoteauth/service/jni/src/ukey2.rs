@@ -0,0 +1,311 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! UKEY2 handshake, to establish channels with peers speaking the Nearby and SmartSetup one.
+//!
+//! The native side is the client. It commits to its `ClientFinished`, holding its public key, by
+//! sending its SHA-512 in `ClientInit`; the peer answers with `ServerInit`, holding its own; the
+//! client then sends `ClientFinished`, which gets no reply. Both sides derive, from the SHA-256
+//! of the agreed secret and the two first messages, an authentication string, to compare out of
+//! band, e.g. as digits shown on both devices, and the secret of the next protocol.
+//!
+//! Messages are the protobufs of `proto/ukey2.proto`, carried as raw payloads: framing splits
+//! them like any other. Only the `CURVE25519_SHA512` cipher is supported, with `ecdh`.
+
+use crate::ecdh::{KeyPair, KEY_LEN};
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::kdf::{hkdf_expand, hkdf_extract, sha256, sha512, HASH_LEN};
+use crate::messages::TypedPlatform;
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::rng::SecureRng;
+use crate::secret::Secret;
+use log::{info, warn};
+use protobuf::{EnumOrUnknown, Message};
+use std::time::Duration;
+use thiserror::Error;
+
+pub use remoteauth_proto::ukey2::{
+    Ukey2Alert, Ukey2AlertType, Ukey2CipherCommitment, Ukey2ClientFinished, Ukey2ClientInit,
+    Ukey2HandshakeCipher, Ukey2Message, Ukey2MessageType, Ukey2ServerInit,
+};
+
+/// Version of UKEY2 spoken.
+pub const UKEY2_VERSION: i32 = 1;
+/// Length of the random bytes of `ClientInit` and `ServerInit`.
+pub const RANDOM_LEN: usize = 32;
+/// Next protocol announced by default, the one of Nearby peers.
+pub const DEFAULT_NEXT_PROTOCOL: &str = "AES_256_CBC-HMAC_SHA256";
+/// Longest UKEY2 message accepted.
+pub const MAX_UKEY2_MESSAGE_LEN: usize = 1024;
+
+const AUTH_SALT: &[u8] = b"UKEY2 v1 auth";
+const NEXT_SALT: &[u8] = b"UKEY2 v1 next";
+
+/// Why a UKEY2 handshake failed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum Ukey2Error {
+    /// The message is not a valid protobuf, or too long.
+    #[error("invalid UKEY2 message: {0}")]
+    Invalid(String),
+    /// The peer sent an alert instead of `ServerInit`.
+    #[error("peer alert {0:?}: {1}")]
+    Alert(EnumOrUnknown<Ukey2AlertType>, String),
+    /// The peer sent a message of another type than `ServerInit`.
+    #[error("unexpected message type {0:?}")]
+    UnexpectedMessage(EnumOrUnknown<Ukey2MessageType>),
+    /// The peer speaks another version.
+    #[error("unsupported version {0}")]
+    BadVersion(i32),
+    /// The random bytes of the peer are not `RANDOM_LEN` long.
+    #[error("bad random")]
+    BadRandom,
+    /// The peer picked a cipher the client didn't offer.
+    #[error("unsupported cipher {0:?}")]
+    BadCipher(EnumOrUnknown<Ukey2HandshakeCipher>),
+    /// The public key of the peer is not an X25519 key.
+    #[error("bad public key")]
+    BadPublicKey,
+}
+
+/// Returns `message` of `message_type` wrapped in a `Ukey2Message`.
+fn wrap(message_type: Ukey2MessageType, message: &impl Message) -> Vec<u8> {
+    let mut wrapped = Ukey2Message::new();
+    wrapped.message_type = EnumOrUnknown::new(message_type);
+    wrapped.message_data = message.write_to_bytes().expect("writing to a Vec doesn't fail");
+    wrapped.write_to_bytes().expect("writing to a Vec doesn't fail")
+}
+
+/// Returns the `M` wrapped in `bytes`, a `Ukey2Message` of `message_type`.
+fn unwrap<M: Message>(bytes: &[u8], message_type: Ukey2MessageType) -> Result<M, Ukey2Error> {
+    let invalid = |e: protobuf::Error| Ukey2Error::Invalid(e.to_string());
+    if bytes.len() > MAX_UKEY2_MESSAGE_LEN {
+        return Err(Ukey2Error::Invalid(format!("{} bytes", bytes.len())));
+    }
+    let wrapped = Ukey2Message::parse_from_bytes(bytes).map_err(invalid)?;
+    if wrapped.message_type.enum_value() == Ok(Ukey2MessageType::ALERT) {
+        let alert = Ukey2Alert::parse_from_bytes(&wrapped.message_data).map_err(invalid)?;
+        return Err(Ukey2Error::Alert(alert.alert_type, alert.error_message));
+    }
+    if wrapped.message_type.enum_value() != Ok(message_type) {
+        return Err(Ukey2Error::UnexpectedMessage(wrapped.message_type));
+    }
+    M::parse_from_bytes(&wrapped.message_data).map_err(invalid)
+}
+
+/// Outcome of a UKEY2 handshake.
+pub struct Ukey2Session {
+    /// Authentication string, to compare out of band.
+    pub auth_string: [u8; HASH_LEN],
+    /// Secret of the next protocol.
+    pub next_protocol_secret: Secret<[u8; HASH_LEN]>,
+    /// `ClientFinished` to send, unanswered, to complete the handshake.
+    pub client_finished: Vec<u8>,
+}
+
+impl std::fmt::Debug for Ukey2Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ukey2Session").finish_non_exhaustive()
+    }
+}
+
+/// Returns the `len` digits of `auth_string`, e.g. to show on both devices.
+pub fn verification_code(auth_string: &[u8; HASH_LEN], len: usize) -> String {
+    auth_string.iter().cycle().take(len).map(|byte| char::from(b'0' + byte % 10)).collect()
+}
+
+/// Returns the authentication string and the next protocol secret of the `shared` X25519 secret
+/// and the two first messages.
+fn derive(
+    shared: &[u8],
+    client_init: &[u8],
+    server_init: &[u8],
+) -> ([u8; HASH_LEN], Secret<[u8; HASH_LEN]>) {
+    let secret = Secret::new(sha256(shared));
+    let info = [client_init, server_init].concat();
+    let expand = |salt: &[u8]| -> [u8; HASH_LEN] {
+        let prk = Secret::new(hkdf_extract(salt, secret.expose()));
+        hkdf_expand(prk.expose(), &info, HASH_LEN)
+            .expect("a hash fits the HKDF output")
+            .try_into()
+            .unwrap()
+    };
+    (expand(AUTH_SALT), Secret::new(expand(NEXT_SALT)))
+}
+
+/// UKEY2 handshake, as the client.
+pub struct Ukey2Client {
+    key: KeyPair,
+    client_init: Vec<u8>,
+    client_finished: Vec<u8>,
+}
+
+impl Ukey2Client {
+    /// Creates a handshake with a fresh `key` pair and `random` bytes, announcing
+    /// `next_protocol`.
+    pub fn new(key: KeyPair, random: [u8; RANDOM_LEN], next_protocol: &str) -> Self {
+        let mut finished = Ukey2ClientFinished::new();
+        finished.public_key = key.public().to_vec();
+        let client_finished = wrap(Ukey2MessageType::CLIENT_FINISH, &finished);
+
+        let mut commitment = Ukey2CipherCommitment::new();
+        commitment.handshake_cipher = EnumOrUnknown::new(Ukey2HandshakeCipher::CURVE25519_SHA512);
+        commitment.commitment = sha512(&client_finished).to_vec();
+        let mut init = Ukey2ClientInit::new();
+        init.version = UKEY2_VERSION;
+        init.random = random.to_vec();
+        init.cipher_commitments.push(commitment);
+        init.next_protocol = next_protocol.to_string();
+        let client_init = wrap(Ukey2MessageType::CLIENT_INIT, &init);
+        Self { key, client_init, client_finished }
+    }
+
+    /// Checks the `ServerInit` of the peer, and returns the session.
+    pub fn finish(&self, server_init: &[u8]) -> anyhow::Result<Ukey2Session> {
+        let init: Ukey2ServerInit = unwrap(server_init, Ukey2MessageType::SERVER_INIT)?;
+        if init.version != UKEY2_VERSION {
+            return Err(Ukey2Error::BadVersion(init.version).into());
+        }
+        if init.random.len() != RANDOM_LEN {
+            return Err(Ukey2Error::BadRandom.into());
+        }
+        if init.handshake_cipher.enum_value() != Ok(Ukey2HandshakeCipher::CURVE25519_SHA512) {
+            return Err(Ukey2Error::BadCipher(init.handshake_cipher).into());
+        }
+        let public_key: [u8; KEY_LEN] =
+            init.public_key.try_into().map_err(|_| Ukey2Error::BadPublicKey)?;
+        let shared = self.key.agree(&public_key)?;
+        let (auth_string, next_protocol_secret) =
+            derive(shared.expose(), &self.client_init, server_init);
+        Ok(Ukey2Session {
+            auth_string,
+            next_protocol_secret,
+            client_finished: self.client_finished.clone(),
+        })
+    }
+}
+
+impl Handshake for Ukey2Client {
+    type Output = Ukey2Session;
+    const NAME: &'static str = "UKEY2";
+
+    fn first_message(&mut self) -> Vec<u8> {
+        self.client_init.clone()
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<Ukey2Session>> {
+        Ok(Step::Done(self.finish(reply)?))
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Runs the UKEY2 handshake on `connection_id` as the client, with a key pair and random
+    /// bytes drawn from `rng`, and sends the final `ClientFinished`. The caller compares the
+    /// authentication string of the session out of band before trusting the channel.
+    pub async fn ukey2_handshake(
+        &self,
+        connection_id: i32,
+        rng: &dyn SecureRng,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Ukey2Session> {
+        let key = KeyPair::generate(rng)?;
+        let mut random = [0; RANDOM_LEN];
+        rng.fill(&mut random)?;
+        let client = Ukey2Client::new(key, random, DEFAULT_NEXT_PROTOCOL);
+        let mut machine = HandshakeMachine::new(client);
+        let session = match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("UKEY2 handshake of connection {} failed: {:#}", connection_id, e);
+                return Err(e);
+            }
+        };
+        self.platform().send_notification(connection_id, &session.client_finished)?;
+        info!("connection {} completed the UKEY2 handshake", connection_id);
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the `ServerInit` of a server with `key`, after checking the commitment of the
+    /// client to `client_finished`.
+    fn server_init(key: &KeyPair, client_init: &[u8], client_finished: &[u8]) -> Vec<u8> {
+        let init: Ukey2ClientInit = unwrap(client_init, Ukey2MessageType::CLIENT_INIT).unwrap();
+        assert_eq!(init.version, UKEY2_VERSION);
+        assert_eq!(init.next_protocol, DEFAULT_NEXT_PROTOCOL);
+        assert_eq!(init.cipher_commitments[0].commitment, sha512(client_finished).to_vec());
+        let mut reply = Ukey2ServerInit::new();
+        reply.version = UKEY2_VERSION;
+        reply.random = vec![2; RANDOM_LEN];
+        reply.handshake_cipher = EnumOrUnknown::new(Ukey2HandshakeCipher::CURVE25519_SHA512);
+        reply.public_key = key.public().to_vec();
+        wrap(Ukey2MessageType::SERVER_INIT, &reply)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let server_key = KeyPair::from_secret([3; KEY_LEN]);
+        let mut client = Ukey2Client::new(
+            KeyPair::from_secret([1; KEY_LEN]),
+            [1; RANDOM_LEN],
+            DEFAULT_NEXT_PROTOCOL,
+        );
+        let client_init = client.first_message();
+        let reply = server_init(&server_key, &client_init, &client.client_finished);
+        let Step::Done(session) = client.on_reply(&reply).unwrap() else {
+            panic!("the client completes on ServerInit");
+        };
+
+        let finished: Ukey2ClientFinished =
+            unwrap(&session.client_finished, Ukey2MessageType::CLIENT_FINISH).unwrap();
+        let client_key: [u8; KEY_LEN] = finished.public_key.try_into().unwrap();
+        let shared = server_key.agree(&client_key).unwrap();
+        let (auth_string, next_protocol_secret) = derive(shared.expose(), &client_init, &reply);
+        assert_eq!(session.auth_string, auth_string);
+        assert_eq!(session.next_protocol_secret, next_protocol_secret);
+        assert_ne!(&auth_string, next_protocol_secret.expose());
+        assert_eq!(verification_code(&auth_string, 6).len(), 6);
+    }
+
+    #[test]
+    fn test_alert() {
+        let client = Ukey2Client::new(
+            KeyPair::from_secret([1; KEY_LEN]),
+            [1; RANDOM_LEN],
+            DEFAULT_NEXT_PROTOCOL,
+        );
+        let mut alert = Ukey2Alert::new();
+        alert.alert_type = EnumOrUnknown::new(Ukey2AlertType::BAD_VERSION);
+        alert.error_message = String::from("version 2 only");
+        let error = client.finish(&wrap(Ukey2MessageType::ALERT, &alert)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&Ukey2Error::Alert(
+                EnumOrUnknown::new(Ukey2AlertType::BAD_VERSION),
+                String::from("version 2 only")
+            ))
+        );
+
+        let mut init = Ukey2ServerInit::new();
+        init.version = UKEY2_VERSION;
+        init.random = vec![2; RANDOM_LEN];
+        init.handshake_cipher = EnumOrUnknown::new(Ukey2HandshakeCipher::P256_SHA512);
+        let error = client.finish(&wrap(Ukey2MessageType::SERVER_INIT, &init)).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Ukey2Error>(), Some(Ukey2Error::BadCipher(_))));
+    }
+}
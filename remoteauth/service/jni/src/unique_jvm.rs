@@ -20,29 +20,88 @@
 //! per [JNI spec](https://docs.oracle.com/javase/8/docs/technotes/guides/jni/spec/invocation.html)
 //! The unique JavaVM need to be shared over (potentially) different threads.
 
-use std::sync::{Arc, Once};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
 
 use anyhow::Result;
 use jni::JavaVM;
+use thiserror::Error;
+
+/// Errors setting the unique JavaVM.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum JvmError {
+    /// A JavaVM other than the one already captured was passed, e.g. after the test harness
+    /// re-created its VM.
+    #[error("JavaVM mismatch: already set to {existing:#x}, got {new:#x}")]
+    Mismatch { existing: usize, new: usize },
+}
+
+// Points to a leaked Arc so that references handed out by get_static_ref() stay valid for the
+// lifetime of the process, even across reset_for_testing().
+static JVM: AtomicPtr<Arc<JavaVM>> = AtomicPtr::new(ptr::null_mut());
 
-static mut JVM: Option<Arc<JavaVM>> = None;
-static INIT: Once = Once::new();
 /// set_once sets the unique JavaVM that can be then accessed using get_static_ref()
 ///
-/// The function shall only be called once.
+/// Calling it again with the same JavaVM is a no-op; a different JavaVM is rejected with
+/// `JvmError::Mismatch`.
 pub(crate) fn set_once(jvm: JavaVM) -> Result<()> {
-    // Safety: follows [this pattern](https://doc.rust-lang.org/std/sync/struct.Once.html).
-    // Modification to static mut is nested inside call_once.
-    unsafe {
-        INIT.call_once(|| {
-            JVM = Some(Arc::new(jvm));
-        });
+    let new = jvm.get_java_vm_pointer();
+    let candidate = Box::into_raw(Box::new(Arc::new(jvm)));
+    match JVM.compare_exchange(ptr::null_mut(), candidate, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(()),
+        Err(existing) => {
+            // Safety: candidate comes from Box::into_raw above and was never published.
+            drop(unsafe { Box::from_raw(candidate) });
+            // Safety: published pointers come from Box::into_raw and are never freed.
+            let existing = unsafe { &*existing }.get_java_vm_pointer();
+            if existing == new {
+                Ok(())
+            } else {
+                Err(JvmError::Mismatch { existing: existing as usize, new: new as usize }.into())
+            }
+        }
     }
-    Ok(())
 }
+
 /// Gets a 'static reference to the unique JavaVM. Returns None if set_once() was never called.
 pub(crate) fn get_static_ref() -> Option<&'static Arc<JavaVM>> {
-    // Safety: follows [this pattern](https://doc.rust-lang.org/std/sync/struct.Once.html).
-    // Modification to static mut is nested inside call_once.
-    unsafe { JVM.as_ref() }
+    // Safety: published pointers come from Box::into_raw and are never freed.
+    unsafe { JVM.load(Ordering::Acquire).as_ref() }
+}
+
+/// Forgets the unique JavaVM so that set_once() accepts a new one.
+///
+/// The previous JavaVM is leaked on purpose: references to it may still be alive.
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) fn reset_for_testing() {
+    JVM.store(ptr::null_mut(), Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_vm(address: usize) -> JavaVM {
+        // Safety: the fake pointer is only compared, never dereferenced.
+        unsafe { JavaVM::from_raw(address as *mut jni::sys::JavaVM) }.unwrap()
+    }
+
+    #[test]
+    fn test_set_once_detects_mismatch() {
+        reset_for_testing();
+        assert!(get_static_ref().is_none());
+        set_once(fake_vm(0x1000)).unwrap();
+        set_once(fake_vm(0x1000)).unwrap();
+        let err = set_once(fake_vm(0x2000)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<JvmError>(),
+            Some(&JvmError::Mismatch { existing: 0x1000, new: 0x2000 })
+        );
+        reset_for_testing();
+        set_once(fake_vm(0x2000)).unwrap();
+        assert_eq!(get_static_ref().unwrap().get_java_vm_pointer() as usize, 0x2000);
+        reset_for_testing();
+    }
 }
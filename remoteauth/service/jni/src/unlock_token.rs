@@ -0,0 +1,306 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Unlock tokens, handed to Java once a remote device authenticated.
+//!
+//! A token says which device authenticated, for a short time, once. It carries a random id, the
+//! id of the device and its expiry, authenticated with HMAC-SHA256 under a key drawn at startup
+//! that never leaves native code, so that tokens don't outlive the process. Java hands the token
+//! back to unlock: it is redeemed only if authentic, unexpired and never redeemed before, the
+//! ids of the tokens issued being kept until they are redeemed or expire.
+//!
+//! Expiry follows a `MonotonicClock`, by default the time since boot, which changes of the wall
+//! clock can't move.
+
+use crate::device_identity::DeviceId;
+use crate::kdf::{hmac_sha256, HASH_LEN};
+use crate::rng::{OsRng, SecureRng};
+use crate::secret::{constant_time_eq, Secret};
+use crate::utils::elapsed_realtime;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Version of the token layout.
+pub const TOKEN_VERSION: u8 = 1;
+/// Length of the random id of a token.
+pub const TOKEN_ID_LEN: usize = 16;
+/// How long a token is valid by default.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(30);
+/// Most tokens outstanding at once. Issuing more drops the ones expiring first.
+pub const MAX_OUTSTANDING_TOKENS: usize = 64;
+
+// Prefixed to the token before its MAC, so that the key authenticates nothing else.
+const MAC_CONTEXT: &[u8] = b"remoteauth unlock token v1";
+// Version, id, expiry and the length of the device id, before the device id.
+const HEADER_LEN: usize = 1 + TOKEN_ID_LEN + 8 + 1;
+
+static UNLOCK_TOKENS: OnceLock<UnlockTokens> = OnceLock::new();
+
+/// Time that only moves forward, from an arbitrary origin.
+pub trait MonotonicClock: Send + Sync {
+    /// Returns the time since the origin.
+    fn now(&self) -> Duration;
+}
+
+/// Time since boot, including deep sleep.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BootClock;
+
+impl MonotonicClock for BootClock {
+    fn now(&self) -> Duration {
+        elapsed_realtime()
+    }
+}
+
+/// Why a token was not issued or redeemed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    /// The token is not of the layout of `TOKEN_VERSION`.
+    #[error("malformed token")]
+    Malformed,
+    /// The token was not issued by this process, or was altered.
+    #[error("bad token MAC")]
+    BadMac,
+    /// The token expired.
+    #[error("token expired")]
+    Expired,
+    /// The token was redeemed before, or dropped for too many outstanding ones.
+    #[error("token already redeemed")]
+    Replayed,
+    /// No random id could be drawn.
+    #[error("no randomness for the token")]
+    Random,
+}
+
+/// Token of an authenticated device, to hand to Java.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UnlockToken(Secret<Vec<u8>>);
+
+impl UnlockToken {
+    /// Returns the bytes of the token.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.expose()
+    }
+}
+
+impl std::fmt::Debug for UnlockToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("UnlockToken").finish_non_exhaustive()
+    }
+}
+
+/// Issues and redeems unlock tokens.
+pub struct UnlockTokens {
+    key: Secret<[u8; HASH_LEN]>,
+    rng: Arc<dyn SecureRng>,
+    clock: Arc<dyn MonotonicClock>,
+    ttl: Duration,
+    // Expiry of the ids of the tokens issued and not redeemed yet.
+    outstanding: Mutex<HashMap<[u8; TOKEN_ID_LEN], Duration>>,
+}
+
+impl UnlockTokens {
+    /// Creates an issuer with a fresh random key from `OsRng`, on the `BootClock`, issuing
+    /// tokens valid for `DEFAULT_TOKEN_TTL`.
+    pub fn new() -> Result<Self, TokenError> {
+        Self::with_rng(Arc::new(OsRng), Arc::new(BootClock))
+    }
+
+    /// Creates an issuer drawing its key and the ids of its tokens from `rng`, on `clock`,
+    /// issuing tokens valid for `DEFAULT_TOKEN_TTL`.
+    pub fn with_rng(
+        rng: Arc<dyn SecureRng>,
+        clock: Arc<dyn MonotonicClock>,
+    ) -> Result<Self, TokenError> {
+        let mut key = Secret::new([0; HASH_LEN]);
+        rng.fill(key.expose_mut()).map_err(|_| TokenError::Random)?;
+        Ok(Self { key, rng, clock, ttl: DEFAULT_TOKEN_TTL, outstanding: Mutex::default() })
+    }
+
+    /// Sets how long tokens are valid.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Issues a token of `device_id`, which just authenticated.
+    pub fn issue(&self, device_id: &DeviceId) -> Result<UnlockToken, TokenError> {
+        let mut id = [0; TOKEN_ID_LEN];
+        self.rng.fill(&mut id).map_err(|_| TokenError::Random)?;
+        let now = self.clock.now();
+        let expires_at = now + self.ttl;
+        // Device ids are at most 64 bytes long.
+        let device_id = device_id.as_str().as_bytes();
+        let mut token = Vec::with_capacity(HEADER_LEN + device_id.len() + HASH_LEN);
+        token.push(TOKEN_VERSION);
+        token.extend_from_slice(&id);
+        token.extend_from_slice(&millis(expires_at).to_be_bytes());
+        token.push(device_id.len() as u8);
+        token.extend_from_slice(device_id);
+        token.extend_from_slice(&hmac_sha256(self.key.expose(), &[MAC_CONTEXT, &token]));
+
+        let mut outstanding = self.outstanding.lock().unwrap();
+        outstanding.retain(|_, expiry| now < *expiry);
+        while outstanding.len() >= MAX_OUTSTANDING_TOKENS {
+            let Some(first) = outstanding.iter().min_by_key(|(_, expiry)| **expiry) else {
+                break;
+            };
+            let first = *first.0;
+            outstanding.remove(&first);
+        }
+        outstanding.insert(id, expires_at);
+        Ok(UnlockToken(Secret::new(token)))
+    }
+
+    /// Redeems `token`, and returns the device it was issued to. A token is redeemed once:
+    /// afterwards, it fails with `Replayed`.
+    pub fn redeem(&self, token: &[u8]) -> Result<DeviceId, TokenError> {
+        let (body, mac) =
+            token.split_at(token.len().checked_sub(HASH_LEN).ok_or(TokenError::Malformed)?);
+        if !constant_time_eq(&hmac_sha256(self.key.expose(), &[MAC_CONTEXT, body]), mac) {
+            return Err(TokenError::BadMac);
+        }
+        if body.len() < HEADER_LEN {
+            return Err(TokenError::Malformed);
+        }
+        let (header, device_id) = body.split_at(HEADER_LEN);
+        let (version, rest) = header.split_at(1);
+        let (id, rest) = rest.split_at(TOKEN_ID_LEN);
+        let (expires_at, device_id_len) = rest.split_at(8);
+        if version != [TOKEN_VERSION] || usize::from(device_id_len[0]) != device_id.len() {
+            return Err(TokenError::Malformed);
+        }
+        let device_id = std::str::from_utf8(device_id)
+            .ok()
+            .and_then(|device_id| DeviceId::new(device_id).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        // Taken out first, so that even an expired token can't be tried again.
+        let id: [u8; TOKEN_ID_LEN] = id.try_into().map_err(|_| TokenError::Malformed)?;
+        if self.outstanding.lock().unwrap().remove(&id).is_none() {
+            return Err(TokenError::Replayed);
+        }
+        let expires_at =
+            u64::from_be_bytes(expires_at.try_into().map_err(|_| TokenError::Malformed)?);
+        if millis(self.clock.now()) >= expires_at {
+            return Err(TokenError::Expired);
+        }
+        Ok(device_id)
+    }
+}
+
+/// Returns the issuer of the tokens handed to Java, created on first use.
+pub fn unlock_tokens() -> Result<&'static UnlockTokens, TokenError> {
+    if let Some(tokens) = UNLOCK_TOKENS.get() {
+        return Ok(tokens);
+    }
+    let tokens = UnlockTokens::new()?;
+    Ok(UNLOCK_TOKENS.get_or_init(|| tokens))
+}
+
+fn millis(time: Duration) -> u64 {
+    u64::try_from(time.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RngError;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Not random: each draw is the number of draws before it, from a seed, repeated.
+    struct FakeRng(AtomicU64);
+
+    impl SecureRng for FakeRng {
+        fn fill(&self, buf: &mut [u8]) -> Result<(), RngError> {
+            let count = self.0.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+            buf.iter_mut().zip(count.iter().cycle()).for_each(|(byte, count)| *byte = *count);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeClock {
+        now: Mutex<Duration>,
+    }
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl MonotonicClock for FakeClock {
+        fn now(&self) -> Duration {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn tokens(clock: &Arc<FakeClock>) -> UnlockTokens {
+        UnlockTokens::with_rng(Arc::new(FakeRng(AtomicU64::new(5))), clock.clone())
+            .unwrap()
+            .with_ttl(Duration::from_secs(30))
+    }
+
+    #[test]
+    fn test_redeem() {
+        let clock = Arc::new(FakeClock::default());
+        let tokens = tokens(&clock);
+        let watch = DeviceId::new("watch").unwrap();
+        let token = tokens.issue(&watch).unwrap();
+        assert_eq!(format!("{:?}", token), "UnlockToken(..)");
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(tokens.redeem(token.as_bytes()), Ok(watch.clone()));
+        assert_eq!(tokens.redeem(token.as_bytes()), Err(TokenError::Replayed));
+
+        let expired = tokens.issue(&watch).unwrap();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(tokens.redeem(expired.as_bytes()), Err(TokenError::Expired));
+        assert_eq!(tokens.redeem(expired.as_bytes()), Err(TokenError::Replayed));
+    }
+
+    #[test]
+    fn test_reject() {
+        let clock = Arc::new(FakeClock::default());
+        let tokens = tokens(&clock);
+        let token = tokens.issue(&DeviceId::new("watch").unwrap()).unwrap();
+        let mut altered = token.as_bytes().to_vec();
+        altered[1] ^= 1;
+        assert_eq!(tokens.redeem(&altered), Err(TokenError::BadMac));
+        assert_eq!(tokens.redeem(&token.as_bytes()[1..]), Err(TokenError::BadMac));
+        assert_eq!(tokens.redeem(&[0; HASH_LEN - 1]), Err(TokenError::Malformed));
+
+        let rng = Arc::new(FakeRng(AtomicU64::new(6)));
+        let other = UnlockTokens::with_rng(rng, clock.clone()).unwrap();
+        assert_eq!(other.redeem(token.as_bytes()), Err(TokenError::BadMac));
+        assert!(tokens.redeem(token.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_outstanding() {
+        let clock = Arc::new(FakeClock::default());
+        let tokens = tokens(&clock);
+        let watch = DeviceId::new("watch").unwrap();
+        let first = tokens.issue(&watch).unwrap();
+        clock.advance(Duration::from_millis(1));
+        let issued: Vec<UnlockToken> =
+            (0..MAX_OUTSTANDING_TOKENS).map(|_| tokens.issue(&watch).unwrap()).collect();
+        assert_eq!(tokens.redeem(first.as_bytes()), Err(TokenError::Replayed));
+        assert!(issued.iter().all(|token| tokens.redeem(token.as_bytes()).is_ok()));
+    }
+}
@@ -14,8 +14,135 @@
  * limitations under the License.
  */
 
+use crate::jnames::{
+    BAD_HANDLE_EXCEPTION_CLASS, ILLEGAL_ARGUMENT_EXCEPTION_CLASS, ILLEGAL_STATE_EXCEPTION_CLASS,
+    MESSAGE_TOO_LARGE_EXCEPTION_CLASS, NATIVE_EXCEPTION_CLASS,
+};
+use crate::jni_onload::get_jni_cache;
+use jni::objects::{GlobalRef, JClass};
 use jni::sys::jboolean;
+use jni::JNIEnv;
 use log::error;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::time::Duration;
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Initializes the Android logger for the library and sets the maximum log level.
+///
+/// The logger itself is only installed once; later calls just update the level.
+pub(crate) fn init_logger(max_level: log::LevelFilter) {
+    logger::init(
+        logger::Config::default()
+            .with_tag_on_device("remoteauth")
+            .with_max_level(log::LevelFilter::Trace)
+            .with_filter("trace,jni=info"),
+    );
+    log::set_max_level(max_level);
+}
+
+/// Installs a panic hook logging the panic location and backtrace before unwinding starts.
+pub(crate) fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            error!("remoteauth native panic: {}\n{}", info, Backtrace::force_capture());
+            default_hook(info);
+        }));
+    });
+}
+
+/// Runs the body of an exported JNI function, catching any panic.
+///
+/// Unwinding across the JNI boundary aborts the process, so a panic is turned into a
+/// RemoteAuthNativeException thrown to Java and `on_panic` is returned instead.
+pub(crate) fn catch_jni_panic<'a, R>(
+    env: JNIEnv<'a>,
+    function_name: &str,
+    on_panic: R,
+    body: impl FnOnce(JNIEnv<'a>) -> R,
+) -> R {
+    let raw_env = env.get_native_interface();
+    match panic::catch_unwind(AssertUnwindSafe(|| body(env))) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = format!("{} panicked: {}", function_name, panic_message(payload.as_ref()));
+            error!("{}", msg);
+            // Safety: raw_env is the JNIEnv of the JNI call in progress on this thread.
+            match unsafe { JNIEnv::from_raw(raw_env) } {
+                Ok(env) => throw_native_exception(&env, msg),
+                Err(e) => error!("Failed to recover JNIEnv in {}: {:?}", function_name, e),
+            }
+            on_panic
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
+    }
+}
+
+fn throw_exception(env: &JNIEnv, class_name: &str, cached_class: Option<&GlobalRef>, msg: String) {
+    let result = match cached_class {
+        Some(class) => env.throw_new(JClass::from(class.as_obj()), msg),
+        None => env.throw_new(class_name, msg),
+    };
+    if let Err(e) = result {
+        error!("Failed to throw {}: {:?}", class_name, e);
+    }
+}
+
+/// Throws PlatformBadHandleException, using the class resolved in JNI_OnLoad when available.
+pub(crate) fn throw_bad_handle(env: &JNIEnv, msg: String) {
+    let cached_class = get_jni_cache().map(|cache| &cache.bad_handle_exception_class);
+    throw_exception(env, BAD_HANDLE_EXCEPTION_CLASS, cached_class, msg);
+}
+
+/// Throws IllegalStateException, e.g. for callbacks reaching a closed platform.
+pub(crate) fn throw_illegal_state(env: &JNIEnv, msg: String) {
+    throw_exception(env, ILLEGAL_STATE_EXCEPTION_CLASS, None, msg);
+}
+
+/// Throws IllegalArgumentException, e.g. for a value Java passes that native doesn't know.
+pub(crate) fn throw_illegal_argument(env: &JNIEnv, msg: String) {
+    throw_exception(env, ILLEGAL_ARGUMENT_EXCEPTION_CLASS, None, msg);
+}
+
+/// Throws MessageTooLargeException, for a message Java delivers that its connection doesn't allow.
+pub(crate) fn throw_message_too_large(env: &JNIEnv, msg: String) {
+    throw_exception(env, MESSAGE_TOO_LARGE_EXCEPTION_CLASS, None, msg);
+}
+
+/// Throws RemoteAuthNativeException, using the class resolved in JNI_OnLoad when available.
+pub(crate) fn throw_native_exception(env: &JNIEnv, msg: String) {
+    let cached_class = get_jni_cache().map(|cache| &cache.native_exception_class);
+    throw_exception(env, NATIVE_EXCEPTION_CLASS, cached_class, msg);
+}
+
+/// Time since boot, including deep sleep: the clock of `SystemClock.elapsedRealtime()`.
+pub(crate) fn elapsed_realtime() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Safety: ts is a valid timespec for clock_gettime to fill in.
+    let result = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    assert_eq!(result, 0, "clock_gettime(CLOCK_BOOTTIME) failed");
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Converts an absolute `elapsedRealtime` deadline in milliseconds into the time left until it,
+/// or None if it has already passed.
+pub(crate) fn remaining_until_elapsed_realtime(deadline_millis: i64) -> Option<Duration> {
+    let deadline = Duration::from_millis(deadline_millis.max(0) as u64);
+    deadline.checked_sub(elapsed_realtime()).filter(|remaining| !remaining.is_zero())
+}
 
 pub(crate) fn get_boolean_result<T>(result: anyhow::Result<T>, error_msg: &str) -> jboolean {
     match result {
@@ -0,0 +1,245 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Negotiation of the protocol version of a connection.
+//!
+//! The first exchange on a new connection offers the range of versions the native side
+//! supports, and the remote device answers with the highest version of both ranges, or refuses.
+//! The messages of the connection are then encoded and decoded as that version defines. The
+//! handshake itself always uses the default encoding, which every version understands.
+
+use crate::handshake::{Handshake, HandshakeMachine, Step};
+use crate::messages::{
+    decode_response, DecodeError, Encoding, Message, Reader, Request, TypedPlatform, Writer,
+};
+use crate::remoteauth_jni_android_platform::Platform;
+use crate::schema::{Field, Rule, Schema};
+use log::{error, info, warn};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Oldest protocol version supported.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Newest protocol version supported.
+pub const MAX_PROTOCOL_VERSION: u32 = 2;
+/// First version ignoring the fields a message doesn't know, appended by later revisions of it.
+pub const UNKNOWN_FIELDS_VERSION: u32 = 2;
+/// Version of a `VersionAccept` refusing the offer.
+const REFUSED: u32 = 0;
+
+/// Why no version could be agreed on.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum VersionError {
+    /// The remote device supports none of the versions offered.
+    #[error("remote device refused versions {min} to {max}")]
+    Refused {
+        /// Oldest version offered.
+        min: u32,
+        /// Newest version offered.
+        max: u32,
+    },
+    /// The remote device picked a version outside of the offer.
+    #[error("remote device picked unsupported version {0}")]
+    Unsupported(u32),
+}
+
+/// Range of protocol versions, bounds included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange {
+    /// Oldest version of the range.
+    pub min: u32,
+    /// Newest version of the range.
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// Versions supported by the native side.
+    pub const SUPPORTED: Self = Self { min: MIN_PROTOCOL_VERSION, max: MAX_PROTOCOL_VERSION };
+
+    /// Whether `version` is in the range.
+    pub fn contains(&self, version: u32) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+
+    /// Returns the highest version of both ranges, if any.
+    pub fn negotiate(&self, other: &VersionRange) -> Option<u32> {
+        let version = self.max.min(other.max);
+        (version >= self.min.max(other.min)).then_some(version)
+    }
+}
+
+/// First message of a connection, offering a range of protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionOffer {
+    /// Versions offered.
+    pub range: VersionRange,
+}
+
+/// Answer to a `VersionOffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionAccept {
+    /// Version agreed on, or 0 if none.
+    pub version: u32,
+}
+
+impl VersionAccept {
+    /// Returns the agreed version, or None if the offer was refused.
+    pub fn version(&self) -> Option<u32> {
+        Some(self.version).filter(|version| *version != REFUSED)
+    }
+}
+
+impl Message for VersionOffer {
+    const TYPE: u8 = 5;
+    const SCHEMA: Option<Schema> = Some(Schema {
+        name: "version_offer",
+        fields: &[Field::new(1, "min", Rule::U32), Field::new(2, "max", Rule::U32)],
+    });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.range.min);
+        writer.put_u32(self.range.max);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { range: VersionRange { min: reader.u32()?, max: reader.u32()? } })
+    }
+}
+
+impl Request for VersionOffer {
+    type Response = VersionAccept;
+}
+
+impl Message for VersionAccept {
+    const TYPE: u8 = 6;
+    const SCHEMA: Option<Schema> =
+        Some(Schema { name: "version_accept", fields: &[Field::new(1, "version", Rule::U32)] });
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        writer.put_u32(self.version);
+    }
+
+    fn decode_fields(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self { version: reader.u32()? })
+    }
+}
+
+/// Answers an offer of the remote device, refusing it if no version is supported by both sides.
+pub fn answer_offer(offer: &VersionOffer) -> VersionAccept {
+    match VersionRange::SUPPORTED.negotiate(&offer.range) {
+        Some(version) => {
+            log_downgrade(version);
+            VersionAccept { version }
+        }
+        None => {
+            warn!(
+                "refusing versions {} to {}, supported {} to {}",
+                offer.range.min, offer.range.max, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION
+            );
+            VersionAccept { version: REFUSED }
+        }
+    }
+}
+
+fn log_downgrade(version: u32) {
+    if version < MAX_PROTOCOL_VERSION {
+        warn!("downgrading to protocol version {} from {}", version, MAX_PROTOCOL_VERSION);
+    }
+}
+
+/// Handshake offering the supported versions, and checking the one the remote device accepts.
+pub struct VersionNegotiation {
+    offer: VersionOffer,
+}
+
+impl VersionNegotiation {
+    /// Creates a negotiation offering `VersionRange::SUPPORTED`.
+    pub fn new() -> Self {
+        Self { offer: VersionOffer { range: VersionRange::SUPPORTED } }
+    }
+}
+
+impl Default for VersionNegotiation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handshake for VersionNegotiation {
+    type Output = u32;
+    const NAME: &'static str = "version negotiation";
+
+    fn first_message(&mut self) -> Vec<u8> {
+        self.offer.encode_as(Encoding::default())
+    }
+
+    fn on_reply(&mut self, reply: &[u8]) -> anyhow::Result<Step<u32>> {
+        let accept: VersionAccept = decode_response(reply, Encoding::default())?;
+        match accept.version() {
+            Some(version) if self.offer.range.contains(version) => Ok(Step::Done(version)),
+            Some(version) => Err(VersionError::Unsupported(version).into()),
+            None => {
+                let VersionRange { min, max } = self.offer.range;
+                Err(VersionError::Refused { min, max }.into())
+            }
+        }
+    }
+}
+
+impl<T: Platform + ?Sized> TypedPlatform<T> {
+    /// Negotiates the protocol version of `connection_id`, which must be its first exchange,
+    /// and returns it. On failure, the connection keeps the default encoding, and should be
+    /// closed: the remote device is not supported.
+    pub async fn negotiate_version(
+        &self,
+        connection_id: i32,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<u32> {
+        // A negotiation starts the transcript of the connection.
+        self.reset_transcript(connection_id);
+        let mut machine = HandshakeMachine::new(VersionNegotiation::new());
+        let version = match self.run_handshake(connection_id, &mut machine, timeout).await {
+            Ok(version) => version,
+            Err(e) => {
+                error!("version negotiation of connection {} failed: {:#}", connection_id, e);
+                return Err(e);
+            }
+        };
+        log_downgrade(version);
+        info!("connection {} speaks protocol version {}", connection_id, version);
+        self.set_protocol_version(connection_id, version);
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let range = |min, max| VersionRange { min, max };
+        assert_eq!(VersionRange::SUPPORTED.negotiate(&range(1, 5)), Some(MAX_PROTOCOL_VERSION));
+        assert_eq!(range(1, 3).negotiate(&range(2, 2)), Some(2));
+        assert_eq!(range(1, 1).negotiate(&range(2, 3)), None);
+
+        let offer = VersionOffer { range: range(1, 1) };
+        assert_eq!(VersionOffer::decode(&offer.encode()), Ok(offer));
+        assert_eq!(answer_offer(&offer).version(), Some(1));
+        let offer = VersionOffer { range: range(MAX_PROTOCOL_VERSION + 1, u32::MAX) };
+        assert_eq!(answer_offer(&offer).version(), None);
+    }
+}